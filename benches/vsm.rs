@@ -0,0 +1,122 @@
+//! Benchmark of indexing and a full scan of a Binary VSM, at the size of the interval index for
+//! 200,000 tunes.
+//! Run with `cargo bench --bench vsm`.
+
+extern crate folktunefinder_abc;
+
+use folktunefinder_abc::relations::{BinaryVSM, ScoreNormalization};
+use folktunefinder_abc::search::Deadline;
+use std::time::Instant;
+
+const NUM_DOCS: usize = 200_000;
+const BIT_CAPACITY: usize = 16127;
+const TERMS_PER_DOC: usize = 200;
+const NUM_TERMS: u32 = 50_000;
+const ITERATIONS: u32 = 10;
+
+// One in this many docs also has a common phrase, which is the same 10 terms.
+const COMMON_PHRASE_EVERY: usize = 20;
+
+// Small deterministic generator, so every run indexes the same terms.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) as u32
+    }
+}
+
+// Run the function a few times and report the average.
+fn time<F: Fn() -> usize>(name: &str, f: F) {
+    let count = f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let millis = (elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0)
+        / ITERATIONS as f64;
+    println!("{}: {:.1} ms per scan, {} results", name, millis, count);
+}
+
+fn main() {
+    let mut random = Lcg(42);
+    let mut vsm: BinaryVSM<u32> = BinaryVSM::new(BIT_CAPACITY, NUM_DOCS);
+
+    let start = Instant::now();
+    for tune_id in 0..NUM_DOCS {
+        let mut terms: Vec<u32> = (0..TERMS_PER_DOC)
+            .map(|_| random.next() % NUM_TERMS)
+            .collect();
+        if tune_id % COMMON_PHRASE_EVERY == 0 {
+            terms.extend(NUM_TERMS..NUM_TERMS + 10);
+        }
+        vsm.add_terms(tune_id, terms);
+    }
+    println!("Indexed {} docs in {:?}", NUM_DOCS, start.elapsed());
+
+    // What compacting the finished index, as the search engine does, saves.
+    let before = vsm.exact_heap_bytes();
+    vsm.shrink_to_fit();
+    println!(
+        "Exact term lists and postings: {:.1} MB before compacting, {:.1} MB after",
+        before as f64 / 1_000_000.0,
+        vsm.exact_heap_bytes() as f64 / 1_000_000.0
+    );
+
+    let query: Vec<u32> = (0..10).map(|_| random.next() % NUM_TERMS).collect();
+    let common_query: Vec<u32> = (NUM_TERMS..NUM_TERMS + 10).collect();
+
+    time("search_by_terms", || {
+        vsm.search_by_terms(&query, 0.1, false, ScoreNormalization::DocA)
+            .total()
+    });
+    time("search_by_terms exact", || {
+        vsm.search_by_terms(&query, 0.1, true, ScoreNormalization::DocA)
+            .total()
+    });
+    time("search_by_postings", || {
+        vsm.search_by_postings(
+            &query,
+            0.1,
+            ScoreNormalization::DocA,
+            None,
+            Deadline::none(),
+        )
+        .total()
+    });
+    time("search_by_terms top 30", || {
+        vsm.search_by_terms_limited(
+            &query,
+            0.1,
+            false,
+            ScoreNormalization::DocA,
+            Some(30),
+            Deadline::none(),
+        )
+        .total()
+    });
+    time("search_by_terms common phrase", || {
+        vsm.search_by_terms(&common_query, 0.1, false, ScoreNormalization::DocA)
+            .total()
+    });
+    time("search_by_terms common phrase top 30", || {
+        vsm.search_by_terms_limited(
+            &common_query,
+            0.1,
+            false,
+            ScoreNormalization::DocA,
+            Some(30),
+            Deadline::none(),
+        )
+        .total()
+    });
+    time("search_by_id", || {
+        vsm.search_by_id(1, 0.1, ScoreNormalization::Max, Deadline::none())
+            .total()
+    });
+}
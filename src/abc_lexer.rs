@@ -1,18 +1,19 @@
+//! ABC Lexer
+//! Transform strings of ABC into a sequence of lexed tokens.
+//! This accepts a String and returns newly allocated strings that have an independent lifetime to
+//! the supplied string.
+//! When lex_* and read_* functions return errors, they should leave the context in the most
+//! helpful state so that the next token has a good chance at understanding it.
+//! e.g. don't bomb out half way through the time signature.
+//! lex_functions are relatively context-free and return a top-level token wrapped in a LexResult.
+//! They are called in a context where the token is expected, and raise an error when an unexpected
+//! character was found.
+//! read_functions are helpers, often represent optional branches, and generally return an Option.
+//!  They are called speculatively, and simply return an option.
+//! Context is a lightweight immutable pointer into a char slice. There's heavy (hopefully
+//! sensible) use of shadowing / rebinding of 'ctx' variables, so check the scope!
+
 use music;
-///! ABC Lexer
-///! Transform strings of ABC into a sequence of lexed tokens.
-///! This accepts a String and returns newly allocated strings that have an independent lifetime to
-///! the supplied string.
-///! When lex_* and read_* functions return errors, they should leave the context in the most
-///! helpful state so that the next token has a good chance at understanding it.
-///! e.g. don't bomb out half way through the time signature.
-///! lex_functions are relatively context-free and return a top-level token wrapped in a LexResult.
-///! They are called in a context where the token is expected, and raise an error when an unexpected
-///! character was found.
-///! read_functions are helpers, often represent optional branches, and generally return an Option.
-///!  They are called speculatively, and simply return an option.
-///! Context is a lightweight immutable pointer into a char slice. There's heavy (hopefully
-///! sensible) use of shadowing / rebinding of 'ctx' variables, so check the scope!
 use std::fmt;
 use std::iter::FromIterator;
 
@@ -22,6 +23,9 @@ use std::iter::FromIterator;
 pub enum T {
     CommentLine(String),
 
+    // Stylesheet directive with its name and value, e.g. "%%staffwidth 16cm".
+    Directive(String, String),
+
     Newline,
     BeamBreak,
 
@@ -51,6 +55,13 @@ pub enum T {
     // TODO parse this properly if it's ever needed.
     Tempo(String),
 
+    // Voice field, with the voice's ID and any key=value properties, e.g. "V:T1 clef=treble".
+    // In the header it declares the voice's properties, in the body it switches to that voice.
+    Voice(String, Vec<(String, String)>),
+
+    // Clef given in a key field, e.g. "K:G clef=bass", for the current voice.
+    Clef(music::Clef),
+
     SingleBar,
     DoubleBar,
     OpenRepeat,
@@ -64,7 +75,7 @@ pub enum T {
 
     // Dot between notes.
     // Positive lengthens the note before, negative the note after.
-    // TODO this should be applied to the durations of notes either side. When?
+    // The AST applies this to the durations of the notes either side and drops the token.
     Dottage(i8),
 }
 
@@ -75,6 +86,42 @@ enum TuneSection {
     Body,
 }
 
+/// Spelling of note names in header fields. Some European collections name the key note in their
+/// own language, and the lexer reads it as the standard pitch class instead of an error.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Dialect {
+    /// A to G, as in the ABC standard.
+    Standard,
+
+    /// German names: "H" is B natural, "B" on its own is B flat, and "is" and "es" make sharps
+    /// and flats, e.g. "Fis", "Es", "As".
+    German,
+
+    /// Solfège names, with or without accents, e.g. "Ré", "Sol", "Sib".
+    Solfege,
+}
+
+impl Dialect {
+    /// Dialect from its name, e.g. "german".
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name.trim().to_lowercase().as_ref() {
+            "standard" => Some(Dialect::Standard),
+            "german" => Some(Dialect::German),
+            "solfege" | "solfège" => Some(Dialect::Solfege),
+            _ => None,
+        }
+    }
+
+    /// Name of the dialect, the reverse of from_name().
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dialect::Standard => "standard",
+            Dialect::German => "german",
+            Dialect::Solfege => "solfege",
+        }
+    }
+}
+
 /// Context required to lex an ABC String.
 /// Context object is immutable for simpler state and testing.
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
@@ -90,6 +137,9 @@ pub struct Context<'a> {
     i: usize,
 
     tune_section: TuneSection,
+
+    // How key notes are spelled.
+    dialect: Dialect,
 }
 
 impl<'a> Context<'a> {
@@ -101,9 +151,20 @@ impl<'a> Context<'a> {
             l,
             i: 0,
             tune_section: TuneSection::Header,
+            dialect: Dialect::Standard,
         }
     }
 
+    /// The current offset into the input, in characters.
+    pub fn offset(&self) -> usize {
+        self.i
+    }
+
+    /// Is this still in the tune's header, before the key signature?
+    pub fn is_header(&self) -> bool {
+        self.tune_section == TuneSection::Header
+    }
+
     /// Are there this many characters available?
     fn has(&self, chars: usize) -> bool {
         self.i + chars <= self.l
@@ -142,6 +203,7 @@ impl<'a> Context<'a> {
     }
 
     /// Take the first n characters, if we have them.
+    #[cfg(test)]
     fn take(&self, n: usize) -> Option<(Context<'a>, &'a [char])> {
         if !self.has(n) {
             None
@@ -173,9 +235,9 @@ impl<'a> Context<'a> {
         if self.i + len > self.l {
             (*self, false)
         } else {
-            for i in 0..len {
+            for (c, p) in self.c[self.i..self.i + len].iter().zip(prefix.iter()) {
                 // If there's no match return original context's offset.
-                if self.c[self.i + i].to_uppercase().next() != prefix[i].to_uppercase().next() {
+                if c.to_uppercase().next() != p.to_uppercase().next() {
                     return (*self, false);
                 }
             }
@@ -224,6 +286,40 @@ fn read_until<'a>(
     }
 }
 
+/// Read the value of a field to the end of the line, including any continuation lines.
+/// A line that ends with a backslash continues the field, as does a following line that starts
+/// with "+:". The parts are joined with a space.
+fn read_field_value<'a>(ctx: Context<'a>) -> Result<(Context<'a>, String), Context<'a>> {
+    let (mut ctx, chars) = read_until(ctx, '\n')?;
+    let mut value: String = chars.iter().collect();
+
+    loop {
+        if let Some(line) = value.trim_end().strip_suffix('\\') {
+            value = line.to_string();
+        } else if ctx.rest().starts_with(&['+', ':']) {
+            ctx = ctx.skip(2);
+        } else {
+            return Ok((ctx, value));
+        }
+
+        let (next_ctx, chars) = read_until(ctx, '\n')?;
+        let next: String = chars.iter().collect();
+        value = format!("{} {}", value.trim_end(), next.trim());
+        ctx = next_ctx;
+    }
+}
+
+/// Lex a backslash at the end of a line of music, which continues it on the next line.
+/// The line break is skipped, so it doesn't break the stave or a beam.
+fn lex_continuation(ctx: Context) -> LexResult {
+    let after = ctx.skip(1).skip_whitespace();
+    match after.first() {
+        Some((after, '\n')) => LexResult::ts(after, vec![]),
+        None => LexResult::ts(after, vec![]),
+        Some(_) => LexResult::Error(ctx, ctx.i, LexError::UnexpectedBodyChar('\\')),
+    }
+}
+
 /// Read an unsigned integer up to 99999999.
 /// Supply a role that the number plays for better error messages.
 /// On success return value and context.
@@ -231,7 +327,7 @@ fn read_until<'a>(
 fn read_number<'a>(
     ctx: Context<'a>,
     role: NumberRole,
-) -> Result<(Context<'a>, u32), (Context, usize, LexError)> {
+) -> Result<(Context<'a>, u32), (Context<'a>, usize, LexError)> {
     // We're not going to read anything longer than this.
     // Doing so would be unlikely and overflow a u32.
     const MAX_CHARS: usize = 8;
@@ -300,14 +396,14 @@ fn read_number<'a>(
     if too_long {
         // Set the context to the end of the number, but report error from the start of it.
         let start_of_number = ctx.i;
-        return Err((
+        Err((
             ctx.skip(length),
             start_of_number,
             LexError::NumberTooLong(role),
-        ));
+        ))
     } else if length == 0 {
         Err((
-            ctx.clone().skip(length),
+            ctx.skip(length),
             ctx.i,
             LexError::ExpectedNumber(role),
         ))
@@ -317,7 +413,8 @@ fn read_number<'a>(
 }
 
 /// Lex a default note length, e.g. "1/9"
-fn lex_note_length<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
+fn lex_note_length<'a>(ctx: Context<'a>, delimiter: char) -> LexResult<'a> {
+    let start = ctx.i;
     match read_until(ctx, delimiter) {
         Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::Metre)),
 
@@ -333,14 +430,18 @@ fn lex_note_length<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
                                     LexResult::Error(whole_line_ctx, offset, err)
                                 }
                                 Ok((ctx, denomenator)) => {
-                                    // Skip one character for the delimiter.
-                                    LexResult::t(
-                                        ctx.skip(1),
-                                        T::DefaultNoteLength(music::FractionalDuration(
-                                            numerator,
-                                            denomenator,
-                                        )),
-                                    )
+                                    match music::FractionalDuration::new(numerator, denomenator) {
+                                        // Skip one character for the delimiter.
+                                        Some(duration) => LexResult::t(
+                                            ctx.skip(1),
+                                            T::DefaultNoteLength(duration),
+                                        ),
+                                        None => LexResult::Error(
+                                            whole_line_ctx,
+                                            start,
+                                            LexError::ZeroDenominator,
+                                        ),
+                                    }
                                 }
                             }
                         }
@@ -355,7 +456,7 @@ fn lex_note_length<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
 }
 
 /// Lex a metre declaration, e.g. "2/4" or "C|".
-fn lex_metre<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
+fn lex_metre<'a>(ctx: Context<'a>, delimiter: char) -> LexResult<'a> {
     // Read the whole line. This does two things:
     // 1 - Check that the field is actually delimited.
     // 2 - Provide a slice that we can compare to literal values like "C|".
@@ -370,9 +471,9 @@ fn lex_metre<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
         // Although this context is discareded for parsing, it is used to return errors,
         // as it enables the lexer to continue at the next token.
         Ok((whole_line_ctx, content)) => {
-            if content == &['C'] {
+            if content == ['C'] {
                 LexResult::t(whole_line_ctx, T::Metre(music::Metre(4, 4)))
-            } else if content == &['C', '|'] {
+            } else if content == ['C', '|'] {
                 LexResult::t(whole_line_ctx, T::Metre(music::Metre(2, 4)))
             } else {
                 // It's a numerical metre.
@@ -406,8 +507,213 @@ fn lex_metre<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
     }
 }
 
-/// Lex a key note, e.g. "C", "Bf", "F Flat".
+/// Where an accidental is being read. Key notes and notes in the tune body use different spellings.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+enum AccidentalPosition {
+    KeyNote,
+    Note,
+}
+
+/// All recognised spellings of accidentals, with the position they're valid in.
+/// Longest spellings come first so that e.g. "##" is matched before "#".
+const ACCIDENTALS: &[(&[char], music::Accidental, AccidentalPosition)] = &[
+    (
+        &['n', 'a', 't', 'u', 'r', 'a', 'l'],
+        music::Accidental::Natural,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['s', 'h', 'a', 'r', 'p'],
+        music::Accidental::Sharp,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['f', 'l', 'a', 't'],
+        music::Accidental::Flat,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['f', 'f'],
+        music::Accidental::DoubleFlat,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['b', 'b'],
+        music::Accidental::DoubleFlat,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['♭', '♭'],
+        music::Accidental::DoubleFlat,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['s', 's'],
+        music::Accidental::DoubleSharp,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['#', '#'],
+        music::Accidental::DoubleSharp,
+        AccidentalPosition::KeyNote,
+    ),
+    (
+        &['♯', '♯'],
+        music::Accidental::DoubleSharp,
+        AccidentalPosition::KeyNote,
+    ),
+    (&['f'], music::Accidental::Flat, AccidentalPosition::KeyNote),
+    (&['b'], music::Accidental::Flat, AccidentalPosition::KeyNote),
+    (&['♭'], music::Accidental::Flat, AccidentalPosition::KeyNote),
+    (&['s'], music::Accidental::Sharp, AccidentalPosition::KeyNote),
+    (&['#'], music::Accidental::Sharp, AccidentalPosition::KeyNote),
+    (&['♯'], music::Accidental::Sharp, AccidentalPosition::KeyNote),
+    (&['='], music::Accidental::Natural, AccidentalPosition::KeyNote),
+    (&['♮'], music::Accidental::Natural, AccidentalPosition::KeyNote),
+    (
+        &['^', '^'],
+        music::Accidental::DoubleSharp,
+        AccidentalPosition::Note,
+    ),
+    (
+        &['_', '_'],
+        music::Accidental::DoubleFlat,
+        AccidentalPosition::Note,
+    ),
+    (&['^'], music::Accidental::Sharp, AccidentalPosition::Note),
+    (&['_'], music::Accidental::Flat, AccidentalPosition::Note),
+    (&['='], music::Accidental::Natural, AccidentalPosition::Note),
+];
+
+/// Read an optional accidental using the spellings valid at this position.
+fn read_accidental<'a>(
+    ctx: Context<'a>,
+    position: AccidentalPosition,
+) -> (Context<'a>, Option<music::Accidental>) {
+    for &(spelling, accidental, spelling_position) in ACCIDENTALS.iter() {
+        if spelling_position == position {
+            if let (ctx, true) = ctx.starts_with_insensitive_eager(spelling) {
+                return (ctx, Some(accidental));
+            }
+        }
+    }
+
+    (ctx, None)
+}
+
+/// German suffixes for accidentals, longest first. "s" and "ses" only follow a vowel, as in "As"
+/// and "Es".
+const GERMAN_ACCIDENTALS: &[(&[char], music::Accidental)] = &[
+    (&['i', 's', 'i', 's'], music::Accidental::DoubleSharp),
+    (&['i', 's'], music::Accidental::Sharp),
+    (&['e', 's', 'e', 's'], music::Accidental::DoubleFlat),
+    (&['e', 's'], music::Accidental::Flat),
+    (&['s', 'e', 's'], music::Accidental::DoubleFlat),
+    (&['s'], music::Accidental::Flat),
+];
+
+/// Read a key note with its German name, e.g. "H", "Fis", "Es". Letters that mean the same as in
+/// the standard, without a German suffix, give None so they can be read as usual.
+fn read_german_key_note<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::PitchClass)> {
+    let (ctx, letter) = ctx.first()?;
+    let diatonic = match letter {
+        'A' => music::DiatonicPitchClass::A,
+        'B' | 'H' => music::DiatonicPitchClass::B,
+        'C' => music::DiatonicPitchClass::C,
+        'D' => music::DiatonicPitchClass::D,
+        'E' => music::DiatonicPitchClass::E,
+        'F' => music::DiatonicPitchClass::F,
+        'G' => music::DiatonicPitchClass::G,
+        _ => return None,
+    };
+
+    let pitch_class = |accidental| music::PitchClass {
+        diatonic_pitch_class: diatonic,
+        accidental,
+    };
+
+    // B is already flat, so it doesn't take a suffix.
+    if letter != 'B' {
+        for &(suffix, accidental) in GERMAN_ACCIDENTALS.iter() {
+            if suffix[0] == 's' && letter != 'A' && letter != 'E' {
+                continue;
+            }
+            if let (ctx, true) = ctx.starts_with_insensitive_eager(suffix) {
+                return Some((ctx, pitch_class(Some(accidental))));
+            }
+        }
+    }
+
+    match letter {
+        'H' => {
+            let (ctx, accidental) = read_accidental(ctx, AccidentalPosition::KeyNote);
+            Some((ctx, pitch_class(accidental)))
+        }
+
+        // "B" with an accidental is probably meant as in the standard.
+        'B' => match read_accidental(ctx, AccidentalPosition::KeyNote) {
+            (_, Some(_)) => None,
+            (ctx, None) => Some((ctx, pitch_class(Some(music::Accidental::Flat)))),
+        },
+        _ => None,
+    }
+}
+
+/// Solfège names, longest first so that "sol" isn't read as "so".
+const SOLFEGE: &[(&str, music::DiatonicPitchClass)] = &[
+    ("sol", music::DiatonicPitchClass::G),
+    ("do", music::DiatonicPitchClass::C),
+    ("ut", music::DiatonicPitchClass::C),
+    ("re", music::DiatonicPitchClass::D),
+    ("mi", music::DiatonicPitchClass::E),
+    ("fa", music::DiatonicPitchClass::F),
+    ("so", music::DiatonicPitchClass::G),
+    ("la", music::DiatonicPitchClass::A),
+    ("si", music::DiatonicPitchClass::B),
+    ("ti", music::DiatonicPitchClass::B),
+];
+
+/// Read a key note with its solfège name, e.g. "Ré", "Sol#", "Sib". Anything else gives None so
+/// it can be read as a letter.
+fn read_solfege_key_note<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::PitchClass)> {
+    // Accents don't change the note.
+    let plain = ctx
+        .rest()
+        .iter()
+        .take(3)
+        .flat_map(|c| c.to_lowercase())
+        .map(|c| match c {
+            'á' | 'à' => 'a',
+            'é' | 'è' => 'e',
+            'í' | 'ì' => 'i',
+            'ó' | 'ò' => 'o',
+            'ú' | 'ù' => 'u',
+            c => c,
+        }).collect::<String>();
+
+    let &(name, diatonic) = SOLFEGE.iter().find(|(name, _)| plain.starts_with(name))?;
+    let (ctx, accidental) = read_accidental(ctx.skip(name.len()), AccidentalPosition::KeyNote);
+
+    Some((
+        ctx,
+        music::PitchClass {
+            diatonic_pitch_class: diatonic,
+            accidental,
+        },
+    ))
+}
+
+/// Lex a key note, e.g. "C", "Bf", "F Flat", or in the context's dialect.
 fn read_key_note<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::PitchClass)> {
+    let dialect_key_note = match ctx.dialect {
+        Dialect::Standard => None,
+        Dialect::German => read_german_key_note(ctx),
+        Dialect::Solfege => read_solfege_key_note(ctx),
+    };
+    if dialect_key_note.is_some() {
+        return dialect_key_note;
+    }
+
     let (ctx, diatonic) = match ctx.first() {
         Some((ctx, 'A')) => (ctx, Some(music::DiatonicPitchClass::A)),
         Some((ctx, 'B')) => (ctx, Some(music::DiatonicPitchClass::B)),
@@ -426,62 +732,18 @@ fn read_key_note<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::PitchClass
         None => (ctx, None),
 
         // If there was a key note, try and read an accidental.
-        // Read longest ones first.
-        Some(_) => {
-            if let (ctx, true) = ctx.starts_with_insensitive_eager(&['f', 'l', 'a', 't']) {
-                (ctx, Some(music::Accidental::Flat))
-            } else if let (ctx, true) =
-                ctx.starts_with_insensitive_eager(&['s', 'h', 'a', 'r', 'p'])
-            {
-                (ctx, Some(music::Accidental::Sharp))
-            } else if let (ctx, true) =
-                ctx.starts_with_insensitive_eager(&['n', 'a', 't', 'u', 'r', 'a', 'l'])
-            {
-                (ctx, Some(music::Accidental::Natural))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['f', 'f']) {
-                (ctx, Some(music::Accidental::DoubleFlat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['s', 's']) {
-                (ctx, Some(music::Accidental::DoubleFlat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['b', 'b']) {
-                (ctx, Some(music::Accidental::DoubleSharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['#', '#']) {
-                (ctx, Some(music::Accidental::DoubleSharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['♯', '♯']) {
-                (ctx, Some(music::Accidental::DoubleSharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['♭', '♭']) {
-                (ctx, Some(music::Accidental::DoubleFlat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['f']) {
-                (ctx, Some(music::Accidental::Flat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['s']) {
-                (ctx, Some(music::Accidental::Sharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['b']) {
-                (ctx, Some(music::Accidental::Flat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['#']) {
-                (ctx, Some(music::Accidental::Sharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['♯']) {
-                (ctx, Some(music::Accidental::Sharp))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['♭']) {
-                (ctx, Some(music::Accidental::Flat))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['=']) {
-                (ctx, Some(music::Accidental::Natural))
-            } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['♮']) {
-                (ctx, Some(music::Accidental::Natural))
-            } else {
-                (ctx, None)
-            }
-        }
+        Some(_) => read_accidental(ctx, AccidentalPosition::KeyNote),
     };
 
-    match diatonic {
-        Some(diatonic) => Some((
+    diatonic.map(|diatonic| {
+        (
             ctx,
             music::PitchClass {
                 diatonic_pitch_class: diatonic,
-                accidental: accidental,
+                accidental,
             },
-        )),
-        _ => None,
-    }
+        )
+    })
 }
 
 /// Read a musical mode.
@@ -539,7 +801,12 @@ pub fn read_mode<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::Mode)> {
 }
 
 /// Read a fractional duration. This can be notated as zero characters.
-fn read_fractional_duration<'a>(ctx: Context<'a>) -> (Context, music::FractionalDuration) {
+/// On failure return context at the end of the duration, error offset, and error.
+fn read_fractional_duration<'a>(
+    ctx: Context<'a>,
+) -> Result<(Context<'a>, music::FractionalDuration), (Context<'a>, usize, LexError)> {
+    let start = ctx.i;
+
     // Get a number, if present.
     let (ctx, numerator) = match read_number(ctx, NumberRole::NoteDurationNumerator) {
         Ok((ctx, val)) => (ctx, Some(val)),
@@ -586,25 +853,39 @@ fn read_fractional_duration<'a>(ctx: Context<'a>) -> (Context, music::Fractional
         _ => (1, 1),
     };
 
-    (ctx, music::FractionalDuration(numerator, denomenator))
+    match music::FractionalDuration::new(numerator, denomenator) {
+        Some(duration) => Ok((ctx, duration)),
+
+        // e.g. "C1/0".
+        None => Err((ctx, start, LexError::ZeroDenominator)),
+    }
 }
 
-fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
+fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult<'a> {
     match read_until(ctx, delimiter) {
         Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::KeySignature)),
 
         // Although this context is discareded for parsing, it is used to return errors,
         // as it enables the lexer to continue at the next token.
-        Ok((whole_line_ctx, _)) => {
+        Ok((whole_line_ctx, chars)) => {
+            let start = ctx.i;
+
             if let Some((ctx, key_note)) = read_key_note(ctx) {
                 // TODO: Assuming empty means 'major'. Is this correct for at the lexer?
                 // Or maybe the AST-level representation should handle the behaviour.
-                let (_, mode) = read_mode(ctx).unwrap_or((ctx, music::Mode::Major));
+                let (ctx, mode) = read_mode(ctx).unwrap_or((ctx, music::Mode::Major));
 
                 // TODO extras like specific accidentals?
 
                 // Skip to end of delimited sequence (line or bracket).
-                LexResult::t(whole_line_ctx, T::KeySignature(key_note, mode))
+                let key_signature = T::KeySignature(key_note, mode);
+
+                // A clef can follow the key, e.g. "K:G clef=bass".
+                let rest = &chars[(ctx.i - start).min(chars.len())..];
+                match key_clef(rest) {
+                    Some(clef) => LexResult::tt(whole_line_ctx, key_signature, T::Clef(clef)),
+                    None => LexResult::t(whole_line_ctx, key_signature),
+                }
             } else {
                 // TODO: There may be an alternative to a key-note. May need to amend this when
                 // fuzzing with real-world inputs.
@@ -614,7 +895,79 @@ fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
     }
 }
 
-fn lex_guitar_chord<'a>(ctx: Context<'a>) -> LexResult {
+/// The clef in the rest of a key field, given as "clef=bass" or just "bass". The last one wins.
+fn key_clef(chars: &[char]) -> Option<music::Clef> {
+    split_words(chars).into_iter().rev().find_map(|word| {
+        let (key, value) = parse_property(word);
+        match key.as_ref() {
+            "clef" => music::Clef::from_name(&value),
+            _ if value.is_empty() => music::Clef::from_name(&key),
+            _ => None,
+        }
+    })
+}
+
+/// Split a field into words on whitespace. Quoted words may contain spaces.
+fn split_words(chars: &[char]) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+
+    for c in chars.iter() {
+        match *c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(word);
+                    word = String::new();
+                }
+            }
+            c => word.push(c),
+        }
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// A `key=value` property. A bare word has an empty value.
+fn parse_property(word: String) -> (String, String) {
+    match word.find('=') {
+        Some(i) => (word[..i].to_lowercase(), word[i + 1..].to_string()),
+        None => (word, String::new()),
+    }
+}
+
+/// Split a voice field into its ID and properties.
+/// Properties are `key=value` pairs, where the value may be quoted. A bare word has an empty value.
+fn parse_voice(chars: &[char]) -> (String, Vec<(String, String)>) {
+    let mut words = split_words(chars).into_iter();
+    let id = words.next().unwrap_or_default();
+    let properties = words.map(parse_property).collect();
+
+    (id, properties)
+}
+
+/// Lex a voice field, e.g. "T1 name="Tenor" clef=treble".
+fn lex_voice<'a>(ctx: Context<'a>, delimiter: char) -> LexResult<'a> {
+    match read_until(ctx, delimiter) {
+        Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::ExpectedDelimiter(delimiter)),
+        Ok((line_ctx, chars)) => {
+            let (id, properties) = parse_voice(chars);
+
+            if id.is_empty() {
+                LexResult::Error(line_ctx, ctx.i, LexError::ExpectedVoiceId)
+            } else {
+                LexResult::t(line_ctx, T::Voice(id, properties))
+            }
+        }
+    }
+}
+
+fn lex_guitar_chord<'a>(ctx: Context<'a>) -> LexResult<'a> {
     match read_until(ctx, '"') {
         Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::GuitarChord)),
         Ok((ctx, content)) => LexResult::t(ctx, T::GuitarChord(String::from_iter(content.iter()))),
@@ -633,7 +986,7 @@ fn read_n_time<'a>(ctx: Context<'a>) -> (Context<'a>, Option<u32>) {
 
 /// Lex a barline, when it is expected.
 /// TODO all tests for this!
-fn lex_barline<'a>(ctx: Context<'a>) -> LexResult {
+fn lex_barline<'a>(ctx: Context<'a>) -> LexResult<'a> {
     // Every barline includes some kind of beam break.
 
     if let (ctx, true) = ctx.starts_with_insensitive_eager(&[':', '|', ':']) {
@@ -707,23 +1060,15 @@ fn lex_barline<'a>(ctx: Context<'a>) -> LexResult {
     }
 }
 
-fn lex_note<'a>(ctx: Context<'a>) -> LexResult {
+// Notes can be at most this many octaves from middle C. Nothing can play notes further out, and
+// their MIDI pitches would be out of range.
+const MAX_OCTAVE: i16 = 10;
+
+fn lex_note<'a>(ctx: Context<'a>) -> LexResult<'a> {
+    let start = ctx.i;
+
     // Optional accidental.
-    let (ctx, accidental) = if let (ctx, true) = ctx.starts_with_insensitive_eager(&['^', '^']) {
-        (ctx, Some(music::Accidental::DoubleSharp))
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['^', '^']) {
-        (ctx, Some(music::Accidental::DoubleSharp))
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['_', '_']) {
-        (ctx, Some(music::Accidental::DoubleFlat))
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['^']) {
-        (ctx, Some(music::Accidental::Sharp))
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['_']) {
-        (ctx, Some(music::Accidental::Flat))
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['=']) {
-        (ctx, Some(music::Accidental::Natural))
-    } else {
-        (ctx, None)
-    };
+    let (ctx, accidental) = read_accidental(ctx, AccidentalPosition::Note);
 
     let (ctx, diatonic, octave) = match ctx.first() {
         Some((ctx, 'A')) => (ctx, Some(music::DiatonicPitchClass::A), 0),
@@ -744,25 +1089,33 @@ fn lex_note<'a>(ctx: Context<'a>) -> LexResult {
         _ => (ctx, None, 0),
     };
 
-    // Optional octave modifier.
-    let (ctx, octave) = if let (ctx, true) = ctx.starts_with_insensitive_eager(&[',', ',', ',']) {
-        (ctx, octave - 3)
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&[',', ',']) {
-        (ctx, octave - 2)
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&[',']) {
-        (ctx, octave - 1)
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['\'', '\'', '\'']) {
-        (ctx, octave + 3)
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['\'', '\'']) {
-        (ctx, octave + 2)
-    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['\'']) {
-        (ctx, octave + 1)
-    } else {
-        (ctx, octave)
-    };
+    // Optional octave modifiers. Each comma is an octave down and each apostrophe an octave up,
+    // and there can be any number of them, e.g. `C,,,,` or `c''`.
+    let mut ctx = ctx;
+    let mut octave: i16 = octave;
+    loop {
+        match ctx.first() {
+            Some((next, ',')) => {
+                ctx = next;
+                octave = octave.saturating_sub(1);
+            }
+            Some((next, '\'')) => {
+                ctx = next;
+                octave = octave.saturating_add(1);
+            }
+            _ => break,
+        }
+    }
 
     // Duration has a few different representations, including zero characters.
-    let (ctx, duration) = read_fractional_duration(ctx);
+    let (ctx, duration) = match read_fractional_duration(ctx) {
+        Ok(result) => result,
+        Err((ctx, offset, err)) => return LexResult::Error(ctx, offset, err),
+    };
+
+    if octave.abs() > MAX_OCTAVE {
+        return LexResult::Error(ctx, start, LexError::TooManyOctaveMarks);
+    }
 
     if let Some(diatonic) = diatonic {
         LexResult::t(
@@ -771,9 +1124,9 @@ fn lex_note<'a>(ctx: Context<'a>) -> LexResult {
                 music::Pitch {
                     pitch_class: music::PitchClass {
                         diatonic_pitch_class: diatonic,
-                        accidental: accidental,
+                        accidental,
                     },
-                    octave: octave,
+                    octave,
                 },
                 duration,
             )),
@@ -783,7 +1136,7 @@ fn lex_note<'a>(ctx: Context<'a>) -> LexResult {
     }
 }
 
-fn lex_rest<'a>(ctx: Context<'a>) -> LexResult {
+fn lex_rest<'a>(ctx: Context<'a>) -> LexResult<'a> {
     let (ctx, _visible) = match ctx.first() {
         Some((ctx, 'z')) | Some((ctx, 'Z')) => (ctx, true),
         Some((ctx, 'x')) | Some((ctx, 'X')) => (ctx, false),
@@ -791,14 +1144,16 @@ fn lex_rest<'a>(ctx: Context<'a>) -> LexResult {
     };
 
     // Duration has a few different representations, including zero characters.
-    let (ctx, duration) = read_fractional_duration(ctx);
-    LexResult::t(ctx, T::Rest(duration))
+    match read_fractional_duration(ctx) {
+        Ok((ctx, duration)) => LexResult::t(ctx, T::Rest(duration)),
+        Err((ctx, offset, err)) => LexResult::Error(ctx, offset, err),
+    }
 }
 
 /// Lex a tune dotting.
 /// This should be applied to notes' duration, though I'm not quite sure when...
 /// Is it a lex error if it doesn't occur between two notes? Or is it up to the AST builder?
-fn lex_dottage<'a>(ctx: Context<'a>) -> LexResult {
+fn lex_dottage<'a>(ctx: Context<'a>) -> LexResult<'a> {
     // Every barline includes some kind of beam break.
 
     if let (ctx, true) = ctx.starts_with_insensitive_eager(&['>', '>', '>', '>']) {
@@ -897,11 +1252,20 @@ pub enum LexError {
     ExpectedSlashInNoteLength,
 
     UnrecognisedDots,
+
+    /// A note length with a zero denominator, e.g. "C1/0" or "L:1/0".
+    ZeroDenominator,
+
+    /// A voice field without an ID, e.g. "V:".
+    ExpectedVoiceId,
+
+    /// A note too many octaves from middle C, e.g. "C,,,,,,,,,,,".
+    TooManyOctaveMarks,
 }
 
 /// Indent and print a line to a string buffer.
 /// This is used for all subsequent lines in an error message (the first is already indented).
-fn indent_and_append_line(indent: usize, buf: &mut String, string: &String) {
+fn indent_and_append_line(indent: usize, buf: &mut String, string: &str) {
     for _ in 0..indent {
         buf.push(' ');
     }
@@ -917,6 +1281,16 @@ fn indent_and_append_lines(indent: usize, buf: &mut String, lines: &[&String]) {
 }
 
 impl LexError {
+    /// Name of the kind of error, e.g. "UnexpectedBodyChar", without its detail.
+    /// These are stable, so tools can match on them.
+    pub fn kind(&self) -> String {
+        let debug = format!("{:?}", self);
+        match debug.find('(') {
+            Some(i) => debug[..i].to_string(),
+            None => debug,
+        }
+    }
+
     /// Format the error to the string buffer.
     /// If more than one line is used, indent by this much.
     /// Don't append a newline.
@@ -970,49 +1344,47 @@ impl LexError {
                     ],
                 );
             }
-            &LexError::ExpectedNumber(ref number_role) => {
+            LexError::ExpectedNumber(number_role) => {
                 buf.push_str("I expected to find a number here.\n");
-                match number_role {
-                    &NumberRole::UpperTimeSignature => indent_and_append_line(
+                match *number_role {
+                    NumberRole::UpperTimeSignature => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected the first / upper part of a time signature.".to_string(),
+                        "I expected the first / upper part of a time signature.",
                     ),
-                    &NumberRole::LowerTimeSignature => indent_and_append_line(
+                    NumberRole::LowerTimeSignature => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected the second / lower part of a time signature.".to_string(),
+                        "I expected the second / lower part of a time signature.",
                     ),
 
                     // NoteDurationNumerator and NoteDurationDenomenator shouldn't ever actually
                     // occur as they are read in an optional context, but if they do, be polite.
-                    &NumberRole::NoteDurationNumerator => indent_and_append_line(
+                    NumberRole::NoteDurationNumerator => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected to find a number for a note length.".to_string(),
+                        "I expected to find a number for a note length.",
                     ),
 
-                    &NumberRole::NoteDurationDenomenator => indent_and_append_line(
+                    NumberRole::NoteDurationDenomenator => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected to find a number for a note length.".to_string(),
+                        "I expected to find a number for a note length.",
                     ),
-                    &NumberRole::UpperDefaultNoteLength => indent_and_append_line(
+                    NumberRole::UpperDefaultNoteLength => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected to find the first / upper part of a default note length."
-                            .to_string(),
+                        "I expected to find the first / upper part of a default note length.",
                     ),
-                    &NumberRole::LowerDefaultNoteLength => indent_and_append_line(
+                    NumberRole::LowerDefaultNoteLength => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected to find the second / lower part of a default note length."
-                            .to_string(),
+                        "I expected to find the second / lower part of a default note length.",
                     ),
-                    &NumberRole::NTimeBar => indent_and_append_line(
+                    NumberRole::NTimeBar => indent_and_append_line(
                         indent,
                         buf,
-                        &"I expected to find a n-time repeat bar.".to_string(),
+                        "I expected to find a n-time repeat bar.",
                     ),
                 }
             }
@@ -1022,33 +1394,33 @@ impl LexError {
             &LexError::NumberTooLong(_) => {
                 buf.push_str("This number is longer than I expected.");
             }
-            &LexError::PrematureEnd(ref during) => {
+            LexError::PrematureEnd(during) => {
                 buf.push_str("I've got to the end of the ABC input before I'm ready.\n");
-                match during {
-                    &During::Metre => indent_and_append_line(
+                match *during {
+                    During::Metre => indent_and_append_line(
                         indent,
                         buf,
-                        &"I was in the middle of reading a time signature".to_string(),
+                        "I was in the middle of reading a time signature",
                     ),
-                    &During::Header => indent_and_append_line(
+                    During::Header => indent_and_append_line(
                         indent,
                         buf,
-                        &"I was in the middle of reading a header field.".to_string(),
+                        "I was in the middle of reading a header field.",
                     ),
-                    &During::KeySignature => indent_and_append_line(
+                    During::KeySignature => indent_and_append_line(
                         indent,
                         buf,
-                        &"I was in the middle of reading a key signature.".to_string(),
+                        "I was in the middle of reading a key signature.",
                     ),
-                    &During::DefaultNoteLenth => indent_and_append_line(
+                    During::DefaultNoteLenth => indent_and_append_line(
                         indent,
                         buf,
-                        &"I was in the middle of reading a default note length.".to_string(),
+                        "I was in the middle of reading a default note length.",
                     ),
-                    &During::GuitarChord => indent_and_append_line(
+                    During::GuitarChord => indent_and_append_line(
                         indent,
                         buf,
-                        &"I was in the middle of reading a chord.".to_string(),
+                        "I was in the middle of reading a chord.",
                     ),
                 }
             }
@@ -1089,6 +1461,18 @@ impl LexError {
             &LexError::UnrecognisedDots => {
                 buf.push_str("I didn't understand how to read these note duration dots.");
             }
+
+            &LexError::ZeroDenominator => {
+                buf.push_str("A note length can't be divided by zero.");
+            }
+
+            &LexError::ExpectedVoiceId => {
+                buf.push_str("I expected to find a name or number for this voice.");
+            }
+
+            &LexError::TooManyOctaveMarks => {
+                buf.push_str("This note is too many octaves away from middle C.");
+            }
         }
     }
 }
@@ -1135,6 +1519,39 @@ impl<'a> LexResult<'a> {
     }
 }
 
+/// A line that started with '%', without it. A second '%' makes it a directive, e.g. "%scale 0.8".
+fn comment_or_directive(value: String) -> T {
+    if let Some(rest) = value.strip_prefix('%') {
+        let mut parts = rest.trim().splitn(2, char::is_whitespace);
+        if let Some(name) = parts.next().filter(|x| !x.is_empty()) {
+            let value = parts.next().unwrap_or("").trim();
+            return T::Directive(name.to_string(), value.to_string());
+        }
+    }
+
+    T::CommentLine(value)
+}
+
+/// Lex a comment in the tune body, which runs to the end of the line.
+/// A comment after some music leaves the newline to be read, as it still breaks the line of music.
+/// A comment on a line of its own doesn't, and can be a directive.
+fn lex_comment(ctx: Context, line_start: bool) -> LexResult {
+    let ctx = ctx.skip(1);
+    let length = ctx.c[ctx.i..]
+        .iter()
+        .position(|c| *c == '\n')
+        .unwrap_or(ctx.l - ctx.i);
+    let value: String = ctx.c[ctx.i..ctx.i + length].iter().collect();
+    let ctx = ctx.skip(length);
+
+    if line_start {
+        let newline = if ctx.i < ctx.l { 1 } else { 0 };
+        LexResult::t(ctx.skip(newline), comment_or_directive(value))
+    } else {
+        LexResult::t(ctx, T::CommentLine(value))
+    }
+}
+
 /// Try to read a single T and return a new context.
 /// Note that there's a lot of aliasing of ctx in nested matches.
 fn read(ctx: Context) -> LexResult {
@@ -1151,7 +1568,7 @@ fn read(ctx: Context) -> LexResult {
                         '%' => match read_until(ctx, '\n') {
                             Ok((ctx, chars)) => {
                                 let value: String = chars.iter().collect();
-                                LexResult::t(ctx, T::CommentLine(value))
+                                LexResult::t(ctx, comment_or_directive(value))
                             }
                             Err(ctx) => {
                                 LexResult::Error(ctx, ctx.i, LexError::ExpectedDelimiter('\n'))
@@ -1163,10 +1580,8 @@ fn read(ctx: Context) -> LexResult {
                         | 'T' | 'W' | 'X' | 'Z' => {
                             match ctx.first() {
                                 Some((ctx, ':')) => {
-                                    match read_until(ctx, '\n') {
-                                        Ok((ctx, chars)) => {
-                                            let value: String = chars.iter().collect();
-
+                                    match read_field_value(ctx) {
+                                        Ok((ctx, value)) => {
                                             // Strip whitespace including leading space and trailing
                                             // newline
                                             let value = value.trim().to_string();
@@ -1220,6 +1635,15 @@ fn read(ctx: Context) -> LexResult {
                             }
                         }
 
+                        // Voice properties.
+                        'V' => match ctx.first() {
+                            Some((ctx, ':')) => lex_voice(ctx.skip_whitespace(), '\n'),
+                            Some((ctx, _)) => LexResult::Error(ctx, ctx.i, LexError::ExpectedColon),
+                            None => {
+                                LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::Header))
+                            }
+                        },
+
                         // Non-text headers.
                         // Grouped for handling code.
                         'K' | 'L' | 'M' | 'P' | 'Q' => {
@@ -1234,18 +1658,18 @@ fn read(ctx: Context) -> LexResult {
                                             // failed to parse.
                                             let ctx = ctx.in_body();
 
-                                            return lex_key_signature(ctx, '\n');
+                                            lex_key_signature(ctx, '\n')
                                         }
 
                                         // Default note length.
-                                        'L' => return lex_note_length(ctx, '\n'),
+                                        'L' => lex_note_length(ctx, '\n'),
 
                                         // Metre.
-                                        'M' => return lex_metre(ctx, '\n'),
+                                        'M' => lex_metre(ctx, '\n'),
 
                                         // Parts.
                                         'P' => {
-                                            return LexResult::Error(
+                                            LexResult::Error(
                                                 ctx,
                                                 ctx.i,
                                                 LexError::UnimplementedError(3),
@@ -1267,7 +1691,7 @@ fn read(ctx: Context) -> LexResult {
 
                                         // This can only happen if the above cases get out of sync.
                                         _ => {
-                                            return LexResult::Error(
+                                            LexResult::Error(
                                                 ctx,
                                                 ctx.i,
                                                 LexError::ExpectedFieldType(first_char),
@@ -1296,6 +1720,15 @@ fn read(ctx: Context) -> LexResult {
                 }
 
                 TuneSection::Body => {
+                    // A voice switch or key change is a field on a line of its own.
+                    let line_start = ctx.i == 0 || ctx.c[ctx.i - 1] == '\n';
+                    if line_start && first_char == 'V' && ctx.c.get(ctx.i + 1) == Some(&':') {
+                        return lex_voice(ctx.skip(2).skip_whitespace(), '\n');
+                    }
+                    if line_start && first_char == 'K' && ctx.c.get(ctx.i + 1) == Some(&':') {
+                        return lex_key_signature(ctx.skip(2).skip_whitespace(), '\n');
+                    }
+
                     match first_char {
                         ' ' => LexResult::t(ctx.skip(1), T::BeamBreak),
                         '\n' => LexResult::t(ctx.skip(1), T::Newline),
@@ -1311,6 +1744,10 @@ fn read(ctx: Context) -> LexResult {
 
                         '>' | '<' => lex_dottage(ctx),
 
+                        '%' => lex_comment(ctx, line_start),
+
+                        '\\' => lex_continuation(ctx),
+
                         // TODO all tune body entities.
                         _ => LexResult::Error(ctx, ctx.i, LexError::UnexpectedBodyChar(first_char)),
                     }
@@ -1332,7 +1769,7 @@ pub struct Lexer<'a> {
 
 impl<'a> Lexer<'a> {
     pub fn new(content: &'a [char]) -> Lexer<'a> {
-        let context = Context::new(&content);
+        let context = Context::new(content);
 
         Lexer {
             context,
@@ -1340,6 +1777,12 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Read key notes in the given dialect.
+    pub fn with_dialect(mut self, dialect: Dialect) -> Lexer<'a> {
+        self.context.dialect = dialect;
+        self
+    }
+
     // Skip into the body. For testing only.
     #[cfg(test)]
     fn in_body(mut self) -> Lexer<'a> {
@@ -1353,7 +1796,7 @@ impl<'a> Lexer<'a> {
             LexResult::T(_, tokens) => Some(tokens),
             LexResult::Error(_, _, _) => None,
             LexResult::Terminal => None,
-        }).flat_map(|x| x)
+        }).flatten()
         .collect::<Vec<T>>()
     }
 
@@ -1377,6 +1820,15 @@ impl<'a> Iterator for Lexer<'a> {
             | Some(LexError::ExpectedNumber(NumberRole::LowerTimeSignature))
             | Some(LexError::ExpectedNumber(NumberRole::UpperTimeSignature)) => 0,
 
+            // A zero denominator leaves the context after the duration, ready for the next token.
+            Some(LexError::ZeroDenominator) => 0,
+
+            // A voice without an ID leaves the context at the start of the next line.
+            Some(LexError::ExpectedVoiceId) => 0,
+
+            // A note with too many octave marks leaves it after the note.
+            Some(LexError::TooManyOctaveMarks) => 0,
+
             // If there was an error that we haven't deliberately discounted,
             // increment by one to try and recover.
             Some(_) => 1,
@@ -1385,12 +1837,12 @@ impl<'a> Iterator for Lexer<'a> {
             _ => 0,
         };
 
-        self.context = self.context.clone().skip(skip_amount);
+        self.context = self.context.skip(skip_amount);
         self.error = None;
 
         // Take a temporary clone of self.context so it can be consumed.
         // TODO could read() work with a ref?
-        let result = read(self.context.clone());
+        let result = read(self.context);
 
         match result {
             // Stop iteration when we reach the terminal.
@@ -1398,20 +1850,68 @@ impl<'a> Iterator for Lexer<'a> {
 
             // If it's an error, return it and set the flag.
             LexResult::Error(context, offset, error) => {
-                self.context = context.clone();
+                self.context = context;
                 self.error = Some(error.clone());
                 Some(LexResult::Error(context, offset, error))
             }
 
             // Otherwise it's a token.
             LexResult::T(context, tokens) => {
-                self.context = context.clone();
+                self.context = context;
                 Some(LexResult::T(context, tokens))
             }
         }
     }
 }
 
+/// Character ranges (start, end) of each note in the input, in order.
+pub fn note_ranges(input: &[char]) -> Vec<(usize, usize)> {
+    voice_note_ranges(input)
+        .into_iter()
+        .map(|(_, start, end)| (start, end))
+        .collect()
+}
+
+/// As `note_ranges`, with the ID of the voice each note is in, as the AST assigns them.
+/// Music before the first voice switch belongs to the first voice declared in the header.
+pub fn voice_note_ranges(input: &[char]) -> Vec<(String, usize, usize)> {
+    let mut result = vec![];
+
+    let mut first_declared: Option<String> = None;
+    let mut current_voice: Option<String> = None;
+
+    // Notes start where the previous token ended.
+    let mut previous_end = 0;
+    for lex_result in Lexer::new(input) {
+        match lex_result {
+            LexResult::T(ctx, tokens) => {
+                for token in tokens.iter() {
+                    match token {
+                        T::Voice(id, _) if current_voice.is_none() => {
+                            first_declared.get_or_insert(id.clone());
+                        }
+                        T::Voice(id, _) => current_voice = Some(id.clone()),
+                        T::KeySignature(_, _) if current_voice.is_none() => {
+                            current_voice = Some(first_declared.clone().unwrap_or("1".to_string()));
+                        }
+                        T::Note(_) => result.push((
+                            current_voice.clone().unwrap_or("1".to_string()),
+                            previous_end,
+                            ctx.i,
+                        )),
+                        _ => (),
+                    }
+                }
+                previous_end = ctx.i;
+            }
+            LexResult::Error(ctx, _, _) => previous_end = ctx.i,
+            LexResult::Terminal => (),
+        }
+    }
+
+    result
+}
+
 /// Parse an ABC input, return nicely formatted error message and number of lex errors.
 pub fn format_error_message<'a>(
     input: &[char],
@@ -1444,12 +1944,10 @@ pub fn format_error_message<'a>(
     // Indent the first line.
     buf.push_str(ABC_PREFIX);
     let mut first = true;
-    for i in 0..input.len() {
+    for (i, &c) in input.iter().enumerate() {
         // Deal both with empty strings and non-empty ones.
         let last_char = i + 1 >= length;
 
-        let c = input[i];
-
         buf.push(c);
 
         // If it's a newline.
@@ -1529,7 +2027,7 @@ pub fn format_error_message<'a>(
                                     });
 
                                     if error_char == error_line {
-                                        buf.push_str(&" ");
+                                        buf.push(' ');
                                         indent += 2;
                                         error.format(indent, &mut buf);
 
@@ -1555,13 +2053,52 @@ pub fn format_error_message<'a>(
     (all_errors.len(), num_unshown, buf)
 }
 
+/// Rewrite key notes spelled in a dialect with the standard spelling, e.g. "K:Fis" in German as
+/// "K:F#", leaving the rest of the ABC as it was.
+pub fn to_standard(content: &str, dialect: Dialect) -> String {
+    if dialect == Dialect::Standard {
+        return content.to_string();
+    }
+
+    content
+        .split('\n')
+        .map(|line| {
+            if !line.starts_with("K:") {
+                return line.to_string();
+            }
+
+            let chars = line.chars().collect::<Vec<char>>();
+            let ctx = Context::new(&chars).skip(2).skip_whitespace();
+            let ctx = Context { dialect, ..ctx };
+
+            match read_key_note(ctx) {
+                Some((end, pitch_class)) => format!(
+                    "{}{}{}",
+                    String::from_iter(&chars[..ctx.i]),
+                    pitch_class.to_abc(),
+                    String::from_iter(&chars[end.i..])
+                ),
+                None => line.to_string(),
+            }
+        }).collect::<Vec<String>>()
+        .join("\n")
+}
+
 /// Parse an ABC input, return nicely formatted error message and number of lex errors.
 pub fn format_error_message_from_abc(input: &[char]) -> (usize, u32, String) {
-    let all_errors = Lexer::new(&input).collect_errors();
-    format_error_message(&input, all_errors)
+    format_error_message_in_dialect(input, Dialect::Standard)
+}
+
+/// As `format_error_message_from_abc`, with key notes read in the dialect. Errors are shown where
+/// they are in the ABC as it's written.
+pub fn format_error_message_in_dialect(input: &[char], dialect: Dialect) -> (usize, u32, String) {
+    let all_errors = Lexer::new(input).with_dialect(dialect).collect_errors();
+    format_error_message(input, all_errors)
 }
 
+// These tests fail a match arm with `assert!(false)`.
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
 
@@ -1584,6 +2121,41 @@ B2EG2EF3|B2EG2E FED|B2EG2EF3|B2dd2B AFD:|
 B2=ce2fg3|B2d g2e dBA|B2=ce2fg2a|b2ag2e dBA:|
 B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
 
+    #[test]
+    fn note_ranges_test() {
+        let input = string_to_vec("X:1\nK:G\nG2 ^A,/|c\n".to_string());
+        let ranges = note_ranges(&input);
+        assert_eq!(ranges, vec![(8, 10), (11, 15), (16, 17)]);
+
+        let notes: Vec<String> = ranges
+            .iter()
+            .map(|(start, end)| input[*start..*end].iter().collect())
+            .collect();
+        assert_eq!(notes, vec!["G2", "^A,/", "c"]);
+    }
+
+    #[test]
+    fn voice_note_ranges_test() {
+        let input = string_to_vec("X:1\nV:T\nV:B\nK:G\nGA|\nV:B\nB|\nV:T\nc|\n".to_string());
+        let voices: Vec<(String, usize)> = voice_note_ranges(&input)
+            .into_iter()
+            .map(|(voice, start, _)| (voice, start))
+            .collect();
+        assert_eq!(
+            voices,
+            vec![
+                ("T".to_string(), 16),
+                ("T".to_string(), 17),
+                ("B".to_string(), 24),
+                ("T".to_string(), 31),
+            ],
+            "Music before the first switch is in the first voice declared."
+        );
+
+        let input = string_to_vec("X:1\nK:G\nG|\n".to_string());
+        assert_eq!(voice_note_ranges(&input), vec![("1".to_string(), 8, 9)]);
+    }
+
     #[test]
     fn context_has() {
         //
@@ -1594,9 +2166,8 @@ B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
         let empty_context = Context::new(&empty);
         let some_context = Context::new(&some);
 
-        assert_eq!(
+        assert!(
             empty_context.has(0),
-            true,
             "Empty string has at least 0 characters"
         );
 
@@ -1606,15 +2177,13 @@ B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
             "Empty input take zero returns empty, context unchanged."
         );
 
-        assert_eq!(
-            empty_context.has(1),
-            false,
+        assert!(
+            !empty_context.has(1),
             "Empty string doesn't have one characters."
         );
 
-        assert_eq!(
-            empty_context.has(20),
-            false,
+        assert!(
+            !empty_context.has(20),
             "Empty string doesn't lots of characters."
         );
 
@@ -1624,9 +2193,8 @@ B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
         // Non-empty
         //
 
-        assert_eq!(
+        assert!(
             some_context.has(0),
-            true,
             "Empty string has at least 0 characters"
         );
 
@@ -1636,15 +2204,13 @@ B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
             "Empty input take zero returns subsequence, context reflects this."
         );
 
-        assert_eq!(
+        assert!(
             some_context.has(1),
-            true,
             "Empty string has one characters."
         );
 
-        assert_eq!(
+        assert!(
             some_context.has(20),
-            true,
             "Empty string has lots of characters."
         );
 
@@ -2245,6 +2811,185 @@ K:    GFmaj
         }
     }
 
+    #[test]
+    fn read_key_note_accidentals_test() {
+        // Every spelling of every accidental, including unicode and double forms.
+        let spellings = vec![
+            ("", None),
+            ("natural", Some(music::Accidental::Natural)),
+            ("Natural", Some(music::Accidental::Natural)),
+            ("=", Some(music::Accidental::Natural)),
+            ("♮", Some(music::Accidental::Natural)),
+            ("sharp", Some(music::Accidental::Sharp)),
+            ("SHARP", Some(music::Accidental::Sharp)),
+            ("s", Some(music::Accidental::Sharp)),
+            ("#", Some(music::Accidental::Sharp)),
+            ("♯", Some(music::Accidental::Sharp)),
+            ("flat", Some(music::Accidental::Flat)),
+            ("Flat", Some(music::Accidental::Flat)),
+            ("f", Some(music::Accidental::Flat)),
+            ("b", Some(music::Accidental::Flat)),
+            ("♭", Some(music::Accidental::Flat)),
+            ("ss", Some(music::Accidental::DoubleSharp)),
+            ("##", Some(music::Accidental::DoubleSharp)),
+            ("♯♯", Some(music::Accidental::DoubleSharp)),
+            ("ff", Some(music::Accidental::DoubleFlat)),
+            ("bb", Some(music::Accidental::DoubleFlat)),
+            ("♭♭", Some(music::Accidental::DoubleFlat)),
+        ];
+
+        for (spelling, expected) in spellings {
+            let input = &(string_to_vec(format!("C{}", spelling)));
+            let ctx = Context::new(input);
+            match read_key_note(ctx) {
+                Some((new_ctx, pitch_class)) => {
+                    assert_eq!(
+                        pitch_class,
+                        music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::C,
+                            accidental: expected,
+                        },
+                        "Key note accidental spelling '{}'",
+                        spelling
+                    );
+                    assert_eq!(
+                        new_ctx,
+                        ctx.skip(input.len()),
+                        "Whole spelling '{}' consumed",
+                        spelling
+                    );
+                }
+                x => assert!(false, "Expected key note for '{}': {:?}", spelling, x),
+            }
+        }
+
+        // Regression: double sharp and double flat were swapped.
+        let input = &(string_to_vec("K:Cbb\n".to_string()));
+        match Lexer::new(input).collect_tokens().first() {
+            Some(T::KeySignature(pitch_class, _)) => {
+                assert_eq!(pitch_class.accidental, Some(music::Accidental::DoubleFlat))
+            }
+            x => assert!(false, "Expected key signature: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn dialect_key_note_test() {
+        use music::Accidental::*;
+        use music::DiatonicPitchClass::*;
+        use music::Mode::*;
+
+        let key_note = |dialect, abc: &str| {
+            let input = string_to_vec(format!("K:{}\n", abc));
+            let tokens = Lexer::new(&input).with_dialect(dialect).collect_tokens();
+            match tokens.first() {
+                Some(T::KeySignature(pitch_class, mode)) => Some((
+                    pitch_class.diatonic_pitch_class,
+                    pitch_class.accidental,
+                    *mode,
+                )),
+                _ => None,
+            }
+        };
+
+        let german = vec![
+            ("H", (B, None, Major)),
+            ("Hmin", (B, None, Minor)),
+            ("B", (B, Some(Flat), Major)),
+            ("Bb", (B, Some(Flat), Major)),
+            ("Fis", (F, Some(Sharp), Major)),
+            ("Cisis", (C, Some(DoubleSharp), Major)),
+            ("Es", (E, Some(Flat), Major)),
+            ("As", (A, Some(Flat), Major)),
+            ("Des", (D, Some(Flat), Major)),
+            ("Eaeolian", (E, None, Aeolian)),
+            ("F#", (F, Some(Sharp), Major)),
+        ];
+        for (abc, expected) in german {
+            assert_eq!(key_note(Dialect::German, abc), Some(expected), "{}", abc);
+        }
+
+        let solfege = vec![
+            ("Do", (C, None, Major)),
+            ("Ré min", (D, None, Minor)),
+            ("Sol", (G, None, Major)),
+            ("Sib", (B, Some(Flat), Major)),
+            ("FA#", (F, Some(Sharp), Major)),
+            ("Lá minor", (A, None, Minor)),
+            ("G", (G, None, Major)),
+        ];
+        for (abc, expected) in solfege {
+            assert_eq!(key_note(Dialect::Solfege, abc), Some(expected), "{}", abc);
+        }
+
+        // The standard has neither, and "Es" is E sharp.
+        assert_eq!(key_note(Dialect::Standard, "H"), None);
+        assert_eq!(key_note(Dialect::Standard, "Re"), None);
+        let e_sharp = (E, Some(Sharp), Major);
+        assert_eq!(key_note(Dialect::Standard, "Es"), Some(e_sharp));
+        assert_eq!(key_note(Dialect::Standard, "B"), Some((B, None, Major)));
+    }
+
+    #[test]
+    fn to_standard_test() {
+        let abc = "X:1\nT:Fis und Es\nK:Fis clef=bass\nFAc|\n";
+        assert_eq!(
+            to_standard(abc, Dialect::German),
+            "X:1\nT:Fis und Es\nK:F# clef=bass\nFAc|\n"
+        );
+        assert_eq!(to_standard("K: Sim\r\n", Dialect::Solfege), "K: Bm\r\n");
+        assert_eq!(to_standard(abc, Dialect::Standard), abc);
+
+        for name in ["standard", "german", "solfege"].iter() {
+            assert_eq!(Dialect::from_name(name).map(|x| x.name()), Some(*name));
+        }
+        assert_eq!(Dialect::from_name("Solfège"), Some(Dialect::Solfege));
+        assert_eq!(Dialect::from_name("dutch"), None);
+    }
+
+    #[test]
+    fn lex_note_accidentals_test() {
+        let spellings = vec![
+            ("", None),
+            ("=", Some(music::Accidental::Natural)),
+            ("^", Some(music::Accidental::Sharp)),
+            ("_", Some(music::Accidental::Flat)),
+            ("^^", Some(music::Accidental::DoubleSharp)),
+            ("__", Some(music::Accidental::DoubleFlat)),
+        ];
+
+        for (spelling, expected) in spellings {
+            let input = &(string_to_vec(format!("{}C", spelling)));
+            match lex_note(Context::new(input)) {
+                LexResult::T(_, tokens) => assert_eq!(
+                    tokens,
+                    &[T::Note(music::Note(
+                        music::Pitch {
+                            pitch_class: music::PitchClass {
+                                diatonic_pitch_class: music::DiatonicPitchClass::C,
+                                accidental: expected,
+                            },
+                            octave: 0,
+                        },
+                        music::FractionalDuration(1, 1),
+                    )),],
+                    "Note accidental spelling '{}'",
+                    spelling
+                ),
+                x => assert!(false, "Expected note for '{}': {:?}", spelling, x),
+            }
+        }
+
+        // Key note spellings aren't accidentals in the tune body.
+        for spelling in ["#", "♯", "♭", "♮"] {
+            let input = &(string_to_vec(format!("{}C", spelling)));
+            match lex_note(Context::new(input)) {
+                LexResult::Error(_, _, LexError::UnrecognisedNote) => (),
+                x => assert!(false, "Expected error for '{}': {:?}", spelling, x),
+            }
+        }
+    }
+
     #[test]
     fn read_mode_test() {
         // Case insensitive long form, ignoring spaces.
@@ -2702,4 +3447,308 @@ K:    GFmaj
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn lex_note_octaves_test() {
+        // Input, octave, and the number of characters read.
+        for (input, expected_octave, expected_length) in vec![
+            ("C,,,,", -4, 5),
+            ("A,,,,,", -5, 6),
+            ("E,,,,2", -4, 6),
+            ("^F,,,,/", -4, 7),
+            ("c''''", 5, 5),
+            ("g'''''", 6, 6),
+            ("_b''''3/2", 5, 9),
+            ("c',", 1, 3),
+            ("B,'", 0, 3),
+            ("d''|", 3, 3),
+        ] {
+            match lex_note(Context::new(&string_to_vec(input.to_string()))) {
+                LexResult::T(ctx, tokens) => {
+                    match tokens[0] {
+                        T::Note(music::Note(pitch, _)) => {
+                            assert_eq!(pitch.octave, expected_octave, "Octave of {}", input)
+                        }
+                        _ => assert!(false, "Expected a note for {}", input),
+                    }
+                    assert_eq!(ctx.i, expected_length, "Characters read for {}", input);
+                }
+                _ => assert!(false, "Expected a note for {}", input),
+            }
+        }
+
+        // Too far from middle C is an error, and lexing carries on after the note.
+        let input = &string_to_vec("C,,,,,,,,,,,2 c'''''''''D\n".to_string());
+        assert_eq!(
+            Lexer::new(input)
+                .in_body()
+                .collect_errors()
+                .iter()
+                .map(|(_, offset, err)| (*offset, err.clone()))
+                .collect::<Vec<(usize, LexError)>>(),
+            vec![(0, LexError::TooManyOctaveMarks)]
+        );
+        assert_eq!(Lexer::new(input).in_body().collect_tokens().len(), 4);
+    }
+
+    /// Zero denominators are a lex error rather than a duration.
+    #[test]
+    fn zero_denominator_test() {
+        match lex_note(Context::new(&(string_to_vec(String::from("C1/0"))))) {
+            LexResult::Error(_, 1, LexError::ZeroDenominator) => assert!(true),
+            _ => assert!(false),
+        }
+
+        match lex_rest(Context::new(&(string_to_vec(String::from("z/0"))))) {
+            LexResult::Error(_, 1, LexError::ZeroDenominator) => assert!(true),
+            _ => assert!(false),
+        }
+
+        match read(Context::new(&(string_to_vec("L:1/0\n".to_string())))) {
+            LexResult::Error(_, 2, LexError::ZeroDenominator) => assert!(true),
+            _ => assert!(false),
+        }
+
+        // Lexing carries on from after the bad duration.
+        let input = &(string_to_vec("C/0D\n".to_string()));
+        assert_eq!(
+            Lexer::new(input)
+                .in_body()
+                .collect_errors()
+                .iter()
+                .map(|(_, offset, err)| (*offset, err.clone()))
+                .collect::<Vec<(usize, LexError)>>(),
+            vec![(1, LexError::ZeroDenominator)]
+        );
+
+        assert_eq!(Lexer::new(input).in_body().collect_tokens().len(), 2);
+    }
+
+    /// Every duration that can be lexed can be turned into a glyph without panicking, whatever the
+    /// default note length.
+    #[test]
+    fn lexed_duration_glyph_test() {
+        let numbers = &["", "0", "1", "2", "3", "5", "7", "16", "33", "99999999"];
+        let note_lengths = &[
+            music::FractionalDuration(1, 1),
+            music::FractionalDuration(1, 8),
+            music::FractionalDuration(3, 7),
+            music::FractionalDuration(99999999, 1),
+            music::FractionalDuration(1, 99999999),
+        ];
+
+        for numerator in numbers.iter() {
+            for slash in &["", "/", "//"] {
+                for denominator in numbers.iter() {
+                    let duration = format!("{}{}{}", numerator, slash, denominator);
+                    let abc = format!("C{} z{}\n", duration, duration);
+
+                    for token in Lexer::new(&string_to_vec(abc)).in_body().collect_tokens() {
+                        let duration = match token {
+                            T::Note(music::Note(_, duration)) => duration,
+                            T::Rest(duration) => duration,
+                            _ => continue,
+                        };
+
+                        assert_ne!(duration.1, 0, "Lexed durations should never divide by zero.");
+
+                        for note_length in note_lengths.iter() {
+                            duration.multiply(*note_length).to_glyph();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_voice_test() {
+        let (id, properties) = parse_voice(&string_to_vec(
+            "T1 name=\"Tenor Banjo\" clef=treble bass".to_string(),
+        ));
+
+        assert_eq!(id, "T1");
+        assert_eq!(
+            properties,
+            vec![
+                ("name".to_string(), "Tenor Banjo".to_string()),
+                ("clef".to_string(), "treble".to_string()),
+                ("bass".to_string(), "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_voice_test() {
+        // Declared in the header, then switched to in the body.
+        let input = &string_to_vec("V:1 nm=\"Flute\"\nK:G\nV:1\nG\n".to_string());
+        let tokens = Lexer::new(input).collect_tokens();
+
+        assert_eq!(
+            tokens[0],
+            T::Voice("1".to_string(), vec![("nm".to_string(), "Flute".to_string())])
+        );
+        assert_eq!(tokens[2], T::Voice("1".to_string(), vec![]));
+
+        // The voice switch line doesn't leave a line break.
+        assert_eq!(tokens.len(), 5);
+
+        // A voice needs an ID.
+        assert_eq!(
+            Lexer::new(&string_to_vec("V:\nK:G\n".to_string()))
+                .collect_errors()
+                .iter()
+                .map(|(_, _, err)| err.clone())
+                .collect::<Vec<LexError>>(),
+            vec![LexError::ExpectedVoiceId]
+        );
+
+        // Only a field at the start of a line switches voice.
+        assert!(
+            Lexer::new(&string_to_vec("GV:1\n".to_string()))
+                .in_body()
+                .collect_tokens()
+                .iter()
+                .all(|x| !matches!(x, T::Voice(_, _)))
+        );
+    }
+
+    #[test]
+    fn lex_key_clef_test() {
+        let tokens = |abc: &str| Lexer::new(&string_to_vec(abc.to_string())).collect_tokens();
+        let g_major = T::KeySignature(
+            music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::G,
+                accidental: None,
+            },
+            music::Mode::Major,
+        );
+
+        assert_eq!(tokens("K:G\n"), vec![g_major.clone()]);
+        assert_eq!(
+            tokens("K:G clef=bass\n"),
+            vec![g_major.clone(), T::Clef(music::Clef::bass())]
+        );
+        assert_eq!(
+            tokens("K:G alto\n"),
+            vec![g_major.clone(), T::Clef(music::Clef::alto())]
+        );
+
+        // Unknown clefs are ignored.
+        assert_eq!(tokens("K:G clef=perc\n"), vec![g_major]);
+    }
+
+    #[test]
+    fn lex_comment_test() {
+        let input = &string_to_vec(
+            "%%scale 0.8\n% Transcribed by ear\nK:G\n%%staffwidth  16cm\nGA % ornament? \nB%%x\n"
+                .to_string(),
+        );
+        let lexer = Lexer::new(input);
+        assert!(lexer.collect_errors().is_empty());
+
+        assert_eq!(
+            Lexer::new(input).collect_tokens(),
+            vec![
+                T::Directive("scale".to_string(), "0.8".to_string()),
+                T::CommentLine(" Transcribed by ear".to_string()),
+                T::KeySignature(
+                    music::PitchClass {
+                        diatonic_pitch_class: music::DiatonicPitchClass::G,
+                        accidental: None,
+                    },
+                    music::Mode::Major,
+                ),
+                // A line of its own doesn't break the music.
+                T::Directive("staffwidth".to_string(), "16cm".to_string()),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::G,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::A,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::BeamBreak,
+                T::CommentLine(" ornament? ".to_string()),
+                T::Newline,
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::B,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                // Only a directive at the start of a line.
+                T::CommentLine("%x".to_string()),
+                T::Newline,
+            ]
+        );
+
+        // A comment can end the tune without a line break.
+        assert_eq!(
+            Lexer::new(&string_to_vec("G%end".to_string()))
+                .in_body()
+                .collect_tokens()
+                .last(),
+            Some(&T::CommentLine("end".to_string()))
+        );
+    }
+
+    #[test]
+    fn line_continuation_test() {
+        let input = &string_to_vec(
+            "X:1\nH:Learned from a fiddler \\\nin Sligo\n+:  in 1920.\nW:Verse\nT:Next\nK:G\n"
+                .to_string(),
+        );
+        assert!(Lexer::new(input).collect_errors().is_empty());
+        assert_eq!(
+            Lexer::new(input).collect_tokens()[1..3],
+            [
+                T::History("Learned from a fiddler in Sligo in 1920.".to_string()),
+                T::Words("Verse".to_string()),
+            ]
+        );
+
+        // A continued line of music doesn't break the line.
+        let input = &string_to_vec("AB \\\nc|\\ \nd\\".to_string());
+        let lexer = Lexer::new(input).in_body();
+        assert!(lexer.collect_errors().is_empty());
+        let tokens = Lexer::new(input).in_body().collect_tokens();
+        assert_eq!(tokens.len(), 7);
+        assert!(!tokens.contains(&T::Newline));
+
+        // Anywhere else it's an error.
+        assert_eq!(
+            Lexer::new(&string_to_vec("A\\B".to_string()))
+                .in_body()
+                .collect_errors()[0]
+                .2,
+            LexError::UnexpectedBodyChar('\\')
+        );
+    }
+
+    #[test]
+    fn kind_test() {
+        assert_eq!(
+            LexError::UnexpectedBodyChar('(').kind(),
+            "UnexpectedBodyChar"
+        );
+        assert_eq!(LexError::ExpectedColon.kind(), "ExpectedColon");
+    }
 }
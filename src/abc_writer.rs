@@ -0,0 +1,602 @@
+//! Write tunes back out as ABC.
+//! The output is normalised rather than a copy of the original, e.g. "M:C" is written as "M:4/4",
+//! but reading it back gives the same tokens.
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+// Durations in the AST are absolute. They're written relative to this default note length.
+const NOTE_LENGTH: music::FractionalDuration = music::FractionalDuration(1, 8);
+
+// A duration after a note or rest, e.g. "3/2". The default length is written as nothing.
+fn duration(duration: music::FractionalDuration) -> String {
+    let relative = duration.multiply(music::FractionalDuration(NOTE_LENGTH.1, NOTE_LENGTH.0));
+
+    match relative {
+        music::FractionalDuration(1, 1) => "".to_string(),
+        music::FractionalDuration(1, 2) => "/".to_string(),
+        music::FractionalDuration(numerator, 1) => numerator.to_string(),
+        music::FractionalDuration(1, denominator) => format!("/{}", denominator),
+        music::FractionalDuration(numerator, denominator) => {
+            format!("{}/{}", numerator, denominator)
+        }
+    }
+}
+
+fn note(pitch: music::Pitch) -> String {
+    let accidental = match pitch.pitch_class.accidental {
+        Some(music::Accidental::Sharp) => "^",
+        Some(music::Accidental::Flat) => "_",
+        Some(music::Accidental::Natural) => "=",
+        Some(music::Accidental::DoubleSharp) => "^^",
+        Some(music::Accidental::DoubleFlat) => "__",
+        None => "",
+    };
+
+    // Lower case is an octave up, then commas and apostrophes for any more.
+    let letter = pitch.pitch_class.diatonic_pitch_class.to_string();
+    let (letter, marks) = if pitch.octave > 0 {
+        (letter.to_lowercase(), "'".repeat(pitch.octave as usize - 1))
+    } else {
+        (letter, ",".repeat(-pitch.octave as usize))
+    };
+
+    format!("{}{}{}", accidental, letter, marks)
+}
+
+fn key_signature(pitch_class: music::PitchClass, mode: music::Mode) -> String {
+    // The lexer has no spelling for the natural mode, so it comes out as major.
+    let mode = match mode {
+        music::Mode::Major | music::Mode::Natural => "",
+        music::Mode::Minor => "min",
+        music::Mode::Lydian => "Lydian",
+        music::Mode::Ionian => "Ionian",
+        music::Mode::Mixolydian => "Mixolydian",
+        music::Mode::Dorian => "Dorian",
+        music::Mode::Aeolian => "Aeolian",
+        music::Mode::Phrygian => "Phrygian",
+        music::Mode::Locrian => "Locrian",
+    };
+
+    format!("{}{}", pitch_class.to_abc(), mode)
+}
+
+fn is_barline(token: &l::T) -> bool {
+    matches!(
+        token,
+        l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_)
+    )
+}
+
+/// ABC for a sequence of tokens from the AST, e.g. a voice.
+pub fn tokens(tokens: &[l::T]) -> String {
+    let mut buf = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = if i > 0 { tokens.get(i - 1) } else { None };
+        let next = tokens.get(i + 1);
+
+        match token {
+            // A comment after some music runs up to a line break that's a token of its own.
+            l::T::CommentLine(x) => {
+                buf.push_str(&format!("%{}", x));
+                if next != Some(&l::T::Newline) {
+                    buf.push('\n');
+                }
+            }
+            l::T::Directive(name, value) => {
+                buf.push_str(format!("%%{} {}", name, value).trim_end());
+                buf.push('\n');
+            }
+
+            // Barlines bring their own beam breaks.
+            l::T::BeamBreak => match next {
+                Some(next) if is_barline(next) => (),
+                _ => buf.push(' '),
+            },
+            l::T::Newline => buf.push('\n'),
+
+            l::T::Area(x) => buf.push_str(&format!("A:{}\n", x)),
+            l::T::Book(x) => buf.push_str(&format!("B:{}\n", x)),
+            l::T::Composer(x) => buf.push_str(&format!("C:{}\n", x)),
+            l::T::Discography(x) => buf.push_str(&format!("D:{}\n", x)),
+            l::T::Filename(x) => buf.push_str(&format!("F:{}\n", x)),
+            l::T::Group(x) => buf.push_str(&format!("G:{}\n", x)),
+            l::T::History(x) => buf.push_str(&format!("H:{}\n", x)),
+            l::T::Information(x) => buf.push_str(&format!("I:{}\n", x)),
+            l::T::Notes(x) => buf.push_str(&format!("N:{}\n", x)),
+            l::T::Origin(x) => buf.push_str(&format!("O:{}\n", x)),
+            l::T::Source(x) => buf.push_str(&format!("S:{}\n", x)),
+            l::T::Title(x) => buf.push_str(&format!("T:{}\n", x)),
+            l::T::Words(x) => buf.push_str(&format!("W:{}\n", x)),
+            l::T::Rhythm(x) => buf.push_str(&format!("R:{}\n", x)),
+            l::T::X(x) => buf.push_str(&format!("X:{}\n", x)),
+            l::T::Transcription(x) => buf.push_str(&format!("Z:{}\n", x)),
+
+            l::T::Metre(metre) => buf.push_str(&format!("M:{}\n", metre)),
+            l::T::KeySignature(pitch_class, mode) => {
+                buf.push_str(&format!("K:{}\n", key_signature(*pitch_class, *mode)))
+            }
+            l::T::DefaultNoteLength(music::FractionalDuration(numerator, denominator)) => {
+                buf.push_str(&format!("L:{}/{}\n", numerator, denominator))
+            }
+            l::T::Tempo(x) => buf.push_str(&format!("Q:{}\n", x)),
+            l::T::Voice(id, properties) => buf.push_str(&format!("{}\n", voice(id, properties))),
+
+            // A clef comes straight after the key field it was in, so goes on the end of it.
+            l::T::Clef(clef) => {
+                if buf.ends_with('\n') {
+                    buf.pop();
+                }
+                buf.push_str(&format!(" clef={}\n", clef.name()));
+            }
+
+            // Some barlines are read as more than one token, so only write the rest of them.
+            l::T::DoubleBar if previous == Some(&l::T::CloseRepeat) => buf.push('|'),
+            l::T::EndBar if previous == Some(&l::T::CloseRepeat) => buf.push(']'),
+            l::T::OpenRepeat if previous == Some(&l::T::DoubleBar) => buf.push(':'),
+
+            l::T::SingleBar => buf.push('|'),
+            l::T::DoubleBar => buf.push_str("||"),
+            l::T::OpenRepeat => buf.push_str("|:"),
+            l::T::CloseRepeat => buf.push_str(":|"),
+            l::T::EndBar => buf.push_str("|]"),
+            l::T::NTimeBar(number) => buf.push_str(&number.to_string()),
+
+            l::T::Note(music::Note(pitch, length)) => {
+                buf.push_str(&note(*pitch));
+                buf.push_str(&duration(*length));
+            }
+            l::T::Rest(length) => {
+                buf.push('z');
+                buf.push_str(&duration(*length));
+            }
+            l::T::GuitarChord(x) => buf.push_str(&format!("\"{}\"", x)),
+
+            l::T::Dottage(dots) => {
+                let symbol = if *dots > 0 { ">" } else { "<" };
+                buf.push_str(&symbol.repeat(dots.unsigned_abs() as usize));
+            }
+        }
+    }
+
+    buf
+}
+
+// A V: field, quoting values with spaces.
+fn voice(id: &str, properties: &[(String, String)]) -> String {
+    let mut result = format!("V:{}", id);
+    for (key, value) in properties.iter() {
+        if value.is_empty() {
+            result.push_str(&format!(" {}", key));
+        } else if value.contains(' ') {
+            result.push_str(&format!(" {}=\"{}\"", key, value));
+        } else {
+            result.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    result
+}
+
+// Properties that differ from the defaults. Notes in the AST have already been moved by the
+// voice's octave, so that isn't included.
+fn voice_properties(properties: &tune_ast_three::VoiceProperties) -> Vec<(String, String)> {
+    let mut result = vec![];
+    if let Some(ref name) = properties.name {
+        result.push(("name".to_string(), name.clone()));
+    }
+    if let Some(ref subname) = properties.subname {
+        result.push(("subname".to_string(), subname.clone()));
+    }
+    if properties.clef != music::Clef::treble() {
+        result.push(("clef".to_string(), properties.clef.name().to_string()));
+    }
+    if properties.transpose != 0 {
+        result.push(("transpose".to_string(), properties.transpose.to_string()));
+    }
+    result
+}
+
+/// ABC for a whole tune.
+pub fn tune(ast: &tune_ast_three::Tune) -> String {
+    let mut buf = String::new();
+
+    // A single voice with nothing special about it doesn't need a V: field.
+    let needs_voices = ast.voices.len() > 1
+        || ast
+            .voice_properties
+            .iter()
+            .any(|x| x.id != "1" || !voice_properties(x).is_empty());
+
+    // The default note length and voice declarations go just before the key, which ends the
+    // header.
+    for token in ast.prelude.iter() {
+        if let l::T::KeySignature(_, _) = token {
+            buf.push_str(&tokens(&[l::T::DefaultNoteLength(NOTE_LENGTH)]));
+
+            if needs_voices {
+                for properties in ast.voice_properties.iter() {
+                    buf.push_str(&voice(&properties.id, &voice_properties(properties)));
+                    buf.push('\n');
+                }
+            }
+        }
+
+        buf.push_str(&tokens(::std::slice::from_ref(token)));
+    }
+
+    for (voice, properties) in ast.voices.iter().zip(ast.voice_properties.iter()) {
+        if needs_voices {
+            if !buf.is_empty() && !buf.ends_with('\n') {
+                buf.push('\n');
+            }
+            buf.push_str(&format!("V:{}\n", properties.id));
+        }
+
+        buf.push_str(&tokens(voice));
+    }
+
+    buf
+}
+
+/// How `format` lays out a tune.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatOptions {
+    /// Start a new line after this many bars. None keeps the tune's own lines.
+    pub bars_per_line: Option<usize>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            bars_per_line: Some(4),
+        }
+    }
+}
+
+// Where a header field goes, from X first to the key, which ends the header.
+fn header_rank(token: &l::T) -> Option<usize> {
+    match token {
+        l::T::X(_) => Some(0),
+        l::T::Title(_) => Some(1),
+        l::T::Composer(_) => Some(2),
+        l::T::Origin(_) => Some(3),
+        l::T::Area(_) => Some(4),
+        l::T::Rhythm(_) => Some(5),
+        l::T::Book(_) => Some(6),
+        l::T::Discography(_) => Some(7),
+        l::T::Source(_) => Some(8),
+        l::T::Filename(_) => Some(9),
+        l::T::Group(_) => Some(10),
+        l::T::History(_) => Some(11),
+        l::T::Notes(_) => Some(12),
+        l::T::Words(_) => Some(13),
+        l::T::Transcription(_) => Some(14),
+        l::T::Information(_) => Some(15),
+        l::T::Metre(_) => Some(16),
+        l::T::DefaultNoteLength(_) => Some(17),
+        l::T::Tempo(_) => Some(18),
+        l::T::Voice(_, _) => Some(19),
+        l::T::KeySignature(_, _) => Some(20),
+        _ => None,
+    }
+}
+
+// The header fields in the usual order. Fields of the same kind keep their order, comments and
+// directives stay with the field after them, and a clef stays with its key.
+fn sort_header(prelude: &[l::T]) -> Vec<l::T> {
+    let mut groups: Vec<(usize, Vec<l::T>)> = vec![];
+    let mut pending = vec![];
+
+    for token in prelude.iter() {
+        match (header_rank(token), token) {
+            (Some(rank), _) => {
+                pending.push(token.clone());
+                groups.push((rank, pending));
+                pending = vec![];
+            }
+            (None, l::T::Clef(_)) if pending.is_empty() && !groups.is_empty() => {
+                if let Some(group) = groups.last_mut() {
+                    group.1.push(token.clone());
+                }
+            }
+            (None, _) => pending.push(token.clone()),
+        }
+    }
+
+    // The sort is stable, so repeated fields stay in order.
+    groups.sort_by_key(|group| group.0);
+
+    let mut result: Vec<l::T> = groups.into_iter().flat_map(|group| group.1).collect();
+    result.extend(pending);
+    result
+}
+
+// Tokens written on a line of their own.
+fn is_line(token: &l::T) -> bool {
+    header_rank(token).is_some() || matches!(token, l::T::CommentLine(_) | l::T::Directive(_, _))
+}
+
+// Tokens that beam breaks can go between.
+fn is_music(token: &l::T) -> bool {
+    !is_barline(token) && !is_line(token) && token != &l::T::BeamBreak && token != &l::T::Newline
+}
+
+// A voice laid out for `format`, with single spaces between groups of notes and none next to
+// barlines, except after an ending's number. When wrapping, the original line breaks become
+// spaces and a line ends every few bars, or at a comment. A pick-up doesn't count as a bar.
+fn layout(voice: &[l::T], pickup: bool, options: &FormatOptions) -> Vec<l::T> {
+    let voice: Vec<l::T> = match options.bars_per_line {
+        Some(_) => voice
+            .iter()
+            .map(|token| match token {
+                l::T::Newline => l::T::BeamBreak,
+                _ => token.clone(),
+            }).collect(),
+        None => voice.to_vec(),
+    };
+
+    let mut result: Vec<l::T> = vec![];
+
+    // Bars on this line, and whether there's been music since the last barline.
+    let mut bars = 0;
+    let mut in_bar = false;
+    let mut pickup = pickup;
+
+    for (i, token) in voice.iter().enumerate() {
+        let next = voice.get(i + 1);
+
+        match token {
+            l::T::BeamBreak => {
+                let after = match result.last() {
+                    Some(l::T::NTimeBar(_)) => true,
+                    last => last.is_some_and(is_music),
+                };
+                if after && next.is_some_and(is_music) {
+                    result.push(l::T::BeamBreak);
+                }
+            }
+            _ if is_line(token) => {
+                if options.bars_per_line.is_some() {
+                    if let Some(last) = result.last() {
+                        if last != &l::T::Newline && !is_line(last) {
+                            result.push(l::T::Newline);
+                        }
+                    }
+                    bars = 0;
+                }
+                result.push(token.clone());
+            }
+            _ if is_barline(token) => {
+                if in_bar && pickup {
+                    pickup = false;
+                } else if in_bar {
+                    bars += 1;
+                }
+                in_bar = false;
+                result.push(token.clone());
+
+                // A line doesn't end in the middle of a barline, or before an ending's number.
+                let ends_bar = match token {
+                    l::T::NTimeBar(_) => false,
+                    _ => !next.is_some_and(is_barline),
+                };
+                if let Some(bars_per_line) = options.bars_per_line {
+                    if ends_bar && bars >= bars_per_line && next.is_some() {
+                        result.push(l::T::Newline);
+                        bars = 0;
+                    }
+                }
+            }
+            l::T::Newline => {
+                bars = 0;
+                result.push(l::T::Newline);
+            }
+            l::T::Note(_) | l::T::Rest(_) => {
+                in_bar = true;
+                result.push(token.clone());
+            }
+            _ => result.push(token.clone()),
+        }
+    }
+
+    // Exactly one line break at the end.
+    while result.last() == Some(&l::T::Newline) {
+        result.pop();
+    }
+    if result.last().is_some_and(|last| !is_line(last)) {
+        result.push(l::T::Newline);
+    }
+
+    result
+}
+
+/// ABC for a whole tune, normalised for reading and comparing: header fields in the usual order,
+/// consistent spacing in the music, and the music's lines wrapped every few bars.
+/// Read the tune with `tune_ast_three::read_as_written` to keep its broken rhythms, e.g. "A>B".
+pub fn format(ast: &tune_ast_three::Tune, options: &FormatOptions) -> String {
+    let formatted = tune_ast_three::Tune {
+        prelude: sort_header(&ast.prelude),
+        voices: ast
+            .voices
+            .iter()
+            .zip(ast.voice_properties.iter())
+            .map(|(voice, properties)| layout(voice, properties.anacrusis.is_some(), options))
+            .collect(),
+        voice_properties: ast.voice_properties.clone(),
+    };
+
+    tune(&formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    // Read the ABC as it's written, for formatting.
+    fn as_written(abc: &str) -> tune_ast_three::Tune {
+        let chars = abc.chars().collect::<Vec<char>>();
+        tune_ast_three::read_as_written(l::Lexer::new(&chars))
+    }
+
+    // Read the ABC, and read back the ABC written from that.
+    fn round_trip(abc: &str) -> (tune_ast_three::Tune, tune_ast_three::Tune) {
+        let original = representations::abc_to_ast(abc);
+        let read = representations::abc_to_ast(&tune(&original));
+        (original, read)
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for abc in [
+            "X:1\nT:Title\nM:C\nL:1/8\nQ:1/4=120\nK:Bbmin\n",
+            "X:1\nK:F#Dorian\n|:GA/B/ c2 d>e ^f_g=a|1 B,,C'3/2:|2 \"Am\"z4||\n",
+            "X:1\nL:1/16\nK:G\n:|: A :: B :|| c ||: d :|]\n",
+            "X:1\nV:1 name=\"Top line\" clef=treble\nK:G\nV:1\nABc|\n",
+            "X:1\nK:G clef=bass\nG,A,B,|\n",
+            "X:1\n%%scale 0.8\n% Header comment\nK:G\n% Part A\nGA % ornament\nB|\n%%vskip\nc|\n",
+        ]
+            .iter()
+        {
+            let (original, read) = round_trip(abc);
+            assert_eq!(original.prelude, read.prelude, "Round trip of {:?}", abc);
+            assert_eq!(original.voices, read.voices, "Round trip of {:?}", abc);
+            assert_eq!(
+                original.voice_properties, read.voice_properties,
+                "Round trip of {:?}",
+                abc
+            );
+        }
+    }
+
+    #[test]
+    fn tune_test() {
+        let abc = "X:1\nT:Two voices\nL:1/4\nV:T name=Tenor octave=-1\nK:Emin\nV:T\nE/F/G A2|\n\
+                   V:B\nE,2 z2|]\n";
+        let ast = representations::abc_to_ast(abc);
+        let written = tune(&ast);
+
+        // The notes have already been moved down an octave.
+        assert_eq!(
+            written,
+            "X:1\nT:Two voices\nL:1/8\nV:T name=Tenor\nV:B\nK:Emin\nV:T\nE,F,G,2 A,4|\n\
+             V:B\nE,4 z4|]\n"
+        );
+
+        let read = representations::abc_to_ast(&written);
+        assert_eq!(read.prelude, ast.prelude);
+        assert_eq!(read.voices, ast.voices);
+    }
+
+    #[test]
+    fn format_test() {
+        let abc = "X:1\nM:6/8\nR:jig\nT:Title\n% Written down by\nC:Someone\nL:1/8\nK:G\n\
+                   GAB  cde | fga  bag :|1 d3 e3 :|2 d3 d3 ||\n|: ga b | c d e | f g a\n\
+                   % Part B\nb a g | d3 z3 |]\n";
+        let ast = as_written(abc);
+
+        assert_eq!(
+            format(
+                &ast,
+                &FormatOptions {
+                    bars_per_line: Some(2),
+                }
+            ),
+            "X:1\nT:Title\n% Written down by\nC:Someone\nR:jig\nM:6/8\nL:1/8\nK:G\n\
+             GAB cde|fga bag:|1 d3 e3:|2 d3 d3||\n|:ga b|c d e|\nf g a\n% Part B\nb a g|d3 z3|]\n"
+        );
+
+        assert_eq!(
+            format(
+                &ast,
+                &FormatOptions {
+                    bars_per_line: None,
+                }
+            ),
+            "X:1\nT:Title\n% Written down by\nC:Someone\nR:jig\nM:6/8\nL:1/8\nK:G\n\
+             GAB cde|fga bag:|1 d3 e3:|2 d3 d3||\n|:ga b|c d e|f g a\n% Part B\nb a g|d3 z3|]\n"
+        );
+
+        // A pick-up isn't one of the bars on the first line.
+        let ast = as_written("X:1\nM:2/4\nL:1/8\nK:G\nd|g2g2|a2a2|b2b2|\n");
+        assert_eq!(
+            format(
+                &ast,
+                &FormatOptions {
+                    bars_per_line: Some(2),
+                }
+            ),
+            "X:1\nM:2/4\nL:1/8\nK:G\nd|g2g2|a2a2|\nb2b2|\n"
+        );
+
+        // Broken rhythms are written as they were.
+        assert_eq!(
+            format(
+                &as_written("X:1\nL:1/8\nK:G\nA>B  d<e A>>B|\n"),
+                &FormatOptions::default()
+            ),
+            "X:1\nL:1/8\nK:G\nA>B d<e A>>B|\n"
+        );
+    }
+
+    // Formatting only moves header fields and changes the spacing, so the music reads the same.
+    #[test]
+    fn format_round_trip_test() {
+        let spacing = |voice: &Vec<l::T>| -> Vec<l::T> {
+            voice
+                .iter()
+                .filter(|x| *x != &l::T::BeamBreak && *x != &l::T::Newline)
+                .cloned()
+                .collect()
+        };
+
+        for abc in [
+            "X:1\nK:G clef=bass\nT:Title\nG,A,B,|\n",
+            "X:1\nL:1/16\nK:G\n:|: A :: B :|| c ||: d :|]\n",
+            "X:1\nK:D\n|:d2 fd Adfd|\"G\"g2 bg \"A\"a2 ag|[1 fedc d4:|\n[2 fedc d2 z2|]\n",
+            "X:1\n%%scale 0.8\nK:G\n% Part A\nGA % ornament\nB|\n%%vskip\nc|\n",
+            "X:1\nM:6/8\nL:1/8\nK:D\nA>B|d2<e f>>g a<<b|c'3 z3|]\n",
+        ]
+            .iter()
+        {
+            let original = representations::abc_to_ast(abc);
+            let formatted = format(&as_written(abc), &FormatOptions::default());
+            let read = representations::abc_to_ast(&formatted);
+
+            assert_eq!(
+                sort_header(&original.prelude),
+                read.prelude,
+                "Format of {:?}",
+                abc
+            );
+            assert_eq!(
+                original.voices.iter().map(spacing).collect::<Vec<_>>(),
+                read.voices.iter().map(spacing).collect::<Vec<_>>(),
+                "Format of {:?}",
+                abc
+            );
+
+            // Formatting again changes nothing.
+            assert_eq!(
+                formatted,
+                format(&as_written(&formatted), &FormatOptions::default())
+            );
+        }
+    }
+
+    #[test]
+    fn duration_test() {
+        assert_eq!(duration(music::FractionalDuration(1, 8)), "");
+        assert_eq!(duration(music::FractionalDuration(1, 4)), "2");
+        assert_eq!(duration(music::FractionalDuration(1, 16)), "/");
+        assert_eq!(duration(music::FractionalDuration(1, 32)), "/4");
+        assert_eq!(duration(music::FractionalDuration(3, 16)), "3/2");
+    }
+}
@@ -0,0 +1,244 @@
+//! Automatic beaming.
+//! Many transcriptions don't use spaces to group notes into beams. This pass inserts beam breaks
+//! at the boundaries of beat groups according to the metre, e.g. groups of three quavers in 6/8.
+//! Bars that already contain beam breaks between notes are left as they are.
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+// Durations are counted in whole-number ticks. Divides evenly by powers of two and three.
+const TICKS_PER_WHOLE_NOTE: u32 = 1536;
+
+fn to_ticks(duration: music::FractionalDuration) -> u32 {
+    let music::FractionalDuration(numerator, denominator) = duration;
+    if denominator == 0 {
+        0
+    } else {
+        // Absurdly long notes saturate rather than overflow.
+        let ticks = numerator as u64 * TICKS_PER_WHOLE_NOTE as u64 / denominator as u64;
+        ticks.min(u32::MAX as u64) as u32
+    }
+}
+
+// Duration of each beam group in the metre, as a fraction of a whole note.
+pub fn beam_group(metre: music::Metre) -> music::FractionalDuration {
+    match metre {
+        // Compound metres, e.g. 6/8, 9/8, 12/8, group in dotted beats of three quavers.
+        music::Metre(numerator, 8) if numerator % 3 == 0 && numerator > 3 => {
+            music::FractionalDuration(3, 8)
+        }
+        // Quavers in fours, i.e. half a bar.
+        music::Metre(4, 4) => music::FractionalDuration(1, 2),
+        // Quavers in pairs, i.e. one beat.
+        music::Metre(_, 4) => music::FractionalDuration(1, 4),
+        music::Metre(_, denominator) => music::FractionalDuration(1, denominator),
+    }
+}
+
+fn bar_length(metre: music::Metre) -> u32 {
+    let music::Metre(numerator, denominator) = metre;
+    to_ticks(music::FractionalDuration(numerator, denominator))
+}
+
+fn duration_of(token: &l::T) -> Option<music::FractionalDuration> {
+    match token {
+        l::T::Note(music::Note(_, duration)) => Some(*duration),
+        l::T::Rest(duration) => Some(*duration),
+        _ => None,
+    }
+}
+
+pub fn is_barline(token: &l::T) -> bool {
+    matches!(
+        token,
+        l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_)
+    )
+}
+
+// Does the bar have a beam break between two notes, i.e. has the transcriber grouped it?
+fn has_explicit_beam_breaks(bar: &[l::T]) -> bool {
+    let mut seen_note = false;
+    let mut seen_break = false;
+
+    for token in bar.iter() {
+        match token {
+            l::T::BeamBreak if seen_note => seen_break = true,
+            l::T::Note(_) | l::T::Rest(_) => {
+                if seen_break {
+                    return true;
+                }
+                seen_note = true;
+            }
+            _ => (),
+        }
+    }
+
+    false
+}
+
+// Insert beam breaks into a bar at beam group boundaries, appending to the result.
+// A short first bar is taken to be a pickup, so is aligned to the end of the bar.
+fn beam_bar(bar: Vec<l::T>, metre: music::Metre, first: bool, result: &mut Vec<l::T>) {
+    if has_explicit_beam_breaks(&bar) {
+        result.extend(bar);
+        return;
+    }
+
+    // Sum in u64, as a bar of several saturated notes would overflow u32.
+    let group = to_ticks(beam_group(metre)) as u64;
+    let length = bar_length(metre) as u64;
+    let total: u64 = bar
+        .iter()
+        .filter_map(duration_of)
+        .map(|x| to_ticks(x) as u64)
+        .sum();
+
+    let mut position = if first && total < length {
+        length - total
+    } else {
+        0
+    };
+
+    let mut previous_note = false;
+    for token in bar {
+        if let Some(duration) = duration_of(&token) {
+            if previous_note && group > 0 && position % group == 0 {
+                result.push(l::T::BeamBreak);
+            }
+            position += to_ticks(duration) as u64;
+            previous_note = true;
+        } else if token == l::T::BeamBreak || token == l::T::Newline {
+            previous_note = false;
+        }
+
+        result.push(token);
+    }
+}
+
+// Return a copy of the tune with beam breaks added according to the metre.
+pub fn auto_beam(ast: &tune_ast_three::Tune) -> tune_ast_three::Tune {
+    // ABC has free metre by default, but 4/4 is what's typeset.
+    let mut metre = music::Metre(4, 4);
+    for token in ast.prelude.iter() {
+        if let l::T::Metre(new_metre) = token {
+            metre = *new_metre;
+        }
+    }
+
+    let mut voices = vec![];
+    for voice in ast.voices.iter() {
+        let mut result = Vec::with_capacity(voice.len());
+        let mut bar = vec![];
+        let mut first = true;
+
+        for token in voice.iter() {
+            if is_barline(token) {
+                // Don't count an opening barline as the first bar.
+                let has_notes = bar.iter().any(|x| duration_of(x).is_some());
+                beam_bar(bar, metre, first, &mut result);
+                bar = vec![];
+                first = first && !has_notes;
+                result.push(token.clone());
+            } else {
+                // Metre changes apply from the next bar.
+                if let l::T::Metre(new_metre) = token {
+                    metre = *new_metre;
+                }
+                bar.push(token.clone());
+            }
+        }
+
+        beam_bar(bar, metre, first, &mut result);
+        voices.push(result);
+    }
+
+    tune_ast_three::Tune {
+        prelude: ast.prelude.clone(),
+        voices,
+        voice_properties: ast.voice_properties.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    // Render the beam groups as a string of lengths, e.g. "3 3|".
+    fn groups(abc: &str) -> String {
+        let ast = auto_beam(&representations::abc_to_ast(abc));
+        let mut result = String::new();
+        let mut count = 0;
+        for token in ast.voices[0].iter() {
+            match token {
+                l::T::Note(_) | l::T::Rest(_) => count += 1,
+                l::T::BeamBreak | l::T::SingleBar => {
+                    if count > 0 {
+                        result.push_str(&format!("{} ", count));
+                    }
+                    count = 0;
+                    if *token == l::T::SingleBar {
+                        result = result.trim().to_string();
+                        result.push('|');
+                    }
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn auto_beam_compound_test() {
+        assert_eq!(groups("M:6/8\nL:1/8\nK:G\nGABcde|fgagfe|\n"), "3 3|3 3|");
+        assert_eq!(
+            groups("M:6/8\nL:1/8\nK:G\nd2BcBA|\n"),
+            "2 3|",
+            "Longer notes count towards the group."
+        );
+    }
+
+    #[test]
+    fn auto_beam_simple_test() {
+        assert_eq!(groups("M:4/4\nL:1/8\nK:D\nDFAFdFAF|\n"), "4 4|");
+        assert_eq!(groups("M:2/4\nL:1/8\nK:D\nDFAF|\n"), "2 2|");
+        assert_eq!(groups("M:3/4\nL:1/8\nK:D\nDFAFdF|\n"), "2 2 2|");
+    }
+
+    #[test]
+    fn auto_beam_explicit_test() {
+        assert_eq!(
+            groups("M:4/4\nL:1/8\nK:D\nDF AF dF AF|DFAFdFAF|\n"),
+            "2 2 2 2|4 4|",
+            "Bars with explicit grouping are left alone."
+        );
+    }
+
+    #[test]
+    fn auto_beam_pickup_test() {
+        assert_eq!(
+            groups("M:6/8\nL:1/8\nK:G\nD|GABcde|\n"),
+            "1|3 3|",
+            "Pickup bar is aligned to the end of the bar."
+        );
+        assert_eq!(
+            groups("M:6/8\nL:1/8\nK:G\nDE|GABcde|\n"),
+            "2|3 3|"
+        );
+    }
+
+    #[test]
+    fn auto_beam_long_notes_test() {
+        assert_eq!(
+            groups("X:1\nM:4/4\nL:1/1\nK:G\nG9999999G9999999|\n"),
+            "2|",
+            "Notes too long to sum in u32 don't overflow."
+        );
+    }
+}
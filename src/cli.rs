@@ -0,0 +1,1678 @@
+//! The `abctool` commands, e.g. `abctool scan`. Each command reads its options from the
+//! command line and the config, and fails with a `CliError`, whose exit code says what went wrong.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde_json;
+
+use abc_lexer;
+use abc_writer;
+use config;
+use corpus;
+use diff;
+use export;
+use fingering;
+use lint;
+use lsp;
+use midi;
+use relations;
+use representations;
+use search;
+use server;
+use spellcheck;
+use sqlite;
+use storage;
+use storage::TuneStore;
+use text;
+use tune_ast_three;
+use typeset;
+
+// How often `typeset --watch` checks the file for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+// Why a command failed. Each kind exits with its own code, so scripts can tell them apart.
+#[derive(Debug)]
+enum CliError {
+    // The ABC, or the tunecache, has errors. Exit code 1.
+    Invalid(String),
+
+    // Unrecognised command, option or value. Exit code 2.
+    Usage(String),
+
+    // The config file or environment is wrong. Exit code 3.
+    Config(String),
+
+    // STDIN couldn't be read, e.g. because it isn't UTF-8. Exit code 4.
+    Input(String),
+
+    // A file, database or socket couldn't be read or written. Exit code 5.
+    Io(String),
+
+    // A tune that was asked for isn't there. Exit code 6.
+    NotFound(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Invalid(_) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Config(_) => 3,
+            CliError::Input(_) => 4,
+            CliError::Io(_) => 5,
+            CliError::NotFound(_) => 6,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::Invalid(message)
+            | CliError::Usage(message)
+            | CliError::Config(message)
+            | CliError::Input(message)
+            | CliError::Io(message)
+            | CliError::NotFound(message) => message,
+        }
+    }
+}
+
+// A CliError for input that couldn't be read, e.g. because it isn't UTF-8.
+fn input_error(err: io::Error) -> CliError {
+    match err.kind() {
+        io::ErrorKind::InvalidData => CliError::Input("Input isn't valid UTF-8.".to_string()),
+        _ => CliError::Input(format!("Can't read input: {}", err)),
+    }
+}
+
+/// Get STDIN as a string.
+fn get_stdin() -> Result<String, CliError> {
+    let mut buffer = String::new();
+
+    match io::stdin().read_to_string(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(err) => Err(input_error(err)),
+    }
+}
+
+// The tunes in STDIN, read one at a time so a large collection isn't all in memory at once.
+fn stdin_tunes() -> impl Iterator<Item = Result<(Option<u32>, usize, String), CliError>> {
+    storage::TuneReader::new(io::stdin().lock()).map(|x| x.map_err(input_error))
+}
+
+// Load the config from CONFIG and the environment, and check it.
+fn load_config() -> Result<config::Config, CliError> {
+    let config = config::Config::load().map_err(CliError::Config)?;
+    config.validate().map_err(CliError::Config)?;
+    Ok(config)
+}
+
+// Open the tunes, see `storage::open_store`.
+fn open_store(config: &config::Config) -> Result<Box<dyn storage::TuneStore>, CliError> {
+    match config.base() {
+        Ok(_) => storage::open_store(config).map_err(CliError::Io),
+        Err(message) => Err(CliError::Config(message)),
+    }
+}
+
+// The base directory, which is needed for anything using the tunes.
+fn base_path(config: &config::Config) -> Result<String, CliError> {
+    config
+        .base()
+        .map(|base| base.to_string_lossy().to_string())
+        .map_err(CliError::Config)
+}
+
+// Print lexer errors in the ABC, if there are any, and say whether there were.
+fn report_errors(content: &str) -> bool {
+    report_dialect_errors(content, abc_lexer::Dialect::Standard)
+}
+
+// As `report_errors`, with key notes read in the dialect.
+fn report_dialect_errors(content: &str, dialect: abc_lexer::Dialect) -> bool {
+    let chars = content.chars().collect::<Vec<char>>();
+    let (num_errors, num_unshown, message) =
+        abc_lexer::format_error_message_in_dialect(&chars, dialect);
+
+    if num_errors > 0 {
+        if num_errors == 1 {
+            eprintln!("There was {} error!", num_errors);
+        } else {
+            eprintln!("There were {} errors!", num_errors);
+        }
+
+        eprintln!("{}", message);
+
+        // Don't expect this to happen but explain if it does.
+        if num_unshown > 0 {
+            eprintln!("{} errors weren't shown", num_unshown);
+        }
+    }
+
+    num_errors > 0
+}
+
+// Stop words and synonyms for text search are optional.
+fn load_vocabulary(config: &config::Config) -> Result<text::Vocabulary, String> {
+    match config.vocabulary_path() {
+        Some(ref path) if path.exists() => text::Vocabulary::load(path),
+        _ => {
+            info!("No vocabulary file, not using stop words or synonyms.");
+            Ok(text::Vocabulary::new())
+        }
+    }
+}
+
+// Provenance of tunes is optional.
+fn load_meta(config: &config::Config) -> Result<HashMap<u32, storage::TuneMeta>, String> {
+    match config.meta_path() {
+        Some(ref path) if path.exists() => storage::load_meta(path),
+        _ => {
+            info!("No metadata file, not showing sources or licences.");
+            Ok(HashMap::new())
+        }
+    }
+}
+
+/// Check an ABC file, print the AST.
+fn main_ast() -> Result<(), CliError> {
+    let input = get_stdin()?;
+    let ast = representations::abc_to_ast(&input);
+    eprintln!("{:#?}", ast);
+    Ok(())
+}
+
+/// Describe an ABC file from STDIN in plain text, bar by bar, to STDOUT.
+fn main_describe() -> Result<(), CliError> {
+    let input = get_stdin()?;
+    if report_errors(&input) {
+        return Err(CliError::Invalid(
+            "Can't describe a tune with errors.".to_string(),
+        ));
+    }
+
+    print!(
+        "{}",
+        representations::ast_to_text(&representations::abc_to_ast(&input))
+    );
+    Ok(())
+}
+
+/// Play an ABC file from STDIN as a MIDI file to STDOUT.
+/// `--swing 2` swings quavers by that ratio, `--swing auto` chooses from the tune.
+fn main_midi(args: Vec<String>) -> Result<(), CliError> {
+    let mut swing = midi::Swing::Straight;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--swing" => match args.next() {
+                Some(value) => swing = midi::Swing::parse(Some(value)).map_err(CliError::Usage)?,
+                None => {
+                    return Err(CliError::Usage(
+                        "Expected a ratio or 'auto' for --swing".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(CliError::Usage(format!("Unrecognised option: {}", arg))),
+        }
+    }
+
+    let input = get_stdin()?;
+    if report_errors(&input) {
+        return Err(CliError::Invalid(
+            "Can't play a tune with errors.".to_string(),
+        ));
+    }
+
+    let ast = representations::abc_to_ast(&input);
+    io::stdout()
+        .write_all(&representations::ast_to_midi(&ast, swing))
+        .map_err(|err| CliError::Io(format!("Can't write output: {}", err)))
+}
+
+/// Check an ABC file, from STDIN to STDOUT.
+/// Fails if any tune has errors.
+/// Key notes in the configured dialect are read as they would be by a scan.
+fn main_check(config: &config::Config) -> Result<(), CliError> {
+    let mut tunes = stdin_tunes().peekable();
+
+    let mut num_tunes = 0;
+    let mut num_invalid = 0;
+    while let Some(tune) = tunes.next() {
+        let (x, line_number, tune) = tune?;
+        num_tunes += 1;
+
+        // Say which tune it is when there's more than one.
+        if num_tunes > 1 || tunes.peek().is_some() {
+            match x {
+                Some(x) => eprintln!("X:{} at line {}", x, line_number),
+                None => eprintln!("Tune at line {}", line_number),
+            }
+        }
+
+        if report_dialect_errors(&tune, config.dialect()) {
+            num_invalid += 1;
+        } else {
+            eprintln!("Ok!");
+        }
+    }
+
+    match num_invalid {
+        0 => Ok(()),
+        1 if num_tunes == 1 => Err(CliError::Invalid("The tune has errors.".to_string())),
+        _ => Err(CliError::Invalid(format!(
+            "{} of {} tunes have errors.",
+            num_invalid, num_tunes
+        ))),
+    }
+}
+
+// Check the length of every bar in the ABC from STDIN against the metre. Each bar that's wrong is
+// printed with its line and column in the whole input, so it can be found in a file of tunes.
+fn main_lint(config: &config::Config) -> Result<(), CliError> {
+    let mut num_invalid = 0;
+    let mut num_problems = 0;
+    for tune in stdin_tunes() {
+        let (x, line_number, tune) = tune?;
+        let chars = tune.chars().collect::<Vec<char>>();
+
+        // A tune that doesn't lex can't be read bar by bar, so report that instead.
+        if abc_lexer::format_error_message_in_dialect(&chars, config.dialect()).0 > 0 {
+            match x {
+                Some(x) => eprintln!("X:{} at line {}", x, line_number),
+                None => eprintln!("Tune at line {}", line_number),
+            }
+            report_dialect_errors(&tune, config.dialect());
+            num_invalid += 1;
+            continue;
+        }
+
+        for problem in lint::bar_problems(&tune, config.dialect()) {
+            println!(
+                "Line {}, column {}: {}",
+                line_number + problem.line - 1,
+                problem.column,
+                problem.message()
+            );
+            num_problems += 1;
+        }
+    }
+
+    let mut messages = vec![];
+    match num_problems {
+        0 => (),
+        1 => messages.push("1 bar is the wrong length.".to_string()),
+        _ => messages.push(format!("{} bars are the wrong length.", num_problems)),
+    }
+    match num_invalid {
+        0 => (),
+        1 => messages.push("1 tune has errors.".to_string()),
+        _ => messages.push(format!("{} tunes have errors.", num_invalid)),
+    }
+
+    if messages.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::Invalid(messages.join(" ")))
+    }
+}
+
+/// Write ABC from STDIN back out normalised to STDOUT, with header fields in the usual order and
+/// consistent spacing. `--bars 4` wraps the music every 4 bars, `--bars 0` keeps its lines.
+/// Tunes with errors are written out unchanged, and the command fails.
+fn main_fmt(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut options = abc_writer::FormatOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_ref(), args.next().map(|x| x.parse::<usize>())) {
+            ("--bars", Some(Ok(0))) => options.bars_per_line = None,
+            ("--bars", Some(Ok(value))) => options.bars_per_line = Some(value),
+            _ => {
+                return Err(CliError::Usage(
+                    "Expected --bars and a whole number.".to_string(),
+                ))
+            }
+        }
+    }
+
+    let mut num_tunes = 0;
+    let mut num_invalid = 0;
+    for tune in stdin_tunes() {
+        let (x, line_number, tune) = tune?;
+        num_tunes += 1;
+
+        if num_tunes > 1 {
+            println!();
+        }
+
+        if report_dialect_errors(&tune, config.dialect()) {
+            match x {
+                Some(x) => eprintln!("Left X:{} at line {} as it was.", x, line_number),
+                None => eprintln!("Left the tune at line {} as it was.", line_number),
+            }
+            println!("{}", tune.trim_end());
+            num_invalid += 1;
+        } else {
+            let tune = abc_lexer::to_standard(&tune, config.dialect());
+            let chars = tune.chars().collect::<Vec<char>>();
+            let ast = tune_ast_three::read_as_written(abc_lexer::Lexer::new(&chars));
+            print!("{}", abc_writer::format(&ast, &options));
+        }
+    }
+
+    match num_invalid {
+        0 => Ok(()),
+        1 if num_tunes == 1 => Err(CliError::Invalid("The tune has errors.".to_string())),
+        _ => Err(CliError::Invalid(format!(
+            "{} of {} tunes have errors.",
+            num_invalid, num_tunes
+        ))),
+    }
+}
+
+/// Lex every tune in the $BASE directory, and report on errors and round trips to STDOUT.
+/// `--examples 20` lists up to 20 tune IDs that didn't round trip.
+fn main_corpus_check(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let base_path = base_path(config)?;
+
+    let mut examples = 10;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_ref(), args.next().map(|x| x.parse::<usize>())) {
+            ("--examples", Some(Ok(value))) => examples = value,
+            _ => {
+                return Err(CliError::Usage(
+                    "Expected --examples and a whole number.".to_string(),
+                ))
+            }
+        }
+    }
+
+    let files = storage::abc_files(&base_path).map_err(CliError::Io)?;
+
+    // Tunes in files of several are reported by the IDs a scan gives them.
+    let mut tune_ids = storage::read_tune_ids(&PathBuf::from(&base_path).join("tunecache"));
+    for (_, file_id) in files.iter() {
+        tune_ids.reserve(*file_id);
+    }
+
+    let mut report = corpus::Report::new();
+    for (path, file_id) in files {
+        let mut content = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+            Ok(_) => {
+                for (tune_id, tune) in storage::tunes_in_file(file_id, &content, &mut tune_ids) {
+                    report.add(tune_id, &abc_lexer::to_standard(&tune, config.dialect()));
+                }
+            }
+            Err(e) => error!("Can't read {}: {:?}", path.display(), e),
+        }
+
+        if report.tunes.is_multiple_of(10000) {
+            info!("Checked {} tunes", report.tunes);
+        }
+    }
+
+    print!("{}", report.format(examples));
+    Ok(())
+}
+
+/// Compare two tunes from the tunecache bar by bar, e.g. `diff 123 456`, printing to STDOUT.
+fn main_diff(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let tune_ids: Vec<u32> = args
+        .iter()
+        .map(|x| x.parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .unwrap_or_default();
+
+    if tune_ids.len() != 2 {
+        return Err(CliError::Usage("Expected two tune IDs.".to_string()));
+    }
+
+    let abcs = open_store(config)?;
+
+    let mut asts = vec![];
+    for tune_id in tune_ids.iter() {
+        match abcs.get(*tune_id) {
+            Some(content) => asts.push(representations::abc_to_ast(&content)),
+            None => return Err(CliError::NotFound(format!("There's no tune {}.", tune_id))),
+        }
+    }
+
+    print!(
+        "{}",
+        diff::format(
+            &tune_ids[0].to_string(),
+            &asts[0],
+            &tune_ids[1].to_string(),
+            &asts[1]
+        )
+    );
+    Ok(())
+}
+
+/// Check an ABC file, from STDIN to STDOUT.
+/// With `--no-auto-beam`, only group notes where the ABC has spaces.
+/// With `png`, write a PNG image instead of SVG, sized with `--width` or `--dpi`.
+/// With `--show-errors`, typeset a tune with errors anyway, marking them on the score.
+/// With `--watch FILE`, read FILE instead and write it out again each time it changes.
+fn main_typeset(args: Vec<String>, png: bool, config: &config::Config) -> Result<(), CliError> {
+    let mut typesetting = typeset::Typesetting::new();
+    typesetting.auto_beam = config.auto_beam;
+    let mut show_errors = false;
+    let mut watch: Option<PathBuf> = None;
+    let mut width: Option<String> = None;
+    let mut dpi: Option<String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--no-auto-beam" => typesetting.auto_beam = false,
+            "--show-errors" => show_errors = true,
+            "--watch" => match args.next() {
+                Some(path) => watch = Some(PathBuf::from(path)),
+                None => return Err(CliError::Usage("Expected a file for --watch".to_string())),
+            },
+            "--width" if png => width = args.next().cloned(),
+            "--dpi" if png => dpi = args.next().cloned(),
+            "--fingering" => match args.next().and_then(|x| fingering::Instrument::from_string(x)) {
+                Some(instrument) => typesetting.fingering = Some(instrument),
+                None => {
+                    return Err(CliError::Usage(
+                        "Expected an instrument for --fingering: d-whistle or anglo".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(CliError::Usage(format!("Unrecognised option: {}", arg))),
+        }
+    }
+
+    let size = representations::PngSize::parse(width.as_deref(), dpi.as_deref())
+        .map_err(CliError::Usage)?;
+
+    if let Some(path) = watch {
+        return watch_typeset(&path, &typesetting, png, size);
+    }
+
+    let stdin = get_stdin()?;
+    let has_errors = report_errors(&stdin);
+    if has_errors && !show_errors {
+        return Err(CliError::Invalid(
+            "Can't typeset a tune with errors. Use --show-errors to mark them on the score."
+                .to_string(),
+        ));
+    }
+
+    io::stdout()
+        .write_all(&typeset_abc(&stdin, &typesetting, show_errors, png, size))
+        .map_err(|err| CliError::Io(format!("Can't write output: {}", err)))?;
+
+    // The score is written, but it's still not a valid tune.
+    if has_errors {
+        return Err(CliError::Invalid(
+            "The tune has errors, marked on the score.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Typeset ABC as an SVG, or a PNG of the given size, optionally with its errors marked.
+fn typeset_abc(
+    content: &str,
+    typesetting: &typeset::Typesetting,
+    show_errors: bool,
+    png: bool,
+    size: representations::PngSize,
+) -> Vec<u8> {
+    let ast = representations::abc_to_ast(content);
+    let errors = if show_errors {
+        representations::error_markers(content)
+    } else {
+        vec![]
+    };
+    let drawing = representations::ast_to_drawing_with_errors(&ast, typesetting, &errors);
+
+    if png {
+        representations::png_from_drawing(&drawing, size)
+    } else {
+        format!("{}\n", drawing.render()).into_bytes()
+    }
+}
+
+/// Typeset an ABC file to an SVG or PNG next to it, e.g. tune.svg for tune.abc, and again
+/// whenever the file changes, until interrupted. This gives a live preview while editing the ABC
+/// in any editor, with the SVG open in a viewer that reloads it.
+/// Errors are marked on the score, as with `--show-errors`.
+fn watch_typeset(
+    path: &Path,
+    typesetting: &typeset::Typesetting,
+    png: bool,
+    size: representations::PngSize,
+) -> Result<(), CliError> {
+    let output = path.with_extension(if png { "png" } else { "svg" });
+    if output == path {
+        return Err(CliError::Usage(format!(
+            "Can't write the output over {}",
+            path.display()
+        )));
+    }
+
+    // Written here first, then moved into place, so a viewer never sees half a file.
+    let mut partial = output.clone().into_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    eprintln!(
+        "Watching {}, writing {}. Press Ctrl-C to stop.",
+        path.display(),
+        output.display()
+    );
+
+    let mut last_modified = None;
+    loop {
+        // Editors often replace the file when saving, so it may be missing for a moment.
+        let modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+
+            // Allow for the file vanishing again between the check and the read.
+            if let Ok(content) = fs::read_to_string(path) {
+                report_errors(&content);
+                let result = typeset_abc(&content, typesetting, true, png, size);
+
+                fs::write(&partial, result)
+                    .and_then(|_| fs::rename(&partial, &output))
+                    .map_err(|err| {
+                        CliError::Io(format!("Can't write {}: {}", output.display(), err))
+                    })?;
+                eprintln!("Wrote {}", output.display());
+            }
+        }
+
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+// Typeset one tune from the store into the directory, named by its ID, or say why it couldn't be.
+fn typeset_file(
+    store: &dyn storage::TuneStore,
+    tune_id: u32,
+    typesetting: &typeset::Typesetting,
+    show_errors: bool,
+    png: bool,
+    size: representations::PngSize,
+    out_dir: &Path,
+) -> Result<(), String> {
+    let content = store
+        .get(tune_id)
+        .ok_or_else(|| "The tune is missing from the store.".to_string())?;
+
+    let chars = content.chars().collect::<Vec<char>>();
+    match abc_lexer::format_error_message_from_abc(&chars).0 {
+        0 => (),
+        _ if show_errors => (),
+        1 => return Err("The tune has an error.".to_string()),
+        num_errors => return Err(format!("The tune has {} errors.", num_errors)),
+    }
+
+    let image = typeset_abc(&content, typesetting, show_errors, png, size);
+
+    let extension = if png { "png" } else { "svg" };
+    let path = out_dir.join(format!("{}.{}", tune_id, extension));
+    fs::write(&path, image).map_err(|err| format!("Can't write {}: {}", path.display(), err))
+}
+
+// Typeset every tune in the store into a file of its own in the directory, e.g. for a static
+// site, sharing them out between threads. A manifest.json in the directory lists the tunes that
+// couldn't be typeset, and why.
+fn main_typeset_all(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut typesetting = typeset::Typesetting::new();
+    typesetting.auto_beam = config.auto_beam;
+    let mut show_errors = false;
+    let mut threads = 4;
+    let mut png = false;
+    let mut width: Option<String> = None;
+    let mut dpi: Option<String> = None;
+    let mut out_dir: Option<PathBuf> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--no-auto-beam" => typesetting.auto_beam = false,
+            "--show-errors" => show_errors = true,
+            "--png" => png = true,
+            "--width" => width = args.next().cloned(),
+            "--dpi" => dpi = args.next().cloned(),
+            "--threads" => match args.next().map(|x| x.parse::<usize>()) {
+                Some(Ok(value)) if value > 0 => threads = value,
+                _ => {
+                    return Err(CliError::Usage(
+                        "Expected a whole number above zero for --threads".to_string(),
+                    ))
+                }
+            },
+            _ if out_dir.is_none() && !arg.starts_with("--") => out_dir = Some(PathBuf::from(arg)),
+            _ => return Err(CliError::Usage(format!("Unrecognised option: {}", arg))),
+        }
+    }
+
+    let out_dir = out_dir.ok_or_else(|| {
+        CliError::Usage("Expected a directory to write to, e.g. 'typeset-all scores'".to_string())
+    })?;
+    let size = representations::PngSize::parse(width.as_deref(), dpi.as_deref())
+        .map_err(CliError::Usage)?;
+
+    fs::create_dir_all(&out_dir)
+        .map_err(|err| CliError::Io(format!("Can't create {}: {}", out_dir.display(), err)))?;
+
+    let store = open_store(config)?;
+    let tune_ids = Arc::new(store.tune_ids());
+    let typesetting = Arc::new(typesetting);
+    eprintln!("Typesetting {} tunes...", tune_ids.len());
+
+    // Each thread takes every nth tune, with its own reader on the store.
+    let (tx, rx) = channel();
+    for thread_i in 0..threads {
+        let tx = tx.clone();
+        let store = store.reader().map_err(CliError::Io)?;
+        let tune_ids = tune_ids.clone();
+        let typesetting = typesetting.clone();
+        let out_dir = out_dir.clone();
+        thread::spawn(move || {
+            for (i, tune_id) in tune_ids.iter().enumerate() {
+                if i % threads == thread_i {
+                    // A tune the typesetter, or the store, can't cope with shouldn't stop the rest.
+                    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        typeset_file(
+                            &*store,
+                            *tune_id,
+                            &typesetting,
+                            show_errors,
+                            png,
+                            size,
+                            &out_dir,
+                        )
+                    })).unwrap_or_else(|err| match err.downcast_ref::<&str>() {
+                        Some(message) => Err(format!("Typesetting failed: {}", message)),
+                        None => match err.downcast_ref::<String>() {
+                            Some(message) => Err(format!("Typesetting failed: {}", message)),
+                            None => Err("Typesetting failed.".to_string()),
+                        },
+                    });
+
+                    if tx.send((*tune_id, result)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut num_typeset = 0;
+    let mut failures = vec![];
+    let mut done = HashSet::new();
+    for (i, (tune_id, result)) in rx.iter().enumerate() {
+        done.insert(tune_id);
+        match result {
+            Ok(()) => num_typeset += 1,
+            Err(reason) => failures.push((tune_id, reason)),
+        }
+
+        if (i + 1) % 1000 == 0 {
+            eprintln!("Done {} of {} tunes...", i + 1, tune_ids.len());
+        }
+    }
+
+    // If a thread stopped early, its other tunes were never tried.
+    for tune_id in tune_ids.iter().filter(|tune_id| !done.contains(tune_id)) {
+        failures.push((*tune_id, "The tune wasn't typeset.".to_string()));
+    }
+    failures.sort();
+
+    let manifest = serde_json::json!({
+        "typeset": num_typeset,
+        "failed": failures
+            .iter()
+            .map(|(tune_id, reason)| serde_json::json!({"tune_id": tune_id, "reason": reason}))
+            .collect::<Vec<serde_json::Value>>(),
+    });
+    let manifest_path = out_dir.join("manifest.json");
+    fs::write(&manifest_path, format!("{}\n", manifest))
+        .map_err(|err| CliError::Io(format!("Can't write {}: {}", manifest_path.display(), err)))?;
+
+    eprintln!(
+        "Typeset {} tunes, {} couldn't be. See {}",
+        num_typeset,
+        failures.len(),
+        manifest_path.display()
+    );
+    Ok(())
+}
+
+// Add fingerings for an instrument to the ABC from STDIN as `w:` lines, e.g.
+// `fingering d-whistle`
+fn main_fingering(args: Vec<String>) -> Result<(), CliError> {
+    let instrument = match args.first().and_then(|x| fingering::Instrument::from_string(x)) {
+        Some(instrument) => instrument,
+        None => {
+            return Err(CliError::Usage(
+                "Expected an instrument: d-whistle or anglo".to_string(),
+            ))
+        }
+    };
+
+    print!(
+        "{}",
+        fingering::abc_with_fingerings(&get_stdin()?, instrument)
+    );
+    Ok(())
+}
+
+// Scan ABCs into tunecache.
+fn main_scan(config: &config::Config) -> Result<(), CliError> {
+    let base_path = base_path(config)?;
+    let tune_cache_path = PathBuf::from(&base_path).join("tunecache");
+
+    eprintln!("Refreshing tunecache...");
+    let mut abcs = storage::ReadWriteCache::new(tune_cache_path.clone()).map_err(CliError::Io)?;
+    eprintln!("Loading cache...");
+    abcs.load_cache();
+    eprintln!("Scanning ABC files...");
+    abcs.scan_dir(&base_path, config.dialect())
+        .map_err(CliError::Io)?;
+    eprintln!("Saving tunecache...");
+    abcs.flush().map_err(CliError::Io)?;
+
+    let duplicates = storage::duplicate_groups(&abcs.canonical_hashes());
+    if !duplicates.is_empty() {
+        eprintln!(
+            "Groups of tunes that only differ in whitespace: {}. List them with 'duplicates'.",
+            duplicates.len()
+        );
+    }
+
+    // The SQLite store is made to match the tunecache, all in one transaction.
+    if config.storage == "sqlite" {
+        eprintln!("Saving SQLite database...");
+        let path = PathBuf::from(&base_path).join(sqlite::DATABASE_FILENAME);
+        let tunes = storage::CacheScanner::new(tune_cache_path, None)
+            .iter()
+            .map(|entry| (entry.tune_id, entry.content));
+
+        let (written, deleted) = sqlite::SqliteStore::new(&path, None)
+            .and_then(|mut store| store.sync(tunes))
+            .map_err(CliError::Io)?;
+        eprintln!("Saved {} changed tunes, deleted {}.", written, deleted);
+    }
+    eprintln!("Done!");
+    Ok(())
+}
+
+// List tunes that only differ in whitespace, one group per line, e.g. `12 345 6789`.
+// These are found without clustering, so it's quick to check a collection for them.
+fn main_duplicates(config: &config::Config) -> Result<(), CliError> {
+    let abcs = open_store(config)?;
+
+    let hashes: HashMap<u32, u64> = abcs
+        .iter()
+        .map(|entry| {
+            let canonical_hash = abcs
+                .get_canonical_hash(entry.tune_id)
+                .unwrap_or_else(|| storage::canonical_hash(&entry.content));
+            (entry.tune_id, canonical_hash)
+        })
+        .collect();
+
+    let groups = storage::duplicate_groups(&hashes);
+    for group in groups.iter() {
+        let tune_ids: Vec<String> = group.iter().map(|x| x.to_string()).collect();
+        println!("{}", tune_ids.join(" "));
+    }
+
+    eprintln!(
+        "Groups of tunes that only differ in whitespace: {}.",
+        groups.len()
+    );
+    Ok(())
+}
+
+// Validate the tunecache file's integrity.
+// Fails if the ways of reading it don't agree, or the tunes aren't in order of tune ID.
+fn main_validate(config: &config::Config) -> Result<(), CliError> {
+    let tune_cache_path = PathBuf::from(base_path(config)?).join("tunecache");
+
+    eprintln!("Load read-only...");
+    let mut read_only_abcs =
+        storage::ReadOnlyCache::new(tune_cache_path.clone(), None).map_err(CliError::Io)?;
+    read_only_abcs.load_cache();
+
+    eprintln!("Load read-write...");
+    let mut read_write_abcs =
+        storage::ReadWriteCache::new(tune_cache_path.clone()).map_err(CliError::Io)?;
+    read_write_abcs.load_cache();
+
+    eprintln!("Load Scanner");
+    let scanner = storage::CacheScanner::new(tune_cache_path.clone(), None);
+
+    eprintln!("Compare...");
+    let mut errs = 0;
+
+    // Tunes added through the API are appended out of order, and a tune edited through the API
+    // appears again later. As when the caches load, the last entry for each tune is the one that
+    // counts, so compare by ID.
+    let mut appended = 0;
+    let mut previous_id = None;
+    let mut scanned = HashMap::new();
+    for entry in scanner.iter() {
+        if previous_id.is_some_and(|previous_id| entry.tune_id <= previous_id) {
+            appended += 1;
+        }
+        previous_id = Some(entry.tune_id);
+        scanned.insert(entry.tune_id, entry.content);
+    }
+
+    let mut tune_ids = scanned.keys().cloned().collect::<Vec<u32>>();
+    tune_ids.sort();
+    for tune_id in tune_ids {
+        let rw_str_value = read_write_abcs.get(tune_id);
+        let ro_str_value = read_only_abcs.get(tune_id);
+        let scanner_value = &scanned[&tune_id];
+
+        // Do all 3 agree?
+        let rw_ro_ok = rw_str_value == ro_str_value;
+        let scanner_ok = Some(scanner_value) == rw_str_value.as_ref();
+
+        if !rw_ro_ok || !scanner_ok {
+            eprintln!("Tune: {}", tune_id);
+            eprintln!("RW val: {:?}", rw_str_value);
+            eprintln!("RO val: {:?}", ro_str_value);
+            eprintln!("Scanner val: {:?}", scanner_value);
+            errs += 1;
+        }
+    }
+
+    eprintln!("{} errors", errs);
+    if appended > 0 {
+        eprintln!(
+            "{} tunes appended out of order. Run scan to write them in order.",
+            appended
+        );
+    }
+    if errs > 0 {
+        return Err(CliError::Invalid(format!(
+            "The tunecache has {} inconsistent tunes.",
+            errs
+        )));
+    }
+    Ok(())
+}
+
+fn main_server(config: &config::Config) -> Result<(), CliError> {
+    info!("Server loading ABCs...");
+    let abc_cache = open_store(config)?;
+
+    // Facets and rollup can be turned off to save memory on small deployments.
+    // Without facets there are no filters either.
+    let rollup = config.rollup;
+
+    // Load clusters outside the SearchEngine engine object as we might want to swap in different ones.
+    let groups = if !rollup {
+        info!("Rollup disabled, not loading clusters.");
+        relations::Clusters::new()
+    } else if let Some(path) = config.clusters_path() {
+        info!("Server loading clusters...");
+        relations::Clusters::load(&path)
+    } else {
+        error!("Couldn't work out where to find clusters file!");
+        relations::Clusters::new()
+    };
+
+    if let Some(params) = groups.params {
+        info!(
+            "Clusters built with cutoff: {}, normalization: {}, window: {}",
+            params.cutoff,
+            params.normalization.to_string(),
+            params.window_size
+        );
+    } else if rollup {
+        warn!("Clusters file doesn't record the parameters that built it.");
+    }
+
+    if rollup && groups.generation.is_none() {
+        warn!("Clusters file doesn't record which tunes built it, so can't check it's up to date.");
+    }
+
+    let vocabulary = load_vocabulary(config).map_err(CliError::Io)?;
+    let meta = load_meta(config).map_err(CliError::Io)?;
+
+    info!("Start server");
+
+    let features = search::SearchEngineFeatures::from_config(config);
+
+    // Serve tunes from an engine with nothing indexed until the real one is ready.
+    if config.warm_start {
+        let placeholder = search::SearchEngine::unindexed(
+            abc_cache.reader().map_err(CliError::Io)?,
+            relations::Clusters::new(),
+            search::SearchEngineFeatures {
+                index_text: false,
+                index_melody_interval_term: false,
+                index_melody_degree_term: false,
+                index_melody_contour_term: false,
+                index_melody_transformations: false,
+                index_features: false,
+                rollup: false,
+                interval_window_sizes: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+                sounding_interval_window_sizes: vec![],
+                feature_extractors: None,
+            },
+            text::Vocabulary::new(),
+        );
+
+        let build = move |progress: &search::IndexProgress| {
+            let mut searcher = search::SearchEngine::new_with_progress(
+                abc_cache, groups, features, vocabulary, progress,
+            );
+            searcher.set_meta(meta);
+            searcher
+        };
+
+        return server::main_warm_start(placeholder, build, config).map_err(CliError::Io);
+    }
+
+    let mut searcher = search::SearchEngine::new(abc_cache, groups, features, vocabulary);
+    searcher.set_meta(meta);
+    server::main(searcher, config).map_err(CliError::Io)
+}
+
+// Search by melody with a snippet of ABC from STDIN, printing JSON results to STDOUT.
+// Other search parameters are given as key=value, as in the API, e.g.
+// `search-abc rows=10 transform=inversion`
+fn main_search_abc(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut params = vec![];
+    for arg in args.iter() {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => params.push((key.to_string(), value.to_string())),
+            _ => return Err(CliError::Usage(format!("Expected key=value, got: {}", arg))),
+        }
+    }
+
+    let snippet = get_stdin()?;
+
+    let abc_cache = open_store(config)?;
+
+    // Clusters are only needed for rollup, which is on by default.
+    let groups = match config.clusters_path() {
+        Some(ref path) if path.exists() => relations::Clusters::load(path),
+        _ => relations::Clusters::new(),
+    };
+
+    let index_melody_transformations = params.iter().any(|(key, _)| key == "transform");
+
+    let interval_window_sizes = config.interval_windows.clone();
+
+    let mut searcher = search::SearchEngine::new(
+        abc_cache,
+        groups,
+        search::SearchEngineFeatures {
+            index_text: false,
+            index_melody_interval_term: true,
+            index_melody_degree_term: false,
+            index_melody_contour_term: false,
+            index_melody_transformations,
+            index_features: true,
+            rollup: true,
+            interval_window_sizes,
+            sounding_interval_window_sizes: config.sounding_interval_windows.clone(),
+            feature_extractors: config.feature_extractors.clone(),
+        },
+        text::Vocabulary::new(),
+    );
+    searcher.set_meta(load_meta(config).map_err(CliError::Io)?);
+
+    let query = searcher
+        .parse_abc_query(&snippet, params)
+        .map_err(|err| CliError::Invalid(err.message))?;
+
+    let (num_total_results, num_unique_results, facets, results) = searcher.search(&query);
+
+    let result_body = serde_json::json!({
+        "query": query,
+        "total": num_total_results,
+        "unique": num_unique_results,
+        "results": results,
+        "facets": facets,
+    });
+
+    println!("{}", result_body);
+    Ok(())
+}
+
+// Clustering settings given on the command line. Those that weren't given are None.
+#[derive(Default)]
+struct ClusterOptions {
+    cutoff: Option<f32>,
+    normalization: Option<relations::ScoreNormalization>,
+    window_size: Option<usize>,
+}
+
+impl ClusterOptions {
+    // The settings that were given, with the rest taken from `base`.
+    fn params(&self, base: relations::ClusterParams) -> relations::ClusterParams {
+        relations::ClusterParams {
+            cutoff: self.cutoff.unwrap_or(base.cutoff),
+            normalization: self.normalization.unwrap_or(base.normalization),
+            window_size: self.window_size.unwrap_or(base.window_size),
+        }
+    }
+}
+
+// Parse clustering options from the command line, e.g.
+// `cluster --cutoff 0.7 --normalization doca --window 4`
+// Anything not supplied is left for the caller to fill in, see `ClusterOptions::params`.
+fn parse_cluster_options(args: Vec<String>) -> Result<ClusterOptions, String> {
+    let mut options = ClusterOptions::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("Missing value for '{}'", arg)),
+        };
+
+        match arg.as_ref() {
+            "--cutoff" => match value.parse::<f32>() {
+                Ok(cutoff) if (0.0..=1.0).contains(&cutoff) => options.cutoff = Some(cutoff),
+                _ => {
+                    return Err("Invalid value for '--cutoff'. Must be between 0 and 1.".to_string())
+                }
+            },
+            "--normalization" => match relations::ScoreNormalization::from_string(&value) {
+                Some(normalization) => options.normalization = Some(normalization),
+                _ => {
+                    return Err(
+                        "Invalid value for '--normalization'. Try 'max', 'doca' or 'tfidf'."
+                            .to_string(),
+                    )
+                }
+            },
+            "--window" => match value.parse::<usize>() {
+                Ok(window_size) if window_size > 0 => options.window_size = Some(window_size),
+                _ => return Err("Invalid value for '--window'.".to_string()),
+            },
+            _ => return Err(format!("Unrecognised option '{}'", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+// Search each of the given tune IDs against the melody index and join any matches into groups.
+fn cluster_tunes(
+    searcher: search::SearchEngine,
+    tune_ids: Vec<u32>,
+    params: relations::ClusterParams,
+) -> relations::Clusters {
+    let max_tune_id = searcher.get_max_tune_id();
+    let mut groups = relations::Clusters::with_max_id(max_tune_id as usize);
+
+    for (a, b, _score) in similar_tunes(searcher, tune_ids, params) {
+        groups.add(a, b);
+    }
+
+    groups
+}
+
+// Search each of the given tune IDs against the melody index, returning (a, b, score) for each
+// match scoring at least the cutoff.
+// The search is mostly about zipping through large amounts of contiguous memory
+// and doing simple bit manipulation, so too many threads may cause cache-thrashing
+// and make things worse.
+fn similar_tunes(
+    searcher: search::SearchEngine,
+    tune_ids: Vec<u32>,
+    params: relations::ClusterParams,
+) -> Vec<(usize, usize, f32)> {
+    const THREADS: usize = 4;
+
+    let mut pairs = vec![];
+
+    let searcher_arc = Arc::new(searcher);
+    let tune_ids_arc = Arc::new(tune_ids);
+    let (tx, rx) = channel();
+    for thread_i in 0..THREADS {
+        let tx_clone = tx.clone();
+        let searcher_clone = searcher_arc.clone();
+        let tune_ids_clone = tune_ids_arc.clone();
+        eprintln!("Start thread: {}", thread_i);
+        thread::spawn(move || {
+            let mut pairs = vec![];
+            let mut a_count = 0;
+            for (i, a) in tune_ids_clone.iter().enumerate() {
+                if (i % THREADS) == thread_i {
+                    let results = &searcher_clone
+                        .interval_term_vsm()
+                        .vsm
+                        .search_by_id(
+                            *a as usize,
+                            params.cutoff,
+                            params.normalization,
+                            search::Deadline::none(),
+                        )
+                        .results();
+
+                    for (b, score) in results {
+                        pairs.push((*a as usize, *b as usize, *score));
+                    }
+
+                    a_count += 1;
+
+                    if a_count % 100 == 0 {
+                        eprintln!(
+                            "Done {} tunes (projected total {}) in thread {}...",
+                            a_count,
+                            a_count * THREADS,
+                            thread_i
+                        );
+                    }
+                }
+            }
+
+            tx_clone.send(pairs).unwrap();
+        });
+    }
+
+    for _ in 0..THREADS {
+        pairs.extend(rx.recv().unwrap());
+    }
+
+    pairs
+}
+
+// A search engine with only the melody index needed for clustering, and no clustering info.
+fn cluster_searcher(
+    config: &config::Config,
+    params: relations::ClusterParams,
+) -> Result<search::SearchEngine, CliError> {
+    let abc_cache = open_store(config)?;
+
+    Ok(search::SearchEngine::new(
+        abc_cache,
+        relations::Clusters::new(),
+        search::SearchEngineFeatures {
+            index_text: false,
+            index_melody_interval_term: true,
+            index_melody_degree_term: false,
+            index_melody_contour_term: false,
+            index_melody_transformations: false,
+            index_features: false,
+            rollup: false,
+            interval_window_sizes: vec![params.window_size],
+            sounding_interval_window_sizes: vec![],
+            feature_extractors: None,
+        },
+        text::Vocabulary::new(),
+    ))
+}
+
+// Default cutoffs for `eval-clusters` to try.
+const EVAL_CUTOFFS: &[f32] = &[0.5, 0.6, 0.7, 0.75, 0.8, 0.85, 0.9, 0.95];
+
+// Report how well clusters match a file of hand-labelled duplicate pairs, e.g.
+// `eval-clusters duplicates.txt --cutoffs 0.7,0.8,0.9 --normalization max --window 5`.
+// The current clusters file is evaluated if there is one, then tunes are clustered at each cutoff,
+// with the other settings as for `cluster`.
+fn main_eval_clusters(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut args = args.into_iter();
+    let labels_path = match args.next() {
+        Some(path) => path,
+        None => {
+            return Err(CliError::Usage(
+                "Expected a file of duplicate tune ID pairs.".to_string(),
+            ))
+        }
+    };
+
+    let mut cutoffs = EVAL_CUTOFFS.to_vec();
+    let mut cluster_args = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--cutoffs" {
+            cutoffs = args
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(|x| x.trim().parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .ok()
+                .filter(|x| !x.is_empty() && x.iter().all(|x| *x >= 0.0 && *x <= 1.0))
+                .ok_or_else(|| {
+                    CliError::Usage(
+                        "Invalid value for '--cutoffs'. Use numbers between 0 and 1, e.g. 0.7,0.8"
+                            .to_string(),
+                    )
+                })?;
+        } else {
+            cluster_args.push(arg);
+        }
+    }
+    let params = parse_cluster_options(cluster_args)
+        .map_err(CliError::Usage)?
+        .params(relations::ClusterParams::default());
+
+    let labels = fs::read_to_string(&labels_path)
+        .map_err(|err| CliError::Io(format!("Can't read {}: {}", labels_path, err)))?;
+    let duplicates = relations::parse_duplicate_pairs(&labels)
+        .map_err(|message| CliError::Input(format!("In {}: {}", labels_path, message)))?;
+    eprintln!("Read {} duplicate pairs.", duplicates.len());
+
+    println!("cutoff\tgroups\tprecision\trecall\tf1");
+    let print_row = |name: String, clusters: &relations::Clusters| {
+        let evaluation = clusters.evaluate(&duplicates);
+        println!(
+            "{}\t{}\t{:.3}\t{:.3}\t{:.3}",
+            name,
+            clusters.num_groups(),
+            evaluation.precision(),
+            evaluation.recall(),
+            evaluation.f1()
+        );
+    };
+
+    if let Some(path) = config.clusters_path().filter(|x| x.exists()) {
+        let current = relations::Clusters::load(&path);
+        let name = match current.params {
+            Some(params) => format!("current ({})", params.cutoff),
+            None => "current".to_string(),
+        };
+        print_row(name, &current);
+    }
+
+    // Search once at the lowest cutoff, then group the matches that reach each cutoff.
+    let lowest = cutoffs.iter().cloned().fold(1.0, f32::min);
+    let searcher = cluster_searcher(config, params)?;
+    let max_tune_id = searcher.get_max_tune_id();
+    let pairs = similar_tunes(
+        searcher,
+        (0..max_tune_id).collect(),
+        relations::ClusterParams {
+            cutoff: lowest,
+            ..params
+        },
+    );
+
+    for cutoff in cutoffs.iter() {
+        let mut clusters = relations::Clusters::with_max_id(max_tune_id as usize);
+        for (a, b, score) in pairs.iter() {
+            if score >= cutoff {
+                clusters.add(*a, *b);
+            }
+        }
+        print_row(cutoff.to_string(), &clusters);
+    }
+
+    Ok(())
+}
+
+// Analyze and cluster tunes into groups, save cluster info to disk.
+// With `--incremental`, load the existing clusters file and only search tunes that aren't yet
+// in a group, e.g. ones added since the last run. Options that differ from the settings the
+// existing clusters were built with are an error.
+// Work in progress.
+// TODO maybe use the SearchEngine object now?
+fn main_cluster_preprocess(mut args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    eprintln!("Pre-process clusters.");
+
+    let incremental = args.iter().any(|x| x == "--incremental");
+    args.retain(|x| x != "--incremental");
+
+    let options = parse_cluster_options(args).map_err(CliError::Usage)?;
+
+    let path = PathBuf::from(base_path(config)?).join("clusters");
+
+    // In incremental mode, start from the existing groups.
+    // They must be extended with the same settings they were built with.
+    let existing = if incremental {
+        Some(relations::Clusters::load(&path))
+    } else {
+        None
+    };
+
+    let params = match existing.as_ref().and_then(|x| x.params) {
+        Some(existing_params) => {
+            if options.params(existing_params) != existing_params {
+                return Err(CliError::Usage(format!(
+                    "The existing clusters were built with cutoff {}, normalization {} and \
+                     window {}. Leave out the options, or give the same ones.",
+                    existing_params.cutoff,
+                    existing_params.normalization,
+                    existing_params.window_size
+                )));
+            }
+            existing_params
+        }
+        _ => options.params(relations::ClusterParams::default()),
+    };
+
+    eprintln!(
+        "Cutoff: {}, normalization: {}, window: {}",
+        params.cutoff,
+        params.normalization,
+        params.window_size
+    );
+
+    let searcher = cluster_searcher(config, params)?;
+
+    let max_tune_id = searcher.get_max_tune_id();
+
+    // Record which tunes these clusters were built from, so the server can tell if they're stale.
+    let generation = searcher.abc_cache.generation();
+
+    let start = SystemTime::now();
+
+    let mut groups = match existing {
+        Some(mut existing) => {
+            // Tunes added since the clusters file was written won't be represented yet.
+            existing.ensure_max_id(max_tune_id as usize);
+
+            let tune_ids = existing.ungrouped_ids(max_tune_id as usize);
+            eprintln!("Found {} ungrouped tunes.", tune_ids.len());
+
+            let new_groups = cluster_tunes(searcher, tune_ids, params);
+            existing.extend(new_groups);
+            existing
+        }
+        None => cluster_tunes(searcher, (0..max_tune_id).collect(), params),
+    };
+    groups.params = Some(params);
+    groups.generation = Some(generation);
+
+    let end = SystemTime::now();
+
+    eprintln!("Took {:?}", end.duration_since(start));
+
+    // This output is suitable for the current (legacy?) Clojure search engine.
+    groups.save(&path).map_err(CliError::Io)?;
+
+    groups.print_debug();
+    Ok(())
+}
+
+// Export a sparse feature matrix of the whole corpus to STDOUT, e.g.
+// `export-features --format csv --terms features`
+// `export-features --format libsvm --vocabulary columns.csv`
+fn main_export_features(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut format = export::Format::LibSVM;
+    let mut columns = export::Columns {
+        features: true,
+        intervals: true,
+    };
+    let mut vocabulary_path: Option<PathBuf> = None;
+    let mut window_size = relations::DEFAULT_INTERVAL_WINDOW_SIZE;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(CliError::Usage(format!("Missing value for option {}", arg))),
+        };
+
+        match arg.as_ref() {
+            "--format" => match export::Format::from_string(value) {
+                Some(value) => format = value,
+                None => {
+                    return Err(CliError::Usage(
+                        "Format must be 'libsvm' or 'csv'.".to_string(),
+                    ))
+                }
+            },
+            "--terms" => {
+                columns = match value.as_ref() {
+                    "features" => export::Columns {
+                        features: true,
+                        intervals: false,
+                    },
+                    "intervals" => export::Columns {
+                        features: false,
+                        intervals: true,
+                    },
+                    "all" => export::Columns {
+                        features: true,
+                        intervals: true,
+                    },
+                    _ => {
+                        return Err(CliError::Usage(
+                            "Terms must be 'features', 'intervals' or 'all'.".to_string(),
+                        ))
+                    }
+                }
+            }
+            "--vocabulary" => vocabulary_path = Some(PathBuf::from(value)),
+            "--window" => match value.parse::<usize>() {
+                Ok(value) if value > 0 => window_size = value,
+                _ => {
+                    return Err(CliError::Usage(
+                        "Window must be a whole number greater than 0.".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(CliError::Usage(format!("Unrecognised option: {}", arg))),
+        }
+    }
+
+    let abc_cache = open_store(config)?;
+
+    let searcher = search::SearchEngine::new(
+        abc_cache,
+        relations::Clusters::new(),
+        search::SearchEngineFeatures {
+            index_text: false,
+            index_melody_interval_term: columns.intervals,
+            index_melody_degree_term: false,
+            index_melody_contour_term: false,
+            index_melody_transformations: false,
+            index_features: columns.features,
+            rollup: false,
+            interval_window_sizes: vec![window_size],
+            sounding_interval_window_sizes: vec![],
+            feature_extractors: None,
+        },
+        text::Vocabulary::new(),
+    );
+
+    let matrix = export::build_matrix(&searcher, columns);
+    eprintln!(
+        "Exporting {} tunes with {} columns.",
+        matrix.rows.len(),
+        matrix.names.len()
+    );
+
+    if let Some(path) = vocabulary_path {
+        File::create(&path)
+            .map_err(|err| format!("Can't create vocabulary file: {:?}", err))
+            .and_then(|file| export::write_vocabulary(&matrix, format, &mut BufWriter::new(file)))
+            .map_err(CliError::Io)?;
+    }
+
+    let stdout = io::stdout();
+    export::write_matrix(&matrix, format, &mut BufWriter::new(stdout.lock())).map_err(CliError::Io)
+}
+
+// Report likely typos in header text across the whole corpus, e.g.
+// `spellcheck --rare 2 --frequent 20`
+fn main_spellcheck(args: Vec<String>, config: &config::Config) -> Result<(), CliError> {
+    let mut params = spellcheck::SpellCheckParams::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next().map(|x| x.parse::<usize>()) {
+            Some(Ok(value)) => value,
+            _ => {
+                return Err(CliError::Usage(format!(
+                    "Expected a whole number for option {}",
+                    arg
+                )))
+            }
+        };
+
+        match arg.as_ref() {
+            "--rare" => params.max_rare = value,
+            "--frequent" => params.min_frequent = value,
+            "--min-length" => params.min_length = value,
+            _ => return Err(CliError::Usage(format!("Unrecognised option: {}", arg))),
+        }
+    }
+
+    let vocabulary = load_vocabulary(config).map_err(CliError::Io)?;
+
+    let abc_cache = open_store(config)?;
+
+    let searcher = search::SearchEngine::new(
+        abc_cache,
+        relations::Clusters::new(),
+        search::SearchEngineFeatures {
+            index_text: true,
+            index_melody_interval_term: false,
+            index_melody_degree_term: false,
+            index_melody_contour_term: false,
+            index_melody_transformations: false,
+            index_features: false,
+            rollup: false,
+            interval_window_sizes: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+            sounding_interval_window_sizes: vec![],
+            feature_extractors: None,
+        },
+        vocabulary,
+    );
+
+    let fields = vec![
+        ("title", &searcher.text_vsm),
+        ("composer", &searcher.composer_vsm),
+        ("origin", &searcher.origin_vsm),
+        ("rhythm", &searcher.rhythm_vsm),
+    ];
+
+    println!("field\tword\tsuggestion\tword_tunes\tsuggestion_tunes\ttune_ids");
+    let mut total = 0;
+    for (field, vsm) in fields {
+        for suggestion in spellcheck::find_suggestions(vsm, &params) {
+            let tune_ids: Vec<String> = suggestion.tune_ids.iter().map(|x| x.to_string()).collect();
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                field,
+                suggestion.word,
+                suggestion.suggestion,
+                suggestion.word_count,
+                suggestion.suggestion_count,
+                tune_ids.join(",")
+            );
+            total += 1;
+        }
+    }
+
+    eprintln!("Found {} possible typos.", total);
+    Ok(())
+}
+
+fn main_unrecognised() -> Result<(), CliError> {
+    Err(CliError::Usage(
+        "Unrecognised command. Try:
+ - scan - Scan tune DB individual tunes into a single $BASE/tunecache file
+ - validate - Validate integrity of the tunecache file.
+ - duplicates - List groups of tunes that only differ in whitespace, one group per line.
+ - cluster - Using the tunecache, cluster tunes and sage to $BASE/clusters file.
+             Options: --cutoff 0.8 --normalization max|doca|tfidf --window 5
+             --incremental only clusters tunes not already in a group, with the same options.
+ - eval-clusters - Report precision and recall of clustering against a file of hand-labelled
+             duplicate tune ID pairs, one pair per line, e.g. 'eval-clusters duplicates.txt'.
+             Options as cluster, plus --cutoffs 0.7,0.8,0.9 to try.
+ - server - Run the server. run 'scan' and 'cluster' first!
+ - check - Parse an ABC file from STDIN and check to see if it parses and get error messages.
+ - fmt - Write ABC from STDIN back out normalised, with header fields in the usual order,
+             consistent spacing and --bars 4 bars to a line, or --bars 0 to keep the lines.
+ - lint - Check that every bar of the ABC from STDIN is as long as the metre says, allowing for
+             pick-ups and endings, and print the line and column of each one that isn't.
+ - corpus-check - Lex every ABC file in $BASE and report counts of each kind of error, tunes
+             that parse cleanly and tunes that read differently after being written back out.
+             Options: --examples 10 lists that many tune IDs that didn't round trip.
+ - diff - Compare two tunes in the tunecache bar by bar, e.g. 'diff 123 456'.
+ - ast - Parse an ABC file from  STDIN and pring out the abstract syntax tree.
+ - describe - Describe an ABC file from STDIN in plain text, with each note bar by bar, for
+             screen readers and braille displays.
+ - typeset - Parse and ABC file from STDIN and print out an SVG file.
+             --no-auto-beam only beams notes where the ABC groups them.
+             --fingering d-whistle|anglo shows how to play each note under the stave.
+             --show-errors typesets what it can of a tune with errors, marking them in red.
+             --watch tune.abc reads the file instead, and writes tune.svg each time it changes.
+ - typeset-png - As typeset, but print out a PNG image. Same options, plus
+             --width 800 for the width in pixels, or --dpi 192, where the SVG is 96 DPI.
+ - typeset-all - Typeset every tune in the tunecache into a directory, e.g. 'typeset-all scores',
+             as 123.svg and so on, with a manifest.json of the tunes that couldn't be typeset.
+             Options as typeset, plus --threads 4, and --png with --width or --dpi for PNGs.
+ - midi - Parse an ABC file from STDIN and print out a MIDI file.
+             --swing 2 plays quavers on the beat twice as long as the ones after them,
+             --swing auto chooses from the tune's rhythm and metre.
+ - fingering - Add fingerings to an ABC file from STDIN as w: lines, e.g. 'fingering d-whistle'.
+ - export-features - Write a sparse matrix of tune features and melody terms to STDOUT.
+             Options: --format libsvm|csv --terms features|intervals|all --window 5
+             --vocabulary FILE writes the column names to FILE.
+ - search-abc - Search for tunes by melody, with a snippet of ABC from STDIN. Prints JSON.
+             Options as the API, e.g. rows=10 transform=retrograde|inversion
+ - spellcheck - Report rare words in header text that look like typos of frequent ones.
+             Options: --rare 2 --frequent 20 --min-length 4
+ - lsp - Run a Language Server over STDIN / STDOUT, giving editors diagnostics and symbols."
+            .to_string(),
+    ))
+}
+
+/// Run the command named by the first argument, exiting with its exit code if it fails.
+pub fn main() {
+    let mut args = env::args();
+
+    // The config is checked before anything else, so mistakes show up straight away.
+    let result = load_config().and_then(|config| match args.nth(1) {
+        Some(first) => match first.as_ref() {
+            "scan" => main_scan(&config),
+            "validate" => main_validate(&config),
+            "duplicates" => main_duplicates(&config),
+            "server" => main_server(&config),
+            "cluster" => main_cluster_preprocess(args.collect(), &config),
+            "check" => main_check(&config),
+            "fmt" => main_fmt(args.collect(), &config),
+            "lint" => main_lint(&config),
+            "corpus-check" => main_corpus_check(args.collect(), &config),
+            "diff" => main_diff(args.collect(), &config),
+            "ast" => main_ast(),
+            "describe" => main_describe(),
+            "typeset" => main_typeset(args.collect(), false, &config),
+            "typeset-png" => main_typeset(args.collect(), true, &config),
+            "typeset-all" => main_typeset_all(args.collect(), &config),
+            "midi" => main_midi(args.collect()),
+            "fingering" => main_fingering(args.collect()),
+            "lsp" => {
+                lsp::main();
+                Ok(())
+            }
+            "search-abc" => main_search_abc(args.collect(), &config),
+            "spellcheck" => main_spellcheck(args.collect(), &config),
+            "export-features" => main_export_features(args.collect(), &config),
+            "eval-clusters" => main_eval_clusters(args.collect(), &config),
+            _ => main_unrecognised(),
+        },
+        _ => main_unrecognised(),
+    });
+
+    if let Err(err) = result {
+        eprintln!("Error! {}", err.message());
+        process::exit(err.exit_code());
+    }
+}
@@ -0,0 +1,464 @@
+//! Config
+//! Settings for the command line tools and server, from an optional TOML file named by the CONFIG
+//! environment variable, e.g.
+//!
+//! ```toml
+//! base = "/var/tunes"
+//! html_templates = "/var/tunes/templates"
+//! http_bind = "0.0.0.0:8765"
+//! interval_windows = [3, 5]
+//! ```
+//!
+//! Any setting can be overridden by an environment variable of the same name in capitals, e.g.
+//! BASE or HTTP_BIND, so a deployment can do without a file. Lists are comma-separated there.
+//! Logging is the exception, and is only set with LOG_LEVEL and LOG_FORMAT, as it starts before
+//! the config is read.
+//! The config is loaded and checked once at startup, then passed to whatever needs it.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use abc_lexer;
+use features;
+use relations;
+use toml;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Directory of ABC files, and where the tunecache, clusters and so on are kept.
+    pub base: Option<PathBuf>,
+
+    // Where tunes are kept, see `storage::open_store`.
+    pub storage: String,
+
+    // How key notes are spelled in the ABC files, e.g. "german" for "K:H", see
+    // `abc_lexer::Dialect`. A scan stores them with the standard spelling.
+    pub dialect: String,
+
+    // Only use tunes up to this ID, to work with a subset when profiling or debugging.
+    pub debug_max_id: Option<u32>,
+
+    // Number of intervals in each melody search term. Each is a separate index.
+    pub interval_windows: Vec<usize>,
+
+    // Number of intervals in each term of the indexes of sounding intervals, searched with
+    // `intervals=sounding`. None by default.
+    pub sounding_interval_windows: Vec<usize>,
+
+    // Index features, for facets and filters.
+    pub facets: bool,
+
+    // Names of the feature extractors to run, e.g. ["metre", "parts"]. All of them if not set.
+    pub feature_extractors: Option<Vec<String>>,
+
+    // Load clusters, to roll up and group results.
+    pub rollup: bool,
+
+    // Index the retrograde and inversion of each tune too.
+    pub index_transformations: bool,
+
+    // Server address and port.
+    pub http_bind: String,
+
+    // Worker threads handling requests.
+    pub http_threads: usize,
+
+    // Give up on a search after this many milliseconds, or never if 0.
+    pub search_timeout_ms: u64,
+
+    // Log searches that take longer than this many milliseconds, with their parameters.
+    pub slow_query_ms: u64,
+
+    // Start serving tunes straight away, and index them in the background. Searches aren't
+    // available until it's done.
+    pub warm_start: bool,
+
+    // Directory of Handlebars templates for the HTML pages. Without one, only the API is served.
+    pub html_templates: Option<PathBuf>,
+
+    // Secret for the admin endpoints, which are disabled without one.
+    pub admin_token: Option<String>,
+
+    // Origins allowed to call the API from a browser, or "*" for any.
+    pub cors_origins: Vec<String>,
+
+    // Beam notes automatically when typesetting.
+    pub auto_beam: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            base: None,
+            storage: "tunecache".to_string(),
+            dialect: "standard".to_string(),
+            debug_max_id: None,
+            interval_windows: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+            sounding_interval_windows: vec![],
+            facets: true,
+            feature_extractors: None,
+            rollup: true,
+            index_transformations: false,
+            http_bind: "0.0.0.0:8000".to_string(),
+            http_threads: 4,
+            search_timeout_ms: 10_000,
+            slow_query_ms: 1_000,
+            warm_start: false,
+            html_templates: None,
+            admin_token: None,
+            cors_origins: vec![],
+            auto_beam: true,
+        }
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!(
+            "Invalid value for {}: {}. Use true or false.",
+            name, value
+        )),
+    }
+}
+
+fn parse_number<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for {}: {}. Use a whole number.", name, value))
+}
+
+// Comma-separated list, ignoring spaces and empty entries.
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+impl Config {
+    // Read the file named by CONFIG, if there is one, then apply environment variables.
+    pub fn load() -> Result<Config, String> {
+        let mut config = match env::var("CONFIG") {
+            Ok(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|err| format!("Can't read config file {}: {}", path, err))?;
+                Config::from_toml(&text).map_err(|message| format!("In {}: {}", path, message))?
+            }
+            Err(_) => Config::default(),
+        };
+
+        config.apply_overrides(|name| env::var(name).ok())?;
+        Ok(config)
+    }
+
+    // Settings from a TOML file. Anything missing has its default.
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|err| format!("Invalid config: {}", err))
+    }
+
+    // Replace settings with those given by `var`, which looks up a variable by name, e.g. from
+    // the environment.
+    pub fn apply_overrides<F>(&mut self, var: F) -> Result<(), String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(value) = var("BASE") {
+            self.base = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("STORAGE") {
+            self.storage = value;
+        }
+        if let Some(value) = var("DIALECT") {
+            self.dialect = value;
+        }
+        if let Some(value) = var("DEBUG_MAX_ID") {
+            self.debug_max_id = Some(parse_number("DEBUG_MAX_ID", &value)?);
+        }
+        if let Some(value) = var("INTERVAL_WINDOWS") {
+            self.interval_windows = parse_list(&value)
+                .iter()
+                .map(|x| parse_number("INTERVAL_WINDOWS", x))
+                .collect::<Result<Vec<usize>, String>>()?;
+        }
+        if let Some(value) = var("SOUNDING_INTERVAL_WINDOWS") {
+            self.sounding_interval_windows = parse_list(&value)
+                .iter()
+                .map(|x| parse_number("SOUNDING_INTERVAL_WINDOWS", x))
+                .collect::<Result<Vec<usize>, String>>()?;
+        }
+        if let Some(value) = var("FACETS") {
+            self.facets = parse_bool("FACETS", &value)?;
+        }
+        if let Some(value) = var("FEATURE_EXTRACTORS") {
+            self.feature_extractors = Some(parse_list(&value));
+        }
+        if let Some(value) = var("ROLLUP") {
+            self.rollup = parse_bool("ROLLUP", &value)?;
+        }
+        if let Some(value) = var("INDEX_TRANSFORMATIONS") {
+            self.index_transformations = parse_bool("INDEX_TRANSFORMATIONS", &value)?;
+        }
+        if let Some(value) = var("HTTP_BIND") {
+            self.http_bind = value;
+        }
+        if let Some(value) = var("HTTP_THREADS") {
+            self.http_threads = parse_number("HTTP_THREADS", &value)?;
+        }
+        if let Some(value) = var("SEARCH_TIMEOUT_MS") {
+            self.search_timeout_ms = parse_number("SEARCH_TIMEOUT_MS", &value)?;
+        }
+        if let Some(value) = var("SLOW_QUERY_MS") {
+            self.slow_query_ms = parse_number("SLOW_QUERY_MS", &value)?;
+        }
+        if let Some(value) = var("WARM_START") {
+            self.warm_start = parse_bool("WARM_START", &value)?;
+        }
+        if let Some(value) = var("HTML_TEMPLATES") {
+            self.html_templates = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("ADMIN_TOKEN") {
+            self.admin_token = Some(value);
+        }
+        if let Some(value) = var("CORS_ORIGINS") {
+            self.cors_origins = parse_list(&value);
+        }
+        if let Some(value) = var("AUTO_BEAM") {
+            self.auto_beam = parse_bool("AUTO_BEAM", &value)?;
+        }
+
+        // An empty token would let anyone in.
+        self.admin_token = self.admin_token.take().filter(|token| !token.is_empty());
+        self.cors_origins = self
+            .cors_origins
+            .iter()
+            .map(|x| x.trim_end_matches('/').to_string())
+            .collect();
+
+        Ok(())
+    }
+
+    // Check the settings make sense, and the directories they name are there.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref base) = self.base {
+            if !base.is_dir() {
+                return Err(format!(
+                    "The base directory {} doesn't exist.",
+                    base.display()
+                ));
+            }
+        }
+
+        if let Some(ref html_templates) = self.html_templates {
+            if !html_templates.is_dir() {
+                return Err(format!(
+                    "The HTML templates directory {} doesn't exist.",
+                    html_templates.display()
+                ));
+            }
+        }
+
+        match self.storage.as_ref() {
+            "tunecache" | "mmap" | "directory" | "sqlite" => (),
+            _ => {
+                return Err(format!(
+                    "Unknown storage '{}'. Use 'tunecache', 'mmap', 'directory' or 'sqlite'.",
+                    self.storage
+                ))
+            }
+        }
+
+        if abc_lexer::Dialect::from_name(&self.dialect).is_none() {
+            return Err(format!(
+                "Unknown dialect '{}'. Use 'standard', 'german' or 'solfege'.",
+                self.dialect
+            ));
+        }
+
+        if self.interval_windows.is_empty() || self.interval_windows.contains(&0) {
+            return Err("Interval windows should be whole numbers above zero.".to_string());
+        }
+
+        if self.sounding_interval_windows.contains(&0) {
+            return Err(
+                "Sounding interval windows should be whole numbers above zero.".to_string(),
+            );
+        }
+
+        if let Some(ref names) = self.feature_extractors {
+            features::FeatureRegistry::with_names(names)?;
+        }
+
+        if self.http_threads == 0 {
+            return Err("HTTP threads should be a whole number above zero.".to_string());
+        }
+
+        if self.http_bind.is_empty() {
+            return Err("The HTTP bind address is empty.".to_string());
+        }
+
+        Ok(())
+    }
+
+    // The dialect of the ABC files. It's checked by `validate`.
+    pub fn dialect(&self) -> abc_lexer::Dialect {
+        abc_lexer::Dialect::from_name(&self.dialect).unwrap_or(abc_lexer::Dialect::Standard)
+    }
+
+    // The base directory, which most commands need.
+    pub fn base(&self) -> Result<&Path, String> {
+        match self.base {
+            Some(ref base) => Ok(base),
+            None => Err(
+                "Base directory config not supplied. Set BASE, or base in the config file."
+                    .to_string(),
+            ),
+        }
+    }
+
+    // Path of a file in the base directory, if there is one.
+    fn base_file(&self, name: &str) -> Option<PathBuf> {
+        self.base.as_ref().map(|base| base.join(name))
+    }
+
+    pub fn tune_cache_path(&self) -> Option<PathBuf> {
+        self.base_file("tunecache")
+    }
+
+    pub fn clusters_path(&self) -> Option<PathBuf> {
+        self.base_file("clusters")
+    }
+
+    // Directory of cached preview images.
+    pub fn previews_path(&self) -> Option<PathBuf> {
+        self.base_file("previews")
+    }
+
+    // Stop words and synonyms for text search.
+    pub fn vocabulary_path(&self) -> Option<PathBuf> {
+        self.base_file("vocabulary")
+    }
+
+    // Source URL and licence of tunes.
+    pub fn meta_path(&self) -> Option<PathBuf> {
+        self.base_file("meta.csv")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn from_toml_test() {
+        let config = Config::from_toml(
+            "base = \"test_resources\"\nhttp_bind = \"127.0.0.1:8765\"\n\
+             interval_windows = [3, 5]\nrollup = false\n",
+        ).unwrap();
+
+        assert_eq!(config.base, Some(PathBuf::from("test_resources")));
+        assert_eq!(config.http_bind, "127.0.0.1:8765");
+        assert_eq!(config.interval_windows, vec![3, 5]);
+        assert!(!config.rollup);
+        assert!(config.facets, "Missing settings have their defaults.");
+        assert_eq!(
+            config.tune_cache_path(),
+            Some(PathBuf::from("test_resources/tunecache"))
+        );
+        assert!(config.validate().is_ok());
+
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+        assert!(
+            Config::from_toml("bsae = \"/tmp\"").is_err(),
+            "Misspelt settings are errors."
+        );
+        assert!(Config::from_toml("http_threads = \"four\"").is_err());
+    }
+
+    #[test]
+    fn apply_overrides_test() {
+        let mut vars = HashMap::new();
+        vars.insert("BASE", "test_resources");
+        vars.insert("INTERVAL_WINDOWS", "3, 5");
+        vars.insert("SOUNDING_INTERVAL_WINDOWS", "4");
+        vars.insert("FACETS", "false");
+        vars.insert("FEATURE_EXTRACTORS", "metre, parts");
+        vars.insert("WARM_START", "true");
+        vars.insert("SEARCH_TIMEOUT_MS", "0");
+        vars.insert(
+            "CORS_ORIGINS",
+            "https://example.com/,https://www.example.com",
+        );
+        vars.insert("ADMIN_TOKEN", "");
+        vars.insert("DIALECT", "german");
+
+        let mut config = Config::from_toml("base = \"/nowhere\"\nadmin_token = \"x\"").unwrap();
+        config
+            .apply_overrides(|name| vars.get(name).map(|x| x.to_string()))
+            .unwrap();
+
+        assert_eq!(config.base, Some(PathBuf::from("test_resources")));
+        assert_eq!(config.interval_windows, vec![3, 5]);
+        assert_eq!(config.sounding_interval_windows, vec![4]);
+        assert!(!config.facets);
+        assert!(config.warm_start);
+        assert_eq!(config.search_timeout_ms, 0);
+        assert_eq!(config.slow_query_ms, 1_000);
+        assert_eq!(
+            config.feature_extractors,
+            Some(vec!["metre".to_string(), "parts".to_string()])
+        );
+        assert_eq!(
+            config.cors_origins,
+            vec!["https://example.com", "https://www.example.com"]
+        );
+        assert_eq!(config.admin_token, None, "An empty token is no token.");
+        assert_eq!(config.dialect(), abc_lexer::Dialect::German);
+
+        vars.insert("FACETS", "no");
+        assert!(config
+            .apply_overrides(|name| vars.get(name).map(|x| x.to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn validate_test() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        assert!(config.base().is_err());
+        assert_eq!(config.clusters_path(), None);
+
+        config.base = Some(PathBuf::from("test_resources/missing"));
+        assert!(config.validate().is_err());
+
+        config.base = None;
+        config.html_templates = Some(PathBuf::from("test_resources/missing"));
+        assert!(config.validate().is_err());
+
+        config.html_templates = None;
+        config.storage = "floppy".to_string();
+        assert!(config.validate().is_err());
+
+        config.storage = "sqlite".to_string();
+        config.dialect = "dutch".to_string();
+        assert!(config.validate().is_err());
+
+        config.dialect = "solfege".to_string();
+        config.interval_windows = vec![0];
+        assert!(config.validate().is_err());
+
+        config.interval_windows = vec![5];
+        config.sounding_interval_windows = vec![3, 0];
+        assert!(config.validate().is_err());
+
+        config.sounding_interval_windows = vec![];
+        config.feature_extractors = Some(vec!["metre".to_string(), "tempo".to_string()]);
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Check how well the lexer copes with a corpus of real-world ABC.
+//! Counts of each kind of error, and of tunes that don't survive being written back out as ABC,
+//! show which gaps in the lexer matter most.
+
+use abc_lexer as l;
+use abc_writer;
+use representations;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Most common details to show for each kind of error, e.g. the characters that weren't expected.
+const DETAILS_SHOWN: usize = 5;
+
+#[derive(Debug, Default)]
+struct ErrorCounts {
+    errors: usize,
+    tunes: usize,
+    details: HashMap<String, usize>,
+}
+
+/// Statistics for a corpus, built up one tune at a time.
+#[derive(Debug)]
+pub struct Report {
+    pub tunes: usize,
+
+    // Tunes without any errors.
+    pub parsed: usize,
+
+    // By name of the LexError variant.
+    errors: HashMap<String, ErrorCounts>,
+
+    // Tunes whose AST is different after writing it as ABC and reading it back.
+    pub diverged: Vec<u32>,
+}
+
+// Split an error into the name of its variant and its detail, if it has one,
+// e.g. "UnexpectedBodyChar" and "'('".
+fn error_kind(error: &l::LexError) -> (String, String) {
+    let kind = error.kind();
+    let debug = format!("{:?}", error);
+    if debug.len() > kind.len() {
+        let detail = debug[kind.len() + 1..debug.len() - 1].to_string();
+        (kind, detail)
+    } else {
+        (kind, String::new())
+    }
+}
+
+// Does the tune read the same after being written out as ABC?
+fn round_trips(content: &str) -> bool {
+    let original = representations::abc_to_ast(content);
+    let read = representations::abc_to_ast(&abc_writer::tune(&original));
+
+    original.prelude == read.prelude && original.voices == read.voices
+}
+
+fn percent(count: usize, total: usize) -> f32 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f32 * 100.0 / total as f32
+    }
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            tunes: 0,
+            parsed: 0,
+            errors: HashMap::new(),
+            diverged: vec![],
+        }
+    }
+
+    pub fn add(&mut self, tune_id: u32, content: &str) {
+        self.tunes += 1;
+
+        let chars = content.chars().collect::<Vec<char>>();
+        let errors = l::Lexer::new(&chars).collect_errors();
+
+        if errors.is_empty() {
+            self.parsed += 1;
+        }
+
+        let mut kinds_seen = vec![];
+        for (_, _, error) in errors.iter() {
+            let (kind, detail) = error_kind(error);
+            let counts = self.errors.entry(kind.clone()).or_default();
+            counts.errors += 1;
+            if !detail.is_empty() {
+                *counts.details.entry(detail).or_insert(0) += 1;
+            }
+
+            if !kinds_seen.contains(&kind) {
+                counts.tunes += 1;
+                kinds_seen.push(kind);
+            }
+        }
+
+        if !round_trips(content) {
+            self.diverged.push(tune_id);
+        }
+    }
+
+    /// Summary for people, with up to this many example tune IDs.
+    pub fn format(&self, examples: usize) -> String {
+        let mut buf = String::new();
+
+        writeln!(buf, "Tunes: {}", self.tunes).unwrap();
+        writeln!(
+            buf,
+            "Parsed without errors: {} ({:.1}%)",
+            self.parsed,
+            percent(self.parsed, self.tunes)
+        ).unwrap();
+        writeln!(
+            buf,
+            "Round trip diverged: {} ({:.1}%)",
+            self.diverged.len(),
+            percent(self.diverged.len(), self.tunes)
+        ).unwrap();
+
+        // Errors that affect the most tunes first.
+        let mut kinds: Vec<(&String, &ErrorCounts)> = self.errors.iter().collect();
+        kinds.sort_by(|a, b| b.1.tunes.cmp(&a.1.tunes).then(a.0.cmp(b.0)));
+
+        if !kinds.is_empty() {
+            writeln!(buf, "\nErrors, tunes, kind:").unwrap();
+        }
+        for (kind, counts) in kinds.iter() {
+            writeln!(buf, "{:>8} {:>8} {}", counts.errors, counts.tunes, kind).unwrap();
+
+            let mut details: Vec<(&String, &usize)> = counts.details.iter().collect();
+            details.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let details: Vec<String> = details
+                .iter()
+                .take(DETAILS_SHOWN)
+                .map(|(detail, count)| format!("{} x{}", detail, count))
+                .collect();
+            if !details.is_empty() {
+                writeln!(buf, "{:>18}{}", "", details.join(", ")).unwrap();
+            }
+        }
+
+        if !self.diverged.is_empty() && examples > 0 {
+            let mut diverged = self.diverged.clone();
+            diverged.sort();
+            let ids: Vec<String> = diverged
+                .iter()
+                .take(examples)
+                .map(|x| x.to_string())
+                .collect();
+            writeln!(buf, "\nRound trip diverged in tunes: {}", ids.join(", ")).unwrap();
+        }
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_test() {
+        let mut report = Report::new();
+        report.add(1, "X:1\nT:Fine\nK:G\nGAB|\n");
+        report.add(2, "X:2\nK:G\nG(AB)|\n");
+        report.add(3, "X:3\nK:G\nG!AB!(c)|\n");
+
+        assert_eq!(report.tunes, 3);
+        assert_eq!(report.parsed, 1);
+
+        let counts = &report.errors["UnexpectedBodyChar"];
+        assert_eq!(counts.tunes, 2);
+        assert_eq!(counts.errors, 6);
+        assert_eq!(counts.details["'('"], 2);
+
+        let formatted = report.format(10);
+        assert!(formatted.contains("Parsed without errors: 1 (33.3%)"));
+        assert!(formatted.contains("       6        2 UnexpectedBodyChar"));
+        assert!(formatted.contains("'!' x2"));
+    }
+
+    #[test]
+    fn error_kind_test() {
+        assert_eq!(
+            error_kind(&l::LexError::UnexpectedBodyChar('(')),
+            ("UnexpectedBodyChar".to_string(), "'('".to_string())
+        );
+        assert_eq!(
+            error_kind(&l::LexError::ExpectedColon),
+            ("ExpectedColon".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn round_trips_test() {
+        assert!(round_trips("X:1\nT:Fine\nM:C|\nL:1/16\nK:Amix\n|:A2 B>c d/e/f:|\n"));
+        assert!(round_trips("X:1\nK:G\nV:2\nAB|\nV:1\ncd|\n"));
+
+        let mut report = Report::new();
+        report.add(1, "X:1\nK:G\nGAB|\n");
+        assert!(report.diverged.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Compare two versions of a tune, bar by bar.
+//! Bars and header fields are written out as ABC and aligned with a longest common subsequence,
+//! so an inserted bar shows up as one addition rather than every following bar being different.
+
+use abc_lexer as l;
+use abc_writer;
+use beaming;
+use std::fmt::Write;
+use tune_ast_three;
+
+#[derive(Debug, PartialEq)]
+enum Change<'a> {
+    Same,
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Align two sequences, returning (index in a, index in b, change) for every line.
+// Indexes are of the next line on each side, which is the bar number for removed and added bars.
+fn align<'a>(a: &'a [String], b: &'a [String]) -> Vec<(usize, usize, Change<'a>)> {
+    // Length of the longest common subsequence of the remainders of a and b.
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                usize::max(lengths[i + 1][j], lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            result.push((i, j, Change::Same));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            result.push((i, j, Change::Removed(&a[i])));
+            i += 1;
+        } else {
+            result.push((i, j, Change::Added(&b[j])));
+            j += 1;
+        }
+    }
+
+    result
+}
+
+// Header fields as ABC, one per line.
+// The reference number is always different, so isn't worth reporting.
+fn header_lines(ast: &tune_ast_three::Tune) -> Vec<String> {
+    ast.prelude
+        .iter()
+        .filter(|x| !matches!(x, l::T::X(_)))
+        .map(|x| {
+            abc_writer::tokens(::std::slice::from_ref(x))
+                .trim()
+                .to_string()
+        })
+        .collect()
+}
+
+// Is there anything in the bar other than barlines?
+fn has_content(bar: &[l::T]) -> bool {
+    bar.iter()
+        .any(|x| !beaming::is_barline(x) && *x != l::T::BeamBreak)
+}
+
+// A voice split into bars, each written as ABC, ignoring line breaks.
+fn bars(voice: &[l::T]) -> Vec<String> {
+    let mut bars: Vec<Vec<l::T>> = vec![];
+    let mut bar = vec![];
+
+    for token in voice.iter() {
+        match token {
+            l::T::Newline => (),
+
+            // These start a bar, and can also end the one before.
+            l::T::OpenRepeat | l::T::NTimeBar(_) => {
+                if has_content(&bar) {
+                    bars.push(bar);
+                    bar = vec![];
+                }
+                bar.push(token.clone());
+            }
+
+            // Barlines that are read as more than one token, e.g. ":|]", belong to the bar they
+            // end.
+            _ if beaming::is_barline(token) && bar.is_empty() && !bars.is_empty() => {
+                let last = bars.len() - 1;
+                bars[last].push(token.clone());
+            }
+            _ if beaming::is_barline(token) && has_content(&bar) => {
+                bar.push(token.clone());
+                bars.push(bar);
+                bar = vec![];
+            }
+            _ => bar.push(token.clone()),
+        }
+    }
+
+    if !bar.is_empty() {
+        bars.push(bar);
+    }
+
+    bars.iter()
+        .map(|x| abc_writer::tokens(x).trim().to_string())
+        .collect()
+}
+
+// Lines that changed, with bar numbers if they're given.
+fn write_changes(buf: &mut String, changes: &[(usize, usize, Change)], numbered: bool) {
+    for (i, j, change) in changes.iter() {
+        match (change, numbered) {
+            (Change::Same, _) => (),
+            (Change::Removed(x), true) => writeln!(buf, "- {:>3} {}", i + 1, x).unwrap(),
+            (Change::Added(x), true) => writeln!(buf, "+ {:>3} {}", j + 1, x).unwrap(),
+            (Change::Removed(x), false) => writeln!(buf, "- {}", x).unwrap(),
+            (Change::Added(x), false) => writeln!(buf, "+ {}", x).unwrap(),
+        }
+    }
+}
+
+fn has_changes(changes: &[(usize, usize, Change)]) -> bool {
+    changes.iter().any(|(_, _, change)| *change != Change::Same)
+}
+
+/// Readable differences between two tunes, with the header fields and then each voice's bars.
+/// Removed lines are from the first tune and start with "-", added lines are from the second and
+/// start with "+". Bars are numbered in their own tune.
+pub fn format(
+    a_name: &str,
+    a: &tune_ast_three::Tune,
+    b_name: &str,
+    b: &tune_ast_three::Tune,
+) -> String {
+    let mut buf = String::new();
+    writeln!(buf, "--- {}", a_name).unwrap();
+    writeln!(buf, "+++ {}", b_name).unwrap();
+
+    let mut any_changes = false;
+
+    let (a_headers, b_headers) = (header_lines(a), header_lines(b));
+    let changes = align(&a_headers, &b_headers);
+    if has_changes(&changes) {
+        writeln!(buf, "Header").unwrap();
+        write_changes(&mut buf, &changes, false);
+        any_changes = true;
+    }
+
+    // Voices are matched up in order. Any extra ones are entirely removed or added.
+    let num_voices = usize::max(a.voices.len(), b.voices.len());
+    for i in 0..num_voices {
+        let a_bars = a.voices.get(i).map(|x| bars(x)).unwrap_or_default();
+        let b_bars = b.voices.get(i).map(|x| bars(x)).unwrap_or_default();
+
+        let changes = align(&a_bars, &b_bars);
+        if has_changes(&changes) {
+            let id = a
+                .voice_properties
+                .get(i)
+                .or_else(|| b.voice_properties.get(i))
+                .map(|x| x.id.clone())
+                .unwrap_or_default();
+            writeln!(buf, "Voice {}", id).unwrap();
+            write_changes(&mut buf, &changes, true);
+            any_changes = true;
+        }
+    }
+
+    if !any_changes {
+        writeln!(buf, "No differences.").unwrap();
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    fn strings(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn align_test() {
+        let a = strings(&["A", "B", "C", "D"]);
+        let b = strings(&["A", "C", "X", "D"]);
+
+        assert_eq!(
+            align(&a, &b),
+            vec![
+                (0, 0, Change::Same),
+                (1, 1, Change::Removed("B")),
+                (2, 1, Change::Same),
+                (3, 2, Change::Added("X")),
+                (3, 3, Change::Same),
+            ]
+        );
+
+        assert_eq!(align(&a, &[]).len(), 4);
+        assert_eq!(align(&[], &b)[3], (0, 3, Change::Added("D")));
+    }
+
+    #[test]
+    fn bars_test() {
+        let ast = representations::abc_to_ast(
+            "X:1\nL:1/8\nK:G\n|:GAB cBA|\nBcd efg:|]\nabc|1 ABC:|2 DEF||\n",
+        );
+        assert_eq!(
+            bars(&ast.voices[0]),
+            vec!["|:GAB cBA|", "Bcd efg:|]", "abc|", "1 ABC:|", "2 DEF||"]
+        );
+    }
+
+    #[test]
+    fn format_test() {
+        let a = representations::abc_to_ast(
+            "X:1\nT:The Butterfly\nM:9/8\nL:1/8\nK:Emin\nB2E G2E F3|B2E G2E FED|\nB2d d2B A3|\n",
+        );
+        let b = representations::abc_to_ast(
+            "X:2\nT:Butterfly, The\nM:9/8\nL:1/8\nK:Emin\nB2E G2E F3|B2E G2E FED|B2d d2B A2B|\n",
+        );
+
+        assert_eq!(
+            format("1", &a, "2", &b),
+            "--- 1\n+++ 2\nHeader\n- T:The Butterfly\n+ T:Butterfly, The\nVoice 1\n\
+             -   3 B2d d2B A3|\n+   3 B2d d2B A2B|\n"
+        );
+
+        assert_eq!(format("1", &a, "1", &a), "--- 1\n+++ 1\nNo differences.\n");
+    }
+}
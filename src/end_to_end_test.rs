@@ -1,15 +1,31 @@
 #[cfg(test)]
+use regex::Regex;
+#[cfg(test)]
 use representations;
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::path::Path;
+#[cfg(test)]
+use typeset;
 
-fn string_to_vec(input: String) -> Vec<char> {
-    input.chars().collect::<Vec<char>>()
-}
+// Reference tunes, each with a golden SVG of the same name next to it.
+// Set UPDATE_SNAPSHOTS=1 to rewrite the golden files after a deliberate change to the layout.
+#[cfg(test)]
+const SNAPSHOTS_DIR: &str = "test_resources/snapshots";
+
+// How far apart two numbers in the SVG may be and still count as the same, to allow for
+// rounding when the arithmetic in typeset.rs is rearranged.
+#[cfg(test)]
+const SNAPSHOT_TOLERANCE: f32 = 0.01;
 
 #[test]
 fn chromatic() {
     assert_eq!(
         representations::ast_to_pitches(&representations::abc_to_ast(
-            &("K:C\nCDEFGABcdefgabc'".to_string())
+            "K:C\nCDEFGABcdefgabc'"
         )),
         vec![60, 62, 64, 65, 67, 69, 71, 72, 74, 76, 77, 79, 81, 83, 84],
         "C Major scale pitches."
@@ -17,7 +33,7 @@ fn chromatic() {
 
     assert_eq!(
         representations::ast_to_pitches(&representations::abc_to_ast(
-            &("K:C\nC ^C D ^D E F ^F G ^G A ^A B c ^c d ^d e f ^f g ^g a ^a b c'".to_string())
+            "K:C\nC ^C D ^D E F ^F G ^G A ^A B c ^c d ^d e f ^f g ^g a ^a b c'"
         )),
         vec![
             60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81,
@@ -28,7 +44,7 @@ fn chromatic() {
 
     assert_eq!(
         representations::ast_to_pitches(&representations::abc_to_ast(
-            &("K:C\nC _D D _E E F _G G _A A _B B c _d d _e e f _g g _a a _b b c'".to_string())
+            "K:C\nC _D D _E E F _G G _A A _B B c _d d _e e f _g g _a a _b b c'"
         )),
         vec![
             60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81,
@@ -47,3 +63,142 @@ fn chromatic() {
     //     "D scale pitches."
     // );
 }
+
+#[test]
+fn octaves() {
+    assert_eq!(
+        representations::ast_to_pitches(&representations::abc_to_ast(
+            "K:C\nC,,,, C,,, C,, C, C c c' c'' c''' c''''"
+        )),
+        vec![12, 24, 36, 48, 60, 72, 84, 96, 108, 120],
+        "Each comma is an octave down and each apostrophe an octave up."
+    );
+
+    assert_eq!(
+        representations::ast_to_pitches(&representations::abc_to_ast(
+            "K:C\nA,,,,2 B,,,/ G,,3/2 a''' b''',"
+        )),
+        vec![21, 35, 43, 117, 107],
+        "Octave modifiers combine with durations and each other."
+    );
+}
+
+// Split an element of SVG into the text between numbers, and the numbers.
+#[cfg(test)]
+fn split_numbers(element: &str) -> (Vec<&str>, Vec<f32>) {
+    let number = Regex::new(r"-?[0-9]+(\.[0-9]+)?").unwrap();
+    let text = number.split(element).collect::<Vec<&str>>();
+    let numbers = number
+        .find_iter(element)
+        .map(|x| x.as_str().parse::<f32>().unwrap())
+        .collect::<Vec<f32>>();
+    (text, numbers)
+}
+
+// Whether two elements of SVG match, with numbers compared within the tolerance.
+#[cfg(test)]
+fn elements_match(expected: &str, actual: &str) -> bool {
+    let (expected_text, expected_numbers) = split_numbers(expected);
+    let (actual_text, actual_numbers) = split_numbers(actual);
+
+    expected_text == actual_text
+        && expected_numbers.len() == actual_numbers.len()
+        && expected_numbers
+            .iter()
+            .zip(actual_numbers.iter())
+            .all(|(a, b)| (a - b).abs() <= SNAPSHOT_TOLERANCE)
+}
+
+// Elements of an SVG, with whitespace collapsed, so a difference can be pinned to one element
+// rather than a whole line.
+#[cfg(test)]
+fn svg_elements(svg: &str) -> Vec<String> {
+    svg.split('<')
+        .map(|x| x.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("<{}", x))
+        .collect()
+}
+
+// Compare an SVG against its golden copy, returning a description of the first difference.
+#[cfg(test)]
+fn compare_svg(expected: &str, actual: &str) -> Option<String> {
+    let expected_elements = svg_elements(expected);
+    let actual_elements = svg_elements(actual);
+
+    for i in 0..expected_elements.len().max(actual_elements.len()) {
+        let expected_element = expected_elements.get(i).map_or("<end>", |x| x.as_str());
+        let actual_element = actual_elements.get(i).map_or("<end>", |x| x.as_str());
+        if !elements_match(expected_element, actual_element) {
+            return Some(format!(
+                "element {}\n  expected: {}\n  actual:   {}",
+                i + 1,
+                expected_element,
+                actual_element
+            ));
+        }
+    }
+
+    None
+}
+
+#[test]
+fn compare_svg_test() {
+    assert_eq!(
+        compare_svg("<line x1=\"10.5\" />", "<line x1=\"10.501\" />"),
+        None,
+        "Small differences in numbers are allowed."
+    );
+    assert!(compare_svg("<line x1=\"10.5\" />", "<line x1=\"10.6\" />").is_some());
+    assert!(compare_svg("<line x1=\"10.5\" />", "<rect x1=\"10.5\" />").is_some());
+    assert!(compare_svg("<svg></svg>", "<svg>").is_some());
+    assert_eq!(
+        compare_svg("<g><text x='1'\n  y='2'>", "<g>\n<text x='1' y='3'>"),
+        Some(
+            "element 2\n  expected: <text x='1' y='2'>\n  actual:   <text x='1' y='3'>".to_string()
+        )
+    );
+}
+
+#[test]
+fn svg_snapshots() {
+    let update = env::var("UPDATE_SNAPSHOTS").is_ok();
+    let typesetting = typeset::Typesetting::new();
+
+    let mut paths = fs::read_dir(SNAPSHOTS_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|x| x == "abc"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "There should be reference tunes.");
+
+    let mut failures = vec![];
+    for path in paths.iter() {
+        let abc = fs::read_to_string(path).unwrap();
+        let svg = representations::ast_to_svg(&representations::abc_to_ast(&abc), &typesetting);
+        let golden_path = path.with_extension("svg");
+
+        if update {
+            fs::write(&golden_path, &svg).unwrap();
+            continue;
+        }
+
+        match fs::read_to_string(&golden_path) {
+            Ok(golden) => {
+                if let Some(difference) = compare_svg(&golden, &svg) {
+                    failures.push(format!("{}: {}", golden_path.display(), difference));
+                }
+            }
+            Err(_) => failures.push(format!("{}: missing", golden_path.display())),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "SVG differs from the golden files in {}. If the change is deliberate, run again with \
+         UPDATE_SNAPSHOTS=1 and check in the new files.\n{}",
+        Path::new(SNAPSHOTS_DIR).display(),
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,249 @@
+//! Export the contents of the search engine's VSMs as a sparse feature matrix.
+//! Rows are tunes, columns are terms: feature type / value pairs and melody interval terms.
+//! All values are binary, as they are in the VSMs.
+
+use search;
+
+use std::io::Write;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    // Sparse 'label index:value' lines, label being the tune ID. Indexes start at 1.
+    LibSVM,
+
+    // One 'tune_id,index,name' line per non-zero value. Indexes start at 0.
+    Csv,
+}
+
+impl Format {
+    pub fn from_string(value: &str) -> Option<Format> {
+        match value {
+            "libsvm" => Some(Format::LibSVM),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+// Which term spaces to include as columns.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Columns {
+    pub features: bool,
+    pub intervals: bool,
+}
+
+// Column names and each tune's column indexes.
+pub struct Matrix {
+    pub names: Vec<String>,
+    pub rows: Vec<(usize, Vec<usize>)>,
+}
+
+// Build the matrix from the search engine. Feature columns come first, then interval terms.
+// Tunes with no terms at all are left out.
+pub fn build_matrix(searcher: &search::SearchEngine, columns: Columns) -> Matrix {
+    let features = match searcher.features_vsm {
+        Some(ref vsm) if columns.features => Some(&vsm.vsm),
+        _ => None,
+    };
+    let intervals = &searcher.interval_term_vsm().vsm;
+
+    // Each term has a column, with the same index as its term ID, so a term without a name
+    // still keeps the others in their places.
+    let mut names = vec![];
+
+    if let Some(features) = features {
+        for term_id in 0..features.num_terms() {
+            names.push(match features.get_term(term_id) {
+                Some((typ, val)) => format!("feature:{}={}", typ, val),
+                None => format!("feature:#{}", term_id),
+            });
+        }
+    }
+
+    let interval_offset = features.map_or(0, |features| features.num_terms());
+    if columns.intervals {
+        for term_id in 0..intervals.num_terms() {
+            names.push(match intervals.get_term(term_id) {
+                Some(term) => {
+                    let term: Vec<String> = term.iter().map(|x| x.to_string()).collect();
+                    format!("interval:{}", term.join(" "))
+                }
+                None => format!("interval:#{}", term_id),
+            });
+        }
+    }
+
+    let mut rows = vec![];
+    for tune_id in 0..(searcher.get_max_tune_id() as usize + 1) {
+        let mut row = vec![];
+
+        if let Some(features) = features {
+            row.extend(features.doc_term_ids(tune_id));
+        }
+
+        if columns.intervals {
+            row.extend(
+                intervals
+                    .doc_term_ids(tune_id)
+                    .iter()
+                    .map(|x| x + interval_offset),
+            );
+        }
+
+        if !row.is_empty() {
+            rows.push((tune_id, row));
+        }
+    }
+
+    Matrix { names, rows }
+}
+
+// Quote a CSV field if it needs it.
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_matrix<W: Write>(
+    matrix: &Matrix,
+    format: Format,
+    writer: &mut W,
+) -> Result<(), String> {
+    let result = match format {
+        Format::LibSVM => matrix.rows.iter().try_for_each(|(tune_id, row)| {
+            let values: Vec<String> = row.iter().map(|x| format!("{}:1", x + 1)).collect();
+            writeln!(writer, "{} {}", tune_id, values.join(" "))
+        }),
+        Format::Csv => writeln!(writer, "tune_id,index,name").and_then(|_| {
+            matrix.rows.iter().flat_map(|(tune_id, row)| {
+                row.iter().map(move |index| (tune_id, index))
+            }).try_for_each(|(tune_id, index)| {
+                writeln!(writer, "{},{},{}", tune_id, index, csv_field(&matrix.names[*index]))
+            })
+        }),
+    };
+
+    result.map_err(|err| format!("Can't write features: {:?}", err))
+}
+
+// Write the column names, for formats that don't include them.
+// One 'index,name' line per column, indexed as in the given format.
+pub fn write_vocabulary<W: Write>(
+    matrix: &Matrix,
+    format: Format,
+    writer: &mut W,
+) -> Result<(), String> {
+    let base = match format {
+        Format::LibSVM => 1,
+        Format::Csv => 0,
+    };
+
+    writeln!(writer, "index,name")
+        .and_then(|_| {
+            matrix.names.iter().enumerate().try_for_each(|(index, name)| {
+                writeln!(writer, "{},{}", index + base, csv_field(name))
+            })
+        }).map_err(|err| format!("Can't write vocabulary: {:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use relations;
+    use std::path::PathBuf;
+    use storage;
+    use text;
+
+    fn matrix() -> Matrix {
+        Matrix {
+            names: vec![
+                "feature:key=G".to_string(),
+                "feature:metre=6/8".to_string(),
+                "interval:2 2 -4".to_string(),
+            ],
+            rows: vec![(1, vec![0, 2]), (5, vec![1])],
+        }
+    }
+
+    #[test]
+    fn build_matrix_test() {
+        let searcher = search::SearchEngine::new(
+            Box::new(
+                storage::ReadOnlyCache::new(PathBuf::from("test_resources/tunecache"), None)
+                    .unwrap(),
+            ),
+            relations::Clusters::new(),
+            search::SearchEngineFeatures {
+                index_text: false,
+                index_melody_interval_term: true,
+                index_melody_degree_term: false,
+                index_melody_contour_term: false,
+                index_melody_transformations: false,
+                index_features: true,
+                rollup: false,
+                interval_window_sizes: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+                sounding_interval_window_sizes: vec![],
+                feature_extractors: None,
+            },
+            text::Vocabulary::new(),
+        );
+
+        let matrix = build_matrix(
+            &searcher,
+            Columns {
+                features: true,
+                intervals: true,
+            },
+        );
+        assert!(!matrix.rows.is_empty());
+        for (tune_id, row) in matrix.rows.iter() {
+            let intervals = searcher.interval_term_vsm().vsm.doc_term_ids(*tune_id);
+            let interval_columns: Vec<&String> = row
+                .iter()
+                .map(|index| &matrix.names[*index])
+                .filter(|name| name.starts_with("interval:"))
+                .collect();
+            assert_eq!(
+                interval_columns.len(),
+                intervals.len(),
+                "Interval terms have interval columns."
+            );
+        }
+    }
+
+    #[test]
+    fn write_matrix_test() {
+        let mut buf = vec![];
+        write_matrix(&matrix(), Format::LibSVM, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1 1:1 3:1\n5 2:1\n",
+            "LibSVM indexes start at 1."
+        );
+
+        let mut buf = vec![];
+        write_matrix(&matrix(), Format::Csv, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "tune_id,index,name\n1,0,feature:key=G\n1,2,interval:2 2 -4\n5,1,feature:metre=6/8\n"
+        );
+    }
+
+    #[test]
+    fn write_vocabulary_test() {
+        let mut matrix = matrix();
+        matrix.names.push("feature:rhythm=jig, slip".to_string());
+
+        let mut buf = vec![];
+        write_vocabulary(&matrix, Format::LibSVM, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "index,name\n1,feature:key=G\n2,feature:metre=6/8\n3,interval:2 2 -4\n\
+             4,\"feature:rhythm=jig, slip\"\n",
+            "Names containing commas should be quoted."
+        );
+    }
+}
@@ -1,62 +1,98 @@
 use abc_lexer as l;
 use music;
+use pitch;
+use std::collections::HashMap;
 use tune_ast_three;
+use unidecode::unidecode;
 
 pub fn key_signature(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
     // TODO if there's no Key, assume C major.
-    for ref token in ast.prelude.iter() {
-        match *token {
-            l::T::KeySignature(pitch_class, mode) => {
+    for token in ast.prelude.iter() {
+        if let l::T::KeySignature(pitch_class, mode) = token {
+            result.push(("key".to_string(), pitch_class.to_string()));
+            result.push(("mode".to_string(), mode.to_string()));
+            result.push((
+                "key-signature".to_string(),
+                format!("{}-{}", pitch_class, mode),
+            ));
+        }
+    }
+
+    for voice in ast.voices.iter() {
+        for token in voice.iter() {
+            if let l::T::KeySignature(pitch_class, mode) = token {
                 result.push(("key".to_string(), pitch_class.to_string()));
                 result.push(("mode".to_string(), mode.to_string()));
                 result.push((
                     "key-signature".to_string(),
-                    format!("{}-{}", pitch_class.to_string(), mode.to_string()),
+                    format!("{}-{}", pitch_class, mode),
                 ));
             }
-            _ => (),
         }
     }
+}
 
-    for ref voice in ast.voices.iter() {
-        for ref token in voice.iter() {
-            match *token {
-                l::T::KeySignature(pitch_class, mode) => {
-                    result.push(("key".to_string(), pitch_class.to_string()));
-                    result.push(("mode".to_string(), mode.to_string()));
-                    result.push((
-                        "key-signature".to_string(),
-                        format!("{}-{}", pitch_class.to_string(), mode.to_string()),
-                    ));
-                }
-                _ => (),
-            }
-        }
+// Feature types whose values are numbers, so can be filtered by a range, e.g. `max_range=12`.
+pub const NUMERIC_FEATURES: &[&str] = &[
+    "metre-beats",
+    "onset-density",
+    "lowest-note",
+    "highest-note",
+    "range",
+    "notes",
+    "parts",
+];
+
+// Feature values that are written differently but mean much the same, as (feature type, value,
+// normalised value). Filters and facets use the normalised value, so e.g. reels written in cut time
+// are counted with those in 4/4.
+const SYNONYMS: &[(&str, &str, &str)] = &[("metre", "2/2", "4/4")];
+
+// Case, accents and spacing don't make a feature value different, e.g. "Bourrée" and "bourree".
+// Sharps and flats are spelled out first, as they'd otherwise be lost.
+fn fold(value: &str) -> String {
+    let spelled = value.replace('♯', "#").replace('♭', "b");
+    unidecode(&spelled)
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// The normalised form of a feature value, which is the value folded to lower-case ASCII, or its
+// synonym. Use `FeaturesBinaryVSM::label` to show it.
+pub fn normalise(feature_type: &str, value: &str) -> String {
+    let folded = fold(value);
+    match SYNONYMS
+        .iter()
+        .find(|(typ, synonym, _)| *typ == feature_type && *synonym == folded)
+    {
+        Some((_, _, normalised)) => normalised.to_string(),
+        None => folded,
     }
 }
 
+// The metre is normalised, with the metre as written kept as metre-written.
+fn push_metre(metre: &music::Metre, result: &mut Vec<(String, String)>) {
+    let music::Metre(numerator, _) = metre;
+    let written = metre.to_string();
+    result.push(("metre".to_string(), normalise("metre", &written)));
+    result.push(("metre-written".to_string(), written));
+    result.push(("metre-beats".to_string(), numerator.to_string()));
+}
+
 pub fn time_signature(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
     // TODO if there's no entry, assume 4/4.
-    for ref token in ast.prelude.iter() {
-        match *token {
-            l::T::Metre(metre) => {
-                let music::Metre(numerator, _) = metre;
-                result.push(("metre".to_string(), metre.to_string()));
-                result.push(("metre-beats".to_string(), numerator.to_string()));
-            }
-            _ => (),
+    for token in ast.prelude.iter() {
+        if let l::T::Metre(metre) = token {
+            push_metre(metre, result)
         }
     }
 
-    for ref voice in ast.voices.iter() {
-        for ref token in voice.iter() {
-            match *token {
-                l::T::Metre(metre) => {
-                    let music::Metre(numerator, _) = metre;
-                    result.push(("metre".to_string(), metre.to_string()));
-                    result.push(("metre-beats".to_string(), numerator.to_string()));
-                }
-                _ => (),
+    for voice in ast.voices.iter() {
+        for token in voice.iter() {
+            if let l::T::Metre(metre) = token {
+                push_metre(metre, result)
             }
         }
     }
@@ -64,35 +100,589 @@ pub fn time_signature(ast: &tune_ast_three::Tune, result: &mut Vec<(String, Stri
 
 pub fn rhythm(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
     // TODO if there's no entry, assume 4/4.
-    for ref token in ast.prelude.iter() {
-        match *token {
-            // TODO normalize
-            l::T::Rhythm(value) => {
+    for token in ast.prelude.iter() {
+        // TODO normalize
+        if let l::T::Rhythm(value) = token {
+            result.push(("rhythm".to_string(), value.to_string()));
+        }
+    }
+
+    for voice in ast.voices.iter() {
+        for token in voice.iter() {
+            if let l::T::Rhythm(value) = token {
                 result.push(("rhythm".to_string(), value.to_string()));
             }
-            _ => (),
         }
     }
+}
 
-    for ref voice in ast.voices.iter() {
-        for ref token in voice.iter() {
-            match *token {
-                l::T::Rhythm(value) => {
-                    result.push(("rhythm".to_string(), value.to_string()));
+// The most common note length, e.g. "1/8" for most jigs and reels, "1/4" for most waltzes.
+pub fn note_length(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let mut counts: HashMap<(u32, u32), usize> = HashMap::new();
+
+    for voice in ast.voices.iter() {
+        for token in voice.iter() {
+            if let l::T::Note(music::Note(_, duration)) = token {
+                let music::FractionalDuration(numerator, denominator) = duration.reduce();
+                *counts.entry((numerator, denominator)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // Break ties in favour of the shorter note, so the result is stable.
+    let dominant = counts.iter().max_by(|(a, a_count), (b, b_count)| {
+        a_count
+            .cmp(b_count)
+            .then((b.0 * a.1).cmp(&(a.0 * b.1)))
+    });
+
+    if let Some(((numerator, denominator), _)) = dominant {
+        result.push((
+            "note-length".to_string(),
+            format!("{}/{}", numerator, denominator),
+        ));
+    }
+}
+
+// Number of notes in a typical bar. This distinguishes e.g. reels (8) from jigs (6) and waltzes
+// (3), regardless of headers. The median is used so that pickups and long final notes don't
+// skew it.
+pub fn onset_density(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let mut notes_per_bar = vec![];
+
+    for voice in ast.voices.iter() {
+        let mut count = 0;
+        for token in voice.iter() {
+            match token {
+                l::T::Note(_) | l::T::Rest(_) => count += 1,
+                l::T::SingleBar
+                | l::T::DoubleBar
+                | l::T::OpenRepeat
+                | l::T::CloseRepeat
+                | l::T::EndBar
+                | l::T::NTimeBar(_) => {
+                    // A barline may not close a bar, e.g. an opening repeat.
+                    if count > 0 {
+                        notes_per_bar.push(count);
+                    }
+                    count = 0;
                 }
                 _ => (),
             }
         }
+
+        if count > 0 {
+            notes_per_bar.push(count);
+        }
+    }
+
+    if !notes_per_bar.is_empty() {
+        notes_per_bar.sort();
+        let median = notes_per_bar[notes_per_bar.len() / 2];
+        result.push(("onset-density".to_string(), median.to_string()));
+    }
+}
+
+// Key and mode worked out from the notes, in the same form as key-signature, e.g. "E-Minor".
+// Useful when the K: header is wrong or missing.
+pub fn detected_key(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    if let Some((pitch_class, mode)) = pitch::detect_key(ast) {
+        result.push((
+            "detected-key".to_string(),
+            format!("{}-{}", pitch_class, mode),
+        ));
+    }
+}
+
+// Lowest and highest notes as they sound, as MIDI pitches, e.g. 62 for the D above middle C, and
+// the range between them in semitones. This tells you if a tune fits an instrument, e.g. a D
+// whistle plays from 62 to 85.
+pub fn note_range(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let pitches = pitch::PitchSequence::from_ast_in_key(ast).pitches;
+
+    if let (Some(lowest), Some(highest)) = (pitches.iter().min(), pitches.iter().max()) {
+        result.push(("lowest-note".to_string(), lowest.to_string()));
+        result.push(("highest-note".to_string(), highest.to_string()));
+        result.push(("range".to_string(), (highest - lowest).to_string()));
+    }
+}
+
+// Number of notes in the tune, in all voices, not counting rests. A rough measure of how long
+// a tune is to learn.
+pub fn note_count(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let count = ast
+        .voices
+        .iter()
+        .flat_map(|voice| voice.iter())
+        .filter(|token| matches!(token, l::T::Note(_)))
+        .count();
+
+    if count > 0 {
+        result.push(("notes".to_string(), count.to_string()));
+    }
+}
+
+// Scale degree of the first note, 1 to 7 relative to the key note, e.g. "5" for a tune that starts
+// on the dominant. The octave doesn't matter.
+pub fn starts_on_degree(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    if let Some(degree) = pitch::DegreeSequence::from_ast(ast).degrees.first() {
+        result.push(("starts-on-degree".to_string(), degree.to_string()));
+    }
+}
+
+// Number of parts, e.g. "2" for a reel with A and B parts. A part ends at a closing repeat,
+// double bar or end bar, and the rest of the tune is a part if it has notes. Alternate endings
+// belong to the part they end, and a pickup before an opening repeat doesn't start a new one.
+// Only the first voice is counted.
+pub fn parts(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let voice = match ast.voices.first() {
+        Some(voice) => voice,
+        None => return,
+    };
+
+    let mut count = 0;
+
+    // Are there notes since the last part ended?
+    let mut notes = false;
+
+    // Are we in a second or later ending, which belongs to the part already counted?
+    let mut alternate = false;
+
+    for token in voice.iter() {
+        match token {
+            l::T::Note(_) | l::T::Rest(_) => notes = true,
+            l::T::NTimeBar(n) if *n > 1 => alternate = true,
+            l::T::CloseRepeat | l::T::DoubleBar | l::T::EndBar => {
+                if notes && !alternate {
+                    count += 1;
+                }
+                if notes {
+                    alternate = false;
+                }
+                notes = false;
+            }
+            _ => (),
+        }
+    }
+
+    if notes && !alternate {
+        count += 1;
+    }
+
+    if count > 0 {
+        result.push(("parts".to_string(), count.to_string()));
+    }
+}
+
+// Length of the pick-up before the first full bar, e.g. "1/8" for a jig that starts on a quaver,
+// or "none". Only the first voice is counted, and there's no value without a metre to tell.
+pub fn anacrusis(ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+    let properties = match ast.voice_properties.first() {
+        Some(properties) => properties,
+        None => return,
+    };
+
+    let value = match properties.anacrusis {
+        Some(music::FractionalDuration(numerator, denominator)) => {
+            format!("{}/{}", numerator, denominator)
+        }
+        None if ast.prelude.iter().any(|x| matches!(x, l::T::Metre(_))) => "none".to_string(),
+        None => return,
+    };
+
+    result.push(("anacrusis".to_string(), value));
+}
+
+// Something that works out features of a tune, as (feature type, value) pairs.
+pub trait FeatureExtractor: Send + Sync {
+    // Name to enable it by, e.g. "note-range".
+    fn name(&self) -> &'static str;
+
+    // Feature types it can produce, e.g. "lowest-note", "highest-note" and "range".
+    fn feature_types(&self) -> &'static [&'static str];
+
+    fn extract(&self, ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>);
+}
+
+// An extractor that's a plain function, as the built-in ones are.
+#[derive(Clone, Copy)]
+struct FnExtractor {
+    name: &'static str,
+    feature_types: &'static [&'static str],
+    extract: fn(&tune_ast_three::Tune, &mut Vec<(String, String)>),
+}
+
+impl FeatureExtractor for FnExtractor {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn feature_types(&self) -> &'static [&'static str] {
+        self.feature_types
+    }
+
+    fn extract(&self, ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+        (self.extract)(ast, result)
+    }
+}
+
+// The built-in extractors, in the order they run.
+const BUILT_IN: &[FnExtractor] = &[
+    FnExtractor {
+        name: "key-signature",
+        feature_types: &["key", "mode", "key-signature"],
+        extract: key_signature,
+    },
+    FnExtractor {
+        name: "metre",
+        feature_types: &["metre", "metre-written", "metre-beats"],
+        extract: time_signature,
+    },
+    FnExtractor {
+        name: "rhythm",
+        feature_types: &["rhythm"],
+        extract: rhythm,
+    },
+    FnExtractor {
+        name: "note-length",
+        feature_types: &["note-length"],
+        extract: note_length,
+    },
+    FnExtractor {
+        name: "onset-density",
+        feature_types: &["onset-density"],
+        extract: onset_density,
+    },
+    FnExtractor {
+        name: "detected-key",
+        feature_types: &["detected-key"],
+        extract: detected_key,
+    },
+    FnExtractor {
+        name: "note-range",
+        feature_types: &["lowest-note", "highest-note", "range"],
+        extract: note_range,
+    },
+    FnExtractor {
+        name: "notes",
+        feature_types: &["notes"],
+        extract: note_count,
+    },
+    FnExtractor {
+        name: "starts-on-degree",
+        feature_types: &["starts-on-degree"],
+        extract: starts_on_degree,
+    },
+    FnExtractor {
+        name: "parts",
+        feature_types: &["parts"],
+        extract: parts,
+    },
+    FnExtractor {
+        name: "anacrusis",
+        feature_types: &["anacrusis"],
+        extract: anacrusis,
+    },
+];
+
+// Names of the built-in extractors, which are all enabled by default.
+pub fn built_in_names() -> Vec<&'static str> {
+    BUILT_IN.iter().map(|extractor| extractor.name).collect()
+}
+
+// The extractors a search engine runs on each tune.
+pub struct FeatureRegistry {
+    extractors: Vec<Box<dyn FeatureExtractor>>,
+}
+
+impl FeatureRegistry {
+    // No extractors.
+    pub fn new() -> FeatureRegistry {
+        FeatureRegistry { extractors: vec![] }
+    }
+
+    // All the built-in extractors.
+    pub fn built_in() -> FeatureRegistry {
+        let mut registry = FeatureRegistry::new();
+        for extractor in BUILT_IN.iter() {
+            registry.register(Box::new(*extractor));
+        }
+        registry
+    }
+
+    // The built-in extractors with these names, in the usual order. Error if any isn't known.
+    pub fn with_names(names: &[String]) -> Result<FeatureRegistry, String> {
+        if let Some(unknown) = names.iter().find(|name| {
+            !BUILT_IN
+                .iter()
+                .any(|extractor| extractor.name == name.as_str())
+        }) {
+            return Err(format!(
+                "Unknown feature extractor '{}'. Use one of: {}.",
+                unknown,
+                built_in_names().join(", ")
+            ));
+        }
+
+        let mut registry = FeatureRegistry::built_in();
+        registry
+            .extractors
+            .retain(|extractor| names.iter().any(|name| name == extractor.name()));
+        Ok(registry)
+    }
+
+    // Add an extractor, to run after the others. It replaces any with the same name.
+    pub fn register(&mut self, extractor: Box<dyn FeatureExtractor>) {
+        self.extractors.retain(|x| x.name() != extractor.name());
+        self.extractors.push(extractor);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.extractors
+            .iter()
+            .map(|extractor| extractor.name())
+            .collect()
+    }
+
+    // Feature types the extractors can produce, in order.
+    pub fn feature_types(&self) -> Vec<&'static str> {
+        self.extractors
+            .iter()
+            .flat_map(|extractor| extractor.feature_types().iter().cloned())
+            .collect()
+    }
+
+    pub fn extract(&self, ast: &tune_ast_three::Tune) -> Vec<(String, String)> {
+        let mut result = vec![];
+        for extractor in self.extractors.iter() {
+            extractor.extract(ast, &mut result);
+        }
+        result
     }
 }
 
-//
+impl Default for FeatureRegistry {
+    fn default() -> FeatureRegistry {
+        FeatureRegistry::built_in()
+    }
+}
+
+// All the features from the built-in extractors.
 pub fn extract_all_features(ast: &tune_ast_three::Tune) -> Vec<(String, String)> {
-    let mut result = vec![];
+    FeatureRegistry::built_in().extract(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    fn features_of_type(abc: &str, typ: &str) -> Vec<String> {
+        let ast = representations::abc_to_ast(abc);
+        extract_all_features(&ast)
+            .into_iter()
+            .filter(|(t, _)| t == typ)
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    #[test]
+    fn note_length_test() {
+        let jig = "X:1\nM:6/8\nL:1/8\nK:G\n|:GAB c2d|e2f g3|\n";
+        assert_eq!(features_of_type(jig, "note-length"), vec!["1/8"]);
+
+        let waltz = "X:1\nM:3/4\nL:1/4\nK:G\nGAB|c2d|\n";
+        assert_eq!(features_of_type(waltz, "note-length"), vec!["1/4"]);
+
+        let tie = "X:1\nL:1/8\nK:G\nG2A|\n";
+        assert_eq!(
+            features_of_type(tie, "note-length"),
+            vec!["1/8"],
+            "Ties go to the shorter note."
+        );
+
+        assert!(features_of_type("X:1\nK:G\n", "note-length").is_empty());
+    }
+
+    #[test]
+    fn onset_density_test() {
+        let reel = "X:1\nM:4/4\nL:1/8\nK:D\nA|:DFAF dFAF|GBdB gBdB|DFAF dFAF|G2 B2 d4:|\n";
+        assert_eq!(
+            features_of_type(reel, "onset-density"),
+            vec!["8"],
+            "Pickup and long final bar shouldn't affect the result."
+        );
+
+        let jig = "X:1\nM:6/8\nL:1/8\nK:G\nGAB cde|fga bag|\n";
+        assert_eq!(features_of_type(jig, "onset-density"), vec!["6"]);
+
+        assert!(features_of_type("X:1\nK:G\n", "onset-density").is_empty());
+    }
 
-    key_signature(ast, &mut result);
-    time_signature(ast, &mut result);
-    rhythm(ast, &mut result);
+    #[test]
+    fn detected_key_test() {
+        let jig = "X:1\nM:6/8\nL:1/8\nK:G\n|:B2E G2E F3|B2E G2E FED|B2d d2B A2F|B2E G2E E3:|\n";
+        assert_eq!(features_of_type(jig, "key-signature"), vec!["G-Major"]);
+        assert_eq!(features_of_type(jig, "detected-key"), vec!["E-Minor"]);
 
-    result
+        assert!(features_of_type("X:1\nK:G\n", "detected-key").is_empty());
+    }
+
+    #[test]
+    fn note_range_test() {
+        let reel = "X:1\nM:4/4\nL:1/8\nK:D\nDFAd fdAF|GABc defg|a2 b2 a'2 fe|\n";
+        assert_eq!(features_of_type(reel, "lowest-note"), vec!["62"]);
+        assert_eq!(features_of_type(reel, "highest-note"), vec!["93"]);
+        assert_eq!(features_of_type(reel, "range"), vec!["31"]);
+
+        // Notes are as they sound, so F is F sharp in D.
+        let low = "X:1\nL:1/8\nK:D\nF,A,D|\n";
+        assert_eq!(features_of_type(low, "lowest-note"), vec!["54"]);
+        assert_eq!(features_of_type(low, "range"), vec!["8"]);
+
+        assert!(features_of_type("X:1\nK:G\n", "range").is_empty());
+    }
+
+    #[test]
+    fn note_count_test() {
+        let tune = "X:1\nL:1/8\nK:D\nDFA z2 d|[DF]2 A>B|\n";
+        assert_eq!(
+            features_of_type(tune, "notes"),
+            vec!["8"],
+            "Rests aren't counted, and each note of a chord is."
+        );
+        assert!(features_of_type("X:1\nK:G\nz4|\n", "notes").is_empty());
+    }
+
+    #[test]
+    fn starts_on_degree_test() {
+        let jig = "X:1\nM:6/8\nL:1/8\nK:G\nDGG BGG|\n";
+        assert_eq!(features_of_type(jig, "starts-on-degree"), vec!["5"]);
+
+        let reel = "X:1\nL:1/8\nK:D\nd2 fd|\n";
+        assert_eq!(features_of_type(reel, "starts-on-degree"), vec!["1"]);
+
+        assert!(features_of_type("X:1\nK:G\n", "starts-on-degree").is_empty());
+    }
+
+    #[test]
+    fn parts_test() {
+        let reel = "X:1\nL:1/8\nK:D\nA|:DFAF dFAF:|:GBdB gBdB:|\n";
+        assert_eq!(
+            features_of_type(reel, "parts"),
+            vec!["2"],
+            "A pickup isn't a part."
+        );
+
+        let endings = "X:1\nL:1/8\nK:D\n|:DFAF|1dFAF:|2dAFD||fafd|gbge|]\n";
+        assert_eq!(
+            features_of_type(endings, "parts"),
+            vec!["2"],
+            "Alternate endings are part of the same part."
+        );
+
+        let unmarked = "X:1\nL:1/8\nK:D\nDFAF|dFAF|\n";
+        assert_eq!(features_of_type(unmarked, "parts"), vec!["1"]);
+
+        assert!(features_of_type("X:1\nK:G\n", "parts").is_empty());
+    }
+
+    #[test]
+    fn anacrusis_test() {
+        let jig = "X:1\nM:6/8\nL:1/8\nK:D\nA|:d2f afd|e3 e2A:|\n";
+        assert_eq!(features_of_type(jig, "anacrusis"), vec!["1/8"]);
+
+        let waltz = "X:1\nM:3/4\nL:1/8\nK:G\nG2 A2 B2|d6|\n";
+        assert_eq!(features_of_type(waltz, "anacrusis"), vec!["none"]);
+
+        let no_metre = "X:1\nL:1/8\nK:G\nA|GAB|\n";
+        assert!(features_of_type(no_metre, "anacrusis").is_empty());
+    }
+
+    // Counts the voices.
+    struct Voices;
+
+    impl FeatureExtractor for Voices {
+        fn name(&self) -> &'static str {
+            "voices"
+        }
+
+        fn feature_types(&self) -> &'static [&'static str] {
+            &["voices"]
+        }
+
+        fn extract(&self, ast: &tune_ast_three::Tune, result: &mut Vec<(String, String)>) {
+            result.push(("voices".to_string(), ast.voices.len().to_string()));
+        }
+    }
+
+    #[test]
+    fn feature_registry_test() {
+        let ast = representations::abc_to_ast("X:1\nM:6/8\nL:1/8\nK:G\nGAB cde|\n");
+
+        let all = FeatureRegistry::built_in();
+        assert_eq!(all.names(), built_in_names());
+        assert_eq!(all.extract(&ast), extract_all_features(&ast));
+        for (feature_type, _) in all.extract(&ast) {
+            assert!(
+                all.feature_types().contains(&feature_type.as_str()),
+                "Extractor didn't declare {}.",
+                feature_type
+            );
+        }
+
+        let mut some =
+            FeatureRegistry::with_names(&["parts".to_string(), "metre".to_string()]).unwrap();
+        assert_eq!(
+            some.names(),
+            vec!["metre", "parts"],
+            "Built-ins stay in order."
+        );
+        assert_eq!(
+            some.feature_types(),
+            vec!["metre", "metre-written", "metre-beats", "parts"]
+        );
+        assert!(some
+            .extract(&ast)
+            .iter()
+            .all(|(feature_type, _)| some.feature_types().contains(&feature_type.as_str())));
+
+        some.register(Box::new(Voices));
+        assert_eq!(some.names(), vec!["metre", "parts", "voices"]);
+        assert!(some
+            .extract(&ast)
+            .contains(&("voices".to_string(), "1".to_string())));
+
+        assert!(FeatureRegistry::with_names(&["tempo".to_string()]).is_err());
+        assert!(FeatureRegistry::new().extract(&ast).is_empty());
+    }
+
+    #[test]
+    fn metre_test() {
+        let reel = "X:1\nM:2/2\nL:1/8\nK:D\nDFAF dFAF|\n";
+        assert_eq!(features_of_type(reel, "metre"), vec!["4/4"]);
+        assert_eq!(features_of_type(reel, "metre-written"), vec!["2/2"]);
+
+        let common = "X:1\nM:C\nL:1/8\nK:D\nDFAF dFAF|\n";
+        assert_eq!(features_of_type(common, "metre"), vec!["4/4"]);
+
+        let jig = "X:1\nM:6/8\nL:1/8\nK:G\nGAB cde|\n";
+        assert_eq!(features_of_type(jig, "metre"), vec!["6/8"]);
+
+        assert_eq!(normalise("metre", "2/2"), "4/4");
+        assert_eq!(normalise("key", "2/2"), "2/2");
+    }
+
+    #[test]
+    fn normalise_test() {
+        assert_eq!(normalise("rhythm", "Bourrée"), "bourree");
+        assert_eq!(normalise("rhythm", " bourree "), "bourree");
+        assert_eq!(normalise("rhythm", "Slip  Jig"), "slip jig");
+        assert_eq!(normalise("key-signature", "F♯-Minor"), "f#-minor");
+        assert_ne!(
+            normalise("key", "B♭"),
+            normalise("key", "B"),
+            "Sharps and flats aren't lost."
+        );
+    }
 }
@@ -0,0 +1,287 @@
+//! Fingering annotations for learners.
+//! For a given instrument, work out how to play each note of a tune. These can be drawn under the
+//! stave when typesetting, or added to the ABC as `w:` lines, which ABC software shows like lyrics.
+
+use pitch;
+use std::fmt;
+use representations;
+use tune_ast_three;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Instrument {
+    // Tin whistle in D. Tunes are written an octave below how it sounds, so D is the bottom note.
+    DWhistle,
+
+    // 20 button C/G Anglo concertina.
+    AngloConcertina,
+}
+
+impl Instrument {
+    pub fn from_string(value: &str) -> Option<Instrument> {
+        match value {
+            "d-whistle" => Some(Instrument::DWhistle),
+            "anglo" => Some(Instrument::AngloConcertina),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instrument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Instrument::DWhistle => "d-whistle",
+            Instrument::AngloConcertina => "anglo",
+        })
+    }
+}
+
+// How to play one note.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum Fingering {
+    // Whistle holes from the top, true when covered, and whether to blow harder for the second
+    // octave.
+    Holes([bool; 6], bool),
+
+    // Concertina button, numbered 1 to 10 from left to right across both hands, on the C row or
+    // the G row, pushing or pulling the bellows.
+    Button { number: u8, g_row: bool, pull: bool },
+}
+
+// Plain text version, e.g. "xxxooo" for a whistle G, "xxxooo+" an octave higher.
+// Concertina buttons are numbered, "a" for the G row and "'" for pull, e.g. "3a'".
+impl fmt::Display for Fingering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fingering::Holes(holes, second_octave) => {
+                let mut result: String = holes
+                    .iter()
+                    .map(|covered| if *covered { 'x' } else { 'o' })
+                    .collect();
+                if second_octave {
+                    result.push('+');
+                }
+                f.write_str(&result)
+            }
+            Fingering::Button {
+                number,
+                g_row,
+                pull,
+            } => write!(
+                f,
+                "{}{}{}",
+                number,
+                if g_row { "a" } else { "" },
+                if pull { "'" } else { "" }
+            ),
+        }
+    }
+}
+
+// Whistle hole patterns, by MIDI pitch as written.
+// Notes that need half-holing are left out.
+const WHISTLE: [(u8, [bool; 6], bool); 15] = [
+    (62, [true, true, true, true, true, true], false),
+    (64, [true, true, true, true, true, false], false),
+    (66, [true, true, true, true, false, false], false),
+    (67, [true, true, true, false, false, false], false),
+    (69, [true, true, false, false, false, false], false),
+    (71, [true, false, false, false, false, false], false),
+    // C natural is cross-fingered.
+    (72, [false, true, true, false, false, false], false),
+    (73, [false, false, false, false, false, false], false),
+    (74, [true, true, true, true, true, true], true),
+    (76, [true, true, true, true, true, false], true),
+    (78, [true, true, true, true, false, false], true),
+    (79, [true, true, true, false, false, false], true),
+    (81, [true, true, false, false, false, false], true),
+    (83, [true, false, false, false, false, false], true),
+    (85, [false, false, false, false, false, false], true),
+];
+
+// Anglo buttons as (number, G row, pull, MIDI pitch).
+// Where a note is on more than one button, the first is used, so the C row is preferred.
+const ANGLO: [(u8, bool, bool, u8); 40] = [
+    // Left hand, C row.
+    (1, false, false, 48),
+    (1, false, true, 55),
+    (2, false, false, 55),
+    (2, false, true, 59),
+    (3, false, false, 60),
+    (3, false, true, 62),
+    (4, false, false, 64),
+    (4, false, true, 65),
+    (5, false, false, 67),
+    (5, false, true, 69),
+    // Right hand, C row.
+    (6, false, false, 72),
+    (6, false, true, 71),
+    (7, false, false, 76),
+    (7, false, true, 74),
+    (8, false, false, 79),
+    (8, false, true, 77),
+    (9, false, false, 84),
+    (9, false, true, 81),
+    (10, false, false, 88),
+    (10, false, true, 83),
+    // Left hand, G row.
+    (1, true, false, 47),
+    (1, true, true, 45),
+    (2, true, false, 50),
+    (2, true, true, 54),
+    (3, true, false, 55),
+    (3, true, true, 57),
+    (4, true, false, 59),
+    (4, true, true, 60),
+    (5, true, false, 62),
+    (5, true, true, 64),
+    // Right hand, G row.
+    (6, true, false, 67),
+    (6, true, true, 66),
+    (7, true, false, 71),
+    (7, true, true, 69),
+    (8, true, false, 74),
+    (8, true, true, 72),
+    (9, true, false, 79),
+    (9, true, true, 76),
+    (10, true, false, 83),
+    (10, true, true, 78),
+];
+
+// How to play a pitch on the instrument, if it can be played.
+pub fn fingering(instrument: Instrument, midi_pitch: u8) -> Option<Fingering> {
+    match instrument {
+        Instrument::DWhistle => WHISTLE
+            .iter()
+            .find(|(pitch, _, _)| *pitch == midi_pitch)
+            .map(|(_, holes, second_octave)| Fingering::Holes(*holes, *second_octave)),
+
+        Instrument::AngloConcertina => ANGLO
+            .iter()
+            .find(|(_, _, _, pitch)| *pitch == midi_pitch)
+            .map(|(number, g_row, pull, _)| Fingering::Button {
+                number: *number,
+                g_row: *g_row,
+                pull: *pull,
+            }),
+    }
+}
+
+// Fingering for each note in the tune, in order, if it can be played.
+pub fn annotate(ast: &tune_ast_three::Tune, instrument: Instrument) -> Vec<Option<Fingering>> {
+    pitch::PitchSequence::from_ast_in_key(ast)
+        .pitches
+        .iter()
+        .map(|x| fingering(instrument, *x))
+        .collect()
+}
+
+// Add a `w:` line of fingerings under each line of music.
+// Notes that can't be played are skipped with `*`, to keep the rest aligned.
+pub fn abc_with_fingerings(abc: &str, instrument: Instrument) -> String {
+    let chars = abc.chars().collect::<Vec<char>>();
+    let ast = representations::abc_to_ast(abc);
+    let ranges = representations::ast_note_ranges(&chars, &ast);
+    let fingerings = annotate(&ast, instrument);
+
+    // Pair each note's offset with its fingering, then put them back in source order.
+    let mut notes = ranges
+        .iter()
+        .map(|(_, start, _)| *start)
+        .zip(fingerings.iter())
+        .collect::<Vec<_>>();
+    notes.sort_by_key(|(start, _)| *start);
+    let mut notes = notes.into_iter().peekable();
+
+    let mut result = String::with_capacity(abc.len() * 2);
+    let mut offset = 0;
+    for line in abc.lines() {
+        let end = offset + line.chars().count();
+
+        let mut words = vec![];
+        while let Some((start, fingering)) = notes.peek().cloned() {
+            if start >= end {
+                break;
+            }
+
+            words.push(match fingering {
+                Some(fingering) => fingering.to_string(),
+                None => "*".to_string(),
+            });
+            notes.next();
+        }
+
+        result.push_str(line);
+        result.push('\n');
+
+        if !words.is_empty() {
+            result.push_str("w:");
+            result.push_str(&words.join(" "));
+            result.push('\n');
+        }
+
+        // Skip the newline, which may be CRLF.
+        offset = end;
+        if chars.get(offset) == Some(&'\r') {
+            offset += 1;
+        }
+        if chars.get(offset) == Some(&'\n') {
+            offset += 1;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingering_test() {
+        assert_eq!(
+            fingering(Instrument::DWhistle, 67).map(|x| x.to_string()),
+            Some("xxxooo".to_string())
+        );
+        assert_eq!(
+            fingering(Instrument::DWhistle, 79).map(|x| x.to_string()),
+            Some("xxxooo+".to_string())
+        );
+        assert_eq!(
+            fingering(Instrument::DWhistle, 60),
+            None,
+            "Below the range of the whistle."
+        );
+
+        assert_eq!(
+            fingering(Instrument::AngloConcertina, 62).map(|x| x.to_string()),
+            Some("3'".to_string())
+        );
+        assert_eq!(
+            fingering(Instrument::AngloConcertina, 66).map(|x| x.to_string()),
+            Some("6a'".to_string()),
+            "F sharp is only on the G row."
+        );
+    }
+
+    #[test]
+    fn abc_with_fingerings_test() {
+        assert_eq!(
+            abc_with_fingerings("X:1\nT:Scale\nK:D\nDEF|\nG_B|\n", Instrument::DWhistle),
+            "X:1\nT:Scale\nK:D\nDEF|\nw:xxxxxx xxxxxo xxxxoo\nG_B|\nw:xxxooo *\n",
+            "Key signature applies. Notes that can't be played are skipped."
+        );
+    }
+
+    #[test]
+    fn abc_with_fingerings_voices_test() {
+        assert_eq!(
+            abc_with_fingerings(
+                "X:1\nL:1/8\nK:D\nV:1\nde|\nV:2\nDE|\nV:1\nf|\n",
+                Instrument::DWhistle
+            ),
+            "X:1\nL:1/8\nK:D\nV:1\nde|\nw:xxxxxx+ xxxxxo+\nV:2\nDE|\nw:xxxxxx xxxxxo\n\
+             V:1\nf|\nw:xxxxoo+\n",
+            "Fingerings follow each voice's notes when voices are interleaved."
+        );
+    }
+}
@@ -1 +1,77 @@
+//! FolkTuneFinder ABC Tools
+//! Read, typeset, analyse and search tunes written in ABC notation.
+//!
+//! The main parts are:
+//!  - `abc_lexer` - turn ABC text into tokens, with errors for anything it doesn't understand.
+//!  - `music` - pitches, durations, keys and other musical building blocks used by the tokens.
+//!  - `representations` - convert a tune from one representation to another, e.g. ABC to AST, SVG,
+//!    PNG or MIDI, or to the pitches and features used for search.
+//!  - `typeset` - lay out a tune as a score.
+//!  - `search` - find tunes by melody and by features.
+//!  - `query` - build or parse the queries that `search` answers.
+//!  - `storage` and `sqlite` - where the tunes are kept.
+//!  - `config` - settings from a TOML file and the environment, loaded once at startup.
+//!  - `cli` - the commands of the `abctool` binary.
+//!
+//! ```
+//! extern crate folktunefinder_abc;
+//! use folktunefinder_abc::{representations, typeset};
+//!
+//! let ast = representations::abc_to_ast(&"X:1\nT:Example\nK:G\nGAB c2|\n".to_string());
+//! assert_eq!(representations::title(&ast), Some("Example".to_string()));
+//!
+//! let svg = representations::ast_to_svg(&ast, &typeset::Typesetting::new());
+//! assert!(svg.contains("Example"));
+//! ```
+//!
+//! The `abctool` binary is a command line interface to all of this.
 
+extern crate serde;
+extern crate serde_json;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate glob;
+extern crate handlebars;
+extern crate memmap2;
+extern crate rand;
+extern crate regex;
+extern crate rusqlite;
+extern crate tiny_http;
+extern crate toml;
+extern crate unidecode;
+extern crate url;
+
+#[macro_use]
+mod logging;
+
+pub mod abc_lexer;
+pub mod abc_writer;
+mod beaming;
+pub mod cli;
+pub mod config;
+mod corpus;
+mod diff;
+mod end_to_end_test;
+mod export;
+pub mod features;
+mod fingering;
+mod lint;
+mod lsp;
+mod midi;
+pub mod music;
+pub mod pitch;
+pub mod query;
+mod raster;
+pub mod relations;
+pub mod representations;
+pub mod search;
+mod server;
+mod spellcheck;
+pub mod sqlite;
+pub mod storage;
+mod svg;
+pub mod text;
+pub mod tune_ast_three;
+pub mod typeset;
@@ -0,0 +1,377 @@
+//! Check the music for mistakes that still lex, e.g. bars that are too long or too short for the
+//! metre. These are the most common problem in the corpus, and nothing else notices them.
+
+use abc_lexer as l;
+use lsp;
+use music;
+use std::collections::HashMap;
+use tune_ast_three;
+
+/// A bar whose notes and rests don't add up to a bar of the metre.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BarProblem {
+    /// ID of the voice the bar is in, if the tune has more than one.
+    pub voice: Option<String>,
+
+    /// Number of the bar, counting from the first full bar, so a pick-up is bar 0.
+    pub bar: usize,
+
+    /// Where the bar's first note is in the ABC, counting from 1. Columns are in UTF-16 code
+    /// units, as in the validate API.
+    pub line: usize,
+    pub column: usize,
+
+    /// How long the bar is, and how long a bar of the metre is.
+    pub duration: music::FractionalDuration,
+    pub expected: music::FractionalDuration,
+}
+
+impl BarProblem {
+    pub fn is_too_long(&self) -> bool {
+        self.duration.gte(&self.expected)
+    }
+
+    /// e.g. "Bar 3 is too long: 7/8 rather than 6/8."
+    pub fn message(&self) -> String {
+        let bar = match self.voice {
+            Some(ref voice) => format!("Bar {} of voice {}", self.bar, voice),
+            None => format!("Bar {}", self.bar),
+        };
+
+        // Count in the metre's units where possible, e.g. 4/8 rather than 1/2 in 6/8.
+        let music::FractionalDuration(numerator, denominator) = self.duration.reduce();
+        let music::FractionalDuration(expected_numerator, expected_denominator) = self.expected;
+        let scale = match denominator {
+            0 => 1,
+            _ if expected_denominator % denominator == 0 => expected_denominator / denominator,
+            _ => 1,
+        };
+        let (numerator, denominator) = (numerator * scale, denominator * scale);
+
+        format!(
+            "{} is too {}: {}/{} rather than {}/{}.",
+            bar,
+            if self.is_too_long() { "long" } else { "short" },
+            numerator,
+            denominator,
+            expected_numerator,
+            expected_denominator
+        )
+    }
+}
+
+// A bar of a voice, as read for checking.
+struct Bar {
+    number: usize,
+    duration: music::FractionalDuration,
+
+    // Length of a full bar in the metre at the time, if there is one.
+    length: Option<music::FractionalDuration>,
+
+    // Index among the voice's notes of the first note in the bar, or after it if it only has
+    // rests.
+    note: usize,
+
+    // Is the bar just after a repeat, double bar or ending number, or just before a repeat or
+    // double bar? Parts and endings can start or finish with part of a bar.
+    starts_section: bool,
+    ends_section: bool,
+}
+
+fn same(a: music::FractionalDuration, b: music::FractionalDuration) -> bool {
+    a.reduce() == b.reduce()
+}
+
+fn bar_length(metre: music::Metre) -> Option<music::FractionalDuration> {
+    music::FractionalDuration::new(metre.0, metre.1).filter(|length| length.0 > 0)
+}
+
+// The bars of a voice that have music in them.
+fn read_bars(voice: &[l::T], metre: Option<music::Metre>, pickup: bool) -> Vec<Bar> {
+    let mut bars = vec![];
+
+    let mut length = metre.and_then(bar_length);
+    let mut number = if pickup { 0 } else { 1 };
+    let mut notes = 0;
+
+    // The bar so far.
+    let mut duration = music::FractionalDuration(0, 1);
+    let mut has_music = false;
+    let mut first_note = 0;
+    let mut starts_section = true;
+
+    for token in voice.iter() {
+        match token {
+            l::T::Note(music::Note(_, note_duration)) | l::T::Rest(note_duration) => {
+                if !has_music {
+                    first_note = notes;
+                    has_music = true;
+                }
+                if let l::T::Note(_) = token {
+                    notes += 1;
+                }
+                duration = duration + *note_duration;
+            }
+
+            l::T::Metre(metre) => length = bar_length(*metre),
+
+            l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_) => {
+                let ends_section =
+                    matches!(token, l::T::CloseRepeat | l::T::DoubleBar | l::T::EndBar);
+
+                // Barlines read as more than one token, e.g. ":|]", belong to the bar before.
+                if has_music {
+                    bars.push(Bar {
+                        number,
+                        duration,
+                        length,
+                        note: first_note,
+                        starts_section,
+                        ends_section,
+                    });
+
+                    number += 1;
+                    duration = music::FractionalDuration(0, 1);
+                    has_music = false;
+                    starts_section = false;
+                } else if let (true, Some(bar)) = (ends_section, bars.last_mut()) {
+                    bar.ends_section = true;
+                }
+
+                if *token != l::T::SingleBar {
+                    starts_section = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Music after the last barline is a bar, and the last bar ends the voice.
+    if has_music {
+        bars.push(Bar {
+            number,
+            duration,
+            length,
+            note: first_note,
+            starts_section,
+            ends_section: true,
+        });
+    } else if let Some(bar) = bars.last_mut() {
+        bar.ends_section = true;
+    }
+
+    bars
+}
+
+// Is the bar as long as it should be, or short for a reason? The first bar can be a pick-up.
+// A bar at the end of a part or ending can be short if it makes a full bar with the pick-up, or
+// with a short bar that starts the next part. A part can also start with a pick-up as long as the
+// first one.
+fn is_ok(bars: &[Bar], i: usize, anacrusis: Option<music::FractionalDuration>) -> bool {
+    let bar = &bars[i];
+    let length = match bar.length {
+        Some(length) => length,
+        None => return true,
+    };
+
+    if same(bar.duration, length) {
+        return true;
+    }
+    if bar.duration.gte(&length) {
+        return false;
+    }
+    if i == 0 {
+        return true;
+    }
+
+    let makes_bar = |other: music::FractionalDuration| same(bar.duration + other, length);
+
+    let at_end = bar.ends_section
+        && (anacrusis.is_some_and(makes_bar)
+            || bars
+                .get(i + 1)
+                .is_some_and(|next| next.starts_section && makes_bar(next.duration)));
+
+    let at_start = bar.starts_section
+        && (anacrusis.is_some_and(|anacrusis| same(anacrusis, bar.duration))
+            || (bars[i - 1].ends_section && makes_bar(bars[i - 1].duration)));
+
+    at_end || at_start
+}
+
+// Where each voice's notes start in the ABC, by voice ID, in the order they're in the voice.
+// Music goes to voices as in `tune_ast_three::read_from_lexer`.
+fn note_offsets(chars: &[char], dialect: l::Dialect) -> HashMap<String, Vec<usize>> {
+    let mut result: HashMap<String, Vec<usize>> = HashMap::new();
+
+    // The first voice declared in the header, and the voice the body has switched to.
+    let mut declared: Option<String> = None;
+    let mut current: Option<String> = None;
+    let mut in_body = false;
+
+    // Notes start where the previous token ended.
+    let mut previous_end = 0;
+
+    for lex_result in l::Lexer::new(chars).with_dialect(dialect) {
+        match lex_result {
+            l::LexResult::T(ctx, tokens) => {
+                for token in tokens.iter() {
+                    match token {
+                        l::T::KeySignature(_, _) => in_body = true,
+                        l::T::Voice(id, _) if in_body => current = Some(id.clone()),
+                        l::T::Voice(id, _) if declared.is_none() => declared = Some(id.clone()),
+                        l::T::Note(_) => {
+                            let voice = current
+                                .clone()
+                                .or_else(|| declared.clone())
+                                .unwrap_or("1".to_string());
+                            result.entry(voice).or_default().push(previous_end);
+                        }
+                        _ => (),
+                    }
+                }
+                previous_end = ctx.offset();
+            }
+            l::LexResult::Error(ctx, _, _) => previous_end = ctx.offset(),
+            l::LexResult::Terminal => (),
+        }
+    }
+
+    result
+}
+
+/// Bars in a tune whose notes and rests don't add up to a bar of the metre, in the order of the
+/// voices. Voices without a metre aren't checked. Key notes are read in the dialect.
+pub fn bar_problems(content: &str, dialect: l::Dialect) -> Vec<BarProblem> {
+    let chars = content.chars().collect::<Vec<char>>();
+    let ast =
+        tune_ast_three::read_with_standard_lengths(l::Lexer::new(&chars).with_dialect(dialect));
+    let offsets = note_offsets(&chars, dialect);
+
+    let metre = ast
+        .prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::Metre(metre) => Some(*metre),
+            _ => None,
+        }).next_back();
+
+    let mut result = vec![];
+    for (voice, properties) in ast.voices.iter().zip(ast.voice_properties.iter()) {
+        let bars = read_bars(voice, metre, properties.anacrusis.is_some());
+        let notes = offsets.get(&properties.id).cloned().unwrap_or_default();
+
+        for (i, bar) in bars.iter().enumerate() {
+            if is_ok(&bars, i, properties.anacrusis) {
+                continue;
+            }
+
+            // A bar of rests at the end is marked at the last note.
+            let offset = notes
+                .get(bar.note)
+                .or_else(|| notes.last())
+                .cloned()
+                .unwrap_or(0);
+            let position = lsp::offset_to_position(&chars, offset);
+
+            result.push(BarProblem {
+                voice: if ast.voices.len() > 1 {
+                    Some(properties.id.clone())
+                } else {
+                    None
+                },
+                bar: bar.number,
+                line: position.line + 1,
+                column: position.character + 1,
+                duration: bar.duration,
+                expected: bar.length.unwrap_or(bar.duration),
+            });
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "line:column message" for each problem.
+    fn problems(abc: &str) -> Vec<String> {
+        bar_problems(abc, l::Dialect::Standard)
+            .iter()
+            .map(|x| format!("{}:{} {}", x.line, x.column, x.message()))
+            .collect()
+    }
+
+    #[test]
+    fn bar_problems_test() {
+        let abc = "X:1\nM:6/8\nL:1/8\nK:D\nd2f afd|e3 e2|\nd2f afdd|e3 e3|]\n";
+        assert_eq!(
+            problems(abc),
+            vec![
+                "5:9 Bar 2 is too short: 5/8 rather than 6/8.",
+                "6:1 Bar 3 is too long: 7/8 rather than 6/8.",
+            ]
+        );
+
+        // Tunes that add up, or have no metre to check against, are fine.
+        assert!(problems("X:1\nM:3/4\nL:1/8\nK:G\nG2 A2 B2|d6|]\n").is_empty());
+        assert!(problems("X:1\nL:1/8\nK:G\nG2 A2 B2|d4|]\n").is_empty());
+    }
+
+    #[test]
+    fn anacrusis_test() {
+        // The pick-up and the short bars at the ends of the parts make full bars together.
+        let jig = "X:1\nM:6/8\nL:1/8\nK:D\nA|:d2f afd|e3 e2:|\n|:f|afd afd|1 e3 e2:|2 e3 d2|]\n";
+        assert!(problems(jig).is_empty());
+
+        // A short bar in the middle isn't a pick-up.
+        let jig = "X:1\nM:6/8\nL:1/8\nK:D\nA|:d2f afd|e3 e2|d2f afd|e3 e2:|\n";
+        assert_eq!(
+            problems(jig),
+            vec!["5:12 Bar 2 is too short: 5/8 rather than 6/8."]
+        );
+
+        // A part can start short if the one before ends short by as much.
+        let reel = "X:1\nM:4/4\nL:1/8\nK:G\nGABc d2e2|d4 B3||A|GABc d2e2|d8|]\n";
+        assert!(problems(reel).is_empty());
+    }
+
+    #[test]
+    fn default_length_test() {
+        // Without an L: field, the notes in 2/4 are sixteenths.
+        let abc = "X:1\nM:2/4\nK:G\nG2A2 B4|c8|d4 c2|]\n";
+        assert_eq!(
+            problems(abc),
+            vec!["4:12 Bar 3 is too short: 3/8 rather than 2/4."]
+        );
+    }
+
+    #[test]
+    fn dialect_test() {
+        let abc = "X:1\nM:2/4\nL:1/8\nK:Fis\nFG A2|B3|\n";
+        let problems = bar_problems(abc, l::Dialect::German)
+            .iter()
+            .map(|x| format!("{}:{} {}", x.line, x.column, x.message()))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            problems,
+            vec!["5:7 Bar 2 is too short: 3/8 rather than 2/4."]
+        );
+    }
+
+    #[test]
+    fn voices_test() {
+        let abc = "X:1\nM:2/4\nL:1/8\nK:G\nV:1\nG2 A2|B4|\nV:2\nG,2 A,2|B,3|\n";
+        assert_eq!(
+            problems(abc),
+            vec!["8:9 Bar 2 of voice 2 is too short: 3/8 rather than 2/4."]
+        );
+    }
+}
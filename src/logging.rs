@@ -0,0 +1,175 @@
+//! Levelled logging to STDERR.
+//! Each line has a timestamp, level and target (the module it came from). Configured with:
+//!  - LOG_LEVEL - error, warn, info or debug. Default info.
+//!  - LOG_FORMAT - text, or json for one JSON object per line. Default text.
+//!
+//! Use the `error!`, `warn!`, `info!` and `debug!` macros, which take the same arguments as
+//! `format!`. They're only for this crate, so they don't clash with the `log` crate's.
+
+extern crate time;
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use serde_json;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    pub fn from_string(value: &str) -> Option<Level> {
+        match value.to_lowercase().as_ref() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+// Zero until read from the environment.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// The most verbose level to log, reading config the first time.
+fn max_level() -> usize {
+    let level = MAX_LEVEL.load(Ordering::Relaxed);
+    if level != 0 {
+        return level;
+    }
+
+    let level = match env::var("LOG_LEVEL") {
+        Ok(value) => Level::from_string(&value).unwrap_or(Level::Info),
+        Err(_) => Level::Info,
+    } as usize;
+
+    let json = match env::var("LOG_FORMAT") {
+        Ok(value) => value == "json",
+        Err(_) => false,
+    };
+
+    JSON.store(json, Ordering::Relaxed);
+    MAX_LEVEL.store(level, Ordering::Relaxed);
+    level
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= max_level()
+}
+
+// Format a log line.
+fn format_line(json: bool, timestamp: &str, level: Level, target: &str, message: &str) -> String {
+    if json {
+        serde_json::json!({
+            "time": timestamp,
+            "level": level.to_string(),
+            "target": target,
+            "message": message,
+        }).to_string()
+    } else {
+        format!(
+            "{} {:5} {}: {}",
+            timestamp,
+            level.to_string(),
+            target,
+            message
+        )
+    }
+}
+
+pub fn log(level: Level, target: &str, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let timestamp = format!("{}", time::now_utc().rfc3339());
+    let line = format_line(
+        JSON.load(Ordering::Relaxed),
+        &timestamp,
+        level,
+        target,
+        &fmt::format(message),
+    );
+
+    eprintln!("{}", line);
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Warn, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_line_test() {
+        assert_eq!(
+            format_line(
+                false,
+                "2018-12-01T10:00:00Z",
+                Level::Warn,
+                "abctool::storage",
+                "Oops"
+            ),
+            "2018-12-01T10:00:00Z WARN  abctool::storage: Oops"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&format_line(
+            true,
+            "2018-12-01T10:00:00Z",
+            Level::Info,
+            "abctool::search",
+            "Say \"hello\"",
+        )).unwrap();
+
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["target"], "abctool::search");
+        assert_eq!(json["message"], "Say \"hello\"");
+    }
+
+    #[test]
+    fn level_test() {
+        assert!(Level::Error < Level::Debug);
+        assert_eq!(Level::from_string("WARN"), Some(Level::Warn));
+        assert_eq!(Level::from_string("loud"), None);
+    }
+}
@@ -0,0 +1,441 @@
+//! Minimal Language Server for ABC files.
+//! Speaks JSON-RPC over STDIN / STDOUT, using the Language Server Protocol framing.
+//! Provides diagnostics from the lexer and document symbols for headers and bars.
+//! Documents are synchronized in full on every change, which is fine for tune-sized files.
+
+use abc_lexer as l;
+use serde_json;
+use serde_json::json;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+// LSP DiagnosticSeverity.
+const SEVERITY_ERROR: u32 = 1;
+
+// LSP SymbolKind.
+const SYMBOL_KIND_PROPERTY: u32 = 7;
+const SYMBOL_KIND_NUMBER: u32 = 16;
+
+// LSP TextDocumentSyncKind. Full text of the document is sent on each change.
+const SYNC_FULL: u32 = 1;
+
+// JSON-RPC error code.
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// Line and character position, as used by the protocol.
+/// Character is counted in UTF-16 code units, as the protocol requires.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Position {
+    fn to_json(self) -> Value {
+        json!({"line": self.line, "character": self.character})
+    }
+}
+
+fn range_to_json(start: Position, end: Position) -> Value {
+    json!({"start": start.to_json(), "end": end.to_json()})
+}
+
+/// Convert a character offset into the input into a line / character position.
+pub fn offset_to_position(input: &[char], offset: usize) -> Position {
+    let mut line = 0;
+    let mut character = 0;
+
+    for c in input.iter().take(offset) {
+        if *c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16();
+        }
+    }
+
+    Position { line, character }
+}
+
+/// Skip forward over whitespace, so token ranges don't start with it.
+fn skip_whitespace(input: &[char], offset: usize, end: usize) -> usize {
+    let mut offset = offset;
+    while offset < end && input[offset].is_whitespace() {
+        offset += 1;
+    }
+    offset
+}
+
+/// Diagnostics for every lexer error.
+pub fn diagnostics(input: &[char]) -> Vec<Value> {
+    l::Lexer::new(input)
+        .collect_errors()
+        .iter()
+        .map(|&(ctx, offset, ref error)| {
+            let mut message = String::new();
+            error.format(0, &mut message);
+
+            // The context points to the end of the troublesome bit.
+            let end = usize::max(offset + 1, ctx.offset());
+
+            json!({
+                "range": range_to_json(
+                    offset_to_position(input, offset),
+                    offset_to_position(input, usize::min(end, input.len()))),
+                "severity": SEVERITY_ERROR,
+                "source": "abctool",
+                "message": message,
+            })
+        }).collect()
+}
+
+/// Name of a header field, if this token is one.
+fn header_name(token: &l::T) -> Option<String> {
+    match token {
+        l::T::Area(x) => Some(format!("A: {}", x)),
+        l::T::Book(x) => Some(format!("B: {}", x)),
+        l::T::Composer(x) => Some(format!("C: {}", x)),
+        l::T::Discography(x) => Some(format!("D: {}", x)),
+        l::T::Filename(x) => Some(format!("F: {}", x)),
+        l::T::Group(x) => Some(format!("G: {}", x)),
+        l::T::History(x) => Some(format!("H: {}", x)),
+        l::T::Information(x) => Some(format!("I: {}", x)),
+        l::T::Notes(x) => Some(format!("N: {}", x)),
+        l::T::Origin(x) => Some(format!("O: {}", x)),
+        l::T::Source(x) => Some(format!("S: {}", x)),
+        l::T::Title(x) => Some(format!("T: {}", x)),
+        l::T::Words(x) => Some(format!("W: {}", x)),
+        l::T::Rhythm(x) => Some(format!("R: {}", x)),
+        l::T::X(x) => Some(format!("X: {}", x)),
+        l::T::Transcription(x) => Some(format!("Z: {}", x)),
+        l::T::Tempo(x) => Some(format!("Q: {}", x)),
+        l::T::Metre(metre) => Some(format!("M: {}", metre)),
+        l::T::KeySignature(pitch_class, mode) => Some(format!(
+            "K: {} {}",
+            pitch_class,
+            mode
+        )),
+        l::T::DefaultNoteLength(duration) => Some(format!("L: {}/{}", duration.0, duration.1)),
+        _ => None,
+    }
+}
+
+fn is_barline(token: &l::T) -> bool {
+    matches!(
+        token,
+        l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_)
+    )
+}
+
+fn symbol(name: String, kind: u32, input: &[char], start: usize, end: usize) -> Value {
+    let range = range_to_json(
+        offset_to_position(input, start),
+        offset_to_position(input, end),
+    );
+
+    json!({
+        "name": name,
+        "kind": kind,
+        "range": range,
+        "selectionRange": range,
+    })
+}
+
+/// Document symbols for headers and numbered bars.
+pub fn document_symbols(input: &[char]) -> Vec<Value> {
+    let mut symbols = vec![];
+
+    // End of the previous token, i.e. start of the next.
+    let mut previous_end = 0;
+
+    // Start of the current bar, and whether it has any content yet.
+    let mut bar_start: Option<usize> = None;
+    let mut bar_has_content = false;
+    let mut bar_number = 1;
+
+    for result in l::Lexer::new(input) {
+        match result {
+            l::LexResult::T(ctx, tokens) => {
+                let end = ctx.offset();
+                let start = skip_whitespace(input, previous_end, end);
+
+                for token in tokens.iter() {
+                    if let Some(name) = header_name(token) {
+                        // Header tokens include the trailing newline.
+                        let mut header_end = end;
+                        while header_end > start && input[header_end - 1].is_whitespace() {
+                            header_end -= 1;
+                        }
+
+                        symbols.push(symbol(
+                            name,
+                            SYMBOL_KIND_PROPERTY,
+                            input,
+                            start,
+                            header_end,
+                        ));
+                    } else if is_barline(token) {
+                        // Ignore barlines that don't close a bar, e.g. an opening repeat.
+                        if bar_has_content {
+                            symbols.push(symbol(
+                                format!("Bar {}", bar_number),
+                                SYMBOL_KIND_NUMBER,
+                                input,
+                                bar_start.unwrap_or(start),
+                                end,
+                            ));
+                            bar_number += 1;
+                        }
+
+                        bar_start = None;
+                        bar_has_content = false;
+                    } else {
+                        match token {
+                            l::T::Note(_) | l::T::Rest(_) => {
+                                if bar_start.is_none() {
+                                    bar_start = Some(start);
+                                }
+                                bar_has_content = true;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+
+                previous_end = end;
+            }
+            l::LexResult::Error(ctx, _, _) => {
+                previous_end = ctx.offset();
+            }
+            l::LexResult::Terminal => (),
+        }
+    }
+
+    // Trailing bar without a closing barline.
+    if let Some(start) = bar_start {
+        if bar_has_content {
+            symbols.push(symbol(
+                format!("Bar {}", bar_number),
+                SYMBOL_KIND_NUMBER,
+                input,
+                start,
+                previous_end,
+            ));
+        }
+    }
+
+    symbols
+}
+
+/// Read one message. None when the input is closed.
+fn read_message<R: BufRead>(reader: &mut R) -> Option<Value> {
+    let mut content_length: Option<usize> = None;
+
+    // Headers, terminated by an empty line.
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            _ => (),
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            if content_length.is_some() {
+                break;
+            } else {
+                continue;
+            }
+        }
+
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut buf = vec![0; content_length.unwrap_or(0)];
+    if reader.read_exact(&mut buf).is_err() {
+        return None;
+    }
+
+    match serde_json::from_slice(&buf) {
+        Ok(message) => Some(message),
+        Err(err) => {
+            warn!("Can't parse message: {:?}", err);
+            Some(Value::Null)
+        }
+    }
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &Value) {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| writer.flush())
+        .expect("Can't write message!");
+}
+
+fn publish_diagnostics<W: Write>(writer: &mut W, uri: &str, diagnostics: Vec<Value>) {
+    write_message(
+        writer,
+        &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        }),
+    );
+}
+
+// Text of the document from a didOpen or didChange notification.
+fn document_text(method: &str, params: &Value) -> Option<String> {
+    match method {
+        "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+        // Full sync, so the last change contains the whole document.
+        _ => params["contentChanges"]
+            .as_array()
+            .and_then(|changes| changes.last())
+            .and_then(|change| change["text"].as_str()),
+    }.map(|x| x.to_string())
+}
+
+/// Run the server until the client sends 'exit' or closes the input.
+pub fn main() {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+
+    // Open documents by URI.
+    let mut documents: HashMap<String, Vec<char>> = HashMap::new();
+
+    while let Some(message) = read_message(&mut reader) {
+        let method = message["method"].as_str().unwrap_or("").to_string();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        // Requests have an ID and expect a response. Notifications don't.
+        let result = match method.as_ref() {
+            "initialize" => Some(Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": SYNC_FULL,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": {"name": "abctool"},
+            }))),
+            "shutdown" => Some(Ok(Value::Null)),
+            "exit" => return,
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                if let Some(text) = document_text(&method, params) {
+                    let chars = text.chars().collect::<Vec<char>>();
+                    publish_diagnostics(&mut writer, &uri, diagnostics(&chars));
+                    documents.insert(uri, chars);
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                publish_diagnostics(&mut writer, &uri, vec![]);
+                None
+            }
+            "textDocument/documentSymbol" => Some(Ok(match documents.get(&uri) {
+                Some(chars) => json!(document_symbols(chars)),
+                None => Value::Null,
+            })),
+            _ => Some(Err(json!({
+                "code": METHOD_NOT_FOUND,
+                "message": format!("Method not supported: {}", method),
+            }))),
+        };
+
+        // Only respond to requests.
+        if let Some(id) = message.get("id") {
+            let response = match result {
+                Some(Ok(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Some(Err(error)) => json!({"jsonrpc": "2.0", "id": id, "error": error}),
+                None => continue,
+            };
+            write_message(&mut writer, &response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(input: &str) -> Vec<char> {
+        input.chars().collect::<Vec<char>>()
+    }
+
+    #[test]
+    fn offset_to_position_test() {
+        let input = chars("X:1\nT:Fé\nK:G\n");
+
+        assert_eq!(
+            offset_to_position(&input, 0),
+            Position {
+                line: 0,
+                character: 0
+            }
+        );
+
+        assert_eq!(
+            offset_to_position(&input, 6),
+            Position {
+                line: 1,
+                character: 2
+            }
+        );
+
+        assert_eq!(
+            offset_to_position(&input, 9),
+            Position {
+                line: 2,
+                character: 0
+            }
+        );
+    }
+
+    #[test]
+    fn diagnostics_test() {
+        assert!(diagnostics(&chars("X:1\nT:Tune\nK:G\nGAB|cde|\n")).is_empty());
+
+        let result = diagnostics(&chars("X:1\nK:G\nGA#B|\n"));
+        assert_eq!(result.len(), 1, "Unrecognised note should be reported.");
+        assert_eq!(result[0]["range"]["start"]["line"], 2);
+        assert_eq!(result[0]["range"]["start"]["character"], 2);
+    }
+
+    #[test]
+    fn document_symbols_test() {
+        let input = chars("X:1\nT:Tune\nK:G\n|:GAB|cde:|\nfga\n");
+        let result = document_symbols(&input);
+        let names = result
+            .iter()
+            .map(|x| x["name"].as_str().unwrap().to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            names,
+            vec!["X: 1", "T: Tune", "K: G Major", "Bar 1", "Bar 2", "Bar 3"],
+            "Headers and bars, but not the opening repeat, should be symbols."
+        );
+
+        assert_eq!(result[1]["range"]["start"]["line"], 1);
+        assert_eq!(result[1]["range"]["end"]["character"], 6);
+        assert_eq!(result[3]["range"]["start"]["character"], 2);
+        assert_eq!(result[5]["range"]["start"]["line"], 4);
+    }
+}
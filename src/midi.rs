@@ -0,0 +1,466 @@
+//! MIDI files of tunes, for playback.
+//! Each voice is a track, played as it sounds: in the key, with accidentals and the voice's
+//! transposition. Repeats are played once, as written.
+//! Written durations can be swung, e.g. to give a hornpipe its triplet feel.
+
+use abc_lexer as l;
+use music;
+use pitch;
+use tune_ast_three;
+
+// Ticks per crotchet.
+const DIVISION: u32 = 480;
+const TICKS_PER_SEMIBREVE: f32 = (DIVISION * 4) as f32;
+
+// Tempo if the tune doesn't give one, in crotchets per minute.
+const DEFAULT_TEMPO: f32 = 120.0;
+
+const VELOCITY: u8 = 80;
+
+// MIDI channel 10 is for percussion, so voices skip it.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+// Swing ratios chosen automatically. Hornpipes get a triplet feel, jigs and other compound metres
+// a lighter lilt.
+const HORNPIPE_SWING: f32 = 2.0;
+const COMPOUND_SWING: f32 = 1.5;
+
+// Largest ratio of long to short notes accepted.
+const MAX_SWING: f32 = 4.0;
+
+// How to play the written durations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Swing {
+    // As written.
+    Straight,
+
+    // Quavers on the beat are played longer than the ones after them, by this ratio, e.g. 2.0
+    // for a triplet feel. In compound metres it's the first of each group of three.
+    Ratio(f32),
+
+    // Chosen from the tune's rhythm and metre.
+    Auto,
+}
+
+impl Swing {
+    // From an optional `swing` option value, e.g. "2" or "auto". Default is straight.
+    pub fn parse(value: Option<&str>) -> Result<Swing, String> {
+        match value {
+            None | Some("straight") => Ok(Swing::Straight),
+            Some("auto") => Ok(Swing::Auto),
+            Some(value) => match value.parse::<f32>() {
+                Ok(ratio) if (1.0..=MAX_SWING).contains(&ratio) => Ok(Swing::Ratio(ratio)),
+                _ => Err(format!(
+                    "Invalid value for 'swing'. Must be 'straight', 'auto' or a ratio from 1 to \
+                     {}.",
+                    MAX_SWING
+                )),
+            },
+        }
+    }
+
+    // Short description, e.g. for cache keys.
+    pub fn cache_key(self) -> String {
+        match self {
+            Swing::Straight => "straight".to_string(),
+            Swing::Ratio(ratio) => format!("swing{}", ratio),
+            Swing::Auto => "auto".to_string(),
+        }
+    }
+
+    // Ratio of long to short notes for this tune, or None to play it straight.
+    pub fn ratio(self, ast: &tune_ast_three::Tune) -> Option<f32> {
+        match self {
+            Swing::Straight => None,
+            Swing::Ratio(ratio) => Some(ratio),
+            Swing::Auto => {
+                let hornpipe = ast.prelude.iter().any(|x| match x {
+                    l::T::Rhythm(rhythm) => rhythm.to_lowercase().contains("hornpipe"),
+                    _ => false,
+                });
+
+                if hornpipe {
+                    Some(HORNPIPE_SWING)
+                } else if is_compound(metre(ast)) {
+                    Some(COMPOUND_SWING)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+// The tune's metre, 4/4 if it doesn't say.
+fn metre(ast: &tune_ast_three::Tune) -> music::Metre {
+    ast.prelude
+        .iter()
+        .rev()
+        .find_map(|x| match x {
+            l::T::Metre(metre) => Some(*metre),
+            _ => None,
+        }).unwrap_or(music::Metre(4, 4))
+}
+
+// Is the metre in groups of three, e.g. 6/8?
+fn is_compound(metre: music::Metre) -> bool {
+    let music::Metre(numerator, denominator) = metre;
+    numerator > 3 && numerator % 3 == 0 && denominator >= 8
+}
+
+// Microseconds per crotchet, from a Q: field such as "1/4=120" or "120".
+fn tempo(ast: &tune_ast_three::Tune) -> u32 {
+    let crotchets_per_minute = ast
+        .prelude
+        .iter()
+        .rev()
+        .find_map(|x| match x {
+            l::T::Tempo(tempo) => Some(tempo),
+            _ => None,
+        }).and_then(|tempo| {
+            let (beat, bpm) = match tempo.find('=') {
+                Some(i) => (&tempo[..i], &tempo[i + 1..]),
+                None => ("1/4", tempo.as_ref()),
+            };
+
+            let bpm = bpm.trim().parse::<f32>().ok()?;
+            let beat = match beat.trim().find('/') {
+                Some(i) => {
+                    let beat = beat.trim();
+                    beat[..i].parse::<f32>().ok()? / beat[i + 1..].parse::<f32>().ok()?
+                }
+                None => return None,
+            };
+
+            Some(bpm * beat * 4.0)
+        }).filter(|x| x.is_finite() && *x > 0.0)
+        .unwrap_or(DEFAULT_TEMPO);
+
+    (60_000_000.0 / crotchets_per_minute) as u32
+}
+
+// A note or rest, with its written duration and where it starts in the bar.
+struct Event {
+    pitch: Option<u8>,
+    duration: music::FractionalDuration,
+    bar_position: music::FractionalDuration,
+}
+
+// Does this position fall on a multiple of the beat?
+fn on_beat(position: music::FractionalDuration, beat: music::FractionalDuration) -> bool {
+    let music::FractionalDuration(_, denominator) =
+        position.multiply(music::FractionalDuration(beat.1, beat.0));
+    denominator == 1
+}
+
+// The notes and rests in a voice, as they sound.
+fn voice_events(
+    prelude: &[l::T],
+    voice: &[l::T],
+    properties: &tune_ast_three::VoiceProperties,
+) -> Vec<Event> {
+    let mut events = vec![];
+    let mut sounding = pitch::Sounding::new(prelude);
+    let mut bar_position = music::FractionalDuration(0, 1);
+
+    for token in voice.iter() {
+        let (pitch, duration) = match token {
+            l::T::Note(music::Note(pitch, duration)) => {
+                let midi_pitch = sounding.pitch(*pitch).midi_number() + properties.transpose;
+                (Some(midi_pitch.clamp(0, 127) as u8), *duration)
+            }
+            l::T::Rest(duration) => (None, *duration),
+            token => {
+                sounding.token(token);
+                if let l::T::SingleBar
+                | l::T::DoubleBar
+                | l::T::OpenRepeat
+                | l::T::CloseRepeat
+                | l::T::EndBar = token
+                {
+                    bar_position = music::FractionalDuration(0, 1);
+                }
+                continue;
+            }
+        };
+
+        events.push(Event {
+            pitch,
+            duration,
+            bar_position,
+        });
+        bar_position = bar_position + duration;
+    }
+
+    events
+}
+
+// How long each event is played for, in ticks, with swing applied.
+// Swing only changes runs of quavers that start on the beat, so the beats stay where they are.
+fn played_lengths(events: &[Event], metre: music::Metre, swing: Option<f32>) -> Vec<f32> {
+    let mut lengths: Vec<f32> = events
+        .iter()
+        .map(|x| x.duration.0 as f32 / x.duration.1 as f32 * TICKS_PER_SEMIBREVE)
+        .collect();
+
+    let ratio = match swing {
+        Some(ratio) => ratio,
+        None => return lengths,
+    };
+
+    // The note that's swung, and how many of them make a beat.
+    let (unit, group) = if is_compound(metre) {
+        (music::FractionalDuration(1, metre.1), 3)
+    } else {
+        (music::FractionalDuration(1, 8), 2)
+    };
+    let beat = unit.multiply(music::FractionalDuration(group as u32, 1));
+    let beat_ticks = beat.0 as f32 / beat.1 as f32 * TICKS_PER_SEMIBREVE;
+
+    // The first of the group gets `ratio` shares of the beat, the rest one share each.
+    let shares = ratio + (group - 1) as f32;
+
+    let mut i = 0;
+    while i + group <= events.len() {
+        let run = &events[i..i + group];
+        let is_group = on_beat(run[0].bar_position, beat) && run.iter().all(|x| x.duration == unit);
+
+        if is_group {
+            lengths[i] = beat_ticks * ratio / shares;
+            for length in lengths[i + 1..i + group].iter_mut() {
+                *length = beat_ticks / shares;
+            }
+            i += group;
+        } else {
+            i += 1;
+        }
+    }
+
+    lengths
+}
+
+// Variable length quantity, as used for delta times.
+fn write_vlq(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    buf.extend(bytes);
+}
+
+fn write_chunk(buf: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn write_meta(track: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    write_vlq(track, 0);
+    track.extend_from_slice(&[0xFF, kind]);
+    write_vlq(track, data.len() as u32);
+    track.extend_from_slice(data);
+}
+
+// Track with the title, tempo and time signature.
+fn tempo_track(ast: &tune_ast_three::Tune) -> Vec<u8> {
+    let mut track = vec![];
+
+    let title = ast.prelude.iter().find_map(|x| match x {
+        l::T::Title(title) => Some(title.clone()),
+        _ => None,
+    });
+    if let Some(title) = title {
+        write_meta(&mut track, 0x03, title.as_bytes());
+    }
+
+    // The tempo has three bytes, so very slow ones are as slow as it can go.
+    write_meta(&mut track, 0x51, &tempo(ast).min(0xFFFFFF).to_be_bytes()[1..]);
+
+    // The denominator is written as a power of two.
+    let music::Metre(numerator, denominator) = metre(ast);
+    let denominator_power = 31 - denominator.max(1).leading_zeros();
+    write_meta(
+        &mut track,
+        0x58,
+        &[numerator.min(255) as u8, denominator_power as u8, 24, 8],
+    );
+
+    write_meta(&mut track, 0x2F, &[]);
+    track
+}
+
+// Track with a voice's notes on the given channel.
+fn voice_track(
+    events: &[Event],
+    lengths: &[f32],
+    channel: u8,
+    properties: &tune_ast_three::VoiceProperties,
+) -> Vec<u8> {
+    let mut track = vec![];
+
+    if let Some(ref name) = properties.name {
+        write_meta(&mut track, 0x03, name.as_bytes());
+    }
+
+    // Times are rounded from the running total, so rounding errors don't add up.
+    let mut time: f32 = 0.0;
+    let mut last_tick = 0;
+    for (event, length) in events.iter().zip(lengths.iter()) {
+        let start = time.round() as u32;
+        time += length;
+        let end = time.round() as u32;
+
+        if let (Some(pitch), true) = (event.pitch, end > start) {
+            write_vlq(&mut track, start - last_tick);
+            track.extend_from_slice(&[0x90 | channel, pitch, VELOCITY]);
+            write_vlq(&mut track, end - start);
+            track.extend_from_slice(&[0x80 | channel, pitch, 0]);
+            last_tick = end;
+        }
+    }
+
+    // A final rest still takes up time.
+    write_vlq(&mut track, (time.round() as u32).saturating_sub(last_tick));
+    track.extend_from_slice(&[0xFF, 0x2F, 0]);
+    track
+}
+
+/// A standard MIDI file of the tune, with a track for the tempo and one for each voice.
+pub fn tune_to_midi(ast: &tune_ast_three::Tune, swing: Swing) -> Vec<u8> {
+    let metre = metre(ast);
+    let ratio = swing.ratio(ast);
+
+    let mut tracks = vec![tempo_track(ast)];
+    let mut channel = 0;
+    for (voice, properties) in ast.voices.iter().zip(ast.voice_properties.iter()) {
+        let events = voice_events(&ast.prelude, voice, properties);
+        let lengths = played_lengths(&events, metre, ratio);
+        tracks.push(voice_track(&events, &lengths, channel, properties));
+
+        channel = (channel + 1) % 16;
+        if channel == PERCUSSION_CHANNEL {
+            channel += 1;
+        }
+    }
+
+    let mut header = vec![];
+    header.extend_from_slice(&1u16.to_be_bytes());
+    header.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    header.extend_from_slice(&(DIVISION as u16).to_be_bytes());
+
+    let mut buf = vec![];
+    write_chunk(&mut buf, b"MThd", &header);
+    for track in tracks.iter() {
+        write_chunk(&mut buf, b"MTrk", track);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    fn ast(abc: &str) -> tune_ast_three::Tune {
+        representations::abc_to_ast(abc)
+    }
+
+    // Written durations and the ticks they're played for.
+    fn lengths(abc: &str, swing: Swing) -> Vec<u32> {
+        let ast = ast(abc);
+        let events = voice_events(&ast.prelude, &ast.voices[0], &ast.voice_properties[0]);
+        played_lengths(&events, metre(&ast), swing.ratio(&ast))
+            .iter()
+            .map(|x| x.round() as u32)
+            .collect()
+    }
+
+    #[test]
+    fn swing_parse_test() {
+        assert_eq!(Swing::parse(None), Ok(Swing::Straight));
+        assert_eq!(Swing::parse(Some("auto")), Ok(Swing::Auto));
+        assert_eq!(Swing::parse(Some("2")), Ok(Swing::Ratio(2.0)));
+        assert!(Swing::parse(Some("0.5")).is_err());
+        assert!(Swing::parse(Some("lots")).is_err());
+    }
+
+    #[test]
+    fn auto_swing_test() {
+        let hornpipe = ast("X:1\nR:Hornpipe\nM:4/4\nK:D\nAB|\n");
+        assert_eq!(Swing::Auto.ratio(&hornpipe), Some(HORNPIPE_SWING));
+
+        let jig = ast("X:1\nR:jig\nM:6/8\nK:D\nABc|\n");
+        assert_eq!(Swing::Auto.ratio(&jig), Some(COMPOUND_SWING));
+
+        let reel = ast("X:1\nR:reel\nM:4/4\nK:D\nAB|\n");
+        assert_eq!(Swing::Auto.ratio(&reel), None);
+        assert_eq!(Swing::Straight.ratio(&jig), None);
+    }
+
+    #[test]
+    fn played_lengths_test() {
+        let abc = "X:1\nM:4/4\nL:1/8\nK:D\nAB cd e2 fg|\n";
+        assert_eq!(
+            lengths(abc, Swing::Straight),
+            vec![240, 240, 240, 240, 480, 240, 240]
+        );
+
+        // Pairs of quavers on the beat are swung, the crotchet isn't.
+        assert_eq!(
+            lengths(abc, Swing::Ratio(2.0)),
+            vec![320, 160, 320, 160, 480, 320, 160]
+        );
+
+        // A quaver off the beat doesn't start a pair.
+        assert_eq!(
+            lengths("X:1\nM:4/4\nL:1/8\nK:D\nA B2 c d2|\n", Swing::Ratio(2.0)),
+            vec![240, 480, 240, 480]
+        );
+
+        // Groups of three in compound metres.
+        assert_eq!(
+            lengths("X:1\nM:6/8\nL:1/8\nK:D\nABc d2e|\n", Swing::Ratio(2.0)),
+            vec![360, 180, 180, 480, 240]
+        );
+    }
+
+    #[test]
+    fn vlq_test() {
+        let vlq = |value| {
+            let mut buf = vec![];
+            write_vlq(&mut buf, value);
+            buf
+        };
+        assert_eq!(vlq(0), vec![0x00]);
+        assert_eq!(vlq(0x7F), vec![0x7F]);
+        assert_eq!(vlq(0x80), vec![0x81, 0x00]);
+        assert_eq!(vlq(0x3FFF), vec![0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn tune_to_midi_test() {
+        let midi = tune_to_midi(&ast("X:1\nT:Test\nQ:1/4=60\nK:D\nF z|\n"), Swing::Straight);
+
+        // Format 1, a tempo track and one voice, 480 ticks per crotchet.
+        assert_eq!(&midi[..8], b"MThd\0\0\0\x06");
+        assert_eq!(&midi[8..14], &[0, 1, 0, 2, 0x01, 0xE0]);
+
+        // One second per crotchet.
+        let tempo = [0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40];
+        assert!(midi.windows(tempo.len()).any(|x| x == tempo));
+
+        // F sharp in D, for a crotchet, then the rest before the end of the track.
+        let voice = [
+            0x00, 0x90, 66, VELOCITY, 0x83, 0x60, 0x80, 66, 0, 0x83, 0x60, 0xFF, 0x2F, 0x00,
+        ];
+        assert!(midi.ends_with(&voice));
+
+        // One crotchet a minute is too slow to write, so it's the slowest there is.
+        let midi = tune_to_midi(&ast("X:1\nQ:1/4=1\nK:D\nF|\n"), Swing::Straight);
+        let tempo = [0xFF, 0x51, 0x03, 0xFF, 0xFF, 0xFF];
+        assert!(midi.windows(tempo.len()).any(|x| x == tempo));
+    }
+}
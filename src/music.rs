@@ -1,3 +1,6 @@
+use std::fmt;
+use std::ops;
+
 pub const NOTES_IN_SCALE: i16 = 7;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -13,42 +16,56 @@ pub enum DiatonicPitchClass {
 
 impl DiatonicPitchClass {
     pub fn to_degree(&self) -> i16 {
-        match self {
-            &DiatonicPitchClass::C => 0,
-            &DiatonicPitchClass::D => 1,
-            &DiatonicPitchClass::E => 2,
-            &DiatonicPitchClass::F => 3,
-            &DiatonicPitchClass::G => 4,
-            &DiatonicPitchClass::A => 5,
-            &DiatonicPitchClass::B => 6,
+        match *self {
+            DiatonicPitchClass::C => 0,
+            DiatonicPitchClass::D => 1,
+            DiatonicPitchClass::E => 2,
+            DiatonicPitchClass::F => 3,
+            DiatonicPitchClass::G => 4,
+            DiatonicPitchClass::A => 5,
+            DiatonicPitchClass::B => 6,
         }
     }
 
-    pub fn to_chromatic(&self) -> u8 {
-        match self {
-            &DiatonicPitchClass::C => 0,
-            &DiatonicPitchClass::D => 2,
-            &DiatonicPitchClass::E => 4,
-            &DiatonicPitchClass::F => 5,
-            &DiatonicPitchClass::G => 7,
-            &DiatonicPitchClass::A => 9,
-            &DiatonicPitchClass::B => 11,
+    pub fn from_degree(degree: i16) -> DiatonicPitchClass {
+        match degree.rem_euclid(NOTES_IN_SCALE) {
+            0 => DiatonicPitchClass::C,
+            1 => DiatonicPitchClass::D,
+            2 => DiatonicPitchClass::E,
+            3 => DiatonicPitchClass::F,
+            4 => DiatonicPitchClass::G,
+            5 => DiatonicPitchClass::A,
+            _ => DiatonicPitchClass::B,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        match self {
-            &DiatonicPitchClass::C => "C".to_string(),
-            &DiatonicPitchClass::D => "D".to_string(),
-            &DiatonicPitchClass::E => "E".to_string(),
-            &DiatonicPitchClass::F => "F".to_string(),
-            &DiatonicPitchClass::G => "G".to_string(),
-            &DiatonicPitchClass::A => "A".to_string(),
-            &DiatonicPitchClass::B => "B".to_string(),
+    pub fn to_chromatic(&self) -> u8 {
+        match *self {
+            DiatonicPitchClass::C => 0,
+            DiatonicPitchClass::D => 2,
+            DiatonicPitchClass::E => 4,
+            DiatonicPitchClass::F => 5,
+            DiatonicPitchClass::G => 7,
+            DiatonicPitchClass::A => 9,
+            DiatonicPitchClass::B => 11,
         }
     }
 }
 
+impl fmt::Display for DiatonicPitchClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DiatonicPitchClass::C => "C",
+            DiatonicPitchClass::D => "D",
+            DiatonicPitchClass::E => "E",
+            DiatonicPitchClass::F => "F",
+            DiatonicPitchClass::G => "G",
+            DiatonicPitchClass::A => "A",
+            DiatonicPitchClass::B => "B",
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum Accidental {
     Sharp,
@@ -60,26 +77,39 @@ pub enum Accidental {
 
 impl Accidental {
     pub fn semitones(&self) -> i16 {
-        match self {
-            &Accidental::Sharp => 1,
-            &Accidental::Flat => -1,
-            &Accidental::Natural => 0,
-            &Accidental::DoubleSharp => 2,
-            &Accidental::DoubleFlat => -2,
+        match *self {
+            Accidental::Sharp => 1,
+            Accidental::Flat => -1,
+            Accidental::Natural => 0,
+            Accidental::DoubleSharp => 2,
+            Accidental::DoubleFlat => -2,
         }
     }
 
-    pub fn to_string(&self) -> String {
+    /// Name in words, e.g. for reading aloud.
+    pub fn name(&self) -> &'static str {
         match self {
-            &Accidental::Sharp => "♯".to_string(),
-            &Accidental::Flat => "♭".to_string(),
-            &Accidental::Natural => "♮".to_string(),
-            &Accidental::DoubleSharp => "𝄪".to_string(),
-            &Accidental::DoubleFlat => "𝄫".to_string(),
+            Accidental::Sharp => "sharp",
+            Accidental::Flat => "flat",
+            Accidental::Natural => "natural",
+            Accidental::DoubleSharp => "double sharp",
+            Accidental::DoubleFlat => "double flat",
         }
     }
 }
 
+impl fmt::Display for Accidental {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Accidental::Sharp => "♯",
+            Accidental::Flat => "♭",
+            Accidental::Natural => "♮",
+            Accidental::DoubleSharp => "𝄪",
+            Accidental::DoubleFlat => "𝄫",
+        })
+    }
+}
+
 /// Musical Mode
 /// Some of these are synonyms, but we want to record what was written.
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -99,50 +129,67 @@ pub enum Mode {
 }
 
 impl Mode {
-    pub fn to_string(&self) -> String {
-        match self {
-            &Mode::Natural => "Natural".to_string(),
-            &Mode::Lydian => "Lydian".to_string(),
-            &Mode::Ionian => "Ionian".to_string(),
-            &Mode::Mixolydian => "Mixolydian".to_string(),
-            &Mode::Dorian => "Dorian".to_string(),
-            &Mode::Aeolian => "Aeolian".to_string(),
-            &Mode::Phrygian => "Phrygian".to_string(),
-            &Mode::Locrian => "Locrian".to_string(),
-            &Mode::Major => "Major".to_string(),
-            &Mode::Minor => "Minor".to_string(),
+    pub fn scale_intervals(&self) -> &[u16] {
+        match *self {
+            Mode::Natural => &[2, 2, 1, 2, 2, 2, 1],
+            Mode::Lydian => &[2, 2, 2, 1, 2, 2, 1],
+            Mode::Ionian => &[2, 2, 1, 2, 2, 2, 1],
+            Mode::Mixolydian => &[2, 2, 1, 2, 2, 1, 2],
+            Mode::Dorian => &[2, 1, 2, 2, 2, 1, 2],
+            Mode::Aeolian => &[2, 1, 2, 2, 1, 2, 2],
+            Mode::Phrygian => &[1, 2, 2, 2, 1, 2, 2],
+            Mode::Locrian => &[1, 2, 2, 1, 2, 2, 2],
+            Mode::Major => &[2, 2, 1, 2, 2, 2, 1],
+            Mode::Minor => &[2, 1, 2, 2, 1, 2, 2],
         }
     }
+}
 
-    pub fn scale_intervals(&self) -> &[u16] {
-        match self {
-            &Mode::Natural => &[2, 2, 1, 2, 2, 2, 1],
-            &Mode::Lydian => &[2, 2, 2, 1, 2, 2, 1],
-            &Mode::Ionian => &[2, 2, 1, 2, 2, 2, 1],
-            &Mode::Mixolydian => &[2, 2, 1, 2, 2, 1, 2],
-            &Mode::Dorian => &[2, 1, 2, 2, 2, 1, 2],
-            &Mode::Aeolian => &[2, 1, 2, 2, 1, 2, 2],
-            &Mode::Phrygian => &[1, 2, 2, 2, 1, 2, 2],
-            &Mode::Locrian => &[1, 2, 2, 1, 2, 2, 2],
-            &Mode::Major => &[2, 2, 1, 2, 2, 2, 1],
-            &Mode::Minor => &[2, 1, 2, 2, 2, 1, 2],
-        }
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Mode::Natural => "Natural",
+            Mode::Lydian => "Lydian",
+            Mode::Ionian => "Ionian",
+            Mode::Mixolydian => "Mixolydian",
+            Mode::Dorian => "Dorian",
+            Mode::Aeolian => "Aeolian",
+            Mode::Phrygian => "Phrygian",
+            Mode::Locrian => "Locrian",
+            Mode::Major => "Major",
+            Mode::Minor => "Minor",
+        })
     }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum ClefShape {
     Treble,
+    Bass,
+    Alto,
 }
 
 impl ClefShape {
     /// What pitch does this shape represent?
     pub fn pitch(&self) -> PitchClass {
+        let diatonic_pitch_class = match self {
+            ClefShape::Treble => DiatonicPitchClass::G,
+            ClefShape::Bass => DiatonicPitchClass::F,
+            ClefShape::Alto => DiatonicPitchClass::C,
+        };
+
+        PitchClass {
+            diatonic_pitch_class,
+            accidental: None,
+        }
+    }
+
+    /// Shift of key signature positions on the stave, relative to the treble clef.
+    pub fn key_signature_offset(&self) -> i32 {
         match self {
-            ClefShape::Treble => PitchClass {
-                diatonic_pitch_class: DiatonicPitchClass::G,
-                accidental: None,
-            },
+            ClefShape::Treble => 0,
+            ClefShape::Bass => -2,
+            ClefShape::Alto => -1,
         }
     }
 }
@@ -156,20 +203,51 @@ pub struct Clef {
 }
 
 impl Clef {
-    /// Construct a treble clef.
-    pub fn treble() -> Clef {
+    /// Clef from its name in ABC, e.g. "treble".
+    pub fn from_name(name: &str) -> Option<Clef> {
+        match name.to_lowercase().as_ref() {
+            "treble" => Some(Clef::treble()),
+            "bass" => Some(Clef::bass()),
+            "alto" => Some(Clef::alto()),
+            _ => None,
+        }
+    }
+
+    /// Name of the clef in ABC, the reverse of from_name().
+    pub fn name(&self) -> &'static str {
+        match self.shape {
+            ClefShape::Treble => "treble",
+            ClefShape::Bass => "bass",
+            ClefShape::Alto => "alto",
+        }
+    }
+
+    // A clef of this shape, on the line at the centre position, showing the pitch in the octave.
+    fn new(shape: ClefShape, centre: i32, octave: i16) -> Clef {
         Clef {
-            shape: ClefShape::Treble,
-            centre: 2,
+            shape,
+            centre,
             pitch: Pitch {
-                pitch_class: PitchClass {
-                    diatonic_pitch_class: DiatonicPitchClass::G,
-                    accidental: None,
-                },
-                octave: 0,
+                pitch_class: shape.pitch(),
+                octave,
             },
         }
     }
+
+    /// Construct a treble clef, with G above middle C on the second line.
+    pub fn treble() -> Clef {
+        Clef::new(ClefShape::Treble, 2, 0)
+    }
+
+    /// Construct a bass clef, with F below middle C on the fourth line.
+    pub fn bass() -> Clef {
+        Clef::new(ClefShape::Bass, 6, -1)
+    }
+
+    /// Construct an alto clef, with middle C on the middle line.
+    pub fn alto() -> Clef {
+        Clef::new(ClefShape::Alto, 4, 0)
+    }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -178,6 +256,17 @@ pub struct PitchClass {
     pub accidental: Option<Accidental>,
 }
 
+// Order that sharps are written in a key signature. Flats are the reverse.
+const ORDER_OF_SHARPS: [DiatonicPitchClass; 7] = [
+    DiatonicPitchClass::F,
+    DiatonicPitchClass::C,
+    DiatonicPitchClass::G,
+    DiatonicPitchClass::D,
+    DiatonicPitchClass::A,
+    DiatonicPitchClass::E,
+    DiatonicPitchClass::B,
+];
+
 impl PitchClass {
     pub fn to_chromatic(&self) -> u8 {
         (self.diatonic_pitch_class.to_chromatic() as i16 + match self.accidental {
@@ -186,15 +275,81 @@ impl PitchClass {
         }) as u8
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{}{}",
-            self.diatonic_pitch_class.to_string(),
-            match self.accidental {
-                Some(accidental) => accidental.to_string(),
-                None => "".to_string(),
+    /// Sharps or flats in the key signature for this key note and mode, in the order they're
+    /// written, e.g. F♯ then C♯ for D Major.
+    pub fn key_signature(&self, mode: Mode) -> Vec<PitchClass> {
+        let tonic_degree = self.diatonic_pitch_class.to_degree();
+
+        // Not to_chromatic(), which would wrap around for C♭.
+        let mut chromatic = self.diatonic_pitch_class.to_chromatic() as i16
+            + match self.accidental {
+                Some(accidental) => accidental.semitones(),
+                _ => 0,
+            };
+
+        let mut result = vec![];
+        for (i, step) in mode.scale_intervals().iter().enumerate() {
+            let diatonic_pitch_class = DiatonicPitchClass::from_degree(tonic_degree + i as i16);
+
+            // Difference between the scale note and the natural note, between -6 and 5.
+            let difference =
+                (chromatic - diatonic_pitch_class.to_chromatic() as i16 + 6).rem_euclid(12) - 6;
+
+            let accidental = match difference {
+                1 => Some(Accidental::Sharp),
+                -1 => Some(Accidental::Flat),
+                2 => Some(Accidental::DoubleSharp),
+                -2 => Some(Accidental::DoubleFlat),
+                _ => None,
+            };
+
+            if accidental.is_some() {
+                result.push(PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                });
             }
-        )
+
+            chromatic += *step as i16;
+        }
+
+        let order = |x: &PitchClass| {
+            let position = ORDER_OF_SHARPS
+                .iter()
+                .position(|y| *y == x.diatonic_pitch_class)
+                .unwrap_or(0);
+            match x.accidental {
+                Some(Accidental::Sharp) | Some(Accidental::DoubleSharp) => position,
+                _ => ORDER_OF_SHARPS.len() * 2 - position,
+            }
+        };
+        result.sort_by_key(order);
+
+        result
+    }
+
+    /// The key note as it's spelled in an ABC key field, e.g. "F#".
+    pub fn to_abc(&self) -> String {
+        let accidental = match self.accidental {
+            Some(Accidental::Sharp) => "#",
+            Some(Accidental::Flat) => "b",
+            Some(Accidental::Natural) => "=",
+            Some(Accidental::DoubleSharp) => "##",
+            Some(Accidental::DoubleFlat) => "bb",
+            None => "",
+        };
+
+        format!("{}{}", self.diatonic_pitch_class, accidental)
+    }
+}
+
+impl fmt::Display for PitchClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.diatonic_pitch_class)?;
+        match self.accidental {
+            Some(accidental) => write!(f, "{}", accidental),
+            None => Ok(()),
+        }
     }
 }
 
@@ -237,10 +392,23 @@ impl Pitch {
         }
     }
 
+    // MIDI note number, where middle C is 60, without wrapping around for C♭ or limiting it to the
+    // MIDI range, so it can still be transposed.
+    pub fn midi_number(&self) -> i32 {
+        let accidental = match self.pitch_class.accidental {
+            Some(accidental) => accidental.semitones() as i32,
+            None => 0,
+        };
+        self.pitch_class.diatonic_pitch_class.to_chromatic() as i32
+            + accidental
+            + self.octave as i32 * 12
+            + 60
+    }
+
     // TODO no key signature or mode yet!
+    // Notes beyond the MIDI range get the highest or lowest pitch.
     pub fn midi_pitch(&self) -> u8 {
-        let diatonic_pitch = self.pitch_class.to_chromatic();
-        (diatonic_pitch as i16 + (self.octave * 12) + 60) as u8
+        self.midi_number().clamp(0, 127) as u8
     }
 }
 
@@ -248,9 +416,9 @@ impl Pitch {
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Metre(pub u32, pub u32);
 
-impl Metre {
-    pub fn to_string(&self) -> String {
-        format!("{}/{}", self.0, self.1)
+impl fmt::Display for Metre {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.0, self.1)
     }
 }
 
@@ -277,25 +445,36 @@ const DURATION_CLASSES: &[DurationClass] = &[
 
 impl DurationClass {
     fn duration(&self) -> FractionalDuration {
-        match self {
-            &DurationClass::Semibreve => FractionalDuration(1, 1),
-            &DurationClass::Minim => FractionalDuration(1, 2),
-            &DurationClass::Crotchet => FractionalDuration(1, 4),
-            &DurationClass::Quaver => FractionalDuration(1, 8),
-            &DurationClass::Semiquaver => FractionalDuration(1, 16),
-            &DurationClass::Demisemiquaver => FractionalDuration(1, 32),
+        match *self {
+            DurationClass::Semibreve => FractionalDuration(1, 1),
+            DurationClass::Minim => FractionalDuration(1, 2),
+            DurationClass::Crotchet => FractionalDuration(1, 4),
+            DurationClass::Quaver => FractionalDuration(1, 8),
+            DurationClass::Semiquaver => FractionalDuration(1, 16),
+            DurationClass::Demisemiquaver => FractionalDuration(1, 32),
         }
     }
 
     // How many beams / tails does this duration render as?
     pub fn beams(&self) -> u32 {
+        match *self {
+            DurationClass::Semibreve => 0,
+            DurationClass::Minim => 0,
+            DurationClass::Crotchet => 0,
+            DurationClass::Quaver => 1,
+            DurationClass::Semiquaver => 2,
+            DurationClass::Demisemiquaver => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
         match self {
-            &DurationClass::Semibreve => 0,
-            &DurationClass::Minim => 0,
-            &DurationClass::Crotchet => 0,
-            &DurationClass::Quaver => 1,
-            &DurationClass::Semiquaver => 2,
-            &DurationClass::Demisemiquaver => 3,
+            DurationClass::Semibreve => "semibreve",
+            DurationClass::Minim => "minim",
+            DurationClass::Crotchet => "crotchet",
+            DurationClass::Quaver => "quaver",
+            DurationClass::Semiquaver => "semiquaver",
+            DurationClass::Demisemiquaver => "demisemiquaver",
         }
     }
 }
@@ -307,59 +486,112 @@ pub struct DurationGlyph {
     pub dots: u32,
 }
 
+impl DurationGlyph {
+    /// The duration this glyph shows, e.g. 3/8 for a dotted crotchet.
+    pub fn duration(&self) -> FractionalDuration {
+        let power = 1u32 << self.dots.min(8);
+        self.shape
+            .duration()
+            .multiply(FractionalDuration(power * 2 - 1, power))
+    }
+}
+
 /// A duration as a fraction of the default duration.
+/// The denominator should never be zero. Use `new()` to check this for values from input.
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct FractionalDuration(pub u32, pub u32);
 
+// Greatest common divisor, by Euclid's algorithm.
+fn gcd(a: u64, b: u64) -> u64 {
+    let (mut a, mut b) = (a, b);
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
 impl FractionalDuration {
+    /// Construct a duration, or None if the denominator is zero.
+    pub fn new(numerator: u32, denominator: u32) -> Option<FractionalDuration> {
+        if denominator == 0 {
+            None
+        } else {
+            Some(FractionalDuration(numerator, denominator))
+        }
+    }
+
+    /// Build a reduced fraction from intermediate values which may not fit in a u32.
+    /// Absurdly long or short durations lose precision rather than overflowing.
+    fn from_wide(numerator: u64, denominator: u64) -> FractionalDuration {
+        if denominator == 0 {
+            return FractionalDuration(numerator.min(u32::MAX as u64) as u32, 0);
+        }
+
+        let divisor = gcd(numerator, denominator);
+        let (mut numerator, mut denominator) = (numerator / divisor, denominator / divisor);
+
+        while numerator > u32::MAX as u64 || denominator > u32::MAX as u64 {
+            numerator >>= 1;
+            denominator >>= 1;
+        }
+
+        FractionalDuration(numerator as u32, denominator.max(1) as u32)
+    }
+
     /// Multiply this fractional duration by another.
     /// Used to resolve a duration against a standard duration.
     pub fn multiply(self, other: FractionalDuration) -> FractionalDuration {
-        let vulgar = FractionalDuration(self.0 * other.0, self.1 * other.1);
-
-        return vulgar.reduce();
+        FractionalDuration::from_wide(
+            self.0 as u64 * other.0 as u64,
+            self.1 as u64 * other.1 as u64,
+        )
     }
 
+    /// Subtract the other duration from this one, stopping at zero.
     pub fn subtract(self, other: FractionalDuration) -> FractionalDuration {
-        let self_numerator = self.0 * other.1;
-        let other_numerator = other.0 * self.1;
-        let denomenator = self.1 * other.1;
+        let self_numerator = self.0 as u64 * other.1 as u64;
+        let other_numerator = other.0 as u64 * self.1 as u64;
+        let denomenator = self.1 as u64 * other.1 as u64;
 
-        FractionalDuration(self_numerator - other_numerator, denomenator).reduce()
+        FractionalDuration::from_wide(self_numerator.saturating_sub(other_numerator), denomenator)
     }
 
     /// Reduce this fraction to its simplest form.
+    /// A zero denominator is left as it is.
     pub fn reduce(self) -> FractionalDuration {
-        let max = u32::max(self.0, self.1);
-        for i in (1..max + 1).rev() {
-            if (self.0 % i == 0) && (self.1 % i) == 0 {
-                return FractionalDuration(self.0 / i, self.1 / i);
-            }
+        let divisor = gcd(self.0 as u64, self.1 as u64) as u32;
+        if divisor == 0 || self.1 == 0 {
+            self
+        } else {
+            FractionalDuration(self.0 / divisor, self.1 / divisor)
         }
-
-        self
     }
 
     /// Is this duration greater than the other one?
     /// TODO Implement PartialOrd properly!
     pub fn gte(&self, other: &FractionalDuration) -> bool {
-        let self_numerator = self.0 * other.1;
-        let other_numerator = other.0 * self.1;
+        let self_numerator = self.0 as u64 * other.1 as u64;
+        let other_numerator = other.0 as u64 * self.1 as u64;
         self_numerator >= other_numerator
     }
 
     /// Transform this duration into a notehead glyph.
     /// i.e. "3/2" becomes "dotted crotchet".
+    /// Durations that can't be represented exactly get the longest glyph that fits.
     /// TODO in future this may be represented as a sequence of tied glyphs
     /// for complicted durations.
     pub fn to_glyph(&self) -> Option<DurationGlyph> {
         const MAX_DOTS: u32 = 4;
 
+        if self.1 == 0 {
+            return None;
+        }
+
         // Start with self's duration, keep chipping away until there's nothing left to represent.
         let mut this = *self;
 
-        let mut result = None;
-
         // Try each top level duration class first.
         for duration_class in DURATION_CLASSES.iter() {
             // When there's nothing left to represent, stop there.
@@ -379,19 +611,35 @@ impl FractionalDuration {
                         break;
                     }
 
-                    // Half the duration to correspond to another dot.
+                    // Half the duration to correspond to another dot, if there's room for it.
                     duration = duration.multiply(FractionalDuration(1, 2));
+                    if !this.gte(&duration) || num_dots == MAX_DOTS {
+                        break;
+                    }
+
                     num_dots += 1;
                 }
 
-                result = Some(DurationGlyph {
+                return Some(DurationGlyph {
                     shape: *duration_class,
                     dots: num_dots,
                 });
             }
         }
 
-        result
+        None
+    }
+}
+
+/// Add the other duration to this one.
+impl ops::Add for FractionalDuration {
+    type Output = FractionalDuration;
+
+    fn add(self, other: FractionalDuration) -> FractionalDuration {
+        FractionalDuration::from_wide(
+            self.0 as u64 * other.1 as u64 + other.0 as u64 * self.1 as u64,
+            self.1 as u64 * other.1 as u64,
+        )
     }
 }
 
@@ -448,6 +696,54 @@ mod tests {
         assert_eq!(FractionalDuration(2, 6).reduce(), FractionalDuration(1, 3));
     }
 
+    #[test]
+    fn fractional_duration_add_test() {
+        assert_eq!(
+            FractionalDuration(1, 8) + FractionalDuration(1, 4),
+            FractionalDuration(3, 8)
+        );
+        assert_eq!(
+            FractionalDuration(0, 1) + FractionalDuration(2, 4),
+            FractionalDuration(1, 2)
+        );
+    }
+
+    #[test]
+    fn fractional_duration_new_test() {
+        assert_eq!(FractionalDuration::new(3, 8), Some(FractionalDuration(3, 8)));
+        assert_eq!(FractionalDuration::new(0, 1), Some(FractionalDuration(0, 1)));
+        assert_eq!(FractionalDuration::new(1, 0), None);
+    }
+
+    #[test]
+    fn duration_to_glyph_awkward_test() {
+        // Zero denominators have no glyph.
+        assert_eq!(FractionalDuration(1, 0).to_glyph(), None);
+        assert_eq!(FractionalDuration(0, 0).to_glyph(), None);
+
+        // Nothing to show.
+        assert_eq!(FractionalDuration(0, 1).to_glyph(), None);
+
+        // Not exactly representable, so get the longest glyph that fits.
+        assert_eq!(
+            FractionalDuration(5, 4).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Semibreve,
+                dots: 0,
+            })
+        );
+
+        // Huge values don't overflow.
+        assert_eq!(
+            FractionalDuration(99999999, 1)
+                .multiply(FractionalDuration(99999999, 7))
+                .to_glyph()
+                .map(|glyph| glyph.shape),
+            Some(DurationClass::Semibreve)
+        );
+        assert_eq!(FractionalDuration(1, 99999999).to_glyph(), None);
+    }
+
     #[test]
     fn duration_to_glyph_simple_test() {
         // Simple durations.
@@ -588,6 +884,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn glyph_duration_test() {
+        let durations = vec![
+            FractionalDuration(1, 8),
+            FractionalDuration(3, 16),
+            FractionalDuration(7, 8),
+            FractionalDuration(15, 16),
+            FractionalDuration(1, 1),
+        ];
+
+        for duration in durations {
+            assert_eq!(duration.to_glyph().unwrap().duration(), duration);
+        }
+    }
+
     #[test]
     fn pitch_minus_as_degrees_test() {
         assert_eq!(
@@ -675,6 +986,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn to_abc_test() {
+        let pitch_class = |diatonic_pitch_class, accidental| PitchClass {
+            diatonic_pitch_class,
+            accidental,
+        };
+
+        assert_eq!(pitch_class(DiatonicPitchClass::G, None).to_abc(), "G");
+        assert_eq!(
+            pitch_class(DiatonicPitchClass::F, Some(Accidental::Sharp)).to_abc(),
+            "F#"
+        );
+        assert_eq!(
+            pitch_class(DiatonicPitchClass::B, Some(Accidental::DoubleFlat)).to_abc(),
+            "Bbb"
+        );
+    }
+
+    #[test]
+    fn scale_intervals_test() {
+        assert_eq!(
+            Mode::Minor.scale_intervals(),
+            Mode::Aeolian.scale_intervals(),
+            "Minor is the natural minor."
+        );
+        assert_eq!(Mode::Major.scale_intervals(), Mode::Ionian.scale_intervals());
+        assert_eq!(Mode::Minor.scale_intervals().iter().sum::<u16>(), 12);
+    }
+
+    #[test]
+    fn key_signature_test() {
+        let key = |diatonic_pitch_class, accidental, mode| {
+            PitchClass {
+                diatonic_pitch_class,
+                accidental,
+            }.key_signature(mode)
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        assert_eq!(key(DiatonicPitchClass::C, None, Mode::Major), "");
+        assert_eq!(key(DiatonicPitchClass::D, None, Mode::Major), "F♯ C♯");
+        assert_eq!(key(DiatonicPitchClass::E, None, Mode::Minor), "F♯");
+        assert_eq!(key(DiatonicPitchClass::E, None, Mode::Dorian), "F♯ C♯");
+        assert_eq!(key(DiatonicPitchClass::A, None, Mode::Mixolydian), "F♯ C♯");
+        assert_eq!(
+            key(DiatonicPitchClass::B, Some(Accidental::Flat), Mode::Major),
+            "B♭ E♭"
+        );
+        assert_eq!(key(DiatonicPitchClass::D, None, Mode::Minor), "B♭");
+        assert_eq!(
+            key(DiatonicPitchClass::C, Some(Accidental::Sharp), Mode::Major),
+            "F♯ C♯ G♯ D♯ A♯ E♯ B♯"
+        );
+        assert_eq!(
+            key(DiatonicPitchClass::C, Some(Accidental::Flat), Mode::Major),
+            "B♭ E♭ A♭ D♭ G♭ C♭ F♭",
+            "C♭ is below C, not above B."
+        );
+    }
+
     #[test]
     fn midi_pitch_test() {
         assert_eq!(
@@ -748,6 +1122,20 @@ mod tests {
             49,
             "Low octave C#"
         );
+
+        let pitch = |accidental, octave| Pitch {
+            pitch_class: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::C,
+                accidental,
+            },
+            octave,
+        };
+        assert_eq!(pitch(Some(Accidental::Flat), 0).midi_pitch(), 59);
+        assert_eq!(pitch(Some(Accidental::Flat), 0).midi_number(), 59);
+        assert_eq!(pitch(None, 6).midi_pitch(), 127, "Too high for MIDI");
+        assert_eq!(pitch(None, 6).midi_number(), 132);
+        assert_eq!(pitch(None, i16::MIN).midi_pitch(), 0, "Too low");
+        assert_eq!(pitch(None, i16::MAX).midi_pitch(), 127);
     }
 
 }
@@ -1,6 +1,8 @@
 use abc_lexer as l;
 use music;
+use std::collections::HashMap;
 use std::f32;
+use std::fmt;
 use tune_ast_three;
 
 pub struct PitchSequence {
@@ -13,31 +15,158 @@ impl PitchSequence {
     pub fn from_ast(ast: &tune_ast_three::Tune) -> PitchSequence {
         let mut pitches = vec![];
 
-        let mut key_signature = l::T::KeySignature(
-            music::PitchClass {
-                diatonic_pitch_class: music::DiatonicPitchClass::C,
-                accidental: None,
-            },
-            music::Mode::Major,
-        );
+        for voice in ast.voices.iter() {
+            for ref token in voice.iter() {
+                if let l::T::Note(note) = token {
+                    // TODO extras like accidentals etc.
+                    let music::Note(pitch, _duration) = note;
+                    let midi_pitch = pitch.midi_pitch();
+                    pitches.push(midi_pitch);
+                }
+            }
+        }
+
+        PitchSequence { pitches }
+    }
+
+    // Convert to a monophonic sequence of pitches as MIDI pitch, as they sound.
+    // Unlike `from_ast`, this applies the key signature, and accidentals which carry to the end
+    // of the bar.
+    pub fn from_ast_in_key(ast: &tune_ast_three::Tune) -> PitchSequence {
+        let mut pitches = vec![];
+        let mut sounding = Sounding::new(&ast.prelude);
 
-        for ref token in ast.prelude.iter() {
-            match *token {
-                l::T::KeySignature(pitch_class, mode) => {
-                    key_signature = l::T::KeySignature(*pitch_class, *mode)
+        for voice in ast.voices.iter() {
+            for token in voice.iter() {
+                match token {
+                    l::T::Note(music::Note(pitch, _duration)) => {
+                        pitches.push(sounding.pitch(*pitch).midi_pitch())
+                    }
+                    token => sounding.token(token),
                 }
-                _ => {}
             }
         }
 
-        for ref voice in ast.voices.iter() {
+        PitchSequence { pitches }
+    }
+
+    pub fn from_pitches(pitches: &[u8]) -> PitchSequence {
+        PitchSequence {
+            pitches: pitches.to_vec(),
+        }
+    }
+}
+
+// Works out the pitches that sound for written notes, from the key signature and accidentals
+// earlier in the bar.
+pub struct Sounding {
+    key_signature: Vec<music::PitchClass>,
+
+    // Accidentals written so far in this bar, by degree and octave.
+    bar_accidentals: HashMap<(i16, i16), music::Accidental>,
+}
+
+impl Sounding {
+    // Start with the key signature in the tune's header, if there is one.
+    pub fn new(prelude: &[l::T]) -> Sounding {
+        let mut sounding = Sounding {
+            key_signature: vec![],
+            bar_accidentals: HashMap::new(),
+        };
+
+        for token in prelude.iter() {
+            sounding.token(token);
+        }
+
+        sounding
+    }
+
+    // Keep track of key changes and barlines, which end accidentals.
+    pub fn token(&mut self, token: &l::T) {
+        match token {
+            l::T::KeySignature(pitch_class, mode) => {
+                self.key_signature = pitch_class.key_signature(*mode);
+            }
+
+            l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_) => self.bar_accidentals.clear(),
+
+            _ => {}
+        }
+    }
+
+    // The pitch that sounds for the next written note.
+    pub fn pitch(&mut self, pitch: music::Pitch) -> music::Pitch {
+        let degree = pitch.pitch_class.diatonic_pitch_class.to_degree();
+
+        let accidental = match pitch.pitch_class.accidental {
+            Some(accidental) => {
+                self.bar_accidentals
+                    .insert((degree, pitch.octave), accidental);
+                Some(accidental)
+            }
+            None => match self.bar_accidentals.get(&(degree, pitch.octave)) {
+                Some(accidental) => Some(*accidental),
+                None => self
+                    .key_signature
+                    .iter()
+                    .find(|x| x.diatonic_pitch_class == pitch.pitch_class.diatonic_pitch_class)
+                    .and_then(|x| x.accidental),
+            },
+        };
+
+        music::Pitch {
+            pitch_class: music::PitchClass {
+                diatonic_pitch_class: pitch.pitch_class.diatonic_pitch_class,
+                accidental,
+            },
+            octave: pitch.octave,
+        }
+    }
+}
+
+// Number of the key note as a scale degree.
+const TONIC_DEGREE: i16 = 1;
+
+pub struct DegreeSequence {
+    // Scale degrees 1 to 7, relative to the key note.
+    pub degrees: Vec<u8>,
+}
+
+impl DegreeSequence {
+    // Convert to a monophonic sequence of scale degrees relative to the key note.
+    // The octave is discarded, so this is invariant to transposition into another key.
+    // If there's no key signature, C is assumed. Key changes in the body apply to the rest of
+    // their voice, and each voice starts in the header's key.
+    pub fn from_ast(ast: &tune_ast_three::Tune) -> DegreeSequence {
+        let mut degrees = vec![];
+
+        let mut header_key_note = music::DiatonicPitchClass::C;
+
+        for token in ast.prelude.iter() {
+            if let l::T::KeySignature(pitch_class, _) = token {
+                header_key_note = pitch_class.diatonic_pitch_class
+            }
+        }
+
+        for voice in ast.voices.iter() {
+            let mut key_note = header_key_note;
             for ref token in voice.iter() {
                 match token {
+                    l::T::KeySignature(pitch_class, _) => {
+                        key_note = pitch_class.diatonic_pitch_class
+                    }
                     l::T::Note(note) => {
-                        // TODO extras like accidentals etc.
                         let music::Note(pitch, _duration) = note;
-                        let midi_pitch = pitch.midi_pitch();
-                        pitches.push(midi_pitch);
+                        let degree = (pitch.pitch_class.diatonic_pitch_class.to_degree()
+                            - key_note.to_degree())
+                            .rem_euclid(music::NOTES_IN_SCALE)
+                            + TONIC_DEGREE;
+                        degrees.push(degree as u8);
                     }
 
                     _ => {}
@@ -45,12 +174,35 @@ impl PitchSequence {
             }
         }
 
-        PitchSequence { pitches }
+        DegreeSequence { degrees }
     }
+}
 
-    pub fn from_pitches(pitches: &Vec<u8>) -> PitchSequence {
-        PitchSequence {
-            pitches: pitches.clone(),
+// Which pitches melodic intervals are measured between. Either way they're in semitones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntervalPitches {
+    // As written, ignoring the key signature and accidentals earlier in the bar, e.g. F in D
+    // major is F natural. This is what the melody indexes have always used.
+    Written,
+
+    // As they sound, with the key signature and accidentals applied, so a chromatic note is
+    // always a semitone from its neighbours however it's written.
+    Sounding,
+}
+
+impl IntervalPitches {
+    pub fn from_string(value: &str) -> Option<IntervalPitches> {
+        match value {
+            "written" => Some(IntervalPitches::Written),
+            "sounding" => Some(IntervalPitches::Sounding),
+            _ => None,
+        }
+    }
+
+    pub fn pitch_sequence(self, ast: &tune_ast_three::Tune) -> PitchSequence {
+        match self {
+            IntervalPitches::Written => PitchSequence::from_ast(ast),
+            IntervalPitches::Sounding => PitchSequence::from_ast_in_key(ast),
         }
     }
 }
@@ -60,6 +212,10 @@ pub struct IntervalSequence {
 }
 
 impl IntervalSequence {
+    pub fn from_ast(ast: &tune_ast_three::Tune, pitches: IntervalPitches) -> IntervalSequence {
+        IntervalSequence::from_pitch_sequence(&pitches.pitch_sequence(ast))
+    }
+
     pub fn from_pitch_sequence(pitches: &PitchSequence) -> IntervalSequence {
         let mut intervals = Vec::with_capacity(pitches.pitches.len());
 
@@ -78,6 +234,93 @@ impl IntervalSequence {
 
         IntervalSequence { intervals }
     }
+
+    // Intervals of the melody played backwards.
+    pub fn retrograde(&self) -> IntervalSequence {
+        IntervalSequence {
+            intervals: self.intervals.iter().rev().map(|x| -x).collect(),
+        }
+    }
+
+    // Intervals of the melody turned upside-down, i.e. every step up becomes a step down.
+    pub fn inversion(&self) -> IntervalSequence {
+        IntervalSequence {
+            intervals: self.intervals.iter().map(|x| -x).collect(),
+        }
+    }
+}
+
+// Direction of one step in a melody.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Contour {
+    Up,
+    Down,
+    Repeat,
+}
+
+impl Contour {
+    // Letters as used in Parsons code. Repeat is also written 's' for 'same'.
+    pub fn from_char(value: char) -> Option<Contour> {
+        match value.to_ascii_lowercase() {
+            'u' => Some(Contour::Up),
+            'd' => Some(Contour::Down),
+            'r' | 's' => Some(Contour::Repeat),
+            _ => None,
+        }
+    }
+
+    pub fn to_char(&self) -> char {
+        match *self {
+            Contour::Up => 'u',
+            Contour::Down => 'd',
+            Contour::Repeat => 'r',
+        }
+    }
+}
+
+// Parsons code, the melodic contour, i.e. whether each note is up, down or the same as the last.
+// Can be used by people who can't read music.
+pub struct ParsonsCode {
+    pub contour: Vec<Contour>,
+}
+
+impl ParsonsCode {
+    pub fn from_interval_sequence(intervals: &IntervalSequence) -> ParsonsCode {
+        ParsonsCode {
+            contour: intervals
+                .intervals
+                .iter()
+                .map(|x| {
+                    if *x > 0 {
+                        Contour::Up
+                    } else if *x < 0 {
+                        Contour::Down
+                    } else {
+                        Contour::Repeat
+                    }
+                }).collect(),
+        }
+    }
+
+    // Parse Parsons code such as "*udrd". The star, which stands for the first note, is optional.
+    pub fn parse(value: &str) -> Result<ParsonsCode, String> {
+        let value = value.trim();
+        let value = value.strip_prefix('*').unwrap_or(value);
+
+        let contour: Option<Vec<Contour>> = value.chars().map(Contour::from_char).collect();
+        match contour {
+            Some(contour) => Ok(ParsonsCode { contour }),
+            None => Err("Parsons code can only contain 'u', 'd' and 'r'.".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ParsonsCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = "*".to_string();
+        result.extend(self.contour.iter().map(|x| x.to_char()));
+        f.write_str(&result)
+    }
 }
 
 // Number of chromatic pitches either size of zero to take.
@@ -95,7 +338,7 @@ impl IntervalHistogram {
         let mut histogram = [0.0; HISTOGRAM_WIDTH];
 
         let _max = HISTOGRAM_WIDTH as i16;
-        let _min = HISTOGRAM_WIDTH as i16 * -1;
+        let _min = -(HISTOGRAM_WIDTH as i16);
 
         for interval in intervals.intervals.iter() {
             // Clamp to range, saturating at each end.
@@ -106,10 +349,10 @@ impl IntervalHistogram {
             histogram[i as usize] += 1.0;
         }
 
-        if intervals.intervals.len() > 0 {
+        if !intervals.intervals.is_empty() {
             let count = intervals.intervals.len() as f32;
-            for i in 0..HISTOGRAM_WIDTH {
-                histogram[i] /= count;
+            for value in histogram.iter_mut() {
+                *value /= count;
             }
         }
 
@@ -126,3 +369,296 @@ impl IntervalHistogram {
         f32::sqrt(result)
     }
 }
+
+// Steps of the major scale from its key note, in semitones.
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+// The mode that starts on each degree of the major scale.
+const MODES_BY_DEGREE: [music::Mode; 7] = [
+    music::Mode::Major,
+    music::Mode::Dorian,
+    music::Mode::Phrygian,
+    music::Mode::Lydian,
+    music::Mode::Mixolydian,
+    music::Mode::Minor,
+    music::Mode::Locrian,
+];
+
+// How often each mode turns up in folk music, to choose between modes that fit equally well,
+// e.g. a tune that never plays the 4th could be major or lydian.
+fn mode_preference(mode: music::Mode) -> u8 {
+    match mode {
+        music::Mode::Major => 6,
+        music::Mode::Minor => 5,
+        music::Mode::Dorian => 4,
+        music::Mode::Mixolydian => 3,
+        music::Mode::Lydian => 2,
+        music::Mode::Phrygian => 1,
+        _ => 0,
+    }
+}
+
+// Pitch classes of a scale as a bit set, with C as the lowest bit.
+fn scale_bits(tonic: u8, mode: music::Mode) -> u16 {
+    let mut bits = 0;
+    let mut chromatic = tonic;
+    for step in mode.scale_intervals().iter() {
+        bits |= 1 << (chromatic % 12);
+        chromatic += *step as u8;
+    }
+    bits
+}
+
+// Spelling of a key note with the fewest accidentals in its key signature, e.g. B♭ rather than
+// A♯ for B♭ major.
+fn spell_key(tonic: u8, mode: music::Mode) -> music::PitchClass {
+    let accidentals = [
+        None,
+        Some(music::Accidental::Sharp),
+        Some(music::Accidental::Flat),
+    ];
+
+    let mut candidates = vec![];
+    for degree in 0..7 {
+        for accidental in accidentals.iter() {
+            let pitch_class = music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::from_degree(degree),
+                accidental: *accidental,
+            };
+            if pitch_class.to_chromatic() % 12 == tonic {
+                candidates.push(pitch_class);
+            }
+        }
+    }
+
+    // Stable sort, so ties go to naturals, then sharps.
+    candidates.sort_by_key(|x| x.key_signature(mode).len());
+    candidates[0]
+}
+
+/// Number of times each pitch class is played, starting from C.
+pub fn pitch_class_histogram(pitches: &PitchSequence) -> [u32; 12] {
+    let mut histogram = [0; 12];
+    for pitch in pitches.pitches.iter() {
+        histogram[(pitch % 12) as usize] += 1;
+    }
+    histogram
+}
+
+/// Most likely key and mode from the notes of the tune, regardless of its K: header.
+/// The scale is the one that the most notes fit, preferring the written key signature if others
+/// fit as well. The key note is the one the tune ends on, if that's in the scale, otherwise the
+/// most played key note and fifth. None if there are no notes.
+pub fn detect_key(ast: &tune_ast_three::Tune) -> Option<(music::PitchClass, music::Mode)> {
+    let pitches = PitchSequence::from_ast_in_key(ast);
+    let last = match pitches.pitches.last() {
+        Some(last) => last % 12,
+        None => return None,
+    };
+    let histogram = pitch_class_histogram(&pitches);
+
+    let written = ast
+        .prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::KeySignature(pitch_class, mode) => {
+                Some(scale_bits(pitch_class.to_chromatic() % 12, *mode))
+            }
+            _ => None,
+        }).next_back();
+
+    let mut best = None;
+    for major_tonic in 0..12 {
+        let bits = scale_bits(major_tonic, music::Mode::Major);
+        let fit: u32 = (0..12)
+            .filter(|x| bits & (1 << x) != 0)
+            .map(|x| histogram[x])
+            .sum();
+
+        for (degree, mode) in MODES_BY_DEGREE.iter().enumerate() {
+            let tonic = (major_tonic + MAJOR_SCALE[degree]) % 12;
+            let emphasis = histogram[tonic as usize] + histogram[((tonic + 7) % 12) as usize];
+
+            // Compared in order, so e.g. the final note only matters between equally good scales.
+            let score = (
+                fit,
+                tonic == last,
+                written == Some(bits),
+                emphasis,
+                mode_preference(*mode),
+            );
+
+            match best {
+                Some((best_score, _, _)) if best_score >= score => (),
+                _ => best = Some((score, tonic, *mode)),
+            }
+        }
+    }
+
+    best.map(|(_, tonic, mode)| (spell_key(tonic, mode), mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use representations;
+
+    #[test]
+    fn from_ast_in_key_test() {
+        let ast = representations::abc_to_ast("K:D\nDEFG ^G=F|F=cc\n");
+        assert_eq!(
+            PitchSequence::from_ast_in_key(&ast).pitches,
+            vec![62, 64, 66, 67, 68, 65, 66, 72, 72],
+            "Key signature applies, accidentals last until the end of the bar."
+        );
+    }
+
+    #[test]
+    fn interval_pitches_test() {
+        let ast = representations::abc_to_ast("K:D\nEF^GA G2F2|\n");
+        assert_eq!(
+            IntervalSequence::from_ast(&ast, IntervalPitches::Written).intervals,
+            vec![1, 3, 1, -2, -2]
+        );
+        assert_eq!(
+            IntervalSequence::from_ast(&ast, IntervalPitches::Sounding).intervals,
+            vec![2, 2, 1, -1, -2],
+            "Semitones between the notes as they sound."
+        );
+
+        assert_eq!(
+            IntervalPitches::from_string("sounding"),
+            Some(IntervalPitches::Sounding)
+        );
+        assert_eq!(IntervalPitches::from_string("diatonic"), None);
+    }
+
+    #[test]
+    fn parsons_code_test() {
+        let pitches = PitchSequence::from_pitches(&[60, 62, 62, 59, 71]);
+        let intervals = IntervalSequence::from_pitch_sequence(&pitches);
+        let parsons = ParsonsCode::from_interval_sequence(&intervals);
+        assert_eq!(parsons.to_string(), "*urdu");
+
+        assert_eq!(ParsonsCode::parse("*URDU").unwrap().contour, parsons.contour);
+        assert_eq!(
+            ParsonsCode::parse("usdu").unwrap().contour,
+            parsons.contour,
+            "Star is optional, 's' is the same as 'r'."
+        );
+        assert!(ParsonsCode::parse("*uxd").is_err());
+    }
+
+    #[test]
+    fn transformations_test() {
+        let pitches = PitchSequence::from_pitches(&[60, 62, 64, 60, 67]);
+        let intervals = IntervalSequence::from_pitch_sequence(&pitches);
+        assert_eq!(intervals.intervals, vec![2, 2, -4, 7]);
+
+        let retrograde = PitchSequence::from_pitches(&[67, 60, 64, 62, 60]);
+        assert_eq!(
+            intervals.retrograde().intervals,
+            IntervalSequence::from_pitch_sequence(&retrograde).intervals,
+            "Retrograde should be the intervals of the reversed melody."
+        );
+
+        let inversion = PitchSequence::from_pitches(&[60, 58, 56, 60, 53]);
+        assert_eq!(
+            intervals.inversion().intervals,
+            IntervalSequence::from_pitch_sequence(&inversion).intervals,
+            "Inversion should be the intervals of the upside-down melody."
+        );
+    }
+
+    #[test]
+    fn degree_sequence_test() {
+        let ast = representations::abc_to_ast("K:G\nGABcdefga");
+        assert_eq!(
+            DegreeSequence::from_ast(&ast).degrees,
+            vec![1, 2, 3, 4, 5, 6, 7, 1, 2],
+            "Degrees are relative to the key note and wrap at the octave."
+        );
+
+        let ast = representations::abc_to_ast("K:D\nDEFGA");
+        let transposed = representations::abc_to_ast("K:G\nGABcd");
+        assert_eq!(
+            DegreeSequence::from_ast(&ast).degrees,
+            DegreeSequence::from_ast(&transposed).degrees,
+            "The same tune in a different key has the same degrees."
+        );
+
+        let ast = representations::abc_to_ast("K:C\nC,E^Fb");
+        assert_eq!(
+            DegreeSequence::from_ast(&ast).degrees,
+            vec![1, 3, 4, 7],
+            "Octave and accidentals don't change the degree."
+        );
+
+        let ast = representations::abc_to_ast("K:G\nGAB\nK:D\nDEF");
+        assert_eq!(
+            DegreeSequence::from_ast(&ast).degrees,
+            vec![1, 2, 3, 1, 2, 3],
+            "A key change in the body applies to the notes after it."
+        );
+
+        let ast =
+            representations::abc_to_ast("K:G\nV:1\nGA\nK:D\nDE\nV:2\nGA");
+        assert_eq!(
+            DegreeSequence::from_ast(&ast).degrees,
+            vec![1, 2, 1, 2, 1, 2],
+            "Each voice starts in the header's key."
+        );
+    }
+
+    #[test]
+    fn detect_key_test() {
+        let key = |abc: &str| {
+            detect_key(&representations::abc_to_ast(abc)).map(|(pitch_class, mode)| {
+                format!("{} {}", pitch_class, mode)
+            })
+        };
+
+        assert_eq!(
+            key("X:1\nK:C\nGAB c2d|e2f g2f|edB G2c|\n"),
+            Some("C Major".to_string()),
+            "Ends on C."
+        );
+        assert_eq!(
+            key("X:1\nK:G\n|:B2E G2E F3|B2E G2E FED|B2d d2B A2F|B2E G2E E3:|\n"),
+            Some("E Minor".to_string()),
+            "A wrong header is corrected by the final note."
+        );
+        assert_eq!(
+            key("X:1\nK:D\nA2AB AFDF|G2BG dGBG|A2AB AFDF|GBdB A2A2|\n"),
+            Some("A Mixolydian".to_string()),
+            "Mixolydian when the tune ends on the fifth of the key signature."
+        );
+        assert_eq!(
+            key("X:1\nK:C\nF_B AG FGAc|_BAGF C2F2|\n"),
+            Some("F Major".to_string()),
+            "A missing key signature, with accidentals written out."
+        );
+        assert_eq!(
+            key("X:1\nK:C\nGABd edBG|\n"),
+            Some("G Mixolydian".to_string()),
+            "The written key signature is used when other scales fit as well."
+        );
+        assert_eq!(
+            key("X:1\nK:Emin\nGABd edBG|\n"),
+            Some("G Major".to_string()),
+            "A minor key signature is the relative major's."
+        );
+        assert_eq!(
+            key("X:1\nK:F\nGA=Bd edBG|\n"),
+            Some("G Major".to_string()),
+            "Otherwise pentatonic tunes prefer the major mode."
+        );
+        assert_eq!(
+            key("X:1\nK:Bb\nfdB Fdc B2|\n"),
+            Some("B♭ Major".to_string()),
+            "Key notes are spelled with the fewest accidentals."
+        );
+
+        assert_eq!(key("X:1\nK:G\n"), None);
+    }
+}
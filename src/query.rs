@@ -0,0 +1,1486 @@
+//! Search queries.
+//! A Query says how to generate a weighted set of tunes, how to filter it, and which page of it
+//! to select. Build one with a `QueryBuilder`, or parse one from query string key-value pairs.
+//!
+//! ```
+//! extern crate folktunefinder_abc;
+//! use folktunefinder_abc::query::QueryBuilder;
+//!
+//! let query = QueryBuilder::new()
+//!     .title("kesh")
+//!     .filter("rhythm", "jig")
+//!     .rows(10)
+//!     .build()
+//!     .unwrap();
+//! assert!(query.filter.has_filters());
+//! ```
+//!
+//! Query syntax, presented as key-value from query string:
+//! Filters:
+//!  - these depend on the data
+//!  - min_ and max_ of a numeric feature, e.g. max_range (see `features::NUMERIC_FEATURES`)
+//!
+//! Generators:
+//!  - all
+//!  - interval_ngram (or a snippet of ABC, via `parse_abc_query`)
+//!    - transform (retrograde or inversion)
+//!    - intervals (written or sounding)
+//!  - title (with "quoted phrases", +required and -excluded words)
+//!  - composer
+//!  - origin
+//!  - rhythm_text
+//!  - degree_ngram
+//!  - parsons
+//!  - interval_histogram
+//!  - degree_histogram
+//!  - sample (with optional seed)
+//!  - a tune ID, for similar tunes (with optional cutoff), via `parse_similar_query`
+//!
+//! Select:
+//!  - offset
+//!  - rows
+//!  - rollup
+//!  - group (only 'cluster' is supported)
+//!  - facet
+//!  - facet.limit (-1 for no limit)
+//!  - total
+//!
+//! Score:
+//!  - normalization (doca or tfidf)
+//!
+//! `parse_pick_query` uses only the filters, to pick one tune for the random and daily endpoints.
+
+use std::collections::{BTreeMap, HashMap};
+
+use features;
+use pitch;
+use relations::{ClusterParams, ScoreNormalization};
+use representations;
+use text;
+
+pub const DEFAULT_ROWS: usize = 30;
+pub const MAX_ROWS: usize = 1000;
+
+// Facet values of each type to include by default.
+pub const DEFAULT_FACET_LIMIT: usize = 20;
+
+// One octave above and below key note.
+pub const HISTOGRAM_LENGTH: usize = 25;
+
+// A Generator supplies a weighted result set. Only one generator per result.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Generator {
+    // All tunes, weighted by ID.
+    All,
+
+    // A reproducible random sample of this many tunes matching the filters, with a seed.
+    Sample(usize, u64),
+
+    Title(String),
+
+    // Search text of the C: (composer), O: (origin) and R: (rhythm) headers.
+    Composer(String),
+    Origin(String),
+    RhythmText(String),
+
+    // Search by interval n-gram similarity, weighted by similarity.
+    IntervalNGram(Vec<u8>),
+
+    // Search for tunes whose retrograde (the melody played backwards) is similar.
+    RetrogradeIntervalNGram(Vec<u8>),
+
+    // Search for tunes whose inversion (the melody upside-down) is similar.
+    InversionIntervalNGram(Vec<u8>),
+
+    // Search by interval n-gram similarity against the indexes of sounding intervals, with the
+    // key signature and accidentals applied, see `pitch::IntervalPitches`.
+    SoundingIntervalNGram(Vec<u8>),
+
+    // Tunes whose melody is like this tune's, from the interval index, scoring at least the
+    // cutoff (0 to 1). The tune itself isn't included.
+    Similar(usize, f32),
+
+    // Search by scale degree n-gram similarity, weighted by similarity.
+    // Degrees are 1 to 7, relative to the key note.
+    DegreeNGram(Vec<u8>),
+
+    // Search by melodic contour, given as Parsons code, weighted by similarity.
+    Parsons(Vec<pitch::Contour>),
+
+    // Search by interval histogram similarity, weighted by similarity.
+    // TODO not yet implemented.
+    IntervalHistogram(Vec<f32>),
+
+    // Search by degree histogram similarity, weighted by similarity.
+    // TODO not yet implemented.
+    DegreeHistogram(Vec<f32>),
+}
+
+impl Generator {
+    // Does the generator say which parts of a tune matched? If so, decorating results needs the
+    // ABC.
+    pub fn has_matches(&self) -> bool {
+        matches!(
+            self,
+            Generator::Title(_)
+                | Generator::Composer(_)
+                | Generator::Origin(_)
+                | Generator::RhythmText(_)
+                | Generator::IntervalNGram(_)
+                | Generator::RetrogradeIntervalNGram(_)
+                | Generator::InversionIntervalNGram(_)
+                | Generator::SoundingIntervalNGram(_)
+                | Generator::DegreeNGram(_)
+                | Generator::Parsons(_)
+        )
+    }
+}
+
+// Only include tunes with a value of a numeric feature type between the bounds, inclusive.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct NumericRange {
+    pub feature_type: String,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+// A filter selects items in the result set.
+// All terms are ANDed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Filter {
+    pub features: Vec<(String, String)>,
+    pub ranges: Vec<NumericRange>,
+}
+
+impl Filter {
+    pub fn has_filters(&self) -> bool {
+        !self.features.is_empty() || !self.ranges.is_empty()
+    }
+}
+
+impl Selection {
+    // Where the next page starts, if there are more results after this one.
+    pub fn next_offset(&self, num_results: usize) -> Option<usize> {
+        if self.offset + self.rows < num_results {
+            Some(self.offset + self.rows)
+        } else {
+            None
+        }
+    }
+
+    // Where the previous page starts, unless this is the first. A page that starts part way into
+    // the first page goes back to the start.
+    pub fn prev_offset(&self) -> Option<usize> {
+        if self.offset > 0 {
+            Some(self.offset.saturating_sub(self.rows))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Selection {
+    // Start at this index of the results.
+    pub offset: usize,
+
+    // Return only this many rows.
+    pub rows: usize,
+
+    // Roll-up tunes based on their cluster.
+    // When true, return only the best tune per group.
+    pub rollup: bool,
+
+    // Nest the other versions of each tune within the best tune of its group.
+    // When true, implies rollup.
+    pub group: bool,
+
+    // Include facets for all features.
+    pub facet: bool,
+
+    // Most values to include for each type of facet, with the rest counted together as "other".
+    // None for all of them.
+    pub facet_limit: Option<usize>,
+
+    // Count all results. Without a count, facets, filters or rollup, only the results up to the
+    // end of the page are found, which is quicker, and the totals only include those.
+    pub total: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Query {
+    pub generator: Generator,
+    pub filter: Filter,
+    pub selection: Selection,
+
+    // How the generator scores tunes, with `normalization=tfidf` to weight rare terms higher.
+    pub normalization: ScoreNormalization,
+}
+
+// Comma-separated list, as in `interval_ngram`.
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+impl Query {
+    // The query as query string key-value pairs that `parse_query` reads as the same query,
+    // without those that have the default value, and sorted. The same query always gives the
+    // same pairs, however it was written, so they can be used for links and cache keys. Text is
+    // written as `text::normalise_query` writes it, and repeated filters appear once.
+    // A search for similar tunes has the tune ID in the path, so only the cutoff is included.
+    pub fn to_params(&self) -> Vec<(String, String)> {
+        let mut params: Vec<(String, String)> = vec![];
+        let mut param = |key: &str, value: String| params.push((key.to_string(), value));
+
+        let defaults = ClusterParams::default();
+        let mut normalization = ScoreNormalization::DocA;
+
+        match self.generator {
+            Generator::All => (),
+            Generator::Sample(size, seed) => {
+                param("sample", size.to_string());
+                if seed != 0 {
+                    param("seed", seed.to_string());
+                }
+            }
+            Generator::Title(ref value) => param("title", text::normalise_query(value)),
+            Generator::Composer(ref value) => param("composer", text::normalise_query(value)),
+            Generator::Origin(ref value) => param("origin", text::normalise_query(value)),
+            Generator::RhythmText(ref value) => param("rhythm_text", text::normalise_query(value)),
+            Generator::IntervalNGram(ref pitches) => param("interval_ngram", join(pitches)),
+            Generator::RetrogradeIntervalNGram(ref pitches) => {
+                param("interval_ngram", join(pitches));
+                param("transform", "retrograde".to_string());
+            }
+            Generator::InversionIntervalNGram(ref pitches) => {
+                param("interval_ngram", join(pitches));
+                param("transform", "inversion".to_string());
+            }
+            Generator::SoundingIntervalNGram(ref pitches) => {
+                param("interval_ngram", join(pitches));
+                param("intervals", "sounding".to_string());
+            }
+            Generator::Similar(_, cutoff) => {
+                if cutoff != defaults.cutoff {
+                    param("cutoff", cutoff.to_string());
+                }
+                normalization = defaults.normalization;
+            }
+            Generator::DegreeNGram(ref degrees) => param("degree_ngram", join(degrees)),
+            Generator::Parsons(ref contour) => {
+                let parsons = pitch::ParsonsCode {
+                    contour: contour.clone(),
+                };
+                param("parsons", parsons.to_string());
+            }
+            Generator::IntervalHistogram(ref value) => param("interval_histogram", join(value)),
+            Generator::DegreeHistogram(ref value) => param("degree_histogram", join(value)),
+        }
+
+        for (feature_type, value) in self.filter.features.iter() {
+            param(feature_type, value.clone());
+        }
+        for range in self.filter.ranges.iter() {
+            if let Some(min) = range.min {
+                param(&format!("min_{}", range.feature_type), min.to_string());
+            }
+            if let Some(max) = range.max {
+                param(&format!("max_{}", range.feature_type), max.to_string());
+            }
+        }
+
+        let selection = &self.selection;
+        if selection.offset != 0 {
+            param("offset", selection.offset.to_string());
+        }
+        if selection.rows != DEFAULT_ROWS {
+            param("rows", selection.rows.to_string());
+        }
+        if !selection.rollup {
+            param("rollup", "false".to_string());
+        }
+        if selection.group {
+            param("group", "cluster".to_string());
+        }
+        if !selection.facet {
+            param("facet", "false".to_string());
+        }
+        match selection.facet_limit {
+            Some(DEFAULT_FACET_LIMIT) => (),
+            Some(limit) => param("facet.limit", limit.to_string()),
+            None => param("facet.limit", "-1".to_string()),
+        }
+        if !selection.total {
+            param("total", "false".to_string());
+        }
+
+        if self.normalization != normalization {
+            param("normalization", self.normalization.to_string());
+        }
+
+        // A filter given twice is the same as given once.
+        params.sort();
+        params.dedup();
+        params
+    }
+}
+
+// Builds a Query, checking the values are in range.
+// Starts as a query for all tunes, with the same defaults as an empty query string.
+pub struct QueryBuilder {
+    generator: Generator,
+    features: Vec<(String, String)>,
+    ranges: Vec<NumericRange>,
+    offset: usize,
+    rows: usize,
+    rollup: bool,
+    group: bool,
+    facet: bool,
+    facet_limit: Option<usize>,
+    total: bool,
+    normalization: ScoreNormalization,
+}
+
+impl Default for QueryBuilder {
+    fn default() -> QueryBuilder {
+        QueryBuilder::new()
+    }
+}
+
+impl QueryBuilder {
+    pub fn new() -> QueryBuilder {
+        QueryBuilder {
+            generator: Generator::All,
+            features: vec![],
+            ranges: vec![],
+            offset: 0,
+            rows: DEFAULT_ROWS,
+            rollup: true,
+            group: false,
+            facet: true,
+            facet_limit: Some(DEFAULT_FACET_LIMIT),
+            total: true,
+            normalization: ScoreNormalization::DocA,
+        }
+    }
+
+    pub fn generator(mut self, generator: Generator) -> QueryBuilder {
+        self.generator = generator;
+        self
+    }
+
+    pub fn title(self, text: &str) -> QueryBuilder {
+        self.generator(Generator::Title(text.to_string()))
+    }
+
+    pub fn composer(self, text: &str) -> QueryBuilder {
+        self.generator(Generator::Composer(text.to_string()))
+    }
+
+    pub fn origin(self, text: &str) -> QueryBuilder {
+        self.generator(Generator::Origin(text.to_string()))
+    }
+
+    pub fn rhythm_text(self, text: &str) -> QueryBuilder {
+        self.generator(Generator::RhythmText(text.to_string()))
+    }
+
+    // Search by a melody, given as MIDI pitches.
+    pub fn melody(self, pitches: Vec<u8>) -> QueryBuilder {
+        self.generator(Generator::IntervalNGram(pitches))
+    }
+
+    // Search for tunes like an existing tune, as when clustering.
+    pub fn similar(self, tune_id: usize, cutoff: f32) -> QueryBuilder {
+        self.generator(Generator::Similar(tune_id, cutoff))
+    }
+
+    pub fn sample(self, size: usize, seed: u64) -> QueryBuilder {
+        self.generator(Generator::Sample(size, seed))
+    }
+
+    // Only include tunes with this feature value. Values of the same type are ORed, and types are
+    // ANDed. The value is normalised in the same way as when it's indexed.
+    pub fn filter(mut self, feature_type: &str, value: &str) -> QueryBuilder {
+        self.features.push((
+            feature_type.to_string(),
+            features::normalise(feature_type, value),
+        ));
+        self
+    }
+
+    // Only include tunes with a value of this numeric feature type between the bounds, inclusive,
+    // e.g. `range("range", None, Some(12.0))` for tunes that span an octave or less.
+    pub fn range(mut self, feature_type: &str, min: Option<f32>, max: Option<f32>) -> QueryBuilder {
+        self.ranges.push(NumericRange {
+            feature_type: feature_type.to_string(),
+            min,
+            max,
+        });
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> QueryBuilder {
+        self.offset = offset;
+        self
+    }
+
+    pub fn rows(mut self, rows: usize) -> QueryBuilder {
+        self.rows = rows;
+        self
+    }
+
+    pub fn rollup(mut self, rollup: bool) -> QueryBuilder {
+        self.rollup = rollup;
+        self
+    }
+
+    pub fn group(mut self, group: bool) -> QueryBuilder {
+        self.group = group;
+        self
+    }
+
+    pub fn facet(mut self, facet: bool) -> QueryBuilder {
+        self.facet = facet;
+        self
+    }
+
+    pub fn facet_limit(mut self, facet_limit: Option<usize>) -> QueryBuilder {
+        self.facet_limit = facet_limit;
+        self
+    }
+
+    pub fn total(mut self, total: bool) -> QueryBuilder {
+        self.total = total;
+        self
+    }
+
+    pub fn normalization(mut self, normalization: ScoreNormalization) -> QueryBuilder {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn build(self) -> Result<Query, QueryError> {
+        if self.rows > MAX_ROWS {
+            return Err(QueryError::invalid(
+                "rows",
+                &format!(
+                    "Too many rows requested for 'rows'. The most is {}.",
+                    MAX_ROWS
+                ),
+            ));
+        }
+
+        for range in self.ranges.iter() {
+            if let (Some(min), Some(max)) = (range.min, range.max) {
+                if min > max {
+                    return Err(QueryError::invalid(
+                        &format!("min_{}", range.feature_type),
+                        &format!(
+                            "Invalid value for 'min_{}'. It's more than 'max_{}'.",
+                            range.feature_type, range.feature_type
+                        ),
+                    ));
+                }
+            }
+        }
+
+        match self.generator {
+            Generator::Sample(size, _) if size > MAX_ROWS => {
+                return Err(QueryError::invalid(
+                    "sample",
+                    &format!("Sample is too big for 'sample'. The most is {}.", MAX_ROWS),
+                ))
+            }
+            Generator::Similar(_, cutoff) if !(0.0..=1.0).contains(&cutoff) => {
+                return Err(QueryError::invalid(
+                    "cutoff",
+                    "Invalid value for 'cutoff'. Must be between 0 and 1.",
+                ))
+            }
+            Generator::DegreeNGram(ref degrees) if degrees.iter().any(|x| *x < 1 || *x > 7) => {
+                return Err(QueryError::invalid(
+                    "degree_ngram",
+                    "Invalid value given for 'degree_ngram'. Degrees must be 1 to 7.",
+                ))
+            }
+            Generator::IntervalHistogram(ref value) if value.len() != HISTOGRAM_LENGTH => {
+                return Err(histogram_length_error("interval_histogram"))
+            }
+            Generator::DegreeHistogram(ref value) if value.len() != HISTOGRAM_LENGTH => {
+                return Err(histogram_length_error("degree_histogram"))
+            }
+            _ => (),
+        }
+
+        // Max is for comparing whole tunes, which only a search for similar tunes does.
+        let whole_tunes = matches!(self.generator, Generator::Similar(_, _));
+        if self.normalization == ScoreNormalization::Max && !whole_tunes {
+            return Err(QueryError::invalid(
+                "normalization",
+                "Invalid value for 'normalization'. Try 'doca' or 'tfidf'.",
+            ));
+        }
+
+        Ok(Query {
+            generator: self.generator,
+            filter: Filter {
+                features: self.features,
+                ranges: self.ranges,
+            },
+            selection: Selection {
+                offset: self.offset,
+                rows: self.rows,
+                rollup: self.rollup,
+                group: self.group,
+                facet: self.facet,
+                facet_limit: self.facet_limit,
+                total: self.total,
+            },
+            normalization: self.normalization,
+        })
+    }
+}
+
+// Why a query can't be built. Most errors are in the value of one parameter, which is named so
+// that clients can point to it.
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    pub parameter: Option<String>,
+    pub message: String,
+}
+
+impl QueryError {
+    // An error in the value of a parameter.
+    fn invalid(parameter: &str, message: &str) -> QueryError {
+        QueryError {
+            parameter: Some(parameter.to_string()),
+            message: message.to_string(),
+        }
+    }
+
+    // An error that isn't down to one parameter, e.g. ABC that's too short to search.
+    fn other(message: &str) -> QueryError {
+        QueryError {
+            parameter: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn histogram_length_error(parameter: &str) -> QueryError {
+    QueryError::invalid(
+        parameter,
+        &format!(
+            "Invalid length for '{}'. Must be exactly {}",
+            parameter, HISTOGRAM_LENGTH
+        ),
+    )
+}
+
+// What the search engine has indexed, which decides what a parsed query can ask for.
+pub struct Capabilities<'a> {
+    // Known feature types, and their values. Other parameters aren't filters.
+    pub features: &'a HashMap<String, Vec<String>>,
+
+    // Feature types that tunes can have, whether or not they're indexed.
+    pub feature_types: Vec<&'static str>,
+
+    // Rollup and facets can be disabled for the whole engine, in which case they're off
+    // regardless of what's asked for.
+    pub rollup: bool,
+    pub facet: bool,
+
+    // Are retrograde and inversion indexed?
+    pub transforms: bool,
+
+    // Length of the contour window. Shorter Parsons codes can't match.
+    pub contour_window_size: usize,
+
+    // Length of the smallest interval window. A melody needs one more note than this to match.
+    pub interval_window_size: usize,
+
+    // Length of the smallest sounding interval window, if sounding intervals are indexed.
+    pub sounding_interval_window_size: Option<usize>,
+}
+
+// The feature type a filter parameter is for, e.g. "range" for "max_range".
+fn filtered_feature_type(k: &str) -> &str {
+    match k.strip_prefix("min_").or_else(|| k.strip_prefix("max_")) {
+        Some(feature_type) if features::NUMERIC_FEATURES.contains(&feature_type) => feature_type,
+        _ => k,
+    }
+}
+
+fn parse_filter(
+    builder: QueryBuilder,
+    params: &[(String, String)],
+    capabilities: &Capabilities,
+) -> Result<QueryBuilder, QueryError> {
+    // Without facets there's no index of features, so a filter would be ignored. Say so, rather
+    // than return tunes that don't match it.
+    if !capabilities.facet {
+        if let Some((k, _)) = params.iter().find(|(k, _)| {
+            capabilities
+                .feature_types
+                .contains(&filtered_feature_type(k))
+        }) {
+            return Err(QueryError::invalid(
+                k,
+                &format!(
+                    "Invalid parameter '{}'. Filtering isn't available on this server.",
+                    k
+                ),
+            ));
+        }
+    }
+
+    // The syntax depends on the features we've extracted from the corpus. Whilst the set of
+    // feature types is hard-coded, it's best to make the parsing data-driven. This couples the
+    // search to the present corpus not the code.
+    let builder = params
+        .iter()
+        .filter(|(k, _)| capabilities.features.contains_key(k))
+        .fold(builder, |builder, (k, v)| builder.filter(k, v));
+
+    // Numeric features can also be filtered by range, e.g. `min_notes=32&max_range=12`.
+    // Feature type -> (min, max).
+    let mut ranges: BTreeMap<&str, (Option<f32>, Option<f32>)> = BTreeMap::new();
+    for (k, v) in params.iter() {
+        let (is_min, feature_type) = if let Some(feature_type) = k.strip_prefix("min_") {
+            (true, feature_type)
+        } else if let Some(feature_type) = k.strip_prefix("max_") {
+            (false, feature_type)
+        } else {
+            continue;
+        };
+
+        if !features::NUMERIC_FEATURES.contains(&feature_type)
+            || !capabilities.features.contains_key(feature_type)
+        {
+            continue;
+        }
+
+        let value = match v.parse::<f32>() {
+            Ok(value) if value.is_finite() => value,
+            _ => {
+                return Err(QueryError::invalid(
+                    k,
+                    &format!("Invalid value for '{}'. Must be a number.", k),
+                ))
+            }
+        };
+
+        let bounds = ranges.entry(feature_type).or_insert((None, None));
+        if is_min {
+            bounds.0 = Some(value);
+        } else {
+            bounds.1 = Some(value);
+        }
+    }
+
+    Ok(ranges
+        .into_iter()
+        .fold(builder, |builder, (feature_type, (min, max))| {
+            builder.range(feature_type, min, max)
+        }))
+}
+
+fn parse_bool(
+    params: &HashMap<String, String>,
+    param_name: &str,
+    default: bool,
+) -> Result<bool, QueryError> {
+    match params.get(param_name) {
+        Some(val) => match val.as_ref() {
+            // HTML forms use on/off . API usage is more conventional true/false.
+            "true" | "on" => Ok(true),
+            "false" | "off" => Ok(false),
+            _ => Err(QueryError::invalid(
+                param_name,
+                &format!("Invalid value for '{}'", param_name),
+            )),
+        },
+        _ => Ok(default),
+    }
+}
+
+fn parse_selection(
+    builder: QueryBuilder,
+    params: &HashMap<String, String>,
+    capabilities: &Capabilities,
+) -> Result<QueryBuilder, QueryError> {
+    let offset: usize = match params.get("offset") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => return Err(QueryError::invalid("offset", "Invalid value for 'offset'.")),
+        },
+        _ => 0,
+    };
+
+    let rows: usize = match params.get("rows") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => return Err(QueryError::invalid("rows", "Invalid value for 'rows'")),
+        },
+        _ => DEFAULT_ROWS,
+    };
+
+    let rollup = parse_bool(params, "rollup", true)? && capabilities.rollup;
+    let facet = parse_bool(params, "facet", true)? && capabilities.facet;
+    let total = parse_bool(params, "total", true)?;
+
+    let facet_limit = match params.get("facet.limit").map(|x| x.as_ref()) {
+        Some("-1") => None,
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                return Err(QueryError::invalid(
+                    "facet.limit",
+                    "Invalid value for 'facet.limit'. Use a number, or -1 for no limit.",
+                ))
+            }
+        },
+        None => Some(DEFAULT_FACET_LIMIT),
+    };
+
+    let group = match params.get("group").map(|x| x.as_ref()) {
+        Some("cluster") => capabilities.rollup,
+        None => false,
+        _ => {
+            return Err(QueryError::invalid(
+                "group",
+                "Invalid value for 'group'. Only 'cluster' is supported.",
+            ))
+        }
+    };
+
+    Ok(builder
+        .offset(offset)
+        .rows(rows)
+        .rollup(rollup)
+        .group(group)
+        .facet(facet)
+        .facet_limit(facet_limit)
+        .total(total))
+}
+
+fn parse_normalization(params: &HashMap<String, String>) -> Result<ScoreNormalization, QueryError> {
+    match params.get("normalization") {
+        Some(value) => match ScoreNormalization::from_string(value) {
+            Some(normalization) => Ok(normalization),
+            None => Err(QueryError::invalid(
+                "normalization",
+                "Invalid value for 'normalization'. Try 'doca' or 'tfidf'.",
+            )),
+        },
+        None => Ok(ScoreNormalization::DocA),
+    }
+}
+
+// Which intervals a melody search is against, with `intervals=sounding` for the indexes of
+// sounding intervals.
+fn parse_interval_pitches(
+    params: &HashMap<String, String>,
+    capabilities: &Capabilities,
+) -> Result<pitch::IntervalPitches, QueryError> {
+    let interval_pitches = match params.get("intervals") {
+        Some(value) => pitch::IntervalPitches::from_string(value).ok_or_else(|| {
+            QueryError::invalid(
+                "intervals",
+                "Invalid value for 'intervals'. Must be 'written' or 'sounding'.",
+            )
+        })?,
+        None => pitch::IntervalPitches::Written,
+    };
+
+    if interval_pitches == pitch::IntervalPitches::Sounding {
+        if capabilities.sounding_interval_window_size.is_none() {
+            return Err(QueryError::other(
+                "Sounding intervals are not indexed on this server.",
+            ));
+        }
+        if params.contains_key("transform") {
+            return Err(QueryError::other(
+                "Retrograde and inversion are only indexed with written intervals.",
+            ));
+        }
+    }
+
+    Ok(interval_pitches)
+}
+
+// Interval search for a melody given as pitches.
+// Optionally search against the transformed versions of the tunes, or the sounding intervals.
+fn parse_interval_generator(
+    pitches: Vec<u8>,
+    params: &HashMap<String, String>,
+    capabilities: &Capabilities,
+) -> Result<Generator, QueryError> {
+    if parse_interval_pitches(params, capabilities)? == pitch::IntervalPitches::Sounding {
+        return Ok(Generator::SoundingIntervalNGram(pitches));
+    }
+
+    let indexed = capabilities.transforms;
+    match params.get("transform").map(|x| x.as_ref()) {
+        None => Ok(Generator::IntervalNGram(pitches)),
+        Some("retrograde") if indexed => Ok(Generator::RetrogradeIntervalNGram(pitches)),
+        Some("inversion") if indexed => Ok(Generator::InversionIntervalNGram(pitches)),
+        Some("retrograde") | Some("inversion") => Err(QueryError::other(
+            "Retrograde and inversion are not indexed on this server.",
+        )),
+        _ => Err(QueryError::invalid(
+            "transform",
+            "Invalid value for 'transform'. Must be 'retrograde' or 'inversion'.",
+        )),
+    }
+}
+
+fn parse_generator(
+    params: &HashMap<String, String>,
+    capabilities: &Capabilities,
+) -> Result<Generator, QueryError> {
+    // This argument is given as absolute pitches, at least for now.
+    // Would be more consistent to convert it to intervals prior to querying API perhaps...
+
+    if let Some(val) = params.get("sample") {
+        let size = match val.parse::<usize>() {
+            Ok(size) => size,
+            Err(_) => {
+                return Err(QueryError::invalid(
+                    "sample",
+                    "Invalid value given for 'sample'",
+                ))
+            }
+        };
+
+        let seed = match params.get("seed").map(|x| x.parse::<u64>()) {
+            Some(Ok(seed)) => seed,
+            Some(Err(_)) => {
+                return Err(QueryError::invalid(
+                    "seed",
+                    "Invalid value given for 'seed'",
+                ))
+            }
+            None => 0,
+        };
+
+        return Ok(Generator::Sample(size, seed));
+    }
+
+    match params.get("title") {
+        Some(val) if !val.is_empty() => return Ok(Generator::Title(val.to_string())),
+        _ => (),
+    }
+
+    match params.get("composer") {
+        Some(val) if !val.is_empty() => return Ok(Generator::Composer(val.to_string())),
+        _ => (),
+    }
+
+    match params.get("origin") {
+        Some(val) if !val.is_empty() => return Ok(Generator::Origin(val.to_string())),
+        _ => (),
+    }
+
+    match params.get("rhythm_text") {
+        Some(val) if !val.is_empty() => return Ok(Generator::RhythmText(val.to_string())),
+        _ => (),
+    }
+
+    if let Some(val) = params.get("interval_ngram") {
+        let value = match val.split(",").map(|s| s.parse::<u8>()).collect() {
+            Ok(value) => value,
+            Err(_) => {
+                return Err(QueryError::invalid(
+                    "interval_ngram",
+                    "Invalid value given for 'interval_ngram'",
+                ))
+            }
+        };
+
+        return parse_interval_generator(value, params, capabilities);
+    }
+
+    if let Some(val) = params.get("degree_ngram") {
+        return match val.split(",").map(|s| s.parse::<u8>()).collect() {
+            Ok(value) => Ok(Generator::DegreeNGram(value)),
+            Err(_) => Err(QueryError::invalid(
+                "degree_ngram",
+                "Invalid value given for 'degree_ngram'. Degrees must be 1 to 7.",
+            )),
+        };
+    }
+
+    if let Some(val) = params.get("parsons") {
+        let parsons = pitch::ParsonsCode::parse(val).map_err(|message| {
+            QueryError::invalid(
+                "parsons",
+                &format!("Invalid value for 'parsons'. {}", message),
+            )
+        })?;
+
+        // Shorter contours can't match any terms.
+        let window_size = capabilities.contour_window_size;
+        if parsons.contour.len() < window_size {
+            return Err(QueryError::invalid(
+                "parsons",
+                &format!(
+                    "Invalid value for 'parsons'. {} is too short to search. Needs at least {} \
+                     steps.",
+                    parsons,
+                    window_size
+                ),
+            ));
+        }
+
+        return Ok(Generator::Parsons(parsons.contour));
+    }
+
+    if let Some(val) = params.get("interval_histogram") {
+        return match val.split(",").map(|s| s.parse::<f32>()).collect() {
+            Ok(value) => Ok(Generator::IntervalHistogram(value)),
+            Err(_) => Err(QueryError::invalid(
+                "interval_histogram",
+                "Invalid value given for 'interval_histogram'",
+            )),
+        };
+    }
+
+    if let Some(val) = params.get("degree_histogram") {
+        return match val.split(",").map(|s| s.parse::<f32>()).collect() {
+            Ok(value) => Ok(Generator::DegreeHistogram(value)),
+            Err(_) => Err(QueryError::invalid(
+                "degree_histogram",
+                "Invalid value given for 'degree_histogram'",
+            )),
+        };
+    }
+
+    Ok(Generator::All)
+}
+
+// Parse a query from query string key-value pairs.
+pub fn parse_query(
+    params: &[(String, String)],
+    capabilities: &Capabilities,
+) -> Result<Query, QueryError> {
+    let params_map: HashMap<_, _> = params.iter().cloned().collect();
+
+    let builder = parse_filter(QueryBuilder::new(), params, capabilities)?;
+    let builder = parse_selection(builder, &params_map, capabilities)?;
+    let generator = parse_generator(&params_map, capabilities)?;
+    let normalization = parse_normalization(&params_map)?;
+
+    builder
+        .generator(generator)
+        .normalization(normalization)
+        .build()
+}
+
+// Search for tunes like an existing tune. Takes a `cutoff`, and filter, selection and
+// normalization params as for `parse_query`. Without them, the cutoff and normalization are the
+// same as for clustering, so the tune's cluster is among the results.
+pub fn parse_similar_query(
+    tune_id: usize,
+    params: &[(String, String)],
+    capabilities: &Capabilities,
+) -> Result<Query, QueryError> {
+    let params_map: HashMap<_, _> = params.iter().cloned().collect();
+    let defaults = ClusterParams::default();
+
+    let cutoff = match params_map.get("cutoff").map(|x| x.parse::<f32>()) {
+        Some(Ok(cutoff)) => cutoff,
+        Some(Err(_)) => {
+            return Err(QueryError::invalid(
+                "cutoff",
+                "Invalid value for 'cutoff'. Must be between 0 and 1.",
+            ))
+        }
+        None => defaults.cutoff,
+    };
+
+    let normalization = if params_map.contains_key("normalization") {
+        parse_normalization(&params_map)?
+    } else {
+        defaults.normalization
+    };
+
+    let builder = parse_filter(QueryBuilder::new(), params, capabilities)?;
+    let builder = parse_selection(builder, &params_map, capabilities)?;
+
+    builder
+        .similar(tune_id, cutoff)
+        .normalization(normalization)
+        .build()
+}
+
+// Pick one tune at random from those matching the filters in the params. The same seed always
+// picks the same tune. Other generators and selection params are ignored.
+pub fn parse_pick_query(
+    params: &[(String, String)],
+    seed: u64,
+    capabilities: &Capabilities,
+) -> Result<Query, QueryError> {
+    parse_filter(QueryBuilder::new(), params, capabilities)?
+        .sample(1, seed)
+        .rows(1)
+        .build()
+}
+
+// Seed for the tune of the day, from the number of days since the Unix epoch.
+// Neighbouring days are mixed (SplitMix64) so they don't give neighbouring seeds.
+pub fn daily_seed(day: i64) -> u64 {
+    let mut z = (day as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// Build a melody query from a snippet of ABC, e.g. a few bars.
+// Other parameters are as for `parse_query`, but the ABC is the only generator.
+pub fn parse_abc_query(
+    snippet: &str,
+    params: &[(String, String)],
+    capabilities: &Capabilities,
+) -> Result<Query, QueryError> {
+    let params_map: HashMap<_, _> = params.iter().cloned().collect();
+
+    // The ABC says which notes are sharp or flat, so a search against sounding intervals needs
+    // the sounding pitches.
+    let (pitches, window_size) = match parse_interval_pitches(&params_map, capabilities)? {
+        pitch::IntervalPitches::Written => (
+            representations::abc_snippet_to_pitches(snippet)
+                .map_err(|message| QueryError::other(&message))?,
+            capabilities.interval_window_size,
+        ),
+        pitch::IntervalPitches::Sounding => (
+            representations::abc_snippet_to_sounding_pitches(snippet)
+                .map_err(|message| QueryError::other(&message))?,
+            capabilities
+                .sounding_interval_window_size
+                .unwrap_or(capabilities.interval_window_size),
+        ),
+    };
+
+    // Each melody term is a window of intervals, so anything shorter can't match.
+    let min_notes = window_size + 1;
+    if pitches.len() < min_notes {
+        return Err(QueryError::other(&format!(
+            "ABC is too short to search. Needs at least {} notes.",
+            min_notes
+        )));
+    }
+
+    let builder = parse_filter(QueryBuilder::new(), params, capabilities)?;
+    let builder = parse_selection(builder, &params_map, capabilities)?;
+    let generator = parse_interval_generator(pitches, &params_map, capabilities)?;
+    let normalization = parse_normalization(&params_map)?;
+
+    builder
+        .generator(generator)
+        .normalization(normalization)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn with_capabilities<F: Fn(&Capabilities)>(rollup: bool, f: F) {
+        let mut features = HashMap::new();
+        features.insert("metre".to_string(), vec!["4/4".to_string()]);
+        features.insert("rhythm".to_string(), vec!["jig".to_string()]);
+        features.insert("range".to_string(), vec!["12".to_string()]);
+        features.insert("notes".to_string(), vec!["64".to_string()]);
+
+        f(&Capabilities {
+            features: &features,
+            feature_types: vec!["metre", "rhythm", "range", "notes"],
+            rollup,
+            facet: true,
+            transforms: false,
+            contour_window_size: 8,
+            interval_window_size: 5,
+            sounding_interval_window_size: Some(3),
+        });
+    }
+
+    #[test]
+    fn page_offsets_test() {
+        let selection = |offset, rows| {
+            QueryBuilder::new()
+                .offset(offset)
+                .rows(rows)
+                .build()
+                .unwrap()
+                .selection
+        };
+
+        // The first page.
+        assert_eq!(selection(0, 10).prev_offset(), None);
+        assert_eq!(selection(0, 10).next_offset(25), Some(10));
+
+        // A page that starts part way into the first.
+        assert_eq!(selection(5, 10).prev_offset(), Some(0));
+
+        // The last page, whether it's full or not.
+        assert_eq!(selection(20, 10).prev_offset(), Some(10));
+        assert_eq!(selection(20, 10).next_offset(25), None);
+        assert_eq!(selection(10, 10).next_offset(20), None);
+        assert_eq!(selection(10, 10).next_offset(21), Some(20));
+
+        // No results.
+        assert_eq!(selection(0, 10).next_offset(0), None);
+    }
+
+    #[test]
+    fn builder_test() {
+        let query = QueryBuilder::new().build().unwrap();
+        assert!(matches!(query.generator, Generator::All));
+        assert!(!query.filter.has_filters());
+        assert_eq!(query.selection.rows, DEFAULT_ROWS);
+        assert_eq!(query.selection.facet_limit, Some(DEFAULT_FACET_LIMIT));
+        assert!(query.selection.rollup && query.selection.facet && query.selection.total);
+
+        let query = QueryBuilder::new()
+            .title("kesh")
+            .filter("metre", "2/2")
+            .offset(20)
+            .rows(10)
+            .normalization(ScoreNormalization::TfIdf)
+            .build()
+            .unwrap();
+        assert!(matches!(query.generator, Generator::Title(ref text) if text == "kesh"));
+        assert_eq!(
+            query.filter.features,
+            vec![("metre".to_string(), "4/4".to_string())],
+            "Filter values are normalised."
+        );
+        assert_eq!((query.selection.offset, query.selection.rows), (20, 10));
+        assert_eq!(query.normalization, ScoreNormalization::TfIdf);
+    }
+
+    #[test]
+    fn builder_errors_test() {
+        assert!(QueryBuilder::new().rows(MAX_ROWS + 1).build().is_err());
+        assert!(QueryBuilder::new().sample(MAX_ROWS + 1, 0).build().is_err());
+        assert!(QueryBuilder::new()
+            .generator(Generator::DegreeNGram(vec![1, 8]))
+            .build()
+            .is_err());
+        assert!(QueryBuilder::new()
+            .generator(Generator::IntervalHistogram(vec![0.0; 3]))
+            .build()
+            .is_err());
+        assert!(QueryBuilder::new()
+            .normalization(ScoreNormalization::Max)
+            .build()
+            .is_err());
+
+        let err = QueryBuilder::new()
+            .generator(Generator::DegreeHistogram(vec![0.0; 3]))
+            .build()
+            .unwrap_err();
+        assert_eq!(err.parameter, Some("degree_histogram".to_string()));
+    }
+
+    #[test]
+    fn error_parameter_test() {
+        with_capabilities(true, |capabilities| {
+            for (bad, parameter) in [
+                (vec![("rows", "many")], "rows"),
+                (vec![("facet", "maybe")], "facet"),
+                (vec![("degree_histogram", "x")], "degree_histogram"),
+                (vec![("min_notes", "64"), ("max_notes", "32")], "min_notes"),
+                (vec![("parsons", "*u")], "parsons"),
+            ] {
+                assert_eq!(
+                    parse_query(&params(&bad), capabilities)
+                        .unwrap_err()
+                        .parameter,
+                    Some(parameter.to_string()),
+                    "{:?} names the parameter.",
+                    bad
+                );
+            }
+
+            let err = parse_abc_query("GAB", &[], capabilities).unwrap_err();
+            assert_eq!(err.parameter, None, "Not down to a parameter.");
+        });
+    }
+
+    #[test]
+    fn parse_query_test() {
+        with_capabilities(true, |capabilities| {
+            let query = parse_query(
+                &params(&[
+                    ("title", "kesh"),
+                    ("rhythm", "jig"),
+                    ("unknown", "x"),
+                    ("rows", "5"),
+                    ("group", "cluster"),
+                    ("total", "off"),
+                    ("facet.limit", "-1"),
+                ]),
+                capabilities,
+            ).unwrap();
+            assert!(matches!(query.generator, Generator::Title(ref text) if text == "kesh"));
+            assert_eq!(
+                query.filter.features,
+                vec![("rhythm".to_string(), "jig".to_string())],
+                "Only known feature types are filters."
+            );
+            assert_eq!(query.selection.rows, 5);
+            assert!(query.selection.group && !query.selection.total);
+            assert_eq!(query.selection.facet_limit, None);
+
+            for bad in [
+                ("rows", "5000"),
+                ("rows", "x"),
+                ("facet", "maybe"),
+                ("facet.limit", "some"),
+                ("group", "title"),
+                ("degree_ngram", "1,9"),
+                ("normalization", "max"),
+                ("interval_ngram", "60,62,64"),
+                ("parsons", "*uuu"),
+            ].iter()
+            {
+                assert!(
+                    parse_query(&params(&[*bad, ("transform", "inversion")]), capabilities)
+                        .is_err(),
+                    "{:?} is an error.",
+                    bad
+                );
+            }
+        });
+
+        with_capabilities(false, |capabilities| {
+            let query = parse_query(&params(&[("group", "cluster")]), capabilities).unwrap();
+            assert!(
+                !query.selection.rollup && !query.selection.group,
+                "Disabled options are ignored even when asked for."
+            );
+        });
+    }
+
+    #[test]
+    fn to_params_test() {
+        with_capabilities(true, |capabilities| {
+            // Defaults and unknown params are left out, and filter values are normalised.
+            let query = parse_query(
+                &params(&[
+                    ("rows", "30"),
+                    ("title", "kesh"),
+                    ("metre", "2/2"),
+                    ("rhythm", "jig"),
+                    ("facet", "off"),
+                    ("max_range", "12"),
+                    ("callback", "f"),
+                ]),
+                capabilities,
+            ).unwrap();
+            assert_eq!(
+                query.to_params(),
+                params(&[
+                    ("facet", "false"),
+                    ("max_range", "12"),
+                    ("metre", "4/4"),
+                    ("rhythm", "jig"),
+                    ("title", "kesh"),
+                ])
+            );
+            let query = parse_query(&[], capabilities).unwrap();
+            assert!(query.to_params().is_empty());
+
+            // Case, spacing and repeated filters don't change the search, so they don't change the
+            // params.
+            let query = parse_query(
+                &params(&[
+                    ("title", " The  Kesh "),
+                    ("rhythm", "jig"),
+                    ("rhythm", "jig"),
+                ]),
+                capabilities,
+            ).unwrap();
+            assert_eq!(
+                query.to_params(),
+                params(&[("rhythm", "jig"), ("title", "the kesh")])
+            );
+
+            // The params read back as the same query.
+            for pairs in [
+                vec![("interval_ngram", "60,62,64,65"), ("intervals", "sounding")],
+                vec![("parsons", "uuddruuddr"), ("normalization", "tfidf")],
+                vec![("sample", "10"), ("seed", "7"), ("group", "cluster")],
+                vec![("offset", "60"), ("facet.limit", "-1"), ("total", "false")],
+            ].iter()
+            {
+                let canonical = parse_query(&params(pairs), capabilities)
+                    .unwrap()
+                    .to_params();
+                assert_eq!(canonical.len(), pairs.len());
+                let query = parse_query(&canonical, capabilities).unwrap();
+                assert_eq!(query.to_params(), canonical);
+            }
+
+            // Searches for similar tunes have different defaults.
+            let query = parse_similar_query(1, &[], capabilities).unwrap();
+            assert!(query.to_params().is_empty());
+            let query = parse_similar_query(1, &params(&[("cutoff", "0.5")]), capabilities);
+            assert_eq!(query.unwrap().to_params(), params(&[("cutoff", "0.5")]));
+        });
+    }
+
+    #[test]
+    fn parse_range_filter_test() {
+        with_capabilities(false, |capabilities| {
+            let query = parse_query(
+                &params(&[
+                    ("max_range", "12"),
+                    ("min_notes", "32"),
+                    ("max_notes", "100"),
+                    ("min_unknown", "3"),
+                    ("max_rhythm", "3"),
+                ]),
+                capabilities,
+            ).unwrap();
+            assert_eq!(
+                query.filter.ranges,
+                vec![
+                    NumericRange {
+                        feature_type: "notes".to_string(),
+                        min: Some(32.0),
+                        max: Some(100.0),
+                    },
+                    NumericRange {
+                        feature_type: "range".to_string(),
+                        min: None,
+                        max: Some(12.0),
+                    },
+                ],
+                "Only numeric feature types are ranges."
+            );
+            assert!(query.filter.has_filters());
+
+            for bad in [
+                vec![("max_range", "twelve")],
+                vec![("max_range", "inf")],
+                vec![("min_notes", "64"), ("max_notes", "32")],
+            ] {
+                assert!(
+                    parse_query(&params(&bad), capabilities).is_err(),
+                    "{:?} is rejected.",
+                    bad
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn parse_similar_query_test() {
+        with_capabilities(false, |capabilities| {
+            let query = parse_similar_query(12, &params(&[]), capabilities).unwrap();
+            assert!(matches!(query.generator, Generator::Similar(12, cutoff) if cutoff == 0.8));
+            assert_eq!(
+                query.normalization,
+                ScoreNormalization::Max,
+                "Defaults are as for clustering."
+            );
+
+            let query = parse_similar_query(
+                12,
+                &params(&[
+                    ("cutoff", "0.6"),
+                    ("normalization", "tfidf"),
+                    ("rhythm", "jig"),
+                ]),
+                capabilities,
+            ).unwrap();
+            assert!(matches!(query.generator, Generator::Similar(12, cutoff) if cutoff == 0.6));
+            assert_eq!(query.normalization, ScoreNormalization::TfIdf);
+            assert!(query.filter.has_filters());
+
+            for bad in ["1.5", "-0.1", "close"] {
+                assert!(
+                    parse_similar_query(12, &params(&[("cutoff", bad)]), capabilities).is_err(),
+                    "Cutoff {} is rejected.",
+                    bad
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn parse_pick_query_test() {
+        with_capabilities(false, |capabilities| {
+            let query = parse_pick_query(
+                &params(&[("rhythm", "jig"), ("title", "kesh"), ("rows", "20")]),
+                7,
+                capabilities,
+            ).unwrap();
+            assert!(matches!(query.generator, Generator::Sample(1, 7)));
+            assert_eq!(
+                query.filter.features,
+                vec![("rhythm".to_string(), "jig".to_string())]
+            );
+            assert_eq!(query.selection.rows, 1, "Selection params are ignored.");
+        });
+    }
+
+    #[test]
+    fn daily_seed_test() {
+        assert_eq!(daily_seed(20000), daily_seed(20000));
+        assert_ne!(daily_seed(20000), daily_seed(20001));
+        assert!(
+            (daily_seed(20000) ^ daily_seed(20001)).count_ones() > 8,
+            "Neighbouring days are well mixed."
+        );
+    }
+
+    #[test]
+    fn parse_abc_query_test() {
+        with_capabilities(true, |capabilities| {
+            let query =
+                parse_abc_query("GABc dedB|", &params(&[("rows", "5")]), capabilities).unwrap();
+            assert!(matches!(
+                query.generator,
+                Generator::IntervalNGram(ref pitches) if pitches.len() == 8
+            ));
+            assert_eq!(query.selection.rows, 5);
+
+            assert!(
+                parse_abc_query("GAB", &[], capabilities).is_err(),
+                "Too short for the interval window."
+            );
+
+            let sounding = params(&[("intervals", "sounding")]);
+            let query = parse_abc_query("K:D\nFGAB", &sounding, capabilities).unwrap();
+            assert!(matches!(
+                query.generator,
+                Generator::SoundingIntervalNGram(ref pitches) if pitches == &vec![66, 67, 69, 71]
+            ));
+
+            assert!(parse_abc_query(
+                "GABcd",
+                &params(&[("intervals", "sounding"), ("transform", "inversion")]),
+                capabilities
+            ).is_err());
+            assert!(
+                parse_abc_query("GABcd", &params(&[("intervals", "pitch")]), capabilities).is_err()
+            );
+        });
+    }
+}
@@ -0,0 +1,594 @@
+//! Raster images.
+//! A greyscale bitmap with just enough drawing operations to render an svg::Drawing, plus a
+//! built-in bitmap font for text and a PNG encoder. Antialiasing is done by drawing at a multiple
+//! of the final size and then shrinking.
+
+use unidecode::unidecode;
+
+const WHITE: u8 = 255;
+const BLACK: u8 = 0;
+
+// Width and height of each character in the font, in font pixels.
+const FONT_WIDTH: u32 = 5;
+const FONT_HEIGHT: u32 = 7;
+
+// Characters are spaced by one column.
+const FONT_ADVANCE: u32 = FONT_WIDTH + 1;
+
+// Classic 5x7 font for printable ASCII, from space to tilde.
+// Each character is five columns, left to right. The lowest bit of each column is the top row.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5F, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1C, 0x00],
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08],
+    [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3E, 0x51, 0x49, 0x45, 0x3E],
+    [0x00, 0x42, 0x7F, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4B, 0x31],
+    [0x18, 0x14, 0x12, 0x7F, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3C, 0x4A, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1E],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3E],
+    [0x7E, 0x11, 0x11, 0x11, 0x7E],
+    [0x7F, 0x49, 0x49, 0x49, 0x36],
+    [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x22, 0x1C],
+    [0x7F, 0x49, 0x49, 0x49, 0x41],
+    [0x7F, 0x09, 0x09, 0x09, 0x01],
+    [0x3E, 0x41, 0x49, 0x49, 0x7A],
+    [0x7F, 0x08, 0x08, 0x08, 0x7F],
+    [0x00, 0x41, 0x7F, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3F, 0x01],
+    [0x7F, 0x08, 0x14, 0x22, 0x41],
+    [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F],
+    [0x7F, 0x04, 0x08, 0x10, 0x7F],
+    [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06],
+    [0x3E, 0x41, 0x51, 0x21, 0x5E],
+    [0x7F, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7F, 0x01, 0x01],
+    [0x3F, 0x40, 0x40, 0x40, 0x3F],
+    [0x1F, 0x20, 0x40, 0x20, 0x1F],
+    [0x3F, 0x40, 0x38, 0x40, 0x3F],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7F, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7F, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00],
+    [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7F, 0x48, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7F],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x08, 0x7E, 0x09, 0x01, 0x02],
+    [0x0C, 0x52, 0x52, 0x52, 0x3E],
+    [0x7F, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7D, 0x40, 0x00],
+    [0x20, 0x40, 0x44, 0x3D, 0x00],
+    [0x7F, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7F, 0x40, 0x00],
+    [0x7C, 0x04, 0x18, 0x04, 0x78],
+    [0x7C, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7C, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7C],
+    [0x7C, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3F, 0x44, 0x40, 0x20],
+    [0x3C, 0x40, 0x40, 0x20, 0x7C],
+    [0x1C, 0x20, 0x40, 0x20, 0x1C],
+    [0x3C, 0x40, 0x30, 0x40, 0x3C],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0C, 0x50, 0x50, 0x50, 0x3C],
+    [0x44, 0x64, 0x54, 0x4C, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7F, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x02, 0x01, 0x02, 0x04, 0x02],
+];
+
+/// Greyscale image, white to start with.
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+
+    // Row by row, 0 is black.
+    pixels: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(width: u32, height: u32) -> Bitmap {
+        Bitmap {
+            width,
+            height,
+            pixels: vec![WHITE; (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    fn set(&mut self, x: i64, y: i64) {
+        if x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64 {
+            self.pixels[(y as u32 * self.width + x as u32) as usize] = BLACK;
+        }
+    }
+
+    // Range of whole pixels whose centres fall between these coordinates, clipped to the image.
+    fn pixel_range(from: f32, to: f32, limit: u32) -> (i64, i64) {
+        let start = (from.min(to) - 0.5).ceil().max(0.0) as i64;
+        let end = ((from.max(to) - 0.5).floor() as i64).min(limit as i64 - 1);
+        (start, end)
+    }
+
+    /// Fill a rectangle.
+    pub fn fill_rect(&mut self, x: f32, y: f32, w: f32, h: f32) {
+        let (x_start, x_end) = Bitmap::pixel_range(x, x + w, self.width);
+        let (y_start, y_end) = Bitmap::pixel_range(y, y + h, self.height);
+
+        for yy in y_start..y_end + 1 {
+            for xx in x_start..x_end + 1 {
+                self.set(xx, yy);
+            }
+        }
+    }
+
+    /// Outline a rectangle, with the stroke centred on the edges.
+    pub fn stroke_rect(&mut self, x: f32, y: f32, w: f32, h: f32, stroke: f32) {
+        let half = stroke / 2.0;
+        self.fill_rect(x - half, y - half, w + stroke, stroke);
+        self.fill_rect(x - half, y + h - half, w + stroke, stroke);
+        self.fill_rect(x - half, y - half, stroke, h + stroke);
+        self.fill_rect(x + w - half, y - half, stroke, h + stroke);
+    }
+
+    /// Draw a straight line of the given thickness.
+    pub fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, stroke: f32) {
+        let half = stroke / 2.0;
+        let (x_start, x_end) =
+            Bitmap::pixel_range(x1.min(x2) - half, x1.max(x2) + half, self.width);
+        let (y_start, y_end) =
+            Bitmap::pixel_range(y1.min(y2) - half, y1.max(y2) + half, self.height);
+
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let length_squared = dx * dx + dy * dy;
+
+        for yy in y_start..y_end + 1 {
+            for xx in x_start..x_end + 1 {
+                let (px, py) = (xx as f32 + 0.5, yy as f32 + 0.5);
+
+                // Distance from the pixel centre to the nearest point on the segment.
+                let t = if length_squared == 0.0 {
+                    0.0
+                } else {
+                    (((px - x1) * dx + (py - y1) * dy) / length_squared).clamp(0.0, 1.0)
+                };
+                let (nx, ny) = (x1 + t * dx - px, y1 + t * dy - py);
+
+                if nx * nx + ny * ny <= half * half {
+                    self.set(xx, yy);
+                }
+            }
+        }
+    }
+
+    /// Fill a polygon, using the even-odd rule.
+    pub fn fill_polygon(&mut self, points: &[(f32, f32)]) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let top = points.iter().map(|p| p.1).fold(f32::MAX, f32::min);
+        let bottom = points.iter().map(|p| p.1).fold(f32::MIN, f32::max);
+        let (y_start, y_end) = Bitmap::pixel_range(top, bottom, self.height);
+
+        for yy in y_start..y_end + 1 {
+            let py = yy as f32 + 0.5;
+
+            // Where each edge crosses the centre line of this row of pixels.
+            let mut crossings = vec![];
+            for i in 0..points.len() {
+                let (x1, y1) = points[i];
+                let (x2, y2) = points[(i + 1) % points.len()];
+                if (y1 <= py) != (y2 <= py) {
+                    crossings.push(x1 + (py - y1) * (x2 - x1) / (y2 - y1));
+                }
+            }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            for pair in crossings.chunks(2) {
+                if pair.len() == 2 {
+                    let (x_start, x_end) = Bitmap::pixel_range(pair[0], pair[1], self.width);
+                    for xx in x_start..x_end + 1 {
+                        self.set(xx, yy);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw a circle, either filled or as an outline with the stroke centred on the radius.
+    pub fn circle(&mut self, x: f32, y: f32, radius: f32, fill: bool, stroke: f32) {
+        let half = stroke / 2.0;
+        let outer = radius + half;
+        let inner = if fill { 0.0 } else { (radius - half).max(0.0) };
+
+        let (x_start, x_end) = Bitmap::pixel_range(x - outer, x + outer, self.width);
+        let (y_start, y_end) = Bitmap::pixel_range(y - outer, y + outer, self.height);
+
+        for yy in y_start..y_end + 1 {
+            for xx in x_start..x_end + 1 {
+                let (dx, dy) = (xx as f32 + 0.5 - x, yy as f32 + 0.5 - y);
+                let distance_squared = dx * dx + dy * dy;
+
+                if distance_squared <= outer * outer && distance_squared >= inner * inner {
+                    self.set(xx, yy);
+                }
+            }
+        }
+    }
+
+    /// Width of text drawn with the given font pixel size.
+    pub fn text_width(text: &str, size: f32) -> f32 {
+        let chars = unidecode(text).chars().count() as u32;
+        if chars == 0 {
+            0.0
+        } else {
+            (chars * FONT_ADVANCE - 1) as f32 * size
+        }
+    }
+
+    /// Draw text with its baseline at y. Each pixel of the font is `size` pixels square.
+    /// Non-ASCII characters are transliterated, e.g. "é" is drawn as "e".
+    pub fn text(&mut self, x: f32, y: f32, text: &str, size: f32) {
+        let top = y - FONT_HEIGHT as f32 * size;
+
+        for (i, chr) in unidecode(text).chars().enumerate() {
+            let columns = match chr as u32 {
+                32..=126 => FONT[chr as usize - 32],
+                _ => FONT['?' as usize - 32],
+            };
+
+            let left = x + (i as u32 * FONT_ADVANCE) as f32 * size;
+
+            for (column, bits) in columns.iter().enumerate() {
+                for row in 0..FONT_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        self.fill_rect(
+                            left + column as f32 * size,
+                            top + row as f32 * size,
+                            size,
+                            size,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shrink by a whole factor, averaging each square of pixels. Used for antialiasing.
+    pub fn shrink(&self, factor: u32) -> Bitmap {
+        let factor = factor.max(1);
+        let width = self.width / factor;
+        let height = self.height / factor;
+        let mut result = Bitmap::new(width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut total: u32 = 0;
+                for yy in 0..factor {
+                    for xx in 0..factor {
+                        total += self.get(x * factor + xx, y * factor + yy) as u32;
+                    }
+                }
+
+                result.pixels[(y * width + x) as usize] = (total / (factor * factor)) as u8;
+            }
+        }
+
+        result
+    }
+
+    /// Encode as an 8 bit greyscale PNG.
+    pub fn to_png(&self) -> Vec<u8> {
+        // Each row is preceded by its filter type, which is always 0 (none).
+        let mut scanlines = Vec::with_capacity(((self.width + 1) * self.height) as usize);
+        for row in self.pixels.chunks(self.width.max(1) as usize) {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+
+        let mut header = vec![];
+        header.extend_from_slice(&be_bytes(self.width));
+        header.extend_from_slice(&be_bytes(self.height));
+
+        // Bit depth 8, colour type 0 (greyscale), default compression, filtering and no interlace.
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &zlib(&scanlines));
+        write_chunk(&mut png, b"IEND", &[]);
+
+        png
+    }
+}
+
+fn be_bytes(value: u32) -> [u8; 4] {
+    [
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&be_bytes(data.len() as u32));
+
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&be_bytes(crc));
+}
+
+/// CRC-32 as used by PNG.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Adler-32 as used by zlib.
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b): (u32, u32) = (1, 0);
+    for byte in data.iter() {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+// Writes bits least significant first, as DEFLATE expects.
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: vec![],
+            current: 0,
+            num_bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, num_bits: u32) {
+        self.current |= value << self.num_bits;
+        self.num_bits += num_bits;
+
+        while self.num_bits >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    // Huffman codes are packed most significant bit first.
+    fn write_code(&mut self, code: u32, num_bits: u32) {
+        let mut reversed = 0;
+        for i in 0..num_bits {
+            reversed |= ((code >> i) & 1) << (num_bits - 1 - i);
+        }
+        self.write(reversed, num_bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.bytes.push(self.current as u8);
+        }
+        self.bytes
+    }
+}
+
+// Base length and number of extra bits for DEFLATE length codes 257 to 285.
+const LENGTH_BASES: [u32; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Write a symbol with the fixed Huffman code.
+fn write_symbol(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+// Write a back-reference to the previous byte, i.e. a run.
+fn write_run(writer: &mut BitWriter, length: u32) {
+    let code = LENGTH_BASES.iter().rposition(|base| *base <= length).unwrap();
+    write_symbol(writer, 257 + code as u32);
+    writer.write(length - LENGTH_BASES[code], LENGTH_EXTRA_BITS[code]);
+
+    // Distance code 0, i.e. one byte back, with no extra bits.
+    writer.write_code(0, 5);
+}
+
+/// Compress with zlib. Only runs of the same byte are compressed, which suits line drawings with
+/// large areas of white.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    // A single final block with fixed Huffman codes.
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        write_symbol(&mut writer, byte as u32);
+
+        let mut run = 0;
+        while i + 1 + run < data.len() && data[i + 1 + run] == byte {
+            run += 1;
+        }
+        i += 1;
+
+        // Repeat the byte just written as many times as needed.
+        let mut remaining = run;
+        while remaining >= 3 {
+            let length = remaining.min(258);
+
+            // Don't leave a remainder too short for another back-reference.
+            let length = if remaining - length > 0 && remaining - length < 3 {
+                length - 3
+            } else {
+                length
+            };
+
+            write_run(&mut writer, length as u32);
+            remaining -= length;
+            i += length;
+        }
+    }
+
+    write_symbol(&mut writer, 256);
+
+    // Compression method 8 (deflate) with a 32K window, and no preset dictionary.
+    let mut result = vec![0x78, 0x01];
+    result.extend(writer.finish());
+    result.extend_from_slice(&be_bytes(adler32(data)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_test() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn drawing_test() {
+        let mut bitmap = Bitmap::new(10, 10);
+        bitmap.fill_rect(2.0, 2.0, 3.0, 3.0);
+
+        assert_eq!(bitmap.get(1, 1), WHITE);
+        assert_eq!(bitmap.get(2, 2), BLACK);
+        assert_eq!(bitmap.get(4, 4), BLACK);
+        assert_eq!(bitmap.get(5, 5), WHITE);
+
+        // Drawing outside the image is clipped.
+        bitmap.fill_rect(-5.0, 8.0, 20.0, 20.0);
+        assert_eq!(bitmap.get(9, 9), BLACK);
+
+        let shrunk = bitmap.shrink(2);
+        assert_eq!(shrunk.width, 5);
+        assert_eq!(shrunk.get(1, 1), BLACK);
+        assert_eq!(shrunk.get(2, 2), 191);
+    }
+
+    #[test]
+    fn text_test() {
+        assert_eq!(Bitmap::text_width("", 1.0), 0.0);
+        assert_eq!(Bitmap::text_width("Hi", 2.0), 22.0);
+        assert_eq!(
+            Bitmap::text_width("Cé", 1.0),
+            Bitmap::text_width("Ce", 1.0),
+            "Accented characters are transliterated."
+        );
+
+        let mut bitmap = Bitmap::new(6, 8);
+        bitmap.text(0.0, 7.0, "|", 1.0);
+
+        // The bar is the middle column.
+        assert_eq!(bitmap.get(2, 0), BLACK);
+        assert_eq!(bitmap.get(2, 6), BLACK);
+        assert_eq!(bitmap.get(0, 0), WHITE);
+    }
+
+    #[test]
+    fn fill_polygon_test() {
+        // A parallelogram, like a beam.
+        let mut bitmap = Bitmap::new(20, 20);
+        bitmap.fill_polygon(&[(0.0, 10.0), (20.0, 0.0), (20.0, 5.0), (0.0, 15.0)]);
+
+        assert_eq!(bitmap.get(1, 12), BLACK);
+        assert_eq!(bitmap.get(18, 2), BLACK);
+        assert_eq!(bitmap.get(1, 2), WHITE);
+        assert_eq!(bitmap.get(18, 12), WHITE);
+
+        // Too few points to fill anything.
+        let mut bitmap = Bitmap::new(5, 5);
+        bitmap.fill_polygon(&[(0.0, 0.0), (5.0, 5.0)]);
+        assert!(bitmap.pixels.iter().all(|x| *x == WHITE));
+    }
+
+    #[test]
+    fn png_test() {
+        let mut bitmap = Bitmap::new(300, 2);
+        bitmap.fill_rect(0.0, 1.0, 100.0, 1.0);
+        let png = bitmap.to_png();
+
+        assert_eq!(&png[0..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &be_bytes(300));
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // Runs of white and black are compressed.
+        assert!(png.len() < 100);
+    }
+}
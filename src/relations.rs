@@ -1,13 +1,12 @@
-use std::usize;
-
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::hash::Hash;
 
 extern crate glob;
 extern crate time;
 
+use std::io;
 use std::io::Write;
 
 use std::fs::File;
@@ -17,12 +16,105 @@ use std::path::PathBuf;
 
 use std::io::{BufReader, BufWriter};
 
-use search::ResultSet;
+use features;
+use pitch;
+use search::{Deadline, ResultSet};
 use text;
 
 // Provide at least this much overhead when reallocating.
 pub const GROWTH_OVERHEAD: usize = 1024;
 
+// Check the deadline once every this many documents or tunes while scoring, as looking at the
+// clock costs more than scoring one.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+// Marks a clusters file that starts with a parameters header.
+// Files written before the header was introduced are a bare sequence of group IDs.
+// "FTFCLST1" as little-endian bytes.
+const CLUSTERS_MAGIC: usize = 0x3154_534C_4346_5446;
+
+// Marks a clusters file whose header also records the tunecache generation it was built from.
+// "FTFCLST2" as little-endian bytes.
+const CLUSTERS_GENERATION_MAGIC: usize = 0x3254_534C_4346_5446;
+
+// Written in place of each of the params in a generation header when they aren't known.
+const NO_PARAM: usize = usize::MAX;
+
+// Read a little-endian u64 from the reader, or None at end of file.
+fn read_u64(reader: &mut BufReader<File>, buf: &mut [u8]) -> Option<usize> {
+    match reader.read_exact(buf) {
+        Err(_) => None,
+        _ => Some(
+            (buf[0] as usize)
+                | (buf[1] as usize) << 8
+                | (buf[2] as usize) << 16
+                | (buf[3] as usize) << 24
+                | (buf[4] as usize) << 32
+                | (buf[5] as usize) << 40
+                | (buf[6] as usize) << 48
+                | (buf[7] as usize) << 56,
+        ),
+    }
+}
+
+// Write a little-endian u64.
+fn write_u64(writer: &mut BufWriter<File>, buf: &mut [u8], value: usize) -> io::Result<()> {
+    buf[0] = (value & 0x00000000000000FF) as u8;
+    buf[1] = ((value & 0x000000000000FF00) >> 8) as u8;
+    buf[2] = ((value & 0x0000000000FF0000) >> 16) as u8;
+    buf[3] = ((value & 0x00000000FF000000) >> 24) as u8;
+    buf[4] = ((value & 0x000000FF00000000) >> 32) as u8;
+    buf[5] = ((value & 0x0000FF0000000000) >> 40) as u8;
+    buf[6] = ((value & 0x00FF000000000000) >> 48) as u8;
+    buf[7] = ((value & 0xFF00000000000000) >> 56) as u8;
+
+    writer.write_all(buf)
+}
+
+// Read the ClusterParams from a clusters file header.
+fn read_params(reader: &mut BufReader<File>, buf: &mut [u8]) -> Option<ClusterParams> {
+    let cutoff = read_u64(reader, buf);
+    let normalization = read_u64(reader, buf);
+    let window_size = read_u64(reader, buf);
+
+    match (cutoff, normalization, window_size) {
+        (Some(NO_PARAM), Some(NO_PARAM), Some(NO_PARAM)) => None,
+        (Some(cutoff), Some(normalization), Some(window_size)) => Some(ClusterParams {
+            cutoff: f64::from_bits(cutoff as u64) as f32,
+            normalization: ScoreNormalization::from_code(normalization)
+                .unwrap_or(ScoreNormalization::Max),
+            window_size,
+        }),
+        _ => {
+            warn!("Clusters file header is truncated.");
+            None
+        }
+    }
+}
+
+// The settings that were used to produce a set of Clusters.
+// These are stored in the clusters file so we can tell how the groups were made.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClusterParams {
+    // Minimum similarity score for two tunes to be joined.
+    pub cutoff: f32,
+
+    pub normalization: ScoreNormalization,
+
+    // Width of the interval window used for melody terms.
+    pub window_size: usize,
+}
+
+impl Default for ClusterParams {
+    fn default() -> ClusterParams {
+        ClusterParams {
+            cutoff: 0.8,
+            normalization: ScoreNormalization::Max,
+            window_size: DEFAULT_INTERVAL_WINDOW_SIZE,
+        }
+    }
+}
+
 // Represent groupings of tunes.
 // Tune ID usize::MAX isn't allowed.
 // If we get over 4 billion tunes, it may be time to consider an Option types.
@@ -35,6 +127,20 @@ pub struct Clusters {
     // - MAX  : Unassigned.
     // - Else : The tune belongs to this group ID.
     groups: Vec<usize>,
+
+    // Settings that produced these groups, if known.
+    // Legacy clusters files don't record this.
+    pub params: Option<ClusterParams>,
+
+    // Generation of the tunecache these groups were built from, see `storage::generation_hash`.
+    // Files written before this was recorded can't be checked.
+    pub generation: Option<u64>,
+}
+
+impl Default for Clusters {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clusters {
@@ -44,7 +150,11 @@ impl Clusters {
         let mut groups = Vec::with_capacity(GROWTH_OVERHEAD);
         groups.resize(GROWTH_OVERHEAD, usize::MAX);
 
-        Clusters { groups }
+        Clusters {
+            groups,
+            params: None,
+            generation: None,
+        }
     }
 
     pub fn with_max_id(id: usize) -> Clusters {
@@ -55,56 +165,86 @@ impl Clusters {
 
     pub fn load(filename: &PathBuf) -> Clusters {
         let mut groups = Vec::with_capacity(GROWTH_OVERHEAD);
+        let mut params = None;
+        let mut generation = None;
 
         if let Ok(f) = File::open(filename) {
             let mut reader = BufReader::new(f);
             let mut buf = vec![0u8; 8];
-            loop {
-                match reader.read_exact(&mut buf) {
-                    // End of file is ok here.
-                    Err(_) => break,
-                    _ => (),
-                }
 
-                let value: usize = (buf[0] as usize)
-                    | (buf[1] as usize) << 8
-                    | (buf[2] as usize) << 16
-                    | (buf[3] as usize) << 24
-                    | (buf[4] as usize) << 32
-                    | (buf[5] as usize) << 40
-                    | (buf[6] as usize) << 48
-                    | (buf[7] as usize) << 56;
+            // The first value is either a header marker or, for legacy files, the first group.
+            match read_u64(&mut reader, &mut buf) {
+                Some(CLUSTERS_MAGIC) => params = read_params(&mut reader, &mut buf),
+                Some(CLUSTERS_GENERATION_MAGIC) => {
+                    params = read_params(&mut reader, &mut buf);
+                    generation = read_u64(&mut reader, &mut buf).map(|x| x as u64);
+                }
+                Some(value) => groups.push(value),
+                None => (),
+            }
 
+            while let Some(value) = read_u64(&mut reader, &mut buf) {
                 groups.push(value);
             }
         } else {
-            eprintln!("No pre-existing tune cache file found, starting from scratch.");
+            info!("No pre-existing clusters file found, starting from scratch.");
         }
 
-        Clusters { groups }
+        Clusters {
+            groups,
+            params,
+            generation,
+        }
+    }
+
+    pub fn save(&self, filename: &PathBuf) -> Result<(), String> {
+        self.write(filename)
+            .map_err(|err| format!("Can't write clusters file {}: {}", filename.display(), err))
     }
 
-    pub fn save(&self, filename: &PathBuf) {
-        let f = File::create(filename).expect("Can't open!");
+    fn write(&self, filename: &PathBuf) -> io::Result<()> {
+        let f = File::create(filename)?;
         let mut writer = BufWriter::new(f);
 
         let mut buf = vec![0u8; 8];
 
-        for value in self.groups.iter() {
-            let value = *value;
-            // let length = buf.len();
+        // Only write a header if we know the params or the generation, otherwise stay compatible
+        // with old readers.
+        let magic = match (self.params, self.generation) {
+            (_, Some(_)) => Some(CLUSTERS_GENERATION_MAGIC),
+            (Some(_), None) => Some(CLUSTERS_MAGIC),
+            (None, None) => None,
+        };
+
+        if let Some(magic) = magic {
+            write_u64(&mut writer, &mut buf, magic)?;
+            match self.params {
+                Some(params) => {
+                    write_u64(
+                        &mut writer,
+                        &mut buf,
+                        (params.cutoff as f64).to_bits() as usize,
+                    )?;
+                    write_u64(&mut writer, &mut buf, params.normalization.code())?;
+                    write_u64(&mut writer, &mut buf, params.window_size)?;
+                }
+                None => {
+                    for _ in 0..3 {
+                        write_u64(&mut writer, &mut buf, NO_PARAM)?;
+                    }
+                }
+            }
 
-            buf[0] = ((value & 0x00000000000000FF) >> 0) as u8;
-            buf[1] = ((value & 0x000000000000FF00) >> 8) as u8;
-            buf[2] = ((value & 0x0000000000FF0000) >> 16) as u8;
-            buf[3] = ((value & 0x00000000FF000000) >> 24) as u8;
-            buf[4] = ((value & 0x000000FF00000000) >> 32) as u8;
-            buf[5] = ((value & 0x0000FF0000000000) >> 40) as u8;
-            buf[6] = ((value & 0x00FF000000000000) >> 48) as u8;
-            buf[7] = ((value & 0xFF00000000000000) >> 52) as u8;
+            if let Some(generation) = self.generation {
+                write_u64(&mut writer, &mut buf, generation as usize)?;
+            }
+        }
 
-            writer.write_all(&buf).expect("Can't write");
+        for value in self.groups.iter() {
+            write_u64(&mut writer, &mut buf, *value)?;
         }
+
+        writer.flush()
     }
 
     // Merge this group by the content of the other.
@@ -248,21 +388,157 @@ impl Clusters {
         result
     }
 
+    // Make sure every ID up to and including this one is represented.
+    pub fn ensure_max_id(&mut self, id: usize) {
+        if id + 1 > self.groups.len() {
+            self.groups.resize(id + 1, usize::MAX);
+        }
+    }
+
+    // All IDs up to and including max_id that aren't assigned to a group.
+    pub fn ungrouped_ids(&self, max_id: usize) -> Vec<u32> {
+        let mut result = vec![];
+
+        let mut next = match self.get(0) {
+            None => Some(0),
+            Some(_) => self.next_ungrouped_after(0),
+        };
+
+        while let Some(id) = next {
+            if id > max_id {
+                break;
+            }
+
+            result.push(id as u32);
+            next = self.next_ungrouped_after(id as u32);
+        }
+
+        result
+    }
+
     // Find the next tune after this ID that isn't assigned to a group.
     // This relies on having been constructed with a max tune id so it knows about all the potential IDs.
     pub fn next_ungrouped_after(&self, a: u32) -> Option<usize> {
-        for i in (a + 1) as usize..self.groups.len() {
-            if self.groups[i] == usize::MAX {
-                return Some(i);
+        ((a + 1) as usize..self.groups.len()).find(|&i| self.groups[i] == usize::MAX)
+    }
+
+    // Compare against hand-labelled pairs of duplicates. The labelled tunes are taken to be
+    // duplicates of everything they're linked to by the pairs, directly or not, and no others.
+    // Every pair of labelled tunes is then judged on whether the clusters put them together.
+    pub fn evaluate(&self, duplicates: &[(usize, usize)]) -> ClusterEvaluation {
+        let mut truth = Clusters::new();
+        let mut labelled: HashSet<usize> = HashSet::new();
+        for &(a, b) in duplicates.iter() {
+            truth.add(a, b);
+            labelled.insert(a);
+            labelled.insert(b);
+        }
+
+        let mut labelled: Vec<usize> = labelled.into_iter().collect();
+        labelled.sort();
+
+        let mut evaluation = ClusterEvaluation {
+            true_positives: 0,
+            false_positives: 0,
+            false_negatives: 0,
+        };
+        for (i, a) in labelled.iter().enumerate() {
+            for b in labelled[i + 1..].iter() {
+                let expected = truth.get(*a).is_some() && truth.get(*a) == truth.get(*b);
+                let actual = self.get(*a).is_some() && self.get(*a) == self.get(*b);
+                match (expected, actual) {
+                    (true, true) => evaluation.true_positives += 1,
+                    (false, true) => evaluation.false_positives += 1,
+                    (true, false) => evaluation.false_negatives += 1,
+                    (false, false) => (),
+                }
             }
         }
 
-        None
+        evaluation
     }
 }
 
+// Counts of pairs of tunes put together, or not, compared with hand-labelled duplicates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterEvaluation {
+    // Duplicates put in the same group.
+    pub true_positives: usize,
+
+    // Tunes put in the same group that aren't duplicates.
+    pub false_positives: usize,
+
+    // Duplicates not put in the same group.
+    pub false_negatives: usize,
+}
+
+impl ClusterEvaluation {
+    // Proportion of pairs put together that are duplicates. 1 if none were put together.
+    pub fn precision(&self) -> f32 {
+        let found = self.true_positives + self.false_positives;
+        if found == 0 {
+            1.0
+        } else {
+            self.true_positives as f32 / found as f32
+        }
+    }
+
+    // Proportion of duplicates that were put together. 1 if there are none.
+    pub fn recall(&self) -> f32 {
+        let expected = self.true_positives + self.false_negatives;
+        if expected == 0 {
+            1.0
+        } else {
+            self.true_positives as f32 / expected as f32
+        }
+    }
+
+    // Harmonic mean of precision and recall.
+    pub fn f1(&self) -> f32 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        }
+    }
+}
+
+// Read hand-labelled duplicates, one pair of tune IDs per line separated by whitespace or a comma,
+// e.g. "123 456". Blank lines and lines starting with '#' are ignored.
+pub fn parse_duplicate_pairs(text: &str) -> Result<Vec<(usize, usize)>, String> {
+    let mut result = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let ids = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>();
+
+        match ids {
+            Ok(ref ids) if ids.len() == 2 && ids[0] != usize::MAX && ids[1] != usize::MAX => {
+                result.push((ids[0], ids[1]))
+            }
+            _ => {
+                return Err(format!(
+                    "Line {}: expected two tune IDs, got '{}'",
+                    i + 1,
+                    line
+                ))
+            }
+        }
+    }
+
+    Ok(result)
+}
+
 // Defines how score normalization for similarity should be done when comparing two documents.
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ScoreNormalization {
     // Score is normalized to the length of the 'A' document.
     // Good when 'a' is a short search term.
@@ -271,12 +547,47 @@ pub enum ScoreNormalization {
     // Score is normalized to the maximum of the two document lengths.
     // Good when 'a' is a whole tune and we're looking for doc similarity.
     Max,
+
+    // Like DocA, but each term is weighted by its inverse document frequency, so common terms such
+    // as a run of steps count for less than rare ones. Terms are binary, so the term frequency is
+    // always 1. Uses exact term lists, so it's slower.
+    TfIdf,
 }
 
 impl ScoreNormalization {
+    pub fn from_string(value: &str) -> Option<ScoreNormalization> {
+        match value.to_lowercase().as_ref() {
+            "doca" => Some(ScoreNormalization::DocA),
+            "max" => Some(ScoreNormalization::Max),
+            "tfidf" => Some(ScoreNormalization::TfIdf),
+            _ => None,
+        }
+    }
+
+    // Numerical code for storing in binary files.
+    fn code(&self) -> usize {
+        match self {
+            ScoreNormalization::DocA => 0,
+            ScoreNormalization::Max => 1,
+            ScoreNormalization::TfIdf => 2,
+        }
+    }
+
+    fn from_code(code: usize) -> Option<ScoreNormalization> {
+        match code {
+            0 => Some(ScoreNormalization::DocA),
+            1 => Some(ScoreNormalization::Max),
+            2 => Some(ScoreNormalization::TfIdf),
+            _ => None,
+        }
+    }
+
+    // Score from counts of terms. TF-IDF needs the terms themselves, so this is the same as DocA.
     pub fn score(&self, num_intersecting_bits: u32, a_bitcount: u32, b_bitcount: u32) -> f32 {
         match self {
-            ScoreNormalization::DocA => (num_intersecting_bits as f32) / (a_bitcount as f32),
+            ScoreNormalization::DocA | ScoreNormalization::TfIdf => {
+                (num_intersecting_bits as f32) / (a_bitcount as f32)
+            }
             ScoreNormalization::Max => {
                 (num_intersecting_bits as f32) / (u32::max(a_bitcount, b_bitcount) as f32)
             }
@@ -284,6 +595,42 @@ impl ScoreNormalization {
     }
 }
 
+impl fmt::Display for ScoreNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ScoreNormalization::DocA => "doca",
+            ScoreNormalization::Max => "max",
+            ScoreNormalization::TfIdf => "tfidf",
+        })
+    }
+}
+
+// Words of each document's bit vector are compared this many at a time, with a separate count for
+// each, so that the compiler can vectorize the loop. Bit vectors are padded to a multiple of this.
+const LANES: usize = 4;
+
+// Number of bits set in both, LANES words at a time.
+fn intersecting_bits(a: &[u64], b: &[u64]) -> u32 {
+    let mut counts = [0u32; LANES];
+    for (a, b) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)) {
+        for lane in 0..LANES {
+            counts[lane] += (a[lane] & b[lane]).count_ones();
+        }
+    }
+    counts.iter().sum()
+}
+
+// Words of a query's bit vector that have any bits set, with their positions.
+// A short query only has a few, so it's quicker to look at only those words of each document.
+fn nonzero_words(words: &[u64]) -> Vec<(usize, u64)> {
+    words
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| **word != 0)
+        .map(|(i, word)| (i, *word))
+        .collect()
+}
+
 // Binary Vector Space Model, with parameterized term type.
 // Allocated with static size, with each document's term vector represented as
 // a bitfield as an array of 64-bit words. The size of the bitfield is static,
@@ -291,6 +638,8 @@ impl ScoreNormalization {
 // are taken as part of the rough-and-tumble, so it's not possible to say exactly which
 // terms are in a given document after the fact.
 // Lookups are done by a linear scan over each document, with bitwise intersection and popcount.
+// Exact lookups, where collisions would give wrong answers, use an inverted index of each term's
+// tunes instead. Most terms are rare, so these lists are short.
 pub struct BinaryVSM<K> {
     // Map of term to term id. This simply increments for each new term found.
     terms: HashMap<K, usize>,
@@ -304,8 +653,19 @@ pub struct BinaryVSM<K> {
     // Indexed 2d array as (tune_id * word_capacity) + term_bit
     docs_terms: Vec<u64>,
 
-    // Map of tune id -> list of term IDs found.
-    pub docs_terms_exact: Vec<HashSet<usize>>,
+    // Number of bits set in each document's bit vector, so that scans only count the intersection.
+    docs_bitcounts: Vec<u32>,
+
+    // Map of tune id -> sorted list of term IDs found.
+    // A sorted Vec is a fraction of the size of a HashSet, and intersections are cheap.
+    pub docs_terms_exact: Vec<Vec<u32>>,
+
+    // Map of term ID -> sorted list of tune IDs it's found in.
+    // The length of each is the term's document frequency.
+    postings: Vec<Vec<u32>>,
+
+    // Number of tunes with any terms.
+    num_docs: usize,
 
     // Top tune id
     top_id: usize,
@@ -320,24 +680,28 @@ where
     K: Eq + Hash + Clone + Debug + Ord,
 {
     pub fn new(bit_capacity: usize, top_id: usize) -> BinaryVSM<K> {
-        let word_capacity = bit_capacity / 64 + 1;
-        eprintln!(
+        let word_capacity = (bit_capacity / 64 / LANES + 1) * LANES;
+        debug!(
             "New BinaryVSM bits: {} words: {} top tune id: {}",
             bit_capacity, word_capacity, top_id
         );
 
         let table = vec![0x0; word_capacity * (top_id + 1)];
-        let exact = vec![HashSet::new(); top_id + 1];
+        let bitcounts = vec![0; top_id + 1];
+        let exact = vec![vec![]; top_id + 1];
 
         BinaryVSM {
             terms: HashMap::new(),
             terms_i: HashMap::new(),
             docs_terms: table,
+            docs_bitcounts: bitcounts,
             next_term_id: 0,
-            word_capacity: word_capacity,
-            bit_capacity: bit_capacity,
+            word_capacity,
+            bit_capacity,
             docs_terms_exact: exact,
-            top_id: top_id,
+            postings: vec![],
+            num_docs: 0,
+            top_id,
         }
     }
 
@@ -365,19 +729,130 @@ where
 
     // TODO could somehow merge add and search_by_terms.
     pub fn add(&mut self, tune_id: usize, term: K) {
+        self.add_terms(tune_id, vec![term]);
+    }
+
+    // Add a tune's terms all at once. They're appended to its list of terms, which is then sorted
+    // once, rather than each being inserted in order.
+    pub fn add_terms(&mut self, tune_id: usize, terms: Vec<K>) {
+        if tune_id > self.top_id {
+            return;
+        }
+
+        let mut new_term_ids = Vec::with_capacity(terms.len());
+        for term in terms {
+            let term_id = self.get_term_id(term);
+
+            // Wrap round to fit in the table.
+            let bit_i = term_id % self.bit_capacity;
+            let (word_offset, bit_offset) = self.get_word_bit(bit_i);
+            let word = &mut self.docs_terms[tune_id * self.word_capacity + word_offset];
+            if *word & (1 << bit_offset) == 0 {
+                *word |= 1 << bit_offset;
+                self.docs_bitcounts[tune_id] += 1;
+            }
+
+            new_term_ids.push(term_id as u32);
+        }
+        new_term_ids.sort_unstable();
+        new_term_ids.dedup();
+
+        let term_ids = &mut self.docs_terms_exact[tune_id];
+        new_term_ids.retain(|term_id| term_ids.binary_search(term_id).is_err());
+        if new_term_ids.is_empty() {
+            return;
+        }
+        if term_ids.is_empty() {
+            self.num_docs += 1;
+        }
+        term_ids.extend_from_slice(&new_term_ids);
+        term_ids.sort_unstable();
+
+        if let Some(max_term_id) = new_term_ids.last() {
+            if self.postings.len() <= *max_term_id as usize {
+                self.postings.resize(*max_term_id as usize + 1, vec![]);
+            }
+        }
+        for term_id in new_term_ids {
+            // Tunes are usually indexed in ID order, so this is usually an append. Only a tune
+            // indexed again has to be inserted.
+            let tune_ids = &mut self.postings[term_id as usize];
+            match tune_ids.last() {
+                Some(last) if *last > tune_id as u32 => {
+                    if let Err(position) = tune_ids.binary_search(&(tune_id as u32)) {
+                        tune_ids.insert(position, tune_id as u32);
+                    }
+                }
+                _ => tune_ids.push(tune_id as u32),
+            }
+        }
+    }
+
+    // Make room for tunes up to this ID, e.g. for a tune added after indexing.
+    pub fn grow(&mut self, top_id: usize) {
+        if top_id <= self.top_id {
+            return;
+        }
+
+        self.docs_terms.resize(self.word_capacity * (top_id + 1), 0x0);
+        self.docs_bitcounts.resize(top_id + 1, 0);
+        self.docs_terms_exact.resize(top_id + 1, vec![]);
+        self.top_id = top_id;
+    }
+
+    // Remove all of a tune's terms, e.g. before indexing a new version of it.
+    // The terms themselves are kept, even if no other tune has them.
+    pub fn remove(&mut self, tune_id: usize) {
         if tune_id > self.top_id {
             return;
         }
 
-        // Squirrel away a copy of this in the lookup table.
-        // This is so we can use things like Strings, which can't be simply copied.
-        let term_id = self.get_term_id(term.clone().to_owned());
+        let start = tune_id * self.word_capacity;
+        for word in self.docs_terms[start..start + self.word_capacity].iter_mut() {
+            *word = 0x0;
+        }
+        self.docs_bitcounts[tune_id] = 0;
+
+        if !self.docs_terms_exact[tune_id].is_empty() {
+            self.num_docs -= 1;
+        }
+        for term_id in self.docs_terms_exact[tune_id].iter() {
+            let tune_ids = &mut self.postings[*term_id as usize];
+            if let Ok(position) = tune_ids.binary_search(&(tune_id as u32)) {
+                tune_ids.remove(position);
+            }
+        }
+        self.docs_terms_exact[tune_id].clear();
+    }
 
-        // Wrap round to fit in the table.
-        let bit_i = term_id % self.bit_capacity;
-        let (word_offset, bit_offset) = self.get_word_bit(bit_i);
-        self.docs_terms[tune_id * self.word_capacity + word_offset] |= 1 << bit_offset;
-        self.docs_terms_exact[tune_id].insert(term_id);
+    // Inverse document frequency of the term, smoothed so it's always above zero.
+    pub fn idf(&self, term_id: usize) -> f32 {
+        let doc_frequency = self
+            .postings
+            .get(term_id)
+            .map_or(0, |tune_ids| tune_ids.len());
+        ((self.num_docs as f32 + 1.0) / (doc_frequency as f32 + 1.0)).ln() + 1.0
+    }
+
+    // Release spare capacity once indexing is finished.
+    pub fn shrink_to_fit(&mut self) {
+        for term_ids in self.docs_terms_exact.iter_mut() {
+            term_ids.shrink_to_fit();
+        }
+        for tune_ids in self.postings.iter_mut() {
+            tune_ids.shrink_to_fit();
+        }
+    }
+
+    // Approximate heap memory used by the exact term lists and the inverted index, in bytes.
+    pub fn exact_heap_bytes(&self) -> usize {
+        let lists_bytes = |lists: &Vec<Vec<u32>>| {
+            lists.capacity() * std::mem::size_of::<Vec<u32>>() + lists
+                .iter()
+                .map(|list| list.capacity() * std::mem::size_of::<u32>())
+                .sum::<usize>()
+        };
+        lists_bytes(&self.docs_terms_exact) + lists_bytes(&self.postings)
     }
 
     // TODO can terms be a ref?
@@ -388,65 +863,254 @@ where
         exact: bool,
         normalization: ScoreNormalization,
     ) -> ResultSet {
-        eprintln!("Search by terms: {:?}", terms);
+        self.search_by_terms_limited(terms, cutoff, exact, normalization, None, Deadline::none())
+    }
+
+    // Search by terms, keeping only the best `limit` results if there's a limit.
+    // This is quicker, as documents that can't make it into the results aren't scored.
+    // If the deadline passes, stop scoring and return what's been found so far.
+    pub fn search_by_terms_limited(
+        &self,
+        terms: &Vec<K>,
+        cutoff: f32,
+        exact: bool,
+        normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        debug!("Search by terms: {:?}", terms);
 
         // Set of term IDs as a bit vector.
         let mut words = vec![0; self.word_capacity];
 
-        // Set of term IDs as a set.
-        let mut terms_set: HashSet<usize> = HashSet::new();
+        // Sorted term IDs.
+        let mut term_ids: Vec<u32> = vec![];
 
         // Set bits for terms.
         for term in terms.iter() {
             // If the term doesn't exist, just ignore.
-            if let Some(term_id) = self.terms.get(&term) {
+            if let Some(term_id) = self.terms.get(term) {
                 let bit_i = term_id % self.bit_capacity;
                 let (word_offset, bit_offset) = self.get_word_bit(bit_i);
                 words[word_offset] |= 1 << bit_offset;
 
-                if exact {
-                    terms_set.insert(*term_id);
+                if exact || normalization == ScoreNormalization::TfIdf {
+                    term_ids.push(*term_id as u32);
                 }
             }
         }
 
+        term_ids.sort();
+        term_ids.dedup();
+
+        if normalization == ScoreNormalization::TfIdf {
+            return self.search_by_term_ids_tf_idf(&words, &term_ids, cutoff, limit, deadline);
+        }
+
         self.search_by_bitfield_words(
             &words,
             cutoff,
-            if exact { Some(terms_set) } else { None },
+            if exact { Some(term_ids) } else { None },
             normalization,
+            limit,
+            deadline,
         )
     }
 
+    // Search by terms using the inverted index, so the results are exact, with no collisions.
+    // Only the tunes that have one of the terms are visited, which is quicker than a scan when
+    // the terms are rare, as most titles and features are.
+    // If the deadline passes, stop and return what's been found so far.
+    pub fn search_by_postings(
+        &self,
+        terms: &[K],
+        cutoff: f32,
+        normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        debug!("Search by postings: {:?}", terms);
+
+        let mut term_ids: Vec<usize> = terms
+            .iter()
+            .filter_map(|term| self.terms.get(term).cloned())
+            .collect();
+        term_ids.sort();
+        term_ids.dedup();
+
+        // Weight of each term, which is only not 1 for TF-IDF.
+        let weights: Vec<f32> = term_ids
+            .iter()
+            .map(|term_id| match normalization {
+                ScoreNormalization::TfIdf => self.idf(*term_id),
+                _ => 1.0,
+            }).collect();
+        let total_weight: f32 = weights.iter().sum();
+
+        // Tune id -> (number of terms, weight of terms).
+        let mut matches: HashMap<u32, (u32, f32)> = HashMap::new();
+        for (term_id, weight) in term_ids.iter().zip(weights.iter()) {
+            if deadline.has_passed() {
+                break;
+            }
+
+            if let Some(tune_ids) = self.postings.get(*term_id) {
+                for tune_id in tune_ids.iter() {
+                    let entry = matches.entry(*tune_id).or_insert((0, 0.0));
+                    entry.0 += 1;
+                    entry.1 += weight;
+                }
+            }
+        }
+
+        // Add in ID order, so that ties are broken the same way as a scan.
+        let mut tune_ids: Vec<u32> = matches.keys().cloned().collect();
+        tune_ids.sort();
+
+        let mut results = ResultSet::with_limit(limit);
+        for (i, tune_id) in tune_ids.into_iter().enumerate() {
+            if i % DEADLINE_CHECK_INTERVAL == 0 && deadline.has_passed() {
+                break;
+            }
+
+            let (count, weight) = matches[&tune_id];
+            let score = match normalization {
+                ScoreNormalization::TfIdf => weight / total_weight,
+                _ => normalization.score(
+                    count,
+                    term_ids.len() as u32,
+                    self.docs_terms_exact[tune_id as usize].len() as u32,
+                ),
+            };
+
+            if score >= cutoff {
+                results.add(tune_id as usize, score);
+            }
+        }
+
+        results
+    }
+
+    // Search by a bit vector and sorted list of term IDs, with terms weighted by their inverse
+    // document frequency. The score is the weight of the terms in both documents as a proportion
+    // of the weight of the query's terms.
+    fn search_by_term_ids_tf_idf(
+        &self,
+        a_words: &[u64],
+        a_term_ids: &[u32],
+        cutoff: f32,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        let mut results = ResultSet::with_limit(limit);
+
+        let weights: Vec<f32> = a_term_ids.iter().map(|x| self.idf(*x as usize)).collect();
+        let total_weight: f32 = weights.iter().sum();
+        if total_weight <= 0.0 {
+            return results;
+        }
+
+        for (b, b_words) in self.docs_terms.chunks_exact(self.word_capacity).enumerate() {
+            if b % DEADLINE_CHECK_INTERVAL == 0 && deadline.has_passed() {
+                break;
+            }
+
+            // Later documents can't beat a full set of perfect scores.
+            if results.threshold().is_some_and(|threshold| threshold >= 1.0) {
+                break;
+            }
+
+            // The lossy bit intersection is a quick way to rule documents out.
+            if intersecting_bits(a_words, b_words) == 0 {
+                continue;
+            }
+
+            let b_term_ids = &self.docs_terms_exact[b];
+            let weight: f32 = a_term_ids
+                .iter()
+                .zip(weights.iter())
+                .filter(|(term_id, _)| b_term_ids.binary_search(term_id).is_ok())
+                .map(|(_, weight)| weight)
+                .sum();
+
+            let score = weight / total_weight;
+            if weight > 0.0 && score >= cutoff {
+                results.add(b, score);
+            }
+        }
+
+        results
+    }
+
     // Search by a bit vector of term IDs. This is lossy, as there can be some wrapping.
-    // If an optional HashSet of term IDs is supplied, scope down results exactly to that.
+    // If an optional sorted list of term IDs is supplied, scope down results exactly to that.
+    // If there's a limit, only the best results are kept.
+    // If the deadline passes, stop scanning and return what's been found so far.
     pub fn search_by_bitfield_words(
         &self,
         a_words: &[u64],
         cutoff: f32,
-        exact_terms: Option<HashSet<usize>>,
+        exact_terms: Option<Vec<u32>>,
         normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
     ) -> ResultSet {
-        let mut results = ResultSet::new();
+        let mut results = ResultSet::with_limit(limit);
 
         let mut a_bitcount = 0;
         for word in a_words {
             a_bitcount += word.count_ones();
         }
 
+        // Compare only the query's non-zero words when there are few enough that it's quicker than
+        // comparing every word.
+        let a_nonzero = nonzero_words(a_words);
+        let sparse = a_nonzero.len() * LANES < self.word_capacity;
+
         // Full scan of each document's bit vector.
         // A is the query document. B is the other document (we're scanning).
-        for b in 0..self.top_id {
-            let mut b_bitcount = 0;
+        // IDs are inclusive of the top ID.
+        let b_docs = self
+            .docs_terms
+            .chunks_exact(self.word_capacity)
+            .zip(self.docs_bitcounts.iter());
+        for (b, (b_words, b_bitcount)) in b_docs.enumerate() {
+            if b % DEADLINE_CHECK_INTERVAL == 0 && deadline.has_passed() {
+                break;
+            }
+
+            let b_bitcount = *b_bitcount;
 
-            let b_words = &self.docs_terms[b * self.word_capacity..(b + 1) * self.word_capacity];
+            // Empty documents, e.g. IDs with no tune, can't match.
+            if b_bitcount == 0 {
+                continue;
+            }
 
-            let mut num_intersecting_bits = 0;
-            for i in 0..self.word_capacity {
-                num_intersecting_bits += (a_words[i] & b_words[i]).count_ones();
-                b_bitcount += b_words[i].count_ones();
+            // Once the results are full, skip documents that couldn't beat the worst of them even
+            // if every bit intersected. Documents are visited in ID order, so a later document
+            // with the same score doesn't beat it either, and nothing beats a full set of perfect
+            // scores.
+            if let Some(threshold) = results.threshold() {
+                if threshold >= 1.0 {
+                    break;
+                }
+
+                let best_score =
+                    normalization.score(u32::min(a_bitcount, b_bitcount), a_bitcount, b_bitcount);
+                if best_score <= threshold {
+                    continue;
+                }
             }
 
+            let num_intersecting_bits = if sparse {
+                a_nonzero
+                    .iter()
+                    .map(|(i, a_word)| (a_word & b_words[*i]).count_ones())
+                    .sum()
+            } else {
+                intersecting_bits(a_words, b_words)
+            };
+
             // If nothing intersects, that's zero match.
             if num_intersecting_bits == 0 {
                 continue;
@@ -472,8 +1136,7 @@ where
                 // Need to do a further test.
                 Some(ref a_term_ids) => {
                     if let Some(b_term_ids) = self.docs_terms_exact.get(b) {
-                        let intersecting_values =
-                            a_term_ids.intersection(b_term_ids).count() as u32;
+                        let intersecting_values = intersection_count(a_term_ids, b_term_ids);
                         let exact_score =
                             normalization.score(intersecting_values, a_bitcount, b_bitcount);
 
@@ -494,6 +1157,7 @@ where
         a: usize,
         cutoff: f32,
         normalization: ScoreNormalization,
+        deadline: Deadline,
     ) -> ResultSet {
         let results = ResultSet::new();
 
@@ -502,98 +1166,320 @@ where
         }
 
         let a_words =
-            &self.docs_terms[self.word_capacity * (a as usize)..self.word_capacity * (a + 1)];
-        self.search_by_bitfield_words(a_words, cutoff, None, normalization)
+            &self.docs_terms[self.word_capacity * a..self.word_capacity * (a + 1)];
+        if normalization == ScoreNormalization::TfIdf {
+            return self.search_by_term_ids_tf_idf(
+                a_words,
+                &self.docs_terms_exact[a],
+                cutoff,
+                None,
+                deadline,
+            );
+        }
+        self.search_by_bitfield_words(a_words, cutoff, None, normalization, None, deadline)
     }
 
     pub fn print_debug_tunes(&self) {
-        for id in 0..self.top_id {
-            if self.docs_terms_exact[id].len() > 0 {
-                eprintln!("Doc {}:", id);
-                for term in self.docs_terms_exact[id].iter() {
-                    eprint!("{:?} ", self.terms_i.get(term));
-                }
-                eprintln!("");
+        for id in 0..self.top_id + 1 {
+            if !self.docs_terms_exact[id].is_empty() {
+                let terms: Vec<String> = self.docs_terms_exact[id]
+                    .iter()
+                    .map(|term| format!("{:?}", self.terms_i.get(&(*term as usize))))
+                    .collect();
+                debug!("Doc {}: {}", id, terms.join(" "));
             }
         }
     }
 
+    // Number of distinct terms. Term IDs run from zero to this.
+    pub fn num_terms(&self) -> usize {
+        self.next_term_id
+    }
+
+    // Term for the term ID, if it exists.
+    pub fn get_term(&self, term_id: usize) -> Option<&K> {
+        self.terms_i.get(&term_id)
+    }
+
+    // Number of tunes that each term is found in.
+    pub fn doc_frequencies(&self) -> HashMap<&K, usize> {
+        self.postings
+            .iter()
+            .enumerate()
+            .filter(|(_, tune_ids)| !tune_ids.is_empty())
+            .filter_map(|(term_id, tune_ids)| {
+                self.terms_i
+                    .get(&term_id)
+                    .map(|term| (term, tune_ids.len()))
+            }).collect()
+    }
+
+    // IDs of tunes that contain the term, in order.
+    pub fn docs_with_term(&self, term: &K) -> Vec<usize> {
+        match self
+            .terms
+            .get(term)
+            .and_then(|term_id| self.postings.get(*term_id))
+        {
+            Some(tune_ids) => tune_ids.iter().map(|x| *x as usize).collect(),
+            None => vec![],
+        }
+    }
+
+    // Term IDs found in the tune, in order.
+    pub fn doc_term_ids(&self, tune_id: usize) -> Vec<usize> {
+        match self.docs_terms_exact.get(tune_id) {
+            Some(term_ids) => term_ids.iter().map(|x| *x as usize).collect(),
+            None => vec![],
+        }
+    }
+
     // Return number of distinct terms, size of term vector, and the load factor.
     // The load factor can be greater than 1, in which case lookups are lossy.
     pub fn load_factor(&self) -> (usize, usize, f32) {
         (
-            self.terms.len() as usize,
+            self.terms.len(),
             self.bit_capacity,
             self.terms.len() as f32 / self.bit_capacity as f32,
         )
     }
 }
 
-const INTERVAL_WINDOW_SIZE: usize = 5;
+// Number of values in both sorted lists.
+// Gallops through the longer list, so it's quick when one list is much shorter, e.g. a short
+// query against a long tune.
+pub fn intersection_count(a: &[u32], b: &[u32]) -> u32 {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+
+    let mut count = 0;
+    let mut start = 0;
+    for value in short.iter() {
+        // Double the step until we overshoot, then binary search the last step.
+        let mut step = 1;
+        while start + step < long.len() && long[start + step] < *value {
+            step *= 2;
+        }
+        let end = usize::min(start + step + 1, long.len());
+
+        match long[start..end].binary_search(value) {
+            Ok(position) => {
+                count += 1;
+                start += position + 1;
+            }
+            Err(position) => start += position,
+        }
+
+        if start >= long.len() {
+            break;
+        }
+    }
+
+    count
+}
+
+// Default width of the sliding window over the interval sequence.
+pub const DEFAULT_INTERVAL_WINDOW_SIZE: usize = 5;
 
 // Binary Vector Space model, each term being a sliding window over the interval sequence.
 pub struct IntervalWindowBinaryVSM {
-    pub vsm: BinaryVSM<[i16; INTERVAL_WINDOW_SIZE]>,
+    pub vsm: BinaryVSM<Vec<i16>>,
+
+    // Number of intervals in each term.
+    window_size: usize,
 }
 
 impl IntervalWindowBinaryVSM {
-    pub fn new(size: usize, top_id: usize) -> IntervalWindowBinaryVSM {
+    pub fn new(size: usize, top_id: usize, window_size: usize) -> IntervalWindowBinaryVSM {
         IntervalWindowBinaryVSM {
             vsm: BinaryVSM::new(size, top_id),
+            window_size,
         }
     }
 
+    // Number of intervals in each term.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
     // TODO this allocates an interrim vec so it can be reused.
     // Could somehow do this as an interator?
-    fn intervals_to_terms(interval_seq: &[i16]) -> Vec<[i16; INTERVAL_WINDOW_SIZE]> {
-        let mut terms = vec![];
+    fn intervals_to_terms(&self, interval_seq: &[i16]) -> Vec<Vec<i16>> {
+        interval_seq
+            .windows(self.window_size)
+            .map(|window| window.to_vec())
+            .collect()
+    }
+
+    pub fn add(&mut self, tune_id: usize, interval_seq: &[i16]) {
+        let terms = self.intervals_to_terms(interval_seq);
+        self.vsm.add_terms(tune_id, terms);
+    }
+
+    pub fn search(
+        &self,
+        interval_seq: &[i16],
+        cutoff: f32,
+        normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        let terms = self.intervals_to_terms(interval_seq);
+
+        debug!("Text search by: {:?}", &terms);
+        self.vsm
+            .search_by_terms_limited(&terms, cutoff, false, normalization, limit, deadline)
+    }
+}
+
+// Default width of the sliding window over the degree sequence.
+pub const DEFAULT_DEGREE_WINDOW_SIZE: usize = 5;
 
-        for window in interval_seq.windows(INTERVAL_WINDOW_SIZE) {
-            let mut window_arr = [0; INTERVAL_WINDOW_SIZE];
-            window_arr[0] = window[0];
-            window_arr[1] = window[1];
-            window_arr[2] = window[2];
-            window_arr[3] = window[3];
-            window_arr[4] = window[4];
-            terms.push(window_arr);
+// Binary Vector Space model, each term being a sliding window over the scale degree sequence.
+pub struct DegreeWindowBinaryVSM {
+    pub vsm: BinaryVSM<Vec<u8>>,
+
+    // Number of degrees in each term.
+    window_size: usize,
+}
+
+impl DegreeWindowBinaryVSM {
+    pub fn new(size: usize, top_id: usize, window_size: usize) -> DegreeWindowBinaryVSM {
+        DegreeWindowBinaryVSM {
+            vsm: BinaryVSM::new(size, top_id),
+            window_size,
         }
+    }
+
+    // Number of degrees in each term.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    fn degrees_to_terms(&self, degree_seq: &[u8]) -> Vec<Vec<u8>> {
+        degree_seq
+            .windows(self.window_size)
+            .map(|window| window.to_vec())
+            .collect()
+    }
 
-        terms
+    pub fn add(&mut self, tune_id: usize, degree_seq: &[u8]) {
+        let terms = self.degrees_to_terms(degree_seq);
+        self.vsm.add_terms(tune_id, terms);
     }
 
-    pub fn add(&mut self, tune_id: usize, interval_seq: &Vec<i16>) {
-        for term in IntervalWindowBinaryVSM::intervals_to_terms(&interval_seq) {
-            self.vsm.add(tune_id, term);
+    pub fn search(
+        &self,
+        degree_seq: &[u8],
+        cutoff: f32,
+        normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        let terms = self.degrees_to_terms(degree_seq);
+
+        debug!("Degree search by: {:?}", &terms);
+        self.vsm
+            .search_by_terms_limited(&terms, cutoff, false, normalization, limit, deadline)
+    }
+}
+
+// Default width of the sliding window over the contour sequence.
+// Each step has only three possible values, so a wider window is needed to tell tunes apart.
+pub const DEFAULT_CONTOUR_WINDOW_SIZE: usize = 8;
+
+// Binary Vector Space model, each term being a sliding window over the melodic contour.
+pub struct ContourWindowBinaryVSM {
+    pub vsm: BinaryVSM<Vec<pitch::Contour>>,
+
+    // Number of steps in each term.
+    window_size: usize,
+}
+
+impl ContourWindowBinaryVSM {
+    pub fn new(size: usize, top_id: usize, window_size: usize) -> ContourWindowBinaryVSM {
+        ContourWindowBinaryVSM {
+            vsm: BinaryVSM::new(size, top_id),
+            window_size,
         }
     }
 
+    // Number of steps in each term.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    fn contour_to_terms(&self, contour: &[pitch::Contour]) -> Vec<Vec<pitch::Contour>> {
+        contour
+            .windows(self.window_size)
+            .map(|window| window.to_vec())
+            .collect()
+    }
+
+    pub fn add(&mut self, tune_id: usize, contour: &[pitch::Contour]) {
+        let terms = self.contour_to_terms(contour);
+        self.vsm.add_terms(tune_id, terms);
+    }
+
     pub fn search(
         &self,
-        interval_seq: &Vec<i16>,
+        contour: &[pitch::Contour],
         cutoff: f32,
         normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
     ) -> ResultSet {
-        let terms = IntervalWindowBinaryVSM::intervals_to_terms(interval_seq);
+        let terms = self.contour_to_terms(contour);
 
-        eprintln!("Text search by: {:?}", &terms);
+        debug!("Contour search by: {:?}", &terms);
         self.vsm
-            .search_by_terms(&terms, cutoff, false, normalization)
+            .search_by_terms_limited(&terms, cutoff, false, normalization, limit, deadline)
     }
 }
 
+// Facet value that counts the values beyond a facet's limit.
+pub const FACET_OTHER: &str = "other";
+
+// Feature values are indexed in their normalised form, see `features::normalise`.
 pub struct FeaturesBinaryVSM {
     pub vsm: BinaryVSM<(String, String)>,
+
+    // Normalised (type, value) => how many times each form of it was written.
+    forms: HashMap<(String, String), HashMap<String, usize>>,
 }
 
 impl FeaturesBinaryVSM {
     pub fn new(size: usize, top_id: usize) -> FeaturesBinaryVSM {
         FeaturesBinaryVSM {
             vsm: BinaryVSM::new(size, top_id),
+            forms: HashMap::new(),
         }
     }
 
     pub fn add(&mut self, tune_id: usize, feature_type: String, value: String) {
-        self.vsm.add(tune_id, (feature_type, value));
+        let normalised = features::normalise(&feature_type, &value);
+        let term = (feature_type, normalised);
+
+        *self
+            .forms
+            .entry(term.clone())
+            .or_default()
+            .entry(value)
+            .or_insert(0) += 1;
+        self.vsm.add(tune_id, term);
+    }
+
+    // How to show a normalised feature value, which is the way it's most often written.
+    // Counts include tunes that have since been removed, which hardly matters for a label.
+    pub fn label(&self, feature_type: &str, value: &str) -> String {
+        self.forms
+            .get(&(feature_type.to_string(), value.to_string()))
+            .and_then(|forms| {
+                forms
+                    .iter()
+                    .max_by(|(form_a, count_a), (form_b, count_b)| {
+                        count_a.cmp(count_b).then(form_b.cmp(form_a))
+                    }).map(|(form, _)| form.to_string())
+            }).unwrap_or_else(|| value.to_string())
     }
 
     // Print out features.
@@ -604,13 +1490,43 @@ impl FeaturesBinaryVSM {
         for (feature_type, feature_value) in all_terms.iter() {
             // In stream of (type, feature) Detect change in type.
             if feature_type != prev_feature_type {
-                eprintln!("{}", feature_type);
+                debug!("{}", feature_type);
                 prev_feature_type = feature_type;
             }
-            eprintln!("  {}", feature_value);
+            debug!("  {}", feature_value);
         }
     }
 
+    // Tunes with a value of this numeric feature type between the bounds, inclusive.
+    // Values that aren't numbers never match.
+    pub fn search_range(
+        &self,
+        feature_type: &str,
+        min: Option<f32>,
+        max: Option<f32>,
+    ) -> ResultSet {
+        let in_range = |value: &str| match value.parse::<f32>() {
+            Ok(value) => min.iter().all(|min| value >= *min) && max.iter().all(|max| value <= *max),
+            Err(_) => false,
+        };
+
+        let terms: Vec<(String, String)> = self
+            .vsm
+            .terms
+            .keys()
+            .filter(|(typ, value)| typ == feature_type && in_range(value))
+            .cloned()
+            .collect();
+
+        self.vsm.search_by_postings(
+            &terms,
+            0.0,
+            ScoreNormalization::DocA,
+            None,
+            Deadline::none(),
+        )
+    }
+
     // Return structure of all known feature values, grouped by type.
     pub fn all_features(&self) -> HashMap<String, Vec<String>> {
         let mut results: HashMap<String, Vec<String>> = HashMap::new();
@@ -622,17 +1538,17 @@ impl FeaturesBinaryVSM {
         for (feature_type, feature_value) in all_terms.iter() {
             // In stream of (type, feature) Detect change in type.
             if feature_type != prev_feature_type {
-                if vals.len() > 0 {
+                if !vals.is_empty() {
                     results.insert(prev_feature_type.to_string(), vals);
                 }
                 vals = vec![];
                 prev_feature_type = feature_type;
             }
 
-            vals.push(feature_value.to_string());
+            vals.push(self.label(feature_type, feature_value));
         }
 
-        if vals.len() > 0 {
+        if !vals.is_empty() {
             results.insert(prev_feature_type.to_string(), vals);
         }
         results
@@ -641,16 +1557,23 @@ impl FeaturesBinaryVSM {
     // Return structure of feature types, values and counts for the Result Set.
     // Used for faceting.
     // Structure is feature-type -> feature-value -> count
+    // With a limit, only the most common values of each type are included, and the rest are
+    // counted together as FACET_OTHER.
     pub fn facet_features_for_resultset(
         &self,
         result_set: &ResultSet,
+        limit: Option<usize>,
     ) -> HashMap<String, Vec<(String, u32)>> {
         let mut counts: HashMap<(String, String), u32> = HashMap::new();
 
         for (tune_id, _) in result_set.results.iter() {
             let feature_ids = &self.vsm.docs_terms_exact[*tune_id];
             for feature_id in feature_ids.iter() {
-                if let Some((ref typ, ref val)) = self.vsm.terms_i.get(feature_id) {
+                if let Some((ref typ, ref val)) = self.vsm.terms_i.get(&(*feature_id as usize)) {
+                    // Numeric features have too many values to be useful as facets.
+                    if features::NUMERIC_FEATURES.contains(&typ.as_str()) {
+                        continue;
+                    }
                     *counts
                         .entry((typ.to_string(), val.to_string()))
                         .or_insert(0) += 1;
@@ -660,15 +1583,21 @@ impl FeaturesBinaryVSM {
 
         let mut results = HashMap::new();
         for ((typ, val), cnt) in counts {
-            (*results.entry(typ.to_string()).or_insert(vec![])).push((val, cnt));
+            let label = self.label(&typ, &val);
+            (*results.entry(typ).or_insert(vec![])).push((label, cnt));
         }
 
-        // Within each type, sort by count.
-        // No limiting just yet. With current data, a full page of all facet values is 200Kb,
-        // which is fine. If this gets fed into a user interface, some kind of limiting should
-        // happen downstream.
+        // Within each type, sort by count, then value so that the cut-off is stable.
+        // With current data, a full page of all facet values is 200Kb, so they're limited.
         for (_k, v) in results.iter_mut() {
-            v.sort_by(|(_, cnt_a), (_, cnt_b)| cnt_b.cmp(cnt_a));
+            v.sort_by(|(val_a, cnt_a), (val_b, cnt_b)| cnt_b.cmp(cnt_a).then(val_a.cmp(val_b)));
+
+            if let Some(limit) = limit {
+                if v.len() > limit {
+                    let other = v.split_off(limit).iter().map(|(_, cnt)| cnt).sum();
+                    v.push((FACET_OTHER.to_string(), other));
+                }
+            }
         }
 
         results
@@ -679,37 +1608,546 @@ impl FeaturesBinaryVSM {
 // Does its own tokenization on indexing and search.
 pub struct TextVSM {
     pub vsm: BinaryVSM<String>,
+
+    // Stop words and synonyms.
+    vocabulary: text::Vocabulary,
 }
 
 impl TextVSM {
-    pub fn new(size: usize, top_id: usize) -> TextVSM {
+    pub fn new(size: usize, top_id: usize, vocabulary: text::Vocabulary) -> TextVSM {
         TextVSM {
             vsm: BinaryVSM::new(size, top_id),
+            vocabulary,
         }
     }
 
     pub fn add(&mut self, tune_id: usize, string: String) {
-        let tokens = text::tokenize(&string);
-        for tok in tokens {
-            self.vsm.add(tune_id, tok);
-        }
+        let tokens = self.vocabulary.apply(text::tokenize(&string));
+        self.vsm.add_terms(tune_id, tokens.into_iter().collect());
     }
 
-    pub fn search(&self, string: String) -> ResultSet {
+    // Search with a `text::TextQuery`, e.g. "jig -slip". The index doesn't know the order of words,
+    // so a phrase only needs all its words here.
+    pub fn search(
+        &self,
+        string: String,
+        normalization: ScoreNormalization,
+        limit: Option<usize>,
+        deadline: Deadline,
+    ) -> ResultSet {
+        let query = text::TextQuery::parse(&string);
+
         // TODO there must be a better way to do this...
         let mut tokens: Vec<String> = vec![];
-        for x in text::tokenize(&string).iter() {
+        for x in self
+            .vocabulary
+            .apply(text::tokenize(&query.words.join(" ")))
+            .iter()
+        {
             tokens.push(x.to_string());
         }
 
-        self.vsm
-            .search_by_terms(&tokens, 0.0, false, ScoreNormalization::DocA)
+        // Results are removed afterwards, so there's no telling which will be the best.
+        let constrained =
+            !query.required.is_empty() || !query.excluded.is_empty() || !query.phrases.is_empty();
+        let limit = if constrained { None } else { limit };
+
+        let mut results = self
+            .vsm
+            .search_by_postings(&tokens, 0.0, normalization, limit, deadline);
+
+        for required in query.required.iter().chain(query.phrases.iter()) {
+            if let Some(tune_ids) = self.docs_containing(required) {
+                results.retain(|tune_id| tune_ids.contains(&tune_id));
+            }
+        }
+
+        for excluded in query.excluded.iter() {
+            if let Some(tune_ids) = self.docs_containing(excluded) {
+                results.retain(|tune_id| !tune_ids.contains(&tune_id));
+            }
+        }
+
+        results
+    }
+
+    // Tunes whose text contains all of the words, or None if there's nothing to look for once
+    // stop words are removed.
+    fn docs_containing(&self, words: &str) -> Option<HashSet<usize>> {
+        let tokens = self.vocabulary.apply(text::required_tokens(words));
+
+        tokens.iter().fold(None, |tune_ids, token| {
+            let with_token: HashSet<usize> = self.vsm.docs_with_term(token).into_iter().collect();
+            match tune_ids {
+                None => Some(with_token),
+                Some(tune_ids) => Some(tune_ids.intersection(&with_token).cloned().collect()),
+            }
+        })
+    }
+
+    // Terms in the text that match the query, in order.
+    pub fn matching_terms(&self, query: &str, text: &str) -> Vec<String> {
+        let query_words = text::TextQuery::parse(query).words.join(" ");
+        let query_tokens = self.vocabulary.apply(text::tokenize(&query_words));
+        let text_tokens = self.vocabulary.apply(text::tokenize(text));
+
+        let mut result: Vec<String> = query_tokens
+            .intersection(&text_tokens)
+            .map(|x| x.to_string())
+            .collect();
+        result.sort();
+        result
     }
 }
 
+// Start positions of windows in the sequence that are the same as any window in the query.
+pub fn matching_window_positions<T>(query: &[T], sequence: &[T], window_size: usize) -> Vec<usize>
+where
+    T: Eq + Hash,
+{
+    if window_size == 0 {
+        return vec![];
+    }
+
+    let query_windows: HashSet<&[T]> = query.windows(window_size).collect();
+
+    sequence
+        .windows(window_size)
+        .enumerate()
+        .filter(|(_, window)| query_windows.contains(window))
+        .map(|(i, _)| i)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn matching_window_positions_test() {
+        let sequence = vec![2, 2, 1, 2, 2, 2, 1];
+        assert_eq!(
+            matching_window_positions(&[2, 2, 1], &sequence, 3),
+            vec![0, 4]
+        );
+        assert_eq!(
+            matching_window_positions(&[2, 2, 1, 2], &sequence, 3),
+            vec![0, 1, 4],
+            "Any window of the query can match."
+        );
+        assert!(matching_window_positions(&[2, 2], &sequence, 3).is_empty());
+    }
+
+    #[test]
+    fn intersection_count_test() {
+        assert_eq!(intersection_count(&[], &[1, 2, 3]), 0);
+        assert_eq!(intersection_count(&[2, 3, 9], &[1, 2, 3, 4, 5, 6, 7, 8, 9]), 3);
+        assert_eq!(intersection_count(&[1, 2, 3, 4, 5, 6, 7, 8, 9], &[0, 5, 10]), 1);
+        assert_eq!(intersection_count(&[1, 3, 5], &[2, 4, 6]), 0);
+
+        let long: Vec<u32> = (0..1000).map(|x| x * 3).collect();
+        let short = vec![0, 2, 3, 300, 301, 2997, 5000];
+        assert_eq!(
+            intersection_count(&short, &long),
+            4,
+            "Galloping finds matches far apart."
+        );
+    }
+
+    #[test]
+    fn exact_terms_test() {
+        let mut vsm: BinaryVSM<String> = BinaryVSM::new(64, 3);
+        for term in ["c", "a", "b", "a"] {
+            vsm.add(1, term.to_string());
+        }
+        vsm.add(3, "b".to_string());
+        vsm.shrink_to_fit();
+
+        assert_eq!(vsm.doc_term_ids(1), vec![0, 1, 2], "Term IDs are sorted and distinct.");
+        assert_eq!(vsm.docs_with_term(&"b".to_string()), vec![1, 3]);
+
+        let results = vsm.search_by_terms(
+            &vec!["b".to_string(), "z".to_string()],
+            0.1,
+            true,
+            ScoreNormalization::DocA,
+        );
+        let mut ids: Vec<usize> = results.results.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn grow_and_remove_test() {
+        let mut vsm: BinaryVSM<String> = BinaryVSM::new(64, 1);
+        vsm.add(2, "a".to_string());
+        assert!(vsm.docs_with_term(&"a".to_string()).is_empty(), "Beyond the top ID.");
+
+        vsm.grow(2);
+        vsm.add(1, "a".to_string());
+        vsm.add(2, "a".to_string());
+        vsm.add(2, "b".to_string());
+        assert_eq!(vsm.docs_with_term(&"a".to_string()), vec![1, 2]);
+
+        vsm.remove(2);
+        assert_eq!(vsm.docs_with_term(&"a".to_string()), vec![1]);
+        assert!(vsm.doc_term_ids(2).is_empty());
+
+        let results = vsm.search_by_terms(
+            &vec!["b".to_string()],
+            0.1,
+            false,
+            ScoreNormalization::DocA,
+        );
+        assert_eq!(results.total(), 0);
+    }
+
+    #[test]
+    fn add_terms_test() {
+        let mut one_by_one: BinaryVSM<u32> = BinaryVSM::new(64, 2);
+        let mut all_at_once: BinaryVSM<u32> = BinaryVSM::new(64, 2);
+        let terms = vec![9, 3, 70, 3, 1, 9];
+        for term in terms.iter() {
+            one_by_one.add(2, *term);
+        }
+        all_at_once.add_terms(2, terms);
+        all_at_once.add_terms(1, vec![3]);
+        all_at_once.add_terms(2, vec![4, 70]);
+
+        assert_eq!(all_at_once.doc_term_ids(2), vec![0, 1, 2, 3, 4]);
+        assert_eq!(all_at_once.docs_with_term(&3), vec![1, 2]);
+        assert_eq!(all_at_once.docs_with_term(&4), vec![2]);
+        assert_eq!(one_by_one.doc_term_ids(2), vec![0, 1, 2, 3]);
+        assert_eq!(one_by_one.docs_bitcounts[2], 4);
+        assert_eq!(all_at_once.docs_bitcounts[2], 5);
+    }
+
+    #[test]
+    fn postings_test() {
+        // Room for 64 bits, so terms 0 and 64 share a bit.
+        let mut vsm: BinaryVSM<usize> = BinaryVSM::new(64, 3);
+        for term in 0..65 {
+            vsm.add(1, term);
+        }
+        vsm.remove(1);
+        vsm.add(1, 64);
+        vsm.add(2, 0);
+        vsm.add(2, 1);
+        vsm.add(3, 1);
+
+        let lossy = vsm.search_by_terms(&vec![0], 0.0, false, ScoreNormalization::DocA);
+        assert_eq!(lossy.total(), 2, "Tune 1 collides with term 0.");
+
+        let exact =
+            vsm.search_by_postings(&[0], 0.0, ScoreNormalization::DocA, None, Deadline::none());
+        assert_eq!(exact.results.keys().collect::<Vec<&usize>>(), vec![&2]);
+
+        let both = vsm.search_by_postings(
+            &[0, 1, 99],
+            0.0,
+            ScoreNormalization::DocA,
+            None,
+            Deadline::none(),
+        );
+        assert_eq!(both.results[&2], 1.0, "Unknown terms are ignored.");
+        assert_eq!(both.results[&3], 0.5);
+
+        let max =
+            vsm.search_by_postings(&[1], 0.0, ScoreNormalization::Max, None, Deadline::none());
+        assert_eq!(max.results[&2], 0.5);
+        assert_eq!(max.results[&3], 1.0);
+
+        let limited = vsm.search_by_postings(
+            &[0, 1],
+            0.0,
+            ScoreNormalization::DocA,
+            Some(1),
+            Deadline::none(),
+        );
+        assert_eq!(limited.results.keys().collect::<Vec<&usize>>(), vec![&2]);
+
+        let late = Deadline::after(Duration::from_secs(0));
+        let stopped = vsm.search_by_postings(&[0, 1], 0.0, ScoreNormalization::DocA, None, late);
+        assert_eq!(stopped.total(), 0, "Gives up once the deadline has passed.");
+
+        assert_eq!(vsm.docs_with_term(&1), vec![2, 3]);
+        assert_eq!(vsm.doc_frequencies()[&1], 2);
+        assert!(
+            !vsm.doc_frequencies().contains_key(&2),
+            "Removed with tune 1."
+        );
+
+        vsm.add(1, 1);
+        assert_eq!(
+            vsm.docs_with_term(&1),
+            vec![1, 2, 3],
+            "Kept in order when added out of order."
+        );
+    }
+
+    #[test]
+    fn text_operators_test() {
+        let vocabulary = text::Vocabulary::parse("stop: the").unwrap();
+        let mut vsm = TextVSM::new(1024, 5, vocabulary);
+        vsm.add(1, "The Butterfly Jig".to_string());
+        vsm.add(2, "Butterfly Slip Jig".to_string());
+        vsm.add(3, "Kesh Jig".to_string());
+        vsm.add(4, "Jig of Slates, The".to_string());
+        vsm.add(5, "Butterfly Reel".to_string());
+
+        let ids = |query: &str| {
+            let mut ids: Vec<usize> = vsm
+                .search(
+                    query.to_string(),
+                    ScoreNormalization::DocA,
+                    None,
+                    Deadline::none(),
+                )
+                .results
+                .keys()
+                .cloned()
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids("jig"), vec![1, 2, 3, 4]);
+        assert_eq!(ids("jig -slip"), vec![1, 3, 4]);
+        assert_eq!(ids("butterfly +jig"), vec![1, 2, 3, 4]);
+        assert_eq!(ids("+butterfly +jig"), vec![1, 2]);
+        assert_eq!(
+            ids("\"slip jig\""),
+            vec![2],
+            "Phrases need all their words."
+        );
+        assert_eq!(ids("jig -\"slip jig\""), vec![1, 3, 4]);
+        assert_eq!(
+            ids("jig -the"),
+            vec![1, 2, 3, 4],
+            "Stop words don't exclude anything."
+        );
+        assert_eq!(ids("jig +reel"), vec![5], "Required words must be there.");
+
+        assert_eq!(
+            vsm.matching_terms("butterfly -jig", "The Butterfly Jig"),
+            vec!["butterfly"]
+        );
+    }
+
+    #[test]
+    fn feature_labels_test() {
+        let mut vsm = FeaturesBinaryVSM::new(64, 5);
+        let mut results = ResultSet::new();
+        for (tune_id, rhythm) in ["Bourrée", "bourree", "Bourrée", "Reel"].iter().enumerate() {
+            vsm.add(tune_id, "rhythm".to_string(), rhythm.to_string());
+            results.add(tune_id, 1.0);
+        }
+
+        assert_eq!(
+            vsm.vsm
+                .docs_with_term(&("rhythm".to_string(), "bourree".to_string())),
+            vec![0, 1, 2],
+            "Indexed in the normalised form."
+        );
+        assert_eq!(
+            vsm.label("rhythm", "bourree"),
+            "Bourrée",
+            "Labelled with the most common form."
+        );
+        assert_eq!(vsm.label("rhythm", "jig"), "jig");
+
+        assert_eq!(
+            vsm.facet_features_for_resultset(&results, None)["rhythm"],
+            vec![("Bourrée".to_string(), 3), ("Reel".to_string(), 1)]
+        );
+        assert_eq!(vsm.all_features()["rhythm"], vec!["Bourrée", "Reel"]);
+    }
+
+    #[test]
+    fn search_range_test() {
+        let mut vsm = FeaturesBinaryVSM::new(64, 5);
+        for (tune_id, range) in ["9", "12", "14", "21"].iter().enumerate() {
+            vsm.add(tune_id, "range".to_string(), range.to_string());
+            vsm.add(tune_id, "metre".to_string(), "12/8".to_string());
+        }
+
+        let ids = |results: ResultSet| {
+            let mut ids: Vec<usize> = results.results.keys().cloned().collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids(vsm.search_range("range", None, Some(12.0))), vec![0, 1]);
+        assert_eq!(
+            ids(vsm.search_range("range", Some(12.0), Some(14.0))),
+            vec![1, 2]
+        );
+        assert_eq!(ids(vsm.search_range("range", Some(15.0), None)), vec![3]);
+        assert!(vsm
+            .search_range("range", Some(30.0), None)
+            .results
+            .is_empty());
+        assert!(
+            vsm.search_range("metre", None, None).results.is_empty(),
+            "Values that aren't numbers don't match."
+        );
+    }
+
+    #[test]
+    fn facet_limit_test() {
+        let mut vsm = FeaturesBinaryVSM::new(64, 5);
+        let mut results = ResultSet::new();
+        for (tune_id, key) in ["G", "G", "D", "A", "E", "D"].iter().enumerate() {
+            vsm.add(tune_id, "key".to_string(), key.to_string());
+            vsm.add(tune_id, "rhythm".to_string(), "jig".to_string());
+            results.add(tune_id, 1.0);
+        }
+
+        let all = vsm.facet_features_for_resultset(&results, None);
+        assert_eq!(all["key"].len(), 4);
+
+        vsm.add(0, "notes".to_string(), "64".to_string());
+        assert!(
+            !vsm.facet_features_for_resultset(&results, None)
+                .contains_key("notes"),
+            "Numeric features aren't facets."
+        );
+
+        let limited = vsm.facet_features_for_resultset(&results, Some(2));
+        assert_eq!(
+            limited["key"],
+            vec![
+                ("D".to_string(), 2),
+                ("G".to_string(), 2),
+                (FACET_OTHER.to_string(), 2),
+            ],
+            "Most common first, then the rest."
+        );
+        assert_eq!(limited["rhythm"], vec![("jig".to_string(), 6)]);
+    }
+
+    #[test]
+    fn intersecting_bits_test() {
+        let a = [0b1011, 0, 0, 1 << 63, 0, 0, 0, 0b1];
+        let b = [0b0011, 7, 0, 1 << 63, 0, 0, 0, 0b11];
+        assert_eq!(intersecting_bits(&a, &b), 4);
+        assert_eq!(
+            nonzero_words(&a),
+            vec![(0, 0b1011), (3, 1 << 63), (7, 0b1)],
+            "Only the words with bits set."
+        );
+
+        // Scores are the same whether the query has few words set or many.
+        let mut vsm: BinaryVSM<usize> = BinaryVSM::new(1000, 2);
+        assert_eq!(vsm.word_capacity % LANES, 0);
+        for term in 0..300 {
+            vsm.add(1, term);
+        }
+        for term in 0..15 {
+            vsm.add(2, term * 2);
+        }
+        let few = vsm.search_by_terms(&vec![0, 2, 4], 0.0, false, ScoreNormalization::DocA);
+        assert_eq!(few.results.get(&1), Some(&1.0));
+        assert_eq!(few.results.get(&2), Some(&1.0));
+
+        let many: Vec<usize> = (0..300).collect();
+        let many = vsm.search_by_terms(&many, 0.0, false, ScoreNormalization::DocA);
+        assert_eq!(many.results.get(&1), Some(&1.0));
+        assert_eq!(many.results.get(&2), Some(&0.05));
+    }
+
+    #[test]
+    fn limited_search_test() {
+        let mut vsm: BinaryVSM<usize> = BinaryVSM::new(1000, 100);
+        for tune_id in 0..100 {
+            for term in 0..(tune_id % 10 + 1) {
+                vsm.add(tune_id, term);
+            }
+        }
+        let terms: Vec<usize> = (0..10).collect();
+
+        // The best results of a full search, ordered as search results are.
+        let best = |results: ResultSet, limit: usize| {
+            let mut results: Vec<(usize, f32)> = results.results.into_iter().collect();
+            results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+            results.truncate(limit);
+            results
+        };
+
+        for normalization in [
+            ScoreNormalization::DocA,
+            ScoreNormalization::Max,
+            ScoreNormalization::TfIdf,
+        ].iter()
+        {
+            for limit in [1, 5, 15, 200].iter() {
+                let all = vsm.search_by_terms(&terms, 0.0, false, *normalization);
+                let limited = vsm.search_by_terms_limited(
+                    &terms,
+                    0.0,
+                    false,
+                    *normalization,
+                    Some(*limit),
+                    Deadline::none(),
+                );
+                assert_eq!(
+                    best(limited, *limit),
+                    best(all, *limit),
+                    "{:?} with limit {}",
+                    normalization,
+                    limit
+                );
+            }
+
+            let late = Deadline::after(Duration::from_secs(0));
+            let stopped =
+                vsm.search_by_terms_limited(&terms, 0.0, false, *normalization, None, late);
+            assert_eq!(
+                stopped.total(),
+                0,
+                "{:?} gives up at the deadline.",
+                normalization
+            );
+        }
+    }
+
+    #[test]
+    fn tf_idf_test() {
+        let mut vsm: BinaryVSM<String> = BinaryVSM::new(64, 3);
+        vsm.add(1, "a".to_string());
+        vsm.add(1, "b".to_string());
+        vsm.add(2, "a".to_string());
+        vsm.add(2, "c".to_string());
+        vsm.add(3, "a".to_string());
+
+        let terms = vec!["a".to_string(), "c".to_string()];
+        let score = |results: &ResultSet, tune_id: usize| results.results.get(&tune_id).cloned();
+
+        let results = vsm.search_by_terms(&terms, 0.1, false, ScoreNormalization::DocA);
+        assert_eq!(score(&results, 1), Some(0.5));
+        assert_eq!(score(&results, 2), Some(1.0));
+
+        // The common term counts for less.
+        let results = vsm.search_by_terms(&terms, 0.1, false, ScoreNormalization::TfIdf);
+        assert!(score(&results, 1).unwrap() < 0.5);
+        assert_eq!(score(&results, 2), Some(1.0));
+        assert_eq!(score(&results, 1), score(&results, 3));
+
+        assert!(vsm.idf(0) < vsm.idf(2));
+        vsm.remove(1);
+        vsm.remove(2);
+        assert_eq!(
+            vsm.idf(0),
+            1.0,
+            "Removed tunes don't count, so 'a' is in every tune."
+        );
+
+        assert_eq!(
+            ScoreNormalization::from_string("TFIDF"),
+            Some(ScoreNormalization::TfIdf)
+        );
+    }
 
     #[test]
     fn extend_groups_test() {
@@ -798,6 +2236,129 @@ mod tests {
         );
     }
 
+    #[test]
+    fn save_load_params_test() {
+        let mut path = std::env::temp_dir();
+        path.push("folktunefinder-clusters-params-test");
+
+        let mut groups = Clusters::with_max_id(10);
+        groups.add(1, 2);
+        groups.add(5, 6);
+        groups.params = Some(ClusterParams {
+            cutoff: 0.75,
+            normalization: ScoreNormalization::DocA,
+            window_size: 4,
+        });
+        groups.save(&path).unwrap();
+
+        let loaded = Clusters::load(&path);
+        assert_eq!(loaded.params, groups.params, "Params survive a round-trip.");
+        assert_eq!(loaded.generation, None, "No generation recorded.");
+        assert_eq!(
+            loaded.get_groups(),
+            vec![vec![1usize, 2usize], vec![5usize, 6usize]],
+            "Groups survive a round-trip, including unassigned tunes."
+        );
+
+        // Files without params are written in the legacy format.
+        groups.params = None;
+        groups.save(&path).unwrap();
+
+        let loaded = Clusters::load(&path);
+        assert_eq!(loaded.params, None, "Legacy file has no params.");
+        assert_eq!(
+            loaded.get_groups(),
+            vec![vec![1usize, 2usize], vec![5usize, 6usize]],
+            "Legacy file groups load."
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_load_generation_test() {
+        let mut path = std::env::temp_dir();
+        path.push("folktunefinder-clusters-generation-test");
+
+        let mut groups = Clusters::with_max_id(10);
+        groups.add(1, 2);
+        groups.params = Some(ClusterParams::default());
+        groups.generation = Some(0xFEDC_BA98_7654_3210);
+        groups.save(&path).unwrap();
+
+        let loaded = Clusters::load(&path);
+        assert_eq!(loaded.params, groups.params, "Params survive a round-trip.");
+        assert_eq!(loaded.generation, groups.generation, "Generation survives a round-trip.");
+        assert_eq!(loaded.get_groups(), vec![vec![1usize, 2usize]]);
+
+        groups.params = None;
+        groups.save(&path).unwrap();
+        let loaded = Clusters::load(&path);
+        assert_eq!(loaded.params, None);
+        assert_eq!(
+            loaded.generation, groups.generation,
+            "Generation is kept without params."
+        );
+        assert_eq!(loaded.get_groups(), vec![vec![1usize, 2usize]]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ungrouped_ids_test() {
+        let mut groups = Clusters::with_max_id(5);
+        groups.add(1, 2);
+        groups.add(4, 5);
+
+        assert_eq!(
+            groups.ungrouped_ids(5),
+            vec![0, 3],
+            "Only IDs not in a group are returned."
+        );
+
+        groups.ensure_max_id(8);
+        assert_eq!(
+            groups.ungrouped_ids(7),
+            vec![0, 3, 6, 7],
+            "Newly represented IDs are ungrouped, up to the max."
+        );
+    }
+
+    #[test]
+    fn evaluate_test() {
+        let duplicates =
+            parse_duplicate_pairs("# Known duplicates\n1 2\n2,3\n\n10 11\n20 21\n").unwrap();
+        assert_eq!(duplicates, vec![(1, 2), (2, 3), (10, 11), (20, 21)]);
+
+        // 1, 2 and 3 are together, 10 is wrongly with 20, and 11 and 21 are on their own.
+        let mut clusters = Clusters::new();
+        clusters.add(1, 2);
+        clusters.add(2, 3);
+        clusters.add(10, 20);
+        clusters.add(30, 31);
+
+        let evaluation = clusters.evaluate(&duplicates);
+        assert_eq!(
+            evaluation,
+            ClusterEvaluation {
+                true_positives: 3,
+                false_positives: 1,
+                false_negatives: 2,
+            }
+        );
+        assert_eq!(evaluation.precision(), 0.75);
+        assert_eq!(evaluation.recall(), 0.6);
+
+        let perfect = Clusters::new().evaluate(&[]);
+        assert_eq!(
+            (perfect.precision(), perfect.recall(), perfect.f1()),
+            (1.0, 1.0, 1.0)
+        );
+
+        assert!(parse_duplicate_pairs("1 2 3").is_err());
+        assert!(parse_duplicate_pairs("1 two").is_err());
+    }
+
     #[test]
     fn join_groups_test() {
         let mut groups = Clusters::new();
@@ -3,34 +3,970 @@
 //! Intended to be chained, cached, etc.
 
 use abc_lexer;
+use beaming;
 use features;
+use fingering;
+use midi;
+use music;
 use pitch;
-use relations;
+use raster;
 use std::collections::HashMap;
-use std::sync::mpsc::channel;
-use std::sync::Arc;
-use std::thread;
-use storage;
+use svg;
 use tune_ast_three;
 use typeset;
 
 use abc_lexer as l;
 
-// This gives the best performance for 200,000 tunes.
-const THREADS: u32 = 8;
+// Size of preview images, as recommended for Open Graph.
+const PREVIEW_WIDTH: u32 = 1200;
+const PREVIEW_HEIGHT: u32 = 630;
+const PREVIEW_MARGIN: f32 = 60.0;
+
+// Previews are drawn at this multiple of their size and shrunk, for antialiasing.
+const PREVIEW_SUPERSAMPLE: u32 = 2;
+
+// Number of bars of music in a preview.
+const PREVIEW_BARS: usize = 2;
+
+// Number of bars and size, relative to the full score, of incipits in search results.
+const INCIPIT_BARS: usize = 2;
+const INCIPIT_SCALE: f32 = 0.5;
+
+// Don't magnify short incipits more than this.
+const PREVIEW_MAX_SCALE: f32 = 2.0;
+
+// Largest and smallest raster font pixel sizes for the title of a preview.
+const PREVIEW_TITLE_MAX_SIZE: f32 = 8.0;
+const PREVIEW_TITLE_MIN_SIZE: f32 = 3.0;
+
+// SVG user units are CSS pixels, at 96 to the inch.
+const SVG_DPI: f32 = 96.0;
+
+// Limits on PNG renderings, to keep memory use in check.
+// Images that would be bigger than PNG_MAX_PIXELS are scaled down to fit.
+pub const PNG_MAX_WIDTH: u32 = 4000;
+pub const PNG_MAX_DPI: u32 = 600;
+const PNG_MAX_PIXELS: f32 = 4_000_000.0;
+
+// PNGs are drawn at this multiple of their size and shrunk, for antialiasing.
+const PNG_SUPERSAMPLE: u32 = 2;
+
+// Requested size of a PNG rendering.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PngSize {
+    // Width in pixels.
+    Width(u32),
+
+    // Resolution, where the SVG rendering is 96 DPI.
+    Dpi(u32),
+}
+
+impl PngSize {
+    // From optional `width` and `dpi` option values. Default is the same size as the SVG.
+    pub fn parse(width: Option<&str>, dpi: Option<&str>) -> Result<PngSize, String> {
+        match (width, dpi) {
+            (Some(_), Some(_)) => Err("Supply either 'width' or 'dpi', not both.".to_string()),
+            (Some(width), None) => match width.parse::<u32>() {
+                Ok(width) if width > 0 && width <= PNG_MAX_WIDTH => Ok(PngSize::Width(width)),
+                _ => Err(format!(
+                    "Invalid value for 'width'. Must be a number from 1 to {}.",
+                    PNG_MAX_WIDTH
+                )),
+            },
+            (None, Some(dpi)) => match dpi.parse::<u32>() {
+                Ok(dpi) if dpi > 0 && dpi <= PNG_MAX_DPI => Ok(PngSize::Dpi(dpi)),
+                _ => Err(format!(
+                    "Invalid value for 'dpi'. Must be a number from 1 to {}.",
+                    PNG_MAX_DPI
+                )),
+            },
+            (None, None) => Ok(PngSize::Dpi(SVG_DPI as u32)),
+        }
+    }
+
+    // Short description, e.g. for cache keys.
+    pub fn cache_key(self) -> String {
+        match self {
+            PngSize::Width(width) => format!("w{}", width),
+            PngSize::Dpi(dpi) => format!("dpi{}", dpi),
+        }
+    }
+}
 
 // Convert an ABC tune as a String into an Abstract Syntax Tree.
-pub fn abc_to_ast(content: &String) -> tune_ast_three::Tune {
+pub fn abc_to_ast(content: &str) -> tune_ast_three::Tune {
     let chars = content.chars().collect::<Vec<char>>();
     tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars))
 }
 
 // Convert an Abstract Syntax Tree into an SVG.
-pub fn ast_to_svg(ast: &tune_ast_three::Tune) -> String {
-    let typeset_page = typeset::typeset_from_ast(ast);
-    typeset::render_page(typeset_page)
+pub fn ast_to_svg(ast: &tune_ast_three::Tune, typesetting: &typeset::Typesetting) -> String {
+    ast_to_drawing(ast, typesetting).render()
+}
+
+// Convert an Abstract Syntax Tree into a small SVG of its first bars, without the header.
+pub fn ast_to_incipit_svg(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+) -> String {
+    let typesetting = typeset::Typesetting {
+        header: false,
+        ..*typesetting
+    };
+    let mut drawing = ast_to_drawing(&incipit(ast, INCIPIT_BARS), &typesetting);
+    drawing.set_scale(INCIPIT_SCALE);
+    drawing.render()
+}
+
+// Typeset an Abstract Syntax Tree, ready to render.
+pub fn ast_to_drawing(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+) -> svg::Drawing {
+    ast_to_drawing_with_errors(ast, typesetting, &[])
+}
+
+// Typeset an Abstract Syntax Tree with errors marked on it, e.g. from `error_markers`.
+pub fn ast_to_drawing_with_errors(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+    errors: &[typeset::ErrorMarker],
+) -> svg::Drawing {
+    typeset::draw_page(ast_to_page(ast, typesetting, errors), typesetting)
+}
+
+fn ast_to_page(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+    errors: &[typeset::ErrorMarker],
+) -> typeset::Page {
+    let fingerings = match typesetting.fingering {
+        Some(instrument) => fingering::annotate(ast, instrument),
+        None => vec![],
+    };
+
+    if typesetting.auto_beam {
+        typeset::typeset_from_ast(&beaming::auto_beam(ast), &fingerings, errors)
+    } else {
+        typeset::typeset_from_ast(ast, &fingerings, errors)
+    }
+}
+
+// Where everything is on the typeset tune, as it would be drawn in the SVG, with notes linked
+// back to where they are in the ABC.
+pub fn abc_to_layout(content: &str, typesetting: &typeset::Typesetting) -> typeset::PageLayout {
+    let ast = abc_to_ast(content);
+    let mut layout = typeset::layout_page(&ast_to_page(&ast, typesetting, &[]), typesetting);
+
+    // The layout counts notes within each voice, so find each voice's notes in the ABC.
+    let chars = content.chars().collect::<Vec<char>>();
+    let mut ranges: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    for (voice, start, end) in abc_lexer::voice_note_ranges(&chars) {
+        ranges.entry(voice).or_default().push((start, end));
+    }
+
+    for stave in layout.lines.iter_mut().flat_map(|x| x.staff.iter_mut()) {
+        let voice_ranges = ranges.get(&stave.voice);
+        for entity in stave.voices.iter_mut().flat_map(|x| x.iter_mut()) {
+            let range = entity
+                .note
+                .and_then(|note| voice_ranges.and_then(|x| x.get(note)));
+            if let Some(&(start, end)) = range {
+                entity.start_char = Some(start);
+                entity.end_char = Some(end);
+            }
+        }
+    }
+
+    layout
+}
+
+// Where each note of the AST is in the ABC, with its voice, in the order the AST has them.
+// The AST concatenates voices, so notes in interleaved voices aren't in source order.
+pub fn ast_note_ranges(
+    content: &[char],
+    ast: &tune_ast_three::Tune,
+) -> Vec<(String, usize, usize)> {
+    let mut ranges: HashMap<String, Vec<(String, usize, usize)>> = HashMap::new();
+    for range in abc_lexer::voice_note_ranges(content) {
+        ranges.entry(range.0.clone()).or_default().push(range);
+    }
+
+    ast.voice_properties
+        .iter()
+        .filter_map(|x| ranges.remove(&x.id))
+        .flat_map(|x| x.into_iter())
+        .collect()
+}
+
+// Lexer errors in an ABC tune, each at the note it's at or just before, to mark on the score.
+// Errors in the header, or after the last note, aren't at any note.
+// Notes are counted within their voice, as the typesetter lays voices out one after another.
+pub fn error_markers(content: &str) -> Vec<typeset::ErrorMarker> {
+    let chars = content.chars().collect::<Vec<char>>();
+    let notes = abc_lexer::voice_note_ranges(&chars);
+
+    abc_lexer::Lexer::new(&chars)
+        .collect_errors()
+        .iter()
+        .map(|&(ref ctx, offset, ref error)| {
+            let mut message = String::new();
+            error.format(0, &mut message);
+
+            let note = if ctx.is_header() {
+                None
+            } else {
+                notes.iter().position(|&(_, _, end)| end > offset).map(|i| {
+                    let voice = &notes[i].0;
+                    let before = notes[..i].iter().filter(|x| x.0 == *voice).count();
+                    (voice.clone(), before)
+                })
+            };
+
+            typeset::ErrorMarker {
+                note,
+                message: message.trim().to_string(),
+            }
+        }).collect()
+}
+
+// The first few bars of a tune on one line, e.g. for a preview.
+// A pick-up before the first full bar doesn't count as a bar.
+pub fn incipit(ast: &tune_ast_three::Tune, bars: usize) -> tune_ast_three::Tune {
+    let mut result = tune_ast_three::Tune::new();
+    result.prelude = ast.prelude.clone();
+
+    let mut tokens = vec![];
+    let mut bar_duration = music::FractionalDuration(0, 1);
+    let mut num_bars = 0;
+    let mut first_bar = true;
+    let has_pickup = ast
+        .voice_properties
+        .first()
+        .is_some_and(|x| x.anacrusis.is_some());
+
+    if let Some(voice) = ast.voices.first() {
+        for token in voice.iter() {
+            match token {
+                l::T::Newline => continue,
+                l::T::Note(music::Note(_, duration)) | l::T::Rest(duration) => {
+                    bar_duration = bar_duration + *duration;
+                }
+                _ => (),
+            }
+
+            tokens.push(token.clone());
+
+            if beaming::is_barline(token) && bar_duration.0 > 0 {
+                if !(first_bar && has_pickup) {
+                    num_bars += 1;
+                }
+
+                first_bar = false;
+                bar_duration = music::FractionalDuration(0, 1);
+
+                if num_bars >= bars {
+                    break;
+                }
+            }
+        }
+    }
+
+    result.voices.push(tokens);
+    result.voice_properties = vec![ast
+        .voice_properties
+        .first()
+        .cloned()
+        .unwrap_or(tune_ast_three::VoiceProperties::new("1"))];
+    result
+}
+
+// All titles of the tune.
+pub fn titles(ast: &tune_ast_three::Tune) -> Vec<String> {
+    ast.prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::Title(x) => Some(x.clone()),
+            _ => None,
+        }).collect()
+}
+
+// The first title of the tune, if it has one.
+pub fn title(ast: &tune_ast_three::Tune) -> Option<String> {
+    ast.prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::Title(x) => Some(x.clone()),
+            _ => None,
+        }).next()
+}
+
+// Header fields other than titles as (name, value) pairs for display, in the order they're written.
+pub fn headers(ast: &tune_ast_three::Tune) -> Vec<(String, String)> {
+    ast.prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::Area(x) => Some(("Area", x.clone())),
+            l::T::Book(x) => Some(("Book", x.clone())),
+            l::T::Composer(x) => Some(("Composer", x.clone())),
+            l::T::Discography(x) => Some(("Discography", x.clone())),
+            l::T::Group(x) => Some(("Group", x.clone())),
+            l::T::History(x) => Some(("History", x.clone())),
+            l::T::Notes(x) => Some(("Notes", x.clone())),
+            l::T::Origin(x) => Some(("Origin", x.clone())),
+            l::T::Source(x) => Some(("Source", x.clone())),
+            l::T::Rhythm(x) => Some(("Rhythm", x.clone())),
+            l::T::Transcription(x) => Some(("Transcription", x.clone())),
+            l::T::Metre(metre) => Some(("Metre", metre.to_string())),
+            l::T::KeySignature(pitch_class, mode) => Some((
+                "Key",
+                format!("{} {}", pitch_class, mode),
+            )),
+            _ => None,
+        }).map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+// A duration in words, e.g. "dotted crotchet", or as a fraction of a semibreve if it has no name.
+fn duration_text(duration: music::FractionalDuration) -> String {
+    match duration.to_glyph() {
+        Some(music::DurationGlyph { shape, dots }) => {
+            let dotted = match dots {
+                0 => "",
+                1 => "dotted ",
+                2 => "double dotted ",
+                _ => "many dotted ",
+            };
+            format!("{}{}", dotted, shape.name())
+        }
+        None => {
+            let duration = duration.reduce();
+            format!("{}/{}", duration.0, duration.1)
+        }
+    }
+}
+
+// A note in words, e.g. "F sharp 4 crotchet", with the octave numbered so middle C is C 4.
+// Accidentals are as written, not from the key signature.
+fn note_text(note: &music::Note) -> String {
+    let music::Note(pitch, duration) = note;
+    let accidental = match pitch.pitch_class.accidental {
+        Some(accidental) => format!(" {}", accidental.name()),
+        None => "".to_string(),
+    };
+
+    format!(
+        "{}{} {} {}",
+        pitch.pitch_class.diatonic_pitch_class,
+        accidental,
+        pitch.octave + 4,
+        duration_text(*duration)
+    )
+}
+
+// A voice described bar by bar, as it's read.
+struct BarDescriptions {
+    lines: Vec<String>,
+
+    // Words for the bar so far, and how long its notes and rests are.
+    words: Vec<String>,
+    duration: music::FractionalDuration,
+
+    // Does the voice start with a pick-up?
+    pickup: bool,
+
+    // Bars described so far, not counting a pick-up.
+    bar_number: usize,
+}
+
+impl BarDescriptions {
+    fn new(pickup: bool) -> BarDescriptions {
+        BarDescriptions {
+            lines: vec![],
+            words: vec![],
+            duration: music::FractionalDuration(0, 1),
+            pickup,
+            bar_number: 0,
+        }
+    }
+
+    fn has_music(&self) -> bool {
+        self.duration.0 > 0
+    }
+
+    // Describe the bar so far. A pick-up isn't numbered.
+    fn finish_bar(&mut self) {
+        let is_pickup = self.lines.is_empty() && self.pickup;
+
+        let words = self.words.join(", ");
+        if is_pickup {
+            self.lines.push(format!("Pick-up: {}.", words));
+        } else {
+            self.bar_number += 1;
+            self.lines
+                .push(format!("Bar {}: {}.", self.bar_number, words));
+        }
+
+        self.words.clear();
+        self.duration = music::FractionalDuration(0, 1);
+    }
+
+    fn read(&mut self, token: &l::T) {
+        // Barlines that start a bar end the one before, if there's music in it.
+        let starts_bar = matches!(token, l::T::OpenRepeat | l::T::NTimeBar(_));
+        if starts_bar && self.has_music() {
+            self.finish_bar();
+        }
+
+        let word = match token {
+            l::T::Note(note) => {
+                self.duration = self.duration + note.1;
+                note_text(note)
+            }
+            l::T::Rest(duration) => {
+                self.duration = self.duration + *duration;
+                format!("rest {}", duration_text(*duration))
+            }
+            l::T::GuitarChord(chord) => format!("chord {}", chord),
+            l::T::KeySignature(pitch_class, mode) => format!(
+                "key change to {} {}",
+                pitch_class,
+                mode
+            ),
+            l::T::Metre(metre) => format!("metre change to {}", metre),
+            l::T::OpenRepeat => "start repeat".to_string(),
+            l::T::NTimeBar(number) => format!("ending {}", number),
+            l::T::CloseRepeat => "end repeat".to_string(),
+            l::T::DoubleBar => "double bar".to_string(),
+            l::T::EndBar => "end".to_string(),
+            l::T::SingleBar => "".to_string(),
+            _ => return,
+        };
+
+        let ends_bar = matches!(
+            token,
+            l::T::SingleBar | l::T::DoubleBar | l::T::CloseRepeat | l::T::EndBar
+        );
+
+        if !ends_bar {
+            self.words.push(word);
+        } else if self.has_music() {
+            if !word.is_empty() {
+                self.words.push(word);
+            }
+            self.finish_bar();
+        } else if !word.is_empty() && self.bar_number > 0 {
+            // Barlines read as more than one token, e.g. ":|]", belong with the bar they end.
+            let last = self.lines.len() - 1;
+            self.lines[last] = format!("{}, {}.", self.lines[last].trim_end_matches('.'), word);
+        }
+    }
+
+    // The description, including any music after the last barline.
+    fn finish(mut self) -> Vec<String> {
+        if self.has_music() {
+            self.finish_bar();
+        }
+        self.lines
+    }
+}
+
+// A tune described in plain text, for screen readers and braille displays: the header fields, then
+// each voice bar by bar, with every note's name, octave and duration.
+pub fn ast_to_text(ast: &tune_ast_three::Tune) -> String {
+    let mut lines = titles(ast);
+    for (name, value) in headers(ast) {
+        lines.push(format!("{}: {}", name, value));
+    }
+
+    for (voice, properties) in ast.voices.iter().zip(ast.voice_properties.iter()) {
+        lines.push("".to_string());
+        if ast.voices.len() > 1 {
+            lines.push(format!(
+                "Voice {}",
+                properties.name.as_ref().unwrap_or(&properties.id)
+            ));
+        }
+
+        let mut bars = BarDescriptions::new(properties.anacrusis.is_some());
+        for token in voice.iter() {
+            bars.read(token);
+        }
+        lines.extend(bars.finish());
+    }
+
+    lines.push("".to_string());
+    lines.join("\n")
+}
+
+// Largest font size that fits text in the width, shortening the text if it doesn't fit at all.
+fn fit_text(text: &str, width: f32) -> (String, f32) {
+    let mut size = PREVIEW_TITLE_MAX_SIZE;
+    while size > PREVIEW_TITLE_MIN_SIZE && raster::Bitmap::text_width(text, size) > width {
+        size -= 1.0;
+    }
+
+    let mut text = text.to_string();
+    if raster::Bitmap::text_width(&text, size) > width {
+        while !text.is_empty() && raster::Bitmap::text_width(&format!("{}...", text), size) > width
+        {
+            text.pop();
+        }
+        text = format!("{}...", text.trim_end());
+    }
+
+    (text, size)
+}
+
+// Convert an Abstract Syntax Tree into a PNG image of its title and first bars, for sharing.
+pub fn ast_to_preview_png(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+) -> Vec<u8> {
+    let supersample = PREVIEW_SUPERSAMPLE as f32;
+    let mut bitmap = raster::Bitmap::new(
+        PREVIEW_WIDTH * PREVIEW_SUPERSAMPLE,
+        PREVIEW_HEIGHT * PREVIEW_SUPERSAMPLE,
+    );
+
+    let width = PREVIEW_WIDTH as f32 - PREVIEW_MARGIN * 2.0;
+
+    // Title across the top.
+    let title = title(ast).unwrap_or("Untitled".to_string());
+    let (title, size) = fit_text(&title, width);
+    let baseline = PREVIEW_MARGIN + 7.0 * size;
+    bitmap.text(
+        PREVIEW_MARGIN * supersample,
+        baseline * supersample,
+        &title,
+        size * supersample,
+    );
+
+    // Music fills the rest, centred.
+    let top = baseline + PREVIEW_MARGIN / 2.0;
+    let height = PREVIEW_HEIGHT as f32 - top - PREVIEW_MARGIN;
+
+    // The title is already there.
+    let typesetting = typeset::Typesetting {
+        header: false,
+        ..*typesetting
+    };
+    let drawing = ast_to_drawing(&incipit(ast, PREVIEW_BARS), &typesetting);
+    if drawing.width() > 0.0 && drawing.height() > 0.0 {
+        let scale = (width / drawing.width())
+            .min(height / drawing.height())
+            .min(PREVIEW_MAX_SCALE);
+        let left = PREVIEW_MARGIN + (width - drawing.width() * scale) / 2.0;
+        let top = top + (height - drawing.height() * scale) / 2.0;
+
+        drawing.rasterise_into(
+            &mut bitmap,
+            left * supersample,
+            top * supersample,
+            scale * supersample,
+        );
+    }
+
+    bitmap.shrink(PREVIEW_SUPERSAMPLE).to_png()
+}
+
+// Convert an Abstract Syntax Tree into a PNG image of the whole tune, for places that can't show
+// SVG.
+pub fn ast_to_png(
+    ast: &tune_ast_three::Tune,
+    typesetting: &typeset::Typesetting,
+    size: PngSize,
+) -> Vec<u8> {
+    let drawing = ast_to_drawing(ast, typesetting);
+    png_from_drawing(&drawing, size)
+}
+
+// Convert an Abstract Syntax Tree into a MIDI file, for playback.
+pub fn ast_to_midi(ast: &tune_ast_three::Tune, swing: midi::Swing) -> Vec<u8> {
+    midi::tune_to_midi(ast, swing)
+}
+
+// Rasterise a drawing at the requested size.
+pub fn png_from_drawing(drawing: &svg::Drawing, size: PngSize) -> Vec<u8> {
+    if drawing.width() <= 0.0 || drawing.height() <= 0.0 {
+        return raster::Bitmap::new(1, 1).to_png();
+    }
+
+    let mut scale = match size {
+        PngSize::Width(width) => width as f32 / drawing.width(),
+        // The drawing's own scale, e.g. from a %%scale directive, applies at any resolution.
+        PngSize::Dpi(dpi) => dpi as f32 / SVG_DPI * drawing.scale(),
+    };
+
+    // Long tunes at high resolution could be huge.
+    let pixels = drawing.width() * drawing.height() * scale * scale;
+    if pixels > PNG_MAX_PIXELS {
+        scale *= (PNG_MAX_PIXELS / pixels).sqrt();
+    }
+
+    let width = ((drawing.width() * scale).round() as u32).max(1);
+    let height = ((drawing.height() * scale).round() as u32).max(1);
+
+    let mut bitmap = raster::Bitmap::new(width * PNG_SUPERSAMPLE, height * PNG_SUPERSAMPLE);
+    drawing.rasterise_into(&mut bitmap, 0.0, 0.0, scale * PNG_SUPERSAMPLE as f32);
+
+    bitmap.shrink(PNG_SUPERSAMPLE).to_png()
+}
+
+// Convert an Abstract Syntax Tree into a monophonic sequence of MIDI pitches.
+pub fn ast_to_pitches(ast: &tune_ast_three::Tune) -> Vec<u8> {
+    pitch::PitchSequence::from_ast(ast).pitches
+}
+
+// MIDI pitches as they sound, with the key signature and accidentals applied.
+pub fn ast_to_sounding_pitches(ast: &tune_ast_three::Tune) -> Vec<u8> {
+    pitch::PitchSequence::from_ast_in_key(ast).pitches
+}
+
+// Semitones between each note and the next.
+pub fn pitches_to_intervals(pitches: &[u8]) -> Vec<i16> {
+    let pitches = pitch::PitchSequence::from_pitches(pitches);
+    pitch::IntervalSequence::from_pitch_sequence(&pitches).intervals
+}
+
+// Scale degree of each note, 1 to 7 from the key note, ignoring the octave.
+pub fn ast_to_degrees(ast: &tune_ast_three::Tune) -> Vec<u8> {
+    pitch::DegreeSequence::from_ast(ast).degrees
+}
+
+// Whether a line of ABC is a header field, e.g. "T:Kesh", rather than notes, e.g. "A:|".
+fn is_header_line(line: &str) -> bool {
+    let mut chars = line.trim_start().chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(field), Some(':'), next) => field.is_ascii_alphabetic() && next != Some('|'),
+        _ => false,
+    }
+}
+
+// Parse a fragment of ABC, such as a few bars pasted as a search query.
+// Header lines are optional. Without a key signature the notes are read in C, with a K: field
+// after any header lines there are, as it has to be the last of them.
+fn abc_snippet_to_ast(snippet: &str) -> Result<tune_ast_three::Tune, String> {
+    let has_key = snippet.lines().any(|line| line.trim_start().starts_with("K:"));
+    let content = if has_key {
+        snippet.to_string()
+    } else {
+        let header_length: usize = snippet
+            .split_inclusive('\n')
+            .take_while(|line| is_header_line(line))
+            .map(|line| line.len())
+            .sum();
+        let (header, body) = snippet.split_at(header_length);
+        let separator = if header.is_empty() || header.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        format!("{}{}K:C\n{}", header, separator, body)
+    };
+
+    let chars = content.chars().collect::<Vec<char>>();
+    let (num_errors, _, message) = abc_lexer::format_error_message_from_abc(&chars);
+    if num_errors > 0 {
+        return Err(message);
+    }
+
+    Ok(abc_to_ast(&content))
+}
+
+// Convert a fragment of ABC into MIDI pitches, as written, see `abc_snippet_to_ast`.
+pub fn abc_snippet_to_pitches(snippet: &str) -> Result<Vec<u8>, String> {
+    abc_snippet_to_ast(snippet).map(|ast| ast_to_pitches(&ast))
+}
+
+// Convert a fragment of ABC into MIDI pitches as they sound, with the key signature applied.
+pub fn abc_snippet_to_sounding_pitches(snippet: &str) -> Result<Vec<u8>, String> {
+    abc_snippet_to_ast(snippet).map(|ast| ast_to_sounding_pitches(&ast))
 }
 
 pub fn ast_to_features(ast: &tune_ast_three::Tune) -> Vec<(String, String)> {
     features::extract_all_features(ast)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abc_snippet_to_pitches_test() {
+        assert_eq!(
+            abc_snippet_to_pitches("GABc|").unwrap(),
+            abc_snippet_to_pitches("X:1\nK:C\nGABc|\n").unwrap(),
+            "Headers are optional."
+        );
+
+        assert_eq!(abc_snippet_to_pitches("GABc|").unwrap().len(), 4);
+        assert_eq!(
+            abc_snippet_to_pitches("X:1\nT:Kesh\nGABc|").unwrap(),
+            abc_snippet_to_pitches("GABc|").unwrap(),
+            "The key goes after the headers there are."
+        );
+        assert_eq!(abc_snippet_to_pitches("A:|B").unwrap().len(), 2);
+
+        assert!(
+            abc_snippet_to_pitches("GA[Bc|").is_err(),
+            "Errors in the ABC should be reported."
+        );
+    }
+
+    #[test]
+    fn incipit_test() {
+        let ast = abc_to_ast(
+            "X:1\nT:Jig\nM:6/8\nL:1/8\nK:G\nD|GAB cBA|\nBcd efg|\nabc|\n",
+        );
+        let incipit = incipit(&ast, 2);
+
+        assert_eq!(incipit.prelude.len(), ast.prelude.len());
+
+        // The pick-up doesn't count, and line breaks are removed.
+        assert_eq!(
+            incipit.voices[0]
+                .iter()
+                .filter(|x| matches!(x, l::T::Note(_)))
+                .count(),
+            13
+        );
+        assert!(incipit.voices[0].iter().all(|x| *x != l::T::Newline));
+        assert_eq!(incipit.voices[0].last(), Some(&l::T::SingleBar));
+
+        assert_eq!(title(&ast), Some("Jig".to_string()));
+    }
+
+    #[test]
+    fn headers_test() {
+        let ast =
+            abc_to_ast("X:1\nT:Jig\nC:Trad.\nR:jig\nM:6/8\nL:1/8\nK:G\nGAB cBA|\n");
+
+        assert_eq!(
+            headers(&ast),
+            vec![
+                ("Composer".to_string(), "Trad.".to_string()),
+                ("Rhythm".to_string(), "jig".to_string()),
+                ("Metre".to_string(), "6/8".to_string()),
+                ("Key".to_string(), "G Major".to_string()),
+            ],
+            "Titles, the tune number and note length aren't included."
+        );
+    }
+
+    #[test]
+    fn ast_to_incipit_svg_test() {
+        let ast = abc_to_ast(
+            "X:1\nT:Jig\nM:6/8\nL:1/8\nK:G\nGAB cBA|BcB AGF|\nGAB cBA|BcB AGF|\n",
+        );
+        let typesetting = typeset::Typesetting::new();
+
+        let incipit = ast_to_incipit_svg(&ast, &typesetting);
+        assert!(incipit.contains("viewBox"), "Incipits are scaled down.");
+        assert!(!incipit.contains("Jig"), "The title isn't shown.");
+        assert!(incipit.len() < ast_to_svg(&ast, &typesetting).len());
+    }
+
+    #[test]
+    fn fit_text_test() {
+        assert_eq!(fit_text("Jig", 1000.0), ("Jig".to_string(), PREVIEW_TITLE_MAX_SIZE));
+
+        let (text, size) = fit_text(&"The Long Title ".repeat(20), 1000.0);
+        assert_eq!(size, PREVIEW_TITLE_MIN_SIZE);
+        assert!(text.ends_with("..."));
+        assert!(raster::Bitmap::text_width(&text, size) <= 1000.0);
+    }
+
+    #[test]
+    fn png_size_test() {
+        assert_eq!(PngSize::parse(None, None), Ok(PngSize::Dpi(96)));
+        assert_eq!(PngSize::parse(Some("640"), None), Ok(PngSize::Width(640)));
+        assert_eq!(PngSize::parse(None, Some("300")), Ok(PngSize::Dpi(300)));
+
+        assert!(PngSize::parse(Some("640"), Some("300")).is_err());
+        assert!(PngSize::parse(Some("0"), None).is_err());
+        assert!(PngSize::parse(Some("100000"), None).is_err());
+        assert!(PngSize::parse(None, Some("x")).is_err());
+    }
+
+    #[test]
+    fn sequences_test() {
+        let ast = abc_to_ast("X:1\nK:G\nGAB F|\n");
+
+        assert_eq!(ast_to_pitches(&ast), vec![67, 69, 71, 65]);
+        assert_eq!(ast_to_sounding_pitches(&ast), vec![67, 69, 71, 66]);
+        assert_eq!(
+            pitches_to_intervals(&ast_to_sounding_pitches(&ast)),
+            vec![2, 2, -5]
+        );
+        assert_eq!(ast_to_degrees(&ast), vec![1, 2, 3, 7]);
+        assert!(pitches_to_intervals(&[]).is_empty());
+    }
+
+    #[test]
+    fn ast_to_text_test() {
+        let abc = "X:1\nT:Jig\nM:6/8\nL:1/8\nK:D\nA|:d2f a>fd|[1 e3 z2 A:|2 e3 e^c=c:|]\n";
+        let ast = abc_to_ast(abc);
+
+        assert_eq!(
+            ast_to_text(&ast),
+            "Jig
+Metre: 6/8
+Key: D Major
+
+Pick-up: A 4 quaver.
+Bar 1: start repeat, D 5 crotchet, F 5 quaver, A 5 dotted quaver, F 5 semiquaver, D 5 quaver.
+Bar 2: ending 1, E 5 dotted crotchet, rest crotchet, A 4 quaver, end repeat.
+Bar 3: ending 2, E 5 dotted crotchet, E 5 quaver, C sharp 5 quaver, C natural 5 quaver, \
+             end repeat, end.
+"
+        );
+
+        // Voices are described one after the other, and music after the last barline is a bar.
+        let abc = "X:1\nL:1/4\nK:C\nV:1 name=Fiddle\nc4|\nV:2\nC,2 z2\n";
+        let ast = abc_to_ast(abc);
+        let text = ast_to_text(&ast);
+        assert!(text.contains("Voice Fiddle\nBar 1: C 5 semibreve.\n"));
+        assert!(text.contains("Voice 2\nBar 1: C 3 minim, rest minim.\n"));
+    }
+
+    #[test]
+    fn abc_to_layout_test() {
+        let content = "X:1\nT:Reel\nK:G\nGAB ^c|\nd4|]";
+        let layout = abc_to_layout(content, &typeset::Typesetting::new());
+
+        assert_eq!(layout.lines.len(), 2);
+        let staves = layout
+            .lines
+            .iter()
+            .flat_map(|x| x.staff.iter())
+            .collect::<Vec<&typeset::StaveLayout>>();
+        assert!(staves[0].top < staves[0].bottom);
+        assert!(staves[0].bottom < staves[1].top);
+
+        // Notes are numbered across staves, and point back to the ABC.
+        let notes = staves
+            .iter()
+            .flat_map(|x| x.voices[0].iter())
+            .filter(|x| x.el_type == "note")
+            .collect::<Vec<&typeset::EntityLayout>>();
+        assert_eq!(
+            notes.iter().map(|x| x.note).collect::<Vec<Option<usize>>>(),
+            vec![Some(0), Some(1), Some(2), Some(3), Some(4)]
+        );
+        let text = |entity: &typeset::EntityLayout| {
+            content
+                .chars()
+                .skip(entity.start_char.unwrap())
+                .take(entity.end_char.unwrap() - entity.start_char.unwrap())
+                .collect::<String>()
+        };
+        assert_eq!(text(notes[3]).trim(), "^c");
+        assert_eq!(text(notes[4]).trim(), "d4");
+
+        // Higher notes are further up.
+        assert!(notes[1].y.unwrap() < notes[0].y.unwrap());
+        assert!(notes[1].x > notes[0].x);
+
+        let drawing = ast_to_drawing(
+            &abc_to_ast(content),
+            &typeset::Typesetting::new(),
+        );
+        assert_eq!(layout.width, drawing.width());
+        assert_eq!(layout.height, drawing.height());
+
+        // The JSON has abcjs's names.
+        let json = serde_json::to_value(&layout).unwrap();
+        let elements = &json["lines"][1]["staff"][0]["voices"][0];
+        let end_bar = elements
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["el_type"] == "bar")
+            .unwrap();
+        assert_eq!(end_bar["type"], "bar_thin_thick");
+        let note = elements
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["el_type"] == "note")
+            .unwrap();
+        assert_eq!(note["startChar"], notes[4].start_char.unwrap());
+
+        // Voices are typeset one after the other, however they're interleaved in the ABC.
+        let content = "X:1\nV:T\nV:B\nK:G\nGA|\nV:B\nB,|\nV:T\nc|\n";
+        let layout = abc_to_layout(content, &typeset::Typesetting::new());
+        let text = |entity: &typeset::EntityLayout| {
+            content
+                .chars()
+                .skip(entity.start_char.unwrap())
+                .take(entity.end_char.unwrap() - entity.start_char.unwrap())
+                .collect::<String>()
+        };
+        let notes = layout
+            .lines
+            .iter()
+            .flat_map(|x| x.staff.iter())
+            .flat_map(|x| x.voices[0].iter())
+            .filter(|x| x.el_type == "note")
+            .map(|x| text(x).trim().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(notes, vec!["G", "A", "c", "B,"]);
+    }
+
+    #[test]
+    fn error_markers_test() {
+        assert!(error_markers("X:1\nK:G\nGAB|\n").is_empty());
+
+        let markers = error_markers("X:1\nL:1/0\nK:G\nG)AB|\n");
+        assert_eq!(
+            markers
+                .iter()
+                .map(|x| x.note.clone())
+                .collect::<Vec<Option<(String, usize)>>>(),
+            vec![None, Some(("1".to_string(), 1))],
+            "Header errors aren't at a note, others are at the next one."
+        );
+        assert_eq!(
+            markers[1].message,
+            "I didn't expect to find the character ')' here."
+        );
+
+        let drawing = ast_to_drawing_with_errors(
+            &abc_to_ast("X:1\nK:G\nG)AB|\n"),
+            &typeset::Typesetting::new(),
+            &markers,
+        );
+        assert!(drawing.render().contains("class='error'"));
+
+        // Notes are counted within each voice, however the voices are interleaved.
+        let markers = error_markers("X:1\nV:T\nV:B\nK:G\nGA|\nV:B\nB)c|\nV:T\nd|\n");
+        assert_eq!(markers[0].note, Some(("B".to_string(), 1)));
+    }
+
+    #[test]
+    fn ast_to_png_test() {
+        let ast = abc_to_ast("X:1\nT:Jig\nM:6/8\nL:1/8\nK:G\nGAB cBA|\n");
+        let typesetting = typeset::Typesetting::new();
+
+        // The image width is at bytes 16 to 20, in the PNG header.
+        let width = |png: &Vec<u8>| {
+            png[16..20]
+                .iter()
+                .fold(0u32, |acc, byte| (acc << 8) | *byte as u32)
+        };
+
+        let png = ast_to_png(&ast, &typesetting, PngSize::Width(400));
+        assert_eq!(&png[1..4], b"PNG");
+        assert_eq!(width(&png), 400);
+
+        let drawing = ast_to_drawing(&ast, &typesetting);
+        let png = ast_to_png(&ast, &typesetting, PngSize::Dpi(192));
+        assert_eq!(width(&png), (drawing.width() * 2.0).round() as u32);
+
+        // Huge images are scaled down.
+        let ast = abc_to_ast(&format!("X:1\nK:G\n{}", "GAB cBA|\n".repeat(200)));
+        let png = ast_to_png(&ast, &typesetting, PngSize::Width(PNG_MAX_WIDTH));
+        assert!(width(&png) < PNG_MAX_WIDTH);
+    }
+}
@@ -3,43 +3,43 @@
 //! Plus structures for conducting searches and representing results.
 //! Results are JSON-serializable.
 //!
-//! Query syntax, presented as key-value from query string:
-//! Filters:
-//!  - these depend on the data
-//!
-//! Generators:
-//!  - all
-//!  - interval_ngram
-//!  - title
-//!  - degree_ngram
-//!  - interval_histogram
-//!  - degree_histogram
-//!
-//! Select:
-//!  - offset
-//!  - rows
-//!  - rollup
+//! Queries are built or parsed in `query`.
 
 use std::cmp::Ordering;
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::fmt::Debug;
+use std::hash::Hash;
 
 use abc_lexer as l;
+use config;
+use features;
 use pitch;
+use query;
+use query::{Generator, Query};
 use relations;
 use representations;
 use storage;
+use storage::TuneStore;
+use text;
 use tune_ast_three;
 
-use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::time::{Duration, Instant};
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
 
 // We think there will be about this many text terms.
 // The load factor of the VSM with real data should dermine this.
 // Tweak until the balance is right.
 const TEXT_SIZE: usize = 65432;
 
+// Composer, origin and rhythm headers have a smaller vocabulary than titles.
+const METADATA_TEXT_SIZE: usize = 16127;
+
 // We think there will be about this many features.
 // The number of features is small and in theory bounded.
 // We want matchines to be exact with no collisions.
@@ -47,22 +47,91 @@ const FEATURES_SIZE: usize = 512;
 
 const INTERVAL_TERM_SIZE: usize = 16127;
 
+// There are only 7 possible degrees, so fewer possible terms than for intervals.
+const DEGREE_TERM_SIZE: usize = 4099;
+
+// Each contour step is one of three, so there are 3^8 possible terms with the default window.
+const CONTOUR_TERM_SIZE: usize = 8191;
+
+// Orders results best first: highest score, then lowest tune ID, as results are sorted.
+// Scores are never negative, so the bits of the float order the same way as the float.
+fn rank(tune_id: usize, score: f32) -> (u32, Reverse<usize>) {
+    (score.to_bits(), Reverse(tune_id))
+}
+
 // Simple lightweight tune ID to weight for collecting results.
 #[derive(Debug)]
 pub struct ResultSet {
     // Tune id => weight.
     pub results: HashMap<usize, f32>,
+
+    // If set, only keep this many of the best results.
+    limit: Option<usize>,
+
+    // Ranks of the kept results with the worst on top, when there's a limit.
+    worst: BinaryHeap<Reverse<(u32, Reverse<usize>)>>,
+}
+
+impl Default for ResultSet {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ResultSet {
     pub fn new() -> ResultSet {
         ResultSet {
             results: HashMap::new(),
+            limit: None,
+            worst: BinaryHeap::new(),
+        }
+    }
+
+    // A result set that only keeps the best `limit` results.
+    pub fn with_limit(limit: Option<usize>) -> ResultSet {
+        ResultSet {
+            limit,
+            ..ResultSet::new()
         }
     }
 
     pub fn add(&mut self, tune_id: usize, score: f32) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                self.results.insert(tune_id, score);
+                return;
+            }
+        };
+
+        if limit == 0 || self.results.contains_key(&tune_id) {
+            return;
+        }
+
+        if self.results.len() >= limit {
+            match self.worst.peek() {
+                Some(Reverse(worst)) if rank(tune_id, score) > *worst => (),
+                _ => return,
+            }
+            if let Some(Reverse((_, Reverse(worst_id)))) = self.worst.pop() {
+                self.results.remove(&worst_id);
+            }
+        }
+
         self.results.insert(tune_id, score);
+        self.worst.push(Reverse(rank(tune_id, score)));
+    }
+
+    // When the set is full, a result needs a score above this to get in.
+    // Results with the same score only get in if they have a lower ID.
+    pub fn threshold(&self) -> Option<f32> {
+        match self.limit {
+            Some(limit) if self.results.len() >= limit => match self.worst.peek() {
+                Some(Reverse((score, _))) => Some(f32::from_bits(*score)),
+                None => Some(0.0),
+            },
+            _ => None,
+        }
     }
 
     // Return a sorted vec of (tune id, score).
@@ -87,84 +156,153 @@ impl ResultSet {
         self.results.len()
     }
 
+    // Keep only the results whose tune ID passes the test.
+    pub fn retain<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.results.retain(|id, _| f(*id));
+        self.worst.retain(|Reverse((_, Reverse(id)))| f(*id));
+    }
+
+    // Remove a result, e.g. the tune that a search for similar tunes started from.
+    pub fn remove(&mut self, tune_id: usize) {
+        self.results.remove(&tune_id);
+        self.worst
+            .retain(|Reverse((_, Reverse(id)))| *id != tune_id);
+    }
+
     // Filter results in this set by intersecting with the supplied filter.
     pub fn filter_by(&mut self, filter_set: &ResultSet) {
         self.results
             .retain(|&id, _| filter_set.results.contains_key(&id));
+        self.worst
+            .retain(|Reverse((_, Reverse(id)))| filter_set.results.contains_key(id));
+    }
+
+    // Reduce to a random sample of up to this many results, using reservoir sampling.
+    // The same seed and results always give the same sample.
+    pub fn sample(&self, size: usize, seed: u64) -> ResultSet {
+        // Visit in a stable order, as HashMap order isn't.
+        let mut ids: Vec<usize> = self.results.keys().cloned().collect();
+        ids.sort();
+
+        let mut seed_bytes = [0; 16];
+        for i in 0..8 {
+            seed_bytes[i] = (seed >> (i * 8)) as u8;
+            seed_bytes[i + 8] = !seed_bytes[i];
+        }
+        let mut rng = XorShiftRng::from_seed(seed_bytes);
+
+        let mut reservoir: Vec<usize> = Vec::with_capacity(size);
+        for (i, id) in ids.iter().enumerate() {
+            if i < size {
+                reservoir.push(*id);
+            } else {
+                let j = rng.gen_range(0, i + 1);
+                if j < size {
+                    reservoir[j] = *id;
+                }
+            }
+        }
+
+        let mut result = ResultSet::new();
+        for id in reservoir {
+            result.add(id, self.results[&id]);
+        }
+        result
     }
 }
 
-// A Generator supplies a weighted result set. Only one generator per result.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Generator {
-    // All tunes, weighted by ID.
-    All,
+// Options for which features to enable in the search engine. We don't always want all of them.
+pub struct SearchEngineFeatures {
+    pub index_text: bool,
+    pub index_melody_interval_term: bool,
+    pub index_melody_degree_term: bool,
+    pub index_melody_contour_term: bool,
 
-    Title(String),
+    // Also index the retrograde and inversion of each tune's intervals, as separate term spaces.
+    pub index_melody_transformations: bool,
 
-    // Search by interval n-gram similarity, weighted by similarity.
-    IntervalNGram(Vec<u8>),
+    // Features are needed for filters and facets. Without them, neither is available.
+    pub index_features: bool,
 
-    // Search by degree n-gram similarity, weighted by similarity.
-    // TODO not yet implemented.
-    DegreeNGram(Vec<u8>),
+    // Roll up results by cluster. When false, requests for rollup or grouping are ignored.
+    pub rollup: bool,
 
-    // Search by interval histogram similarity, weighted by similarity.
-    // TODO not yet implemented.
-    IntervalHistogram(Vec<f32>),
+    // Number of intervals in each melody term. Each size is indexed separately, and queries use
+    // the largest that fits. Transformations are only indexed with the largest.
+    pub interval_window_sizes: Vec<usize>,
 
-    // Search by degree histogram similarity, weighted by similarity.
-    // TODO not yet implemented.
-    DegreeHistogram(Vec<f32>),
-}
+    // Window sizes for the indexes of sounding intervals, see `pitch::IntervalPitches`. These are
+    // separate from the written ones, and there are none unless asked for.
+    pub sounding_interval_window_sizes: Vec<usize>,
 
-// A filter selects items in the result set.
-// All terms are ANDed.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Filter {
-    pub features: Vec<(String, String)>,
+    // Names of the feature extractors to run, see `features::FeatureRegistry`. All of them if None.
+    pub feature_extractors: Option<Vec<String>>,
 }
 
-impl Filter {
-    pub fn has_filters(&self) -> bool {
-        self.features.len() > 0
+impl SearchEngineFeatures {
+    // Every index, with the options set in the config, as the server uses.
+    pub fn from_config(config: &config::Config) -> SearchEngineFeatures {
+        SearchEngineFeatures {
+            index_text: true,
+            index_melody_interval_term: true,
+            index_melody_degree_term: true,
+            index_melody_contour_term: true,
+            index_melody_transformations: config.index_transformations,
+            index_features: config.facets,
+            rollup: config.rollup,
+            interval_window_sizes: config.interval_windows.clone(),
+            sounding_interval_window_sizes: config.sounding_interval_windows.clone(),
+            feature_extractors: config.feature_extractors.clone(),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Selection {
-    // Start at this index of the results.
-    pub offset: usize,
-
-    // Return only this many rows.
-    pub rows: usize,
+// Details of a tune worked out when it's indexed, so decorating results doesn't parse the ABC.
+struct TuneSummary {
+    titles: Vec<String>,
 
-    // Roll-up tunes based on their cluster.
-    // When true, return only the best tune per group.
-    pub rollup: bool,
+    // Key and mode worked out from the notes, e.g. "E-Minor".
+    detected_key: Option<String>,
 
-    // Include facets for all features.
-    pub facet: bool,
+    // Hash of the ABC without whitespace, see `storage::canonical_hash`.
+    canonical_hash: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Query {
-    pub generator: Generator,
-    pub filter: Filter,
-    pub selection: Selection,
+impl TuneSummary {
+    fn from_ast(ast: &tune_ast_three::Tune, canonical_hash: u64) -> TuneSummary {
+        TuneSummary {
+            canonical_hash,
+            titles: representations::titles(ast),
+            detected_key: pitch::detect_key(ast).map(|(pitch_class, mode)| {
+                format!("{}-{}", pitch_class, mode)
+            }),
+        }
+    }
 }
 
-const DEFAULT_ROWS: usize = 30;
-const MAX_ROWS: usize = 1000;
+// Features for a tune's provenance, e.g. ("source", "thesession.org").
+// Feature types from tune metadata, see `meta_features`.
+const META_FEATURE_TYPES: &[&str] = &["source", "licence"];
+
+fn meta_features(meta: &storage::TuneMeta) -> Vec<(String, String)> {
+    let mut features = vec![];
+    if let Some(source) = meta.source_name() {
+        features.push(("source".to_string(), source));
+    }
+    if let Some(ref licence) = meta.licence {
+        features.push(("licence".to_string(), licence.clone()));
+    }
+    features
+}
 
-// One octave above and below key note.
-const HISTOGRAM_LENGTH: usize = 25;
+// What a result is rolled up with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum RollupGroup {
+    // Group ID of a cluster of melodies that are 'the same' tune.
+    Cluster(usize),
 
-// Options for which features to enable in the search engine. We don't always want all of them.
-pub struct SearchEngineFeatures {
-    pub index_text: bool,
-    pub index_melody_interval_term: bool,
-    pub index_features: bool,
+    // Canonical hash of tunes that only differ in whitespace.
+    Exact(u64),
 }
 
 // A search engine.
@@ -177,284 +315,731 @@ pub struct SearchEngine {
     clusters: relations::Clusters,
 
     // ABCs are shared around threads.
-    pub abc_cache: storage::ReadOnlyCache,
+    pub abc_cache: Box<dyn storage::TuneStore>,
 
-    // Tune features in a binary VSM.
-    pub features_vsm: relations::FeaturesBinaryVSM,
+    // Tune features in a binary VSM, if enabled.
+    pub features_vsm: Option<relations::FeaturesBinaryVSM>,
 
-    // Interval window VSM for melody searching.
+    // Interval window VSMs for melody searching, one per window size, largest first.
     // TODO normalize this to the other nomenclature 0f interval / degree + histogram / ngram.
-    pub interval_term_vsm: relations::IntervalWindowBinaryVSM,
+    pub interval_term_vsms: Vec<relations::IntervalWindowBinaryVSM>,
+
+    // The same for sounding intervals, if enabled.
+    pub sounding_interval_term_vsms: Vec<relations::IntervalWindowBinaryVSM>,
+
+    // Scale degree window VSM for melody searching.
+    pub degree_term_vsm: relations::DegreeWindowBinaryVSM,
+
+    // Melodic contour window VSM, for searching by Parsons code.
+    pub contour_term_vsm: relations::ContourWindowBinaryVSM,
+
+    // Interval window VSMs for the retrograde and inversion of each tune, if enabled.
+    pub retrograde_term_vsm: Option<relations::IntervalWindowBinaryVSM>,
+    pub inversion_term_vsm: Option<relations::IntervalWindowBinaryVSM>,
 
     // Index of title text.
     pub text_vsm: relations::TextVSM,
 
+    // Indexes of other text headers, searched separately.
+    pub composer_vsm: relations::TextVSM,
+    pub origin_vsm: relations::TextVSM,
+    pub rhythm_vsm: relations::TextVSM,
+
+    // Titles by prefix, for autocomplete.
+    title_trie: text::TitleTrie,
+
+    // Titles and other details for decorating results, by tune ID.
+    summaries: HashMap<u32, TuneSummary>,
+
+    // Tunes by canonical hash, so those that only differ in whitespace are together, in order.
+    exact_duplicates: HashMap<u64, Vec<u32>>,
+
+    // Provenance from the metadata file, by tune ID.
+    meta: HashMap<u32, storage::TuneMeta>,
+
     // Cache of all known features.
     all_features_cached: HashMap<String, Vec<String>>,
 
+    // Is rollup by cluster enabled for this engine?
+    rollup: bool,
+
+    // Which indexes are enabled, for tunes added later.
+    features: SearchEngineFeatures,
+
+    // Extractors for the features index.
+    feature_registry: features::FeatureRegistry,
+
     max_tune_id: u32,
 }
 
+// Summary of the corpus, for the API and home page.
+// An empty corpus means the tunecache hasn't been built yet.
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub num_tunes: usize,
+    pub max_tune_id: u32,
+    pub num_clusters: usize,
+    pub empty: bool,
+}
+
+// Results of a search.
+pub type SearchResults = (
+    // Total results.
+    usize,
+    // Result set (may be cut down by dedupe).
+    usize,
+    // Facet for (whole) result set.
+    Option<HashMap<String, Vec<(String, u32)>>>,
+    // Page of results.
+    Vec<DecoratedResult>,
+);
+
+// When a search should give up, if ever.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn none() -> Deadline {
+        Deadline(None)
+    }
+
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline(Some(Instant::now() + budget))
+    }
+
+    pub fn has_passed(&self) -> bool {
+        match self.0 {
+            Some(at) => Instant::now() >= at,
+            None => false,
+        }
+    }
+}
+
+// How far a search engine has got with indexing, shared so another thread can report it.
+#[derive(Default, Debug)]
+pub struct IndexProgress {
+    indexed: AtomicUsize,
+    total: AtomicUsize,
+
+    // Set once the engine is built and in use.
+    done: AtomicBool,
+
+    // Set if building the engine failed, so it never will be done.
+    failed: AtomicBool,
+}
+
+impl IndexProgress {
+    // Nothing indexed yet, out of `total` tunes.
+    pub fn new(total: usize) -> IndexProgress {
+        IndexProgress {
+            total: AtomicUsize::new(total),
+            ..IndexProgress::default()
+        }
+    }
+
+    // Tunes indexed so far.
+    pub fn indexed(&self) -> usize {
+        self.indexed.load(atomic::Ordering::SeqCst)
+    }
+
+    // Tunes to index in all.
+    pub fn total(&self) -> usize {
+        self.total.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn finish(&self) {
+        self.indexed.store(self.total(), atomic::Ordering::SeqCst);
+        self.done.store(true, atomic::Ordering::SeqCst);
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn fail(&self) {
+        self.failed.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
 impl SearchEngine {
     pub fn new(
-        cache_path: PathBuf,
+        abc_cache: Box<dyn TuneStore>,
         clusters: relations::Clusters,
         features: SearchEngineFeatures,
+        vocabulary: text::Vocabulary,
     ) -> SearchEngine {
-        // TODO build synonyms and development tools for features, specifically Rhythm.
+        SearchEngine::new_with_progress(
+            abc_cache,
+            clusters,
+            features,
+            vocabulary,
+            &IndexProgress::default(),
+        )
+    }
 
-        let scanner = storage::CacheScanner::new(cache_path.clone());
-        let max_tune_id = scanner.iter().map(|x| x.tune_id).max().unwrap_or(0);
+    // As `new`, counting tunes in `progress` as they're indexed.
+    pub fn new_with_progress(
+        abc_cache: Box<dyn TuneStore>,
+        clusters: relations::Clusters,
+        features: SearchEngineFeatures,
+        vocabulary: text::Vocabulary,
+        progress: &IndexProgress,
+    ) -> SearchEngine {
+        progress
+            .total
+            .store(abc_cache.num_tunes(), atomic::Ordering::SeqCst);
 
-        // Melodic index.
-        let mut interval_term_vsm =
-            relations::IntervalWindowBinaryVSM::new(INTERVAL_TERM_SIZE, max_tune_id as usize);
+        if abc_cache.num_tunes() == 0 {
+            warn!("No tunes indexed. Run `abctool scan` to build the tunecache from ABC files.");
+        }
 
-        // Feature index.
-        let mut features_vsm =
-            relations::FeaturesBinaryVSM::new(FEATURES_SIZE, max_tune_id as usize);
+        // Clusters from a different generation of the tunecache would roll up the wrong tunes.
+        let clusters = match check_generation(&*abc_cache, &clusters) {
+            Err(message) if features.rollup => {
+                error!("{} Rollup is disabled until matching clusters are loaded.", message);
+                relations::Clusters::new()
+            }
+            _ => clusters,
+        };
 
-        // Title text index.
-        let mut text_vsm = relations::TextVSM::new(TEXT_SIZE, max_tune_id as usize);
+        let tunes = abc_cache.iter();
+        let max_tune_id = abc_cache.max_id();
+        let mut engine =
+            SearchEngine::with_capacity(abc_cache, clusters, features, vocabulary, max_tune_id);
 
-        for (cnt, entry) in scanner.iter().enumerate() {
+        for (cnt, mut entry) in tunes.enumerate() {
             if (cnt % 1000) == 0 {
-                eprintln!("Indexing {}...", cnt);
+                debug!("Indexing {}...", cnt);
             }
-            let ast = representations::abc_to_ast(&entry.content);
+            let canonical_hash = engine
+                .abc_cache
+                .get_canonical_hash(entry.tune_id)
+                .unwrap_or_else(|| storage::canonical_hash(&entry.content));
+            engine.index_tune(entry.tune_id as usize, canonical_hash, entry.ast());
+            progress.indexed.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+        info!(
+            "Indexed all tunes. Groups of tunes that only differ in whitespace: {}.",
+            engine
+                .exact_duplicates
+                .values()
+                .filter(|tune_ids| tune_ids.len() > 1)
+                .count()
+        );
 
-            // Extract features, insert into VSM.
-            if features.index_features {
-                let features = representations::ast_to_features(&ast);
-                for (feature_type, feature_value) in features {
-                    features_vsm.add(entry.tune_id as usize, feature_type, feature_value);
-                }
-            }
+        // Exact term lists grow as tunes are indexed, so trim them now they're complete.
+        let mut exact_heap_bytes = 0;
+        for vsm in engine
+            .interval_term_vsms
+            .iter_mut()
+            .chain(engine.sounding_interval_term_vsms.iter_mut())
+        {
+            exact_heap_bytes += compact(&mut vsm.vsm);
+        }
+        if let Some(ref mut vsm) = engine.retrograde_term_vsm {
+            exact_heap_bytes += compact(&mut vsm.vsm);
+        }
+        if let Some(ref mut vsm) = engine.inversion_term_vsm {
+            exact_heap_bytes += compact(&mut vsm.vsm);
+        }
+        if let Some(ref mut vsm) = engine.features_vsm {
+            exact_heap_bytes += compact(&mut vsm.vsm);
+        }
+        exact_heap_bytes += compact(&mut engine.degree_term_vsm.vsm);
+        exact_heap_bytes += compact(&mut engine.contour_term_vsm.vsm);
+        exact_heap_bytes += compact(&mut engine.text_vsm.vsm);
+        exact_heap_bytes += compact(&mut engine.composer_vsm.vsm);
+        exact_heap_bytes += compact(&mut engine.origin_vsm.vsm);
+        exact_heap_bytes += compact(&mut engine.rhythm_vsm.vsm);
+        debug!("Exact term lists: {} bytes", exact_heap_bytes);
+
+        let (distinct_terms, vector_width, load_factor) = engine.text_vsm.vsm.load_factor();
+        debug!(
+            "Text: distinct_terms: {}, vector_width: {}, load_factor: {})",
+            distinct_terms, vector_width, load_factor
+        );
 
-            // Extract title text, insert into VSM.
-            if features.index_text {
-                let titles = ast.prelude.iter().filter_map(|x| match x {
-                    l::T::Title(x) => Some((*x).clone()),
-                    _ => None,
-                });
+        // Keep a copy of all known features.
+        engine.cache_features();
 
-                for title in titles {
-                    text_vsm.add(entry.tune_id as usize, title);
-                }
-            }
+        info!("Done!");
+        engine
+    }
 
-            // Melodic index.
-            if features.index_melody_interval_term {
-                let pitches = pitch::PitchSequence::from_ast(&ast);
-                let intervals = pitch::IntervalSequence::from_pitch_sequence(&pitches);
-                interval_term_vsm.add(entry.tune_id as usize, &intervals.intervals);
-            }
+    // A search engine with empty indexes. It can fetch tunes by ID but doesn't find any, e.g. to
+    // serve tunes while the real one is built. The indexes don't have room for any tunes, so it
+    // doesn't take up the memory of a full-size one alongside it.
+    pub fn unindexed(
+        abc_cache: Box<dyn TuneStore>,
+        clusters: relations::Clusters,
+        features: SearchEngineFeatures,
+        vocabulary: text::Vocabulary,
+    ) -> SearchEngine {
+        SearchEngine::with_capacity(abc_cache, clusters, features, vocabulary, 0)
+    }
+
+    // A search engine with empty indexes, with room for tunes up to `max_tune_id`.
+    fn with_capacity(
+        abc_cache: Box<dyn TuneStore>,
+        clusters: relations::Clusters,
+        features: SearchEngineFeatures,
+        vocabulary: text::Vocabulary,
+        max_tune_id: u32,
+    ) -> SearchEngine {
+        // TODO build synonyms and development tools for features, specifically Rhythm.
+
+
+        // Melodic indexes, largest window first.
+        let mut window_sizes = features.interval_window_sizes.clone();
+        if window_sizes.is_empty() {
+            window_sizes.push(relations::DEFAULT_INTERVAL_WINDOW_SIZE);
         }
-        eprintln!("Indexed all tunes.");
+        window_sizes.sort_by(|a, b| b.cmp(a));
+        window_sizes.dedup();
+        let largest_window_size = window_sizes[0];
 
-        let (distinct_terms, vector_width, load_factor) = text_vsm.vsm.load_factor();
-        eprintln!(
-            "Text: distinct_terms: {}, vector_width: {}, load_factor: {})",
-            distinct_terms, vector_width, load_factor
+        let interval_term_vsms: Vec<relations::IntervalWindowBinaryVSM> = window_sizes
+            .iter()
+            .map(|window_size| {
+                relations::IntervalWindowBinaryVSM::new(
+                    INTERVAL_TERM_SIZE,
+                    max_tune_id as usize,
+                    *window_size,
+                )
+            }).collect();
+
+        let mut sounding_window_sizes = features.sounding_interval_window_sizes.clone();
+        sounding_window_sizes.sort_by(|a, b| b.cmp(a));
+        sounding_window_sizes.dedup();
+
+        let sounding_interval_term_vsms: Vec<relations::IntervalWindowBinaryVSM> =
+            sounding_window_sizes
+                .iter()
+                .map(|window_size| {
+                    relations::IntervalWindowBinaryVSM::new(
+                        INTERVAL_TERM_SIZE,
+                        max_tune_id as usize,
+                        *window_size,
+                    )
+                }).collect();
+
+        let degree_term_vsm = relations::DegreeWindowBinaryVSM::new(
+            DEGREE_TERM_SIZE,
+            max_tune_id as usize,
+            relations::DEFAULT_DEGREE_WINDOW_SIZE,
         );
 
-        // Keep a copy of all known features.
-        let all_features_cached = features_vsm.all_features();
+        let contour_term_vsm = relations::ContourWindowBinaryVSM::new(
+            CONTOUR_TERM_SIZE,
+            max_tune_id as usize,
+            relations::DEFAULT_CONTOUR_WINDOW_SIZE,
+        );
+
+        // Melodic transformation indexes.
+        let (retrograde_term_vsm, inversion_term_vsm) =
+            if features.index_melody_transformations {
+                (
+                    Some(relations::IntervalWindowBinaryVSM::new(
+                        INTERVAL_TERM_SIZE,
+                        max_tune_id as usize,
+                        largest_window_size,
+                    )),
+                    Some(relations::IntervalWindowBinaryVSM::new(
+                        INTERVAL_TERM_SIZE,
+                        max_tune_id as usize,
+                        largest_window_size,
+                    )),
+                )
+            } else {
+                (None, None)
+            };
+
+        // Feature index.
+        let feature_registry = match features.feature_extractors {
+            Some(ref names) => {
+                features::FeatureRegistry::with_names(names).unwrap_or_else(|message| {
+                    error!("{} Using all feature extractors.", message);
+                    features::FeatureRegistry::built_in()
+                })
+            }
+            None => features::FeatureRegistry::built_in(),
+        };
+
+        let features_vsm = if features.index_features {
+            Some(relations::FeaturesBinaryVSM::new(
+                FEATURES_SIZE,
+                max_tune_id as usize,
+            ))
+        } else {
+            None
+        };
+
+        // Title text index.
+        let text_vsm =
+            relations::TextVSM::new(TEXT_SIZE, max_tune_id as usize, vocabulary.clone());
 
-        // Now build a cache for future access to ABCs.
-        eprintln!("Building file offset index...");
-        let abc_cache = storage::ReadOnlyCache::new(cache_path).unwrap();
+        // Other text header indexes.
+        let composer_vsm =
+            relations::TextVSM::new(METADATA_TEXT_SIZE, max_tune_id as usize, vocabulary.clone());
+        let origin_vsm =
+            relations::TextVSM::new(METADATA_TEXT_SIZE, max_tune_id as usize, vocabulary.clone());
+        let rhythm_vsm =
+            relations::TextVSM::new(METADATA_TEXT_SIZE, max_tune_id as usize, vocabulary);
 
-        eprintln!("Done!");
         SearchEngine {
             clusters,
             features_vsm,
             text_vsm,
-            all_features_cached,
+            composer_vsm,
+            origin_vsm,
+            rhythm_vsm,
+            title_trie: text::TitleTrie::new(),
+            summaries: HashMap::new(),
+            exact_duplicates: HashMap::new(),
+            meta: HashMap::new(),
+            all_features_cached: HashMap::new(),
+            rollup: features.rollup,
+            features,
+            feature_registry,
             abc_cache,
-            interval_term_vsm,
+            interval_term_vsms,
+            sounding_interval_term_vsms,
+            degree_term_vsm,
+            contour_term_vsm,
+            retrograde_term_vsm,
+            inversion_term_vsm,
             max_tune_id,
         }
     }
 
-    fn parse_filter(&self, params: &Vec<(String, String)>) -> Result<Filter, String> {
-        // The syntax depends on the features we've extracted from the corpus. Whilst the set of
-        // feature types is hard-coded, it's best to make the parsing data-driven. This couples the
-        // search to the present corpus not the code.
+    // Add a tune's terms to the indexes that are enabled.
+    fn index_tune(&mut self, tune_id: usize, canonical_hash: u64, ast: &tune_ast_three::Tune) {
+        self.summaries
+            .insert(tune_id as u32, TuneSummary::from_ast(ast, canonical_hash));
 
-        // Filter and take a copy of those filter key value pairs that correspond to known features.
-        let relevant: Vec<(String, String)> = params
-            .iter()
-            .filter_map(|(k, v)| {
-                if self.all_features_cached.contains_key(k) {
-                    Some((k.to_string(), v.to_string()))
-                } else {
-                    None
-                }
-            }).collect();
+        let duplicates = self.exact_duplicates.entry(canonical_hash).or_default();
+        if let Err(position) = duplicates.binary_search(&(tune_id as u32)) {
+            duplicates.insert(position, tune_id as u32);
+        }
 
-        Ok(Filter { features: relevant })
-    }
+        // Extract features, insert into VSM.
+        if let Some(ref mut vsm) = self.features_vsm {
+            let features = self.feature_registry.extract(ast);
+            for (feature_type, feature_value) in features {
+                vsm.add(tune_id, feature_type, feature_value);
+            }
 
-    fn parse_bool(
-        &self,
-        params: &HashMap<String, String>,
-        param_name: &str,
-        default: bool,
-    ) -> Result<bool, String> {
-        match params.get(param_name) {
-            Some(val) => match val.as_ref() {
-                // HTML forms use on/off . API usage is more conventional true/false.
-                "true" | "on" => Ok(true),
-                "false" | "off" => Ok(false),
-                _ => Err(format!("Invalid value for '{}'", &param_name).to_string()),
-            },
-            _ => Ok(default),
+            if let Some(meta) = self.meta.get(&(tune_id as u32)) {
+                for (feature_type, feature_value) in meta_features(meta) {
+                    vsm.add(tune_id, feature_type, feature_value);
+                }
+            }
         }
-    }
 
-    fn parse_selection(&self, params: &HashMap<String, String>) -> Result<Selection, String> {
-        let offset: usize = match params.get("offset") {
-            Some(v) => match v.parse::<usize>() {
-                Ok(v) => v,
-                Err(_) => return Err("Invalid value for 'offset'.".to_string()),
-            },
-            _ => 0,
-        };
+        // Extract title and other header text, insert into VSMs.
+        if self.features.index_text {
+            for token in ast.prelude.iter() {
+                match token {
+                    l::T::Title(x) => {
+                        self.text_vsm.add(tune_id, x.clone());
+                        self.title_trie.add(tune_id, x);
+                    }
+                    l::T::Composer(x) => self.composer_vsm.add(tune_id, x.clone()),
+                    l::T::Origin(x) => self.origin_vsm.add(tune_id, x.clone()),
+                    l::T::Rhythm(x) => self.rhythm_vsm.add(tune_id, x.clone()),
+                    _ => (),
+                }
+            }
+        }
 
-        let rows: usize = match params.get("rows") {
-            Some(v) => match v.parse::<usize>() {
-                Ok(v) if (v <= MAX_ROWS) => v,
-                Ok(_v) => return Err("Too many rows requested".to_string()),
-                Err(_) => return Err("Invalid value for 'rows'".to_string()),
-            },
-            _ => DEFAULT_ROWS,
-        };
+        // Melodic index.
+        if self.features.index_melody_interval_term
+            || self.features.index_melody_transformations
+            || self.features.index_melody_contour_term
+        {
+            let pitches = pitch::PitchSequence::from_ast(ast);
+            let intervals = pitch::IntervalSequence::from_pitch_sequence(&pitches);
+
+            if self.features.index_melody_contour_term {
+                let parsons = pitch::ParsonsCode::from_interval_sequence(&intervals);
+                self.contour_term_vsm.add(tune_id, &parsons.contour);
+            }
 
-        let rollup = match self.parse_bool(&params, "rollup", true) {
-            Ok(val) => val,
-            Err(x) => return Err(x),
-        };
+            if self.features.index_melody_interval_term {
+                for vsm in self.interval_term_vsms.iter_mut() {
+                    vsm.add(tune_id, &intervals.intervals);
+                }
+            }
 
-        let facet = match self.parse_bool(&params, "facet", true) {
-            Ok(val) => val,
-            Err(x) => return Err(x),
-        };
+            if let Some(ref mut vsm) = self.retrograde_term_vsm {
+                vsm.add(tune_id, &intervals.retrograde().intervals);
+            }
 
-        Ok(Selection {
-            offset,
-            rows,
-            rollup,
-            facet,
-        })
-    }
+            if let Some(ref mut vsm) = self.inversion_term_vsm {
+                vsm.add(tune_id, &intervals.inversion().intervals);
+            }
+        }
 
-    fn parse_generator(&self, params: &HashMap<String, String>) -> Result<Generator, String> {
-        // This argument is given as absolute pitches, at least for now.
-        // Would be more consistent to convert it to intervals prior to querying API perhaps...
+        if self.features.index_melody_interval_term && !self.sounding_interval_term_vsms.is_empty()
+        {
+            let intervals =
+                pitch::IntervalSequence::from_ast(ast, pitch::IntervalPitches::Sounding);
+            for vsm in self.sounding_interval_term_vsms.iter_mut() {
+                vsm.add(tune_id, &intervals.intervals);
+            }
+        }
 
-        match params.get("title") {
-            Some(val) if val.len() > 0 => return Ok(Generator::Title(val.to_string())),
-            _ => (),
+        if self.features.index_melody_degree_term {
+            let degrees = pitch::DegreeSequence::from_ast(ast);
+            self.degree_term_vsm.add(tune_id, &degrees.degrees);
         }
+    }
 
-        if let Some(val) = params.get("interval_ngram") {
-            match val.split(",").map(|s| s.parse::<u8>()).collect() {
-                Ok(value) => return Ok(Generator::IntervalNGram(value)),
-                Err(_) => return Err("Invalid value given for 'interval_ngram'".to_string()),
-            }
+    // Make room in the indexes for tunes up to this ID.
+    fn grow(&mut self, top_id: u32) {
+        if top_id <= self.max_tune_id {
+            return;
         }
 
-        if let Some(val) = params.get("degree_ngram") {
-            match val.split(",").map(|s| s.parse::<u8>()).collect() {
-                Ok(value) => return Ok(Generator::DegreeNGram(value)),
-                Err(_) => return Err("Invalid value given for 'degree_ngram'".to_string()),
-            }
+        let top_id_usize = top_id as usize;
+        for vsm in self
+            .interval_term_vsms
+            .iter_mut()
+            .chain(self.sounding_interval_term_vsms.iter_mut())
+        {
+            vsm.vsm.grow(top_id_usize);
+        }
+        if let Some(ref mut vsm) = self.retrograde_term_vsm {
+            vsm.vsm.grow(top_id_usize);
+        }
+        if let Some(ref mut vsm) = self.inversion_term_vsm {
+            vsm.vsm.grow(top_id_usize);
+        }
+        if let Some(ref mut vsm) = self.features_vsm {
+            vsm.vsm.grow(top_id_usize);
         }
+        self.degree_term_vsm.vsm.grow(top_id_usize);
+        self.contour_term_vsm.vsm.grow(top_id_usize);
+        self.text_vsm.vsm.grow(top_id_usize);
+        self.composer_vsm.vsm.grow(top_id_usize);
+        self.origin_vsm.vsm.grow(top_id_usize);
+        self.rhythm_vsm.vsm.grow(top_id_usize);
+
+        self.max_tune_id = top_id;
+    }
 
-        if let Some(val) = params.get("interval_histogram") {
-            let result: Result<Vec<_>, _> = val.split(",").map(|s| s.parse::<f32>()).collect();
-            match result {
-                Ok(value) => {
-                    if value.len() == HISTOGRAM_LENGTH {
-                        return Ok(Generator::IntervalHistogram(value));
-                    } else {
-                        return Err(format!(
-                            "Invalid length for 'interval_histogram'. Must be exactly {}",
-                            HISTOGRAM_LENGTH
-                        ));
-                    }
+    // Remove a tune from all the indexes.
+    fn unindex_tune(&mut self, tune_id: usize) {
+        for vsm in self
+            .interval_term_vsms
+            .iter_mut()
+            .chain(self.sounding_interval_term_vsms.iter_mut())
+        {
+            vsm.vsm.remove(tune_id);
+        }
+        if let Some(ref mut vsm) = self.retrograde_term_vsm {
+            vsm.vsm.remove(tune_id);
+        }
+        if let Some(ref mut vsm) = self.inversion_term_vsm {
+            vsm.vsm.remove(tune_id);
+        }
+        if let Some(ref mut vsm) = self.features_vsm {
+            vsm.vsm.remove(tune_id);
+        }
+        self.degree_term_vsm.vsm.remove(tune_id);
+        self.contour_term_vsm.vsm.remove(tune_id);
+        self.text_vsm.vsm.remove(tune_id);
+        self.composer_vsm.vsm.remove(tune_id);
+        self.origin_vsm.vsm.remove(tune_id);
+        self.rhythm_vsm.vsm.remove(tune_id);
+        self.title_trie.remove(tune_id);
+
+        if let Some(summary) = self.summaries.remove(&(tune_id as u32)) {
+            if let Some(duplicates) = self.exact_duplicates.get_mut(&summary.canonical_hash) {
+                duplicates.retain(|x| *x != tune_id as u32);
+                if duplicates.is_empty() {
+                    self.exact_duplicates.remove(&summary.canonical_hash);
                 }
-                Err(_) => return Err("Invalid value given for 'interval_histogram'".to_string()),
             }
         }
+    }
 
-        if let Some(val) = params.get("degree_histogram") {
-            let result: Result<Vec<_>, _> = val.split(",").map(|s| s.parse::<f32>()).collect();
-            match result {
-                Ok(value) => if value.len() == HISTOGRAM_LENGTH {
-                    return Ok(Generator::DegreeHistogram(value));
-                } else {
-                    return Err(format!(
-                        "Invalid length for 'interval_histogram'. Must be exactly {}",
-                        HISTOGRAM_LENGTH
-                    ));
-                },
-                Err(_) => return Err("Invalid value given for 'interval_histogram'".to_string()),
+    // Set the provenance of tunes, from the metadata file. Their source and licence are indexed as
+    // features, so they can be filtered and faceted.
+    pub fn set_meta(&mut self, meta: HashMap<u32, storage::TuneMeta>) {
+        if let Some(ref mut vsm) = self.features_vsm {
+            for (tune_id, tune_meta) in meta.iter() {
+                if !self.summaries.contains_key(tune_id) {
+                    continue;
+                }
+                for (feature_type, feature_value) in meta_features(tune_meta) {
+                    vsm.add(*tune_id as usize, feature_type, feature_value);
+                }
             }
         }
 
-        Ok(Generator::All)
+        info!("Loaded provenance for {} tunes.", meta.len());
+        self.meta = meta;
+        self.cache_features();
     }
 
-    pub fn parse_query(&self, params: Vec<(String, String)>) -> Result<Query, String> {
-        eprintln!("Search query: {:?}", &params);
-
-        let params_map: HashMap<_, _> = params.clone().into_iter().collect();
+    // Provenance of a tune, if the metadata file has any.
+    pub fn meta(&self, tune_id: u32) -> Option<&storage::TuneMeta> {
+        self.meta.get(&tune_id)
+    }
 
-        let filter = match self.parse_filter(&params) {
-            Ok(filter) => filter,
-            Err(message) => return Err(message),
+    fn cache_features(&mut self) {
+        self.all_features_cached = match self.features_vsm {
+            Some(ref vsm) => vsm.all_features(),
+            None => HashMap::new(),
         };
+    }
 
-        let selection = match self.parse_selection(&params_map) {
-            Ok(selection) => selection,
-            Err(message) => return Err(message),
-        };
+    // Titles starting with the prefix, most common first, with the number of tunes with each.
+    // Empty if text isn't indexed.
+    pub fn autocomplete(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        self.title_trie.complete(prefix, limit)
+    }
 
-        let generator = match self.parse_generator(&params_map) {
-            Ok(generator) => generator,
-            Err(message) => return Err(message),
-        };
+    // Add a new tune to the tunecache and the indexes, returning its ID.
+    // The ID is the next after the largest one in use. The tune should already have been checked.
+    pub fn add_tune(&mut self, content: &str) -> Result<u32, String> {
+        let tune_id = self
+            .max_tune_id
+            .checked_add(1)
+            .filter(|tune_id| !storage::is_reserved_id(*tune_id))
+            .ok_or_else(|| "No more tune IDs available.".to_string())?;
+
+        self.abc_cache.put(tune_id, content)?;
+        self.grow(tune_id);
+        self.reindex_tune(tune_id)?;
+
+        info!("Added tune {}", tune_id);
+        Ok(tune_id)
+    }
 
-        Ok(Query {
-            filter,
-            selection,
-            generator,
-        })
+    // Replace an existing tune's ABC in the tunecache, and index it again.
+    pub fn replace_tune(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        if self.abc_cache.get_hash(tune_id).is_none() {
+            return Err(format!("There's no tune {}.", tune_id));
+        }
+
+        self.abc_cache.put(tune_id, content)?;
+        self.reindex_tune(tune_id)?;
+
+        info!("Replaced tune {}", tune_id);
+        Ok(())
+    }
+
+    // Index a tune again from its ABC in the tunecache, replacing whatever was indexed before.
+    pub fn reindex_tune(&mut self, tune_id: u32) -> Result<(), String> {
+        let content = self
+            .abc_cache
+            .get(tune_id)
+            .ok_or_else(|| format!("There's no tune {}.", tune_id))?;
+
+        self.unindex_tune(tune_id as usize);
+        let ast = representations::abc_to_ast(&content);
+        let canonical_hash = self
+            .abc_cache
+            .get_canonical_hash(tune_id)
+            .unwrap_or_else(|| storage::canonical_hash(&content));
+        self.index_tune(tune_id as usize, canonical_hash, &ast);
+        self.cache_features();
+
+        Ok(())
+    }
+
+    // What this engine has indexed, which decides what a parsed query can ask for.
+    pub fn capabilities(&self) -> query::Capabilities<'_> {
+        query::Capabilities {
+            features: &self.all_features_cached,
+            feature_types: self
+                .feature_registry
+                .feature_types()
+                .into_iter()
+                .chain(META_FEATURE_TYPES.iter().cloned())
+                .collect(),
+            rollup: self.rollup,
+            facet: self.features_vsm.is_some(),
+            transforms: self.retrograde_term_vsm.is_some() && self.inversion_term_vsm.is_some(),
+            contour_window_size: self.contour_term_vsm.window_size(),
+            interval_window_size: self.interval_term_vsm_for(0).window_size(),
+            sounding_interval_window_size: self
+                .sounding_interval_term_vsm_for(0)
+                .map(|vsm| vsm.window_size()),
+        }
+    }
+
+    pub fn parse_query(&self, params: Vec<(String, String)>) -> Result<Query, query::QueryError> {
+        info!("Search query: {:?}", &params);
+        query::parse_query(&params, &self.capabilities())
+    }
+
+    // Search for tunes like an existing tune.
+    pub fn parse_similar_query(
+        &self,
+        tune_id: usize,
+        params: Vec<(String, String)>,
+    ) -> Result<Query, query::QueryError> {
+        info!("Similar query: {} {:?}", tune_id, &params);
+        query::parse_similar_query(tune_id, &params, &self.capabilities())
+    }
+
+    // Pick one tune matching the filters in the params, chosen by the seed.
+    pub fn parse_pick_query(
+        &self,
+        params: Vec<(String, String)>,
+        seed: u64,
+    ) -> Result<Query, query::QueryError> {
+        info!("Pick query: {:?} {}", &params, seed);
+        query::parse_pick_query(&params, seed, &self.capabilities())
+    }
+
+    // Build a melody query from a snippet of ABC, e.g. a few bars.
+    pub fn parse_abc_query(
+        &self,
+        snippet: &str,
+        params: Vec<(String, String)>,
+    ) -> Result<Query, query::QueryError> {
+        info!("Search ABC query: {:?} {:?}", snippet, &params);
+        query::parse_abc_query(snippet, &params, &self.capabilities())
+    }
+
+    pub fn search(&self, query: &Query) -> SearchResults {
+        self.search_before(query, Deadline::none())
+            .expect("A search without a deadline always finishes.")
     }
 
-    pub fn search(
-        &mut self,
-        query: &Query,
-    ) -> (
-        // Total results.
-        usize,
-        // Result set (may be cut down by dedupe).
-        usize,
-        // Facet for (whole) result set.
-        Option<HashMap<String, Vec<(String, u32)>>>,
-        // Page of results.
-        Vec<DecoratedResult>,
-    ) {
+    // Search, giving up if the deadline passes first. The deadline is checked between the steps of
+    // the search, while the indexes are scanned, and between results as they're decorated, so a
+    // search only overruns it by as long as one of those takes, e.g. scoring a thousand tunes.
+    pub fn search_before(&self, query: &Query, deadline: Deadline) -> Option<SearchResults> {
+        // If nothing needs the whole result set, only find the results up to the end of the page.
+        let limit = if query.selection.total
+            || query.selection.facet
+            || query.selection.rollup
+            || query.selection.group
+            || query.filter.has_filters()
+        {
+            None
+        } else {
+            Some(query.selection.offset + query.selection.rows)
+        };
+
         // First generate a weighted set.
 
         let mut generated = match query.generator {
             // TODO should be all
-            Generator::All => {
+            // Sampling happens after filtering, so start with everything.
+            Generator::All | Generator::Sample(_, _) => {
                 let mut results = ResultSet::new();
-                for i in 0..self.abc_cache.max_id() {
-                    results.add(i as usize, 1.0);
+                for tune_id in self.abc_cache.tune_ids() {
+                    results.add(tune_id as usize, 1.0);
                 }
                 results
             }
@@ -462,17 +1047,101 @@ impl SearchEngine {
                 let search_pitches = pitch::PitchSequence::from_pitches(melody);
                 let search_intervals =
                     pitch::IntervalSequence::from_pitch_sequence(&search_pitches);
-                self.interval_term_vsm.search(
-                    &search_intervals.intervals,
-                    0.8,
-                    relations::ScoreNormalization::DocA,
-                )
+                self.interval_term_vsm_for(search_intervals.intervals.len())
+                    .search(
+                        &search_intervals.intervals,
+                        0.8,
+                        query.normalization,
+                        limit,
+                        deadline,
+                    )
+            }
+            Generator::SoundingIntervalNGram(ref melody) => {
+                let search_pitches = pitch::PitchSequence::from_pitches(melody);
+                let search_intervals =
+                    pitch::IntervalSequence::from_pitch_sequence(&search_pitches);
+                match self.sounding_interval_term_vsm_for(search_intervals.intervals.len()) {
+                    Some(vsm) => vsm.search(
+                        &search_intervals.intervals,
+                        0.8,
+                        query.normalization,
+                        limit,
+                        deadline,
+                    ),
+                    None => ResultSet::new(),
+                }
+            }
+            Generator::RetrogradeIntervalNGram(ref melody) => search_transformation(
+                &self.retrograde_term_vsm,
+                melody,
+                query.normalization,
+                limit,
+                deadline,
+            ),
+            Generator::InversionIntervalNGram(ref melody) => search_transformation(
+                &self.inversion_term_vsm,
+                melody,
+                query.normalization,
+                limit,
+                deadline,
+            ),
+            Generator::Similar(tune_id, cutoff) => {
+                let mut results = self.interval_term_vsm().vsm.search_by_id(
+                    tune_id,
+                    cutoff,
+                    query.normalization,
+                    deadline,
+                );
+                results.remove(tune_id);
+                results
+            }
+            Generator::DegreeNGram(ref degrees) => {
+                self.degree_term_vsm
+                    .search(degrees, 0.8, query.normalization, limit, deadline)
+            }
+            Generator::Parsons(ref contour) => {
+                self.contour_term_vsm
+                    .search(contour, 0.8, query.normalization, limit, deadline)
+            }
+            Generator::Title(ref text) => {
+                let mut results =
+                    self.text_vsm
+                        .search(text.to_string(), query.normalization, limit, deadline);
+
+                // The index doesn't know the order of words, so check phrases against the titles.
+                let phrases = text::TextQuery::parse(text).phrases;
+                if !phrases.is_empty() {
+                    results.retain(|tune_id| {
+                        let titles = self.titles(tune_id as u32);
+                        phrases.iter().all(|phrase| {
+                            titles
+                                .iter()
+                                .any(|title| text::contains_phrase(title, phrase))
+                        })
+                    });
+                }
+                results
+            }
+            Generator::Composer(ref text) => {
+                self.composer_vsm
+                    .search(text.to_string(), query.normalization, limit, deadline)
+            }
+            Generator::Origin(ref text) => {
+                self.origin_vsm
+                    .search(text.to_string(), query.normalization, limit, deadline)
+            }
+            Generator::RhythmText(ref text) => {
+                self.rhythm_vsm
+                    .search(text.to_string(), query.normalization, limit, deadline)
             }
-            Generator::Title(ref text) => self.text_vsm.search(text.to_string()),
-
-            // TODO implement other generators.
-            _ => ResultSet::new(),
-        };
+
+            // TODO implement other generators.
+            _ => ResultSet::new(),
+        };
+
+        if deadline.has_passed() {
+            return None;
+        }
 
         // Then generate a filter set. This is all docs that match the filter.
         // TODO it may be more efficient to build this as a predicate that can be supplied to the
@@ -488,20 +1157,38 @@ impl SearchEngine {
             generated.filter_by(&filtered_results);
         };
 
+        if deadline.has_passed() {
+            return None;
+        }
+
+        if let Generator::Sample(size, seed) = query.generator {
+            generated = generated.sample(size, seed);
+        }
+
         // Then generate facets if they were requested.
-        let facets = if query.selection.facet {
-            Some(self.features_vsm.facet_features_for_resultset(&generated))
-        } else {
-            None
+        let facets = match self.features_vsm {
+            Some(ref vsm) if query.selection.facet => {
+                Some(vsm.facet_features_for_resultset(&generated, query.selection.facet_limit))
+            }
+            _ => None,
         };
 
+        if deadline.has_passed() {
+            return None;
+        }
+
         // Then do selection.
         let mut results: Vec<DecoratedResult> = vec![];
         for (id, score) in generated.results.iter() {
-            let mut result = DecoratedResult {
+            let result = DecoratedResult {
                 titles: vec![],
                 id: *id,
                 score: *score,
+                detected_key: None,
+                source: None,
+                licence: None,
+                matches: None,
+                versions: None,
             };
 
             results.push(result);
@@ -515,20 +1202,32 @@ impl SearchEngine {
         results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
 
         // If this is set (and it is by default) only include the first (best) result in any group.
-        let mut results: Vec<DecoratedResult> = if query.selection.rollup {
-            let mut seen = HashSet::new();
+        // If grouping, the rest of the group are nested within that result.
+        let mut results: Vec<DecoratedResult> = if query.selection.rollup || query.selection.group {
+            // Group ID to index of the group's first result.
+            let mut seen = HashMap::new();
             let mut new_results: Vec<DecoratedResult> = vec![];
 
             // Results are sorted best-first, so the first result in any group should stay,
             // the rest should go.
-            for result in results.drain(..) {
-                match self.clusters.get(result.id) {
+            for mut result in results.drain(..) {
+                match self.rollup_group(result.id) {
                     // If it's not in a group, add as normal.
                     None => new_results.push(result),
 
-                    Some(group_id) => if !seen.contains(&group_id) {
-                        seen.insert(group_id);
-                        new_results.push(result);
+                    Some(group_id) => match seen.entry(group_id) {
+                        Entry::Vacant(v) => {
+                            v.insert(new_results.len());
+                            if query.selection.group {
+                                result.versions = Some(vec![]);
+                            }
+                            new_results.push(result);
+                        }
+                        Entry::Occupied(o) => if query.selection.group {
+                            if let Some(ref mut versions) = new_results[*o.get()].versions {
+                                versions.push(result);
+                            }
+                        },
                     },
                 }
             }
@@ -537,6 +1236,10 @@ impl SearchEngine {
             results
         };
 
+        if deadline.has_passed() {
+            return None;
+        }
+
         // The number of results from the set we're going to return.
         let num_unique_results = results.len();
 
@@ -554,25 +1257,262 @@ impl SearchEngine {
         // Decorate with Titles and maybe other things.
         // TODO Store metadata a bit better. This involves jumping all over the file currently.
         for result in results.iter_mut() {
+            if deadline.has_passed() {
+                return None;
+            }
+
+            self.decorate(result, &query.generator);
+
+            if let Some(ref mut versions) = result.versions {
+                for version in versions.iter_mut() {
+                    self.decorate(version, &query.generator);
+                }
+            }
+        }
+
+        Some((total_results, num_unique_results, facets, results))
+    }
+
+    // Add titles, and the reason the tune matched the generator.
+    // Only the reason needs the ABC, so it's only read for generators that have one.
+    fn decorate(&self, result: &mut DecoratedResult, generator: &Generator) {
+        if let Some(summary) = self.summaries.get(&(result.id as u32)) {
+            result.titles = summary.titles.clone();
+            result.detected_key = summary.detected_key.clone();
+        }
+
+        if let Some(meta) = self.meta.get(&(result.id as u32)) {
+            result.source = meta.source.clone();
+            result.licence = meta.licence.clone();
+        }
+
+        if generator.has_matches() {
             if let Some(entry) = self.abc_cache.get(result.id as u32) {
                 let ast = representations::abc_to_ast(&entry);
-                let titles = ast
-                    .prelude
-                    .iter()
-                    .filter_map(|x| match x {
-                        l::T::Title(x) => Some((*x).clone()),
-                        _ => None,
-                    }).collect();
-                result.titles = titles;
+                result.matches = self.get_matches(&entry, &ast, generator);
+            }
+        }
+    }
+
+    // Other tunes that are the same as this one apart from whitespace, in order.
+    pub fn exact_duplicates(&self, tune_id: u32) -> Vec<u32> {
+        self.summaries
+            .get(&tune_id)
+            .and_then(|summary| self.exact_duplicates.get(&summary.canonical_hash))
+            .map(|tune_ids| tune_ids.iter().cloned().filter(|x| *x != tune_id).collect())
+            .unwrap_or_default()
+    }
+
+    // Group to roll a result up into: its cluster, or that of one of its exact duplicates, or
+    // failing that, its exact duplicates, e.g. for tunes that are too short to cluster, or added
+    // since clustering. So exact duplicates always end up in the same group.
+    fn rollup_group(&self, tune_id: usize) -> Option<RollupGroup> {
+        if let Some(group_id) = self.clusters.get(tune_id) {
+            return Some(RollupGroup::Cluster(group_id));
+        }
+
+        let canonical_hash = self.summaries.get(&(tune_id as u32))?.canonical_hash;
+        let tune_ids = self.exact_duplicates.get(&canonical_hash)?;
+        if tune_ids.len() < 2 {
+            return None;
+        }
+
+        let group_id = tune_ids
+            .iter()
+            .filter_map(|x| self.clusters.get(*x as usize))
+            .next();
+        match group_id {
+            Some(group_id) => Some(RollupGroup::Cluster(group_id)),
+            None => Some(RollupGroup::Exact(canonical_hash)),
+        }
+    }
+
+    // Titles of the tune, without reading its ABC.
+    pub fn titles(&self, tune_id: u32) -> Vec<String> {
+        match self.summaries.get(&tune_id) {
+            Some(summary) => summary.titles.clone(),
+            None => vec![],
+        }
+    }
+
+    // Which terms of the generator's query matched the tune, if that makes sense for it.
+    fn get_matches(
+        &self,
+        content: &str,
+        ast: &tune_ast_three::Tune,
+        generator: &Generator,
+    ) -> Option<Matches> {
+        let terms = match generator {
+            Generator::Title(ref text) => Some(self.text_vsm.matching_terms(
+                text,
+                &header_text(ast, |x| match x {
+                    l::T::Title(x) => Some(x.clone()),
+                    _ => None,
+                }),
+            )),
+            Generator::Composer(ref text) => Some(self.composer_vsm.matching_terms(
+                text,
+                &header_text(ast, |x| match x {
+                    l::T::Composer(x) => Some(x.clone()),
+                    _ => None,
+                }),
+            )),
+            Generator::Origin(ref text) => Some(self.origin_vsm.matching_terms(
+                text,
+                &header_text(ast, |x| match x {
+                    l::T::Origin(x) => Some(x.clone()),
+                    _ => None,
+                }),
+            )),
+            Generator::RhythmText(ref text) => Some(self.rhythm_vsm.matching_terms(
+                text,
+                &header_text(ast, |x| match x {
+                    l::T::Rhythm(x) => Some(x.clone()),
+                    _ => None,
+                }),
+            )),
+            _ => None,
+        };
+
+        if let Some(terms) = terms {
+            return Some(Matches {
+                terms,
+                ranges: vec![],
+            });
+        }
+
+        // Melodic matches, as positions of windows in the tune's note sequence and the number of
+        // notes each window covers.
+        // Transformations are matched by transforming the query rather than the tune, so the
+        // positions refer to the tune as written.
+        let intervals = |melody: &Vec<u8>| {
+            let pitches = pitch::PitchSequence::from_pitches(melody);
+            pitch::IntervalSequence::from_pitch_sequence(&pitches)
+        };
+        let tune_intervals = || {
+            pitch::IntervalSequence::from_pitch_sequence(&pitch::PitchSequence::from_ast(ast))
+                .intervals
+        };
+        let interval_window = |intervals: &pitch::IntervalSequence| {
+            self.interval_term_vsm_for(intervals.intervals.len())
+                .window_size()
+        };
+        // Transformations are only indexed with the largest window.
+        let transformation_window = self.interval_term_vsm().window_size();
+
+        let (positions, notes_per_window) = match generator {
+            Generator::IntervalNGram(ref melody) => {
+                let window = interval_window(&intervals(melody));
+                (
+                    relations::matching_window_positions(
+                        &intervals(melody).intervals,
+                        &tune_intervals(),
+                        window,
+                    ),
+                    window + 1,
+                )
+            }
+            Generator::SoundingIntervalNGram(ref melody) => {
+                let window = self
+                    .sounding_interval_term_vsm_for(intervals(melody).intervals.len())?
+                    .window_size();
+                (
+                    relations::matching_window_positions(
+                        &intervals(melody).intervals,
+                        &pitch::IntervalSequence::from_ast(ast, pitch::IntervalPitches::Sounding)
+                            .intervals,
+                        window,
+                    ),
+                    window + 1,
+                )
+            }
+            Generator::RetrogradeIntervalNGram(ref melody) => (
+                relations::matching_window_positions(
+                    &intervals(melody).retrograde().intervals,
+                    &tune_intervals(),
+                    transformation_window,
+                ),
+                transformation_window + 1,
+            ),
+            Generator::InversionIntervalNGram(ref melody) => (
+                relations::matching_window_positions(
+                    &intervals(melody).inversion().intervals,
+                    &tune_intervals(),
+                    transformation_window,
+                ),
+                transformation_window + 1,
+            ),
+            Generator::DegreeNGram(ref degrees) => (
+                relations::matching_window_positions(
+                    degrees,
+                    &pitch::DegreeSequence::from_ast(ast).degrees,
+                    self.degree_term_vsm.window_size(),
+                ),
+                self.degree_term_vsm.window_size(),
+            ),
+            Generator::Parsons(ref contour) => {
+                let tune_contour =
+                    pitch::ParsonsCode::from_interval_sequence(&pitch::IntervalSequence {
+                        intervals: tune_intervals(),
+                    }).contour;
+                let contour_window = self.contour_term_vsm.window_size();
+                (
+                    relations::matching_window_positions(contour, &tune_contour, contour_window),
+                    contour_window + 1,
+                )
+            }
+            _ => return None,
+        };
+
+        // Convert to character ranges in the ABC.
+        // Positions count notes voice by voice, so a window is split where it moves to another
+        // voice, which may be anywhere in the ABC.
+        let note_ranges =
+            representations::ast_note_ranges(&content.chars().collect::<Vec<char>>(), ast);
+        let mut windows: Vec<(usize, usize)> = vec![];
+        for position in positions {
+            let last = position + notes_per_window - 1;
+            if last >= note_ranges.len() {
+                continue;
+            }
+
+            let mut run = note_ranges[position].clone();
+            for note in note_ranges[position + 1..last + 1].iter() {
+                if note.0 == run.0 {
+                    run.2 = note.2;
+                } else {
+                    windows.push((run.1, run.2));
+                    run = note.clone();
+                }
+            }
+            windows.push((run.1, run.2));
+        }
+
+        // Merge overlapping windows.
+        windows.sort();
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for (start, end) in windows {
+            match ranges.last_mut() {
+                Some(ref mut previous) if start <= previous.1 => previous.1 = previous.1.max(end),
+                _ => ranges.push((start, end)),
             }
         }
 
-        (total_results, num_unique_results, facets, results)
+        Some(Matches {
+            terms: vec![],
+            ranges,
+        })
     }
 
     // Produce a result set by applying filters.
     // These are ORed within a type, then ANDed.
     fn generate_filter_resultset(&self, query: &Query) -> ResultSet {
+        // Without features there can be no filters.
+        let features_vsm = match self.features_vsm {
+            Some(ref vsm) => vsm,
+            None => return ResultSet::new(),
+        };
+
         // Into type -> [(type, val)...]
         let mut groups: HashMap<String, Vec<(String, String)>> = HashMap::new();
         for (typ, val) in query.filter.features.iter() {
@@ -592,11 +1532,12 @@ impl SearchEngine {
 
         for (_typ, vals) in groups.iter() {
             // OR within the type.
-            let group_result = self.features_vsm.vsm.search_by_terms(
+            let group_result = features_vsm.vsm.search_by_postings(
                 vals,
                 0.0,
-                true,
                 relations::ScoreNormalization::DocA,
+                None,
+                Deadline::none(),
             );
 
             // First time use this group's results.
@@ -610,17 +1551,172 @@ impl SearchEngine {
             };
         }
 
-        results.unwrap_or(ResultSet::new())
+        // Each numeric range is ANDed too.
+        for range in query.filter.ranges.iter() {
+            let range_result = features_vsm.search_range(&range.feature_type, range.min, range.max);
+
+            results = match results {
+                Some(mut r) => {
+                    r.filter_by(&range_result);
+                    Some(r)
+                }
+                None => Some(range_result),
+            };
+        }
+
+        results.unwrap_or_default()
     }
 
-    // Return groups of features that we recognise.
-    pub fn get_features(&self) -> &HashMap<String, Vec<String>> {
-        &self.all_features_cached
+    // Summary of what's indexed.
+    pub fn get_stats(&self) -> Stats {
+        let num_tunes = self.abc_cache.num_tunes();
+        Stats {
+            num_tunes,
+            max_tune_id: self.max_tune_id,
+            num_clusters: self.clusters.num_groups(),
+            empty: num_tunes == 0,
+        }
+    }
+
+    // Iterate over the tunes in the engine's cache, parsing each one on demand.
+    pub fn iter_tunes(&self) -> storage::TuneIterator {
+        self.abc_cache.iter()
+    }
+
+    // Return groups of features that we recognise. Numeric features are left out, as they have
+    // too many values to list. They can be filtered by range instead.
+    pub fn get_features(&self) -> HashMap<String, Vec<String>> {
+        self.all_features_cached
+            .iter()
+            .filter(|(feature_type, _)| {
+                !features::NUMERIC_FEATURES.contains(&feature_type.as_str())
+            })
+            .map(|(feature_type, values)| (feature_type.clone(), values.clone()))
+            .collect()
+    }
+
+    // The main interval index, with the largest window.
+    pub fn interval_term_vsm(&self) -> &relations::IntervalWindowBinaryVSM {
+        &self.interval_term_vsms[0]
+    }
+
+    // The interval index to use for a query with this many intervals.
+    // A larger window gives more precise results, but a query shorter than the window can't match
+    // anything, so use the largest window that fits, or failing that the smallest.
+    fn interval_term_vsm_for(&self, num_intervals: usize) -> &relations::IntervalWindowBinaryVSM {
+        vsm_for_intervals(&self.interval_term_vsms, num_intervals).unwrap()
+    }
+
+    // The sounding interval index to use for a query, in the same way. None if there aren't any.
+    fn sounding_interval_term_vsm_for(
+        &self,
+        num_intervals: usize,
+    ) -> Option<&relations::IntervalWindowBinaryVSM> {
+        vsm_for_intervals(&self.sounding_interval_term_vsms, num_intervals)
     }
 
     pub fn get_max_tune_id(&self) -> u32 {
         self.max_tune_id
     }
+
+    pub fn get_clusters(&self) -> &relations::Clusters {
+        &self.clusters
+    }
+
+    // Replace the clusters used for rollup, e.g. after re-clustering.
+    pub fn set_clusters(&mut self, clusters: relations::Clusters) -> Result<(), String> {
+        if !self.rollup {
+            return Err("Rollup is disabled, so clusters aren't used.".to_string());
+        }
+
+        check_generation(&*self.abc_cache, &clusters)?;
+
+        self.clusters = clusters;
+        Ok(())
+    }
+}
+
+// Check that clusters were built from this generation of the tunecache.
+// Clusters that don't record their generation can't be checked, so they pass.
+fn check_generation(
+    abc_cache: &dyn TuneStore,
+    clusters: &relations::Clusters,
+) -> Result<(), String> {
+    match clusters.generation {
+        Some(generation) if generation != abc_cache.generation() => Err(format!(
+            "Clusters were built from tunecache generation {:016x} but it's now {:016x}. Run \
+             `abctool cluster` again.",
+            generation,
+            abc_cache.generation()
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Trim a finished index, returning the memory used by its exact term lists.
+fn compact<K>(vsm: &mut relations::BinaryVSM<K>) -> usize
+where
+    K: Eq + Hash + Clone + Debug + Ord,
+{
+    vsm.shrink_to_fit();
+    vsm.exact_heap_bytes()
+}
+
+// Text of the tune's header fields of one type, as one string.
+fn header_text(ast: &tune_ast_three::Tune, field: fn(&l::T) -> Option<String>) -> String {
+    ast.prelude
+        .iter()
+        .filter_map(field)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Of interval indexes, largest window first, the largest that fits this many intervals, or
+// failing that the smallest.
+fn vsm_for_intervals(
+    vsms: &[relations::IntervalWindowBinaryVSM],
+    num_intervals: usize,
+) -> Option<&relations::IntervalWindowBinaryVSM> {
+    vsms.iter()
+        .find(|vsm| vsm.window_size() <= num_intervals)
+        .or_else(|| vsms.last())
+}
+
+// Search a melody against a transformed interval index, if there is one.
+fn search_transformation(
+    vsm: &Option<relations::IntervalWindowBinaryVSM>,
+    melody: &[u8],
+    normalization: relations::ScoreNormalization,
+    limit: Option<usize>,
+    deadline: Deadline,
+) -> ResultSet {
+    match vsm {
+        Some(vsm) => {
+            let search_pitches = pitch::PitchSequence::from_pitches(melody);
+            let search_intervals = pitch::IntervalSequence::from_pitch_sequence(&search_pitches);
+            vsm.search(
+                &search_intervals.intervals,
+                0.8,
+                normalization,
+                limit,
+                deadline,
+            )
+        }
+        None => ResultSet::new(),
+    }
+}
+
+// Why a result matched.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Matches {
+    // Words that matched, for text searches.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub terms: Vec<String>,
+
+    // Start and end character offsets in the ABC of the phrases that matched, for melody
+    // searches.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<(usize, usize)>,
 }
 
 // A user-facing result with metadata etc.
@@ -629,4 +1725,628 @@ pub struct DecoratedResult {
     pub titles: Vec<String>,
     pub id: usize,
     pub score: f32,
+
+    // Key and mode worked out from the notes, e.g. "E-Minor", which may differ from the K: header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_key: Option<String>,
+
+    // Where the tune came from and its licence, from the metadata file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub licence: Option<String>,
+
+    // Why it matched. Only present for searches that match terms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Matches>,
+
+    // Other versions in the same group, best first. Only present when grouping.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versions: Option<Vec<DecoratedResult>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    // The tunecache at this path, as a store for a search engine.
+    fn open_cache(path: &Path) -> Box<dyn TuneStore> {
+        Box::new(storage::ReadOnlyCache::new(path.to_path_buf(), None).unwrap())
+    }
+
+    #[test]
+    fn sample_test() {
+        let mut results = ResultSet::new();
+        for i in 0..100 {
+            results.add(i, 1.0);
+        }
+
+        let a = results.sample(10, 1234);
+        let b = results.sample(10, 1234);
+        let c = results.sample(10, 5678);
+
+        assert_eq!(a.total(), 10);
+        assert_eq!(a.results, b.results, "Same seed gives the same sample.");
+        assert!(a.results != c.results, "Different seed gives a different sample.");
+        assert!(a.results.keys().all(|id| results.results.contains_key(id)));
+
+        assert_eq!(
+            results.sample(200, 1234).total(),
+            100,
+            "Sample can't be bigger than the results."
+        );
+    }
+
+    #[test]
+    fn limit_test() {
+        let mut results = ResultSet::with_limit(Some(3));
+        assert_eq!(results.threshold(), None);
+
+        results.add(5, 0.5);
+        results.add(1, 0.9);
+        results.add(2, 0.2);
+        assert_eq!(results.threshold(), Some(0.2));
+
+        results.add(3, 0.1);
+        results.add(4, 0.7);
+        results.add(6, 0.5);
+        results.add(0, 0.5);
+
+        let mut ids: Vec<usize> = results.results.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![0, 1, 4],
+            "The best results are kept, and lower IDs win ties."
+        );
+        assert_eq!(results.threshold(), Some(0.5));
+
+        let mut results = ResultSet::with_limit(Some(0));
+        results.add(1, 1.0);
+        assert_eq!(results.total(), 0);
+    }
+
+    #[test]
+    fn disabled_facets_and_rollup_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            SearchEngineFeatures {
+                index_text: false,
+                index_melody_interval_term: false,
+                index_melody_degree_term: false,
+                index_melody_contour_term: false,
+                index_melody_transformations: false,
+                index_features: false,
+                rollup: false,
+                interval_window_sizes: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+                sounding_interval_window_sizes: vec![],
+                feature_extractors: None,
+            },
+            text::Vocabulary::new(),
+        );
+
+        assert!(searcher.features_vsm.is_none());
+
+        let query = searcher
+            .parse_query(vec![
+                ("rollup".to_string(), "true".to_string()),
+                ("facet".to_string(), "true".to_string()),
+                ("group".to_string(), "cluster".to_string()),
+            ]).unwrap();
+
+        assert!(
+            !query.selection.rollup && !query.selection.facet && !query.selection.group,
+            "Disabled options are ignored even when asked for."
+        );
+
+        let err = searcher
+            .parse_query(vec![
+                ("title".to_string(), "kesh".to_string()),
+                ("rhythm".to_string(), "jig".to_string()),
+            ]).unwrap_err();
+        assert_eq!(
+            err.parameter,
+            Some("rhythm".to_string()),
+            "Filters aren't ignored when they can't be applied."
+        );
+        let licence = searcher.parse_query(vec![("licence".to_string(), "CC0".to_string())]);
+        assert!(licence.is_err());
+        let range = searcher.parse_query(vec![("max_range".to_string(), "12".to_string())]);
+        assert_eq!(range.unwrap_err().parameter, Some("max_range".to_string()));
+        let similar = searcher.parse_similar_query(1, vec![("key".to_string(), "G".to_string())]);
+        assert!(similar.is_err());
+    }
+
+    fn all_features() -> SearchEngineFeatures {
+        SearchEngineFeatures {
+            index_text: true,
+            index_melody_interval_term: true,
+            index_melody_degree_term: true,
+            index_melody_contour_term: true,
+            index_melody_transformations: true,
+            index_features: true,
+            rollup: true,
+            interval_window_sizes: vec![relations::DEFAULT_INTERVAL_WINDOW_SIZE],
+            sounding_interval_window_sizes: vec![3],
+            feature_extractors: None,
+        }
+    }
+
+    #[test]
+    fn stale_clusters_test() {
+        let stale = || {
+            let mut clusters = relations::Clusters::new();
+            clusters.add(1, 2);
+            clusters.generation = Some(0);
+            clusters
+        };
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            stale(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+        assert_eq!(
+            searcher.get_clusters().num_groups(),
+            0,
+            "Clusters from another generation aren't used."
+        );
+
+        assert!(searcher.set_clusters(stale()).is_err());
+
+        let mut current = stale();
+        current.generation = Some(searcher.abc_cache.generation());
+        assert!(searcher.set_clusters(current).is_ok());
+        assert_eq!(searcher.get_clusters().num_groups(), 1);
+
+        let mut legacy = stale();
+        legacy.generation = None;
+        assert!(searcher.set_clusters(legacy).is_ok(), "Legacy clusters can't be checked.");
+    }
+
+    #[test]
+    fn empty_corpus_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/missing-tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        let stats = searcher.get_stats();
+        assert!(stats.empty);
+        assert_eq!(stats.num_tunes, 0);
+
+        for params in [
+            vec![],
+            vec![("title".to_string(), "butterfly".to_string())],
+            vec![("interval_ngram".to_string(), "72,74,76,77,79,81".to_string())],
+            vec![("facet".to_string(), "true".to_string())],
+        ] {
+            let query = searcher.parse_query(params).unwrap();
+            let (total, unique, _, results) = searcher.search(&query);
+            assert_eq!((total, unique, results.len()), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn top_tune_id_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        assert_eq!(searcher.get_stats().num_tunes, 1);
+
+        for params in [
+            vec![],
+            vec![("title".to_string(), "butterfly".to_string())],
+        ] {
+            let query = searcher.parse_query(params).unwrap();
+            let (_, _, _, results) = searcher.search(&query);
+            assert_eq!(
+                results.iter().map(|x| x.id).collect::<Vec<usize>>(),
+                vec![1],
+                "The only tune has the top ID, which should be searched."
+            );
+        }
+    }
+
+    #[test]
+    fn interval_window_selection_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            SearchEngineFeatures {
+                index_text: false,
+                index_melody_interval_term: true,
+                index_melody_degree_term: false,
+                index_melody_contour_term: false,
+                index_melody_transformations: false,
+                index_features: false,
+                rollup: false,
+                interval_window_sizes: vec![3, 5],
+                sounding_interval_window_sizes: vec![],
+                feature_extractors: None,
+            },
+            text::Vocabulary::new(),
+        );
+
+        assert_eq!(searcher.interval_term_vsm().window_size(), 5);
+        assert_eq!(
+            searcher.interval_term_vsm_for(10).window_size(),
+            5,
+            "Use the largest window that fits."
+        );
+        assert_eq!(searcher.interval_term_vsm_for(4).window_size(), 3);
+        assert_eq!(
+            searcher.interval_term_vsm_for(2).window_size(),
+            3,
+            "Fall back to the smallest window."
+        );
+    }
+
+    #[test]
+    fn sounding_intervals_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        // The Butterfly is in E minor, so the F is sharp.
+        let params = vec![("intervals".to_string(), "sounding".to_string())];
+        let query = searcher
+            .parse_abc_query("B2E G2E ^F3|", params.clone())
+            .unwrap();
+        let (_, _, _, results) = searcher.search(&query);
+        assert_eq!(
+            results.iter().map(|x| x.id).collect::<Vec<usize>>(),
+            vec![1]
+        );
+        assert_eq!(
+            results[0].matches.as_ref().unwrap().ranges.len(),
+            2,
+            "The phrase starts the first bar and the second."
+        );
+
+        // Against the written intervals the F sharp isn't there.
+        let query = searcher.parse_abc_query("B2E G2E ^F3|B", vec![]).unwrap();
+        let (total, _, _, _) = searcher.search(&query);
+        assert_eq!(total, 0);
+
+        let mut features = all_features();
+        features.sounding_interval_window_sizes = vec![];
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            features,
+            text::Vocabulary::new(),
+        );
+        assert!(
+            searcher.parse_abc_query("B2E G2E ^F3|", params).is_err(),
+            "Sounding intervals aren't indexed."
+        );
+    }
+
+    #[test]
+    fn matches_voices_test() {
+        let mut features = all_features();
+        features.interval_window_sizes = vec![2];
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            features,
+            text::Vocabulary::new(),
+        );
+
+        let content = "X:1\nL:1/8\nK:D\nV:1\ndef|\nV:2\nDEF|\nV:1\nABc|\n".to_string();
+        let ast = representations::abc_to_ast(&content);
+        let ranges = |melody: Vec<u8>| {
+            searcher
+                .get_matches(&content, &ast, &Generator::IntervalNGram(melody))
+                .unwrap()
+                .ranges
+        };
+
+        assert_eq!(
+            ranges(vec![62, 64, 65]),
+            vec![(18, 21), (27, 30), (36, 39)],
+            "Each voice's phrase is found where it is in the ABC."
+        );
+        assert_eq!(
+            ranges(vec![71, 72, 62]),
+            vec![(27, 28), (37, 39)],
+            "A phrase that runs on into the next voice is split."
+        );
+    }
+
+    #[test]
+    fn add_tune_test() {
+        let path = std::env::temp_dir().join(format!("tunecache-add-test-{}", std::process::id()));
+        std::fs::copy("test_resources/tunecache", &path).unwrap();
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        let tune_id = searcher
+            .add_tune("X:1\nT:Submitted Reel\nM:4/4\nL:1/8\nK:G\nGABd edBd|gedB AGEA|\n")
+            .unwrap();
+        assert_eq!(tune_id, 2);
+        assert_eq!(searcher.get_stats().num_tunes, 2);
+        assert!(searcher.abc_cache.get(2).unwrap().contains("Submitted Reel"));
+
+        for params in [
+            vec![("title".to_string(), "submitted".to_string())],
+            vec![("interval_ngram".to_string(), "67,69,71,74,76,74".to_string())],
+        ] {
+            let query = searcher.parse_query(params).unwrap();
+            let (_, _, _, results) = searcher.search(&query);
+            assert_eq!(results.iter().map(|x| x.id).collect::<Vec<usize>>(), vec![2]);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn exact_duplicates_test() {
+        let path =
+            std::env::temp_dir().join(format!("tunecache-dupes-test-{}", std::process::id()));
+        std::fs::copy("test_resources/tunecache", &path).unwrap();
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        let reel = "X:1\nT:Submitted Reel\nM:4/4\nL:1/8\nK:G\nGABd edBd|gedB AGEA|\n";
+        let copy = "X:7\nT: Submitted Reel\nM:4/4\nL:1/8\nK:G\nGABdedBd|\ngedBAGEA|\n";
+        searcher.add_tune(reel).unwrap();
+        searcher.add_tune(copy).unwrap();
+        assert_eq!(searcher.exact_duplicates(2), vec![3]);
+        assert_eq!(searcher.exact_duplicates(3), vec![2]);
+        assert!(searcher.exact_duplicates(1).is_empty());
+
+        let query = searcher
+            .parse_query(vec![("title".to_string(), "submitted".to_string())])
+            .unwrap();
+        let (total, unique, _, _) = searcher.search(&query);
+        assert_eq!((total, unique), (2, 1), "Rolled up without clusters.");
+
+        // When only one of them is clustered, the other is rolled up with that cluster.
+        let mut clusters = relations::Clusters::new();
+        clusters.add(1, 2);
+        searcher.set_clusters(clusters).unwrap();
+        assert!(searcher.rollup_group(3) == Some(RollupGroup::Cluster(1)));
+        assert!(searcher.rollup_group(3) == searcher.rollup_group(2));
+
+        searcher
+            .replace_tune(3, "X:1\nT:Another Reel\nK:D\nDFAF|\n")
+            .unwrap();
+        assert!(searcher.exact_duplicates(2).is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deadline_test() {
+        let searcher = SearchEngine::new(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+        let query = searcher.parse_query(vec![]).unwrap();
+
+        assert!(!Deadline::none().has_passed());
+        assert!(!Deadline::after(Duration::from_secs(60)).has_passed());
+        assert!(Deadline::after(Duration::from_secs(0)).has_passed());
+
+        assert_eq!(
+            searcher
+                .search_before(&query, Deadline::after(Duration::from_secs(60)))
+                .map(|(total, _, _, _)| total),
+            Some(searcher.search(&query).0)
+        );
+        assert!(searcher
+            .search_before(&query, Deadline::after(Duration::from_secs(0)))
+            .is_none());
+    }
+
+    #[test]
+    fn index_progress_test() {
+        let progress = IndexProgress::default();
+        let searcher = SearchEngine::new_with_progress(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+            &progress,
+        );
+        assert_eq!(progress.total(), searcher.get_stats().num_tunes);
+        assert_eq!(progress.indexed(), progress.total());
+        assert!(
+            !progress.is_done(),
+            "Done is up to whoever uses the engine."
+        );
+
+        progress.finish();
+        assert!(progress.is_done());
+        assert!(!progress.has_failed());
+
+        let failed = IndexProgress::new(10);
+        failed.fail();
+        assert!(failed.has_failed());
+        assert!(!failed.is_done(), "A failed build is never done.");
+
+        let placeholder = SearchEngine::unindexed(
+            open_cache(&PathBuf::from("test_resources/tunecache")),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+        assert!(
+            placeholder.abc_cache.get(1).is_some(),
+            "Tunes can be fetched."
+        );
+        let query = placeholder
+            .parse_query(vec![("title".to_string(), "kesh".to_string())])
+            .unwrap();
+        assert_eq!(placeholder.search(&query).0, 0, "Nothing is indexed.");
+        assert_eq!(
+            placeholder.get_max_tune_id(),
+            0,
+            "No room is taken for tunes."
+        );
+    }
+
+    #[test]
+    fn meta_test() {
+        let path = std::env::temp_dir().join(format!("tunecache-meta-test-{}", std::process::id()));
+        std::fs::copy("test_resources/tunecache", &path).unwrap();
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        let meta = storage::parse_meta(
+            "id,source,licence\n\
+             1,https://thesession.org/tunes/1,CC BY-SA 4.0\n\
+             2,https://www.thesession.org/tunes/2,\n",
+        ).unwrap();
+        searcher.set_meta(meta);
+
+        // Tune 2 is added after the metadata is loaded.
+        searcher
+            .add_tune("X:1\nT:Submitted Reel\nK:G\nGABd edBd|\n")
+            .unwrap();
+
+        let query = searcher
+            .parse_query(vec![("source".to_string(), "thesession.org".to_string())])
+            .unwrap();
+        let (total, _, _, results) = searcher.search(&query);
+        assert_eq!(total, 2);
+
+        let result = results.iter().find(|x| x.id == 1).unwrap();
+        assert_eq!(
+            result.source,
+            Some("https://thesession.org/tunes/1".to_string())
+        );
+        assert_eq!(result.licence, Some("CC BY-SA 4.0".to_string()));
+
+        let result = results.iter().find(|x| x.id == 2).unwrap();
+        assert_eq!(result.licence, None);
+
+        assert_eq!(
+            searcher.get_features().get("licence"),
+            Some(&vec!["CC BY-SA 4.0".to_string()])
+        );
+        assert!(
+            !searcher.get_features().contains_key("notes"),
+            "Numeric features aren't listed."
+        );
+        let query = searcher
+            .parse_query(vec![("max_notes".to_string(), "1000".to_string())])
+            .unwrap();
+        assert_eq!(query.filter.ranges.len(), 1, "They can still be filtered.");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_tune_test() {
+        let path = std::env::temp_dir()
+            .join(format!("tunecache-replace-test-{}", std::process::id()));
+        std::fs::copy("test_resources/tunecache", &path).unwrap();
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+
+        let titles = |searcher: &SearchEngine, title: &str| {
+            let query = searcher
+                .parse_query(vec![("title".to_string(), title.to_string())])
+                .unwrap();
+            searcher.search(&query).3.len()
+        };
+        assert_eq!(titles(&searcher, "butterfly"), 1);
+        assert_eq!(searcher.titles(1), vec!["The Butterfly".to_string()]);
+        assert_eq!(
+            searcher.autocomplete("butt", 10),
+            vec![("The Butterfly".to_string(), 1)]
+        );
+
+        searcher
+            .replace_tune(1, "X:1\nT:The Moth\nK:G\nGAB cBA|\n")
+            .unwrap();
+        assert_eq!(titles(&searcher, "butterfly"), 0, "Old terms are removed.");
+        assert_eq!(titles(&searcher, "moth"), 1);
+        assert!(searcher.autocomplete("butt", 10).is_empty());
+        assert_eq!(searcher.titles(1), vec!["The Moth".to_string()]);
+        assert_eq!(searcher.autocomplete("mo", 10).len(), 1);
+
+        // Reindexing what's there changes nothing.
+        searcher.reindex_tune(1).unwrap();
+        assert_eq!(titles(&searcher, "moth"), 1);
+
+        assert!(searcher.replace_tune(2, "X:1\nK:G\nG|\n").is_err());
+        assert!(searcher.reindex_tune(2).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // A tune replaced in the cache is indexed as its latest version when the cache is loaded again.
+    #[test]
+    fn reload_replaced_tune_test() {
+        let path = std::env::temp_dir()
+            .join(format!("tunecache-reload-test-{}", std::process::id()));
+        std::fs::copy("test_resources/tunecache", &path).unwrap();
+
+        let mut searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+        searcher
+            .replace_tune(1, "X:1\nT:The Moth\nK:G\nGAB cBA|\n")
+            .unwrap();
+
+        let searcher = SearchEngine::new(
+            open_cache(&path),
+            relations::Clusters::new(),
+            all_features(),
+            text::Vocabulary::new(),
+        );
+        let titles = |title: &str| {
+            let query = searcher
+                .parse_query(vec![("title".to_string(), title.to_string())])
+                .unwrap();
+            searcher.search(&query).3.len()
+        };
+        assert_eq!(titles("butterfly"), 0, "The old version isn't indexed.");
+        assert_eq!(titles("moth"), 1);
+        assert!(searcher.autocomplete("butt", 10).is_empty());
+        assert_eq!(searcher.titles(1), vec!["The Moth".to_string()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
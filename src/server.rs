@@ -1,149 +1,1389 @@
+extern crate time;
+
+use abc_lexer;
+use config;
+use fingering;
+use lsp;
+use midi;
+use query;
+use rand;
 use regex;
 use representations;
 use search;
-use std::env;
+use relations;
+use std::fs;
+use std::path::{Path, PathBuf};
 use storage;
+use text;
+use typeset;
 
 use std::collections::HashMap;
-use url::Url;
+use url::{form_urlencoded, Url};
 
+use serde::Serialize;
 use serde_json;
 
-use handlebars::Handlebars;
-use std::io::Cursor;
-use tiny_http::{Header, Request, Response, Server, StatusCode};
+use handlebars::{
+    Handlebars, Helper, HelperDef, JsonRender, RenderContext, RenderError, ScopedJson,
+};
+use std::io::{Cursor, Read, Write};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+// How long a worker waits for a request before checking whether to shut down.
+const RECV_TIMEOUT_MS: u64 = 500;
+
+// Value of a query string parameter.
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+    match Url::join(&base, request.url()) {
+        Ok(url) => url
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned()),
+        Err(_) => None,
+    }
+}
+
+// A JSONP callback must be a plain JavaScript name, e.g. `handleResults` or `app.results`.
+fn is_valid_callback(callback: &str) -> bool {
+    callback.len() <= 64
+        && callback
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && callback
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.')
+}
+
+// Respond with JSON, or JSONP if there's a `callback` parameter, for browsers that can't use CORS.
+fn json_response(request: &Request, body: &serde_json::Value) -> Response<Cursor<Vec<u8>>> {
+    match query_param(request, "callback") {
+        Some(callback) => {
+            if !is_valid_callback(&callback) {
+                return parameter_error("callback", "Invalid value for 'callback'.");
+            }
+
+            // The leading comment stops the response being sniffed as anything other than script.
+            Response::from_string(format!("/**/{}({});", callback, body))
+                .with_status_code(StatusCode(200))
+                .with_header(
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/javascript"[..])
+                        .unwrap(),
+                )
+        }
+        None => Response::from_string(body.to_string())
+            .with_status_code(StatusCode(200))
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+            ),
+    }
+}
+
+// Stable codes for API errors. Messages are for people and may change, but clients can rely on
+// these.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    // The URL or request body couldn't be read.
+    InvalidRequest,
+
+    // A query string parameter has a value that isn't allowed. Named in `parameter`.
+    InvalidParameter,
+
+    // The ABC isn't something that can be stored or searched for. Lexer errors are in `errors`.
+    InvalidAbc,
+
+    NotFound,
+    MethodNotAllowed,
+    Unauthorized,
+
+    // The request doesn't fit the state of the server, e.g. clusters from another tunecache.
+    Conflict,
+
+    InternalError,
+
+    // The server can't do this yet, e.g. search while it's still indexing. Try again later.
+    Unavailable,
+
+    // The search took longer than the server allows.
+    Timeout,
+
+    // The request body is bigger than the server reads, see `MAX_BODY_BYTES`.
+    TooLarge,
+}
+
+impl ErrorCode {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidParameter => StatusCode(400),
+            ErrorCode::Unauthorized => StatusCode(401),
+            ErrorCode::NotFound => StatusCode(404),
+            ErrorCode::MethodNotAllowed => StatusCode(405),
+            ErrorCode::Conflict => StatusCode(409),
+            ErrorCode::TooLarge => StatusCode(413),
+            ErrorCode::InvalidAbc => StatusCode(422),
+            ErrorCode::InternalError => StatusCode(500),
+            ErrorCode::Unavailable | ErrorCode::Timeout => StatusCode(503),
+        }
+    }
+}
+
+// A lexer error in submitted ABC. Lines and columns count from 1, and columns are counted in
+// UTF-16 code units, as JavaScript does.
+#[derive(Serialize, Debug)]
+struct AbcError {
+    line: usize,
+    column: usize,
+    offset: usize,
+
+    // Name of the lexer error, e.g. "UnexpectedBodyChar", for tools to match on.
+    error_kind: String,
+
+    message: String,
+}
+
+// Body of an API error response, inside `{"error": ...}`.
+#[derive(Serialize, Debug)]
+struct ApiError {
+    code: ErrorCode,
+    message: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameter: Option<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<AbcError>,
+}
+
+// Respond with an API error. This is always JSON, as a JSONP script can't see the status.
+fn error_response(error: ApiError) -> Response<Cursor<Vec<u8>>> {
+    let status_code = error.code.status_code();
+    Response::from_string(serde_json::json!({ "error": error }).to_string())
+        .with_status_code(status_code)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn api_error(code: ErrorCode, message: &str) -> Response<Cursor<Vec<u8>>> {
+    error_response(ApiError {
+        code,
+        message: message.to_string(),
+        parameter: None,
+        errors: vec![],
+    })
+}
+
+// Respond to an error in the value of a query string parameter, which is named in the response.
+fn parameter_error(parameter: &str, message: &str) -> Response<Cursor<Vec<u8>>> {
+    error_response(ApiError {
+        code: ErrorCode::InvalidParameter,
+        message: message.to_string(),
+        parameter: Some(parameter.to_string()),
+        errors: vec![],
+    })
+}
+
+// Respond to a query that can't be parsed. Errors that aren't down to one parameter get the
+// fallback code, e.g. for a problem with the request body.
+fn query_error(err: query::QueryError, fallback: ErrorCode) -> Response<Cursor<Vec<u8>>> {
+    let parameter = err.parameter;
+    error_response(ApiError {
+        code: if parameter.is_some() {
+            ErrorCode::InvalidParameter
+        } else {
+            fallback
+        },
+        message: err.message,
+        parameter,
+        errors: vec![],
+    })
+}
+
+// Lexer errors in the ABC, with where they are, reading key notes in the dialect.
+fn abc_errors(input: &[char], dialect: abc_lexer::Dialect) -> Vec<AbcError> {
+    abc_lexer::Lexer::new(input)
+        .with_dialect(dialect)
+        .collect_errors()
+        .iter()
+        .map(|&(_, offset, ref error)| {
+            let mut message = String::new();
+            error.format(0, &mut message);
+
+            let position = lsp::offset_to_position(input, offset);
+            AbcError {
+                line: position.line + 1,
+                column: position.character + 1,
+                offset,
+                error_kind: error.kind(),
+                message: message.trim().to_string(),
+            }
+        }).collect()
+}
+
+// Value for Access-Control-Allow-Origin, if the request's origin is allowed.
+fn allowed_origin(request: &Request, origins: &[String]) -> Option<String> {
+    if origins.iter().any(|x| x == "*") {
+        return Some("*".to_string());
+    }
+
+    header_value(request, "Origin").filter(|origin| origins.contains(origin))
+}
+
+// Let browsers on allowed origins read the response.
+fn with_cors(
+    response: Response<Cursor<Vec<u8>>>,
+    request: &Request,
+    origins: &[String],
+) -> Response<Cursor<Vec<u8>>> {
+    match allowed_origin(request, origins) {
+        Some(origin) => {
+            let response = response
+                .with_header(
+                    Header::from_bytes(&b"Access-Control-Allow-Origin"[..], origin.as_bytes())
+                        .unwrap(),
+                ).with_header(
+                    Header::from_bytes(
+                        &b"Access-Control-Expose-Headers"[..],
+                        &b"ETag, Last-Modified"[..],
+                    ).unwrap(),
+                );
+
+            // A specific origin is echoed back, so caches must key on it.
+            if origin == "*" {
+                response
+            } else {
+                response.with_header(Header::from_bytes(&b"Vary"[..], &b"Origin"[..]).unwrap())
+            }
+        }
+        None => response,
+    }
+}
+
+// Answer a CORS pre-flight OPTIONS request.
+fn preflight(request: &Request, origins: &[String]) -> Response<Cursor<Vec<u8>>> {
+    let response = Response::from_string("").with_status_code(StatusCode(204));
+
+    if allowed_origin(request, origins).is_none() {
+        return response;
+    }
+
+    with_cors(
+        response
+            .with_header(
+                Header::from_bytes(&b"Access-Control-Allow-Methods"[..], &b"GET, POST, OPTIONS"[..])
+                    .unwrap(),
+            ).with_header(
+                Header::from_bytes(
+                    &b"Access-Control-Allow-Headers"[..],
+                    &b"Authorization, Content-Type, If-None-Match, If-Modified-Since"[..],
+                ).unwrap(),
+            ).with_header(
+                Header::from_bytes(&b"Access-Control-Max-Age"[..], &b"86400"[..]).unwrap(),
+            ),
+        request,
+        origins,
+    )
+}
+
+// Optional instrument to annotate a tune with fingerings for, e.g. `?fingering=d-whistle`.
+fn fingering_param(request: &Request) -> Result<Option<fingering::Instrument>, String> {
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+    let url = Url::join(&base, request.url()).map_err(|_| "Invalid URL...".to_string())?;
+
+    match url.query_pairs().find(|(key, _)| key == "fingering") {
+        Some((_, value)) => match fingering::Instrument::from_string(&value) {
+            Some(instrument) => Ok(Some(instrument)),
+            None => {
+                Err("Invalid value for 'fingering'. Must be 'd-whistle' or 'anglo'.".to_string())
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+// Format for HTTP dates, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+const HTTP_DATE: &str = "%a, %d %b %Y %T GMT";
+
+fn header_value(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_string())
+}
+
+// ETag for one representation of a tune, e.g. its SVG with whistle fingerings.
+// Rendering changes between versions, so that's included too.
+fn etag(hash: u64, variant: &str) -> String {
+    format!(
+        "\"{:016x}-{}-{}\"",
+        hash,
+        variant,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+// Does the client already have this version?
+// If-None-Match takes precedence over If-Modified-Since.
+fn is_not_modified(request: &Request, etag: &str, modified: Option<u64>) -> bool {
+    if let Some(value) = header_value(request, "If-None-Match") {
+        return value
+            .split(',')
+            .map(|x| x.trim().trim_start_matches("W/"))
+            .any(|x| x == "*" || x == etag);
+    }
+
+    match (header_value(request, "If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match time::strptime(&since, HTTP_DATE) {
+            Ok(since) => since.to_timespec().sec >= modified as i64,
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+// Add validators so clients can make conditional requests next time.
+fn with_cache_headers(
+    response: Response<Cursor<Vec<u8>>>,
+    etag: &str,
+    modified: Option<u64>,
+) -> Response<Cursor<Vec<u8>>> {
+    let response = response.with_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap());
+
+    match modified {
+        Some(modified) => {
+            let date = time::at_utc(time::Timespec::new(modified as i64, 0));
+            let date = format!("{}", date.strftime(HTTP_DATE).unwrap());
+            response
+                .with_header(Header::from_bytes(&b"Last-Modified"[..], date.as_bytes()).unwrap())
+        }
+        None => response,
+    }
+}
+
+// Tune ID from the URL and the hash of its content, if it exists.
+fn tune_id_and_hash(
+    groups: &regex::Captures,
+    abc_cache: &dyn storage::TuneStore,
+) -> Option<(u32, u64)> {
+    groups
+        .get(1)
+        .and_then(|id| id.as_str().parse::<u32>().ok())
+        .and_then(|id| abc_cache.get_hash(id).map(|hash| (id, hash)))
+}
 
 fn api_abc(
+    request: &Request,
     groups: &regex::Captures,
-    abc_cache: &mut storage::ReadOnlyCache,
+    abc_cache: &mut dyn storage::TuneStore,
 ) -> Response<Cursor<Vec<u8>>> {
-    match groups.get(1) {
-        Some(id) => match id.as_str().parse::<u32>() {
-            Ok(id) => match abc_cache.get(id) {
-                Some(content) => {
-                    Response::from_string(content.as_str()).with_status_code(StatusCode(200))
-                }
-                _ => Response::from_string("Didn't recognise ABC tune id.")
-                    .with_status_code(StatusCode(404)),
-            },
-            _ => Response::from_string("Didn't recognise ABC tune id.")
-                .with_status_code(StatusCode(404)),
-        },
-        _ => {
-            Response::from_string("Didn't recognise ABC tune id.").with_status_code(StatusCode(404))
+    let instrument = match fingering_param(request) {
+        Ok(instrument) => instrument,
+        Err(message) => return parameter_error("fingering", &message),
+    };
+
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise ABC tune id."),
+    };
+
+    let variant = match instrument {
+        Some(instrument) => format!("abc-{}", instrument),
+        None => "abc".to_string(),
+    };
+    let etag = etag(hash, &variant);
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let content = match instrument {
+                Some(instrument) => fingering::abc_with_fingerings(&content, instrument),
+                None => content,
+            };
+
+            with_cache_headers(
+                Response::from_string(content).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
         }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise ABC tune id."),
+    }
+}
+
+// The tune described in plain text, bar by bar, for screen readers and braille displays.
+fn api_text(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise text tune id."),
+    };
+
+    let etag = etag(hash, "txt");
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let text = representations::ast_to_text(&representations::abc_to_ast(&content));
+
+            with_cache_headers(
+                Response::from_string(text)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"text/plain; charset=utf-8"[..])
+                            .unwrap(),
+                    ).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise text tune id."),
     }
 }
 
 fn api_svg(
+    request: &Request,
     groups: &regex::Captures,
-    abc_cache: &mut storage::ReadOnlyCache,
+    abc_cache: &mut dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
 ) -> Response<Cursor<Vec<u8>>> {
-    match groups.get(1) {
-        Some(id) => {
-            match id.as_str().parse::<u32>() {
-                Ok(id) => {
-                    match abc_cache.get(id) {
-                        Some(content) => {
-                            // TODO AST already exists?
-                            let ast = representations::abc_to_ast(&content);
-                            let svg = representations::ast_to_svg(&ast);
-
-                            Response::from_string(svg)
-                                .with_header(
-                                    Header::from_bytes(&b"Content-Type"[..], &b"image/svg+xml"[..])
-                                        .unwrap(),
-                                ).with_status_code(StatusCode(200))
-                        }
-                        _ => Response::from_string("Didn't recognise SVG tune id.")
-                            .with_status_code(StatusCode(404)),
-                    }
+    let typesetting = match fingering_param(request) {
+        Ok(fingering) => typeset::Typesetting {
+            fingering,
+            ..*typesetting
+        },
+        Err(message) => return parameter_error("fingering", &message),
+    };
+
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise SVG tune id."),
+    };
+
+    let mut variant = "svg".to_string();
+    if let Some(instrument) = typesetting.fingering {
+        variant.push_str(&format!("-{}", instrument));
+    }
+    if !typesetting.auto_beam {
+        variant.push_str("-unbeamed");
+    }
+    let etag = etag(hash, &variant);
+    let modified = abc_cache.get_modified(id);
+
+    // Skip rendering altogether if the client has it.
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            // TODO AST already exists?
+            let ast = representations::abc_to_ast(&content);
+            let svg = representations::ast_to_svg(&ast, &typesetting);
+
+            with_cache_headers(
+                Response::from_string(svg)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"image/svg+xml"[..]).unwrap(),
+                    ).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise SVG tune id."),
+    }
+}
+
+// Small SVG of the first bars of the tune, for search results.
+fn api_incipit(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise incipit tune id."),
+    };
+
+    let variant = if typesetting.auto_beam {
+        "incipit"
+    } else {
+        "incipit-unbeamed"
+    };
+    let etag = etag(hash, variant);
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let ast = representations::abc_to_ast(&content);
+            let svg = representations::ast_to_incipit_svg(&ast, typesetting);
+
+            with_cache_headers(
+                Response::from_string(svg)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"image/svg+xml"[..]).unwrap(),
+                    ).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise incipit tune id."),
+    }
+}
+
+// Melody of a tune, as computed for indexing, for research.
+#[derive(Serialize, Debug)]
+struct TuneSequences {
+    id: u32,
+
+    // MIDI pitches as written, ignoring the key signature, as used by melody search.
+    pitches: Vec<u8>,
+    intervals: Vec<i16>,
+
+    // MIDI pitches as they sound, with the key signature and accidentals applied.
+    sounding_pitches: Vec<u8>,
+    sounding_intervals: Vec<i16>,
+
+    // Scale degrees, 1 to 7 from the key note.
+    degrees: Vec<u8>,
+}
+
+// The tune's pitch, interval and scale degree sequences as JSON.
+fn api_intervals(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise intervals tune id."),
+    };
+
+    let etag = etag(hash, "intervals");
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let ast = representations::abc_to_ast(&content);
+            let pitches = representations::ast_to_pitches(&ast);
+            let sounding_pitches = representations::ast_to_sounding_pitches(&ast);
+            let sequences = TuneSequences {
+                id,
+                intervals: representations::pitches_to_intervals(&pitches),
+                pitches,
+                sounding_intervals: representations::pitches_to_intervals(&sounding_pitches),
+                sounding_pitches,
+                degrees: representations::ast_to_degrees(&ast),
+            };
+
+            with_cache_headers(
+                json_response(request, &serde_json::to_value(&sequences).unwrap()),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise intervals tune id."),
+    }
+}
+
+// Positions of the notes and other glyphs in the tune's SVG, with the same options, so clients can
+// do their own rendering or hit-testing.
+fn api_layout(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
+) -> Response<Cursor<Vec<u8>>> {
+    let typesetting = match fingering_param(request) {
+        Ok(fingering) => typeset::Typesetting {
+            fingering,
+            ..*typesetting
+        },
+        Err(message) => return parameter_error("fingering", &message),
+    };
+
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise layout tune id."),
+    };
+
+    let mut variant = "layout".to_string();
+    if let Some(instrument) = typesetting.fingering {
+        variant.push_str(&format!("-{}", instrument));
+    }
+    if !typesetting.auto_beam {
+        variant.push_str("-unbeamed");
+    }
+    let etag = etag(hash, &variant);
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let layout = representations::abc_to_layout(&content, &typesetting);
+
+            with_cache_headers(
+                json_response(request, &serde_json::to_value(&layout).unwrap()),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise layout tune id."),
+    }
+}
+
+// PNG rendering of the whole tune, for places that can't show SVG.
+// Size with `?width=800` in pixels, or `?dpi=192`, where the SVG is 96 DPI.
+fn api_png(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
+) -> Response<Cursor<Vec<u8>>> {
+    let typesetting = match fingering_param(request) {
+        Ok(fingering) => typeset::Typesetting {
+            fingering,
+            ..*typesetting
+        },
+        Err(message) => return parameter_error("fingering", &message),
+    };
+
+    let width = query_param(request, "width");
+    let size = match representations::PngSize::parse(
+        width.as_deref(),
+        query_param(request, "dpi").as_deref(),
+    ) {
+        Ok(size) => size,
+        // Only a DPI on its own can be wrong without a width.
+        Err(message) => {
+            let parameter = if width.is_some() { "width" } else { "dpi" };
+            return parameter_error(parameter, &message);
+        }
+    };
+
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise PNG tune id."),
+    };
+
+    let mut variant = format!("png-{}", size.cache_key());
+    if let Some(instrument) = typesetting.fingering {
+        variant.push_str(&format!("-{}", instrument));
+    }
+    if !typesetting.auto_beam {
+        variant.push_str("-unbeamed");
+    }
+    let etag = etag(hash, &variant);
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let ast = representations::abc_to_ast(&content);
+            let png = representations::ast_to_png(&ast, &typesetting, size);
+
+            with_cache_headers(
+                Response::from_data(png)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"image/png"[..]).unwrap(),
+                    ).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise PNG tune id."),
+    }
+}
+
+// MIDI file of the tune, for playback.
+// Swing the quavers with `?swing=2` for the ratio of long to short, or `?swing=auto` to choose from
+// the tune's rhythm and metre.
+fn api_midi(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+) -> Response<Cursor<Vec<u8>>> {
+    let swing = match midi::Swing::parse(query_param(request, "swing").as_deref()) {
+        Ok(swing) => swing,
+        Err(message) => return parameter_error("swing", &message),
+    };
+
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise MIDI tune id."),
+    };
+
+    let etag = etag(hash, &format!("midi-{}", swing.cache_key()));
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    match abc_cache.get(id) {
+        Some(content) => {
+            let ast = representations::abc_to_ast(&content);
+            let midi = representations::ast_to_midi(&ast, swing);
+
+            with_cache_headers(
+                Response::from_data(midi)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"audio/midi"[..]).unwrap(),
+                    ).with_status_code(StatusCode(200)),
+                &etag,
+                modified,
+            )
+        }
+        _ => api_error(ErrorCode::NotFound, "Didn't recognise MIDI tune id."),
+    }
+}
+
+// Read a cached preview, or render and cache it.
+// If the cache directory isn't available the preview is still served, just not cached.
+fn cached_preview<F: Fn() -> Vec<u8>>(
+    previews_path: &Option<PathBuf>,
+    file_name: &str,
+    render: F,
+) -> Vec<u8> {
+    let path = match previews_path {
+        Some(ref previews_path) => previews_path.join(file_name),
+        None => return render(),
+    };
+
+    if let Ok(png) = fs::read(&path) {
+        return png;
+    }
+
+    let png = render();
+    if let Err(error) = write_atomically(&path, &png) {
+        warn!("Couldn't cache preview {:?}: {:?}", path, error);
+    }
+
+    png
+}
+
+// Write to a temporary file and rename it, so other threads never read a partial file.
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = path.with_extension(format!("{:08x}.tmp", rand::random::<u32>()));
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| file.write_all(content))
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+// Preview image for sharing links to the tune.
+fn api_preview(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &mut dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
+    previews_path: &Option<PathBuf>,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, hash) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise preview tune id."),
+    };
+
+    let variant = if typesetting.auto_beam {
+        "preview"
+    } else {
+        "preview-unbeamed"
+    };
+    let etag = etag(hash, variant);
+    let modified = abc_cache.get_modified(id);
+
+    if is_not_modified(request, &etag, modified) {
+        return with_cache_headers(
+            Response::from_string("").with_status_code(StatusCode(304)),
+            &etag,
+            modified,
+        );
+    }
+
+    let content = match abc_cache.get(id) {
+        Some(content) => content,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise preview tune id."),
+    };
+
+    // The file name changes with the tune, so stale previews are never served.
+    let file_name = format!(
+        "{}-{:016x}-{}-{}.png",
+        id,
+        hash,
+        variant,
+        env!("CARGO_PKG_VERSION")
+    );
+    let png = cached_preview(previews_path, &file_name, || {
+        representations::ast_to_preview_png(&representations::abc_to_ast(&content), typesetting)
+    });
+
+    with_cache_headers(
+        Response::from_data(png)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"image/png"[..]).unwrap())
+            .with_status_code(StatusCode(200)),
+        &etag,
+        modified,
+    )
+}
+
+// Time limits for searches.
+#[derive(Clone, Copy, Debug)]
+struct SearchLimits {
+    // Give up on a search after this long, if ever.
+    timeout: Option<Duration>,
+
+    // Log searches that take longer than this.
+    slow: Duration,
+}
+
+impl SearchLimits {
+    fn from_config(config: &config::Config) -> SearchLimits {
+        SearchLimits {
+            timeout: match config.search_timeout_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            slow: Duration::from_millis(config.slow_query_ms),
+        }
+    }
+}
+
+// Search within the time limit, logging the request and query if it's slow or runs out of time.
+// None if it runs out of time.
+fn timed_search(
+    request: &Request,
+    query: &query::Query,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
+) -> Option<search::SearchResults> {
+    let start = Instant::now();
+    let deadline = match limits.timeout {
+        Some(timeout) => search::Deadline::after(timeout),
+        None => search::Deadline::none(),
+    };
+
+    let results = searcher.search_before(query, deadline);
+
+    let elapsed = start.elapsed();
+    if results.is_none() {
+        warn!(
+            "Search timed out after {} ms: {} {} {}",
+            elapsed.as_millis(),
+            request.method(),
+            request.url(),
+            serde_json::json!(query)
+        );
+    } else if elapsed >= limits.slow {
+        warn!(
+            "Slow search, {} ms: {} {} {}",
+            elapsed.as_millis(),
+            request.method(),
+            request.url(),
+            serde_json::json!(query)
+        );
+    }
+
+    results
+}
+
+const SEARCH_TIMEOUT_MESSAGE: &str =
+    "The search took too long. Try a shorter melody, or add filters.";
+
+fn search_timeout() -> Response<Cursor<Vec<u8>>> {
+    api_error(ErrorCode::Timeout, SEARCH_TIMEOUT_MESSAGE)
+}
+
+// Search.
+fn api_search(
+    request: &Request,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
+) -> Response<Cursor<Vec<u8>>> {
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+
+    match Url::join(&base, request.url()) {
+        Err(error) => {
+            warn!("Invalid URL {}: {:?}", request.url(), error);
+            api_error(ErrorCode::InvalidRequest, "Invalid URL...")
+        }
+        Ok(url) => {
+            let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+
+            match searcher.parse_query(params) {
+                Err(err) => query_error(err, ErrorCode::InvalidRequest),
+                Ok(query) => search_response(request, &query, searcher, limits),
+            }
+        }
+    }
+}
+
+// The canonical query string for a search, e.g. for links that can be shared or cached. Equal
+// searches give the same string: parameters are sorted, and those with the default value or that
+// aren't part of the search are left out.
+fn api_normalize(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+
+    match Url::join(&base, request.url()) {
+        Err(error) => {
+            warn!("Invalid URL {}: {:?}", request.url(), error);
+            api_error(ErrorCode::InvalidRequest, "Invalid URL...")
+        }
+        Ok(url) => {
+            let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+
+            match searcher.parse_query(params) {
+                Err(err) => query_error(err, ErrorCode::InvalidRequest),
+                Ok(query) => {
+                    let params = form_urlencoded::Serializer::new(String::new())
+                        .extend_pairs(query.to_params())
+                        .finish();
+                    json_response(request, &serde_json::json!({ "params": params }))
+                }
+            }
+        }
+    }
+}
+
+// Run a search and respond with the JSON results.
+fn search_response(
+    request: &Request,
+    query: &query::Query,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
+) -> Response<Cursor<Vec<u8>>> {
+    let (num_total_results, num_unique_results, facets, results) =
+        match timed_search(request, query, searcher, limits) {
+            Some(results) => results,
+            None => return search_timeout(),
+        };
+
+    let result_body = serde_json::json!({
+                "query": query,
+                "total": num_total_results,
+                "unique": num_unique_results,
+                "results": results,
+                "facets": facets,
+            });
+
+    json_response(request, &result_body)
+}
+
+// Tunes like this one, scored by the interval index as when clustering, above a `cutoff`.
+// Other search parameters, such as filters and rows, are taken from the query string.
+fn api_similar(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &dyn storage::TuneStore,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, _) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise similar tune id."),
+    };
+
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+
+    match Url::join(&base, request.url()) {
+        Err(error) => {
+            warn!("Invalid URL {}: {:?}", request.url(), error);
+            api_error(ErrorCode::InvalidRequest, "Invalid URL...")
+        }
+        Ok(url) => {
+            let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+
+            match searcher.parse_similar_query(id as usize, params) {
+                Err(err) => query_error(err, ErrorCode::InvalidRequest),
+                Ok(query) => search_response(request, &query, searcher, limits),
+            }
+        }
+    }
+}
+
+// Other tunes that only differ from this one in whitespace, regardless of clustering.
+fn api_duplicates(
+    request: &Request,
+    groups: &regex::Captures,
+    abc_cache: &dyn storage::TuneStore,
+    searcher: &search::SearchEngine,
+) -> Response<Cursor<Vec<u8>>> {
+    let (id, _) = match tune_id_and_hash(groups, abc_cache) {
+        Some(found) => found,
+        None => return api_error(ErrorCode::NotFound, "Didn't recognise duplicates tune id."),
+    };
+
+    let body = serde_json::json!({
+        "id": id,
+        "duplicates": searcher.exact_duplicates(id),
+    });
+
+    json_response(request, &body)
+}
+
+// A random tune, optionally filtered, e.g. /api/v3/tunes/random?rhythm=jig
+fn api_random(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    pick_response(request, searcher, rand::random::<u64>(), None, None)
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Tune of the day. The same all day (UTC) for the same filters, so it can be cached until midnight.
+fn api_daily(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    let now = time::now_utc();
+    let seconds = now.to_timespec().sec;
+    let day = seconds.div_euclid(SECONDS_PER_DAY);
+    let date = format!("{}", now.strftime("%Y-%m-%d").unwrap());
+
+    let max_age = (day + 1) * SECONDS_PER_DAY - seconds;
+    pick_response(
+        request,
+        searcher,
+        query::daily_seed(day),
+        Some(&date),
+        Some(max_age),
+    )
+}
+
+// Respond with one tune picked by the seed from those matching the filters in the query string.
+// The tune can be cached for `max_age` seconds, but errors, e.g. for a bad filter, aren't cached.
+fn pick_response(
+    request: &Request,
+    searcher: &search::SearchEngine,
+    seed: u64,
+    date: Option<&str>,
+    max_age: Option<i64>,
+) -> Response<Cursor<Vec<u8>>> {
+    let base = Url::parse("http://0.0.0.0/").unwrap();
+
+    let params: Vec<(String, String)> = match Url::join(&base, request.url()) {
+        Ok(url) => url.query_pairs().into_owned().collect(),
+        Err(error) => {
+            warn!("Invalid URL {}: {:?}", request.url(), error);
+            return api_error(ErrorCode::InvalidRequest, "Invalid URL...");
+        }
+    };
+
+    let query = match searcher.parse_pick_query(params, seed) {
+        Ok(query) => query,
+        Err(err) => return query_error(err, ErrorCode::InvalidRequest),
+    };
+
+    let (_, _, _, results) = searcher.search(&query);
+
+    match results.into_iter().next() {
+        None => api_error(ErrorCode::NotFound, "No tunes match the filters."),
+        Some(tune) => {
+            let mut result_body = serde_json::json!({ "tune": tune });
+            if let Some(date) = date {
+                result_body["date"] = serde_json::json!(date);
+            }
+
+            let response = json_response(request, &result_body);
+            match max_age {
+                Some(max_age) => {
+                    let value = format!("max-age={}", max_age);
+                    response.with_header(
+                        Header::from_bytes(&b"Cache-Control"[..], value.as_bytes()).unwrap(),
+                    )
                 }
-                _ => Response::from_string("Didn't recognise SVG tune id.")
-                    .with_status_code(StatusCode(404)),
+                None => response,
             }
         }
-        _ => {
-            Response::from_string("Didn't recognise SVG tune id.").with_status_code(StatusCode(404))
-        }
     }
 }
 
-// Search.
-fn api_search(request: &Request, searcher: &mut search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+// Largest request body that's read, in bytes. Plenty for a tune, or a file of them to check.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+// A request body of ABC, reading no more than `MAX_BODY_BYTES` of it, so a huge body can't use up
+// the server's memory.
+fn read_body<R: Read>(reader: R) -> Result<String, Response<Cursor<Vec<u8>>>> {
+    let mut bytes = vec![];
+    if let Err(error) = reader.take(MAX_BODY_BYTES + 1).read_to_end(&mut bytes) {
+        warn!("Couldn't read request body: {:?}", error);
+        return Err(api_error(ErrorCode::InvalidRequest, "Couldn't read ABC."));
+    }
+
+    if bytes.len() as u64 > MAX_BODY_BYTES {
+        return Err(api_error(
+            ErrorCode::TooLarge,
+            &format!("ABC can't be more than {} bytes.", MAX_BODY_BYTES),
+        ));
+    }
+
+    String::from_utf8(bytes)
+        .map_err(|_| api_error(ErrorCode::InvalidRequest, "Couldn't read ABC."))
+}
+
+// Search by melody, with a snippet of ABC as the POST body.
+// Other search parameters are taken from the query string.
+fn api_search_abc(
+    request: &mut Request,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
+) -> Response<Cursor<Vec<u8>>> {
+    if *request.method() != Method::Post {
+        return api_error(
+            ErrorCode::MethodNotAllowed,
+            "POST a snippet of ABC to search.",
+        );
+    }
+
+    let snippet = match read_body(request.as_reader()) {
+        Ok(snippet) => snippet,
+        Err(response) => return response,
+    };
+
     let base = Url::parse("http://0.0.0.0/").unwrap();
 
     match Url::join(&base, request.url()) {
         Err(error) => {
-            eprint!("Error: {:?}", error);
-            Response::from_string("Invalid URL...").with_status_code(StatusCode(400))
+            warn!("Invalid URL {}: {:?}", request.url(), error);
+            api_error(ErrorCode::InvalidRequest, "Invalid URL...")
         }
         Ok(url) => {
-            let mut params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+            let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
 
-            match searcher.parse_query(params) {
-                Err(message) => Response::from_string(message).with_status_code(StatusCode(400)),
-                Ok(query) => {
-                    let (num_total_results, num_unique_results, facets, results) =
-                        searcher.search(&query);
-
-                    let result_body = serde_json::json!({
-                                "query": query,
-                                "total": num_total_results,
-                                "unique": num_unique_results,
-                                "results": results,
-                                "facets": facets,
-                            });
-
-                    Response::from_string(result_body.to_string())
-                        .with_status_code(StatusCode(200))
-                        .with_header(
-                            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
-                                .unwrap(),
-                        )
-                }
+            match searcher.parse_abc_query(&snippet, params) {
+                Err(err) => query_error(err, ErrorCode::InvalidAbc),
+                Ok(query) => search_response(request, &query, searcher, limits),
             }
         }
     }
 }
 
+// Check ABC for an editor, with the ABC as the POST body. Responds with an array of lexer errors
+// in every tune, empty if there are none. Positions are in the whole body, so they can be shown
+// inline.
+fn api_validate(request: &mut Request, dialect: abc_lexer::Dialect) -> Response<Cursor<Vec<u8>>> {
+    if *request.method() != Method::Post {
+        return api_error(ErrorCode::MethodNotAllowed, "POST ABC to check it.");
+    }
+
+    let content = match read_body(request.as_reader()) {
+        Ok(content) => content,
+        Err(response) => return response,
+    };
+
+    // Tunes are lexed one at a time, as `abctool check` does, so an error in one doesn't run on
+    // into the next. They're contiguous, so each starts where the last one ended.
+    let mut errors = vec![];
+    let mut start_offset = 0;
+    for (_, start_line, tune) in storage::split_tunes(&content) {
+        let chars = tune.chars().collect::<Vec<char>>();
+        for mut error in abc_errors(&chars, dialect) {
+            error.line += start_line - 1;
+            error.offset += start_offset;
+            errors.push(error);
+        }
+        start_offset += chars.len();
+    }
+
+    json_response(request, &serde_json::to_value(&errors).unwrap())
+}
+
+// Check a tune that's been sent to be stored.
+// Tunes that don't lex cleanly are rejected with the same error message as `abctool check`, and
+// the position of each error in the ABC as it was sent.
+fn validate_tune(
+    content: &str,
+    dialect: abc_lexer::Dialect,
+) -> Result<(), Response<Cursor<Vec<u8>>>> {
+    if storage::split_tunes(content).len() > 1 {
+        return Err(api_error(
+            ErrorCode::InvalidAbc,
+            "Submit one tune at a time.",
+        ));
+    }
+
+    let chars = content.chars().collect::<Vec<char>>();
+    let (num_errors, _, message) = abc_lexer::format_error_message_in_dialect(&chars, dialect);
+    if num_errors > 0 {
+        return Err(error_response(ApiError {
+            code: ErrorCode::InvalidAbc,
+            message,
+            parameter: None,
+            errors: abc_errors(&chars, dialect),
+        }));
+    }
+
+    let ast = representations::abc_to_ast(&abc_lexer::to_standard(content, dialect));
+    let has_notes = ast
+        .voices
+        .iter()
+        .any(|voice| voice.iter().any(|x| matches!(x, abc_lexer::T::Note(_))));
+    if !has_notes {
+        return Err(api_error(
+            ErrorCode::InvalidAbc,
+            "There are no notes in this tune.",
+        ));
+    }
+
+    Ok(())
+}
+
+// Add a tune, with its ABC as the POST body.
+fn api_submit(
+    request: &mut Request,
+    searcher: &RwLock<search::SearchEngine>,
+    dialect: abc_lexer::Dialect,
+) -> Response<Cursor<Vec<u8>>> {
+    let content = match read_body(request.as_reader()) {
+        Ok(content) => content,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = validate_tune(&content, dialect) {
+        return response;
+    }
+
+    // Tunes are stored with the standard spelling, as a scan stores them.
+    let content = abc_lexer::to_standard(&content, dialect);
+
+    let result = write_searcher(searcher).add_tune(&content);
+    match result {
+        Err(message) => {
+            error!("Couldn't add tune: {}", message);
+            api_error(ErrorCode::InternalError, "Couldn't add tune.")
+        }
+        Ok(tune_id) => {
+            let location = format!("/api/v3/tunes/{}.abc", tune_id);
+            json_response(request, &serde_json::json!({ "id": tune_id }))
+                .with_status_code(StatusCode(201))
+                .with_header(Header::from_bytes(&b"Location"[..], location.as_bytes()).unwrap())
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct HtmlSearchContext {
-    query: search::Query,
+    query: query::Query,
     num_total_results: usize,
     num_unique_results: usize,
     results: Vec<search::DecoratedResult>,
     facets: Option<HashMap<String, Vec<(String, u32)>>>,
+
+    // Show the first bars of each result, with `incipits=true`.
+    incipits: bool,
+
+    // Path and query of the page, for building links with `url_with_param`.
+    url: String,
+
+    // Offsets of the next and previous pages, if there are any.
+    next_offset: Option<usize>,
+    prev_offset: Option<usize>,
 }
 
 // Search.
 // TODO add links for navigation:
-// - next page link
-// - prev page link
 // - all vs rollup
-// - add filters
-// - remove filters
 fn html_search(
     request: &Request,
-    searcher: &mut search::SearchEngine,
+    searcher: &search::SearchEngine,
+    limits: &SearchLimits,
     handlebars: &Handlebars,
 ) -> Response<Cursor<Vec<u8>>> {
     let base = Url::parse("http://0.0.0.0/").unwrap();
 
     match Url::join(&base, request.url()) {
         Err(error) => {
-            eprint!("Error: {:?}", error);
+            warn!("Invalid URL {}: {:?}", request.url(), error);
             Response::from_string("Invalid URL...").with_status_code(StatusCode(400))
         }
         Ok(url) => {
-            let mut params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+            let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
 
             match searcher.parse_query(params) {
                 // TODO bit nicer message.
-                Err(message) => Response::from_string(message).with_status_code(StatusCode(400)),
+                Err(err) => Response::from_string(err.message).with_status_code(StatusCode(400)),
                 Ok(query) => {
                     let (num_total_results, num_unique_results, facets, results) =
-                        searcher.search(&query);
+                        match timed_search(request, &query, searcher, limits) {
+                            Some(results) => results,
+                            None => {
+                                return Response::from_string(SEARCH_TIMEOUT_MESSAGE)
+                                    .with_status_code(StatusCode(503))
+                            }
+                        };
+
+                    let incipits = match query_param(request, "incipits") {
+                        Some(value) => value == "true" || value == "on",
+                        None => false,
+                    };
+
+                    let next_offset = query.selection.next_offset(num_unique_results);
+                    let prev_offset = query.selection.prev_offset();
 
                     let context = HtmlSearchContext {
                         query,
@@ -151,6 +1391,10 @@ fn html_search(
                         num_unique_results,
                         results,
                         facets,
+                        incipits,
+                        url: request.url().to_string(),
+                        next_offset,
+                        prev_offset,
                     };
 
                     Response::from_string(
@@ -168,12 +1412,84 @@ fn html_search(
     }
 }
 
-fn html_from_template(
+#[derive(Serialize)]
+struct HtmlTuneVersion {
+    id: usize,
+    titles: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct HtmlTuneContext {
+    id: u32,
+    titles: Vec<String>,
+    headers: Vec<(String, String)>,
+    svg: String,
+
+    // Where the tune came from and its licence, from the metadata file.
+    source: Option<String>,
+    licence: Option<String>,
+
+    // Other versions of the tune from the same cluster.
+    versions: Vec<HtmlTuneVersion>,
+}
+
+// Tune page, with the notation, headers and other versions.
+fn html_tune(
     request: &Request,
+    groups: &regex::Captures,
+    searcher: &search::SearchEngine,
+    abc_cache: &dyn storage::TuneStore,
+    typesetting: &typeset::Typesetting,
+    handlebars: &Handlebars,
+) -> Response<Cursor<Vec<u8>>> {
+    let found = groups
+        .get(1)
+        .and_then(|id| id.as_str().parse::<u32>().ok())
+        .and_then(|id| abc_cache.get(id).map(|content| (id, content)));
+
+    let (id, content) = match found {
+        Some(found) => found,
+        None => {
+            return Response::from_string("Didn't recognise tune id.")
+                .with_status_code(StatusCode(404))
+        }
+    };
+
+    let ast = representations::abc_to_ast(&content);
+
+    let clusters = searcher.get_clusters();
+    let versions = match clusters.get(id as usize) {
+        Some(group_id) => clusters
+            .get_members(group_id)
+            .into_iter()
+            .filter(|member| *member != id as usize)
+            .map(|member| HtmlTuneVersion {
+                id: member,
+                titles: searcher.titles(member as u32),
+            }).collect(),
+        None => vec![],
+    };
+
+    let context = HtmlTuneContext {
+        id,
+        titles: representations::titles(&ast),
+        headers: representations::headers(&ast),
+        svg: representations::ast_to_svg(&ast, typesetting),
+        source: searcher.meta(id).and_then(|meta| meta.source.clone()),
+        licence: searcher.meta(id).and_then(|meta| meta.licence.clone()),
+        versions,
+    };
+
+    html_from_template(request, "tune", &context, handlebars)
+}
+
+fn html_from_template<T: Serialize>(
+    _request: &Request,
     template: &str,
+    context: &T,
     handlebars: &Handlebars,
 ) -> Response<Cursor<Vec<u8>>> {
-    if !handlebars.has_template(&template) {
+    if !handlebars.has_template(template) {
         return Response::from_string("Couldn't find that.".to_string())
             .with_status_code(StatusCode(400))
             .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..]).unwrap());
@@ -181,7 +1497,7 @@ fn html_from_template(
 
     Response::from_string(
         handlebars
-            .render(template, &HashMap::<String, String>::new())
+            .render(template, context)
             .unwrap_or("Template error!".to_string())
             .to_string(),
     ).with_status_code(StatusCode(200))
@@ -194,104 +1510,881 @@ fn html_free(
     handlebars: &Handlebars,
 ) -> Response<Cursor<Vec<u8>>> {
     match groups.get(1) {
-        Some(template) => html_from_template(request, template.as_str(), handlebars),
+        Some(template) => html_from_template(
+            request,
+            template.as_str(),
+            &HashMap::<String, String>::new(),
+            handlebars,
+        ),
         _ => Response::from_string("Not found!").with_status_code(StatusCode(404)),
     }
 }
 
-fn features(_request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+fn features(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
     let result = searcher.get_features();
 
-    let body = serde_json::json!(result);
+    json_response(request, &serde_json::json!(result))
+}
 
-    Response::from_string(body.to_string())
-        .with_status_code(StatusCode(200))
-        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+// Describe the clusters used for roll-up, including the settings that produced them.
+fn clusters(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    let clusters = searcher.get_clusters();
+
+    let body = serde_json::json!({
+        "params": clusters.params,
+        "groups": clusters.num_groups(),
+    });
+
+    json_response(request, &body)
+}
+
+// Seconds to suggest waiting before trying a search again while indexing.
+const RETRY_AFTER_SECONDS: &str = "10";
+
+// Error for a request that needs the index before it's built.
+fn still_indexing(progress: &search::IndexProgress) -> Response<Cursor<Vec<u8>>> {
+    let message = format!(
+        "Still indexing, {} of {} tunes done. Try again soon.",
+        progress.indexed(),
+        progress.total()
+    );
+
+    api_error(ErrorCode::Unavailable, &message).with_header(
+        Header::from_bytes(&b"Retry-After"[..], RETRY_AFTER_SECONDS.as_bytes()).unwrap(),
+    )
+}
+
+// Error for a request that needs the index when building it failed.
+fn indexing_failed() -> Response<Cursor<Vec<u8>>> {
+    api_error(
+        ErrorCode::InternalError,
+        "Indexing failed, so searches aren't available. See the server log.",
+    )
+}
+
+// Whether searches are available yet, and how far indexing has got.
+fn status(request: &Request, progress: &search::IndexProgress) -> Response<Cursor<Vec<u8>>> {
+    json_response(
+        request,
+        &serde_json::json!({
+            "ready": progress.is_done(),
+            "failed": progress.has_failed(),
+            "indexed": progress.indexed(),
+            "total": progress.total(),
+        }),
+    )
+}
+
+// Number of tunes and clusters. A new installation has no tunes until the first scan.
+fn stats(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    json_response(request, &serde_json::json!(searcher.get_stats()))
+}
+
+// Number of title completions, by default and at most.
+const DEFAULT_COMPLETIONS: usize = 10;
+const MAX_COMPLETIONS: usize = text::MAX_COMPLETIONS;
+
+// Title completions for a search box, in the OpenSearch suggestions format, e.g.
+// `["butt", ["The Butterfly", "Butter Churn"]]`.
+fn autocomplete(request: &Request, searcher: &search::SearchEngine) -> Response<Cursor<Vec<u8>>> {
+    let prefix = query_param(request, "prefix").unwrap_or_default();
+
+    let limit = match query_param(request, "limit") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(limit) if limit > 0 && limit <= MAX_COMPLETIONS => limit,
+            _ => {
+                return parameter_error(
+                    "limit",
+                    &format!(
+                        "Invalid value for 'limit'. Must be between 1 and {}.",
+                        MAX_COMPLETIONS
+                    ),
+                )
+            }
+        },
+        None => DEFAULT_COMPLETIONS,
+    };
+
+    let titles: Vec<String> = searcher
+        .autocomplete(&prefix, limit)
+        .into_iter()
+        .map(|(title, _)| title)
+        .collect();
+
+    json_response(request, &serde_json::json!([prefix, titles]))
+}
+
+// Admin endpoints are only available when the ADMIN_TOKEN environment variable is set, and must
+// be called with an `Authorization: Bearer <token>` header.
+fn is_admin(request: &Request, admin_token: &Option<String>) -> bool {
+    match admin_token {
+        Some(token) => request.headers().iter().any(|header| {
+            header.field.equiv("Authorization")
+                && header.value.as_str() == format!("Bearer {}", token)
+        }),
+        None => false,
+    }
+}
+
+// Reload the clusters file, e.g. after running `cluster` again, without restarting.
+// The file is loaded before taking the write lock, so searches only wait for the swap.
+fn admin_reload(
+    request: &Request,
+    searcher: &RwLock<search::SearchEngine>,
+    clusters_path: &Option<PathBuf>,
+) -> Response<Cursor<Vec<u8>>> {
+    if *request.method() != Method::Post {
+        return api_error(ErrorCode::MethodNotAllowed, "POST to reload clusters.");
+    }
+
+    let path = match clusters_path {
+        Some(path) if path.exists() => path,
+        _ => return api_error(ErrorCode::InternalError, "No clusters file to reload."),
+    };
+
+    let clusters = relations::Clusters::load(path);
+    let body = serde_json::json!({
+        "params": clusters.params,
+        "groups": clusters.num_groups(),
+    });
+
+    match write_searcher(searcher).set_clusters(clusters) {
+        Err(message) => api_error(ErrorCode::Conflict, &message),
+        Ok(_) => {
+            info!("Reloaded clusters from {}", path.display());
+
+            Response::from_string(body.to_string())
+                .with_status_code(StatusCode(200))
+                .with_header(
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+                )
+        }
+    }
+}
+
+// Replace a tune with the ABC in the POST body, or with an empty body, index it again from the
+// tunecache as it is.
+fn admin_tune(
+    request: &mut Request,
+    groups: &regex::Captures,
+    searcher: &RwLock<search::SearchEngine>,
+    dialect: abc_lexer::Dialect,
+) -> Response<Cursor<Vec<u8>>> {
+    if *request.method() != Method::Post {
+        return api_error(ErrorCode::MethodNotAllowed, "POST ABC to replace the tune.");
+    }
+
+    let tune_id = match groups.get(1).and_then(|id| id.as_str().parse::<u32>().ok()) {
+        Some(tune_id) => tune_id,
+        None => return api_error(ErrorCode::InvalidRequest, "Invalid tune id."),
+    };
+
+    let content = match read_body(request.as_reader()) {
+        Ok(content) => content,
+        Err(response) => return response,
+    };
+
+    // An empty body reindexes the tune as it is.
+    let reindex = content.trim().is_empty();
+    if !reindex {
+        if let Err(response) = validate_tune(&content, dialect) {
+            return response;
+        }
+    }
+
+    let mut searcher = write_searcher(searcher);
+    if searcher.abc_cache.get_hash(tune_id).is_none() {
+        return api_error(ErrorCode::NotFound, "Didn't recognise ABC tune id.");
+    }
+
+    let result = if reindex {
+        searcher.reindex_tune(tune_id)
+    } else {
+        searcher.replace_tune(tune_id, &abc_lexer::to_standard(&content, dialect))
+    };
+
+    match result {
+        Err(message) => {
+            error!("Couldn't reindex tune {}: {}", tune_id, message);
+            api_error(ErrorCode::InternalError, "Couldn't reindex tune.")
+        }
+        Ok(_) => json_response(request, &serde_json::json!({ "id": tune_id })),
+    }
+}
+
+// Stop serving. Each worker finishes the request it's handling before it stops.
+fn admin_shutdown(request: &Request, shutdown: &AtomicBool) -> Response<Cursor<Vec<u8>>> {
+    if *request.method() != Method::Post {
+        return api_error(ErrorCode::MethodNotAllowed, "POST to shut down.");
+    }
+
+    shutdown.store(true, Ordering::SeqCst);
+    Response::from_string("Shutting down.").with_status_code(StatusCode(200))
+}
+
+fn unauthorized() -> Response<Cursor<Vec<u8>>> {
+    api_error(ErrorCode::Unauthorized, "Unauthorized.")
+}
+
+// A template helper's parameter as a string, e.g. numbers as "30". Missing parameters and null are
+// empty.
+fn helper_param(h: &Helper, index: usize) -> String {
+    h.param(index)
+        .map(|x| x.value().render())
+        .unwrap_or_default()
+}
+
+// `{{url_with_param url "offset" 30}}` is the URL, a path and query, with the parameter set to the
+// value, replacing any it had. An empty value removes the parameter, e.g. to remove a filter.
+struct UrlWithParam;
+
+impl HelperDef for UrlWithParam {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc handlebars::Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let key = helper_param(h, 1);
+        let value = helper_param(h, 2);
+
+        let base = Url::parse("http://0.0.0.0/").unwrap();
+        let mut url = Url::join(&base, &helper_param(h, 0))
+            .map_err(|_| RenderError::new("url_with_param needs a URL"))?;
+
+        let mut params: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(k, _)| *k != key)
+            .collect();
+        if !value.is_empty() {
+            params.push((key, value));
+        }
+
+        if params.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(params);
+        }
+
+        let result = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        Ok(Some(ScopedJson::Derived(serde_json::Value::String(result))))
+    }
+}
+
+// `{{format_score score}}` rounds a score to 2 decimal places, or `{{format_score score 1}}` to
+// another number.
+struct FormatScore;
+
+impl HelperDef for FormatScore {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc handlebars::Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let score = h
+            .param(0)
+            .and_then(|x| x.value().as_f64())
+            .ok_or_else(|| RenderError::new("format_score needs a number"))?;
+        let places = h.param(1).and_then(|x| x.value().as_u64()).unwrap_or(2) as usize;
+
+        let result = format!("{:.*}", places, score);
+        Ok(Some(ScopedJson::Derived(serde_json::Value::String(result))))
+    }
+}
+
+// `{{pluralize count "tune"}}` is e.g. "1 tune" or "3 tunes". Give the plural if it isn't just
+// an "s" on the end, e.g. `{{pluralize count "match" "matches"}}`.
+struct Pluralize;
+
+impl HelperDef for Pluralize {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc handlebars::Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let count = h
+            .param(0)
+            .and_then(|x| x.value().as_u64())
+            .ok_or_else(|| RenderError::new("pluralize needs a count"))?;
+        let singular = helper_param(h, 1);
+
+        let word = match (count, h.param(2)) {
+            (1, _) => singular,
+            (_, Some(_)) => helper_param(h, 2),
+            (_, None) => format!("{}s", singular),
+        };
+
+        let result = format!("{} {}", count, word);
+        Ok(Some(ScopedJson::Derived(serde_json::Value::String(result))))
+    }
 }
 
 // Return a Handlebars object for templating HTML. This is optional, and by default only the API
 // is available.
-// If there is a template directory specified in the HTML_TEMPLATES environment variable, load that
-// directory.
-fn build_template_registry() -> Handlebars {
+// If there is a template directory in the config, load that directory.
+fn build_template_registry(path: Option<&Path>) -> Handlebars {
     let mut handlebars = Handlebars::new();
 
-    let key = "HTML_TEMPLATES";
-    if let Ok(path) = env::var(key) {
-        match handlebars.register_templates_directory(".html", &path) {
-            Err(err) => {
-                eprintln!("Error loading template dir {} : {:?}", &path, err);
-            }
-            _ => (),
+    handlebars.register_helper("url_with_param", Box::new(UrlWithParam));
+    handlebars.register_helper("format_score", Box::new(FormatScore));
+    handlebars.register_helper("pluralize", Box::new(Pluralize));
+
+    if let Some(path) = path {
+        if let Err(err) = handlebars.register_templates_directory(".html", path) {
+            error!("Error loading template dir {} : {:?}", path.display(), err);
         }
     }
 
     handlebars
 }
 
-pub fn main(mut searcher: search::SearchEngine) {
+// Regular expressions for routing requests.
+struct Routes {
     // API endpoints.
-    // There have been folktunefinders before.
-    let re_api_abc = regex::Regex::new(r"^/api/v3/tunes/(\d+).abc$").unwrap();
-    let re_api_svg = regex::Regex::new(r"^/api/v3/tunes/(\d+).svg$").unwrap();
-    let re_api_tunes = regex::Regex::new(r"^/api/v3/tunes(\?.*)?$").unwrap();
-    let re_api_features = regex::Regex::new(r"^/api/v3/features$").unwrap();
+    api_abc: regex::Regex,
+    api_svg: regex::Regex,
+    api_text: regex::Regex,
+    api_png: regex::Regex,
+    api_midi: regex::Regex,
+    api_preview: regex::Regex,
+    api_incipit: regex::Regex,
+    api_layout: regex::Regex,
+    api_intervals: regex::Regex,
+    api_duplicates: regex::Regex,
+    api_similar: regex::Regex,
+    api_random: regex::Regex,
+    api_daily: regex::Regex,
+    api_tunes: regex::Regex,
+    api_search_abc: regex::Regex,
+    api_normalize: regex::Regex,
+    api_features: regex::Regex,
+    api_clusters: regex::Regex,
+    api_stats: regex::Regex,
+    api_autocomplete: regex::Regex,
+    api_validate: regex::Regex,
+    api_status: regex::Regex,
+
+    // Admin endpoints.
+    admin_reload: regex::Regex,
+    admin_shutdown: regex::Regex,
+    admin_tune: regex::Regex,
 
     // HTML endpoints.
-    let re_html_home = regex::Regex::new(r"^/$").unwrap();
-    let re_html_tunes = regex::Regex::new(r"/tunes(\?.*)?$").unwrap();
-    let re_html_tune = regex::Regex::new(r"/tunes/(\d+)$").unwrap();
+    html_home: regex::Regex,
+    html_tunes: regex::Regex,
+    html_tune: regex::Regex,
+    html_wildcard: regex::Regex,
+}
+
+impl Routes {
+    fn new() -> Routes {
+        // There have been folktunefinders before.
+        Routes {
+            api_abc: regex::Regex::new(r"^/api/v3/tunes/(\d+).abc(\?.*)?$").unwrap(),
+            api_svg: regex::Regex::new(r"^/api/v3/tunes/(\d+).svg(\?.*)?$").unwrap(),
+            api_text: regex::Regex::new(r"^/api/v3/tunes/(\d+).txt(\?.*)?$").unwrap(),
+            api_png: regex::Regex::new(r"^/api/v3/tunes/(\d+).png(\?.*)?$").unwrap(),
+            api_midi: regex::Regex::new(r"^/api/v3/tunes/(\d+).mid(\?.*)?$").unwrap(),
+            api_preview: regex::Regex::new(r"^/api/v3/tunes/(\d+)/preview.png(\?.*)?$").unwrap(),
+            api_incipit: regex::Regex::new(r"^/api/v3/tunes/(\d+)/incipit.svg(\?.*)?$").unwrap(),
+            api_layout: regex::Regex::new(r"^/api/v3/tunes/(\d+)/layout.json(\?.*)?$").unwrap(),
+            api_intervals: regex::Regex::new(r"^/api/v3/tunes/(\d+)/intervals(\?.*)?$").unwrap(),
+            api_random: regex::Regex::new(r"^/api/v3/tunes/random(\?.*)?$").unwrap(),
+            api_daily: regex::Regex::new(r"^/api/v3/tunes/daily(\?.*)?$").unwrap(),
+            api_duplicates: regex::Regex::new(r"^/api/v3/tunes/(\d+)/duplicates(\?.*)?$").unwrap(),
+            api_similar: regex::Regex::new(r"^/api/v3/tunes/(\d+)/similar(\?.*)?$").unwrap(),
+            api_tunes: regex::Regex::new(r"^/api/v3/tunes(\?.*)?$").unwrap(),
+            api_search_abc: regex::Regex::new(r"^/api/v3/search/abc(\?.*)?$").unwrap(),
+            api_normalize: regex::Regex::new(r"^/api/v3/query/normalize(\?.*)?$").unwrap(),
+            api_features: regex::Regex::new(r"^/api/v3/features(\?.*)?$").unwrap(),
+            api_clusters: regex::Regex::new(r"^/api/v3/clusters(\?.*)?$").unwrap(),
+            api_stats: regex::Regex::new(r"^/api/v3/stats(\?.*)?$").unwrap(),
+            api_autocomplete: regex::Regex::new(r"^/api/v3/autocomplete(\?.*)?$").unwrap(),
+            api_validate: regex::Regex::new(r"^/api/v3/validate(\?.*)?$").unwrap(),
+            api_status: regex::Regex::new(r"^/api/v3/status(\?.*)?$").unwrap(),
 
-    let re_html_wildcard = regex::Regex::new(r"^/(.+)$").unwrap();
+            admin_reload: regex::Regex::new(r"^/admin/reload$").unwrap(),
+            admin_shutdown: regex::Regex::new(r"^/admin/shutdown$").unwrap(),
+            admin_tune: regex::Regex::new(r"^/admin/tunes/(\d+)$").unwrap(),
 
-    let key = "HTTP_BIND";
-    let bind = match env::var(key) {
-        Ok(address) => address,
-        Err(_) => {
-            eprintln!("Using bind default HTTP_BIND address of : 0.0.0.0:8000");
-            "0.0.0.0:8000".to_string()
+            html_home: regex::Regex::new(r"^/$").unwrap(),
+            html_tunes: regex::Regex::new(r"/tunes(\?.*)?$").unwrap(),
+            html_tune: regex::Regex::new(r"^/tunes/(\d+)$").unwrap(),
+            html_wildcard: regex::Regex::new(r"^/(.+)$").unwrap(),
         }
-    };
+    }
+
+    // Does the route need the index, so isn't available while it's being built?
+    // Tunes by ID only need the tunecache.
+    fn needs_index(&self, url: &str) -> bool {
+        [
+            &self.admin_reload,
+            &self.admin_tune,
+            &self.api_duplicates,
+            &self.api_similar,
+            &self.api_random,
+            &self.api_daily,
+            &self.api_tunes,
+            &self.api_search_abc,
+            &self.api_normalize,
+            &self.api_features,
+            &self.api_clusters,
+            &self.api_autocomplete,
+            &self.html_tunes,
+        ]
+        .iter()
+        .any(|route| route.is_match(url))
+    }
+}
+
+// The search engine for reading. If a request panicked while holding the lock, carry on with the
+// engine as it is, rather than failing every request after it.
+fn read_searcher(
+    searcher: &RwLock<search::SearchEngine>,
+) -> RwLockReadGuard<'_, search::SearchEngine> {
+    searcher.read().unwrap_or_else(|e| e.into_inner())
+}
+
+// The search engine for writing, as `read_searcher`.
+fn write_searcher(
+    searcher: &RwLock<search::SearchEngine>,
+) -> RwLockWriteGuard<'_, search::SearchEngine> {
+    searcher.write().unwrap_or_else(|e| e.into_inner())
+}
 
-    // This can optionally run a HTML UI.
-    let templates = build_template_registry();
+// Everything needed to handle a request, shared between worker threads.
+struct Context {
+    routes: Routes,
 
-    let mut server = Server::http(bind).unwrap();
+    // Searches share a read lock. It's only locked for writing to reload clusters, change tunes,
+    // or swap in the real engine after a warm start.
+    searcher: RwLock<search::SearchEngine>,
 
-    // Create a local mutable copy.
-    let mut abc_cache = searcher.abc_cache.clone();
+    // Indexing progress. Until it's done, routes that need the index are unavailable.
+    progress: search::IndexProgress,
 
-    for request in server.incoming_requests() {
-        let response: Response<_> =
-        // API
-            if let Some(groups) = re_api_abc.captures(request.url()) {
-            api_abc(&groups, &mut abc_cache)
-        } else if let Some(groups) = re_api_svg.captures(request.url()) {
-            api_svg(&groups, &mut abc_cache)
-        } else if let Some(_groups) = re_api_tunes.captures(request.url()) {
-            api_search(&request, &mut searcher)
-        } else if let Some(_groups) = re_api_features.captures(request.url()) {
-            features(&request, &mut searcher)
-        }
+    search_limits: SearchLimits,
+
+    templates: Handlebars,
+    typesetting: typeset::Typesetting,
+
+    // How key notes are spelled in ABC sent to the server.
+    dialect: abc_lexer::Dialect,
+
+    admin_token: Option<String>,
+    clusters_path: Option<PathBuf>,
+    previews_path: Option<PathBuf>,
+
+    // Origins allowed to call the API from a browser, or `*` for any.
+    cors_origins: Vec<String>,
+
+    // Set when the workers should stop.
+    shutdown: AtomicBool,
+}
+
+// Route a request to its handler.
+// Each worker thread has its own copy of the ABC cache, so fetching tunes doesn't contend.
+fn handle(
+    context: &Context,
+    request: &mut Request,
+    abc_cache: &mut dyn storage::TuneStore,
+) -> Response<Cursor<Vec<u8>>> {
+    // Only the API is available to browsers on other origins.
+    if !request.url().starts_with("/api/") {
+        return route(context, request, abc_cache);
+    }
+
+    if *request.method() == Method::Options {
+        return preflight(request, &context.cors_origins);
+    }
+
+    let response = route(context, request, abc_cache);
+    with_cors(response, request, &context.cors_origins)
+}
+
+fn route(
+    context: &Context,
+    request: &mut Request,
+    abc_cache: &mut dyn storage::TuneStore,
+) -> Response<Cursor<Vec<u8>>> {
+    let routes = &context.routes;
+    let url = request.url().to_string();
 
-                // HTML routes.
+    if !context.progress.is_done() && routes.needs_index(&url) {
+        let is_api = url.starts_with("/api/") || url.starts_with("/admin/");
+        return if context.progress.has_failed() {
+            if is_api {
+                indexing_failed()
+            } else {
+                Response::from_string("Indexing failed.").with_status_code(StatusCode(500))
+            }
+        } else if is_api {
+            still_indexing(&context.progress)
+        } else {
+            Response::from_string("Still indexing, try again soon.")
+                .with_status_code(StatusCode(503))
+        };
+    }
 
-        else if let Some(_) = re_html_tunes.captures(request.url()) {
-            html_search(&request, &mut searcher, &templates)
+    // Admin
+    if routes.admin_reload.is_match(&url) {
+        if is_admin(request, &context.admin_token) {
+            admin_reload(request, &context.searcher, &context.clusters_path)
+        } else {
+            unauthorized()
+        }
+    } else if routes.admin_shutdown.is_match(&url) {
+        if is_admin(request, &context.admin_token) {
+            admin_shutdown(request, &context.shutdown)
+        } else {
+            unauthorized()
+        }
+    } else if let Some(groups) = routes.admin_tune.captures(&url) {
+        if is_admin(request, &context.admin_token) {
+            admin_tune(request, &groups, &context.searcher, context.dialect)
+        } else {
+            unauthorized()
         }
+    }
+    // API
+    else if let Some(groups) = routes.api_abc.captures(&url) {
+        api_abc(request, &groups, abc_cache)
+    } else if let Some(groups) = routes.api_svg.captures(&url) {
+        api_svg(request, &groups, abc_cache, &context.typesetting)
+    } else if let Some(groups) = routes.api_text.captures(&url) {
+        api_text(request, &groups, abc_cache)
+    } else if let Some(groups) = routes.api_png.captures(&url) {
+        api_png(request, &groups, abc_cache, &context.typesetting)
+    } else if let Some(groups) = routes.api_midi.captures(&url) {
+        api_midi(request, &groups, abc_cache)
+    } else if let Some(groups) = routes.api_incipit.captures(&url) {
+        api_incipit(request, &groups, abc_cache, &context.typesetting)
+    } else if let Some(groups) = routes.api_layout.captures(&url) {
+        api_layout(request, &groups, abc_cache, &context.typesetting)
+    } else if let Some(groups) = routes.api_intervals.captures(&url) {
+        api_intervals(request, &groups, abc_cache)
+    } else if let Some(groups) = routes.api_preview.captures(&url) {
+        api_preview(
+            request,
+            &groups,
+            abc_cache,
+            &context.typesetting,
+            &context.previews_path,
+        )
+    } else if let Some(groups) = routes.api_duplicates.captures(&url) {
+        api_duplicates(
+            request,
+            &groups,
+            abc_cache,
+            &read_searcher(&context.searcher),
+        )
+    } else if let Some(groups) = routes.api_similar.captures(&url) {
+        api_similar(
+            request,
+            &groups,
+            abc_cache,
+            &read_searcher(&context.searcher),
+            &context.search_limits,
+        )
+    } else if routes.api_random.is_match(&url) {
+        api_random(request, &read_searcher(&context.searcher))
+    } else if routes.api_daily.is_match(&url) {
+        api_daily(request, &read_searcher(&context.searcher))
+    } else if routes.api_tunes.is_match(&url) && *request.method() == Method::Post {
+        if is_admin(request, &context.admin_token) {
+            api_submit(request, &context.searcher, context.dialect)
+        } else {
+            unauthorized()
+        }
+    } else if routes.api_tunes.is_match(&url) {
+        api_search(
+            request,
+            &read_searcher(&context.searcher),
+            &context.search_limits,
+        )
+    } else if routes.api_search_abc.is_match(&url) {
+        api_search_abc(
+            request,
+            &read_searcher(&context.searcher),
+            &context.search_limits,
+        )
+    } else if routes.api_normalize.is_match(&url) {
+        api_normalize(request, &read_searcher(&context.searcher))
+    } else if routes.api_features.is_match(&url) {
+        features(request, &read_searcher(&context.searcher))
+    } else if routes.api_clusters.is_match(&url) {
+        clusters(request, &read_searcher(&context.searcher))
+    } else if routes.api_stats.is_match(&url) {
+        stats(request, &read_searcher(&context.searcher))
+    } else if routes.api_autocomplete.is_match(&url) {
+        autocomplete(request, &read_searcher(&context.searcher))
+    } else if routes.api_validate.is_match(&url) {
+        api_validate(request, context.dialect)
+    } else if routes.api_status.is_match(&url) {
+        status(request, &context.progress)
+    } else if url.starts_with("/api/") {
+        api_error(ErrorCode::NotFound, "Didn't recognise that.")
+    }
+    // HTML routes.
+    else if routes.html_tunes.is_match(&url) {
+        html_search(
+            request,
+            &read_searcher(&context.searcher),
+            &context.search_limits,
+            &context.templates,
+        )
+    } else if let Some(groups) = routes.html_tune.captures(&url) {
+        html_tune(
+            request,
+            &groups,
+            &read_searcher(&context.searcher),
+            abc_cache,
+            &context.typesetting,
+            &context.templates,
+        )
+    } else if routes.html_home.is_match(&url) {
+        let stats = read_searcher(&context.searcher).get_stats();
+        html_from_template(request, "home", &stats, &context.templates)
+    } else if let Some(path) = routes.html_wildcard.captures(&url) {
+        html_free(request, &path, &context.templates)
+    } else {
+        Response::from_string("Didn't recognise that.").with_status_code(StatusCode(404))
+    }
+}
+
+pub fn main(searcher: search::SearchEngine, config: &config::Config) -> Result<(), String> {
+    let progress = search::IndexProgress::new(searcher.get_stats().num_tunes);
+    progress.finish();
+
+    serve(Arc::new(build_context(searcher, progress, config)), config)
+}
 
-        else if let Some(_) = re_html_home.captures(request.url()) {
-            html_from_template(&request, "home", &templates)
+// Serve tunes from `placeholder` straight away, while `build` makes the real search engine in the
+// background. Searches are unavailable until it's done, and `/api/v3/status` reports progress.
+pub fn main_warm_start<F>(
+    placeholder: search::SearchEngine,
+    build: F,
+    config: &config::Config,
+) -> Result<(), String>
+where
+    F: FnOnce(&search::IndexProgress) -> search::SearchEngine + Send + 'static,
+{
+    let progress = search::IndexProgress::new(placeholder.get_stats().num_tunes);
+    let context = Arc::new(build_context(placeholder, progress, config));
+
+    let builder_context = context.clone();
+    thread::spawn(move || {
+        info!("Indexing in the background.");
+
+        // Keep serving tunes if it fails, and report that searches won't be available.
+        let built =
+            panic::catch_unwind(panic::AssertUnwindSafe(|| build(&builder_context.progress)));
+        match built {
+            Ok(searcher) => {
+                *write_searcher(&builder_context.searcher) = searcher;
+                builder_context.progress.finish();
+                info!("Indexing done, searches are available.");
+            }
+            Err(err) => {
+                builder_context.progress.fail();
+                match err.downcast_ref::<&str>() {
+                    Some(message) => error!("Indexing failed: {}", message),
+                    None => match err.downcast_ref::<String>() {
+                        Some(message) => error!("Indexing failed: {}", message),
+                        None => error!("Indexing failed."),
+                    },
+                }
+            }
         }
+    });
+
+    serve(context, config)
+}
+
+fn build_context(
+    searcher: search::SearchEngine,
+    progress: search::IndexProgress,
+    config: &config::Config,
+) -> Context {
+    let mut typesetting = typeset::Typesetting::new();
+    typesetting.auto_beam = config.auto_beam;
+
+    if config.admin_token.is_none() {
+        info!("No ADMIN_TOKEN set, admin endpoints are disabled.");
+    }
+
+    Context {
+        routes: Routes::new(),
+        searcher: RwLock::new(searcher),
+        progress,
+        search_limits: SearchLimits::from_config(config),
+        // This can optionally run a HTML UI.
+        templates: build_template_registry(config.html_templates.as_deref()),
+        typesetting,
+        dialect: config.dialect(),
+        admin_token: config.admin_token.clone(),
+        clusters_path: config.clusters_path(),
+        previews_path: config.previews_path(),
+        cors_origins: config.cors_origins.clone(),
+        shutdown: AtomicBool::new(false),
+    }
+}
+
+// Handle requests with worker threads until shut down.
+fn serve(context: Arc<Context>, config: &config::Config) -> Result<(), String> {
+    info!("Binding to {}", config.http_bind);
+
+    let server = Arc::new(
+        Server::http(&config.http_bind)
+            .map_err(|err| format!("Can't listen on {}: {}", config.http_bind, err))?,
+    );
 
-        else if let Some(path) = re_html_wildcard.captures(request.url()) {
-            html_free(&request, &path, &templates)
+    let threads = config.http_threads;
+    info!("Starting {} worker threads.", threads);
+
+    let mut workers = vec![];
+    for _ in 0..threads {
+        let server = server.clone();
+        let context = context.clone();
+        let mut abc_cache = read_searcher(&context.searcher).abc_cache.reader()?;
+
+        workers.push(thread::spawn(move || {
+            // Wake up now and again to check whether to stop.
+            while !context.shutdown.load(Ordering::SeqCst) {
+                let mut request = match server.recv_timeout(Duration::from_millis(RECV_TIMEOUT_MS))
+                {
+                    Ok(Some(request)) => request,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!("Can't receive request: {:?}", err);
+                        continue;
+                    }
+                };
+
+                info!("{} {}", request.method(), request.url());
+
+                // Pick up any tunes added since this worker's copy of the cache was made.
+                {
+                    let searcher = read_searcher(&context.searcher);
+                    if searcher.abc_cache.version() != abc_cache.version() {
+                        match searcher.abc_cache.reader() {
+                            Ok(reader) => abc_cache = reader,
+                            Err(message) => error!("Can't pick up new tunes: {}", message),
+                        }
+                    }
+                }
+
+                let response = handle(&context, &mut request, &mut *abc_cache);
+
+                if let Err(err) = request.respond(response) {
+                    warn!("Can't write response: {:?}", err);
+                }
+            }
+        }));
+    }
+
+    for worker in workers {
+        if worker.join().is_err() {
+            error!("Worker thread panicked.");
         }
+    }
 
-        else {
-            Response::from_string("Didn't recognise that.").with_status_code(StatusCode(404))
-        };
+    info!("Shut down.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> String {
+        build_template_registry(None)
+            .render_template(template, &serde_json::json!({}))
+            .unwrap()
+    }
+
+    #[test]
+    fn url_with_param_test() {
+        // Set, replace and remove a parameter, keeping the others.
+        assert_eq!(
+            render("{{url_with_param \"/search?title=kesh\" \"offset\" 30}}"),
+            "/search?title=kesh&offset=30"
+        );
+        assert_eq!(
+            render("{{url_with_param \"/search?offset=10&title=kesh\" \"offset\" 30}}"),
+            "/search?title=kesh&offset=30"
+        );
+        assert_eq!(
+            render("{{url_with_param \"/search?rhythm=jig&title=kesh\" \"rhythm\" \"\"}}"),
+            "/search?title=kesh"
+        );
+
+        // Removing the last one leaves no query.
+        assert_eq!(
+            render("{{url_with_param \"/search?rhythm=jig\" \"rhythm\" \"\"}}"),
+            "/search"
+        );
+
+        // Values are encoded.
+        assert_eq!(
+            render("{{url_with_param \"/search\" \"title\" \"the kesh & co\"}}"),
+            "/search?title=the+kesh+%26+co"
+        );
+    }
+
+    #[test]
+    fn format_score_test() {
+        assert_eq!(render("{{format_score 0.456}}"), "0.46");
+        assert_eq!(render("{{format_score 0.444}}"), "0.44");
+        assert_eq!(render("{{format_score 0.456 1}}"), "0.5");
+        assert_eq!(render("{{format_score 1 0}}"), "1");
+        assert!(build_template_registry(None)
+            .render_template("{{format_score \"high\"}}", &serde_json::json!({}))
+            .is_err());
+    }
+
+    // Status line of a response, e.g. "HTTP/1.1 404 Not Found".
+    fn status_line(response: Response<Cursor<Vec<u8>>>) -> String {
+        let mut bytes = vec![];
+        response
+            .raw_print(&mut bytes, tiny_http::HTTPVersion(1, 1), &[], true, None)
+            .unwrap();
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[test]
+    fn read_body_test() {
+        assert_eq!(
+            read_body(&b"X:1\nK:G\nGAB|\n"[..]).ok(),
+            Some("X:1\nK:G\nGAB|\n".to_string())
+        );
+
+        let too_large = read_body(std::io::repeat(b'A')).unwrap_err();
+        assert!(status_line(too_large).starts_with("HTTP/1.1 413"));
+
+        let not_utf8 = read_body(&[0xff, 0xfe][..]).unwrap_err();
+        assert!(status_line(not_utf8).starts_with("HTTP/1.1 400"));
+    }
 
-        request.respond(response).expect("Can't write response!");
+    #[test]
+    fn pluralize_test() {
+        assert_eq!(render("{{pluralize 1 \"tune\"}}"), "1 tune");
+        assert_eq!(render("{{pluralize 0 \"tune\"}}"), "0 tunes");
+        assert_eq!(render("{{pluralize 3 \"tune\"}}"), "3 tunes");
+        assert_eq!(render("{{pluralize 1 \"match\" \"matches\"}}"), "1 match");
+        assert_eq!(render("{{pluralize 2 \"match\" \"matches\"}}"), "2 matches");
     }
 }
@@ -0,0 +1,144 @@
+//! Spell-check header text across the whole corpus.
+//! Words that are rare in the corpus but a small edit away from frequent words are likely typos,
+//! e.g. "hornpip" for "hornpipe". These are reported with the tunes they occur in for review.
+
+use relations;
+use text;
+
+// Options for what counts as rare and frequent.
+#[derive(Debug, Clone, Copy)]
+pub struct SpellCheckParams {
+    // A word found in at most this many tunes is rare.
+    pub max_rare: usize,
+
+    // A word found in at least this many tunes is frequent.
+    pub min_frequent: usize,
+
+    // Words shorter than this aren't checked, as too many short words are a small edit apart.
+    pub min_length: usize,
+}
+
+impl SpellCheckParams {
+    pub fn default() -> SpellCheckParams {
+        SpellCheckParams {
+            max_rare: 2,
+            min_frequent: 20,
+            min_length: 4,
+        }
+    }
+}
+
+// A rare word that is probably a misspelling of a frequent one.
+#[derive(Debug, PartialEq)]
+pub struct Suggestion {
+    pub word: String,
+    pub suggestion: String,
+    pub word_count: usize,
+    pub suggestion_count: usize,
+    pub tune_ids: Vec<usize>,
+}
+
+// Allow more edits in longer words.
+fn max_distance(word: &str) -> usize {
+    if word.chars().count() < 7 {
+        1
+    } else {
+        2
+    }
+}
+
+// Only check plain words. Tokens with diacritics are indexed alongside their ASCII versions, so
+// they would look like typos of them.
+fn is_checkable(word: &str, params: &SpellCheckParams) -> bool {
+    word.len() >= params.min_length && word.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+// Find likely typos in a text index, ordered by word.
+pub fn find_suggestions(
+    vsm: &relations::TextVSM,
+    params: &SpellCheckParams,
+) -> Vec<Suggestion> {
+    let frequencies = vsm.vsm.doc_frequencies();
+
+    let mut frequent: Vec<(&String, usize)> = frequencies
+        .iter()
+        .filter(|(word, count)| **count >= params.min_frequent && is_checkable(word, params))
+        .map(|(word, count)| (*word, *count))
+        .collect();
+
+    // Prefer the most frequent suggestion, then alphabetical for stable output.
+    frequent.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+    let mut rare: Vec<(&String, usize)> = frequencies
+        .iter()
+        .filter(|(word, count)| **count <= params.max_rare && is_checkable(word, params))
+        .map(|(word, count)| (*word, *count))
+        .collect();
+    rare.sort();
+
+    let mut result = vec![];
+    for (word, word_count) in rare {
+        let max = max_distance(word);
+        let length = word.len();
+
+        let found = frequent.iter().find(|(candidate, _)| {
+            // Cheap check before the expensive one.
+            let length_difference = if candidate.len() > length {
+                candidate.len() - length
+            } else {
+                length - candidate.len()
+            };
+
+            *candidate != word
+                && length_difference <= max
+                && text::edit_distance(word, candidate) <= max
+        });
+
+        if let Some((suggestion, suggestion_count)) = found {
+            result.push(Suggestion {
+                word: word.to_string(),
+                suggestion: suggestion.to_string(),
+                word_count,
+                suggestion_count: *suggestion_count,
+                tune_ids: vsm.vsm.docs_with_term(word),
+            });
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_suggestions_test() {
+        let mut vsm = relations::TextVSM::new(1024, 30, text::Vocabulary::new());
+        for tune_id in 0..25 {
+            vsm.add(tune_id, "The Hornpipe".to_string());
+        }
+        vsm.add(25, "Sailor's Hornpip".to_string());
+        vsm.add(26, "Teh Hornpipe".to_string());
+
+        let params = SpellCheckParams {
+            max_rare: 2,
+            min_frequent: 20,
+            min_length: 3,
+        };
+
+        let suggestions = find_suggestions(&vsm, &params);
+
+        assert_eq!(
+            suggestions,
+            vec![Suggestion {
+                word: "hornpip".to_string(),
+                suggestion: "hornpipe".to_string(),
+                word_count: 1,
+                suggestion_count: 26,
+                tune_ids: vec![25],
+            }],
+            "Only rare words close to frequent ones are reported. 'teh' is two edits from 'the'."
+        );
+    }
+}
@@ -0,0 +1,562 @@
+//! SQLite store
+//! Tunes kept in an SQLite database, as an alternative to the tunecache, with `STORAGE=sqlite`.
+//! As well as the ABC of each tune, its header fields are stored in a table of their own, so the
+//! corpus can be queried with SQL, e.g. to count tunes by rhythm:
+//!
+//! ```sql
+//! SELECT value, COUNT(*) FROM headers WHERE field = 'R' GROUP BY value ORDER BY 2 DESC;
+//! ```
+//!
+//! A tune and its headers are written in one transaction, so they always agree, and a whole scan
+//! is stored in one transaction with `put_all`.
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use abc_writer;
+use representations;
+use storage;
+use storage::TuneStore;
+
+// Name of the database file in the base directory.
+pub const DATABASE_FILENAME: &str = "tunes.sqlite";
+
+// Tunes read at a time when iterating.
+const ITER_BATCH_SIZE: u32 = 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tunes (
+    id INTEGER PRIMARY KEY,
+    abc TEXT NOT NULL,
+
+    -- storage::content_hash of the ABC, as a signed integer.
+    hash INTEGER NOT NULL,
+
+    -- When the tune was written, as seconds since the epoch.
+    modified INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS headers (
+    tune_id INTEGER NOT NULL REFERENCES tunes (id),
+
+    -- Order of the field in the header, from 0.
+    position INTEGER NOT NULL,
+
+    -- Letter of the field, e.g. 'T' for a title.
+    field TEXT NOT NULL,
+
+    value TEXT NOT NULL,
+
+    PRIMARY KEY (tune_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS headers_by_field ON headers (field, value);
+";
+
+fn sql_error(err: rusqlite::Error) -> String {
+    format!("SQLite error: {:?}", err)
+}
+
+// Header fields of a tune as their letter and value, e.g. ("T", "The Butterfly"), in the order
+// they're written. Values are written back out as ABC, so keys and metres are in a standard form.
+pub fn headers(content: &str) -> Vec<(String, String)> {
+    let ast = representations::abc_to_ast(content);
+    ast.prelude
+        .iter()
+        .filter_map(|token| {
+            let field = abc_writer::tokens(std::slice::from_ref(token));
+            let mut parts = field.trim_end().splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value))
+                    if name.len() == 1 && name.chars().all(|c| c.is_ascii_uppercase()) =>
+                {
+                    Some((name.to_string(), value.trim().to_string()))
+                }
+                _ => None,
+            }
+        }).collect()
+}
+
+// Write a tune and its headers, replacing any tune with the same ID.
+// Call this in a transaction.
+fn write_tune(connection: &Connection, tune_id: u32, content: &str) -> Result<(), String> {
+    let modified = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO tunes (id, abc, hash, modified) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                tune_id,
+                content,
+                storage::content_hash(content.as_bytes()) as i64,
+                modified
+            ],
+        ).map_err(sql_error)?;
+
+    connection
+        .execute("DELETE FROM headers WHERE tune_id = ?1", params![tune_id])
+        .map_err(sql_error)?;
+
+    let mut statement = connection
+        .prepare_cached(
+            "INSERT INTO headers (tune_id, position, field, value) VALUES (?1, ?2, ?3, ?4)",
+        ).map_err(sql_error)?;
+    for (position, (field, value)) in headers(content).iter().enumerate() {
+        statement
+            .execute(params![tune_id, position as i64, field, value])
+            .map_err(sql_error)?;
+    }
+
+    Ok(())
+}
+
+// Store of tunes in an SQLite database, created if it doesn't exist.
+pub struct SqliteStore {
+    path: PathBuf,
+
+    // Connections can't be shared between threads, so this is behind a lock. As with the
+    // tunecache, threads that make a lot of lookups should have their own `reader`.
+    connection: Mutex<Connection>,
+
+    // Tunes put since the store was opened.
+    num_put: usize,
+
+    // Ignore tunes over this id, for debugging / profiling.
+    max_id: Option<u32>,
+}
+
+impl SqliteStore {
+    pub fn new(path: &Path, max_id: Option<u32>) -> Result<SqliteStore, String> {
+        let connection = Connection::open(path).map_err(|err| {
+            format!(
+                "Failed to open SQLite database {}: {:?}",
+                path.display(),
+                err
+            )
+        })?;
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            connection: Mutex::new(connection),
+            num_put: 0,
+            max_id,
+        })
+    }
+
+    // Largest tune ID to read, as a query parameter.
+    fn limit(&self) -> i64 {
+        self.max_id.map_or(i64::MAX, i64::from)
+    }
+
+    // Store a lot of tunes at once, e.g. after a scan, returning how many there were.
+    // They're all stored in one transaction, so if one fails none are.
+    pub fn put_all<I>(&mut self, tunes: I) -> Result<usize, String>
+    where
+        I: Iterator<Item = (u32, String)>,
+    {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction().map_err(sql_error)?;
+
+        let mut count = 0;
+        for (tune_id, content) in tunes {
+            write_tune(&transaction, tune_id, &content)?;
+            count += 1;
+        }
+
+        transaction.commit().map_err(sql_error)?;
+        self.num_put += count;
+        Ok(count)
+    }
+
+    // Make the database hold just these tunes, e.g. after a scan, returning how many were written
+    // and how many deleted. Tunes that haven't changed are left alone, so they keep their modified
+    // time. It's all one transaction, so if it fails the database is as it was.
+    pub fn sync<I>(&mut self, tunes: I) -> Result<(usize, usize), String>
+    where
+        I: Iterator<Item = (u32, String)>,
+    {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction().map_err(sql_error)?;
+
+        let mut hashes = transaction
+            .prepare("SELECT id, hash FROM tunes")
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![], |row| {
+                        Ok((row.get::<_, u32>(0)?, row.get::<_, i64>(1)? as u64))
+                    })?.collect::<Result<HashMap<u32, u64>, _>>()
+            }).map_err(sql_error)?;
+
+        let mut written = 0;
+        for (tune_id, content) in tunes {
+            if hashes.remove(&tune_id) != Some(storage::content_hash(content.as_bytes())) {
+                write_tune(&transaction, tune_id, &content)?;
+                written += 1;
+            }
+        }
+
+        // Whatever's left isn't in the tunecache any more.
+        for tune_id in hashes.keys() {
+            transaction
+                .execute("DELETE FROM headers WHERE tune_id = ?1", params![tune_id])
+                .map_err(sql_error)?;
+            transaction
+                .execute("DELETE FROM tunes WHERE id = ?1", params![tune_id])
+                .map_err(sql_error)?;
+        }
+
+        transaction.commit().map_err(sql_error)?;
+        self.num_put += written + hashes.len();
+        Ok((written, hashes.len()))
+    }
+}
+
+impl TuneStore for SqliteStore {
+    fn get(&self, tune_id: u32) -> Option<String> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        match connection
+            .query_row(
+                "SELECT abc FROM tunes WHERE id = ?1 AND id <= ?2",
+                params![tune_id, self.limit()],
+                |row| row.get(0),
+            ).optional()
+        {
+            Ok(content) => content,
+            Err(err) => {
+                error!("Can't read tune {}: {:?}", tune_id, err);
+                None
+            }
+        }
+    }
+
+    // Iterate over the tunes in ID order. This has its own connection, so doesn't hold the lock.
+    fn iter(&self) -> storage::TuneIterator {
+        match Connection::open(&self.path) {
+            Ok(connection) => storage::TuneIterator::new(
+                Box::new(SqliteIterator {
+                    connection,
+                    limit: self.limit(),
+                    batch: VecDeque::new(),
+                    last_id: None,
+                    finished: false,
+                }),
+                None,
+            ),
+            Err(err) => {
+                error!("Can't open SQLite database to read tunes: {:?}", err);
+                storage::TuneIterator::new(Box::new(vec![].into_iter()), None)
+            }
+        }
+    }
+
+    fn max_id(&self) -> u32 {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .query_row(
+                "SELECT COALESCE(MAX(id), 0) FROM tunes WHERE id <= ?1",
+                params![self.limit()],
+                |row| row.get(0),
+            ).unwrap_or(0)
+    }
+
+    fn put(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        self.put_all(vec![(tune_id, content.to_string())].into_iter())
+            .map(|_| ())
+    }
+
+    fn tune_ids(&self) -> Vec<u32> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let result = connection
+            .prepare("SELECT id FROM tunes WHERE id <= ?1 ORDER BY id")
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![self.limit()], |row| row.get(0))?
+                    .collect::<Result<Vec<u32>, _>>()
+            });
+
+        result.unwrap_or_else(|err| {
+            error!("Can't read tune IDs: {:?}", err);
+            vec![]
+        })
+    }
+
+    fn reader(&self) -> Result<Box<dyn TuneStore>, String> {
+        let connection = Connection::open(&self.path).map_err(|err| {
+            format!(
+                "Failed to open SQLite database {}: {:?}",
+                self.path.display(),
+                err
+            )
+        })?;
+        Ok(Box::new(SqliteStore {
+            path: self.path.clone(),
+            connection: Mutex::new(connection),
+            num_put: self.num_put,
+            max_id: self.max_id,
+        }))
+    }
+
+    fn version(&self) -> usize {
+        self.num_put
+    }
+
+    fn get_hash(&self, tune_id: u32) -> Option<u64> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .query_row(
+                "SELECT hash FROM tunes WHERE id = ?1 AND id <= ?2",
+                params![tune_id, self.limit()],
+                |row| row.get::<_, i64>(0),
+            ).optional()
+            .unwrap_or(None)
+            .map(|hash| hash as u64)
+    }
+
+    fn generation(&self) -> u64 {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let result = connection
+            .prepare("SELECT id, hash FROM tunes WHERE id <= ?1")
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![self.limit()], |row| {
+                        Ok((row.get::<_, u32>(0)?, row.get::<_, i64>(1)? as u64))
+                    })?.collect::<Result<HashMap<u32, u64>, _>>()
+            });
+
+        match result {
+            Ok(hashes) => storage::generation_hash(&hashes),
+            Err(err) => {
+                error!("Can't read tune hashes: {:?}", err);
+                storage::generation_hash(&HashMap::new())
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<u64> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .query_row(
+                "SELECT MAX(modified) FROM tunes WHERE id <= ?1",
+                params![self.limit()],
+                |row| row.get::<_, Option<i64>>(0),
+            ).unwrap_or(None)
+            .map(|modified| modified as u64)
+    }
+
+    fn get_modified(&self, tune_id: u32) -> Option<u64> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .query_row(
+                "SELECT modified FROM tunes WHERE id = ?1 AND id <= ?2",
+                params![tune_id, self.limit()],
+                |row| row.get::<_, i64>(0),
+            ).optional()
+            .unwrap_or(None)
+            .map(|modified| modified as u64)
+    }
+
+    fn num_tunes(&self) -> usize {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM tunes WHERE id <= ?1",
+                params![self.limit()],
+                |row| row.get::<_, i64>(0),
+            ).unwrap_or(0) as usize
+    }
+}
+
+// Iterator over the tunes in the database, reading a batch at a time in ID order.
+struct SqliteIterator {
+    connection: Connection,
+
+    // Largest tune ID to read.
+    limit: i64,
+
+    batch: VecDeque<(u32, String)>,
+    last_id: Option<u32>,
+    finished: bool,
+}
+
+impl SqliteIterator {
+    fn read_batch(&mut self) -> Result<(), rusqlite::Error> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, abc FROM tunes WHERE id > ?1 AND id <= ?2 ORDER BY id LIMIT ?3",
+        )?;
+
+        // Tune 0 is allowed, so start below it.
+        let after = self.last_id.map_or(-1, i64::from);
+        let rows = statement.query_map(params![after, self.limit, ITER_BATCH_SIZE], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        for row in rows {
+            self.batch.push_back(row?);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SqliteIterator {
+    type Item = (u32, String);
+
+    fn next(&mut self) -> Option<(u32, String)> {
+        if self.batch.is_empty() && !self.finished {
+            if let Err(err) = self.read_batch() {
+                error!("Can't read tunes: {:?}", err);
+            }
+            self.finished = self.batch.len() < ITER_BATCH_SIZE as usize;
+        }
+
+        let entry = self.batch.pop_front()?;
+        self.last_id = Some(entry.0);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn headers_test() {
+        assert_eq!(
+            headers("X:1\nT:The Butterfly\nR: slip jig\nM:9/8\nK:Emin\nB2EG2EF3|\n"),
+            vec![
+                ("X".to_string(), "1".to_string()),
+                ("T".to_string(), "The Butterfly".to_string()),
+                ("R".to_string(), "slip jig".to_string()),
+                ("M".to_string(), "9/8".to_string()),
+                ("K".to_string(), "Emin".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sqlite_store_test() {
+        let path = env::temp_dir().join(format!("sqlite-store-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = SqliteStore::new(&path, None).unwrap();
+        assert_eq!(store.max_id(), 0);
+        assert_eq!(store.modified(), None);
+
+        let count = store
+            .put_all(
+                (1..2500).map(|tune_id| (tune_id, format!("X:1\nT:Tune {}\nK:G\nGAB|\n", tune_id))),
+            )
+            .unwrap();
+        assert_eq!(count, 2499);
+        assert_eq!(store.num_tunes(), 2499);
+        assert_eq!(store.max_id(), 2499);
+        assert!(store.modified().is_some());
+        // Each batch is stamped as it's written, so an early tune can be older than the store.
+        assert!(store.get_modified(7).is_some());
+        assert!(store.get_modified(7) <= store.modified());
+        assert_eq!(store.get_modified(9999), None);
+
+        // Iteration reads every tune once, across batches.
+        let tune_ids: Vec<u32> = store.iter().map(|x| x.tune_id).collect();
+        assert_eq!(tune_ids, store.tune_ids());
+        assert_eq!(tune_ids.len(), 2499);
+
+        // Replacing a tune replaces its headers.
+        let copy = store.reader().unwrap();
+        store.put(7, "X:1\nT:Seven\nC:Trad\nK:D\nDEF|\n").unwrap();
+        assert_eq!(
+            store.get(7),
+            Some("X:1\nT:Seven\nC:Trad\nK:D\nDEF|\n".to_string())
+        );
+        assert_ne!(copy.version(), store.version());
+        assert_eq!(copy.get(7), store.get(7), "Copies see the same database.");
+
+        let hash = storage::content_hash(store.get(7).unwrap().as_bytes());
+        assert_eq!(store.get_hash(7), Some(hash));
+        assert_eq!(store.get_hash(9999), None);
+
+        let mut hashes = HashMap::new();
+        for entry in store.iter() {
+            hashes.insert(
+                entry.tune_id,
+                storage::content_hash(entry.content.as_bytes()),
+            );
+        }
+        assert_eq!(store.generation(), storage::generation_hash(&hashes));
+
+        let connection = Connection::open(&path).unwrap();
+        let titles: Vec<String> = connection
+            .prepare("SELECT value FROM headers WHERE tune_id = 7 AND field = 'T'")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+        assert_eq!(titles, vec!["Seven".to_string()]);
+
+        // Syncing writes only the tunes that changed, and deletes the ones that are gone.
+        let modified: i64 = connection
+            .query_row("SELECT MIN(modified) FROM tunes", params![], |row| {
+                row.get(0)
+            }).unwrap();
+        connection
+            .execute("UPDATE tunes SET modified = 0", params![])
+            .unwrap();
+        assert!(modified > 0);
+
+        let tunes: Vec<(u32, String)> = store
+            .iter()
+            .filter(|entry| entry.tune_id != 2000)
+            .map(|entry| match entry.tune_id {
+                9 => (9, "X:1\nT:Nine\nK:A\nABc|\n".to_string()),
+                _ => (entry.tune_id, entry.content),
+            }).collect();
+        let version = store.version();
+        assert_eq!(store.sync(tunes.into_iter()).unwrap(), (1, 1));
+        assert_ne!(store.version(), version);
+        assert_eq!(store.num_tunes(), 2498);
+        assert_eq!(store.get(2000), None);
+        assert_eq!(store.get(9), Some("X:1\nT:Nine\nK:A\nABc|\n".to_string()));
+        let changed: Vec<u32> = connection
+            .prepare("SELECT id FROM tunes WHERE modified > 0")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<u32>, _>>()
+            .unwrap();
+        assert_eq!(changed, vec![9]);
+        let headers: i64 = connection
+            .query_row(
+                "SELECT COUNT(*) FROM headers WHERE tune_id = 2000",
+                params![],
+                |row| row.get(0),
+            ).unwrap();
+        assert_eq!(headers, 0);
+
+        // Tunes over the max id are left out.
+        let limited = SqliteStore::new(&path, Some(1500)).unwrap();
+        assert_eq!(limited.num_tunes(), 1500);
+        assert_eq!(limited.max_id(), 1500);
+        assert_eq!(limited.iter().count(), 1500);
+        assert_eq!(limited.get(1501), None);
+        assert!(limited.get(7).is_some());
+
+        // A reader that can't open the database is an error.
+        let missing = env::temp_dir()
+            .join(format!("sqlite-store-test-{}-missing", std::process::id()))
+            .join("tunes.sqlite");
+        let mut unopenable = SqliteStore::new(&path, None).unwrap();
+        unopenable.path = missing;
+        assert!(unopenable.reader().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
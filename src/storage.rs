@@ -2,45 +2,96 @@
 //! Scan a directory tree of ABC files and store them in a single file.
 //! We expect to find a directory of ABC files with numerical filenames (e.g. "123.abc"). They can
 //! be found in any directory structure. Internal u32 tune IDs are derived from these numerical IDs.
-//! Duplicate IDs are ignored.
+//! A file can hold several tunes, each starting with an X: field, which get IDs of their own, see
+//! `TuneIds`. Duplicate IDs are ignored.
 //! Because it takes a long time to scan hundreds of thousands of files, they must be scanned into
 //! a 'tunecache' file, which is the concatenation of all the ABC files.
 //! CacheScanner iterates over this, returning entries.
 //! ReadOnlyCache maintains a set of file offsets for retrieval of strings.
+//! MappedCache is the same, but maps the file into memory so tunes are read without a seek.
 //! ReadWriteCache stores the strings in memory for quick (large) access.
+//! ReadWriteCache writes tunes in order of tune ID, so the same tunes always make the same file,
+//! and backups and rsync only see what changed. Tunes added or replaced by the server are appended
+//! after them, until the next scan writes the file again.
+//! The tunecache is only ever appended to, or replaced by a new file, so a MappedCache of the old
+//! one stays valid while a scan is running. A MappedCache that appends to the file after a scan
+//! has replaced it loads the new one first.
+//! Searching and serving tunes only needs a TuneStore, so the tunecache can be swapped for another
+//! store, e.g. DirectoryStore, which reads the ABC files where they are without a scan.
 
 extern crate glob;
 extern crate time;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
+use std::io::ErrorKind;
 use std::io::SeekFrom;
 use std::io::Write;
 
-use std::fs::File;
+use std::fs;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::io::Seek;
-use std::sync::Arc;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::vec;
 
-use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::io::{BufReader, BufWriter};
+use std::io::{self, BufRead, BufReader, BufWriter};
+
+use memmap2::Mmap;
+use url::Url;
+
+use abc_lexer;
+use config;
+use representations;
+use sqlite;
+use tune_ast_three;
 
 /// Object for returning iterators that scan over the TuneDB.
 pub struct CacheScanner {
     cache_path: PathBuf,
+
+    // Limit `iter_tunes` to this max id for debugging / profiling.
+    max_id: Option<u32>,
 }
 
 impl CacheScanner {
-    pub fn new(cache_path: PathBuf) -> CacheScanner {
-        CacheScanner { cache_path }
+    pub fn new(cache_path: PathBuf, max_id: Option<u32>) -> CacheScanner {
+        CacheScanner { cache_path, max_id }
     }
 
     pub fn iter(&self) -> CacheIterator {
         CacheIterator::new(&self.cache_path)
     }
 
+    // Iterate over tunes for analysis, up to the max id.
+    // Use this rather than `iter` unless you need every tune in the file.
+    pub fn iter_tunes(&self) -> TuneIterator {
+        TuneIterator::new(
+            Box::new(self.iter().map(|entry| (entry.tune_id, entry.content))),
+            self.max_id,
+        )
+    }
+
+    // Iterate over the latest version of each tune, up to the max id, skipping any that were
+    // replaced by a later entry. `latest` maps each tune ID to the offset of its latest content.
+    pub fn iter_latest_tunes(&self, latest: HashMap<u32, usize>) -> TuneIterator {
+        TuneIterator::new(
+            Box::new(
+                self.iter()
+                    .filter(move |entry| {
+                        latest.get(&entry.tune_id) == Some(&(entry.offset as usize))
+                    })
+                    .map(|entry| (entry.tune_id, entry.content)),
+            ),
+            self.max_id,
+        )
+    }
+
     // Linear scan to retrieve tune by its ID.
     // Not quick, opens a file handle, but OK for quick lookups.
     pub fn find_by_id(&self, tune_id: u32) -> Option<CacheEntry> {
@@ -49,7 +100,8 @@ impl CacheScanner {
 }
 
 pub struct CacheIterator {
-    reader: BufReader<std::fs::File>,
+    // None if there's no cache file yet, which is the same as an empty one.
+    reader: Option<BufReader<std::fs::File>>,
 
     // Header for each chunk is:
     // 4 bytes of tune ID.
@@ -59,8 +111,7 @@ pub struct CacheIterator {
 
 impl CacheIterator {
     fn new(cache_path: &PathBuf) -> CacheIterator {
-        let f = File::open(&cache_path).unwrap();
-        let reader = BufReader::new(f);
+        let reader = File::open(cache_path).ok().map(BufReader::new);
         CacheIterator {
             reader,
             header_buf: vec![0u8; 8],
@@ -89,27 +140,19 @@ fn read_cache_entry(
     reader: &mut BufReader<std::fs::File>,
     header_buf: &mut [u8],
 ) -> Option<CacheEntry> {
-    let tune_id: u32 = (header_buf[0] as u32)
-        | (header_buf[1] as u32) << 8
-        | (header_buf[2] as u32) << 16
-        | (header_buf[3] as u32) << 24;
-
-    let length: usize = (header_buf[4] as usize)
-        | (header_buf[5] as usize) << 8
-        | (header_buf[6] as usize) << 16
-        | (header_buf[7] as usize) << 24;
+    let (tune_id, length) = parse_entry_header(header_buf);
 
     // Allocate each time, as we pass it into the result.
     let mut content_buf = vec![0u8; length];
 
     // Need to get the current offset.
-    let offset = reader.seek(SeekFrom::Current(0)).unwrap();
+    let offset = reader.stream_position().unwrap();
 
     // This fills the buffer, which has been resized to the the right length.
     match reader.read_exact(&mut content_buf) {
         Err(_) => {
-            eprintln!("Error! Tried to read invalid file offset.");
-            return None;
+            error!("Tried to read invalid file offset.");
+            None
         }
         _ => {
             let content = match String::from_utf8(content_buf) {
@@ -117,7 +160,7 @@ fn read_cache_entry(
                 _ => {
                     // E.g. encoding issues.
                     // Return empty string rather than None, or we'd stop iteration.
-                    eprintln!("Failed to read string buffer.");
+                    warn!("Failed to read string buffer for tune {}.", tune_id);
                     String::new()
                 }
             };
@@ -131,294 +174,2548 @@ fn read_cache_entry(
     }
 }
 
-// Given a filename of a source ABC file, return the tune ID.
-fn tune_id_from_filename(filepath: &PathBuf) -> Option<u32> {
-    if let Some(file_name) = filepath.file_name() {
-        if let Some(file_name) = file_name.to_str() {
-            if let Some(first) = file_name.split(".").next() {
-                if let Ok(val) = first.parse::<u32>() {
-                    return Some(val);
-                }
-            }
-        }
-    }
+// Tune ID and length from the header of a tune in the cache file, see `entry_header`.
+fn parse_entry_header(header_buf: &[u8]) -> (u32, usize) {
+    let tune_id: u32 = (header_buf[0] as u32)
+        | (header_buf[1] as u32) << 8
+        | (header_buf[2] as u32) << 16
+        | (header_buf[3] as u32) << 24;
+
+    let length: usize = (header_buf[4] as usize)
+        | (header_buf[5] as usize) << 8
+        | (header_buf[6] as usize) << 16
+        | (header_buf[7] as usize) << 24;
 
-    return None;
+    (tune_id, length)
 }
 
-impl Iterator for CacheIterator {
-    type Item = CacheEntry;
+// Entries in the cache file with this ID aren't tunes, but the IDs of tunes in files of several,
+// see `TuneIds`. The last one in the file is the current one.
+pub const TUNE_IDS_ENTRY: u32 = u32::MAX;
 
-    fn next(&mut self) -> Option<CacheEntry> {
-        match self.reader.read_exact(&mut self.header_buf) {
-            // End of file is ok here.
-            Err(_) => return None,
-            _ => (),
-        };
+// Entries in the cache file with this ID aren't tunes, but records of tunes, see `TuneRecord`.
+// A record for a tune replaces any earlier one.
+pub const TUNE_RECORDS_ENTRY: u32 = u32::MAX - 1;
 
-        read_cache_entry(&mut self.reader, &mut self.header_buf)
-    }
+// Whether the ID is kept for an entry in the cache file that isn't a tune.
+pub fn is_reserved_id(tune_id: u32) -> bool {
+    tune_id == TUNE_IDS_ENTRY || tune_id == TUNE_RECORDS_ENTRY
 }
 
-// Read-only cache of ABC tunes, indexed by u32 ID, returning a string.
-// Doesn't store all the tunes in RAM, instead stores only offset pointers.
-// Every access involves a file seek. Holds a file handle open.
-pub struct ReadOnlyCache {
-    cache_path: PathBuf,
+// What's worked out about a tune when it's stored, so loading the tunecache doesn't have to work
+// it out again for every tune. Kept in the tunecache, see `TUNE_RECORDS_ENTRY`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuneRecord {
+    // Hash of the ABC without whitespace, see `canonical_hash`.
+    pub canonical_hash: u64,
 
-    // Map of Tune ID to start offset, length.
-    // This is always populated, and serves as the canonical index of tune IDs we know about.
-    offset_cache: HashMap<u32, (usize, usize)>,
+    // Hash of the ABC, see `content_hash`.
+    pub content_hash: u64,
 
-    // Open file handle which we keep for the lifetime of this object.
-    reader: BufReader<std::fs::File>,
+    // When the tune last changed, as seconds since the epoch, if it's known.
+    pub modified: Option<u64>,
 }
 
-impl ReadOnlyCache {
-    pub fn new(cache_path: PathBuf) -> Result<ReadOnlyCache, String> {
-        if let Ok(f) = File::open(&cache_path) {
-            let mut reader = BufReader::new(f);
-
-            // Start by loading.
-            let mut cache = ReadOnlyCache {
-                cache_path,
-                reader,
-                offset_cache: HashMap::new(),
-            };
-            cache.load_cache();
-
-            Ok(cache)
-        } else {
-            Err("Failed to open file reader".to_string())
+impl TuneRecord {
+    pub fn new(content: &str, modified: Option<u64>) -> TuneRecord {
+        TuneRecord {
+            canonical_hash: canonical_hash(content),
+            content_hash: content_hash(content.as_bytes()),
+            modified,
         }
     }
+}
 
-    // Load the cache file from disk.
-    pub fn load_cache(&mut self) {
-        self.offset_cache = HashMap::new();
+// Read records written by `records_to_string`, adding them to those given.
+// Lines that can't be read are skipped with a warning.
+fn parse_records(content: &str, records: &mut HashMap<u32, TuneRecord>) {
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let record = match fields.as_slice() {
+            [tune_id, canonical_hash, content_hash, modified] => (
+                tune_id.parse::<u32>(),
+                canonical_hash.parse::<u64>(),
+                content_hash.parse::<u64>(),
+                match *modified {
+                    "-" => Ok(None),
+                    modified => modified.parse::<u64>().map(Some),
+                },
+            ),
+            _ => {
+                warn!("Can't read tune record '{}' in the tunecache.", line);
+                continue;
+            }
+        };
 
-        // Limit the tunes to this max id for debugging / profiling.
-        let key = "DEBUG_MAX_ID";
-        let debug_max_id = match env::var(key) {
-            Ok(value) => {
-                eprintln!("Using {} {}", key, value);
-                Some(value.parse::<u32>().unwrap())
+        match record {
+            (Ok(tune_id), Ok(canonical_hash), Ok(content_hash), Ok(modified)) => {
+                records.insert(
+                    tune_id,
+                    TuneRecord {
+                        canonical_hash,
+                        content_hash,
+                        modified,
+                    },
+                );
             }
-            _ => None,
+            _ => warn!("Can't read tune record '{}' in the tunecache.", line),
+        }
+    }
+}
+
+// One line per tune, e.g. "12 9876543210 1234567890 1546300800" for tune 12, its canonical hash,
+// its content hash and when it was modified, or "-" if that isn't known.
+fn records_to_string(records: &[(u32, TuneRecord)]) -> String {
+    let mut result = String::new();
+    for (tune_id, record) in records.iter() {
+        let modified = match record.modified {
+            Some(modified) => modified.to_string(),
+            None => "-".to_string(),
         };
+        result.push_str(&format!(
+            "{} {} {} {}\n",
+            tune_id, record.canonical_hash, record.content_hash, modified
+        ));
+    }
+    result
+}
 
-        let mut scanner = CacheScanner::new(self.cache_path.clone());
+// Header for a tune in the cache file: 4 bytes of tune ID then 4 bytes of length, little-endian.
+fn entry_header(tune_id: u32, length: usize) -> [u8; 8] {
+    [
+        (tune_id & 0x000000FF) as u8,
+        ((tune_id & 0x0000FF00) >> 8) as u8,
+        ((tune_id & 0x00FF0000) >> 16) as u8,
+        ((tune_id & 0xFF000000) >> 24) as u8,
+        (length & 0x000000FF) as u8,
+        ((length & 0x0000FF00) >> 8) as u8,
+        ((length & 0x00FF0000) >> 16) as u8,
+        ((length & 0xFF000000) >> 24) as u8,
+    ]
+}
 
-        for entry in scanner.iter() {
-            if let Some(max_id) = debug_max_id {
-                if entry.tune_id > max_id {
-                    continue;
-                }
-            }
+// A tune from a store, with its AST parsed on first use.
+pub struct TuneEntry {
+    pub tune_id: u32,
+    pub content: String,
+
+    ast: Option<tune_ast_three::Tune>,
+}
 
-            // Only need the offset and length.
-            self.offset_cache
-                .insert(entry.tune_id, (entry.offset as usize, entry.length));
+impl TuneEntry {
+    pub fn ast(&mut self) -> &tune_ast_three::Tune {
+        if self.ast.is_none() {
+            self.ast = Some(representations::abc_to_ast(&self.content));
         }
+        self.ast.as_ref().unwrap()
     }
+}
 
-    pub fn max_id(&self) -> u32 {
-        let mut max = 0;
+// Iterator over tunes in a store, skipping those over the debug max id and any excluded.
+pub struct TuneIterator {
+    // Tune ID and content.
+    entries: Box<dyn Iterator<Item = (u32, String)>>,
 
-        for tune_id in self.offset_cache.keys() {
-            max = u32::max(max, *tune_id);
-        }
+    max_id: Option<u32>,
+    excluded: HashSet<u32>,
+}
 
-        max
+impl TuneIterator {
+    // Tune IDs and content from a store, skipping any over the max id.
+    pub fn new(
+        entries: Box<dyn Iterator<Item = (u32, String)>>,
+        max_id: Option<u32>,
+    ) -> TuneIterator {
+        TuneIterator {
+            entries,
+            max_id,
+            excluded: HashSet::new(),
+        }
     }
 
-    // Because this involves a file seek, this needs to be mutable.
-    pub fn get(&mut self, tune_id: u32) -> Option<String> {
-        match self.offset_cache.get(&tune_id) {
-            Some((offset, length)) => {
-                let mut string_buf = Vec::with_capacity(*length);
+    // Skip these tunes, e.g. ones known to be broken.
+    pub fn excluding(mut self, tune_ids: &[u32]) -> TuneIterator {
+        self.excluded.extend(tune_ids.iter());
+        self
+    }
+}
 
-                match self.reader.seek(SeekFrom::Start(*offset as u64)) {
-                    Err(_) => {
-                        eprintln!("Error! Tried to seek to invalid file offset.");
-                        return None;
-                    }
-                    Ok(_) => (),
-                };
+impl Iterator for TuneIterator {
+    type Item = TuneEntry;
 
-                string_buf.resize(*length, 0x0);
-                match self.reader.read_exact(&mut string_buf) {
-                    Ok(_) => Some(String::from_utf8(string_buf).unwrap()),
-                    Err(_) => None,
-                }
+    fn next(&mut self) -> Option<TuneEntry> {
+        for (tune_id, content) in self.entries.by_ref() {
+            if self.max_id.is_some_and(|max_id| tune_id > max_id)
+                || self.excluded.contains(&tune_id)
+            {
+                continue;
             }
 
-            // Or none.
-            _ => None,
+            return Some(TuneEntry {
+                tune_id,
+                content,
+                ast: None,
+            });
         }
+
+        None
     }
 }
 
-// Read-write cache of ABC tunes, indexed by u32 tune ID, returning a string.
-// Stores all tunes in a big hash table.
-pub struct ReadWriteCache {
-    // Path of the tune cache file.
-    cache_path: PathBuf,
+// FNV-1a hash of a tune's content.
+// Unlike the standard library's hasher, this is the same between runs and builds, so it can be sent
+// to clients, e.g. in an ETag.
+pub fn content_hash(content: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in content.iter() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
 
-    // Map of Tune ID to ABC String.
-    // This doesn't need to be populated necessarily,
-    // but all lookups will look here first.
-    string_cache: HashMap<u32, String>,
+// Hash of a tune's content ignoring whitespace and the X: reference number, so copies of a tune
+// that only differ in layout, or in their place in a file, hash the same.
+pub fn canonical_hash(content: &str) -> u64 {
+    let canonical: String = content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("X:"))
+        .flat_map(|line| line.chars())
+        .filter(|x| !x.is_whitespace())
+        .collect();
+    content_hash(canonical.as_bytes())
 }
 
-impl ReadWriteCache {
-    // Load the cache file from disk.
-    pub fn load_cache(&mut self) {
-        // Reset everything.
-        self.string_cache = HashMap::new();
+// Groups of tunes with the same canonical hash, i.e. exact duplicates apart from whitespace, from
+// tune IDs to their canonical hashes. Each group is in order of tune ID, as are the groups.
+pub fn duplicate_groups(hashes: &HashMap<u32, u64>) -> Vec<Vec<u32>> {
+    let mut by_hash: HashMap<u64, Vec<u32>> = HashMap::new();
+    for (tune_id, hash) in hashes.iter() {
+        by_hash.entry(*hash).or_default().push(*tune_id);
+    }
 
-        // Limit the tunes to this max id for debugging / profiling.
-        let key = "DEBUG_MAX_ID";
-        let debug_max_id = match env::var(key) {
-            Ok(value) => {
-                eprintln!("Using {} {}", key, value);
-                Some(value.parse::<u32>().unwrap())
-            }
-            _ => None,
-        };
+    let mut groups: Vec<Vec<u32>> = by_hash
+        .into_values()
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .filter(|group| group.len() > 1)
+        .collect();
+    groups.sort();
+    groups
+}
+
+// Hash identifying one generation of the tunecache, from the IDs and content hashes of its tunes.
+// Anything built from the tunecache, like the clusters file, records this so we can tell if it's
+// stale. Doesn't depend on the order tunes are stored in.
+pub fn generation_hash(hashes: &HashMap<u32, u64>) -> u64 {
+    let mut entries: Vec<(&u32, &u64)> = hashes.iter().collect();
+    entries.sort();
+
+    let mut bytes = Vec::with_capacity(entries.len() * 12);
+    for (tune_id, hash) in entries {
+        for i in 0..4 {
+            bytes.push((tune_id >> (i * 8)) as u8);
+        }
+        for i in 0..8 {
+            bytes.push((hash >> (i * 8)) as u8);
+        }
+    }
 
-        let mut scanner = CacheScanner::new(self.cache_path.clone());
+    content_hash(&bytes)
+}
 
-        for entry in scanner.iter() {
-            if let Some(max_id) = debug_max_id {
-                if entry.tune_id > max_id {
-                    continue;
-                }
+// Where a tune came from and how it can be reused, from the optional metadata file.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TuneMeta {
+    // URL, or description, of where the tune was found.
+    pub source: Option<String>,
+
+    // Licence it's published under, e.g. "CC BY-SA 4.0".
+    pub licence: Option<String>,
+}
+
+impl TuneMeta {
+    // Where the tune came from, for filtering: the host of the source URL without "www.", or the
+    // source as written if it's not a URL, e.g. "thesession.org" or "O'Neill's 1001".
+    pub fn source_name(&self) -> Option<String> {
+        self.source.as_ref().map(|source| match Url::parse(source) {
+            Ok(ref url) if url.host_str().is_some() => {
+                let host = url.host_str().unwrap_or_default();
+                host.strip_prefix("www.").unwrap_or(host).to_string()
             }
+            _ => source.clone(),
+        })
+    }
+}
 
-            self.string_cache.insert(entry.tune_id, entry.content);
+// Split a line of CSV into fields. Fields may be quoted, with "" for a quote inside.
+fn csv_fields(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
         }
     }
 
-    // Construct a new empty ReadWriteCache.
-    pub fn new(cache_path: PathBuf) -> Result<ReadWriteCache, String> {
-        if let Ok(f) = File::open(&cache_path) {
-            let mut reader = BufReader::new(f);
-            let mut cache = ReadWriteCache {
-                cache_path,
-                string_cache: HashMap::new(),
-            };
-            cache.load_cache();
-            Ok(cache)
-        } else {
-            Err("Failed to open file reader".to_string())
-        }
+    if quoted {
+        return Err("Unclosed quote.".to_string());
     }
 
-    // Flush the string cache.
-    pub fn flush(&mut self) {
-        eprintln!("Saving {} tunes", self.string_cache.len());
-        let f = File::create(&self.cache_path).expect("Can't open!");
-        let mut writer = BufWriter::new(f);
+    fields.push(field);
+    Ok(fields.into_iter().map(|x| x.trim().to_string()).collect())
+}
+
+// Parse the metadata file, CSV with a header row naming the columns, e.g.
+//
+// id,source,licence
+// 1,https://thesession.org/tunes/1,"CC BY-SA 4.0"
+//
+// The `id` column is required, `source` and `licence` are optional, and other columns are ignored
+// so the file can carry notes. Empty values are treated as missing.
+pub fn parse_meta(content: &str) -> Result<HashMap<u32, TuneMeta>, String> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let header = match lines.next() {
+        Some((_, line)) => csv_fields(line).map_err(|err| format!("Line 1: {}", err))?,
+        None => return Ok(HashMap::new()),
+    };
+    let column = |name: &str| header.iter().position(|x| x.eq_ignore_ascii_case(name));
+
+    let id_column =
+        column("id").ok_or_else(|| "Header doesn't have an 'id' column.".to_string())?;
+    let source_column = column("source");
+    let licence_column = column("licence").or_else(|| column("license"));
+
+    let mut meta = HashMap::new();
+    for (i, line) in lines {
+        let fields = csv_fields(line).map_err(|err| format!("Line {}: {}", i + 1, err))?;
+        let value = |column: Option<usize>| {
+            column
+                .and_then(|column| fields.get(column))
+                .filter(|x| !x.is_empty())
+                .cloned()
+        };
 
-        let mut metadata_buf = vec![0u8; 8];
+        let tune_id = match value(Some(id_column)).map(|x| x.parse::<u32>()) {
+            Some(Ok(tune_id)) => tune_id,
+            _ => return Err(format!("Line {}: Expected a tune ID.", i + 1)),
+        };
+
+        meta.insert(
+            tune_id,
+            TuneMeta {
+                source: value(source_column),
+                licence: value(licence_column),
+            },
+        );
+    }
 
-        for (tune_id, value) in self.string_cache.iter() {
-            let string_buf = value.as_bytes();
-            let length = string_buf.len();
+    Ok(meta)
+}
 
-            metadata_buf[0] = (tune_id & 0x000000FF) as u8;
-            metadata_buf[1] = ((tune_id & 0x0000FF00) >> 8) as u8;
-            metadata_buf[2] = ((tune_id & 0x00FF0000) >> 16) as u8;
-            metadata_buf[3] = ((tune_id & 0xFF000000) >> 24) as u8;
+// Load the metadata file.
+pub fn load_meta(path: &Path) -> Result<HashMap<u32, TuneMeta>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Can't read metadata file {:?}: {:?}", path, err))?;
+    parse_meta(&content).map_err(|err| format!("Can't parse metadata file {:?}: {}", path, err))
+}
 
-            metadata_buf[4] = (length & 0x000000FF) as u8;
-            metadata_buf[5] = ((length & 0x0000FF00) >> 8) as u8;
-            metadata_buf[6] = ((length & 0x00FF0000) >> 16) as u8;
-            metadata_buf[7] = ((length & 0xFF000000) >> 24) as u8;
+// Glob pattern for all the ABC files anywhere in the base directory.
+pub fn abc_glob(base: &str) -> String {
+    let mut glob_path = PathBuf::new();
+    glob_path.push(base);
+    glob_path.push("**");
+    glob_path.push("*");
+    glob_path.set_extension("abc");
 
-            writer.write_all(&metadata_buf).expect("Can't write");
+    glob_path.to_str().expect("Can't create path").to_string()
+}
 
-            writer.write_all(&string_buf).expect("Can't write");
+// Given a filename of a source ABC file, return the tune ID.
+pub fn tune_id_from_filename(filepath: &Path) -> Option<u32> {
+    if let Some(file_name) = filepath.file_name() {
+        if let Some(file_name) = file_name.to_str() {
+            if let Some(first) = file_name.split(".").next() {
+                match first.parse::<u32>() {
+                    Ok(val) if is_reserved_id(val) => warn!("{} is a reserved ID.", val),
+                    Ok(val) => return Some(val),
+                    Err(_) => (),
+                }
+            }
         }
     }
 
-    // Recursively scan a directory of ABC files into String cache.
-    pub fn scan_dir(&mut self, base: &String) {
-        let mut glob_path = PathBuf::new();
-        glob_path.push(base);
-        glob_path.push("**");
-        glob_path.push("*");
-        glob_path.set_extension("abc");
+    None
+}
 
-        let mut num_scanned = 0;
-        let mut num_indexed = 0;
+// The number in an X: field that starts a tune, if it's a number, or None if the line isn't one.
+fn x_field(line: &str) -> Option<Option<u32>> {
+    line.strip_prefix("X:")
+        .map(|value| value.trim().parse::<u32>().ok())
+}
 
-        // Iterate and load into cache.
-        for entry in glob::glob(glob_path.to_str().expect("Can't create path"))
-            .expect("Failed to read glob pattern")
-        {
-            match entry {
-                Ok(filepath) => {
-                    if let Some(tune_id) = tune_id_from_filename(&filepath) {
-                        // Check our index, only read the file if we haven't got it yet.
-                        // The offset cache serves as the canonical index of tune IDs.
-                        if !self.string_cache.contains_key(&tune_id) {
-                            let mut content = String::new();
+// Split the content of an ABC file into tunes, each starting with an X: field.
+// Returns the X: number, if it is one, the line the tune starts on, counting from 1, and the tune.
+// Anything before the first X: field stays with the first tune, so a file with one tune is returned
+// unchanged.
+pub fn split_tunes(content: &str) -> Vec<(Option<u32>, usize, &str)> {
+    let mut starts = vec![];
+    let mut offset = 0;
+    for (line_number, line) in content.split('\n').enumerate() {
+        if let Some(x) = x_field(line) {
+            starts.push((x, line_number + 1, offset));
+        }
+        offset += line.len() + 1;
+    }
+
+    if starts.is_empty() {
+        return vec![(None, 1, content)];
+    }
+
+    // The first tune starts at the beginning of the file.
+    starts[0].1 = 1;
+    starts[0].2 = 0;
+
+    let mut tunes = vec![];
+    for (i, (x, line_number, start)) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map_or(content.len(), |next| next.2);
+        tunes.push((*x, *line_number, &content[*start..end]));
+    }
+    tunes
+}
+
+// Read tunes one at a time from a stream, split as `split_tunes` does, so a large collection
+// doesn't have to be in memory all at once. Only the next tune's X: line is read ahead.
+pub struct TuneReader<R> {
+    reader: R,
+
+    // Lines read so far.
+    line_number: usize,
+
+    // The X: line starting the next tune, and its line number, if it's been read.
+    next_start: Option<(String, usize)>,
+
+    // Has a tune been returned, and has the stream ended?
+    started: bool,
+    finished: bool,
+}
+
+impl<R: BufRead> TuneReader<R> {
+    pub fn new(reader: R) -> TuneReader<R> {
+        TuneReader {
+            reader,
+            line_number: 0,
+            next_start: None,
+            started: false,
+            finished: false,
+        }
+    }
+}
 
-                            let mut f = File::open(filepath).expect("file not found");
+impl<R: BufRead> Iterator for TuneReader<R> {
+    // As `split_tunes`, the X: number, the line the tune starts on, and the tune.
+    type Item = io::Result<(Option<u32>, usize, String)>;
 
-                            f.read_to_string(&mut content).expect("Can't read file");
+    fn next(&mut self) -> Option<io::Result<(Option<u32>, usize, String)>> {
+        // The first tune is returned even if there's nothing there, as in `split_tunes`.
+        if self.started && self.finished && self.next_start.is_none() {
+            return None;
+        }
 
-                            self.string_cache.insert(tune_id, content);
-                            num_indexed += 1;
+        let (mut tune, line_number) = self.next_start.take().unwrap_or((String::new(), 1));
+        let mut x = x_field(&tune);
+        self.started = true;
+
+        let mut line = String::new();
+        while !self.finished {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => self.finished = true,
+                Ok(_) => {
+                    self.line_number += 1;
+
+                    if let Some(number) = x_field(&line) {
+                        // Anything before the first X: field stays with the first tune.
+                        if x.is_some() {
+                            self.next_start = Some((line.clone(), self.line_number));
+                            break;
                         }
-                    } else {
-                        eprintln!("Failed to get tune id for path: {}", filepath.display());
+                        x = Some(number);
                     }
+                    tune.push_str(&line);
+                }
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
                 }
-                Err(e) => eprintln!("Error {:?}", e),
             }
+        }
 
-            num_scanned += 1;
+        Some(Ok((x.unwrap_or(None), line_number, tune)))
+    }
+}
 
-            if num_scanned % 10000 == 0 {
-                eprintln!("Scanned {} tunes, indexed {}", num_scanned, num_indexed);
+// The tunes in a file, each with its key among the file's tunes: its X: number, or "#n" for the
+// nth tune if its X: field isn't a number or is used twice. A file with one tune has no key.
+fn keyed_tunes(content: &str) -> Vec<(Option<String>, &str)> {
+    let tunes = split_tunes(content);
+    if tunes.len() == 1 {
+        return vec![(None, content)];
+    }
+
+    let mut keys: HashSet<String> = HashSet::new();
+    let mut result = vec![];
+    for (i, (x, _, tune)) in tunes.iter().enumerate() {
+        let key = match x {
+            Some(x) if !keys.contains(&x.to_string()) => x.to_string(),
+            _ => format!("#{}", i + 1),
+        };
+        keys.insert(key.clone());
+        result.push((Some(key), *tune));
+    }
+    result
+}
+
+// Tune IDs of tunes in files of several, which can't have the file's ID. Each tune is given the
+// next ID from a counter the first time it's seen, and keeps it, however the file is edited or
+// renumbered around it. IDs are handed out above those of files, so they're as dense as the
+// files' IDs, and don't clash with them.
+// They're kept in the tunecache, see `TUNE_IDS_ENTRY`, so a scan gives tunes the same IDs as the
+// scan before.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TuneIds {
+    // (file ID, key of the tune in the file, see `keyed_tunes`) -> tune ID.
+    ids: BTreeMap<(u32, String), u32>,
+
+    // The ID to give the next new tune.
+    next_id: u32,
+}
+
+impl TuneIds {
+    pub fn new() -> TuneIds {
+        TuneIds::default()
+    }
+
+    // Read IDs written by `to_string`. Lines that can't be read are skipped with a warning.
+    pub fn parse(content: &str) -> TuneIds {
+        let mut tune_ids = TuneIds::new();
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["next", next_id] => match next_id.parse::<u32>() {
+                    Ok(next_id) => tune_ids.next_id = tune_ids.next_id.max(next_id),
+                    Err(_) => warn!("Can't read tune ID '{}' in the tunecache.", line),
+                },
+                [file_id, key, tune_id] => match (file_id.parse::<u32>(), tune_id.parse::<u32>()) {
+                    (Ok(file_id), Ok(tune_id)) => {
+                        tune_ids.ids.insert((file_id, key.to_string()), tune_id);
+                        tune_ids.reserve(tune_id);
+                    }
+                    _ => warn!("Can't read tune ID '{}' in the tunecache.", line),
+                },
+                _ => warn!("Can't read tune ID '{}' in the tunecache.", line),
             }
         }
+        tune_ids
     }
 
-    pub fn max_id(&self) -> u32 {
-        let mut max = 0;
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
 
-        for tune_id in self.string_cache.keys() {
-            max = u32::max(max, *tune_id);
+    // Don't give out this ID or any below it, e.g. because a file has it.
+    pub fn reserve(&mut self, tune_id: u32) {
+        self.next_id = self.next_id.max(tune_id.saturating_add(1));
+    }
+
+    // ID of a tune in a file of several, giving it the next one if it hasn't got one.
+    // None if the IDs have run out.
+    pub fn get_or_assign(&mut self, file_id: u32, key: &str) -> Option<u32> {
+        let key = (file_id, key.to_string());
+        if let Some(tune_id) = self.ids.get(&key) {
+            return Some(*tune_id);
         }
 
-        max
+        if is_reserved_id(self.next_id) {
+            return None;
+        }
+        let tune_id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(key, tune_id);
+        Some(tune_id)
     }
 
-    pub fn get(&self, tune_id: u32) -> Option<String> {
-        // Return from string cache if it's there.
-        match self.string_cache.get(&tune_id) {
-            // TODO this could be wasteful making a copy. Maybe revert back to ARC.
-            Some(val) => Some(val.to_string()),
+    // Forget the IDs of tunes in files of several that files now have, e.g. because 12.abc was
+    // added after tune 12 was given to a tune in another file, so those tunes get new ones.
+    // Returns the tunes that lost their IDs, as (file ID, key, tune ID).
+    pub fn release(&mut self, file_ids: &HashSet<u32>) -> Vec<(u32, String, u32)> {
+        let released: Vec<(u32, String, u32)> = self
+            .ids
+            .iter()
+            .filter(|(_, tune_id)| file_ids.contains(tune_id))
+            .map(|((file_id, key), tune_id)| (*file_id, key.clone(), *tune_id))
+            .collect();
+
+        for (file_id, key, _) in released.iter() {
+            self.ids.remove(&(*file_id, key.clone()));
+        }
+        released
+    }
+}
 
-            // Or none.
-            _ => None,
+// One line per tune, e.g. "12 3 1001" for X:3 in 12.abc, after the next ID to give, e.g.
+// "next 1002".
+impl fmt::Display for TuneIds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "next {}", self.next_id)?;
+        for ((file_id, key), tune_id) in self.ids.iter() {
+            writeln!(f, "{} {} {}", file_id, key, tune_id)?;
         }
+        Ok(())
     }
 }
 
-// Cloning involves opening a new file handle.
-impl Clone for ReadOnlyCache {
-    fn clone(&self) -> ReadOnlyCache {
-        let f = File::open(&self.cache_path).unwrap();
-        let reader = BufReader::new(f);
-        ReadOnlyCache {
-            reader,
-            cache_path: self.cache_path.clone(),
-            offset_cache: self.offset_cache.clone(),
+// Give new IDs to tunes in files of several whose IDs files now have, with a warning.
+// Returns the IDs they had.
+fn release_file_ids(tune_ids: &mut TuneIds, files: &[(PathBuf, u32)]) -> Vec<u32> {
+    let file_ids: HashSet<u32> = files.iter().map(|(_, file_id)| *file_id).collect();
+    let mut result = vec![];
+    for (file_id, key, tune_id) in tune_ids.release(&file_ids) {
+        warn!(
+            "Tune {} in file {} had the ID of {}.abc, so it's given a new one.",
+            key, file_id, tune_id
+        );
+        result.push(tune_id);
+    }
+    result
+}
+
+// The tunes in a file with their tune IDs.
+// A file with one tune has the file's ID. Tunes in a file with more get IDs from `tune_ids`.
+pub fn tunes_in_file(file_id: u32, content: &str, tune_ids: &mut TuneIds) -> Vec<(u32, String)> {
+    let mut result = vec![];
+    for (key, tune) in keyed_tunes(content) {
+        match key {
+            None => result.push((file_id, tune.to_string())),
+            Some(key) => match tune_ids.get_or_assign(file_id, &key) {
+                Some(tune_id) => result.push((tune_id, tune.to_string())),
+                None => warn!("No more tune IDs for tune {} in file {}.", key, file_id),
+            },
+        }
+    }
+    result
+}
+
+// The ABC files anywhere in the base directory, with their file IDs.
+// Files without a number for a name are skipped with a warning.
+pub fn abc_files(base: &str) -> Result<Vec<(PathBuf, u32)>, String> {
+    let pattern =
+        glob::glob(&abc_glob(base)).map_err(|err| format!("Can't scan {}: {}", base, err))?;
+
+    let mut files = vec![];
+    for entry in pattern {
+        match entry {
+            Ok(filepath) => match tune_id_from_filename(&filepath) {
+                Some(file_id) => files.push((filepath, file_id)),
+                None => warn!("Failed to get tune id for path: {}", filepath.display()),
+            },
+            Err(e) => error!("Error {:?}", e),
+        }
+    }
+    Ok(files)
+}
+
+// Contents of the entries with this reserved ID in a cache file, in order, skipping the tunes.
+// Empty if there's no cache file.
+fn read_reserved_entries(cache_path: &Path, entry_id: u32) -> Vec<String> {
+    let mut reader = match File::open(cache_path) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return vec![],
+    };
+
+    let mut result = vec![];
+    let mut header_buf = [0u8; 8];
+    while reader.read_exact(&mut header_buf).is_ok() {
+        let (tune_id, length) = parse_entry_header(&header_buf);
+        if tune_id != entry_id {
+            if reader.seek_relative(length as i64).is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let mut content_buf = vec![0u8; length];
+        match reader
+            .read_exact(&mut content_buf)
+            .map(|_| String::from_utf8(content_buf))
+        {
+            Ok(Ok(content)) => result.push(content),
+            _ => error!("Can't read entry {} in the tunecache.", entry_id),
+        }
+    }
+    result
+}
+
+// Tune IDs, offsets and lengths of the tunes in a cache file, in the order they were written,
+// found from their headers without reading the tunes. Empty if there's no cache file.
+fn read_tune_offsets(cache_path: &Path) -> Vec<(u32, usize, usize)> {
+    let file = match File::open(cache_path) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    let file_length = file.metadata().map_or(0, |metadata| metadata.len() as usize);
+    let mut reader = BufReader::new(file);
+
+    let mut result = vec![];
+    let mut offset = 0;
+    let mut header_buf = [0u8; 8];
+    while reader.read_exact(&mut header_buf).is_ok() {
+        let (tune_id, length) = parse_entry_header(&header_buf);
+        let start = offset + 8;
+        offset = start + length;
+        if offset > file_length {
+            error!("Tune {} runs past the end of the tunecache.", tune_id);
+            break;
+        }
+
+        if !is_reserved_id(tune_id) {
+            result.push((tune_id, start, length));
+        }
+        if reader.seek_relative(length as i64).is_err() {
+            break;
         }
     }
+    result
+}
+
+// Tune IDs for the tunes in files of several, from the last list of them in a cache file.
+// Empty if there's no cache file or list.
+pub fn read_tune_ids(cache_path: &Path) -> TuneIds {
+    read_reserved_entries(cache_path, TUNE_IDS_ENTRY)
+        .last()
+        .map(|content| TuneIds::parse(content))
+        .unwrap_or_default()
+}
+
+// Records of the tunes in a cache file, the last for each tune. Tunes in a cache file written
+// before there were records don't have any.
+fn read_tune_records(cache_path: &Path) -> HashMap<u32, TuneRecord> {
+    let mut records = HashMap::new();
+    for content in read_reserved_entries(cache_path, TUNE_RECORDS_ENTRY) {
+        parse_records(&content, &mut records);
+    }
+    records
+}
+
+impl Iterator for CacheIterator {
+    type Item = CacheEntry;
+
+    fn next(&mut self) -> Option<CacheEntry> {
+        let reader = match self.reader {
+            Some(ref mut reader) => reader,
+            None => return None,
+        };
+
+        loop {
+            // End of file is ok here.
+            if reader.read_exact(&mut self.header_buf).is_err() {
+                return None;
+            }
+
+            // Skip the tune IDs and records, which aren't tunes.
+            let (tune_id, length) = parse_entry_header(&self.header_buf);
+            if !is_reserved_id(tune_id) {
+                return read_cache_entry(reader, &mut self.header_buf);
+            }
+            if reader.seek_relative(length as i64).is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+// Somewhere tunes are kept, indexed by u32 tune ID.
+// The search engine and server only use this, so deployments can choose how tunes are stored.
+pub trait TuneStore: Send + Sync {
+    // ABC of a tune.
+    fn get(&self, tune_id: u32) -> Option<String>;
+
+    // Iterate over all the tunes, up to the store's max id.
+    fn iter(&self) -> TuneIterator;
+
+    // Largest tune ID in use, or 0 if there are no tunes.
+    fn max_id(&self) -> u32;
+
+    // Store a tune, replacing any with the same ID.
+    fn put(&mut self, tune_id: u32, content: &str) -> Result<(), String>;
+
+    // All known tune IDs, in order.
+    fn tune_ids(&self) -> Vec<u32>;
+
+    // A copy for another thread, so lookups don't contend, e.g. with its own file handle.
+    // Fails if that can't be opened.
+    fn reader(&self) -> Result<Box<dyn TuneStore>, String>;
+
+    // Changes whenever a tune is put, so copies can tell they're out of date.
+    fn version(&self) -> usize;
+
+    // Hash of the tune's content, which changes when the tune does.
+    fn get_hash(&self, tune_id: u32) -> Option<u64> {
+        self.get(tune_id)
+            .map(|content| content_hash(content.as_bytes()))
+    }
+
+    // Hash of the tune's ABC without whitespace, see `canonical_hash`, if the store keeps it.
+    fn get_canonical_hash(&self, _tune_id: u32) -> Option<u64> {
+        None
+    }
+
+    // Generation of the tunes, see `generation_hash`.
+    // This reads every tune, so stores should keep their hashes if they can.
+    fn generation(&self) -> u64 {
+        let hashes: HashMap<u32, u64> = self
+            .iter()
+            .map(|entry| (entry.tune_id, content_hash(entry.content.as_bytes())))
+            .collect();
+        generation_hash(&hashes)
+    }
+
+    // When the tunes last changed, as seconds since the epoch, if it's known.
+    fn modified(&self) -> Option<u64> {
+        None
+    }
+
+    // When the tune last changed, as seconds since the epoch, if it's known. Stores that don't
+    // keep this for each tune give when any of them last changed.
+    fn get_modified(&self, _tune_id: u32) -> Option<u64> {
+        self.modified()
+    }
+
+    fn num_tunes(&self) -> usize {
+        self.tune_ids().len()
+    }
+}
+
+// Open the store chosen in the config, for the tunes in the base directory:
+// - "tunecache" for the tunecache file built by `abctool scan`.
+// - "mmap" for the same file, mapped into memory, see `MappedCache`.
+// - "directory" for the ABC files themselves, see `DirectoryStore`.
+// - "sqlite" for a database in the base directory, see `sqlite::SqliteStore`.
+// Only tunes up to the config's `debug_max_id` are used.
+pub fn open_store(config: &config::Config) -> Result<Box<dyn TuneStore>, String> {
+    let base = config.base()?;
+    let max_id = config.debug_max_id;
+    if let Some(max_id) = max_id {
+        info!("Using tunes up to {}", max_id);
+    }
+
+    match config.storage.as_ref() {
+        "tunecache" => Ok(Box::new(ReadOnlyCache::new(
+            base.join("tunecache"),
+            max_id,
+        )?)),
+        "mmap" => Ok(Box::new(MappedCache::new(base.join("tunecache"), max_id)?)),
+        "directory" => Ok(Box::new(DirectoryStore::new(
+            base,
+            max_id,
+            config.dialect(),
+        )?)),
+        "sqlite" => Ok(Box::new(sqlite::SqliteStore::new(
+            &base.join(sqlite::DATABASE_FILENAME),
+            max_id,
+        )?)),
+        _ => Err(format!(
+            "Unknown storage '{}'. Use 'tunecache', 'mmap', 'directory' or 'sqlite'.",
+            config.storage
+        )),
+    }
+}
+
+// Add a tune to the end of a cache file, creating it if need be.
+// Returns the offset of the tune's content, after its header.
+fn append_entry(cache_path: &Path, tune_id: u32, content: &str) -> Result<usize, String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cache_path)
+        .map_err(|err| format!("Failed to open tunecache for writing: {:?}", err))?;
+
+    let offset = file
+        .seek(SeekFrom::End(0))
+        .map_err(|err| format!("Failed to find end of tunecache: {:?}", err))?;
+
+    let bytes = content.as_bytes();
+    file.write_all(&entry_header(tune_id, bytes.len()))
+        .and_then(|_| file.write_all(bytes))
+        .map_err(|err| format!("Failed to append to tunecache: {:?}", err))?;
+
+    // The content starts after the header.
+    Ok(offset as usize + 8)
+}
+
+// Add a tune to the end of a cache file, followed by its record.
+// Returns the offset of the tune's content, its record, and the offset of the end of the file.
+fn append_tune(
+    cache_path: &Path,
+    tune_id: u32,
+    content: &str,
+) -> Result<(usize, TuneRecord, usize), String> {
+    let offset = append_entry(cache_path, tune_id, content)?;
+
+    let record = TuneRecord::new(content, now());
+    let records = records_to_string(&[(tune_id, record)]);
+    let end = append_entry(cache_path, TUNE_RECORDS_ENTRY, &records)? + records.len();
+
+    Ok((offset, record, end))
+}
+
+// Now, as seconds since the epoch.
+fn now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+// When a file was last written, as seconds since the epoch.
+fn modified_time(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+}
+
+// Read-only cache of ABC tunes, indexed by u32 ID, returning a string.
+// Doesn't store all the tunes in RAM, instead stores only offset pointers.
+// Every access involves a file seek. Holds a file handle open.
+// The file handle is behind a lock so the cache can be shared between threads. Threads that make a
+// lot of lookups should have their own clone, which has its own file handle.
+pub struct ReadOnlyCache {
+    cache_path: PathBuf,
+
+    // Map of Tune ID to start offset, length.
+    // This is always populated, and serves as the canonical index of tune IDs we know about.
+    offset_cache: HashMap<u32, (usize, usize)>,
+
+    // Map of Tune ID to hash of its content.
+    hashes: HashMap<u32, u64>,
+
+    // Map of Tune ID to its record.
+    records: HashMap<u32, TuneRecord>,
+
+    // When the cache file was last written, as seconds since the epoch.
+    modified: Option<u64>,
+
+    // Tunes appended since the cache file was loaded. Copies can compare this to see if they're
+    // out of date.
+    num_appended: usize,
+
+    // Ignore tunes over this id, for debugging / profiling.
+    max_id: Option<u32>,
+
+    // Open file handles which we keep for the lifetime of this object, shared out by tune ID so
+    // concurrent reads don't all queue for one.
+    // None if there's no cache file yet, in which case there are no tunes.
+    readers: Vec<Mutex<Option<BufReader<std::fs::File>>>>,
+}
+
+// Number of file handles each ReadOnlyCache keeps open.
+const READER_SHARDS: usize = 8;
+
+// File handles for the cache file, or Nones if it doesn't exist yet.
+fn open_readers(cache_path: &Path) -> Vec<Mutex<Option<BufReader<std::fs::File>>>> {
+    (0..READER_SHARDS)
+        .map(|_| Mutex::new(File::open(cache_path).ok().map(BufReader::new)))
+        .collect()
+}
+
+impl ReadOnlyCache {
+    // A missing cache file is treated as empty, as it won't exist until the first scan.
+    pub fn new(cache_path: PathBuf, max_id: Option<u32>) -> Result<ReadOnlyCache, String> {
+        match File::open(&cache_path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                warn!("No tunecache file at {}, starting empty.", cache_path.display());
+            }
+            Err(err) => return Err(format!("Failed to open file reader: {:?}", err)),
+        };
+
+        // Start by loading.
+        let mut cache = ReadOnlyCache {
+            readers: open_readers(&cache_path),
+            cache_path,
+            offset_cache: HashMap::new(),
+            hashes: HashMap::new(),
+            records: HashMap::new(),
+            modified: None,
+            num_appended: 0,
+            max_id,
+        };
+        cache.load_cache();
+
+        Ok(cache)
+    }
+
+    // Load the cache file from disk.
+    // Only the tunes' headers and records are read. Tunes without a record, from a cache file
+    // written before there were records, are read to make one now.
+    pub fn load_cache(&mut self) {
+        self.offset_cache = HashMap::new();
+        self.modified = modified_time(&self.cache_path);
+
+        for (tune_id, offset, length) in read_tune_offsets(&self.cache_path) {
+            if self.max_id.is_some_and(|max_id| tune_id > max_id) {
+                continue;
+            }
+            self.offset_cache.insert(tune_id, (offset, length));
+        }
+
+        let offset_cache = &self.offset_cache;
+        self.records = read_tune_records(&self.cache_path);
+        self.records
+            .retain(|tune_id, _| offset_cache.contains_key(tune_id));
+        if self.records.len() < self.offset_cache.len() {
+            let missing: Vec<u32> = self
+                .offset_cache
+                .keys()
+                .filter(|tune_id| !self.records.contains_key(tune_id))
+                .cloned()
+                .collect();
+            for tune_id in missing {
+                let content = self.get(tune_id).unwrap_or_default();
+                self.records.insert(tune_id, TuneRecord::new(&content, None));
+            }
+        }
+
+        self.hashes = self
+            .records
+            .iter()
+            .map(|(tune_id, record)| (*tune_id, record.content_hash))
+            .collect();
+    }
+
+    pub fn num_appended(&self) -> usize {
+        self.num_appended
+    }
+
+    // Add a tune to the end of the cache file, e.g. one submitted through the API.
+    // Other copies of the cache don't see it until they're cloned again.
+    pub fn append(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        let (offset, record, _) = append_tune(&self.cache_path, tune_id, content)?;
+
+        self.offset_cache.insert(tune_id, (offset, content.len()));
+        self.hashes.insert(tune_id, record.content_hash);
+        self.records.insert(tune_id, record);
+        self.num_appended += 1;
+        self.modified = now();
+
+        // There are no readers if the file didn't exist until now.
+        for reader in self.readers.iter() {
+            let mut reader = reader.lock().unwrap_or_else(|e| e.into_inner());
+            if reader.is_none() {
+                *reader = File::open(&self.cache_path).ok().map(BufReader::new);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TuneStore for ReadOnlyCache {
+    // This involves a file seek, so holds the lock on one of the file handles.
+    // A panic while it was held leaves nothing half-done, as every read starts with a seek.
+    fn get(&self, tune_id: u32) -> Option<String> {
+        let shard = tune_id as usize % self.readers.len();
+        let mut reader = self.readers[shard]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let reader = match *reader {
+            Some(ref mut reader) => reader,
+            None => return None,
+        };
+
+        match self.offset_cache.get(&tune_id) {
+            Some((offset, length)) => {
+                let mut string_buf = Vec::with_capacity(*length);
+
+                if reader.seek(SeekFrom::Start(*offset as u64)).is_err() {
+                    error!("Tried to seek to invalid file offset.");
+                    return None;
+                }
+
+                string_buf.resize(*length, 0x0);
+                match reader.read_exact(&mut string_buf) {
+                    Ok(_) => match String::from_utf8(string_buf) {
+                        Ok(content) => Some(content),
+                        Err(_) => {
+                            error!("Tune {} in the tunecache isn't valid UTF-8.", tune_id);
+                            None
+                        }
+                    },
+                    Err(_) => None,
+                }
+            }
+
+            // Or none.
+            _ => None,
+        }
+    }
+
+    // Iterate over the latest version of each tune in this cache, in the order they were written.
+    fn iter(&self) -> TuneIterator {
+        let latest = self
+            .offset_cache
+            .iter()
+            .map(|(tune_id, (offset, _))| (*tune_id, *offset))
+            .collect();
+        CacheScanner::new(self.cache_path.clone(), self.max_id).iter_latest_tunes(latest)
+    }
+
+    fn max_id(&self) -> u32 {
+        self.offset_cache.keys().cloned().max().unwrap_or(0)
+    }
+
+    fn put(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        self.append(tune_id, content)
+    }
+
+    fn tune_ids(&self) -> Vec<u32> {
+        let mut tune_ids: Vec<u32> = self.offset_cache.keys().cloned().collect();
+        tune_ids.sort();
+        tune_ids
+    }
+
+    fn reader(&self) -> Result<Box<dyn TuneStore>, String> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn version(&self) -> usize {
+        self.num_appended
+    }
+
+    fn get_hash(&self, tune_id: u32) -> Option<u64> {
+        self.hashes.get(&tune_id).cloned()
+    }
+
+    fn get_canonical_hash(&self, tune_id: u32) -> Option<u64> {
+        self.records.get(&tune_id).map(|x| x.canonical_hash)
+    }
+
+    fn generation(&self) -> u64 {
+        generation_hash(&self.hashes)
+    }
+
+    // When the cache file was last written, as seconds since the epoch.
+    fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+
+    // Tunes from a cache file written before there were records have the file's time.
+    fn get_modified(&self, tune_id: u32) -> Option<u64> {
+        match self.records.get(&tune_id) {
+            Some(record) => record.modified.or(self.modified),
+            None => None,
+        }
+    }
+
+    fn num_tunes(&self) -> usize {
+        self.offset_cache.len()
+    }
+}
+
+// Read-only cache of ABC tunes like ReadOnlyCache, but with the cache file mapped into memory.
+// Tunes are read without a seek or a lock, and `get_str` borrows them without a copy.
+// Copies share the mapping, so server threads don't contend for a file handle.
+// Each tune is checked to be UTF-8 once, when the file is loaded, and any that aren't are left out.
+#[derive(Clone)]
+pub struct MappedCache {
+    cache_path: PathBuf,
+
+    // The cache file, shared between copies.
+    // None if there's no cache file yet, or it's empty, in which case there are no tunes.
+    map: Option<Arc<Mmap>>,
+
+    // Which file is mapped, so we can tell if it's been replaced, see `file_identity`.
+    identity: Option<(u64, u64)>,
+
+    // Map of Tune ID to start offset, length in the map.
+    offset_cache: Arc<HashMap<u32, (usize, usize)>>,
+
+    // Map of Tune ID to hash of its content.
+    hashes: Arc<HashMap<u32, u64>>,
+
+    // Map of Tune ID to its record.
+    records: Arc<HashMap<u32, TuneRecord>>,
+
+    // When the cache file was last written, as seconds since the epoch.
+    modified: Option<u64>,
+
+    // Tunes appended since the cache file was loaded.
+    num_appended: usize,
+
+    // Ignore tunes over this id, for debugging / profiling.
+    max_id: Option<u32>,
+}
+
+// Device and inode of a file, which stay the same while it's appended to, but not when it's
+// replaced by another file with the same name.
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+// Elsewhere the best we have is when the file was created.
+#[cfg(not(unix))]
+fn file_identity(metadata: &fs::Metadata) -> (u64, u64) {
+    let created = metadata
+        .created()
+        .ok()
+        .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+    (created, 0)
+}
+
+// A mapped cache file and its identity.
+type MappedFile = (Arc<Mmap>, (u64, u64));
+
+// Map a cache file into memory. None if it's missing or empty.
+fn map_cache_file(cache_path: &Path) -> Result<Option<MappedFile>, String> {
+    let file = match File::open(cache_path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Failed to open tunecache: {:?}", err)),
+    };
+
+    let metadata = file
+        .metadata()
+        .map_err(|err| format!("Failed to read tunecache metadata: {:?}", err))?;
+    if metadata.len() == 0 {
+        return Ok(None);
+    }
+
+    // Safe as long as the file isn't truncated or written over while it's mapped. The tunecache is
+    // only appended to, or replaced with a new file, see `ReadWriteCache::flush`.
+    let map =
+        unsafe { Mmap::map(&file) }.map_err(|err| format!("Failed to map tunecache: {:?}", err))?;
+    Ok(Some((Arc::new(map), file_identity(&metadata))))
+}
+
+impl MappedCache {
+    // A missing cache file is treated as empty, as it won't exist until the first scan.
+    pub fn new(cache_path: PathBuf, max_id: Option<u32>) -> Result<MappedCache, String> {
+        let mut cache = MappedCache {
+            modified: modified_time(&cache_path),
+            cache_path,
+            map: None,
+            identity: None,
+            offset_cache: Arc::new(HashMap::new()),
+            hashes: Arc::new(HashMap::new()),
+            records: Arc::new(HashMap::new()),
+            num_appended: 0,
+            max_id,
+        };
+        cache.remap()?;
+        if cache.map.is_none() {
+            warn!(
+                "No tunecache at {}, starting empty.",
+                cache.cache_path.display()
+            );
+        }
+        cache.load_cache();
+
+        Ok(cache)
+    }
+
+    // Map the cache file again, e.g. after appending to it.
+    fn remap(&mut self) -> Result<(), String> {
+        let mapped = map_cache_file(&self.cache_path)?;
+        self.identity = mapped.as_ref().map(|mapped| mapped.1);
+        self.map = mapped.map(|mapped| mapped.0);
+        Ok(())
+    }
+
+    // Whether the cache file isn't the one that's mapped any more, because a scan has replaced it
+    // or something else has appended to it.
+    fn is_stale(&self) -> bool {
+        let mapped_length = self.map.as_ref().map_or(0, |map| map.len() as u64);
+        match fs::metadata(&self.cache_path) {
+            Ok(metadata) => {
+                metadata.len() != mapped_length
+                    || (mapped_length > 0 && Some(file_identity(&metadata)) != self.identity)
+            }
+            Err(_) => mapped_length > 0,
+        }
+    }
+
+    // Find the tunes in the mapped file, checking each is UTF-8.
+    // Later tunes with the same ID replace earlier ones, as in ReadOnlyCache, as do their records.
+    fn load_cache(&mut self) {
+        let mut offset_cache = HashMap::new();
+        let mut records = HashMap::new();
+
+        if let Some(ref map) = self.map {
+            let mut offset = 0;
+            while offset + 8 <= map.len() {
+                let (tune_id, length) = parse_entry_header(&map[offset..offset + 8]);
+                let start = offset + 8;
+                let end = start + length;
+                if end > map.len() {
+                    error!("Tune {} runs past the end of the tunecache.", tune_id);
+                    break;
+                }
+                offset = end;
+
+                if tune_id == TUNE_RECORDS_ENTRY {
+                    match str::from_utf8(&map[start..end]) {
+                        Ok(content) => parse_records(content, &mut records),
+                        Err(_) => error!("Can't read the tune records in the tunecache."),
+                    }
+                    continue;
+                }
+
+                if tune_id == TUNE_IDS_ENTRY || self.max_id.is_some_and(|max_id| tune_id > max_id) {
+                    continue;
+                }
+
+                let bytes = &map[start..end];
+                if str::from_utf8(bytes).is_err() {
+                    warn!("Tune {} in the tunecache isn't valid UTF-8.", tune_id);
+                    continue;
+                }
+
+                offset_cache.insert(tune_id, (start, length));
+            }
+        }
+
+        // Tunes without a record, from a cache file written before there were records, get one
+        // now.
+        records.retain(|tune_id, _| offset_cache.contains_key(tune_id));
+        if records.len() < offset_cache.len() {
+            for tune_id in offset_cache.keys() {
+                if !records.contains_key(tune_id) {
+                    if let Some(content) = self.get_str_at(&offset_cache, *tune_id) {
+                        records.insert(*tune_id, TuneRecord::new(content, None));
+                    }
+                }
+            }
+        }
+
+        let hashes = records
+            .iter()
+            .map(|(tune_id, record)| (*tune_id, record.content_hash))
+            .collect();
+        self.offset_cache = Arc::new(offset_cache);
+        self.hashes = Arc::new(hashes);
+        self.records = Arc::new(records);
+    }
+
+    // ABC of a tune, borrowed from the mapped file.
+    // The offsets always go with the mapping they were found in, but check anyway, as it's cheap
+    // next to what's done with the tune.
+    pub fn get_str(&self, tune_id: u32) -> Option<&str> {
+        self.get_str_at(&self.offset_cache, tune_id)
+    }
+
+    // ABC of a tune at its offset in the mapped file.
+    fn get_str_at(
+        &self,
+        offset_cache: &HashMap<u32, (usize, usize)>,
+        tune_id: u32,
+    ) -> Option<&str> {
+        let map = self.map.as_ref()?;
+        let (offset, length) = *offset_cache.get(&tune_id)?;
+        let bytes = map.get(offset..offset + length)?;
+        str::from_utf8(bytes).ok()
+    }
+
+    pub fn num_appended(&self) -> usize {
+        self.num_appended
+    }
+
+    // Add a tune to the end of the cache file, and map it again to include it.
+    // Other copies keep the old mapping, and don't see it until they're cloned again.
+    // If the file has changed underneath us, all of it is loaded again, new tune and all.
+    pub fn append(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        let stale = self.is_stale();
+        if stale {
+            warn!(
+                "Tunecache {} has changed since it was loaded, loading it again.",
+                self.cache_path.display()
+            );
+        }
+
+        let (offset, record, end) = append_tune(&self.cache_path, tune_id, content)?;
+        self.remap()?;
+
+        let mapped_length = self.map.as_ref().map_or(0, |map| map.len());
+        if stale || end != mapped_length {
+            self.load_cache();
+        } else {
+            Arc::make_mut(&mut self.offset_cache).insert(tune_id, (offset, content.len()));
+            Arc::make_mut(&mut self.hashes).insert(tune_id, record.content_hash);
+            Arc::make_mut(&mut self.records).insert(tune_id, record);
+        }
+        self.num_appended += 1;
+        self.modified = now();
+
+        Ok(())
+    }
+}
+
+impl TuneStore for MappedCache {
+    fn get(&self, tune_id: u32) -> Option<String> {
+        self.get_str(tune_id).map(|content| content.to_string())
+    }
+
+    // Iterate over the latest version of each tune in this cache, in the order they were written.
+    fn iter(&self) -> TuneIterator {
+        let latest = self
+            .offset_cache
+            .iter()
+            .map(|(tune_id, (offset, _))| (*tune_id, *offset))
+            .collect();
+        CacheScanner::new(self.cache_path.clone(), self.max_id).iter_latest_tunes(latest)
+    }
+
+    fn max_id(&self) -> u32 {
+        self.offset_cache.keys().cloned().max().unwrap_or(0)
+    }
+
+    fn put(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        self.append(tune_id, content)
+    }
+
+    fn tune_ids(&self) -> Vec<u32> {
+        let mut tune_ids: Vec<u32> = self.offset_cache.keys().cloned().collect();
+        tune_ids.sort();
+        tune_ids
+    }
+
+    // Copies share the mapping and the offsets, so this is cheap.
+    fn reader(&self) -> Result<Box<dyn TuneStore>, String> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn version(&self) -> usize {
+        self.num_appended
+    }
+
+    fn get_hash(&self, tune_id: u32) -> Option<u64> {
+        self.hashes.get(&tune_id).cloned()
+    }
+
+    fn get_canonical_hash(&self, tune_id: u32) -> Option<u64> {
+        self.records.get(&tune_id).map(|x| x.canonical_hash)
+    }
+
+    fn generation(&self) -> u64 {
+        generation_hash(&self.hashes)
+    }
+
+    fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+
+    // As in ReadOnlyCache.
+    fn get_modified(&self, tune_id: u32) -> Option<u64> {
+        match self.records.get(&tune_id) {
+            Some(record) => record.modified.or(self.modified),
+            None => None,
+        }
+    }
+
+    fn num_tunes(&self) -> usize {
+        self.offset_cache.len()
+    }
+}
+
+// Read-write cache of ABC tunes, indexed by u32 tune ID, returning a string.
+// Stores all tunes in a big hash table.
+pub struct ReadWriteCache {
+    // Path of the tune cache file.
+    cache_path: PathBuf,
+
+    // Map of Tune ID to ABC String.
+    // This doesn't need to be populated necessarily,
+    // but all lookups will look here first.
+    string_cache: HashMap<u32, String>,
+
+    // Map of Tune ID to its record. Tunes that don't have one yet get one when the cache is
+    // flushed.
+    records: HashMap<u32, TuneRecord>,
+
+    // IDs of the tunes in files of several.
+    tune_ids: TuneIds,
+}
+
+impl ReadWriteCache {
+    // Load the cache file from disk.
+    pub fn load_cache(&mut self) {
+        // Reset everything.
+        self.string_cache = HashMap::new();
+        self.tune_ids = read_tune_ids(&self.cache_path);
+        self.records = read_tune_records(&self.cache_path);
+
+        let scanner = CacheScanner::new(self.cache_path.clone(), None);
+
+        for entry in scanner.iter_tunes() {
+            self.string_cache.insert(entry.tune_id, entry.content);
+        }
+        let string_cache = &self.string_cache;
+        self.records
+            .retain(|tune_id, _| string_cache.contains_key(tune_id));
+    }
+
+    // Construct a new ReadWriteCache, loaded from the cache file.
+    // As with the ReadOnlyCache, a missing cache file is treated as empty.
+    pub fn new(cache_path: PathBuf) -> Result<ReadWriteCache, String> {
+        match File::open(&cache_path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                warn!(
+                    "No tunecache file at {}, starting empty.",
+                    cache_path.display()
+                );
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Can't open tunecache {}: {}",
+                    cache_path.display(),
+                    err
+                ))
+            }
+        }
+
+        let mut cache = ReadWriteCache {
+            cache_path,
+            string_cache: HashMap::new(),
+            records: HashMap::new(),
+            tune_ids: TuneIds::new(),
+        };
+        cache.load_cache();
+        Ok(cache)
+    }
+
+    // Flush the string cache, in order of tune ID, after the IDs of tunes in files of several, and
+    // followed by the tunes' records.
+    // It's written to a new file which then replaces the old one, so a server with the old one
+    // open or mapped carries on reading it undisturbed.
+    pub fn flush(&mut self) -> Result<(), String> {
+        info!("Saving {} tunes", self.string_cache.len());
+        let records = records_to_string(&self.sorted_records());
+        let write_error = |err: std::io::Error| {
+            format!(
+                "Can't write tunecache {}: {}",
+                self.cache_path.display(),
+                err
+            )
+        };
+
+        let partial_path = self.cache_path.with_extension("partial");
+        let f = File::create(&partial_path).map_err(write_error)?;
+        let mut writer = BufWriter::new(f);
+
+        if !self.tune_ids.is_empty() {
+            let tune_ids = self.tune_ids.to_string();
+            writer
+                .write_all(&entry_header(TUNE_IDS_ENTRY, tune_ids.len()))
+                .and_then(|_| writer.write_all(tune_ids.as_bytes()))
+                .map_err(write_error)?;
+        }
+
+        let mut tune_ids = self.string_cache.keys().cloned().collect::<Vec<u32>>();
+        tune_ids.sort();
+
+        for tune_id in tune_ids {
+            let string_buf = self.string_cache[&tune_id].as_bytes();
+
+            writer
+                .write_all(&entry_header(tune_id, string_buf.len()))
+                .map_err(write_error)?;
+
+            writer.write_all(string_buf).map_err(write_error)?;
+        }
+
+        writer
+            .write_all(&entry_header(TUNE_RECORDS_ENTRY, records.len()))
+            .and_then(|_| writer.write_all(records.as_bytes()))
+            .map_err(write_error)?;
+
+        writer.flush().map_err(write_error)?;
+        drop(writer);
+        fs::rename(&partial_path, &self.cache_path).map_err(write_error)
+    }
+
+    // Recursively scan a directory of ABC files into String cache.
+    // Files that can't be read, e.g. because they aren't UTF-8, are skipped with a warning.
+    // Key notes in the dialect are stored with the standard spelling.
+    pub fn scan_dir(&mut self, base: &str, dialect: abc_lexer::Dialect) -> Result<(), String> {
+        let mut num_scanned = 0;
+        let mut num_indexed = 0;
+
+        let files = abc_files(base)?;
+
+        // New tunes in files of several get IDs after all the files' and tunes' IDs.
+        for (_, file_id) in files.iter() {
+            self.tune_ids.reserve(*file_id);
+        }
+        for tune_id in self.string_cache.keys() {
+            self.tune_ids.reserve(*tune_id);
+        }
+
+        // The file keeps its ID, so the tune that had it isn't kept under it.
+        for tune_id in release_file_ids(&mut self.tune_ids, &files) {
+            self.string_cache.remove(&tune_id);
+            self.records.remove(&tune_id);
+        }
+
+        // Iterate and load into cache.
+        for (filepath, file_id) in files {
+            // Check our index, only read the file if we haven't got it yet.
+            // The offset cache serves as the canonical index of tune IDs.
+            // A file of several tunes doesn't have its own ID, so is always read.
+            if !self.string_cache.contains_key(&file_id) {
+                match fs::read_to_string(&filepath) {
+                    Ok(content) => {
+                        let modified = modified_time(&filepath);
+                        for (tune_id, tune) in tunes_in_file(file_id, &content, &mut self.tune_ids)
+                        {
+                            if !self.string_cache.contains_key(&tune_id) {
+                                let tune = abc_lexer::to_standard(&tune, dialect);
+                                self.records.insert(tune_id, TuneRecord::new(&tune, modified));
+                                self.string_cache.insert(tune_id, tune);
+                                num_indexed += 1;
+                            }
+                        }
+                    }
+                    Err(err) => warn!("Can't read {}: {}", filepath.display(), err),
+                }
+            }
+
+            num_scanned += 1;
+
+            if num_scanned % 10000 == 0 {
+                info!("Scanned {} tunes, indexed {}", num_scanned, num_indexed);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Records of all the tunes in order of tune ID, making them for any that don't have one.
+    fn sorted_records(&mut self) -> Vec<(u32, TuneRecord)> {
+        let records = &mut self.records;
+        let mut result: Vec<(u32, TuneRecord)> = self
+            .string_cache
+            .iter()
+            .map(|(tune_id, content)| {
+                let record = records
+                    .entry(*tune_id)
+                    .or_insert_with(|| TuneRecord::new(content, None));
+                (*tune_id, *record)
+            })
+            .collect();
+        result.sort_by_key(|(tune_id, _)| *tune_id);
+        result
+    }
+
+    // Tune IDs to canonical hashes, see `canonical_hash`.
+    pub fn canonical_hashes(&mut self) -> HashMap<u32, u64> {
+        self.sorted_records()
+            .into_iter()
+            .map(|(tune_id, record)| (tune_id, record.canonical_hash))
+            .collect()
+    }
+
+    pub fn max_id(&self) -> u32 {
+        let mut max = 0;
+
+        for tune_id in self.string_cache.keys() {
+            max = u32::max(max, *tune_id);
+        }
+
+        max
+    }
+
+    pub fn get(&self, tune_id: u32) -> Option<String> {
+        // Return from string cache if it's there.
+        self.string_cache.get(&tune_id).map(|val| val.to_string())
+    }
+}
+
+// Store of tunes in the ABC files themselves, as found in the base directory.
+// Files are read when tunes are asked for, so edits show up without a scan, and new tunes are
+// written to files of their own. The tunecache only holds the IDs of tunes in files of several,
+// but every file is read when the store is opened to find the tunes in it, which takes a while for
+// a big collection. Key notes in the dialect are read with the standard spelling, as a scan stores
+// them.
+#[derive(Clone)]
+pub struct DirectoryStore {
+    base: PathBuf,
+
+    // How key notes are spelled in the files.
+    dialect: abc_lexer::Dialect,
+
+    // Map of tune ID to the file it's in, and its key among the file's tunes if there are others,
+    // see `keyed_tunes`.
+    files: HashMap<u32, (PathBuf, Option<String>)>,
+
+    // Map of tune ID to hash of its content.
+    hashes: HashMap<u32, u64>,
+
+    // Map of tune ID to its record.
+    records: HashMap<u32, TuneRecord>,
+
+    // When a file was last written, as seconds since the epoch.
+    modified: Option<u64>,
+
+    // Tunes put since the store was opened.
+    num_put: usize,
+
+    // IDs of the tunes in files of several, and whether any are new since they were last kept in
+    // the tunecache. They're only kept when a tune is put, so opening the store writes nothing.
+    tune_ids: TuneIds,
+    tune_ids_changed: bool,
+}
+
+impl DirectoryStore {
+    // Tunes over the max id are ignored, for debugging / profiling.
+    pub fn new(
+        base: &Path,
+        max_id: Option<u32>,
+        dialect: abc_lexer::Dialect,
+    ) -> Result<DirectoryStore, String> {
+        if !base.is_dir() {
+            return Err(format!("There's no directory at {}.", base.display()));
+        }
+
+        let mut store = DirectoryStore {
+            base: base.to_path_buf(),
+            dialect,
+            files: HashMap::new(),
+            hashes: HashMap::new(),
+            records: HashMap::new(),
+            modified: None,
+            num_put: 0,
+            tune_ids: TuneIds::default(),
+            tune_ids_changed: false,
+        };
+
+        // Tunes in files of several have the IDs they were given by a scan, or new ones, which are
+        // kept in the tunecache for next time.
+        let mut tune_ids = read_tune_ids(&base.join("tunecache"));
+        let known_tune_ids = tune_ids.clone();
+
+        let files = abc_files(base.to_str().ok_or("Can't read base directory path.")?)?;
+        for (_, file_id) in files.iter() {
+            tune_ids.reserve(*file_id);
+        }
+        release_file_ids(&mut tune_ids, &files);
+
+        for (filepath, file_id) in files {
+            let content = match fs::read_to_string(&filepath) {
+                Ok(content) => content,
+                Err(err) => {
+                    warn!("Can't read {}: {:?}", filepath.display(), err);
+                    continue;
+                }
+            };
+
+            for (key, tune) in keyed_tunes(&content) {
+                let tune_id = match key {
+                    None => file_id,
+                    Some(ref key) => match tune_ids.get_or_assign(file_id, key) {
+                        Some(tune_id) => tune_id,
+                        None => {
+                            warn!("No more tune IDs for tune {} in file {}.", key, file_id);
+                            continue;
+                        }
+                    },
+                };
+
+                // As with a scan, the first file found with the ID wins.
+                if !store.files.contains_key(&tune_id)
+                    && max_id.is_none_or(|max_id| tune_id <= max_id)
+                {
+                    let tune = abc_lexer::to_standard(tune, dialect);
+                    store.hashes.insert(tune_id, content_hash(tune.as_bytes()));
+                    store
+                        .records
+                        .insert(tune_id, TuneRecord::new(&tune, modified_time(&filepath)));
+                    store.files.insert(tune_id, (filepath.clone(), key));
+                }
+            }
+
+            store.modified = store.modified.max(modified_time(&filepath));
+        }
+
+        store.tune_ids_changed = !tune_ids.is_empty() && tune_ids != known_tune_ids;
+        store.tune_ids = tune_ids;
+
+        Ok(store)
+    }
+
+    // Keep the IDs of tunes in files of several in the tunecache, if any are new.
+    fn save_tune_ids(&mut self) -> Result<(), String> {
+        if self.tune_ids_changed {
+            append_entry(
+                &self.base.join("tunecache"),
+                TUNE_IDS_ENTRY,
+                &self.tune_ids.to_string(),
+            )?;
+            self.tune_ids_changed = false;
+        }
+        Ok(())
+    }
+}
+
+// Read a tune from its file, picking it out by its key if the file has others, with key notes in
+// the dialect given the standard spelling.
+fn read_tune_file(
+    filepath: &Path,
+    key: Option<&str>,
+    dialect: abc_lexer::Dialect,
+) -> Option<String> {
+    let content = fs::read_to_string(filepath).ok()?;
+    match key {
+        None => Some(abc_lexer::to_standard(&content, dialect)),
+        Some(key) => keyed_tunes(&content)
+            .into_iter()
+            .find(|(x, _)| x.as_deref() == Some(key))
+            .map(|(_, tune)| abc_lexer::to_standard(tune, dialect)),
+    }
+}
+
+// Tunes from a directory store's files, in the order given. A file of several tunes is read and
+// split once, and its other tunes are kept until they come up.
+struct DirectoryTunes {
+    files: vec::IntoIter<(u32, PathBuf, Option<String>)>,
+
+    // How key notes are spelled in the files.
+    dialect: abc_lexer::Dialect,
+
+    // Map of file to the tunes in it that haven't come up yet, by key.
+    pending: HashMap<PathBuf, HashMap<String, String>>,
+}
+
+impl Iterator for DirectoryTunes {
+    type Item = (u32, String);
+
+    fn next(&mut self) -> Option<(u32, String)> {
+        let dialect = self.dialect;
+        for (tune_id, filepath, key) in self.files.by_ref() {
+            let key = match key {
+                None => match fs::read_to_string(&filepath) {
+                    Ok(content) => {
+                        return Some((tune_id, abc_lexer::to_standard(&content, dialect)))
+                    }
+                    Err(_) => continue,
+                },
+                Some(key) => key,
+            };
+
+            if !self.pending.contains_key(&filepath) {
+                let tunes = match fs::read_to_string(&filepath) {
+                    Ok(content) => keyed_tunes(&content)
+                        .into_iter()
+                        .filter_map(|(key, tune)| {
+                            key.map(|key| (key, abc_lexer::to_standard(tune, dialect)))
+                        })
+                        .collect(),
+                    Err(_) => HashMap::new(),
+                };
+                self.pending.insert(filepath.clone(), tunes);
+            }
+
+            let tunes = self.pending.get_mut(&filepath).unwrap();
+            let tune = tunes.remove(&key);
+            if tunes.is_empty() {
+                self.pending.remove(&filepath);
+            }
+
+            if let Some(tune) = tune {
+                return Some((tune_id, tune));
+            }
+        }
+        None
+    }
+}
+
+impl TuneStore for DirectoryStore {
+    fn get(&self, tune_id: u32) -> Option<String> {
+        let (filepath, key) = self.files.get(&tune_id)?;
+        read_tune_file(filepath, key.as_deref(), self.dialect)
+    }
+
+    // Iterate over the tunes in ID order.
+    fn iter(&self) -> TuneIterator {
+        let mut files: Vec<(u32, PathBuf, Option<String>)> = self
+            .files
+            .iter()
+            .map(|(tune_id, (filepath, key))| (*tune_id, filepath.clone(), key.clone()))
+            .collect();
+        files.sort();
+
+        TuneIterator::new(
+            Box::new(DirectoryTunes {
+                files: files.into_iter(),
+                dialect: self.dialect,
+                pending: HashMap::new(),
+            }),
+            None,
+        )
+    }
+
+    fn max_id(&self) -> u32 {
+        self.files.keys().cloned().max().unwrap_or(0)
+    }
+
+    // A tune that has a file of its own is written over. A tune in a file of several can't be
+    // replaced without rewriting the others, so isn't allowed.
+    fn put(&mut self, tune_id: u32, content: &str) -> Result<(), String> {
+        let filepath = match self.files.get(&tune_id) {
+            Some((filepath, Some(_))) => {
+                return Err(format!(
+                    "Tune {} is one of several in {}, so can't be replaced on its own.",
+                    tune_id,
+                    filepath.display()
+                ))
+            }
+            Some((filepath, None)) => filepath.clone(),
+            None => self.base.join(format!("{}.abc", tune_id)),
+        };
+
+        self.save_tune_ids()?;
+        fs::write(&filepath, content)
+            .map_err(|err| format!("Failed to write {}: {:?}", filepath.display(), err))?;
+
+        self.files.insert(tune_id, (filepath, None));
+        self.hashes
+            .insert(tune_id, content_hash(content.as_bytes()));
+        self.records.insert(tune_id, TuneRecord::new(content, now()));
+        self.num_put += 1;
+        self.modified = now();
+
+        Ok(())
+    }
+
+    fn tune_ids(&self) -> Vec<u32> {
+        let mut tune_ids: Vec<u32> = self.files.keys().cloned().collect();
+        tune_ids.sort();
+        tune_ids
+    }
+
+    fn reader(&self) -> Result<Box<dyn TuneStore>, String> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn version(&self) -> usize {
+        self.num_put
+    }
+
+    fn get_hash(&self, tune_id: u32) -> Option<u64> {
+        self.hashes.get(&tune_id).cloned()
+    }
+
+    fn get_canonical_hash(&self, tune_id: u32) -> Option<u64> {
+        self.records.get(&tune_id).map(|x| x.canonical_hash)
+    }
+
+    fn generation(&self) -> u64 {
+        generation_hash(&self.hashes)
+    }
+
+    fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+
+    // When the tune's file was last written.
+    fn get_modified(&self, tune_id: u32) -> Option<u64> {
+        self.records.get(&tune_id).and_then(|record| record.modified)
+    }
+
+    fn num_tunes(&self) -> usize {
+        self.files.len()
+    }
+}
+
+// Cloning involves opening a new file handle.
+impl Clone for ReadOnlyCache {
+    fn clone(&self) -> ReadOnlyCache {
+        ReadOnlyCache {
+            readers: open_readers(&self.cache_path),
+            cache_path: self.cache_path.clone(),
+            offset_cache: self.offset_cache.clone(),
+            hashes: self.hashes.clone(),
+            records: self.records.clone(),
+            modified: self.modified,
+            num_appended: self.num_appended,
+            max_id: self.max_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn content_hash_test() {
+        // Published FNV-1a test vectors.
+        assert_eq!(content_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(content_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(content_hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn canonical_hash_test() {
+        let tune = "X:1\nT:The Kesh\nK:G\nGABd edBd|\n";
+        assert_eq!(
+            canonical_hash(tune),
+            canonical_hash("X:12\r\nT: The Kesh\r\n\r\nK:G\r\nGAB d edB d |\r\n"),
+            "Whitespace and the reference number don't matter."
+        );
+        assert_eq!(
+            canonical_hash(tune),
+            canonical_hash("X:1\nT:The Kesh\nK:G\nGABd\nedBd|\n")
+        );
+        assert_ne!(
+            canonical_hash(tune),
+            canonical_hash("X:1\nT:The Kesh\nK:D\nGABd edBd|\n")
+        );
+    }
+
+    #[test]
+    fn duplicate_groups_test() {
+        let hashes: HashMap<u32, u64> = [(5, 1), (2, 1), (3, 2), (4, 3), (1, 3), (6, 1)]
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(duplicate_groups(&hashes), vec![vec![1, 4], vec![2, 5, 6]]);
+    }
+
+    #[test]
+    fn parse_meta_test() {
+        let meta = parse_meta(
+            "id,source,notes,licence\n\
+             1,https://www.thesession.org/tunes/1,,\"CC BY-SA 4.0\"\n\
+             \n\
+             2,\"O'Neill's \"\"1001\"\", 1907\",copied,\n\
+             3,,,Public domain\n",
+        ).unwrap();
+
+        assert_eq!(meta.len(), 3);
+        assert_eq!(
+            meta[&1],
+            TuneMeta {
+                source: Some("https://www.thesession.org/tunes/1".to_string()),
+                licence: Some("CC BY-SA 4.0".to_string()),
+            }
+        );
+        assert_eq!(
+            meta[&2].source,
+            Some("O'Neill's \"1001\", 1907".to_string()),
+            "Quoted fields can have commas and quotes."
+        );
+        assert_eq!(meta[&2].licence, None, "Empty values are missing.");
+        assert_eq!(meta[&3].source, None);
+
+        assert_eq!(meta[&1].source_name(), Some("thesession.org".to_string()));
+        assert_eq!(meta[&2].source_name(), meta[&2].source);
+        assert_eq!(meta[&3].source_name(), None);
+
+        assert_eq!(parse_meta("").unwrap(), HashMap::new());
+        assert!(
+            parse_meta("source,licence\n").is_err(),
+            "ID column is required."
+        );
+        assert!(parse_meta("id,source\nx,y\n").is_err());
+        assert!(parse_meta("id,source\n1,\"y\n").is_err());
+    }
+
+    #[test]
+    fn iter_tunes_test() {
+        let scanner = CacheScanner::new(PathBuf::from("test_resources/tunecache"), None);
+
+        let mut tunes: Vec<TuneEntry> = scanner.iter_tunes().collect();
+        assert_eq!(tunes.len(), 1);
+        assert_eq!(tunes[0].tune_id, 1);
+        assert!(tunes[0].ast.is_none(), "AST isn't parsed until it's asked for.");
+        assert_eq!(tunes[0].ast().voices.len(), 1);
+        assert!(tunes[0].ast.is_some(), "AST is kept once parsed.");
+
+        assert_eq!(scanner.iter_tunes().excluding(&[1]).count(), 0);
+        assert_eq!(
+            CacheScanner::new(PathBuf::from("test_resources/missing"), None)
+                .iter_tunes()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn read_only_cache_hash_test() {
+        let cache = ReadOnlyCache::new(PathBuf::from("test_resources/tunecache"), None).unwrap();
+        let content = cache.get(1).unwrap();
+
+        assert_eq!(cache.get_hash(1), Some(content_hash(content.as_bytes())));
+        assert_eq!(cache.get_hash(2), None);
+        assert!(cache.modified().is_some());
+    }
+
+    #[test]
+    fn read_only_cache_poisoned_test() {
+        let cache = ReadOnlyCache::new(PathBuf::from("test_resources/tunecache"), None).unwrap();
+        let tune_id = cache.tune_ids()[0];
+        let expected = cache.get(tune_id);
+        assert!(expected.is_some());
+
+        // A panic while reading doesn't stop later reads.
+        let shard = tune_id as usize % cache.readers.len();
+        let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _reader = cache.readers[shard].lock().unwrap();
+            panic!("Panicked while reading.");
+        }));
+        assert!(cache.readers[shard].is_poisoned());
+        assert_eq!(cache.get(tune_id), expected);
+    }
+
+    #[test]
+    fn generation_hash_test() {
+        let mut a = HashMap::new();
+        a.insert(1, 100);
+        a.insert(2, 200);
+
+        let mut b = HashMap::new();
+        b.insert(2, 200);
+        b.insert(1, 100);
+        assert_eq!(generation_hash(&a), generation_hash(&b), "Order doesn't matter.");
+
+        b.insert(2, 201);
+        assert_ne!(generation_hash(&a), generation_hash(&b), "Edited tune changes generation.");
+
+        b.insert(2, 200);
+        b.insert(3, 300);
+        assert_ne!(generation_hash(&a), generation_hash(&b), "New tune changes generation.");
+
+        let cache = ReadOnlyCache::new(PathBuf::from("test_resources/tunecache"), None).unwrap();
+        let missing = ReadOnlyCache::new(PathBuf::from("test_resources/missing"), None).unwrap();
+        assert_eq!(cache.generation(), generation_hash(&cache.hashes));
+        assert_ne!(cache.generation(), missing.generation());
+    }
+
+    #[test]
+    fn split_tunes_test() {
+        let content = "%abc-2.1\nX:1\nT:One\nK:G\nGAB|\n\nX:2\nT:Two\nK:D\nDEF|\n\nX: b\nK:A\n";
+        let tunes = split_tunes(content);
+
+        assert_eq!(
+            tunes,
+            vec![
+                (Some(1), 1, "%abc-2.1\nX:1\nT:One\nK:G\nGAB|\n\n"),
+                (Some(2), 7, "X:2\nT:Two\nK:D\nDEF|\n\n"),
+                (None, 12, "X: b\nK:A\n"),
+            ]
+        );
+
+        assert_eq!(split_tunes("K:G\nGAB|\n"), vec![(None, 1, "K:G\nGAB|\n")]);
+    }
+
+    #[test]
+    fn tune_reader_test() {
+        let content = "%abc-2.1\nX:1\nT:One\nK:G\nGAB|\n\nX:2\nT:Two\nK:D\nDEF|\n\nX: b\nK:A\n";
+        let read = |content: &str| {
+            TuneReader::new(content.as_bytes())
+                .map(|x| x.unwrap())
+                .collect::<Vec<(Option<u32>, usize, String)>>()
+        };
+
+        // The same as reading it all and splitting it.
+        for content in [content, "K:G\nGAB|\n", "", "X:1\r\nK:G\r\nX:2\r\nK:D"].iter() {
+            let expected = split_tunes(content)
+                .into_iter()
+                .map(|(x, line_number, tune)| (x, line_number, tune.to_string()))
+                .collect::<Vec<(Option<u32>, usize, String)>>();
+            assert_eq!(read(content), expected);
+        }
+
+        // Bad UTF-8 is an error.
+        let mut reader = TuneReader::new(&b"X:1\nT:\xff\n"[..]);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn tunes_in_file_test() {
+        let content = "X:1\nK:G\nGAB|\nX:5\nK:D\nDEF|\nX:5\nK:A\nABc|\nX:\nK:C\nCDE|\n";
+        let mut tune_ids = TuneIds::new();
+        tune_ids.reserve(12);
+        let ids = |content, tune_ids: &mut TuneIds| {
+            tunes_in_file(12, content, tune_ids)
+                .iter()
+                .map(|x| x.0)
+                .collect::<Vec<u32>>()
+        };
+
+        // IDs come after the file's, whatever the X: numbers are.
+        assert_eq!(ids(content, &mut tune_ids), vec![13, 14, 15, 16]);
+
+        // Tunes keep their IDs when the file is edited around them.
+        let edited = "X:5\nK:D\nDEF|\nX:2\nK:E\nEFG|\nX:1\nK:G\nGAB|\n";
+        assert_eq!(ids(edited, &mut tune_ids), vec![14, 17, 13]);
+
+        // One tune keeps the ID of the file.
+        assert_eq!(
+            tunes_in_file(12, "X:3\nK:G\nGAB|\n", &mut tune_ids),
+            vec![(12, "X:3\nK:G\nGAB|\n".to_string())]
+        );
+
+        assert_eq!(TuneIds::parse(&tune_ids.to_string()), tune_ids);
+        assert_eq!(tune_ids.get_or_assign(12, "2"), Some(17));
+        assert_eq!(tune_ids.get_or_assign(20, "1"), Some(18));
+
+        let mut full = TuneIds::new();
+        full.reserve(TUNE_IDS_ENTRY - 1);
+        assert!(ids(content, &mut full).is_empty(), "IDs can run out.");
+    }
+
+    #[test]
+    fn scan_tune_ids_test() {
+        let base = env::temp_dir().join(format!("scan-tune-ids-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("3.abc"), "X:1\nK:G\nGAB|\n").unwrap();
+        fs::write(base.join("5.abc"), "X:1\nK:D\nDEF|\nX:2\nK:A\nABc|\n").unwrap();
+        let path = base.join("tunecache");
+
+        let scan = || {
+            let mut cache = ReadWriteCache::new(path.clone()).unwrap();
+            cache
+                .scan_dir(base.to_str().unwrap(), abc_lexer::Dialect::Standard)
+                .unwrap();
+            cache.flush().unwrap();
+            ReadOnlyCache::new(path.clone(), None).unwrap().tune_ids()
+        };
+        assert_eq!(scan(), vec![3, 6, 7]);
+
+        // A new file and a new tune in the file of several don't change the IDs.
+        fs::write(base.join("9.abc"), "X:1\nK:E\nEFG|\n").unwrap();
+        fs::write(
+            base.join("5.abc"),
+            "X:3\nK:C\nCDE|\nX:1\nK:D\nDEF|\nX:2\nK:A\nABc|\n",
+        ).unwrap();
+        assert_eq!(scan(), vec![3, 6, 7, 9, 10]);
+        assert_eq!(
+            MappedCache::new(path.clone(), None).unwrap().tune_ids(),
+            vec![3, 6, 7, 9, 10]
+        );
+        assert_eq!(read_tune_ids(&path).get_or_assign(5, "3"), Some(10));
+
+        // A new file with the ID of a tune in the file of several takes it from the tune.
+        fs::write(base.join("7.abc"), "X:1\nK:Bb\nBcd|\n").unwrap();
+        assert_eq!(scan(), vec![3, 6, 7, 9, 10, 11]);
+        let cache = ReadOnlyCache::new(path.clone(), None).unwrap();
+        assert_eq!(cache.get(7), Some("X:1\nK:Bb\nBcd|\n".to_string()));
+        assert_eq!(cache.get(11), Some("X:2\nK:A\nABc|\n".to_string()));
+
+        fs::write(base.join("10.abc"), "X:1\nK:F\nFGA|\n").unwrap();
+        let store = DirectoryStore::new(&base, None, abc_lexer::Dialect::Standard).unwrap();
+        assert_eq!(store.get(10), Some("X:1\nK:F\nFGA|\n".to_string()));
+        assert_eq!(store.get(12), Some("X:3\nK:C\nCDE|\n".to_string()));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn append_test() {
+        let path = env::temp_dir().join(format!("tunecache-append-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut cache = ReadOnlyCache::new(path.clone(), None).unwrap();
+        let copy = cache.clone();
+
+        cache.append(7, "X:1\nK:G\nGAB|\n").unwrap();
+        cache.append(9, "X:1\nK:D\nDEF|\n").unwrap();
+        assert_eq!(cache.get(9), Some("X:1\nK:D\nDEF|\n".to_string()));
+        assert_eq!(cache.tune_ids(), vec![7, 9]);
+        assert_eq!(cache.num_appended(), 2);
+        assert_ne!(copy.num_appended(), cache.num_appended());
+
+        // Appending again replaces the tune when the file is next loaded.
+        cache.append(7, "X:1\nK:A\nABc|\n").unwrap();
+        let reloaded = ReadOnlyCache::new(path.clone(), None).unwrap();
+        assert_eq!(reloaded.get(7), cache.get(7));
+        assert_eq!(reloaded.num_tunes(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tune_records_test() {
+        let path = env::temp_dir().join(format!("tunecache-records-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut cache = ReadWriteCache::new(path.clone()).unwrap();
+        cache.string_cache.insert(3, "X:1\nK:G\nG A B|\n".to_string());
+        cache.flush().unwrap();
+        assert_eq!(
+            read_tune_records(&path).get(&3),
+            Some(&TuneRecord::new("X:1\nK:G\nG A B|\n", None))
+        );
+
+        // Stored records are read back rather than worked out again.
+        append_entry(&path, TUNE_RECORDS_ENTRY, "3 42 43 1000\n").unwrap();
+        let read_only = ReadOnlyCache::new(path.clone(), None).unwrap();
+        let mapped = MappedCache::new(path.clone(), None).unwrap();
+        for store in [&read_only as &dyn TuneStore, &mapped as &dyn TuneStore].iter() {
+            assert_eq!(store.get_canonical_hash(3), Some(42));
+            assert_eq!(store.get_hash(3), Some(43));
+            assert_eq!(store.get_modified(3), Some(1000));
+        }
+
+        // Appended tunes get records, and tunes without one have it worked out when loading.
+        let mut read_only = read_only;
+        read_only.append(5, "X:1\nK:D\nDEF|\n").unwrap();
+        append_entry(&path, 7, "X:1\nK:A\nABc|\n").unwrap();
+        let reloaded = MappedCache::new(path.clone(), None).unwrap();
+        assert_eq!(
+            reloaded.get_canonical_hash(5),
+            Some(canonical_hash("X:1\nK:D\nDEF|\n"))
+        );
+        assert_eq!(
+            reloaded.get_canonical_hash(7),
+            Some(canonical_hash("X:1\nK:A\nABc|\n"))
+        );
+        assert_eq!(reloaded.get_canonical_hash(9), None);
+        assert!(reloaded.get_modified(5) > Some(1000));
+        assert_eq!(
+            reloaded.get_modified(7),
+            reloaded.modified(),
+            "Tunes without a record have the file's time."
+        );
+        assert_eq!(reloaded.get_modified(3), Some(1000));
+        assert_eq!(
+            ReadOnlyCache::new(path.clone(), None).unwrap().generation(),
+            reloaded.generation()
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flush_order_test() {
+        let path = env::temp_dir().join(format!("tunecache-flush-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut cache = ReadWriteCache::new(path.clone()).unwrap();
+        for tune_id in [30, 2, 17, 1000, 5].iter() {
+            cache
+                .string_cache
+                .insert(*tune_id, format!("X:1\nT:Tune {}\nK:G\nGAB|\n", tune_id));
+        }
+        cache.flush().unwrap();
+        let first = fs::read(&path).unwrap();
+
+        let tune_ids = CacheScanner::new(path.clone(), None)
+            .iter()
+            .map(|entry| entry.tune_id)
+            .collect::<Vec<u32>>();
+        assert_eq!(
+            tune_ids,
+            vec![2, 5, 17, 30, 1000],
+            "Tunes are written in order of ID."
+        );
+
+        // Loading and writing again makes the same file.
+        let mut reloaded = ReadWriteCache::new(path.clone()).unwrap();
+        reloaded.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), first);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mapped_cache_test() {
+        let path = PathBuf::from("test_resources/tunecache");
+        let mapped = MappedCache::new(path.clone(), None).unwrap();
+        let read_only = ReadOnlyCache::new(path, None).unwrap();
+
+        assert_eq!(mapped.tune_ids(), read_only.tune_ids());
+        for tune_id in read_only.tune_ids() {
+            assert_eq!(mapped.get(tune_id), read_only.get(tune_id));
+            assert_eq!(mapped.get_hash(tune_id), read_only.get_hash(tune_id));
+        }
+        assert_eq!(mapped.generation(), read_only.generation());
+        assert_eq!(mapped.get_str(2), None);
+
+        let missing = MappedCache::new(PathBuf::from("test_resources/missing"), None).unwrap();
+        assert_eq!(missing.num_tunes(), 0);
+        assert_eq!(missing.get_str(1), None);
+    }
+
+    #[test]
+    fn mapped_cache_append_test() {
+        let path = env::temp_dir().join(format!("tunecache-mapped-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut cache = MappedCache::new(path.clone(), None).unwrap();
+        cache.append(7, "X:1\nK:G\nGAB|\n").unwrap();
+        let copy = cache.clone();
+        cache.append(9, "X:1\nK:D\nDEF|\n").unwrap();
+        cache.append(7, "X:1\nK:A\nABc|\n").unwrap();
+
+        assert_eq!(cache.get_str(7), Some("X:1\nK:A\nABc|\n"));
+        assert_eq!(cache.tune_ids(), vec![7, 9]);
+        assert_eq!(
+            copy.get_str(7),
+            Some("X:1\nK:G\nGAB|\n"),
+            "Copies keep their mapping."
+        );
+        assert_eq!(copy.get_str(9), None);
+        assert_ne!(copy.version(), cache.version());
+
+        // A tune that isn't UTF-8 is left out.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&entry_header(11, 2)).unwrap();
+        file.write_all(&[0xff, 0xfe]).unwrap();
+
+        let reloaded = MappedCache::new(path.clone(), None).unwrap();
+        assert_eq!(reloaded.get_str(7), cache.get_str(7));
+        assert_eq!(reloaded.tune_ids(), vec![7, 9]);
+
+        // Appending after something else has appended finds its tunes too.
+        let mut other = MappedCache::new(path.clone(), None).unwrap();
+        other.append(13, "X:1\nK:E\nEFG|\n").unwrap();
+        cache.append(15, "X:1\nK:F\nFGA|\n").unwrap();
+        assert_eq!(cache.tune_ids(), vec![7, 9, 13, 15]);
+
+        // A scan replaces the file with a new one, which is loaded before appending to it.
+        let replacement = path.with_extension("replacement");
+        append_entry(&replacement, 5, "X:1\nK:C\nCDE|\n").unwrap();
+        fs::rename(&replacement, &path).unwrap();
+        cache.append(9, "X:1\nK:Bb\nBcd|\n").unwrap();
+        assert_eq!(cache.tune_ids(), vec![5, 9]);
+        assert_eq!(cache.get_str(7), None);
+        assert_eq!(cache.get_str(9), Some("X:1\nK:Bb\nBcd|\n"));
+        assert_eq!(
+            copy.get_str(7),
+            Some("X:1\nK:G\nGAB|\n"),
+            "Copies still have the old file."
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_store_test() {
+        let base = env::temp_dir().join(format!("directory-store-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("a")).unwrap();
+        fs::write(base.join("a").join("3.abc"), "X:1\nK:G\nGAB|\n").unwrap();
+        fs::write(base.join("5.abc"), "X:1\nK:D\nDEF|\nX:2\nK:A\nABc|\n").unwrap();
+        fs::write(base.join("notes.abc"), "X:1\nK:C\nCDE|\n").unwrap();
+
+        let standard = abc_lexer::Dialect::Standard;
+        let mut store = DirectoryStore::new(&base, None, standard).unwrap();
+        assert!(!base.join("tunecache").exists(), "Opening the store writes nothing.");
+        assert_eq!(store.tune_ids(), vec![3, 6, 7]);
+        assert_eq!(store.max_id(), 7);
+        assert_eq!(store.get(7), Some("X:2\nK:A\nABc|\n".to_string()));
+        assert_eq!(
+            store
+                .iter()
+                .map(|x| (x.tune_id, x.content))
+                .collect::<Vec<(u32, String)>>(),
+            vec![
+                (3, "X:1\nK:G\nGAB|\n".to_string()),
+                (6, "X:1\nK:D\nDEF|\n".to_string()),
+                (7, "X:2\nK:A\nABc|\n".to_string()),
+            ]
+        );
+
+        // A tune with a file of its own can be replaced, and new ones get their own file.
+        let copy = store.reader().unwrap();
+        store.put(3, "X:1\nK:Em\nEFG|\n").unwrap();
+        store.put(8, "X:1\nK:Bm\nBcd|\n").unwrap();
+        assert!(store.put(6, "X:1\nK:F\nFGA|\n").is_err());
+        assert_ne!(copy.version(), store.version());
+        assert_ne!(copy.generation(), store.generation());
+
+        // Tunes in the file of several keep their IDs, which are kept in the tunecache.
+        let reopened = DirectoryStore::new(&base, None, standard).unwrap();
+        assert_eq!(reopened.tune_ids(), vec![3, 6, 7, 8]);
+        assert_eq!(reopened.get(3), Some("X:1\nK:Em\nEFG|\n".to_string()));
+        assert_eq!(reopened.get(8), store.get(8));
+        assert_eq!(reopened.generation(), store.generation());
+        assert_eq!(
+            read_tune_ids(&base.join("tunecache")).get_or_assign(5, "2"),
+            Some(7)
+        );
+
+        let limited = DirectoryStore::new(&base, Some(6), standard).unwrap();
+        assert_eq!(limited.tune_ids(), vec![3, 6]);
+        assert_eq!(limited.iter().count(), 2);
+
+        assert!(DirectoryStore::new(&base.join("missing"), None, standard).is_err());
+
+        // Key notes in the dialect are read with the standard spelling, as a scan stores them.
+        fs::write(base.join("9.abc"), "X:1\nK:Fis\nFGA|\n").unwrap();
+        let german = DirectoryStore::new(&base, None, abc_lexer::Dialect::German).unwrap();
+        assert_eq!(german.get(9), Some("X:1\nK:F#\nFGA|\n".to_string()));
+        assert_eq!(german.get(7), Some("X:2\nK:A\nABc|\n".to_string()));
+        assert!(german
+            .iter()
+            .any(|x| x.tune_id == 9 && x.content == "X:1\nK:F#\nFGA|\n"));
+        assert_eq!(
+            german.get_hash(9),
+            Some(content_hash("X:1\nK:F#\nFGA|\n".as_bytes()))
+        );
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn open_store_test() {
+        let mut config = config::Config::default();
+        assert!(
+            open_store(&config).is_err(),
+            "The base directory is needed."
+        );
+
+        config.base = Some(PathBuf::from("test_resources"));
+        let tunecache = open_store(&config).unwrap();
+        config.storage = "directory".to_string();
+        let directory = open_store(&config).unwrap();
+        assert_eq!(tunecache.tune_ids(), vec![1]);
+        assert_eq!(directory.tune_ids(), vec![1]);
+        assert_eq!(tunecache.max_id(), 1);
+
+        config.debug_max_id = Some(0);
+        assert_eq!(open_store(&config).unwrap().num_tunes(), 0);
+
+        config.storage = "floppy".to_string();
+        assert!(open_store(&config).is_err());
+    }
 }
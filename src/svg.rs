@@ -1,20 +1,144 @@
 //! SVG building tools
 
+use raster;
 use std::fmt::Write;
 
+// Width of every stroke.
+const STROKE_WIDTH: f32 = 2.0;
+
+// Size of text drawn by text(), which is the SVG default.
+const DEFAULT_FONT_SIZE: f32 = 16.0;
+
+const DEFAULT_FONT_FAMILY: &str = "sans-serif";
+
+// Pixel size of the raster font that roughly matches the default SVG font size.
+const RASTER_FONT_SIZE: f32 = 1.6;
+
+/// Which part of the text is at its x position.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+impl Anchor {
+    fn name(self) -> &'static str {
+        match self {
+            Anchor::Start => "start",
+            Anchor::Middle => "middle",
+            Anchor::End => "end",
+        }
+    }
+}
+
+/// Fonts for the text in a drawing.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Fonts {
+    /// Used for all text. CSS can override it.
+    pub family: &'static str,
+
+    /// The first title.
+    pub title: f32,
+
+    /// Any other titles.
+    pub subtitle: f32,
+
+    pub composer: f32,
+
+    /// Rhythm and tempo.
+    pub tempo: f32,
+}
+
+impl Fonts {
+    pub fn new() -> Fonts {
+        Fonts {
+            family: DEFAULT_FONT_FAMILY,
+            title: 24.0,
+            subtitle: 18.0,
+            composer: 14.0,
+            tempo: 14.0,
+        }
+    }
+}
+
+/// How to show some text.
+/// The class lets it be styled with CSS when the SVG is embedded in a page.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TextStyle {
+    pub size: f32,
+    pub anchor: Anchor,
+    pub class: Option<&'static str>,
+
+    /// Fill colour, if not the default black.
+    pub colour: Option<&'static str>,
+}
+
+impl TextStyle {
+    pub fn new() -> TextStyle {
+        TextStyle {
+            size: DEFAULT_FONT_SIZE,
+            anchor: Anchor::Start,
+            class: None,
+            colour: None,
+        }
+    }
+}
+
+/// Escape text for use in XML content or a quoted attribute.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for chr in text.chars() {
+        match chr {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '\'' => result.push_str("&apos;"),
+            '"' => result.push_str("&quot;"),
+            // Control characters aren't allowed in XML at all.
+            '\t' | '\n' | '\r' => result.push(chr),
+            chr if chr.is_control() => (),
+            chr => result.push(chr),
+        }
+    }
+    result
+}
+
+/// Approximate width of text at the given font size.
+/// This is exact for rasterised text, and near enough for SVG fonts.
+pub fn text_width(text: &str, size: f32) -> f32 {
+    raster::Bitmap::text_width(text, RASTER_FONT_SIZE * size / DEFAULT_FONT_SIZE)
+}
+
+/// Distance from the left of text to its x position.
+fn anchor_offset(text: &str, style: TextStyle) -> f32 {
+    match style.anchor {
+        Anchor::Start => 0.0,
+        Anchor::Middle => text_width(text, style.size) / 2.0,
+        Anchor::End => text_width(text, style.size),
+    }
+}
+
 enum Entity {
     Rect(f32, f32, f32, f32),
     FillRect(f32, f32, f32, f32),
     DebugRect(f32, f32, f32, f32),
-    Text(f32, f32, String),
+    ErrorRect(f32, f32, f32, f32),
+    Text(f32, f32, String, TextStyle),
     LinePath(f32, f32, String),
     Line(f32, f32, f32, f32),
     Circle(f32, f32, f32, bool),
+    FillPolygon(Vec<(f32, f32)>),
 }
 
 pub struct Drawing {
     width: f32,
     height: f32,
+    font_family: &'static str,
+
+    // Size to show the drawing at, relative to its coordinates.
+    scale: f32,
+
     entities: Vec<Entity>,
 }
 
@@ -24,18 +148,43 @@ impl Drawing {
         Drawing {
             width: 0.0,
             height: 0.0,
+            font_family: DEFAULT_FONT_FAMILY,
+            scale: 1.0,
             entities: vec![],
         }
     }
 
+    pub fn set_font_family(&mut self, font_family: &'static str) {
+        self.font_family = font_family;
+    }
+
+    /// Show the drawing bigger or smaller, without changing the coordinates of what's in it.
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
     pub fn render(&self) -> String {
         let mut buf = String::new();
 
+        // A scaled drawing keeps its coordinates in the view box.
+        let view_box = if self.scale == 1.0 {
+            String::new()
+        } else {
+            format!(" viewBox='0 0 {} {}'", self.width, self.height)
+        };
+
         write!(
             &mut buf,
-            "<svg version='1.1' baseProfile='full' width='{}' height='{}' \
+            "<svg version='1.1' baseProfile='full' width='{}' height='{}'{} font-family='{}' \
              xmlns='http://www.w3.org/2000/svg'>",
-            self.width, self.height
+            self.width * self.scale,
+            self.height * self.scale,
+            view_box,
+            escape(self.font_family)
         ).unwrap();
 
         for entity in self.entities.iter() {
@@ -67,10 +216,37 @@ impl Drawing {
                     ).expect("Can't write");
                 }
 
-                &Entity::Text(x, y, ref text) => {
-                    // TOOD ESCAPE
-                    write!(&mut buf, "<text x='{}' y='{}' >{}</text>", x, y, text)
-                        .expect("Can't write");
+                &Entity::ErrorRect(x, y, w, h) => {
+                    write!(
+                        &mut buf,
+                        "<rect x='{}' y='{}' width='{}' height='{}' class='error' \
+                         style='fill:red;fill-opacity:0.15;stroke:red;stroke-width:2' />",
+                        x, y, w, h
+                    ).expect("Can't write");
+                }
+
+                &Entity::Text(x, y, ref text, style) => {
+                    let class = match style.class {
+                        Some(class) => format!(" class='{}'", escape(class)),
+                        None => String::new(),
+                    };
+
+                    let colour = match style.colour {
+                        Some(colour) => format!(" fill='{}'", escape(colour)),
+                        None => String::new(),
+                    };
+
+                    write!(
+                        &mut buf,
+                        "<text x='{}' y='{}' font-size='{}' text-anchor='{}'{}{} >{}</text>",
+                        x,
+                        y,
+                        style.size,
+                        style.anchor.name(),
+                        class,
+                        colour,
+                        escape(text)
+                    ).expect("Can't write");
                 }
 
                 &Entity::LinePath(x, y, ref path) => {
@@ -102,6 +278,16 @@ impl Drawing {
                         x, y, xx, yy
                     ).expect("Can't write");
                 }
+
+                Entity::FillPolygon(points) => {
+                    let points: Vec<String> =
+                        points.iter().map(|&(x, y)| format!("{},{}", x, y)).collect();
+                    write!(
+                        &mut buf,
+                        "<polygon points='{}' fill='black' />",
+                        points.join(" ")
+                    ).expect("Can't write");
+                }
             }
         }
 
@@ -110,6 +296,77 @@ impl Drawing {
         buf
     }
 
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    /// Draw onto a bitmap, with the top left at x, y and magnified by scale.
+    /// Debug rectangles are left out.
+    pub fn rasterise_into(&self, bitmap: &mut raster::Bitmap, x: f32, y: f32, scale: f32) {
+        let stroke = STROKE_WIDTH * scale;
+        let px = |value: f32| x + value * scale;
+        let py = |value: f32| y + value * scale;
+
+        for entity in self.entities.iter() {
+            match entity {
+                &Entity::Rect(xx, yy, w, h) => {
+                    bitmap.stroke_rect(px(xx), py(yy), w * scale, h * scale, stroke)
+                }
+
+                &Entity::FillRect(xx, yy, w, h) => bitmap.fill_rect(
+                    px(xx) - stroke / 2.0,
+                    py(yy) - stroke / 2.0,
+                    w * scale + stroke,
+                    h * scale + stroke,
+                ),
+
+                &Entity::DebugRect(_, _, _, _) => (),
+
+                // Without colour, an outline is the best a bitmap can do.
+                &Entity::ErrorRect(xx, yy, w, h) => {
+                    bitmap.stroke_rect(px(xx), py(yy), w * scale, h * scale, stroke)
+                }
+
+                &Entity::Text(xx, yy, ref text, style) => bitmap.text(
+                    px(xx - anchor_offset(text, style)),
+                    py(yy),
+                    text,
+                    RASTER_FONT_SIZE * style.size / DEFAULT_FONT_SIZE * scale,
+                ),
+
+                &Entity::LinePath(xx, yy, ref path) => {
+                    for (x1, y1, x2, y2) in path_segments(path).iter() {
+                        bitmap.line(
+                            px(xx + x1),
+                            py(yy + y1),
+                            px(xx + x2),
+                            py(yy + y2),
+                            stroke,
+                        );
+                    }
+                }
+
+                &Entity::Circle(xx, yy, radius, fill) => {
+                    bitmap.circle(px(xx), py(yy), radius * scale, fill, stroke)
+                }
+
+                &Entity::Line(xx, yy, xxx, yyy) => {
+                    bitmap.line(px(xx), py(yy), px(xxx), py(yyy), stroke)
+                }
+
+                Entity::FillPolygon(points) => {
+                    let points: Vec<(f32, f32)> =
+                        points.iter().map(|&(xx, yy)| (px(xx), py(yy))).collect();
+                    bitmap.fill_polygon(&points);
+                }
+            }
+        }
+    }
+
     fn ensure(&mut self, x: f32, y: f32) {
         self.width = f32::max(x, self.width);
         self.height = f32::max(y, self.height);
@@ -136,6 +393,14 @@ impl Drawing {
         self.entities.push(Entity::DebugRect(x, y, w, h));
     }
 
+    /// Highlight part of the drawing, e.g. where there's an error.
+    pub fn rect_error(&mut self, x: f32, y: f32, w: f32, h: f32) {
+        self.ensure(x, y);
+        self.ensure(x + w, y + h);
+
+        self.entities.push(Entity::ErrorRect(x, y, w, h));
+    }
+
     pub fn point_debug(&mut self, x: f32, y: f32, w: f32, h: f32) {
         self.ensure(x, y);
         self.ensure(x + w, y + h);
@@ -146,7 +411,16 @@ impl Drawing {
     pub fn text(&mut self, x: f32, y: f32, text: String) {
         self.ensure(x, y);
 
-        self.entities.push(Entity::Text(x, y, text));
+        self.entities
+            .push(Entity::Text(x, y, text, TextStyle::new()));
+    }
+
+    /// Styled text, with the anchor at x and the baseline at y.
+    pub fn text_styled(&mut self, x: f32, y: f32, text: String, style: TextStyle) {
+        let left = x - anchor_offset(&text, style);
+        self.ensure(left + text_width(&text, style.size), y);
+
+        self.entities.push(Entity::Text(x, y, text, style));
     }
 
     pub fn line_path(&mut self, x: f32, y: f32, path: String) {
@@ -165,4 +439,166 @@ impl Drawing {
         self.ensure(x + radius, y + radius);
         self.entities.push(Entity::Circle(x, y, radius, fill));
     }
+
+    pub fn polygon_fill(&mut self, points: Vec<(f32, f32)>) {
+        for &(x, y) in points.iter() {
+            self.ensure(x, y);
+        }
+        self.entities.push(Entity::FillPolygon(points));
+    }
+}
+
+/// Straight line segments in a path, as (x1, y1, x2, y2).
+/// Only understands the move and line commands, which are all that line_path() is used for.
+fn path_segments(path: &str) -> Vec<(f32, f32, f32, f32)> {
+    let mut segments = vec![];
+    let (mut x, mut y) = (0.0, 0.0);
+
+    let mut command = 'M';
+    let mut numbers: Vec<f32> = vec![];
+
+    // Commands are letters, followed by pairs of coordinates.
+    for word in path.split_whitespace() {
+        let (letter, rest) = match word.chars().next() {
+            Some(c) if c.is_alphabetic() => (Some(c), &word[c.len_utf8()..]),
+            _ => (None, word),
+        };
+
+        if let Some(letter) = letter {
+            command = letter;
+            numbers.clear();
+        }
+
+        if let Ok(number) = rest.parse::<f32>() {
+            numbers.push(number);
+        }
+
+        if numbers.len() == 2 {
+            let (a, b) = (numbers[0], numbers[1]);
+            numbers.clear();
+
+            let (xx, yy) = match command {
+                'm' | 'l' => (x + a, y + b),
+                _ => (a, b),
+            };
+
+            if command == 'l' || command == 'L' {
+                segments.push((x, y, xx, yy));
+            }
+
+            x = xx;
+            y = yy;
+
+            // Coordinates following a move are implicit lines.
+            command = match command {
+                'm' => 'l',
+                'M' => 'L',
+                other => other,
+            };
+        }
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_styled_test() {
+        let width = text_width("Title", 24.0);
+        assert!(width > text_width("Title", 16.0));
+
+        // The drawing grows to fit the text, wherever its anchor is.
+        let style = TextStyle {
+            size: 24.0,
+            anchor: Anchor::Middle,
+            class: Some("title"),
+            colour: None,
+        };
+
+        let mut drawing = Drawing::new();
+        drawing.text_styled(100.0, 30.0, "Title".to_string(), style);
+        assert_eq!(drawing.width(), 100.0 + width / 2.0);
+        assert_eq!(drawing.height(), 30.0);
+
+        let mut drawing = Drawing::new();
+        drawing.text_styled(
+            100.0,
+            30.0,
+            "Title".to_string(),
+            TextStyle {
+                anchor: Anchor::End,
+                ..style
+            },
+        );
+        assert_eq!(drawing.width(), 100.0);
+
+        let rendered = drawing.render();
+        assert!(rendered.contains("font-size='24' text-anchor='end' class='title'"));
+    }
+
+    #[test]
+    fn error_test() {
+        let mut drawing = Drawing::new();
+        drawing.rect_error(10.0, 20.0, 30.0, 40.0);
+        drawing.text_styled(
+            10.0,
+            20.0,
+            "Oops".to_string(),
+            TextStyle {
+                colour: Some("red"),
+                ..TextStyle::new()
+            },
+        );
+        assert_eq!(drawing.width(), 10.0 + text_width("Oops", 16.0));
+        assert_eq!(drawing.height(), 60.0);
+
+        let rendered = drawing.render();
+        assert!(rendered.contains("<rect x='10' y='20' width='30' height='40' class='error'"));
+        assert!(rendered.contains("fill='red' >Oops<"));
+    }
+
+    #[test]
+    fn scale_test() {
+        let mut drawing = Drawing::new();
+        drawing.rect(0.0, 0.0, 200.0, 100.0);
+        assert!(!drawing.render().contains("viewBox"));
+
+        drawing.set_scale(0.5);
+        assert!(
+            drawing
+                .render()
+                .contains("width='100' height='50' viewBox='0 0 200 100'")
+        );
+        assert_eq!(drawing.width(), 200.0, "Coordinates aren't scaled.");
+    }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!(escape("Tom & Jerry"), "Tom &amp; Jerry");
+        assert_eq!(escape("<b>'Hi'</b>"), "&lt;b&gt;&apos;Hi&apos;&lt;/b&gt;");
+        assert_eq!(escape("\"Ríl\"\u{0}"), "&quot;Ríl&quot;");
+
+        // Text can't break out of the element.
+        let mut drawing = Drawing::new();
+        drawing.text(0.0, 10.0, "</text><script>".to_string());
+        assert!(drawing
+            .render()
+            .contains(">&lt;/text&gt;&lt;script&gt;</text>"));
+    }
+
+    #[test]
+    fn path_segments_test() {
+        assert_eq!(
+            path_segments("M0 0 l2 1 l5 3"),
+            vec![(0.0, 0.0, 2.0, 1.0), (2.0, 1.0, 7.0, 4.0)]
+        );
+
+        assert_eq!(
+            path_segments("M1 1 L3 3 m1 1 2 2"),
+            vec![(1.0, 1.0, 3.0, 3.0), (4.0, 4.0, 6.0, 6.0)]
+        );
+    }
 }
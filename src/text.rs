@@ -1,12 +1,446 @@
 //! Words.
 
-// TODO:
-//  - stop words?
-//  - synonyms- bourree? jigg?
-
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
 use unidecode::unidecode;
 
+// Stop words and synonyms, applied to tokens both when indexing and searching.
+// Loaded from a plain text file, e.g.
+//
+//   # Comments start with a hash.
+//   stop: the a an of
+//   synonyms: jig jigg gigue
+//
+// Each 'synonyms' line maps all words to the first one.
+#[derive(Debug, Clone)]
+pub struct Vocabulary {
+    stop_words: HashSet<String>,
+    synonyms: HashMap<String, String>,
+}
+
+impl Default for Vocabulary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vocabulary {
+    pub fn new() -> Vocabulary {
+        Vocabulary {
+            stop_words: HashSet::new(),
+            synonyms: HashMap::new(),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Vocabulary, String> {
+        let mut vocabulary = Vocabulary::new();
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            let (kind, words) = match (parts.next(), parts.next()) {
+                (Some(kind), Some(words)) => (kind.trim(), words),
+                _ => return Err(format!("Expected 'stop:' or 'synonyms:' on line {}", i + 1)),
+            };
+
+            let words: Vec<String> = words.split_whitespace().map(|x| x.to_lowercase()).collect();
+
+            match kind {
+                "stop" => vocabulary.stop_words.extend(words),
+                "synonyms" => {
+                    if let Some(canonical) = words.first() {
+                        for word in words.iter() {
+                            vocabulary
+                                .synonyms
+                                .insert(word.to_string(), canonical.to_string());
+                        }
+                    }
+                }
+                _ => return Err(format!("Unrecognised '{}' on line {}", kind, i + 1)),
+            }
+        }
+
+        Ok(vocabulary)
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Vocabulary, String> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|err| format!("Can't read vocabulary file {:?}: {:?}", path, err))?;
+
+        Vocabulary::parse(&content)
+    }
+
+    // Remove stop words and replace synonyms.
+    pub fn apply(&self, tokens: HashSet<String>) -> HashSet<String> {
+        tokens
+            .into_iter()
+            .filter(|x| !self.stop_words.contains(x))
+            .map(|x| match self.synonyms.get(&x) {
+                Some(canonical) => canonical.to_string(),
+                None => x,
+            }).collect()
+    }
+}
+
+// A text search, e.g. `"the butterfly" +jig -slip`.
+// Plain words score the results. A word starting with `+` must be in the text, and one starting
+// with `-` must not. A quoted phrase must be in the text, with its words in order. `-"slip jig"`
+// excludes texts with all the words of the phrase.
+#[derive(Debug, PartialEq)]
+pub struct TextQuery {
+    // Everything that scores, which is the plain and required words, and the phrases.
+    pub words: Vec<String>,
+    pub required: Vec<String>,
+    pub excluded: Vec<String>,
+    pub phrases: Vec<String>,
+}
+
+impl TextQuery {
+    pub fn parse(text: &str) -> TextQuery {
+        let mut query = TextQuery {
+            words: vec![],
+            required: vec![],
+            excluded: vec![],
+            phrases: vec![],
+        };
+
+        let mut rest = text.trim_start();
+        while !rest.is_empty() {
+            let sign = rest.chars().next().filter(|x| *x == '+' || *x == '-');
+            if sign.is_some() {
+                rest = &rest[1..];
+            }
+
+            // An unclosed quote runs to the end.
+            let (term, quoted, after) = if let Some(quoted) = rest.strip_prefix('"') {
+                match quoted.find('"') {
+                    Some(end) => (&quoted[..end], true, &quoted[end + 1..]),
+                    None => (quoted, true, ""),
+                }
+            } else {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], false, &rest[end..])
+            };
+            rest = after.trim_start();
+
+            let term = term.trim().to_string();
+            if term.is_empty() {
+                continue;
+            }
+
+            match (sign, quoted) {
+                (Some('-'), _) => query.excluded.push(term),
+                (_, true) => {
+                    query.words.push(term.clone());
+                    query.phrases.push(term);
+                }
+                (Some(_), false) => {
+                    query.words.push(term.clone());
+                    query.required.push(term);
+                }
+                (None, false) => query.words.push(term),
+            }
+        }
+
+        query
+    }
+}
+
+// A text query written the same way whatever the case and spacing it was typed with, which the
+// search ignores. Repeated terms are left out.
+pub fn normalise_query(text: &str) -> String {
+    let query = TextQuery::parse(&text.to_lowercase());
+    let quoted = |term: &str| {
+        let words = term.split_whitespace().collect::<Vec<&str>>();
+        format!("\"{}\"", words.join(" "))
+    };
+
+    let mut terms: Vec<String> = vec![];
+    let included = query.words.iter().map(|word| {
+        if query.phrases.contains(word) {
+            quoted(word)
+        } else if query.required.contains(word) {
+            format!("+{}", word)
+        } else {
+            word.clone()
+        }
+    });
+    let excluded = query.excluded.iter().map(|term| {
+        if term.contains(char::is_whitespace) {
+            format!("-{}", quoted(term))
+        } else {
+            format!("-{}", term)
+        }
+    });
+    for term in included.chain(excluded) {
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+
+    terms.join(" ")
+}
+
+// Is the phrase in the text, with its words in order? Case, accents and punctuation are ignored.
+pub fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let phrase = normalise_title(phrase);
+    phrase.is_empty() || format!(" {} ", normalise_title(text)).contains(&format!(" {} ", phrase))
+}
+
+// The tokens of a word or phrase that a text must have to contain it. Only the ASCII tokens of
+// letters and numbers are used, because these are indexed whatever the accents and punctuation,
+// e.g. "O'Carolan" gives "o" and "carolan".
+pub fn required_tokens(text: &str) -> HashSet<String> {
+    tokenize(&unidecode(text))
+        .into_iter()
+        .filter(|x| x.chars().all(char::is_alphanumeric))
+        .collect()
+}
+
+// Lower-case ASCII with words separated by single spaces, for matching titles by prefix.
+fn normalise_title(text: &str) -> String {
+    unidecode(&text.to_lowercase())
+        .split(|x: char| !x.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Most completions a title prefix can have.
+pub const MAX_COMPLETIONS: usize = 50;
+
+// Most common first, then alphabetically.
+fn completion_order(a: &(String, usize), b: &(String, usize)) -> ::std::cmp::Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))
+}
+
+struct TrieNode {
+    children: BTreeMap<char, usize>,
+
+    // Titles as written that end here, with the number of tunes that have each.
+    titles: HashMap<String, usize>,
+
+    // The top titles that end here or below, in completion order, so completing a short prefix
+    // doesn't walk the whole trie.
+    top: Vec<(String, usize)>,
+}
+
+impl TrieNode {
+    fn new() -> TrieNode {
+        TrieNode {
+            children: BTreeMap::new(),
+            titles: HashMap::new(),
+            top: vec![],
+        }
+    }
+
+    // Take account of a title's count going up.
+    // A title can be found under more than one key below here, so keep the larger count.
+    fn offer(&mut self, title: &str, count: usize) {
+        match self.top.iter().position(|(x, _)| x == title) {
+            Some(i) => self.top[i].1 = self.top[i].1.max(count),
+            None => self.top.push((title.to_string(), count)),
+        }
+        self.top.sort_by(completion_order);
+        self.top.truncate(MAX_COMPLETIONS);
+    }
+}
+
+// Prefix trie of titles, for completing a title as it's typed.
+// Titles are matched ignoring case, accents and punctuation, and also without a leading article,
+// so "butt" finds "The Butterfly".
+pub struct TitleTrie {
+    // Nodes by index, the root first.
+    nodes: Vec<TrieNode>,
+
+    // Titles added for each tune, so they can be removed again.
+    tune_titles: HashMap<usize, Vec<String>>,
+}
+
+impl Default for TitleTrie {
+    fn default() -> TitleTrie {
+        TitleTrie::new()
+    }
+}
+
+impl TitleTrie {
+    pub fn new() -> TitleTrie {
+        TitleTrie {
+            nodes: vec![TrieNode::new()],
+            tune_titles: HashMap::new(),
+        }
+    }
+
+    // Keys a title is found under.
+    fn keys(title: &str) -> Vec<String> {
+        let key = normalise_title(title);
+        let mut result = vec![];
+
+        for article in ["the ", "a ", "an "].iter() {
+            if let Some(rest) = key.strip_prefix(article) {
+                result.push(rest.to_string());
+            }
+        }
+
+        if !key.is_empty() {
+            result.push(key);
+        }
+        result
+    }
+
+    // Node for the key, if there is one.
+    fn find(&self, key: &str) -> Option<usize> {
+        self.find_path(key).and_then(|path| path.last().cloned())
+    }
+
+    // Nodes from the root to the key's node, if there is one.
+    fn find_path(&self, key: &str) -> Option<Vec<usize>> {
+        let mut path = vec![0];
+        for c in key.chars() {
+            let node = *self.nodes[*path.last()?].children.get(&c)?;
+            path.push(node);
+        }
+        Some(path)
+    }
+
+    // Work out a node's top titles again from its own and its children's.
+    // The top of each child includes everything that could be in the top of this one.
+    fn recompute_top(&mut self, node: usize) {
+        let mut found: HashMap<&str, usize> = HashMap::new();
+        let candidates = self.nodes[node]
+            .titles
+            .iter()
+            .chain(
+                self.nodes[node]
+                    .children
+                    .values()
+                    .flat_map(|child| self.nodes[*child].top.iter().map(|(x, y)| (x, y))),
+            );
+        for (title, count) in candidates {
+            let entry = found.entry(title).or_insert(0);
+            *entry = (*entry).max(*count);
+        }
+
+        let mut top: Vec<(String, usize)> = found
+            .into_iter()
+            .map(|(title, count)| (title.to_string(), count))
+            .collect();
+        top.sort_by(completion_order);
+        top.truncate(MAX_COMPLETIONS);
+        self.nodes[node].top = top;
+    }
+
+    pub fn add(&mut self, tune_id: usize, title: &str) {
+        for key in TitleTrie::keys(title) {
+            let mut path = vec![0];
+            for c in key.chars() {
+                let node = *path.last().unwrap();
+                path.push(match self.nodes[node].children.get(&c) {
+                    Some(child) => *child,
+                    None => {
+                        let child = self.nodes.len();
+                        self.nodes.push(TrieNode::new());
+                        self.nodes[node].children.insert(c, child);
+                        child
+                    }
+                });
+            }
+
+            let node = *path.last().unwrap();
+            let count = {
+                let count = self.nodes[node].titles.entry(title.to_string()).or_insert(0);
+                *count += 1;
+                *count
+            };
+
+            // Counts only go up here, so each top list just needs to take in this title.
+            for node in path {
+                self.nodes[node].offer(title, count);
+            }
+        }
+
+        self.tune_titles
+            .entry(tune_id)
+            .or_default()
+            .push(title.to_string());
+    }
+
+    // Remove all of a tune's titles. Empty nodes are left in place.
+    pub fn remove(&mut self, tune_id: usize) {
+        for title in self.tune_titles.remove(&tune_id).unwrap_or_default() {
+            for key in TitleTrie::keys(&title) {
+                if let Some(path) = self.find_path(&key) {
+                    let titles = &mut self.nodes[*path.last().unwrap()].titles;
+                    let remaining = match titles.get_mut(&title) {
+                        Some(count) => {
+                            *count -= 1;
+                            *count
+                        }
+                        None => continue,
+                    };
+                    if remaining == 0 {
+                        titles.remove(&title);
+                    }
+
+                    // Another title may now belong in the top lists, so rebuild them from the
+                    // bottom up.
+                    for node in path.into_iter().rev() {
+                        self.recompute_top(node);
+                    }
+                }
+            }
+        }
+    }
+
+    // Up to `limit` titles starting with the prefix, with the number of tunes with each.
+    // The most common titles come first, then alphabetically. No more than MAX_COMPLETIONS.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let key = normalise_title(prefix);
+        if key.is_empty() {
+            return vec![];
+        }
+
+        match self.find(&key) {
+            Some(node) => self.nodes[node].top.iter().take(limit).cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+// Levenshtein distance between two words, in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // Distances from the previous row of the table.
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = if *a_char == b[j] { 0 } else { 1 };
+            current[j + 1] = usize::min(
+                previous[j] + substitution,
+                usize::min(previous[j + 1] + 1, current[j] + 1),
+            );
+        }
+        previous.copy_from_slice(&current);
+    }
+
+    previous[b.len()]
+}
+
 // Tokenize input a number of different ways.
 // These are all unioned.
 pub fn tokenize(text: &str) -> HashSet<String> {
@@ -17,7 +451,7 @@ pub fn tokenize(text: &str) -> HashSet<String> {
     let ascii = unidecode(&lowercase);
     let mut preprocessed = String::new();
     preprocessed.push_str(&lowercase);
-    preprocessed.push_str(" ");
+    preprocessed.push(' ');
     preprocessed.push_str(&ascii);
 
     // Take a number of steps, adding to the set each time.
@@ -64,6 +498,144 @@ pub fn tokenize(text: &str) -> HashSet<String> {
     result
 }
 
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("hornpipe", "hornpipe"), 0);
+    assert_eq!(edit_distance("hornpip", "hornpipe"), 1);
+    assert_eq!(edit_distance("compser", "composer"), 1);
+    assert_eq!(edit_distance("jgi", "jig"), 2);
+    assert_eq!(edit_distance("", "reel"), 4);
+    assert_eq!(edit_distance("café", "cafe"), 1);
+}
+
+#[test]
+fn test_vocabulary() {
+    let vocabulary = Vocabulary::parse(
+        "# Comment\n\nstop: the  of\nsynonyms: jig jigg Gigue\nsynonyms: waltz valse walzer\n",
+    ).unwrap();
+
+    let expected: HashSet<String> = ["jig", "road", "waltz"]
+        .iter()
+        .map(|x| String::from(*x))
+        .collect();
+
+    assert_eq!(
+        vocabulary.apply(tokenize("The Jigg of the Road valse")),
+        expected
+    );
+
+    assert_eq!(
+        vocabulary.apply(tokenize("gigue")),
+        vocabulary.apply(tokenize("jig")),
+        "Synonyms should be the same at index and query time."
+    );
+
+    assert!(Vocabulary::parse("jig jigg").is_err());
+    assert!(Vocabulary::parse("synonym: jig jigg").is_err());
+}
+
+#[test]
+fn test_text_query() {
+    let query =
+        TextQuery::parse(" jig  -slip +\"Kesh\" \"the butterfly\" +reel -\"slip jig\" - \"open");
+    assert_eq!(
+        query.words,
+        vec!["jig", "Kesh", "the butterfly", "reel", "open"]
+    );
+    assert_eq!(query.required, vec!["reel"]);
+    assert_eq!(query.excluded, vec!["slip", "slip jig"]);
+    assert_eq!(
+        query.phrases,
+        vec!["Kesh", "the butterfly", "open"],
+        "Quotes are phrases, with or without a plus, and an unclosed quote runs to the end."
+    );
+
+    assert!(TextQuery::parse("").words.is_empty());
+    assert!(TextQuery::parse("+ - \"\"").words.is_empty());
+}
+
+#[test]
+fn test_normalise_query() {
+    assert_eq!(
+        normalise_query("  Kesh  -Slip +Jig \"The   Butterfly\" kesh -\"slip jig\""),
+        "kesh +jig \"the butterfly\" -slip -\"slip jig\""
+    );
+    assert_eq!(
+        normalise_query("\"the butterfly\" KESH"),
+        normalise_query("\"The Butterfly\"   kesh")
+    );
+    assert_eq!(normalise_query(""), "");
+}
+
+#[test]
+fn test_contains_phrase() {
+    assert!(contains_phrase("The Butterfly", "the butterfly"));
+    assert!(contains_phrase("Róisín Dubh, The", "roisin dubh"));
+    assert!(!contains_phrase("Butterfly, The", "the butterfly"));
+    assert!(!contains_phrase("The Butterfly", "butter"));
+    assert!(contains_phrase("The Butterfly", "..."));
+}
+
+#[test]
+fn test_required_tokens() {
+    let expected: HashSet<String> = ["o", "carolan"].iter().map(|x| x.to_string()).collect();
+    assert_eq!(required_tokens("O'Carolan"), expected);
+    assert!(required_tokens("Róisín").contains("roisin"));
+}
+
+#[test]
+fn test_title_trie() {
+    let mut trie = TitleTrie::new();
+    trie.add(1, "The Butterfly");
+    trie.add(2, "The Butterfly");
+    trie.add(3, "Butter Churn");
+    trie.add(4, "Bútterfly Jig");
+
+    assert_eq!(
+        trie.complete("butt", 10),
+        vec![
+            ("The Butterfly".to_string(), 2),
+            ("Butter Churn".to_string(), 1),
+            ("Bútterfly Jig".to_string(), 1),
+        ],
+        "Most common first, ignoring articles, case and accents."
+    );
+    assert_eq!(trie.complete("THE BUTTERFLY", 10).len(), 1);
+    assert_eq!(trie.complete("butterfly", 1).len(), 1);
+    assert!(trie.complete("moth", 10).is_empty());
+    assert!(trie.complete("  ", 10).is_empty());
+
+    trie.remove(1);
+    trie.remove(3);
+    assert_eq!(
+        trie.complete("butt", 10),
+        vec![
+            ("Bútterfly Jig".to_string(), 1),
+            ("The Butterfly".to_string(), 1),
+        ]
+    );
+
+    // Only the top titles are kept at each node, so removing one has to bring the next back.
+    let mut trie = TitleTrie::new();
+    for tune_id in 0..MAX_COMPLETIONS {
+        trie.add(tune_id, "Reel A");
+        trie.add(MAX_COMPLETIONS + tune_id, &format!("Reel {}", tune_id));
+    }
+    trie.add(2 * MAX_COMPLETIONS, "Reel Z");
+    trie.add(2 * MAX_COMPLETIONS + 1, "Reel Z");
+    assert_eq!(trie.complete("r", 100).len(), MAX_COMPLETIONS);
+    assert_eq!(trie.complete("r", 2)[1], ("Reel Z".to_string(), 2));
+    assert!(!trie.complete("r", 100).contains(&("Reel 9".to_string(), 1)));
+
+    for tune_id in 0..MAX_COMPLETIONS {
+        trie.remove(tune_id);
+    }
+    trie.remove(2 * MAX_COMPLETIONS);
+    assert_eq!(trie.complete("r", 100).len(), MAX_COMPLETIONS);
+    assert!(trie.complete("r", 100).contains(&("Reel 9".to_string(), 1)));
+    assert!(!trie.complete("re", 100).iter().any(|(title, _)| title == "Reel A"));
+}
+
 #[test]
 fn test_regressions() {
     // Test regressions.
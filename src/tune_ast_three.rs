@@ -9,62 +9,566 @@ pub struct Tune {
     pub prelude: Vec<l::T>,
 
     pub voices: Vec<Vec<l::T>>,
+
+    /// Properties of each voice, in the same order as voices.
+    pub voice_properties: Vec<VoiceProperties>,
 }
 
 // TODO SHOULD BE ENTITY?
 // Would allow for attachment of accidentals etc.
 
+impl Default for Tune {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tune {
     pub fn new() -> Tune {
         Tune {
             prelude: vec![],
             voices: vec![],
+            voice_properties: vec![],
+        }
+    }
+}
+
+/// Properties of a voice, declared in V: fields.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VoiceProperties {
+    pub id: String,
+
+    /// Name to label the first stave with, and subname for the rest.
+    pub name: Option<String>,
+    pub subname: Option<String>,
+
+    pub clef: music::Clef,
+
+    /// Octaves to move the written notes by, e.g. -1 for notes written an octave too high.
+    pub octave: i16,
+
+    /// Semitones to transpose by when played. The written notes are unchanged.
+    pub transpose: i32,
+
+    /// Length of the pick-up notes before the first full bar, if the voice starts with any.
+    /// Worked out from the music rather than declared.
+    pub anacrusis: Option<music::FractionalDuration>,
+}
+
+impl VoiceProperties {
+    pub fn new(id: &str) -> VoiceProperties {
+        VoiceProperties {
+            id: id.to_string(),
+            name: None,
+            subname: None,
+            clef: music::Clef::treble(),
+            octave: 0,
+            transpose: 0,
+            anacrusis: None,
+        }
+    }
+
+    /// Apply the properties from a V: field over these ones. Unrecognised properties are ignored.
+    pub fn apply(&mut self, properties: &[(String, String)]) {
+        for (key, value) in properties.iter() {
+            match key.as_ref() {
+                "name" | "nm" => self.name = Some(value.clone()),
+                "subname" | "sname" | "snm" => self.subname = Some(value.clone()),
+                "clef" => {
+                    if let Some(clef) = music::Clef::from_name(value) {
+                        self.clef = clef;
+                    }
+                }
+                "octave" => {
+                    if let Ok(octave) = value.parse::<i16>() {
+                        self.octave = octave;
+                    }
+                }
+                "transpose" => {
+                    if let Ok(transpose) = value.parse::<i32>() {
+                        self.transpose = transpose;
+                    }
+                }
+
+                // The clef can be given on its own, e.g. "V:1 treble".
+                _ => {
+                    if let (true, Some(clef)) = (value.is_empty(), music::Clef::from_name(key)) {
+                        self.clef = clef;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Index of the voice with this ID, adding it if it's new.
+// New voices inherit the properties declared for them in the header.
+fn find_voice(tune: &mut Tune, declared: &[VoiceProperties], id: &str) -> usize {
+    if let Some(i) = tune.voice_properties.iter().position(|x| x.id == id) {
+        return i;
+    }
+
+    let properties = declared
+        .iter()
+        .find(|x| x.id == id)
+        .cloned()
+        .unwrap_or(VoiceProperties::new(id));
+
+    tune.voices.push(vec![]);
+    tune.voice_properties.push(properties);
+    tune.voices.len() - 1
+}
+
+// Does this voice have any music in it, rather than just line and beam breaks?
+fn has_music(voice: &[l::T]) -> bool {
+    voice.iter().any(|x| {
+        !matches!(
+            x,
+            l::T::Newline | l::T::BeamBreak | l::T::CommentLine(_) | l::T::Directive(_, _)
+        )
+    })
+}
+
+// The note length when there's no L: field, as in the ABC standard: a sixteenth for metres shorter
+// than 3/4, otherwise an eighth, including when there's no metre.
+fn default_note_length(metre: Option<music::Metre>) -> music::FractionalDuration {
+    match metre {
+        Some(music::Metre(numerator, denominator)) if numerator * 4 < denominator * 3 => {
+            music::FractionalDuration(1, 16)
         }
+        _ => music::FractionalDuration(1, 8),
     }
 }
 
 /// Read from a Lexer and build a new AST.
+/// Without an L: field, notes are a quarter long.
 pub fn read_from_lexer(lexer: l::Lexer) -> Tune {
+    read_tune(lexer, false, false)
+}
+
+/// As `read_from_lexer`, but with broken rhythm markers, e.g. "A>B", left in and the notes either
+/// side of them as they're written, so the ABC can be written back out the same way.
+pub fn read_as_written(lexer: l::Lexer) -> Tune {
+    read_tune(lexer, true, false)
+}
+
+/// As `read_from_lexer`, but without an L: field the note length depends on the metre, as in the
+/// ABC standard. The lint uses this, so bars are checked as other ABC software plays them.
+pub fn read_with_standard_lengths(lexer: l::Lexer) -> Tune {
+    read_tune(lexer, false, true)
+}
+
+fn read_tune(lexer: l::Lexer, as_written: bool, standard_lengths: bool) -> Tune {
     // Every Entity has an index.
     let _i = 0;
 
     let mut tune = Tune::new();
 
     let mut finished_prelude = false;
-    let mut current_sequence = vec![];
+    let mut prelude = vec![];
+
+    // Voices declared in the header, in order.
+    let mut declared: Vec<VoiceProperties> = vec![];
 
-    // The base note length. This can change during the tune.
-    let mut note_length = music::FractionalDuration(1, 4);
+    // Index of the voice that tokens in the body belong to.
+    let mut current_voice = 0;
+
+    // The base note length. This can change during the tune. For standard lengths, until there's
+    // an L: field it depends on the metre in the header.
+    let mut note_length = if standard_lengths {
+        default_note_length(None)
+    } else {
+        music::FractionalDuration(1, 4)
+    };
+    let mut explicit_length = false;
 
     for token in lexer.collect_tokens() {
+        // Header fields build up the prelude, everything after belongs to the current voice.
+        let sequence = if finished_prelude {
+            &mut tune.voices[current_voice]
+        } else {
+            &mut prelude
+        };
+
         match token {
             l::T::KeySignature(pitch_class, mode) => {
-                current_sequence.push(l::T::KeySignature(pitch_class, mode));
+                sequence.push(l::T::KeySignature(pitch_class, mode));
 
                 // K marks the end of the prelude.
                 if !finished_prelude {
-                    tune.prelude = current_sequence;
+                    tune.prelude = prelude;
+                    prelude = vec![];
                     finished_prelude = true;
-                    current_sequence = vec![];
+
+                    // Music before the first voice switch belongs to the first voice declared.
+                    let id = declared
+                        .first()
+                        .map(|x| x.id.clone())
+                        .unwrap_or("1".to_string());
+                    current_voice = find_voice(&mut tune, &declared, &id);
                 }
             }
 
             // The "L:" token doesn't produce an entity, it just updates the running status.
-            l::T::DefaultNoteLength(new_note_length) => note_length = new_note_length,
+            l::T::DefaultNoteLength(new_note_length) => {
+                note_length = new_note_length;
+                explicit_length = true;
+            }
+
+            l::T::Metre(metre) if !finished_prelude => {
+                if standard_lengths && !explicit_length {
+                    note_length = default_note_length(Some(metre));
+                }
+                sequence.push(l::T::Metre(metre));
+            }
+
+            // In the header, declare the voice's properties. In the body, switch to it.
+            l::T::Voice(id, properties) => {
+                if finished_prelude {
+                    current_voice = find_voice(&mut tune, &declared, &id);
+                    tune.voice_properties[current_voice].apply(&properties);
+                } else {
+                    match declared.iter().position(|x| x.id == id) {
+                        Some(i) => declared[i].apply(&properties),
+                        None => {
+                            let mut voice = VoiceProperties::new(&id);
+                            voice.apply(&properties);
+                            declared.push(voice);
+                        }
+                    }
+                }
+            }
+
+            // The clef in the K: field is for the voice the music starts in.
+            l::T::Clef(clef) if finished_prelude => {
+                tune.voice_properties[current_voice].clef = clef
+            }
 
             l::T::Note(note) => {
-                current_sequence.push(l::T::Note(note.resolve_duration(note_length)))
+                let mut note = note.resolve_duration(note_length);
+                if finished_prelude {
+                    note.0.octave += tune.voice_properties[current_voice].octave;
+                }
+                sequence.push(l::T::Note(note))
             }
 
-            token => current_sequence.push(token),
+            l::T::Rest(duration) => sequence.push(l::T::Rest(duration.multiply(note_length))),
+
+            token => sequence.push(token),
         }
     }
 
-    tune.voices.push(current_sequence);
+    if !finished_prelude {
+        // No K: field, so there's no body. Keep the tokens as a voice, as before.
+        tune.voices.push(prelude);
+        tune.voice_properties.push(VoiceProperties::new("1"));
+    } else if tune.voices.len() > 1 && !has_music(&tune.voices[0]) {
+        // Drop the implicit first voice if the body started by switching voice.
+        tune.voices.remove(0);
+        tune.voice_properties.remove(0);
+    }
+
+    if !as_written {
+        tune.voices = tune.voices.into_iter().map(apply_broken_rhythm).collect();
+    }
+
+    let metre = tune
+        .prelude
+        .iter()
+        .filter_map(|x| match x {
+            l::T::Metre(metre) => Some(*metre),
+            _ => None,
+        }).next_back();
+    for (voice, properties) in tune.voices.iter().zip(tune.voice_properties.iter_mut()) {
+        // A pick-up is as long as it sounds.
+        properties.anacrusis = if as_written {
+            anacrusis(&apply_broken_rhythm(voice.clone()), metre)
+        } else {
+            anacrusis(voice, metre)
+        };
+    }
 
     tune
 }
 
+/// Length of the notes and rests before the first barline, if they're shorter than a bar of the
+/// metre, i.e. a pick-up. None if the first bar is full, or there's no metre or barline to tell.
+pub fn anacrusis(voice: &[l::T], metre: Option<music::Metre>) -> Option<music::FractionalDuration> {
+    let bar_length = metre.and_then(|music::Metre(numerator, denominator)| {
+        music::FractionalDuration::new(numerator, denominator)
+    })?;
+
+    let mut duration = music::FractionalDuration(0, 1);
+    for token in voice.iter() {
+        match token {
+            l::T::Note(music::Note(_, length)) | l::T::Rest(length) => {
+                duration = duration + *length
+            }
+
+            // A barline before any music, e.g. an opening repeat, doesn't end a bar.
+            l::T::SingleBar
+            | l::T::DoubleBar
+            | l::T::OpenRepeat
+            | l::T::CloseRepeat
+            | l::T::EndBar
+            | l::T::NTimeBar(_)
+                if duration.0 > 0 =>
+            {
+                return if duration.gte(&bar_length) {
+                    None
+                } else {
+                    Some(duration.reduce())
+                };
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+// The duration factors for a broken rhythm marker, as (before, after).
+// One '>' dots the note before and halves the note after, each extra one halves it again.
+fn broken_rhythm_factors(dots: i8) -> (music::FractionalDuration, music::FractionalDuration) {
+    let power = 1u32 << dots.unsigned_abs().min(4);
+    let longer = music::FractionalDuration(power * 2 - 1, power);
+    let shorter = music::FractionalDuration(1, power);
+
+    if dots > 0 {
+        (longer, shorter)
+    } else {
+        (shorter, longer)
+    }
+}
+
+fn scale_duration(token: &mut l::T, factor: music::FractionalDuration) {
+    match token {
+        l::T::Note(music::Note(_, duration)) => *duration = duration.multiply(factor),
+        l::T::Rest(duration) => *duration = duration.multiply(factor),
+        _ => (),
+    }
+}
+
+// Apply broken rhythm markers, e.g. "A>B", to the notes or rests either side.
+// The markers are removed, so the durations in the AST are the ones that sound.
+fn apply_broken_rhythm(voice: Vec<l::T>) -> Vec<l::T> {
+    let mut result: Vec<l::T> = Vec::with_capacity(voice.len());
+
+    // Index in the result of the most recent note or rest.
+    let mut previous: Option<usize> = None;
+
+    // Factor waiting to be applied to the next note or rest.
+    let mut pending: Option<music::FractionalDuration> = None;
+
+    for mut token in voice {
+        match token {
+            l::T::Dottage(dots) => {
+                let (before, after) = broken_rhythm_factors(dots);
+                if let Some(i) = previous {
+                    scale_duration(&mut result[i], before);
+                }
+                pending = Some(after);
+            }
+            l::T::Note(_) | l::T::Rest(_) => {
+                if let Some(factor) = pending.take() {
+                    scale_duration(&mut token, factor);
+                }
+                previous = Some(result.len());
+                result.push(token);
+            }
+            _ => result.push(token),
+        }
+    }
+
+    result
+}
+
 // Heuristics:
 // 1 - Remove consecutive beam breaks.
 // 2 - Remove unused beam breaks, e.g. first thing in a sequence.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(abc: &str) -> Tune {
+        let chars = abc.chars().collect::<Vec<char>>();
+        read_from_lexer(l::Lexer::new(&chars))
+    }
+
+    fn octaves(voice: &[l::T]) -> Vec<i16> {
+        voice
+            .iter()
+            .filter_map(|x| match x {
+                l::T::Note(music::Note(pitch, _)) => Some(pitch.octave),
+                _ => None,
+            }).collect()
+    }
+
+    #[test]
+    fn single_voice_test() {
+        let tune = read("X:1\nK:G\nGA|\n");
+        assert_eq!(tune.voices.len(), 1);
+        assert_eq!(tune.voice_properties, vec![VoiceProperties::new("1")]);
+    }
+
+    #[test]
+    fn voice_properties_test() {
+        let tune = read(
+            "X:1\nV:1 name=\"Flute\" snm=\"Fl.\"\nV:2 octave=-1 transpose=2\nK:G\n\
+             V:1\nGA|\nV:2 name=\"Whistle\"\nGA|\nV:1\nB|\n",
+        );
+
+        // The body started with a voice switch, so there's no implicit first voice.
+        assert_eq!(tune.voices.len(), 2);
+
+        let flute = &tune.voice_properties[0];
+        assert_eq!(flute.id, "1");
+        assert_eq!(flute.name, Some("Flute".to_string()));
+        assert_eq!(flute.subname, Some("Fl.".to_string()));
+
+        // Properties in the header are inherited, and added to in the body.
+        let whistle = &tune.voice_properties[1];
+        assert_eq!(whistle.name, Some("Whistle".to_string()));
+        assert_eq!(whistle.transpose, 2);
+
+        // Switching back to a voice carries on where it left off.
+        assert_eq!(octaves(&tune.voices[0]), vec![0, 0, 0]);
+        assert_eq!(octaves(&tune.voices[1]), vec![-1, -1]);
+    }
+
+    #[test]
+    fn key_clef_test() {
+        let tune = read("X:1\nK:G clef=bass\nG,A,|\n");
+        assert_eq!(tune.voice_properties[0].clef, music::Clef::bass());
+
+        // It's for the first voice, and others keep their own.
+        let tune = read("X:1\nV:1\nV:2 alto\nK:G bass\nGA|\nV:2\nGA|\n");
+        assert_eq!(tune.voice_properties[0].clef, music::Clef::bass());
+        assert_eq!(tune.voice_properties[1].clef, music::Clef::alto());
+    }
+
+    #[test]
+    fn undeclared_voice_test() {
+        // Music before the first switch is its own voice.
+        let tune = read("X:1\nK:G\nGA|\nV:2 treble\nGA|\n");
+        assert_eq!(tune.voices.len(), 2);
+        assert_eq!(tune.voice_properties[0].id, "1");
+        assert_eq!(tune.voice_properties[1].id, "2");
+        assert_eq!(tune.voice_properties[1].clef, music::Clef::treble());
+    }
+
+    fn durations(voice: &[l::T]) -> Vec<music::FractionalDuration> {
+        voice
+            .iter()
+            .filter_map(|x| match x {
+                l::T::Note(music::Note(_, duration)) => Some(*duration),
+                l::T::Rest(duration) => Some(*duration),
+                _ => None,
+            }).collect()
+    }
+
+    #[test]
+    fn default_note_length_test() {
+        let length = |abc: &str| {
+            let chars = abc.chars().collect::<Vec<char>>();
+            durations(&read_with_standard_lengths(l::Lexer::new(&chars)).voices[0])[0]
+        };
+        let (quarter, eighth, sixteenth) = (
+            music::FractionalDuration(1, 4),
+            music::FractionalDuration(1, 8),
+            music::FractionalDuration(1, 16),
+        );
+
+        // An eighth without a metre, or in 3/4 and longer.
+        assert_eq!(length("X:1\nK:G\nG|\n"), eighth);
+        assert_eq!(length("X:1\nM:3/4\nK:G\nG|\n"), eighth);
+        assert_eq!(length("X:1\nM:C\nK:G\nG|\n"), eighth);
+
+        // A sixteenth in shorter metres, unless there's an L: field before or after.
+        assert_eq!(length("X:1\nM:2/4\nK:G\nG|\n"), sixteenth);
+        assert_eq!(length("X:1\nM:2/4\nL:1/4\nK:G\nG|\n"), quarter);
+        assert_eq!(length("X:1\nL:1/4\nM:2/4\nK:G\nG|\n"), quarter);
+
+        // Otherwise a quarter, whatever the metre.
+        assert_eq!(durations(&read("X:1\nK:G\nG|\n").voices[0])[0], quarter);
+        assert_eq!(
+            durations(&read("X:1\nM:2/4\nK:G\nG|\n").voices[0])[0],
+            quarter
+        );
+    }
+
+    #[test]
+    fn broken_rhythm_test() {
+        let tune = read("X:1\nL:1/8\nK:G\nA>B c<d e>>f g2>a z>B|\n");
+
+        assert_eq!(
+            durations(&tune.voices[0]),
+            vec![
+                music::FractionalDuration(3, 16),
+                music::FractionalDuration(1, 16),
+                music::FractionalDuration(1, 16),
+                music::FractionalDuration(3, 16),
+                music::FractionalDuration(7, 32),
+                music::FractionalDuration(1, 32),
+                music::FractionalDuration(3, 8),
+                music::FractionalDuration(1, 16),
+                music::FractionalDuration(3, 16),
+                music::FractionalDuration(1, 16),
+            ]
+        );
+
+        // The markers are used up.
+        assert!(!tune.voices[0].iter().any(|x| matches!(x, l::T::Dottage(_))));
+
+        // Unless the tune is read as written.
+        let chars = "X:1\nM:2/4\nL:1/8\nK:G\nA>B|c2d2|\n"
+            .chars()
+            .collect::<Vec<char>>();
+        let written = read_as_written(l::Lexer::new(&chars));
+        assert_eq!(
+            durations(&written.voices[0][..2]),
+            vec![music::FractionalDuration(1, 8)]
+        );
+        assert_eq!(written.voices[0][1], l::T::Dottage(1));
+        assert_eq!(
+            written.voice_properties[0].anacrusis,
+            Some(music::FractionalDuration(1, 4))
+        );
+    }
+
+    #[test]
+    fn anacrusis_test() {
+        let tune = read("X:1\nM:6/8\nL:1/8\nK:D\nA|:d2f a>fd|e3 z2 A:|\n");
+        assert_eq!(
+            tune.voice_properties[0].anacrusis,
+            Some(music::FractionalDuration(1, 8))
+        );
+
+        // Broken rhythm counts as it sounds, so two notes can make up a beat.
+        let tune = read("X:1\nM:4/4\nL:1/8\nK:G\n|:G>A|B2 d2 g4|\n");
+        assert_eq!(
+            tune.voice_properties[0].anacrusis,
+            Some(music::FractionalDuration(1, 4))
+        );
+
+        // A full first bar, no barline or no metre isn't a pick-up.
+        for abc in [
+            "X:1\nM:3/4\nL:1/8\nK:G\nG2 A2 B2|c6|\n",
+            "X:1\nM:3/4\nL:1/8\nK:G\nGAB\n",
+            "X:1\nL:1/8\nK:G\nA|GAB|\n",
+        ]
+            .iter()
+        {
+            assert_eq!(read(abc).voice_properties[0].anacrusis, None, "{}", abc);
+        }
+
+        // Each voice has its own.
+        let tune = read("X:1\nM:2/4\nL:1/8\nK:G\nV:1\nd|g2 g2|\nV:2\nB2 B2|\n");
+        assert_eq!(
+            tune.voice_properties[0].anacrusis,
+            Some(music::FractionalDuration(1, 8))
+        );
+        assert_eq!(tune.voice_properties[1].anacrusis, None);
+    }
+}
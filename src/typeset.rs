@@ -1,5 +1,8 @@
 use abc_lexer as l;
+use beaming;
+use fingering;
 use music;
+use std::collections::HashMap;
 use std::iter::FromIterator;
 use svg;
 use tune_ast_three;
@@ -18,6 +21,27 @@ const HEAD_WIDTH: f32 = HEAD_HEIGHT * 1.25;
 
 const STEM_HEIGHT: f32 = 40.0;
 
+// Position of the middle line of the stave. Notes on or above it have their stems down.
+const MIDDLE_POSITION: i32 = 4;
+
+// Beams are this thick, vertically, and this far apart when there's more than one.
+const BEAM_THICKNESS: f32 = 5.0;
+const BEAM_SPACING: f32 = 8.0;
+
+// Steepest slope allowed for a beam, however far apart its notes are.
+const MAX_BEAM_SLOPE: f32 = 0.25;
+
+// Beams can shorten stems, but not by more than this.
+const MIN_BEAMED_STEM_HEIGHT: f32 = STEM_HEIGHT * 0.75;
+
+// Space above the stave for n-time ending brackets, and the height of the bracket's ends.
+const ENDING_HEIGHT: f32 = 20.0;
+const ENDING_HOOK: f32 = 15.0;
+const ENDING_FONT_SIZE: f32 = 14.0;
+
+// Bar numbers go at the start of each stave, above the clef.
+const BAR_NUMBER_FONT_SIZE: f32 = 12.0;
+
 // Vertical padding between each stave.
 const STAVE_V_MARGIN: f32 = 20.0;
 
@@ -27,39 +51,428 @@ const SYSTEM_V_MARGIN: f32 = 20.0;
 // How many lines (including spaces) in a stave.
 const LINES_IN_STAVE: i32 = 9;
 
-// If the scale is below this (i.e. we won't fill the line) then use the natural stave length.
-// Prevents non-full-width staves from being forced to be full width.
-const MINIMUM_STAVE_SCALE: f32 = 1.8;
+// Space under the stave for fingering annotations.
+const ANNOTATION_HEIGHT: f32 = 70.0;
+
+// Space above the stave for the voice's name.
+const LABEL_HEIGHT: f32 = 20.0;
+
+// Lines of error messages above a stave, or above the music for errors not at a note.
+const ERROR_LINE_HEIGHT: f32 = 16.0;
+const ERROR_FONT_SIZE: f32 = 12.0;
+
+// Spacing of the holes in whistle fingering diagrams.
+const HOLE_SPACING: f32 = 8.0;
+
+// Horizontal space taken by each sharp or flat in a key signature.
+const KEY_ACCIDENTAL_WIDTH: f32 = 14.0;
+
+// Digits in a time signature are drawn on a grid this many units wide and twice as high,
+// magnified so each number fills half the stave.
+const DIGIT_GRID_WIDTH: f32 = 4.0;
+const DIGIT_SCALE: f32 = 4.5;
+
+// Space between lines of text in the header, as a proportion of the font size.
+const HEADER_LINE_SPACING: f32 = 1.3;
+
+// Units of length in directives, in the drawing's units, which are pixels at 96 DPI.
+const PX_PER_POINT: f32 = 96.0 / 72.0;
+const PX_PER_INCH: f32 = 96.0;
+const PX_PER_CM: f32 = 96.0 / 2.54;
+
+// Space after a note grows with its duration, like a spring. The shortest note gets the base
+// space, and each doubling of the duration adds the same again.
+const SPRING_BASE: f32 = HEAD_WIDTH * 0.25;
+const SPRING_PER_DOUBLING: f32 = HEAD_WIDTH * 0.375;
+const SHORTEST_DURATION: f32 = 1.0 / 32.0;
+
+// If the springs would stretch more than this (i.e. we won't fill the line) then use the natural
+// stave length. Prevents non-full-width staves from being forced to be full width.
+const MAXIMUM_SPRING_SCALE: f32 = 2.5;
+
+/// Options for typesetting.
+pub struct Typesetting {
+    /// Group notes into beams according to the metre, in bars where the ABC doesn't.
+    pub auto_beam: bool,
 
-pub struct Typesetting {}
+    /// Show how to play each note on this instrument under the stave.
+    pub fingering: Option<fingering::Instrument>,
+
+    /// Show the titles, composer, rhythm and tempo above the score.
+    pub header: bool,
+
+    pub fonts: svg::Fonts,
+}
+
+impl Default for Typesetting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Typesetting {
     pub fn new() -> Typesetting {
-        Typesetting {}
+        Typesetting {
+            auto_beam: true,
+            fingering: None,
+            header: true,
+            fonts: svg::Fonts::new(),
+        }
+    }
+}
+
+/// An error in the ABC, to mark on the score.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErrorMarker {
+    /// ID of the voice and index of the note the error is at or just before, counting the voice's
+    /// notes in order. None if there's no note to mark, e.g. for an error in the header, in which
+    /// case the message goes above the music.
+    pub note: Option<(String, usize)>,
+
+    pub message: String,
+}
+
+/// Where things are on a typeset page, for clients that draw or hit-test it themselves, e.g. to
+/// play a note when it's clicked. Coordinates are the SVG's, before its scale is applied.
+/// It's shaped like abcjs's tune object, lines of staves of voices of elements, so clients written
+/// for abcjs can read it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PageLayout {
+    pub width: f32,
+    pub height: f32,
+    pub scale: f32,
+    pub lines: Vec<LineLayout>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LineLayout {
+    pub staff: Vec<StaveLayout>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StaveLayout {
+    /// Heights of the top and bottom lines.
+    pub top: f32,
+    pub bottom: f32,
+
+    pub width: f32,
+
+    /// ID of the voice on the stave.
+    #[serde(skip)]
+    pub voice: String,
+
+    /// Elements of each voice on the stave. Voices are typeset on staves of their own, so there's
+    /// only ever one.
+    pub voices: Vec<Vec<EntityLayout>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct EntityLayout {
+    /// What's drawn, with abcjs's names, e.g. "note", "bar" or "keySignature".
+    pub el_type: &'static str,
+
+    /// For barlines, abcjs's name for the kind, e.g. "bar_thin" or "bar_left_repeat".
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub bar_type: Option<&'static str>,
+
+    pub x: f32,
+    pub width: f32,
+
+    /// For notes, the height of the centre of the head.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f32>,
+
+    /// For notes, the index among the notes in the stave's voice, in order.
+    #[serde(skip)]
+    pub note: Option<usize>,
+
+    /// For notes, the range of characters in the ABC, where known.
+    #[serde(rename = "startChar", skip_serializing_if = "Option::is_none")]
+    pub start_char: Option<usize>,
+    #[serde(rename = "endChar", skip_serializing_if = "Option::is_none")]
+    pub end_char: Option<usize>,
+}
+
+/// Text from the tune's header fields, shown above the score.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+struct Header {
+    // Centred, the first one larger than the rest.
+    titles: Vec<String>,
+
+    // Right-aligned.
+    composers: Vec<String>,
+
+    // Left-aligned, rhythm first.
+    tempos: Vec<String>,
+}
+
+impl Header {
+    fn from_prelude(prelude: &[l::T]) -> Option<Header> {
+        let mut header = Header {
+            titles: vec![],
+            composers: vec![],
+            tempos: vec![],
+        };
+
+        for token in prelude.iter() {
+            match token {
+                l::T::Title(x) => header.titles.push(x.clone()),
+                l::T::Composer(x) => header.composers.push(x.clone()),
+                l::T::Rhythm(x) => header.tempos.insert(0, x.clone()),
+                l::T::Tempo(x) => header.tempos.push(x.clone()),
+                _ => (),
+            }
+        }
+
+        // Blank fields aren't worth the space.
+        header.titles.retain(|x| !x.trim().is_empty());
+        header.composers.retain(|x| !x.trim().is_empty());
+        header.tempos.retain(|x| !x.trim().is_empty());
+
+        if header.titles.is_empty() && header.composers.is_empty() && header.tempos.is_empty() {
+            None
+        } else {
+            Some(header)
+        }
+    }
+
+    /// Font sizes of the title lines.
+    fn title_sizes(&self, fonts: &svg::Fonts) -> Vec<f32> {
+        (0..self.titles.len())
+            .map(|i| if i == 0 { fonts.title } else { fonts.subtitle })
+            .collect()
+    }
+
+    fn height(&self, fonts: &svg::Fonts) -> f32 {
+        let titles: f32 = self.title_sizes(fonts).iter().sum();
+
+        // Composers and tempos share lines below the titles.
+        let others = f32::max(
+            self.composers.len() as f32 * fonts.composer,
+            self.tempos.len() as f32 * fonts.tempo,
+        );
+
+        (titles + others) * HEADER_LINE_SPACING + SYSTEM_V_MARGIN
+    }
+
+    /// Render across the top of the given width.
+    fn render(&self, svg: &mut svg::Drawing, width: f32, fonts: &svg::Fonts) {
+        let mut y = 0.0;
+
+        for (i, (title, size)) in self.titles.iter().zip(self.title_sizes(fonts)).enumerate() {
+            y += size * HEADER_LINE_SPACING;
+            svg.text_styled(
+                width / 2.0,
+                y,
+                title.clone(),
+                svg::TextStyle {
+                    size,
+                    anchor: svg::Anchor::Middle,
+                    class: Some(if i == 0 { "title" } else { "subtitle" }),
+                    colour: None,
+                },
+            );
+        }
+
+        let mut composer_y = y;
+        for composer in self.composers.iter() {
+            composer_y += fonts.composer * HEADER_LINE_SPACING;
+            svg.text_styled(
+                width,
+                composer_y,
+                composer.clone(),
+                svg::TextStyle {
+                    size: fonts.composer,
+                    anchor: svg::Anchor::End,
+                    class: Some("composer"),
+                    colour: None,
+                },
+            );
+        }
+
+        let mut tempo_y = y;
+        for tempo in self.tempos.iter() {
+            tempo_y += fonts.tempo * HEADER_LINE_SPACING;
+            svg.text_styled(
+                0.0,
+                tempo_y,
+                tempo.clone(),
+                svg::TextStyle {
+                    size: fonts.tempo,
+                    anchor: svg::Anchor::Start,
+                    class: Some("tempo"),
+                    colour: None,
+                },
+            );
+        }
+    }
+}
+
+/// Layout set by stylesheet directives in the header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Layout {
+    // From "%%scale", the size of the whole page.
+    scale: f32,
+
+    // From "%%staffwidth", where lines are broken.
+    stave_width: f32,
+}
+
+impl Layout {
+    fn from_prelude(prelude: &[l::T]) -> Layout {
+        let mut layout = Layout {
+            scale: 1.0,
+            stave_width: STAVE_WIDTH,
+        };
+
+        // Anything that doesn't make sense is ignored.
+        for token in prelude.iter() {
+            if let l::T::Directive(name, value) = token {
+                match name.as_ref() {
+                    "scale" => match value.parse::<f32>() {
+                        Ok(scale) if scale > 0.0 && scale.is_finite() => layout.scale = scale,
+                        _ => (),
+                    },
+                    "staffwidth" => {
+                        if let Some(width) = parse_length(value) {
+                            layout.stave_width = width;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        layout
+    }
+}
+
+// A length in a directive, e.g. "16cm", "6.5in" or "450pt", in the drawing's units.
+// Points are the default.
+fn parse_length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let (number, unit) = match value.len().checked_sub(2) {
+        Some(i) if value.is_char_boundary(i) => value.split_at(i),
+        _ => (value, ""),
+    };
+
+    let (number, px_per_unit) = match unit {
+        "cm" => (number, PX_PER_CM),
+        "in" => (number, PX_PER_INCH),
+        "pt" => (number, PX_PER_POINT),
+        _ => (value, PX_PER_POINT),
+    };
+
+    match number.trim().parse::<f32>() {
+        Ok(length) if length > 0.0 && length.is_finite() => Some(length * px_per_unit),
+        _ => None,
     }
 }
 
 /// A Page is made up of a number of boxes which span the page.
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct Page {
+    header: Option<Header>,
     boxes: Vec<HorizontalBox>,
+
+    // IDs of the voices, by index of the box each starts at.
+    voices: Indexed<String>,
+
+    // Messages for errors that aren't at any note.
+    errors: Vec<String>,
+
+    // Size to show the page at.
+    scale: f32,
 }
 
 impl Page {
     fn new() -> Page {
-        Page { boxes: vec![] }
+        Page {
+            header: None,
+            boxes: vec![],
+            voices: vec![],
+            errors: vec![],
+            scale: 1.0,
+        }
+    }
+
+    fn shown_header(&self, typesetting: &Typesetting) -> Option<&Header> {
+        if typesetting.header {
+            self.header.as_ref()
+        } else {
+            None
+        }
     }
 
-    fn render(&self, svg: &mut svg::Drawing) {
-        let mut y: f32 = 0.0;
+    // Height of the header, or zero if there isn't one.
+    fn header_height(&self, typesetting: &Typesetting) -> f32 {
+        match self.shown_header(typesetting) {
+            Some(header) => header.height(&typesetting.fonts),
+            None => 0.0,
+        }
+    }
+
+    // Where the music starts, below the header and any errors listed above it.
+    fn music_y(&self, typesetting: &Typesetting) -> f32 {
+        let errors_height = if self.errors.is_empty() {
+            0.0
+        } else {
+            self.errors.len() as f32 * ERROR_LINE_HEIGHT + SYSTEM_V_MARGIN
+        };
+
+        self.header_height(typesetting) + errors_height
+    }
+
+    fn render(&self, svg: &mut svg::Drawing, typesetting: &Typesetting) {
+        let mut y = self.header_height(typesetting);
+        for message in self.errors.iter() {
+            y += ERROR_LINE_HEIGHT;
+            svg.text_styled(0.0, y, message.clone(), error_style());
+        }
+
+        let mut y = self.music_y(typesetting);
         for horizontal_box in self.boxes.iter() {
             horizontal_box.render(svg, y);
 
             y += horizontal_box.height();
         }
+
+        // The header spans the music, so goes in once its width is known.
+        if let Some(header) = self.shown_header(typesetting) {
+            let width = svg.width();
+            header.render(svg, width, &typesetting.fonts);
+        }
+    }
+}
+
+// Error messages are small and red.
+fn error_style() -> svg::TextStyle {
+    svg::TextStyle {
+        size: ERROR_FONT_SIZE,
+        class: Some("error"),
+        colour: Some("red"),
+        ..svg::TextStyle::new()
     }
 }
 
+// Things belonging to a stave's entities, with the index of the entity.
+type Indexed<T> = Vec<(usize, T)>;
+
+// Split indexed things when the stave is broken before `break_i`, into those that stay and those
+// that move, re-indexed to follow `offset` entities.
+fn split_indexed<T>(items: Indexed<T>, break_i: usize, offset: usize) -> (Indexed<T>, Indexed<T>) {
+    let (kept, moved): (Indexed<T>, Indexed<T>) =
+        items.into_iter().partition(|(i, _)| *i < break_i);
+
+    (
+        kept,
+        moved
+            .into_iter()
+            .map(|(i, item)| (i - break_i + offset, item))
+            .collect(),
+    )
+}
+
 /// A box that spans the page.
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 enum HorizontalBox {
@@ -70,13 +483,13 @@ enum HorizontalBox {
 impl HorizontalBox {
     fn height(&self) -> f32 {
         match self {
-            &HorizontalBox::System(ref stave) => stave.height() + SYSTEM_V_MARGIN,
+            HorizontalBox::System(stave) => stave.height() + SYSTEM_V_MARGIN,
         }
     }
 
     fn render(&self, svg: &mut svg::Drawing, y: f32) {
         match self {
-            &HorizontalBox::System(ref stave) => stave.render(svg, y),
+            HorizontalBox::System(stave) => stave.render(svg, y),
         }
     }
 }
@@ -90,15 +503,210 @@ enum Glyph {
     CloseRepeat,
     /// Note head of (position-on-stave)
     /// If we're unable to determine the glyph, can be none.
-    NoteHead(i32, Option<music::DurationGlyph>),
+    /// Accidentals written in the ABC are shown before the head.
+    NoteHead(i32, Option<music::DurationGlyph>, Option<music::Accidental>),
     Clef(music::Clef),
+    /// One sharp or flat of a key signature, at (position-on-stave).
+    KeyAccidental(i32, music::Accidental),
+    TimeSignature(music::Metre),
     BeamBreak,
+    /// Start of an n-time ending, e.g. the "2" in ":|2", which takes up no space itself.
+    /// None continues an ending from the line before.
+    Ending(Option<u32>),
+}
+
+impl Glyph {
+    /// Name of the kind of glyph, for the layout.
+    // abcjs's element type for the glyph.
+    fn el_type(&self) -> &'static str {
+        match self {
+            Glyph::SingleBar
+            | Glyph::DoubleBar
+            | Glyph::EndBar
+            | Glyph::OpenRepeat
+            | Glyph::CloseRepeat => "bar",
+            Glyph::NoteHead(_, _, _) => "note",
+            Glyph::Clef(_) => "clef",
+            Glyph::KeyAccidental(_, _) => "keySignature",
+            Glyph::TimeSignature(_) => "timeSignature",
+            Glyph::BeamBreak => "beamBreak",
+            Glyph::Ending(_) => "ending",
+        }
+    }
+
+    // abcjs's name for the kind of barline.
+    fn bar_type(&self) -> Option<&'static str> {
+        match self {
+            Glyph::SingleBar => Some("bar_thin"),
+            Glyph::DoubleBar => Some("bar_thin_thin"),
+            Glyph::EndBar => Some("bar_thin_thick"),
+            Glyph::OpenRepeat => Some("bar_left_repeat"),
+            Glyph::CloseRepeat => Some("bar_right_repeat"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum StemDirection {
+    Up,
+    Down,
+}
+
+/// Where a note's stem goes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Stem {
+    direction: StemDirection,
+
+    // Vertical offset of the far end of the stem within the stave.
+    end: f32,
+
+    // Beamed notes don't have tails.
+    beamed: bool,
+}
+
+fn draw_tail(svg: &mut svg::Drawing, x: f32, y: f32, direction: StemDirection) {
+    match direction {
+        StemDirection::Up => svg.line_path(x, y, "M0 0 l2 1 l5 3 l2 14 l-2 5".to_string()),
+        StemDirection::Down => svg.line_path(x, y, "M0 0 l2 -1 l5 -3 l2 -14 l-2 -5".to_string()),
+    }
 }
 
-impl Glyph {}
+/// Treble clef, with x, y on the G line where the curl is centred.
+/// Straight lines only, so the same path can be rasterised.
+fn draw_treble_clef(svg: &mut svg::Drawing, x: f32, y: f32) {
+    svg.line_path(
+        x,
+        y,
+        "M13 -2 L9 0 L9 4 L13 6 L18 5 L21 0 L19 -6 L14 -10 L6 -9 L2 -4 L0 2 L2 9 L8 13 L15 14 \
+         L22 11 L25 4 L24 -5 L19 -13 L11 -21 L5 -29 L2 -38 L5 -50 L10 -59 L14 -64 L17 -59 \
+         L17 -51 L13 -43 L13 24 L11 29 L6 30 L2 27 L4 23 L8 23"
+            .to_string(),
+    );
+}
 
-fn draw_tail(svg: &mut svg::Drawing, x: f32, y: f32) {
-    svg.line_path(x, y, "M0 0 l2 1 l5 3 l2 14 l-2 5".to_string());
+/// Bass clef, with x, y on the F line, between the dots.
+fn draw_bass_clef(svg: &mut svg::Drawing, x: f32, y: f32) {
+    svg.circle(x + 4.0, y, 3.5, true);
+    svg.line_path(
+        x,
+        y,
+        "M4 0 L1 -5 L3 -12 L9 -17 L17 -18 L24 -13 L27 -4 L26 6 L21 17 L13 27 L2 36".to_string(),
+    );
+    svg.circle(x + 32.0, y - HEAD_HEIGHT, 2.0, true);
+    svg.circle(x + 32.0, y + HEAD_HEIGHT, 2.0, true);
+}
+
+/// Alto clef, with x, y on the middle line where the two curves meet.
+fn draw_alto_clef(svg: &mut svg::Drawing, x: f32, y: f32) {
+    svg.rect(x, y - 40.0, 5.0, 80.0);
+    svg.line_path(
+        x,
+        y,
+        "M9 -40 L9 40 M9 0 L14 -8 L16 -2 L19 -16 L24 -22 L30 -21 L32 -14 L31 -7 L26 -3 L21 -6 \
+         M9 0 L14 8 L16 2 L19 16 L24 22 L30 21 L32 14 L31 7 L26 3 L21 6"
+            .to_string(),
+    );
+}
+
+/// Sharp, flat or natural, with x, y on the line or space it applies to.
+fn draw_accidental(svg: &mut svg::Drawing, x: f32, y: f32, accidental: music::Accidental) {
+    let path = match accidental {
+        music::Accidental::Sharp => "M2 -10 l0 20 M6 -12 l0 20 M0 -2 l8 -3 M0 5 l8 -3",
+        music::Accidental::Flat => "M0 -15 L0 5 l5 -4 l1 -3 l-2 -2 l-4 2",
+        music::Accidental::Natural => "M0 -10 L0 4 l7 -2 M7 10 L7 -4 l-7 2",
+        music::Accidental::DoubleSharp => "M0 -4 l8 8 M0 4 l8 -8",
+        music::Accidental::DoubleFlat => {
+            "M0 -15 L0 5 l5 -4 l1 -3 l-2 -2 l-4 2 M6 -15 L6 5 l5 -4 l1 -3 l-2 -2 l-4 2"
+        }
+    };
+
+    svg.line_path(x, y, path.to_string());
+}
+
+/// Horizontal space taken by an accidental before a note, including a gap before the head.
+fn accidental_width(accidental: music::Accidental) -> f32 {
+    match accidental {
+        music::Accidental::DoubleFlat => 16.0,
+        _ => 12.0,
+    }
+}
+
+/// Path of each digit on a grid 4 units wide and 8 high, from the top left.
+fn digit_path(digit: u32) -> &'static str {
+    match digit {
+        0 => "M0 1 L1 0 L3 0 L4 1 L4 7 L3 8 L1 8 L0 7 L0 1",
+        1 => "M1 2 L2.5 0 L2.5 8 M1 8 L4 8",
+        2 => "M0 1.5 L1 0 L3 0 L4 1 L4 3 L0 8 L4 8",
+        3 => "M0 0 L4 0 L2 3.5 L3 3.5 L4 4.5 L4 7 L3 8 L1 8 L0 7",
+        4 => "M3 8 L3 0 L0 5.5 L4 5.5",
+        5 => "M4 0 L0.5 0 L0 3.5 L3 3.5 L4 4.5 L4 7 L3 8 L0.5 8 L0 7.5",
+        6 => "M3.5 0 L1 1.5 L0 4 L0 7 L1 8 L3 8 L4 7 L4 5 L3 4 L1 4 L0 5",
+        7 => "M0 0 L4 0 L1.5 8",
+        8 => "M1 4 L0 3 L0 1 L1 0 L3 0 L4 1 L4 3 L3 4 L1 4 L0 5 L0 7 L1 8 L3 8 L4 7 L4 5 L3 4",
+        _ => "M4 3 L3 4 L1 4 L0 3 L0 1 L1 0 L3 0 L4 1 L4 4 L3 6.5 L0.5 8",
+    }
+}
+
+/// Width of a number drawn with draw_number().
+fn number_width(number: u32) -> f32 {
+    let digits = number.to_string().len() as f32;
+    digits * DIGIT_GRID_WIDTH * DIGIT_SCALE + (digits - 1.0) * HALF_HEAD_HEIGHT
+}
+
+/// A number for a time signature, with x, y at the top left.
+fn draw_number(svg: &mut svg::Drawing, x: f32, y: f32, number: u32) {
+    let mut path = vec![];
+    for (i, digit) in number.to_string().chars().enumerate() {
+        let left = i as f32 * (DIGIT_GRID_WIDTH * DIGIT_SCALE + HALF_HEAD_HEIGHT);
+
+        // Digit paths only use absolute coordinates, which alternate between x and y.
+        let digit_path = digit_path(digit.to_digit(10).unwrap_or(0));
+        for (j, word) in digit_path.split_whitespace().enumerate() {
+            let value = word.trim_start_matches(char::is_alphabetic);
+            let command = &word[..word.len() - value.len()];
+            let value = value.parse::<f32>().unwrap_or(0.0) * DIGIT_SCALE;
+            let value = if j % 2 == 0 { value + left } else { value };
+            path.push(format!("{}{}", command, value));
+        }
+    }
+
+    svg.line_path(x, y, path.join(" "));
+}
+
+/// Position on the stave for a sharp or flat in a key signature.
+/// Sharps go as high as the top line, flats as high as the space below it, as for treble clef.
+fn key_accidental_position(clef: music::Clef, pitch_class: music::PitchClass) -> i32 {
+    let clef_degree = clef.pitch.pitch_class.diatonic_pitch_class.to_degree()
+        + music::NOTES_IN_SCALE * clef.pitch.octave;
+    let position =
+        (pitch_class.diatonic_pitch_class.to_degree() - clef_degree) as i32 + clef.centre;
+
+    let lowest = match pitch_class.accidental {
+        Some(music::Accidental::Flat) | Some(music::Accidental::DoubleFlat) => 1,
+        _ => 3,
+    } + clef.shape.key_signature_offset();
+
+    lowest + (position - lowest).rem_euclid(music::NOTES_IN_SCALE as i32)
+}
+
+/// Entities for a key signature in the given clef.
+fn key_signature_entities(
+    clef: music::Clef,
+    key_note: music::PitchClass,
+    mode: music::Mode,
+) -> Vec<Entity> {
+    key_note
+        .key_signature(mode)
+        .iter()
+        .filter_map(|pitch_class| {
+            pitch_class.accidental.map(|accidental| {
+                Entity::new(Glyph::KeyAccidental(
+                    key_accidental_position(clef, *pitch_class),
+                    accidental,
+                ))
+            })
+        }).collect()
 }
 
 /// Entity
@@ -111,7 +719,7 @@ struct Entity {
 impl Entity {
     fn new(glyph: Glyph) -> Entity {
         Entity {
-            glyph: glyph,
+            glyph,
             x: 0.0,
         }
     }
@@ -120,8 +728,7 @@ impl Entity {
     fn is_front_matter(&self) -> bool {
         match self.glyph {
             // Normal front matter things.
-            // TODO time signature, key signature.
-            Glyph::Clef(_) => true,
+            Glyph::Clef(_) | Glyph::KeyAccidental(_, _) | Glyph::TimeSignature(_) => true,
 
             // Any kind of barline should be part of front matter.
             // Even weird things that shouldn't be there like close repeat.
@@ -133,30 +740,77 @@ impl Entity {
 
             // Notehead and friends are definitely out.
             // TODO no catch-all until all glyph types initially settled.
-            Glyph::NoteHead(_, _) => false,
+            Glyph::NoteHead(_, _, _) => false,
 
             Glyph::BeamBreak => false,
+
+            Glyph::Ending(_) => false,
         }
     }
 
+    /// Is this anything other than a clef, key or time signature?
+    fn has_music(&self) -> bool {
+        !matches!(
+            self.glyph,
+            Glyph::Clef(_) | Glyph::KeyAccidental(_, _) | Glyph::TimeSignature(_)
+        )
+    }
+
+    /// Does this barline finish an n-time ending?
+    fn ends_ending(&self) -> bool {
+        matches!(
+            self.glyph,
+            Glyph::DoubleBar | Glyph::EndBar | Glyph::OpenRepeat | Glyph::CloseRepeat
+        )
+    }
+
     /// Does this constitute the type of glyph that should be included in end matter?
     fn is_end_matter(&self) -> bool {
+        matches!(
+            self.glyph,
+            Glyph::SingleBar
+                | Glyph::DoubleBar
+                | Glyph::EndBar
+                | Glyph::OpenRepeat
+                | Glyph::CloseRepeat
+        )
+    }
+
+    /// Natural width, including the spring after it.
+    fn width(&self) -> f32 {
+        self.rigid_width() + self.spring()
+    }
+
+    /// Space after a note that stretches to justify the line, proportional to the log of its
+    /// duration so that longer notes take up more room.
+    fn spring(&self) -> f32 {
         match self.glyph {
+            Glyph::NoteHead(_, Some(glyph), _) => {
+                let music::FractionalDuration(numerator, denominator) = glyph.duration();
+                let doublings = (numerator as f32 / denominator as f32 / SHORTEST_DURATION)
+                    .log2()
+                    .max(0.0);
+                SPRING_BASE + SPRING_PER_DOUBLING * doublings
+            }
+            Glyph::NoteHead(_, None, _) => SPRING_BASE,
+
+            // Keep the next note off a barline in the middle of the line.
             Glyph::SingleBar
             | Glyph::DoubleBar
             | Glyph::EndBar
             | Glyph::OpenRepeat
-            | Glyph::CloseRepeat => true,
-            _ => false,
+            | Glyph::CloseRepeat => SPRING_BASE,
+
+            _ => 0.0,
         }
     }
 
-    fn width(&self) -> f32 {
+    /// Width that doesn't stretch.
+    fn rigid_width(&self) -> f32 {
         match self.glyph {
-            // TODO number of dots will make a difference.
-            Glyph::NoteHead(_, glyph) => {
-                // Space for the head.
-                HEAD_WIDTH * 2.0 +
+            Glyph::NoteHead(_, glyph, _) => {
+                // Space for the accidental and the head.
+                self.head_offset() + HEAD_WIDTH +
                     // Space for the dots.
                     match glyph {
                         Some(music::DurationGlyph { shape: _, dots }) => HEAD_WIDTH * dots as f32,
@@ -164,43 +818,90 @@ impl Entity {
                     }
             }
 
-            // TODO add padding, but in a way that is flush with the end of the line.
+            // Padding after a barline is its spring, which is left off at the end of the line.
             Glyph::SingleBar => 1.0,
             Glyph::DoubleBar => 3.0,
             Glyph::EndBar => 8.0,
             Glyph::OpenRepeat => 20.0,
             Glyph::CloseRepeat => 10.0,
 
-            Glyph::Clef(_) => 50.0,
+            Glyph::Clef(_) => 35.0,
+
+            Glyph::KeyAccidental(_, _) => KEY_ACCIDENTAL_WIDTH,
+
+            Glyph::TimeSignature(music::Metre(numerator, denominator)) => {
+                f32::max(number_width(numerator), number_width(denominator)) + HEAD_WIDTH
+            }
 
             // Beam breaks are invisible.
             Glyph::BeamBreak => 0.0,
+
+            // Endings are drawn above the stave, over the entities that follow.
+            Glyph::Ending(_) => 0.0,
         }
     }
 
-    /// The absolute coordinate of the end of this Entity's glyph's tail.
-    /// Only applies to NoteHeads, and only those that have tails.
-    /// TODO currently assumes only up.
-    fn tail_anchor(&self) -> Option<(f32, f32)> {
+    /// Vertical offset of the centre of a note head at this position, within the stave.
+    fn head_y(position: i32) -> f32 {
+        (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT
+    }
+
+    /// Horizontal offset of the stem within the stave. Stems go on the right going up, the left
+    /// going down.
+    fn stem_x(&self, direction: StemDirection) -> f32 {
+        match direction {
+            StemDirection::Up => self.x + self.head_offset() + HEAD_WIDTH,
+            StemDirection::Down => self.x + self.head_offset(),
+        }
+    }
+
+    /// How far the note head is from the start of the entity, leaving room for an accidental.
+    fn head_offset(&self) -> f32 {
         match self.glyph {
-            Glyph::NoteHead(position, duration) => {
-                match duration {
-                    Some(music::DurationGlyph { shape: _, dots: _ }) => {
-                        let y = (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
+            Glyph::NoteHead(_, _, Some(accidental)) => accidental_width(accidental),
+            _ => 0.0,
+        }
+    }
 
-                        // TODO switch on direction.
-                        Some((self.x + HEAD_WIDTH, y - STEM_HEIGHT))
-                    }
+    /// How many beams this note would take, or zero if it can't be beamed.
+    fn beams(&self) -> u32 {
+        match self.glyph {
+            Glyph::NoteHead(_, Some(duration), _) => duration.shape.beams(),
+            _ => 0,
+        }
+    }
 
-                    None => None,
-                }
+    /// The stem of a note on its own. Only applies to NoteHeads that have stems.
+    fn natural_stem(&self) -> Option<Stem> {
+        match self.glyph {
+            Glyph::NoteHead(_, None, _) => None,
+            Glyph::NoteHead(_, Some(duration), _)
+                if duration.shape == music::DurationClass::Semibreve =>
+            {
+                None
+            }
+            Glyph::NoteHead(position, Some(_), _) => {
+                let head_y = Entity::head_y(position);
+
+                Some(if position >= MIDDLE_POSITION {
+                    Stem {
+                        direction: StemDirection::Down,
+                        end: head_y + STEM_HEIGHT,
+                        beamed: false,
+                    }
+                } else {
+                    Stem {
+                        direction: StemDirection::Up,
+                        end: head_y - STEM_HEIGHT,
+                        beamed: false,
+                    }
+                })
             }
-
             _ => None,
         }
     }
 
-    fn render(&self, svg: &mut svg::Drawing, x: f32, y: f32) {
+    fn render(&self, svg: &mut svg::Drawing, x: f32, y: f32, stem: Option<Stem>) {
         // x in argument is the general offset, i.e. left margin.
         // self.x is the offset within the stave.
         let x = x + self.x;
@@ -209,8 +910,36 @@ impl Entity {
             Glyph::Clef(clef) => {
                 let yy = y + (LINES_IN_STAVE - clef.centre) as f32 * HEAD_HEIGHT;
 
-                svg.rect(x, yy - HEAD_HEIGHT / 2.0, 10.0, HEAD_HEIGHT);
-                svg.text(x, yy - HEAD_HEIGHT / 2.0, "clef".to_string());
+                match clef.shape {
+                    music::ClefShape::Treble => draw_treble_clef(svg, x, yy),
+                    music::ClefShape::Bass => draw_bass_clef(svg, x, yy),
+                    music::ClefShape::Alto => draw_alto_clef(svg, x, yy),
+                }
+            }
+            Glyph::KeyAccidental(position, accidental) => {
+                let yy = y + (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
+                draw_accidental(svg, x, yy, accidental);
+            }
+            Glyph::TimeSignature(music::Metre(numerator, denominator)) => {
+                // Each number fills half the stave, centred on the wider one.
+                let width = self.width() - HEAD_WIDTH;
+                let top = y + HEAD_HEIGHT + (HEAD_HEIGHT * 8.0 - DIGIT_SCALE * 16.0) / 4.0;
+                let middle = y
+                    + (LINES_IN_STAVE - 4) as f32 * HEAD_HEIGHT
+                    + (HEAD_HEIGHT * 8.0 - DIGIT_SCALE * 16.0) / 4.0;
+
+                draw_number(
+                    svg,
+                    x + (width - number_width(numerator)) / 2.0,
+                    top,
+                    numerator,
+                );
+                draw_number(
+                    svg,
+                    x + (width - number_width(denominator)) / 2.0,
+                    middle,
+                    denominator,
+                );
             }
             Glyph::SingleBar => {
                 svg.rect(
@@ -295,105 +1024,66 @@ impl Entity {
                 );
             }
 
-            Glyph::NoteHead(position, glyph) => {
-                let yy = y + (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
+            Glyph::NoteHead(position, glyph, accidental) => {
+                let yy = y + Entity::head_y(position);
+
+                if let Some(accidental) = accidental {
+                    draw_accidental(svg, x, yy, accidental);
+                }
+                let head_x = x + self.head_offset();
 
                 match glyph {
                     None => {
-                        svg.text(x, yy, "?".to_string());
+                        svg.text(head_x, yy, "?".to_string());
                     }
                     Some(music::DurationGlyph { shape, dots }) => {
                         // Note head
-                        match shape {
-                            music::DurationClass::Semibreve | music::DurationClass::Minim => {
-                                svg.circle(
-                                    x + HEAD_WIDTH / 2.0,
-                                    yy + HEAD_WIDTH / 2.0,
-                                    HEAD_WIDTH / 2.0,
-                                    false,
-                                );
-                            }
-
-                            music::DurationClass::Crotchet
-                            | music::DurationClass::Quaver
-                            | music::DurationClass::Semiquaver
-                            | music::DurationClass::Demisemiquaver => {
-                                svg.circle(
-                                    x + HEAD_WIDTH / 2.0,
-                                    yy + HEAD_WIDTH / 2.0,
-                                    HEAD_WIDTH / 2.0,
-                                    true,
-                                );
-                            }
-                        }
-
-                        if let Some((stem_x, stem_y)) = self.tail_anchor() {
-                            // Stem
-                            match shape {
-                                music::DurationClass::Minim
-                                | music::DurationClass::Crotchet
-                                | music::DurationClass::Quaver
-                                | music::DurationClass::Semiquaver
-                                | music::DurationClass::Demisemiquaver => {
-                                    svg.line(stem_x, stem_y + y, stem_x, yy);
+                        let filled = !matches!(
+                            shape,
+                            music::DurationClass::Semibreve | music::DurationClass::Minim
+                        );
+                        svg.circle(head_x + HEAD_WIDTH / 2.0, yy, HEAD_WIDTH / 2.0, filled);
+
+                        if let Some(stem) = stem {
+                            let stem_x = x - self.x + self.stem_x(stem.direction);
+                            svg.line(stem_x, yy, stem_x, y + stem.end);
+
+                            // Tails step back towards the note head.
+                            if !stem.beamed {
+                                for tail in 0..shape.beams() {
+                                    let offset = HALF_HEAD_HEIGHT + tail as f32 * 8.0;
+                                    let tail_y = match stem.direction {
+                                        StemDirection::Up => y + stem.end + offset,
+                                        StemDirection::Down => y + stem.end - offset,
+                                    };
+                                    draw_tail(svg, stem_x, tail_y, stem.direction);
                                 }
-
-                                _ => (),
-                            }
-
-                            // Tail 1
-                            match shape {
-                                music::DurationClass::Quaver
-                                | music::DurationClass::Semiquaver
-                                | music::DurationClass::Demisemiquaver => {
-                                    draw_tail(svg, x + HEAD_WIDTH, stem_y + y + HALF_HEAD_HEIGHT);
-                                }
-
-                                _ => (),
-                            }
-
-                            // Tail 2
-                            match shape {
-                                music::DurationClass::Semiquaver
-                                | music::DurationClass::Demisemiquaver => {
-                                    draw_tail(
-                                        svg,
-                                        x + HEAD_WIDTH,
-                                        stem_y + y + HALF_HEAD_HEIGHT + 8.0,
-                                    );
-                                }
-
-                                _ => (),
-                            }
-
-                            // Tail 3
-                            match shape {
-                                music::DurationClass::Demisemiquaver => {
-                                    draw_tail(
-                                        svg,
-                                        x + HEAD_WIDTH,
-                                        stem_y + y + HALF_HEAD_HEIGHT + 16.0,
-                                    );
-                                }
-
-                                _ => (),
                             }
                         }
 
+                        // Dots go in a space, so move up off a line.
+                        let dot_y = if position % 2 == 0 {
+                            yy - HALF_HEAD_HEIGHT
+                        } else {
+                            yy
+                        };
                         for dot in 0..dots {
                             svg.circle(
-                                x + HEAD_WIDTH + (dot + 2) as f32 * HEAD_HEIGHT * 0.5,
-                                yy - HEAD_HEIGHT / 2.0,
+                                head_x + HEAD_WIDTH + (dot + 2) as f32 * HEAD_HEIGHT * 0.5,
+                                dot_y,
                                 2.0,
                                 true,
                             );
                         }
-                    } // svg.rect_fill(x, yy - HEAD_HEIGHT / 2.0, HEAD_HEIGHT * 1.5, HEAD_HEIGHT);
+                    }
                 }
             }
 
             // As a glyph this doesn't render.
             Glyph::BeamBreak => (),
+
+            // The stave draws these once it knows where they finish.
+            Glyph::Ending(_) => (),
         }
     }
 }
@@ -401,20 +1091,335 @@ impl Entity {
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 struct Stave {
     entities: Vec<Entity>,
+
+    // Fingerings to show under notes, by index of the note's entity.
+    annotations: Vec<(usize, fingering::Fingering)>,
+
+    // Error messages to mark at notes, by index of the note's entity.
+    errors: Vec<(usize, String)>,
+
+    // Numbers of the bars that start on this stave, by index of their first note's entity.
+    bar_numbers: Indexed<usize>,
+
+    // Name of the voice, shown above the stave.
+    label: Option<String>,
+
+    // Width to justify to, and to break lines at.
+    width: f32,
 }
 
 impl Stave {
     fn new() -> Stave {
-        Stave { entities: vec![] }
+        Stave {
+            entities: vec![],
+            annotations: vec![],
+            errors: vec![],
+            bar_numbers: vec![],
+            label: None,
+            width: STAVE_WIDTH,
+        }
+    }
+
+    // Start a stave for the voice, with its clef and key signature.
+    // The time signature is only shown on the first stave, or when it changes.
+    fn for_voice(
+        clef: music::Clef,
+        key: (music::PitchClass, music::Mode),
+        metre: Option<music::Metre>,
+        label: Option<String>,
+        width: f32,
+    ) -> Stave {
+        let mut stave = Stave::new();
+        stave.entities.push(Entity::new(Glyph::Clef(clef)));
+        stave
+            .entities
+            .extend(key_signature_entities(clef, key.0, key.1));
+        if let Some(metre) = metre {
+            stave
+                .entities
+                .push(Entity::new(Glyph::TimeSignature(metre)));
+        }
+        stave.label = label;
+        stave.width = width;
+        stave
+    }
+
+    // Width of everything on the stave without justification.
+    fn natural_width(&self) -> f32 {
+        self.entities.iter().map(|x| x.width()).sum()
+    }
+
+    /// If the stave is too wide for the page, move the end of it onto the continuation stave,
+    /// which has its own front matter, and return what's left. Breaks after the last barline that
+    /// fits if there is one, otherwise before the last entity.
+    fn break_line(&mut self, continuation: Stave) -> Option<Stave> {
+        if self.natural_width() <= self.width {
+            return None;
+        }
+
+        // The front matter always stays where it is.
+        let front_matter = self.entities.iter().take_while(|x| !x.has_music()).count();
+
+        let mut width = 0.0;
+        let mut break_i = None;
+        for (i, entity) in self.entities.iter().enumerate() {
+            width += entity.width();
+            if width > self.width {
+                break;
+            }
+
+            if i >= front_matter && entity.is_end_matter() {
+                break_i = Some(i + 1);
+            }
+        }
+
+        let break_i = match break_i {
+            Some(break_i) => break_i,
+            None if self.entities.len() > front_matter + 1 => self.entities.len() - 1,
+            None => return None,
+        };
+
+        // Swap the continuation in as this stave, and move the entities, annotations and errors
+        // over.
+        let mut full_stave = std::mem::replace(self, continuation);
+        if Stave::open_ending(&full_stave.entities[..break_i]) {
+            self.entities.push(Entity::new(Glyph::Ending(None)));
+        }
+        let offset = self.entities.len();
+        self.entities.extend(full_stave.entities.split_off(break_i));
+
+        let (kept, moved) = split_indexed(full_stave.annotations, break_i, offset);
+        full_stave.annotations = kept;
+        self.annotations.extend(moved);
+
+        let (kept, moved) = split_indexed(full_stave.errors, break_i, offset);
+        full_stave.errors = kept;
+        self.errors.extend(moved);
+
+        let (kept, moved) = split_indexed(full_stave.bar_numbers, break_i, offset);
+        full_stave.bar_numbers = kept;
+        self.bar_numbers.extend(moved);
+
+        Some(full_stave)
+    }
+
+    // Is an n-time ending still going at the end of these entities?
+    fn open_ending(entities: &[Entity]) -> bool {
+        let mut open = false;
+        for entity in entities.iter() {
+            if let Glyph::Ending(_) = entity.glyph {
+                open = true;
+            } else if entity.ends_ending() {
+                open = false;
+            }
+        }
+        open
+    }
+
+    fn has_endings(&self) -> bool {
+        self.entities
+            .iter()
+            .any(|x| matches!(x.glyph, Glyph::Ending(_)))
+    }
+
+    // The number to show at the start of the stave, if it starts with a new bar. The first bar
+    // isn't numbered, and nor is a pick-up before it.
+    fn first_bar_number(&self) -> Option<usize> {
+        let first_note = self
+            .entities
+            .iter()
+            .position(|x| matches!(x.glyph, Glyph::NoteHead(_, _, _)));
+
+        match self.bar_numbers.first() {
+            Some(&(i, number)) if Some(i) == first_note && number > 1 => Some(number),
+            _ => None,
+        }
+    }
+
+    // Is there anything on this stave apart from the clef, key and time signature?
+    fn has_music(&self) -> bool {
+        self.entities.iter().any(|x| x.has_music())
     }
 
     fn height(&self) -> f32 {
         // TODO Include size of stave, ledger lines, etc.
         // Currently this is 5 lines and spaces + one space either side.
-        (HEAD_HEIGHT * LINES_IN_STAVE as f32) + STAVE_V_MARGIN
+        let annotation_height = if self.annotations.is_empty() {
+            0.0
+        } else {
+            ANNOTATION_HEIGHT
+        };
+
+        let label_height = if self.label.is_some() {
+            LABEL_HEIGHT
+        } else {
+            0.0
+        };
+
+        let ending_height = if self.has_endings() {
+            ENDING_HEIGHT
+        } else {
+            0.0
+        };
+
+        let error_height = self.errors.len() as f32 * ERROR_LINE_HEIGHT;
+
+        (HEAD_HEIGHT * LINES_IN_STAVE as f32)
+            + STAVE_V_MARGIN
+            + annotation_height
+            + label_height
+            + ending_height
+            + error_height
     }
 
-    fn render(&self, svg: &mut svg::Drawing, y: f32) {
+    // Draw brackets over n-time endings, once the entities have been laid out.
+    // Each runs until the next ending, double bar or repeat, or the end of the line. Those that
+    // finish with a repeat are closed at the end.
+    fn render_endings(&self, svg: &mut svg::Drawing, entities: &[Entity], y: f32, width: f32) {
+        let draw = |svg: &mut svg::Drawing, start: f32, end: f32, number: Option<u32>, closed| {
+            svg.line(start, y, end, y);
+            if let Some(number) = number {
+                svg.line(start, y, start, y + ENDING_HOOK);
+                svg.text_styled(
+                    start + HALF_HEAD_HEIGHT,
+                    y + ENDING_HOOK - 2.0,
+                    format!("{}.", number),
+                    svg::TextStyle {
+                        size: ENDING_FONT_SIZE,
+                        anchor: svg::Anchor::Start,
+                        class: Some("ending"),
+                        colour: None,
+                    },
+                );
+            }
+            if closed {
+                svg.line(end, y, end, y + ENDING_HOOK);
+            }
+        };
+
+        // Start and number of the ending in progress, and whether it has any notes yet.
+        let mut current: Option<(f32, Option<u32>, bool)> = None;
+
+        for entity in entities.iter() {
+            match (entity.glyph, current) {
+                (Glyph::Ending(number), _) => {
+                    if let Some((start, previous, true)) = current {
+                        draw(svg, start, entity.x, previous, false);
+                    }
+                    current = Some((entity.x, number, false));
+                }
+
+                (Glyph::NoteHead(_, _, _), Some((start, number, _))) => {
+                    current = Some((start, number, true));
+                }
+
+                (_, Some((start, number, has_notes))) if entity.ends_ending() => {
+                    if has_notes {
+                        let closed = entity.glyph == Glyph::CloseRepeat;
+                        draw(svg, start, entity.x, number, closed);
+                    }
+                    current = None;
+                }
+
+                _ => (),
+            }
+        }
+
+        if let Some((start, number, true)) = current {
+            draw(svg, start, width, number, false);
+        }
+    }
+
+    // Highlight the notes with errors, once the entities have been laid out, with a line for each
+    // message above the stave starting where its note is.
+    fn render_errors(&self, svg: &mut svg::Drawing, entities: &[Entity], messages_y: f32, y: f32) {
+        for (line, (i, message)) in self.errors.iter().enumerate() {
+            let x = entities[*i].x + entities[*i].head_offset() - HALF_HEAD_HEIGHT;
+
+            svg.rect_error(
+                x,
+                y,
+                HEAD_WIDTH + HEAD_HEIGHT,
+                (LINES_IN_STAVE + 1) as f32 * HEAD_HEIGHT,
+            );
+            svg.text_styled(
+                x,
+                messages_y + (line + 1) as f32 * ERROR_LINE_HEIGHT,
+                message.clone(),
+                error_style(),
+            );
+        }
+    }
+
+    // Draw fingerings under the stave, once the entities have been laid out.
+    fn render_annotations(&self, svg: &mut svg::Drawing, entities: &[Entity], y: f32) {
+        // Leave room for notes below the stave.
+        let top = y + (LINES_IN_STAVE + 2) as f32 * HEAD_HEIGHT;
+
+        for (i, annotation) in self.annotations.iter() {
+            let x = entities[*i].x + entities[*i].head_offset();
+
+            match annotation {
+                // A column of holes, filled in when covered.
+                fingering::Fingering::Holes(holes, second_octave) => {
+                    for (hole, covered) in holes.iter().enumerate() {
+                        svg.circle(
+                            x + HEAD_WIDTH / 2.0,
+                            top + hole as f32 * HOLE_SPACING,
+                            HOLE_SPACING / 2.0 - 1.0,
+                            *covered,
+                        );
+                    }
+
+                    if *second_octave {
+                        svg.text_styled(
+                            x,
+                            top + (holes.len() + 1) as f32 * HOLE_SPACING,
+                            "+".to_string(),
+                            svg::TextStyle {
+                                class: Some("fingering"),
+                                ..svg::TextStyle::new()
+                            },
+                        );
+                    }
+                }
+
+                fingering::Fingering::Button { .. } => {
+                    svg.text_styled(
+                        x,
+                        top + HEAD_HEIGHT,
+                        annotation.to_string(),
+                        svg::TextStyle {
+                            class: Some("fingering"),
+                            ..svg::TextStyle::new()
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    // Vertical positions of the parts of a stave drawn from `y` down: the error messages, any
+    // n-time endings, then the stave itself. The label goes above everything else.
+    fn offsets(&self, y: f32) -> (f32, Option<f32>, f32) {
+        let y = match self.label {
+            Some(_) => y + LABEL_HEIGHT,
+            None => y,
+        };
+
+        let (messages_y, y) = (y, y + self.errors.len() as f32 * ERROR_LINE_HEIGHT);
+
+        let (ending_y, y) = if self.has_endings() {
+            (Some(y + HALF_HEAD_HEIGHT), y + ENDING_HEIGHT)
+        } else {
+            (None, y)
+        };
+
+        (messages_y, ending_y, y)
+    }
+
+    // Copies of the entities with their x positions, justified, and the width of the stave.
+    fn layout(&self) -> (Vec<Entity>, f32) {
         // Split the line in to three regions:
         // 1 - Front matter, including clef, time signature, key signature. This should be typeset
         //     to the same scale on every line.
@@ -445,56 +1450,109 @@ impl Stave {
         let mut entities: Vec<Entity> = Vec::from_iter(self.entities.iter().cloned());
 
         // Get the natural width of each section so we can work out the scale.
-        // The scale for the front and end matter is always 1.
-        // The scale for the justifiable section is whatever's left in the middle.
-        let front_matter_width: f32 = (&entities[..justifiable_start_i])
+        // The front and end matter are never stretched, and the end matter has no spring so it's
+        // flush with the end of the line.
+        // In the justifiable section only the springs after notes stretch, to fill whatever's left
+        // in the middle. The rest of the width is rigid, so notes never collide.
+        let front_matter_width: f32 = entities[..justifiable_start_i]
             .iter()
             .map(|x| x.width())
             .sum();
-        let end_matter_width: f32 = (&entities[justifiable_end_i..])
+        let end_matter_width: f32 = entities[justifiable_end_i..]
             .iter()
-            .map(|x| x.width())
+            .map(|x| x.rigid_width())
             .sum();
-        let justifiable_width: f32 = (&entities[justifiable_start_i..justifiable_end_i])
+        let rigid_width: f32 = entities[justifiable_start_i..justifiable_end_i]
             .iter()
-            .map(|x| x.width())
+            .map(|x| x.rigid_width())
+            .sum();
+        let springs: f32 = entities[justifiable_start_i..justifiable_end_i]
+            .iter()
+            .map(|x| x.spring())
             .sum();
 
-        // TODO prevent divide by zero
-        let _justifiable_scale =
-            (STAVE_WIDTH - (front_matter_width + end_matter_width)) / justifiable_width;
-
-        let justifiable_scale = f32::min(STAVE_WIDTH / justifiable_width, MINIMUM_STAVE_SCALE);
+        let available = self.width - (front_matter_width + end_matter_width + rigid_width);
+        let spring_scale = if springs > 0.0 {
+            (available / springs).clamp(0.0, MAXIMUM_SPRING_SCALE)
+        } else {
+            1.0
+        };
 
         // Stave width doesn't always add up to the ideal STAVE_WIDTH, i.e. a short stave for a
         // short line.
         let stave_width: f32 =
-            (justifiable_width * justifiable_scale) + front_matter_width + end_matter_width;
+            rigid_width + springs * spring_scale + front_matter_width + end_matter_width;
 
         // Lay out all the entities' x values.
         let mut x = 0.0;
-        for i in 0..justifiable_start_i {
-            entities[i].x = x;
-            x += entities[i].width() * 1.0;
+        for entity in entities[..justifiable_start_i].iter_mut() {
+            entity.x = x;
+            x += entity.width() * 1.0;
         }
 
-        for i in justifiable_start_i..justifiable_end_i {
-            entities[i].x = x;
-            x += entities[i].width() * justifiable_scale;
+        for entity in entities[justifiable_start_i..justifiable_end_i].iter_mut() {
+            entity.x = x;
+            x += entity.rigid_width() + entity.spring() * spring_scale;
         }
 
         // Need to wind back from the end so the right-hand edge aligns perfectly.
         x = stave_width - end_matter_width;
-        for i in justifiable_end_i..entities.len() {
-            entities[i].x = x;
-            x += entities[i].width() * 1.0;
+        for entity in entities[justifiable_end_i..].iter_mut() {
+            entity.x = x;
+            x += entity.rigid_width();
+        }
+
+        (entities, stave_width)
+    }
+
+    fn render(&self, svg: &mut svg::Drawing, y: f32) {
+        if let Some(ref label) = self.label {
+            svg.text_styled(
+                0.0,
+                y + LABEL_HEIGHT - HALF_HEAD_HEIGHT,
+                label.clone(),
+                svg::TextStyle {
+                    class: Some("label"),
+                    ..svg::TextStyle::new()
+                },
+            );
+        }
+
+        let (messages_y, ending_y, y) = self.offsets(y);
+        let (entities, stave_width) = self.layout();
+
+        if let Some(number) = self.first_bar_number() {
+            svg.text_styled(
+                0.0,
+                y,
+                number.to_string(),
+                svg::TextStyle {
+                    size: BAR_NUMBER_FONT_SIZE,
+                    anchor: svg::Anchor::Start,
+                    class: Some("bar-number"),
+                    colour: None,
+                },
+            );
+        }
+
+        // Beams decide the direction and length of their notes' stems.
+        let beams = beam_groups(&entities);
+        let mut stems: Vec<Option<Stem>> = entities.iter().map(|x| x.natural_stem()).collect();
+        for beam in beams.iter() {
+            for i in beam.notes.iter() {
+                stems[*i] = Some(Stem {
+                    direction: beam.direction,
+                    end: beam.y_at(entities[*i].stem_x(beam.direction)),
+                    beamed: true,
+                });
+            }
         }
 
         // Now typeset.
-        for entity in entities.iter() {
+        for (entity, stem) in entities.iter().zip(stems.iter()) {
             // The entity has its own offset within the stave. The 0.0 here is page margin.
             // TODO add page margin?
-            entity.render(svg, 0.0, y);
+            entity.render(svg, 0.0, y, *stem);
         }
 
         for bar_i in 0..LINES_IN_STAVE {
@@ -505,64 +1563,231 @@ impl Stave {
             }
         }
 
-        // Now draw beams.
+        self.render_annotations(svg, &entities, y);
+        self.render_errors(svg, &entities, messages_y, y);
 
-        // Start (most recent qualifying glyph entity) of this beam group.
-        let mut beam_start_i = None;
-        // End (most recent qualifying glyph entity) of beam group.
-        let mut beam_end_i = None;
+        if let Some(ending_y) = ending_y {
+            self.render_endings(svg, &entities, ending_y, stave_width);
+        }
 
-        for i in 0..entities.len() {
-            let entity = &entities[i];
+        for beam in beams.iter() {
+            beam.render(svg, &entities, y);
+        }
+    }
 
-            match entity.glyph {
-                Glyph::NoteHead(_, duration) => match duration {
-                    Some(duration) => {
-                        if duration.shape.beams() > 0 {
-                            if beam_start_i == None {
-                                beam_start_i = Some(i);
-                            } else {
-                                beam_end_i = Some(i);
-                            }
-                        }
-                    }
+    // Where the stave and its entities are when drawn at `y`. Notes are numbered from
+    // `first_note`. Beam breaks aren't drawn, so are left out.
+    fn layout_at(&self, y: f32, voice: &str, first_note: usize) -> StaveLayout {
+        let (_, _, y) = self.offsets(y);
+        let (entities, width) = self.layout();
 
-                    None => (),
-                },
+        let mut note = first_note;
+        let entities = entities
+            .iter()
+            .filter(|x| x.glyph != Glyph::BeamBreak)
+            .map(|entity| {
+                let mut result = EntityLayout {
+                    el_type: entity.glyph.el_type(),
+                    bar_type: entity.glyph.bar_type(),
+                    x: entity.x,
+                    width: entity.width(),
+                    y: None,
+                    note: None,
+                    start_char: None,
+                    end_char: None,
+                };
+
+                if let Glyph::NoteHead(position, _, _) = entity.glyph {
+                    result.y = Some(y + Entity::head_y(position));
+                    result.note = Some(note);
+                    note += 1;
+                }
 
-                Glyph::BeamBreak => {
-                    if let Some(start_i) = beam_start_i {
-                        if let Some(end_i) = beam_end_i {
-                            // draw beam
-                            // TODO next step is get notehead to show line up or down, then a
-                            // method called end_of_stalk to return absolute position.
-                            svg.rect_debug(
-                                entities[start_i].x + HEAD_WIDTH,
-                                y,
-                                entities[end_i].x - entities[start_i].x,
-                                20.0,
-                            );
-                        }
-                    }
+                result
+            }).collect();
+
+        StaveLayout {
+            top: y + Entity::head_y(LINES_IN_STAVE - 1),
+            bottom: y + Entity::head_y(0),
+            width,
+            voice: voice.to_string(),
+            voices: vec![entities],
+        }
+    }
+}
+
+/// A beam joining the stems of a group of notes.
+#[derive(Debug, PartialEq, Clone)]
+struct Beam {
+    // Indexes of the notes' entities, in order.
+    notes: Vec<usize>,
+
+    direction: StemDirection,
+
+    // The outer beam is a straight line through this point with this slope.
+    x: f32,
+    y: f32,
+    slope: f32,
+}
 
-                    beam_start_i = None;
-                    beam_end_i = None;
+impl Beam {
+    /// Fit a beam to a group of notes that have been laid out.
+    fn new(entities: &[Entity], notes: Vec<usize>) -> Beam {
+        let position = |i: &usize| match entities[*i].glyph {
+            Glyph::NoteHead(position, _, _) => position,
+            _ => MIDDLE_POSITION,
+        };
+
+        // The note furthest from the middle of the stave decides the direction for all of them.
+        let furthest = notes
+            .iter()
+            .map(&position)
+            .max_by_key(|x| (x - MIDDLE_POSITION).abs())
+            .unwrap_or(MIDDLE_POSITION);
+        let direction = if furthest >= MIDDLE_POSITION {
+            StemDirection::Down
+        } else {
+            StemDirection::Up
+        };
+
+        let sign = match direction {
+            StemDirection::Up => -1.0,
+            StemDirection::Down => 1.0,
+        };
+
+        // Start with a line between the natural stem ends of the first and last notes.
+        let first = notes[0];
+        let last = notes[notes.len() - 1];
+        let (x1, y1) = (
+            entities[first].stem_x(direction),
+            Entity::head_y(position(&first)) + sign * STEM_HEIGHT,
+        );
+        let (x2, y2) = (
+            entities[last].stem_x(direction),
+            Entity::head_y(position(&last)) + sign * STEM_HEIGHT,
+        );
+        let slope = if x2 > x1 {
+            ((y2 - y1) / (x2 - x1)).clamp(-MAX_BEAM_SLOPE, MAX_BEAM_SLOPE)
+        } else {
+            0.0
+        };
+
+        let mut beam = Beam {
+            notes,
+            direction,
+            x: x1,
+            y: y1,
+            slope,
+        };
+
+        // Move the beam away from the notes until every stem is long enough for its beams.
+        let shortfall = beam
+            .notes
+            .iter()
+            .map(|i| {
+                let entity = &entities[*i];
+                let stem_length =
+                    sign * (beam.y_at(entity.stem_x(direction)) - Entity::head_y(position(i)));
+                let needed =
+                    MIN_BEAMED_STEM_HEIGHT + (entity.beams().max(1) - 1) as f32 * BEAM_SPACING;
+                needed - stem_length
+            }).fold(0.0, f32::max);
+        beam.y += sign * shortfall;
+
+        beam
+    }
+
+    /// Vertical offset of the outer edge of the beam at x.
+    fn y_at(&self, x: f32) -> f32 {
+        self.y + (x - self.x) * self.slope
+    }
+
+    /// Draw one level of beam between two stems.
+    fn render_segment(&self, svg: &mut svg::Drawing, x1: f32, x2: f32, level: u32, y: f32) {
+        // Inner beams are closer to the note heads, and all beams are inside the stem ends.
+        let (near, far) = match self.direction {
+            StemDirection::Up => (level as f32 * BEAM_SPACING, BEAM_THICKNESS),
+            StemDirection::Down => (-(level as f32) * BEAM_SPACING, -BEAM_THICKNESS),
+        };
+
+        let y1 = y + self.y_at(x1) + near;
+        let y2 = y + self.y_at(x2) + near;
+
+        svg.polygon_fill(vec![(x1, y1), (x2, y2), (x2, y2 + far), (x1, y1 + far)]);
+    }
+
+    fn render(&self, svg: &mut svg::Drawing, entities: &[Entity], y: f32) {
+        let stem_x = |i: usize| entities[self.notes[i]].stem_x(self.direction);
+        let beams = |i: usize| entities[self.notes[i]].beams();
+        let last = self.notes.len() - 1;
+
+        // The outer beam joins them all.
+        self.render_segment(svg, stem_x(0), stem_x(last), 0, y);
+
+        // Inner beams join neighbours that both have them. A note on its own gets a stub, which
+        // points forward unless it's the last note.
+        let levels = (0..self.notes.len()).map(&beams).max().unwrap_or(1);
+        for level in 1..levels {
+            for i in 0..self.notes.len() {
+                if beams(i) <= level {
+                    continue;
                 }
 
-                // Not interested in anything else for drawing beams.
-                _ => (),
+                let previous = i > 0 && beams(i - 1) > level;
+                let next = i < last && beams(i + 1) > level;
+
+                if next {
+                    self.render_segment(svg, stem_x(i), stem_x(i + 1), level, y);
+                } else if !previous {
+                    if i < last {
+                        self.render_segment(svg, stem_x(i), stem_x(i) + HEAD_WIDTH, level, y);
+                    } else {
+                        self.render_segment(svg, stem_x(i) - HEAD_WIDTH, stem_x(i), level, y);
+                    }
+                }
             }
         }
     }
 }
 
-pub fn typeset_from_ast(ast: &tune_ast_three::Tune) -> Page {
+/// Groups of two or more notes to be beamed together, once they have been laid out.
+/// Groups are broken by anything other than a note that can be beamed, including beam breaks.
+fn beam_groups(entities: &[Entity]) -> Vec<Beam> {
+    let mut groups = vec![];
+    let mut group = vec![];
+
+    for (i, entity) in entities.iter().enumerate() {
+        if entity.beams() > 0 {
+            group.push(i);
+        } else {
+            if group.len() > 1 {
+                groups.push(Beam::new(entities, group));
+            }
+            group = vec![];
+        }
+    }
+
+    if group.len() > 1 {
+        groups.push(Beam::new(entities, group));
+    }
+
+    groups
+}
+
+/// Typeset the tune, with optional fingerings for each note, in order, and errors to mark.
+pub fn typeset_from_ast(
+    ast: &tune_ast_three::Tune,
+    fingerings: &[Option<fingering::Fingering>],
+    errors: &[ErrorMarker],
+) -> Page {
     let mut page = Page::new();
+    page.header = Header::from_prelude(&ast.prelude);
 
-    let mut current_stave = Stave::new();
+    let layout = Layout::from_prelude(&ast.prelude);
+    page.scale = layout.scale;
 
     // Always have a key and time signature on the go.
-    let mut key_signature = l::T::KeySignature(
+    let mut key = (
         music::PitchClass {
             diatonic_pitch_class: music::DiatonicPitchClass::C,
             accidental: None,
@@ -571,37 +1796,102 @@ pub fn typeset_from_ast(ast: &tune_ast_three::Tune) -> Page {
     );
     let mut metre = music::Metre(4, 4);
 
-    // TODO We only ever use treble clef at the moment.
-    let current_clef = music::Clef::treble();
+    // Tunes without voice fields have one voice with the defaults.
+    let default_properties = tune_ast_three::VoiceProperties::new("1");
+    let voice_properties =
+        |i: usize| ast.voice_properties.get(i).unwrap_or(&default_properties);
 
-    for ref token in ast.prelude.iter() {
-        match *token {
-            l::T::KeySignature(pitch_class, mode) => {
-                key_signature = l::T::KeySignature(*pitch_class, *mode)
-            }
+    // Count notes to find their fingerings.
+    let mut note_i = 0;
+
+    // Number of notes in each voice, by ID, to find the errors at them.
+    let mut voice_notes: HashMap<&str, usize> = HashMap::new();
+
+    for token in ast.prelude.iter() {
+        match token {
+            l::T::KeySignature(pitch_class, mode) => key = (*pitch_class, *mode),
             l::T::Metre(new_metre) => metre = *new_metre,
             _ => (),
         }
     }
 
-    current_stave
-        .entities
-        .push(Entity::new(Glyph::Clef(current_clef)));
-    // TODO add key signature with params
-    // TODO add time signature with params.
+    // Each voice starts from the key and time signature in the header.
+    let header_key = key;
+    let header_metre = metre;
+
+    // The first stave is labelled with the voice's name, the rest with its subname.
+    let mut current_clef = voice_properties(0).clef;
+    let mut current_stave = Stave::for_voice(
+        current_clef,
+        key,
+        Some(metre),
+        voice_properties(0).name.clone(),
+        layout.stave_width,
+    );
+
+    for (voice_i, voice) in ast.voices.iter().enumerate() {
+        // Bars are numbered from the first full one, so a pick-up is bar 0.
+        let mut bar_number = if voice_properties(voice_i).anacrusis.is_some() {
+            0
+        } else {
+            1
+        };
+
+        // Is there music in the bar so far, and has its number been given to a note yet?
+        let mut in_bar = false;
+        let mut numbered = false;
+        let voice_first_note = note_i;
+
+        // Each voice starts on a new stave.
+        if voice_i > 0 {
+            let properties = voice_properties(voice_i);
+            current_clef = properties.clef;
+            key = header_key;
+            metre = header_metre;
+
+            // Drop the empty stave left by a line break at the end of the last voice.
+            if current_stave.has_music() {
+                page.boxes.push(HorizontalBox::System(current_stave));
+            }
+            current_stave = Stave::for_voice(
+                current_clef,
+                key,
+                Some(metre),
+                properties.name.clone(),
+                layout.stave_width,
+            );
+        }
+        page.voices
+            .push((page.boxes.len(), voice_properties(voice_i).id.clone()));
 
-    for ref voice in ast.voices.iter() {
         for ref token in voice.iter() {
             match token {
                 l::T::Newline => {
                     page.boxes.push(HorizontalBox::System(current_stave));
-                    current_stave = Stave::new();
+                    current_stave = Stave::for_voice(
+                        current_clef,
+                        key,
+                        None,
+                        voice_properties(voice_i).subname.clone(),
+                        layout.stave_width,
+                    );
+                }
 
+                // Changes part-way through show the new signature where they happen.
+                l::T::KeySignature(pitch_class, mode) => {
+                    key = (*pitch_class, *mode);
+                    current_stave.entities.extend(key_signature_entities(
+                        current_clef,
+                        *pitch_class,
+                        *mode,
+                    ));
+                }
+
+                l::T::Metre(new_metre) => {
+                    metre = *new_metre;
                     current_stave
                         .entities
-                        .push(Entity::new(Glyph::Clef(current_clef)));
-                    // TODO add key signature with params
-                    // TODO add time signature with params.
+                        .push(Entity::new(Glyph::TimeSignature(metre)));
                 }
 
                 // TODO can collapse some sequential things down into single glyphs.
@@ -615,17 +1905,47 @@ pub fn typeset_from_ast(ast: &tune_ast_three::Tune) -> Page {
 
                 l::T::EndBar => current_stave.entities.push(Entity::new(Glyph::EndBar)),
 
+                l::T::NTimeBar(number) => current_stave
+                    .entities
+                    .push(Entity::new(Glyph::Ending(Some(*number)))),
+
                 l::T::Note(note) => {
-                    // TODO extras like accidentals etc.
                     let music::Note(pitch, duration) = note;
                     let clef_interval = current_clef.pitch.interval_to(*pitch);
 
-                    let position = (clef_interval.pitch_classes + current_clef.centre) as i32;
+                    let position = clef_interval.pitch_classes + current_clef.centre;
                     let glyph = duration.to_glyph();
 
-                    current_stave
-                        .entities
-                        .push(Entity::new(Glyph::NoteHead(position, glyph)));
+                    if let Some(Some(fingering)) = fingerings.get(note_i) {
+                        current_stave
+                            .annotations
+                            .push((current_stave.entities.len(), fingering.clone()));
+                    }
+                    let voice_note = (&voice_properties(voice_i).id, note_i - voice_first_note);
+                    for error in errors.iter().filter(|x| {
+                        x.note
+                            .as_ref()
+                            .is_some_and(|(id, i)| (id, *i) == voice_note)
+                    }) {
+                        current_stave
+                            .errors
+                            .push((current_stave.entities.len(), error.message.clone()));
+                    }
+
+                    // The voice's first stave isn't numbered, whichever bar it starts in.
+                    if !numbered && note_i > voice_first_note {
+                        current_stave
+                            .bar_numbers
+                            .push((current_stave.entities.len(), bar_number));
+                    }
+                    numbered = true;
+                    note_i += 1;
+
+                    current_stave.entities.push(Entity::new(Glyph::NoteHead(
+                        position,
+                        glyph,
+                        pitch.pitch_class.accidental,
+                    )));
                 }
 
                 // Beam break manifests as a zero-width entity. Just like in ABC.
@@ -636,18 +1956,737 @@ pub fn typeset_from_ast(ast: &tune_ast_three::Tune) -> Page {
                     // TODO don't ignore!
                 }
             }
+
+            // A barline after some music ends the bar. Others, e.g. an opening repeat after a
+            // closing one, don't start a new one.
+            match token {
+                l::T::Note(_) | l::T::Rest(_) => in_bar = true,
+                _ if in_bar && beaming::is_barline(token) => {
+                    bar_number += 1;
+                    in_bar = false;
+                    numbered = false;
+                }
+                _ => (),
+            }
+
+            // Long lines in the ABC are broken up rather than squashed to fit.
+            loop {
+                let continuation = Stave::for_voice(
+                    current_clef,
+                    key,
+                    None,
+                    voice_properties(voice_i).subname.clone(),
+                    layout.stave_width,
+                );
+
+                match current_stave.break_line(continuation) {
+                    Some(full_stave) => page.boxes.push(HorizontalBox::System(full_stave)),
+                    None => break,
+                }
+            }
         }
+
+        voice_notes.insert(&voice_properties(voice_i).id, note_i - voice_first_note);
     }
 
     page.boxes.push(HorizontalBox::System(current_stave));
 
+    // Anything that didn't find its note goes above the music.
+    page.errors = errors
+        .iter()
+        .filter(|x| {
+            x.note
+                .as_ref()
+                .is_none_or(|(id, i)| voice_notes.get(id.as_str()).is_none_or(|notes| i >= notes))
+        })
+        .map(|x| x.message.clone())
+        .collect();
+
     page
 }
 
-pub fn render_page(page: Page) -> String {
+/// Lay out the page as it would be drawn, without drawing it.
+pub fn layout_page(page: &Page, typesetting: &Typesetting) -> PageLayout {
+    let mut lines = vec![];
+    let mut y = page.music_y(typesetting);
+
+    // Notes are counted within each voice.
+    let mut voice = "1";
+    let mut voice_notes: HashMap<&str, usize> = HashMap::new();
+    for (i, horizontal_box) in page.boxes.iter().enumerate() {
+        if let Some((_, id)) = page.voices.iter().rev().find(|(start, _)| *start <= i) {
+            voice = id;
+        }
+
+        match horizontal_box {
+            HorizontalBox::System(ref stave) => {
+                let first_note = voice_notes.entry(voice).or_insert(0);
+                let layout = stave.layout_at(y, voice, *first_note);
+                *first_note += layout
+                    .voices
+                    .iter()
+                    .flat_map(|x| x.iter())
+                    .filter(|x| x.note.is_some())
+                    .count();
+                lines.push(LineLayout {
+                    staff: vec![layout],
+                });
+            }
+        }
+
+        y += horizontal_box.height();
+    }
+
+    // The header can be wider than the music, so the size is whatever the drawing comes to.
+    let drawing = draw_page(page.clone(), typesetting);
+
+    PageLayout {
+        width: drawing.width(),
+        height: drawing.height(),
+        scale: page.scale,
+        lines,
+    }
+}
+
+pub fn draw_page(page: Page, typesetting: &Typesetting) -> svg::Drawing {
     let mut svg = svg::Drawing::new();
+    svg.set_font_family(typesetting.fonts.family);
+    svg.set_scale(page.scale);
+
+    page.render(&mut svg, typesetting);
+
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    page.render(&mut svg);
+    fn positions(
+        key_note: music::DiatonicPitchClass,
+        accidental: Option<music::Accidental>,
+    ) -> Vec<i32> {
+        clef_positions(music::Clef::treble(), key_note, accidental)
+    }
 
-    svg.render()
+    fn clef_positions(
+        clef: music::Clef,
+        key_note: music::DiatonicPitchClass,
+        accidental: Option<music::Accidental>,
+    ) -> Vec<i32> {
+        key_signature_entities(
+            clef,
+            music::PitchClass {
+                diatonic_pitch_class: key_note,
+                accidental,
+            },
+            music::Mode::Major,
+        ).iter()
+        .map(|x| match x.glyph {
+            Glyph::KeyAccidental(position, _) => position,
+            _ => panic!("Expected only accidentals."),
+        }).collect()
+    }
+
+    #[test]
+    fn key_signature_test() {
+        assert!(positions(music::DiatonicPitchClass::C, None).is_empty());
+
+        // F♯ on the top line, C♯ in the third space.
+        assert_eq!(positions(music::DiatonicPitchClass::D, None), vec![8, 5]);
+
+        // The usual zig-zag for seven sharps and flats.
+        assert_eq!(
+            positions(music::DiatonicPitchClass::C, Some(music::Accidental::Sharp)),
+            vec![8, 5, 9, 6, 3, 7, 4]
+        );
+        assert_eq!(
+            positions(music::DiatonicPitchClass::C, Some(music::Accidental::Flat)),
+            vec![4, 7, 3, 6, 2, 5, 1]
+        );
+
+        // The same shapes, a line lower in the bass clef and a space lower in the alto clef.
+        assert_eq!(
+            clef_positions(
+                music::Clef::bass(),
+                music::DiatonicPitchClass::C,
+                Some(music::Accidental::Sharp)
+            ),
+            vec![6, 3, 7, 4, 1, 5, 2]
+        );
+        assert_eq!(
+            clef_positions(
+                music::Clef::bass(),
+                music::DiatonicPitchClass::C,
+                Some(music::Accidental::Flat)
+            ),
+            vec![2, 5, 1, 4, 0, 3, -1]
+        );
+        assert_eq!(
+            clef_positions(
+                music::Clef::alto(),
+                music::DiatonicPitchClass::C,
+                Some(music::Accidental::Sharp)
+            ),
+            vec![7, 4, 8, 5, 2, 6, 3]
+        );
+    }
+
+    #[test]
+    fn clef_test() {
+        // Middle C is on the ledger line above the bass clef's stave, two lines above its F, and on
+        // the alto clef's middle line.
+        for (clef, position) in [(music::Clef::bass(), 10), (music::Clef::alto(), 4)].iter() {
+            let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+                &format!("X:1\nK:C clef={}\nC|\n", clef.name())
+                    .chars()
+                    .collect::<Vec<char>>(),
+            ));
+            let page = typeset_from_ast(&ast, &[], &[]);
+            let HorizontalBox::System(ref stave) = page.boxes[0];
+
+            assert_eq!(stave.entities[0].glyph, Glyph::Clef(*clef));
+            assert!(stave.entities.iter().any(|x| match x.glyph {
+                Glyph::NoteHead(note_position, _, _) => note_position == *position,
+                _ => false,
+            }));
+        }
+    }
+
+    #[test]
+    fn front_matter_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nM:6/8\nK:G\nGAB|\nGAB|\n".chars().collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(&ast, &[], &[]);
+
+        let glyphs = |i: usize| match page.boxes[i] {
+            HorizontalBox::System(ref stave) => stave
+                .entities
+                .iter()
+                .take(3)
+                .map(|x| x.glyph)
+                .collect::<Vec<Glyph>>(),
+        };
+
+        // The time signature is only on the first line.
+        assert_eq!(
+            glyphs(0),
+            vec![
+                Glyph::Clef(music::Clef::treble()),
+                Glyph::KeyAccidental(8, music::Accidental::Sharp),
+                Glyph::TimeSignature(music::Metre(6, 8)),
+            ]
+        );
+        assert_eq!(
+            glyphs(1)[..2].to_vec(),
+            vec![
+                Glyph::Clef(music::Clef::treble()),
+                Glyph::KeyAccidental(8, music::Accidental::Sharp),
+            ]
+        );
+        assert!(!glyphs(1).contains(&Glyph::TimeSignature(music::Metre(6, 8))));
+    }
+
+    #[test]
+    fn header_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nT:Kesh\nT:\nT:The Kesh Jig\nQ:3/8=120\nR:jig\nC:Trad.\nK:G\nGAB|\n"
+                .chars()
+                .collect::<Vec<char>>(),
+        ));
+
+        assert_eq!(
+            Header::from_prelude(&ast.prelude),
+            Some(Header {
+                titles: vec!["Kesh".to_string(), "The Kesh Jig".to_string()],
+                composers: vec!["Trad.".to_string()],
+                tempos: vec!["jig".to_string(), "3/8=120".to_string()],
+            })
+        );
+
+        assert_eq!(Header::from_prelude(&[l::T::X("1".to_string())]), None);
+
+        // The header pushes the music down, unless it's turned off.
+        let mut typesetting = Typesetting::new();
+        let with_header = draw_page(typeset_from_ast(&ast, &[], &[]), &typesetting);
+        typesetting.header = false;
+        let without_header = draw_page(typeset_from_ast(&ast, &[], &[]), &typesetting);
+
+        assert!(with_header.height() > without_header.height());
+        assert!(with_header.render().contains(">The Kesh Jig<"));
+        assert!(!without_header.render().contains(">The Kesh Jig<"));
+    }
+
+    #[test]
+    fn ending_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nL:1/4\nK:G\n|:GABc|1 dedB:|2 dBAG||\n"
+                .chars()
+                .collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(&ast, &[], &[]);
+
+        let HorizontalBox::System(ref stave) = page.boxes[0];
+        assert!(stave.has_endings());
+        assert!(!Stave::open_ending(&stave.entities));
+
+        // The second ending is still open at its last note.
+        let last_note = stave
+            .entities
+            .iter()
+            .rposition(|x| matches!(x.glyph, Glyph::NoteHead(_, _, _)))
+            .unwrap();
+        assert!(Stave::open_ending(&stave.entities[..last_note]));
+
+        // The stave makes room for the brackets above it.
+        let mut without_endings = stave.clone();
+        without_endings
+            .entities
+            .retain(|x| !matches!(x.glyph, Glyph::Ending(_)));
+        assert_eq!(stave.height(), without_endings.height() + ENDING_HEIGHT);
+
+        let svg = draw_page(page, &Typesetting::new()).render();
+        assert!(svg.contains(">1.</text>"));
+        assert!(svg.contains(">2.</text>"));
+    }
+
+    #[test]
+    fn errors_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nK:G\nGAB|\ncde|\n".chars().collect::<Vec<char>>(),
+        ));
+        let marker = |note: Option<usize>, message: &str| ErrorMarker {
+            note: note.map(|i| ("1".to_string(), i)),
+            message: message.to_string(),
+        };
+        let page = typeset_from_ast(
+            &ast,
+            &[],
+            &[
+                marker(None, "In the header"),
+                marker(Some(4), "At the d"),
+                marker(Some(6), "After the end"),
+            ],
+        );
+
+        // Errors at notes go with their stave, the rest above the music.
+        assert_eq!(page.errors, vec!["In the header", "After the end"]);
+        let staves = page
+            .boxes
+            .iter()
+            .map(|x| match x {
+                HorizontalBox::System(ref stave) => stave,
+            }).collect::<Vec<&Stave>>();
+        assert!(staves[0].errors.is_empty());
+        assert_eq!(staves[1].errors.len(), 1);
+
+        let (i, ref message) = staves[1].errors[0];
+        assert_eq!(message, "At the d");
+        assert!(matches!(
+            staves[1].entities[i].glyph,
+            Glyph::NoteHead(_, _, _)
+        ));
+        assert_eq!(
+            staves[1].height(),
+            staves[0].height() + ERROR_LINE_HEIGHT,
+            "The stave makes room for the message."
+        );
+
+        let svg = draw_page(page, &Typesetting::new()).render();
+        assert_eq!(svg.matches("class='error'").count(), 4);
+        assert!(svg.contains(">At the d<"));
+
+        // Markers are matched to notes by voice.
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nK:G\nV:1\nGAB|\nV:2\nd|\nV:1\nc|\n"
+                .chars()
+                .collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(
+            &ast,
+            &[],
+            &[
+                ErrorMarker {
+                    note: Some(("2".to_string(), 0)),
+                    message: "At the d".to_string(),
+                },
+                ErrorMarker {
+                    note: Some(("2".to_string(), 1)),
+                    message: "After the end".to_string(),
+                },
+            ],
+        );
+        assert_eq!(page.errors, vec!["After the end"]);
+        let staves = page
+            .boxes
+            .iter()
+            .map(|x| match x {
+                HorizontalBox::System(ref stave) => stave,
+            }).collect::<Vec<&Stave>>();
+        let notes = |stave: &Stave| {
+            stave
+                .entities
+                .iter()
+                .filter(|x| matches!(x.glyph, Glyph::NoteHead(_, _, _)))
+                .count()
+        };
+        assert_eq!(
+            staves
+                .iter()
+                .map(|x| (notes(x), x.errors.len()))
+                .collect::<Vec<(usize, usize)>>(),
+            vec![(3, 0), (1, 0), (1, 1), (0, 0)]
+        );
+    }
+
+    #[test]
+    fn split_indexed_test() {
+        let (kept, moved) = split_indexed(vec![(1, 'a'), (4, 'b'), (6, 'c')], 4, 2);
+        assert_eq!(kept, vec![(1, 'a')]);
+        assert_eq!(moved, vec![(2, 'b'), (4, 'c')]);
+    }
+
+    #[test]
+    fn ending_line_break_test() {
+        let mut stave = Stave::for_voice(
+            music::Clef::treble(),
+            (
+                music::PitchClass {
+                    diatonic_pitch_class: music::DiatonicPitchClass::C,
+                    accidental: None,
+                },
+                music::Mode::Major,
+            ),
+            None,
+            None,
+            STAVE_WIDTH,
+        );
+        stave.entities.push(Entity::new(Glyph::Ending(Some(1))));
+        for _ in 0..40 {
+            stave.entities.extend(notes(&[quaver(4), quaver(5)]));
+            stave.entities.push(Entity::new(Glyph::SingleBar));
+        }
+
+        let original = stave.clone();
+        let full_stave = stave.break_line(Stave::new()).unwrap();
+        assert!(Stave::open_ending(&full_stave.entities));
+
+        // The ending carries on over the break, without its number.
+        assert_eq!(stave.entities[0].glyph, Glyph::Ending(None));
+        assert_eq!(
+            full_stave.entities.len() + stave.entities.len(),
+            original.entities.len() + 1
+        );
+    }
+
+    #[test]
+    fn line_break_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nM:6/8\nL:1/8\nK:D\n\
+              DFA dfa|BAG FED|DFA dfa|BAG FED|DFA dfa|BAG FED|DFA dfa|BAG FED|]\n"
+                .chars()
+                .collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(&ast, &[], &[]);
+
+        let staves = page
+            .boxes
+            .iter()
+            .map(|x| match x {
+                HorizontalBox::System(ref stave) => stave,
+            }).filter(|x| x.has_music())
+            .collect::<Vec<&Stave>>();
+
+        // One line in the ABC becomes several on the page.
+        assert!(staves.len() > 1);
+
+        for stave in staves.iter() {
+            assert!(stave.natural_width() <= STAVE_WIDTH);
+        }
+
+        // Every line but the last is broken at a barline.
+        for stave in staves[..staves.len() - 1].iter() {
+            assert!(stave.entities.last().unwrap().is_end_matter());
+        }
+
+        // Nothing is lost.
+        let heads = staves
+            .iter()
+            .map(|stave| {
+                stave
+                    .entities
+                    .iter()
+                    .filter(|x| matches!(x.glyph, Glyph::NoteHead(_, _, _)))
+                    .count()
+            }).sum::<usize>();
+        assert_eq!(heads, 48);
+    }
+
+    #[test]
+    fn bar_number_test() {
+        let staves = |abc: &str| -> Vec<Option<usize>> {
+            let ast =
+                tune_ast_three::read_from_lexer(l::Lexer::new(&abc.chars().collect::<Vec<char>>()));
+            typeset_from_ast(&ast, &[], &[])
+                .boxes
+                .iter()
+                .map(|x| match x {
+                    HorizontalBox::System(ref stave) => stave,
+                }).filter(|x| x.has_music())
+                .map(|x| x.first_bar_number())
+                .collect()
+        };
+
+        // The pick-up isn't counted, so the second line starts with bar 3.
+        assert_eq!(
+            staves("X:1\nM:6/8\nL:1/8\nK:D\nA|:d2f afd|e3 e2A|\nd2f afd|e3 e2:|\n"),
+            vec![None, Some(3)]
+        );
+        assert_eq!(
+            staves("X:1\nM:6/8\nL:1/8\nK:D\n|:d2f afd|e3 e2A|\nd2f afd|e3 e2:|\n"),
+            vec![None, Some(3)]
+        );
+
+        // A line that starts part-way through a bar isn't numbered, and bars of rests count.
+        assert_eq!(
+            staves("X:1\nM:3/4\nL:1/8\nK:D\nz6|d2 f2\na2|d6|]\n"),
+            vec![None, None]
+        );
+        assert_eq!(
+            staves("X:1\nM:3/4\nL:1/8\nK:D\nz6|d2 f2 a2|\nd6|]\n"),
+            vec![None, Some(3)]
+        );
+
+        // Lines broken to fit the page are numbered too.
+        let long = "X:1\nM:6/8\nL:1/8\nK:D\nA|DFA dfa|BAG FED|DFA dfa|BAG FED|DFA dfa|BAG FED|\
+                    DFA dfa|BAG FED|]\n";
+        let numbers = staves(long);
+        assert!(numbers.len() > 1);
+        assert_eq!(numbers[0], None);
+        assert!(numbers[1..].iter().all(|x| x.is_some()));
+    }
+
+    #[test]
+    fn directives_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\n%%scale 0.5\n%%staffwidth 8cm\nM:6/8\nL:1/8\nK:D\n\
+              DFA dfa|BAG FED|DFA dfa|BAG FED|]\n"
+                .chars()
+                .collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(&ast, &[], &[]);
+        assert_eq!(page.scale, 0.5);
+
+        let staves = page
+            .boxes
+            .iter()
+            .map(|x| match x {
+                HorizontalBox::System(ref stave) => stave,
+            }).filter(|x| x.has_music())
+            .collect::<Vec<&Stave>>();
+
+        // Four bars fit on one line of the default width, but not in 8cm.
+        assert!(staves.len() > 1);
+        for stave in staves.iter() {
+            assert!(stave.natural_width() <= 8.0 * PX_PER_CM);
+        }
+
+        let drawing = draw_page(page, &Typesetting::new());
+        assert_eq!(drawing.scale(), 0.5);
+    }
+
+    #[test]
+    fn parse_length_test() {
+        assert_eq!(parse_length("72"), Some(96.0));
+        assert_eq!(parse_length("72pt"), Some(96.0));
+        assert_eq!(parse_length("2in"), Some(192.0));
+        assert_eq!(parse_length(" 2.54cm"), Some(96.0));
+        assert_eq!(parse_length("-1in"), None);
+        assert_eq!(parse_length("wide"), None);
+        assert_eq!(parse_length("é"), None);
+    }
+
+    // Notes laid out at regular intervals.
+    fn notes(glyphs: &[Glyph]) -> Vec<Entity> {
+        glyphs
+            .iter()
+            .enumerate()
+            .map(|(i, glyph)| Entity {
+                glyph: *glyph,
+                x: i as f32 * 30.0,
+            }).collect()
+    }
+
+    fn quaver(position: i32) -> Glyph {
+        Glyph::NoteHead(
+            position,
+            Some(music::DurationGlyph {
+                shape: music::DurationClass::Quaver,
+                dots: 0,
+            }),
+            None,
+        )
+    }
+
+    #[test]
+    fn beam_groups_test() {
+        let crotchet = Glyph::NoteHead(
+            0,
+            Some(music::DurationGlyph {
+                shape: music::DurationClass::Crotchet,
+                dots: 0,
+            }),
+            None,
+        );
+
+        let entities = notes(&[
+            quaver(0),
+            quaver(1),
+            Glyph::BeamBreak,
+            quaver(2),
+            Glyph::SingleBar,
+            quaver(3),
+            crotchet,
+            quaver(4),
+            quaver(5),
+            quaver(6),
+        ]);
+
+        let groups: Vec<Vec<usize>> = beam_groups(&entities)
+            .iter()
+            .map(|x| x.notes.clone())
+            .collect();
+
+        // Single notes aren't beamed.
+        assert_eq!(groups, vec![vec![0, 1], vec![7, 8, 9]]);
+    }
+
+    #[test]
+    fn note_accidental_test() {
+        let ast = tune_ast_three::read_from_lexer(l::Lexer::new(
+            &"X:1\nK:G\n^F_B=cd|\n".chars().collect::<Vec<char>>(),
+        ));
+        let page = typeset_from_ast(&ast, &[], &[]);
+
+        let accidentals: Vec<Option<music::Accidental>> = match page.boxes[0] {
+            HorizontalBox::System(ref stave) => stave
+                .entities
+                .iter()
+                .filter_map(|x| match x.glyph {
+                    Glyph::NoteHead(_, _, accidental) => Some(accidental),
+                    _ => None,
+                }).collect(),
+        };
+
+        // Only accidentals written in the ABC are shown, not ones from the key signature.
+        assert_eq!(
+            accidentals,
+            vec![
+                Some(music::Accidental::Sharp),
+                Some(music::Accidental::Flat),
+                Some(music::Accidental::Natural),
+                None,
+            ]
+        );
+
+        // Room is made for the accidental before the head.
+        let plain = Entity::new(quaver(0));
+        let sharp = Entity::new(Glyph::NoteHead(
+            0,
+            Some(music::DurationGlyph {
+                shape: music::DurationClass::Quaver,
+                dots: 0,
+            }),
+            Some(music::Accidental::Sharp),
+        ));
+        assert!(sharp.width() > plain.width());
+        assert_eq!(
+            sharp.stem_x(StemDirection::Up) - plain.stem_x(StemDirection::Up),
+            sharp.head_offset()
+        );
+    }
+
+    #[test]
+    fn beam_direction_test() {
+        // Low notes have stems up, with the beam above.
+        let entities = notes(&[quaver(0), quaver(2)]);
+        let beam = &beam_groups(&entities)[0];
+        assert_eq!(beam.direction, StemDirection::Up);
+        assert!(beam.y_at(entities[0].stem_x(StemDirection::Up)) < Entity::head_y(0));
+
+        // The note furthest from the middle decides.
+        let entities = notes(&[quaver(3), quaver(10)]);
+        let beam = &beam_groups(&entities)[0];
+        assert_eq!(beam.direction, StemDirection::Down);
+        assert!(beam.y_at(entities[0].stem_x(StemDirection::Down)) > Entity::head_y(3));
+    }
+
+    #[test]
+    fn beam_slope_test() {
+        // A big leap doesn't make a steep beam.
+        let entities = notes(&[quaver(-2), quaver(3)]);
+        let beam = &beam_groups(&entities)[0];
+        assert_eq!(beam.slope, -MAX_BEAM_SLOPE);
+
+        // Every stem is still long enough.
+        for entity in entities.iter() {
+            if let Glyph::NoteHead(position, _, _) = entity.glyph {
+                let stem_length =
+                    Entity::head_y(position) - beam.y_at(entity.stem_x(StemDirection::Up));
+                assert!(stem_length >= MIN_BEAMED_STEM_HEIGHT - 0.001);
+            }
+        }
+
+        // A level group has a level beam.
+        let entities = notes(&[quaver(1), quaver(1), quaver(1)]);
+        assert_eq!(beam_groups(&entities)[0].slope, 0.0);
+    }
+
+    #[test]
+    fn duration_spacing_test() {
+        let note = |shape| {
+            Glyph::NoteHead(
+                0,
+                Some(music::DurationGlyph {
+                    shape,
+                    dots: 0,
+                }),
+                None,
+            )
+        };
+
+        // Longer notes have more space after them, but always leave room for the head.
+        let semiquaver = Entity::new(note(music::DurationClass::Semiquaver));
+        let quaver = Entity::new(note(music::DurationClass::Quaver));
+        let minim = Entity::new(note(music::DurationClass::Minim));
+        assert!(semiquaver.spring() < quaver.spring());
+        assert!(quaver.spring() < minim.spring());
+        assert_eq!(semiquaver.rigid_width(), minim.rigid_width());
+
+        // A short line doesn't stretch all the way to the stave width.
+        let mut stave = Stave::new();
+        stave.entities = notes(&[
+            note(music::DurationClass::Minim),
+            note(music::DurationClass::Quaver),
+            note(music::DurationClass::Quaver),
+            Glyph::SingleBar,
+        ]);
+        let (entities, width) = stave.layout();
+        assert_eq!(
+            entities[1].x - entities[0].x,
+            minim.rigid_width() + minim.spring() * MAXIMUM_SPRING_SCALE
+        );
+        assert_eq!(width, entities[3].x + entities[3].rigid_width());
+        assert!(width < stave.width);
+
+        // A long line is justified to the stave width by stretching only the springs.
+        stave.width = stave.natural_width() + 10.0;
+        let (entities, width) = stave.layout();
+        assert!((width - stave.width).abs() < 0.01);
+        let stretch =
+            |i: usize, entity: &Entity| entities[i + 1].x - entities[i].x - entity.width();
+        assert!(stretch(0, &minim) > stretch(1, &quaver));
+        assert!((stretch(1, &quaver) - stretch(2, &quaver)).abs() < 0.01);
+    }
 }